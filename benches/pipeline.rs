@@ -0,0 +1,96 @@
+//! Per-stage microbenchmarks: each keystream backend, each record encoder
+//! and each output format's serializer, on one thread and one chunk at a
+//! time, so a change to one stage can be measured apart from the others.
+//! End-to-end runs are what `bench` (and `bench --compare`) are for.
+//!
+//! `cargo bench --bench pipeline -- <filter>` runs a subset, e.g. `cipher/`.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use mass_password_gen_optimized::{
+    Capitalization, Charset, Cipher, Encoder, Format, Passphrase, PasswordGenerator,
+    PasswordGeneratorBuilder, Template,
+};
+use std::hint::black_box;
+
+const KEY: [u8; 16] = [0x13; 16];
+
+/// Records per chunk: a cache-resident chunk and the CLI's default.
+const CHUNK_SIZES: [usize; 2] = [10_000, 1_000_000];
+
+/// Records per chunk for encoder and serializer benchmarks.
+const RECORDS: usize = 100_000;
+
+fn generator(builder: PasswordGeneratorBuilder, chunk_size: usize) -> PasswordGenerator {
+    builder
+        .key(KEY)
+        .count(chunk_size)
+        .chunk_size(chunk_size)
+        .build()
+        .unwrap()
+}
+
+/// Times generating chunk 0 of `generator`.
+fn fill(c: &mut Criterion, group: &str, id: BenchmarkId, generator: &PasswordGenerator) {
+    let mut group = c.benchmark_group(group);
+    group.throughput(Throughput::Elements(generator.chunk_len(0) as u64));
+    let mut scratch = Vec::new();
+    let mut out = vec![0; generator.chunk_len(0) * generator.record_width()];
+    group.bench_function(id, |b| {
+        b.iter(|| black_box(generator.fill_chunk(0, &mut scratch, &mut out)))
+    });
+    group.finish();
+}
+
+fn ciphers(c: &mut Criterion) {
+    for cipher in Cipher::ALL {
+        for chunk_size in CHUNK_SIZES {
+            let g = generator(PasswordGenerator::builder().cipher(cipher), chunk_size);
+            let id = BenchmarkId::new(cipher.to_string(), chunk_size);
+            fill(c, "cipher", id, &g);
+        }
+    }
+}
+
+fn encoders(c: &mut Criterion) {
+    let template = Template::parse("Cvccvc-####-Cvccvc").unwrap();
+    let shapes = [
+        ("alnum-16", Encoder::new(&Charset::Alnum, 16)),
+        ("symbols-24", Encoder::new(&Charset::AlnumSymbols, 24)),
+        ("hex-32", Encoder::new(&Charset::Hex, 32)),
+        ("template", Encoder::from_template(&template)),
+    ];
+    for (name, encoder) in shapes {
+        let g = generator(PasswordGenerator::builder().encoder(encoder), RECORDS);
+        fill(c, "encoder", BenchmarkId::new(name, RECORDS), &g);
+    }
+    let passphrase = Passphrase::new(5, "-", Capitalization::Title, 2).unwrap();
+    let g = generator(PasswordGenerator::builder().passphrase(passphrase), RECORDS);
+    fill(c, "encoder", BenchmarkId::new("passphrase-5", RECORDS), &g);
+}
+
+fn serializers(c: &mut Criterion) {
+    let g = generator(
+        PasswordGenerator::builder().encoder(Encoder::new(&Charset::Alnum, 16)),
+        RECORDS,
+    );
+    let mut chunk = vec![0; RECORDS * g.record_width()];
+    let len = g.fill_chunk(0, &mut Vec::new(), &mut chunk);
+    chunk.truncate(len);
+    let mut group = c.benchmark_group("sink");
+    group.throughput(Throughput::Elements(RECORDS as u64));
+    for format in [Format::Hex, Format::Base64, Format::Csv, Format::Jsonl] {
+        let sink = format.sink(&g).unwrap();
+        let mut out = Vec::new();
+        group.bench_function(BenchmarkId::new(format.to_string(), RECORDS), |b| {
+            b.iter(|| {
+                out.clear();
+                sink.write_chunk(&g, 0, &chunk, &mut out);
+                black_box(out.len())
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, ciphers, encoders, serializers);
+criterion_main!(benches);
@@ -0,0 +1,36 @@
+//! Records the git commit and compiler a binary is built with, for the
+//! host information manifests keep (`MPG_GIT_COMMIT`, `MPG_RUSTC`).
+
+use std::path::Path;
+use std::process::Command;
+
+fn main() {
+    let output = |program: &str, args: &[&str]| {
+        let out = Command::new(program).args(args).output().ok()?;
+        let text = String::from_utf8(out.stdout).ok()?;
+        (out.status.success() && !text.trim().is_empty()).then(|| text.trim().to_string())
+    };
+    if let Some(commit) = output("git", &["rev-parse", "HEAD"]) {
+        println!("cargo:rustc-env=MPG_GIT_COMMIT={}", commit);
+    }
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    if let Some(version) = output(&rustc, &["--version"]) {
+        println!("cargo:rustc-env=MPG_RUSTC={}", version);
+    }
+    // A new commit changes HEAD, or the branch it names
+    println!("cargo:rerun-if-changed=build.rs");
+    let head = Path::new(".git/HEAD");
+    if head.exists() {
+        println!("cargo:rerun-if-changed=.git/HEAD");
+        let named = std::fs::read_to_string(head).unwrap_or_default();
+        if let Some(branch) = named.trim().strip_prefix("ref: ") {
+            let branch = Path::new(".git").join(branch);
+            if branch.exists() {
+                println!("cargo:rerun-if-changed={}", branch.display());
+            }
+        }
+        if Path::new(".git/packed-refs").exists() {
+            println!("cargo:rerun-if-changed=.git/packed-refs");
+        }
+    }
+}
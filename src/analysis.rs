@@ -0,0 +1,392 @@
+//! Password strength and output audits.
+//!
+//! [`entropy_bits`] is the theoretical strength of a run's records, from
+//! the charset, length, policy or passphrase settings alone, and
+//! [`crack_seconds`] turns it into the average time an attacker guessing
+//! at a given rate needs to find one password.
+//!
+//! [`audit`] checks that the records actually generated live up to that.
+//! It regenerates a sample of the run and tests every character position
+//! with a chi-squared test against the expected symbol frequencies, and
+//! the symbols as one sequence for serial correlation, so that an encoding
+//! bug that skews some characters (modulo bias, an off-by-one in the
+//! alphabet) shows up as a failed audit instead of weaker passwords.
+
+use crate::PasswordGenerator;
+
+/// Guess rates crack times are quoted at: attack and guesses per second.
+pub const GUESS_RATES: [(&str, f64); 4] = [
+    ("online, throttled (100/s)", 1e2),
+    ("offline, slow hash (10^4/s)", 1e4),
+    ("offline, fast hash on one GPU (10^10/s)", 1e10),
+    ("offline, fast hash on a cluster (10^12/s)", 1e12),
+];
+
+/// The audit fails if any of its tests gives a p-value below this, split
+/// evenly between the tests.
+pub const SIGNIFICANCE: f64 = 1e-6;
+
+/// Least expected count of a symbol at a position for the chi-squared
+/// approximation to hold.
+const MIN_EXPECTED: usize = 5;
+
+/// Entropy of one of `generator`'s records in bits; a policy removes the
+/// non-compliant ones.
+pub fn entropy_bits(generator: &PasswordGenerator) -> f64 {
+    if let Some(pass) = generator.passphrase() {
+        return pass.entropy_bits();
+    }
+    let bits = generator.encoder().map_or(128.0, |enc| enc.entropy_bits());
+    bits + generator.policy_acceptance().map_or(0.0, f64::log2)
+}
+
+/// Average time to guess a `bits`-bit password at `rate` guesses a second:
+/// half the space.
+pub fn crack_seconds(bits: f64, rate: f64) -> f64 {
+    (bits - 1.0).exp2() / rate
+}
+
+/// Expected number of records repeating an earlier one among `n` drawn
+/// uniformly from `space` values.
+pub fn expected_duplicates(n: f64, space: f64) -> f64 {
+    let load = n / space;
+    if load < 1e-4 {
+        // The exact form below cancels to nothing here
+        n * load / 2.0
+    } else {
+        n + space * (-load).exp_m1()
+    }
+}
+
+/// `secs` in the largest unit that keeps it at least one.
+pub fn format_duration(secs: f64) -> String {
+    const UNITS: [(&str, f64); 5] = [
+        ("years", 365.25 * 86400.0),
+        ("days", 86400.0),
+        ("hours", 3600.0),
+        ("minutes", 60.0),
+        ("seconds", 1.0),
+    ];
+    if secs < 1.0 {
+        return "under a second".to_string();
+    }
+    let (unit, size) = UNITS.iter().find(|(_, size)| secs >= *size).unwrap();
+    let n = secs / size;
+    if n >= 1e6 {
+        format!("{:.1e} {}", n, unit)
+    } else {
+        format!("{:.1} {}", n, unit)
+    }
+}
+
+/// A chi-squared test of one character position.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PositionTest {
+    pub chi_squared: f64,
+    pub degrees: usize,
+    pub p_value: f64,
+}
+
+/// What [`audit`] found.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Audit {
+    /// Records sampled
+    pub records: usize,
+    /// Symbols in the alphabet tested against
+    pub symbols: usize,
+    /// Whether positions were tested against the sample's pooled
+    /// frequencies rather than uniform ones, as a policy skews them
+    pub pooled: bool,
+    /// One test per character (or byte, for raw blocks) position
+    pub positions: Vec<PositionTest>,
+    /// Serial correlation of consecutive symbols (with `pooled`, the mean
+    /// over positions of each position's symbols from record to record)
+    pub serial_correlation: f64,
+    pub serial_p_value: f64,
+    /// Characters outside the alphabet
+    pub foreign: usize,
+}
+
+impl Audit {
+    /// The position with the smallest p-value.
+    pub fn worst_position(&self) -> (usize, PositionTest) {
+        self.positions
+            .iter()
+            .copied()
+            .enumerate()
+            .min_by(|a, b| a.1.p_value.total_cmp(&b.1.p_value))
+            .expect("records have at least one position")
+    }
+
+    /// Per-test p-value below which a test fails.
+    pub fn threshold(&self) -> f64 {
+        SIGNIFICANCE / (self.positions.len() + 1) as f64
+    }
+
+    /// Whether every test passed.
+    pub fn passed(&self) -> bool {
+        self.foreign == 0
+            && self.serial_p_value >= self.threshold()
+            && self.worst_position().1.p_value >= self.threshold()
+    }
+}
+
+/// Audits the first `sample` records of `generator`'s run (all of them,
+/// if the run is shorter). Passphrases and templates are not covered, and
+/// of lengths drawn from a distribution only the shortest is.
+pub fn audit(generator: &PasswordGenerator, sample: usize) -> Result<Audit, String> {
+    if generator.passphrase().is_some() {
+        return Err("audits cover charset and raw records, not passphrases".to_string());
+    }
+    if generator
+        .encoder()
+        .is_some_and(|enc| enc.template().is_some())
+    {
+        // Positions have alphabets of their own, so one expected
+        // distribution fits none of them
+        return Err("audits cover charset and raw records, not templates".to_string());
+    }
+    let alphabet: Vec<u8> = match generator.encoder() {
+        Some(enc) => enc.alphabet().to_vec(),
+        None => (0..=255).collect(),
+    };
+    let sample = sample.min(generator.count());
+    let needed = MIN_EXPECTED * alphabet.len();
+    if sample < needed {
+        return Err(format!(
+            "an audit of {}-symbol records needs a sample of at least {} records",
+            alphabet.len(),
+            needed
+        ));
+    }
+    // Every record has the positions of the shortest length
+    let shortest = generator
+        .encoder()
+        .and_then(|enc| enc.lengths())
+        .map_or(usize::MAX, |lengths| lengths.min());
+    let records = generator.records().take(sample).map(|mut record| {
+        record.truncate(shortest);
+        record
+    });
+    Ok(audit_records(
+        records,
+        &alphabet,
+        generator.policy().is_some(),
+    ))
+}
+
+/// Tests `records`, all the same length, drawn from `alphabet`; `pooled`
+/// tests the positions against each other rather than against uniform
+/// frequencies.
+fn audit_records(records: impl Iterator<Item = Vec<u8>>, alphabet: &[u8], pooled: bool) -> Audit {
+    let mut symbol_of = [None; 256];
+    for (i, &c) in alphabet.iter().enumerate() {
+        symbol_of[c as usize] = Some(i);
+    }
+    let k = alphabet.len();
+    // counts[position * k + symbol]
+    let mut counts: Vec<u64> = Vec::new();
+    let mut sample = 0usize;
+    let mut foreign = 0usize;
+    // A policy ties a record's characters together, so with one each
+    // position is followed across records instead
+    let mut serials: Vec<Serial> = Vec::new();
+    for record in records {
+        if counts.is_empty() {
+            counts = vec![0; record.len() * k];
+            serials.resize_with(if pooled { record.len() } else { 1 }, Serial::default);
+        }
+        sample += 1;
+        for (position, &c) in record.iter().enumerate() {
+            match symbol_of[c as usize] {
+                Some(symbol) if position * k < counts.len() => {
+                    counts[position * k + symbol] += 1;
+                    let lane = position % serials.len();
+                    serials[lane].push(symbol as f64);
+                }
+                _ => foreign += 1,
+            }
+        }
+    }
+
+    let n = sample as f64;
+    let expected: Vec<f64> = if pooled {
+        let positions = (counts.len() / k) as f64;
+        (0..k)
+            .map(|s| counts.iter().skip(s).step_by(k).sum::<u64>() as f64 / positions)
+            .collect()
+    } else {
+        vec![n / k as f64; k]
+    };
+    let positions = counts
+        .chunks(k)
+        .map(|observed| {
+            let chi_squared = observed
+                .iter()
+                .zip(&expected)
+                .filter(|(_, &e)| e > 0.0)
+                .map(|(&o, &e)| (o as f64 - e).powi(2) / e)
+                .sum();
+            let degrees = expected.iter().filter(|&&e| e > 0.0).count().max(2) - 1;
+            PositionTest {
+                chi_squared,
+                degrees,
+                p_value: chi_squared_tail(chi_squared, degrees),
+            }
+        })
+        .collect();
+    let (serial_correlation, serial_p_value) = serial_test(&serials);
+    Audit {
+        records: sample,
+        symbols: k,
+        pooled,
+        positions,
+        serial_correlation,
+        serial_p_value,
+        foreign,
+    }
+}
+
+/// Running sums for Knuth's serial correlation coefficient of a sequence
+/// and its successor-shifted self, taken cyclically.
+#[derive(Default)]
+struct Serial {
+    n: f64,
+    sum: f64,
+    sum_sq: f64,
+    sum_lag: f64,
+    first: f64,
+    last: f64,
+}
+
+impl Serial {
+    fn push(&mut self, u: f64) {
+        if self.n == 0.0 {
+            self.first = u;
+        } else {
+            self.sum_lag += self.last * u;
+        }
+        self.n += 1.0;
+        self.sum += u;
+        self.sum_sq += u * u;
+        self.last = u;
+    }
+
+    fn coefficient(&self) -> f64 {
+        let lag = self.sum_lag + self.last * self.first;
+        let den = self.n * self.sum_sq - self.sum * self.sum;
+        if den > 0.0 {
+            (self.n * lag - self.sum * self.sum) / den
+        } else {
+            0.0
+        }
+    }
+
+    /// The coefficient as a standard normal deviate: about normal with
+    /// mean -1/(n-1) and standard deviation 1/sqrt(n) for an independent
+    /// sequence.
+    fn z(&self) -> f64 {
+        if self.n < 3.0 {
+            return 0.0;
+        }
+        (self.coefficient() + 1.0 / (self.n - 1.0)) * self.n.sqrt()
+    }
+}
+
+/// Mean coefficient of `serials` and the two-sided p-value of their
+/// combined deviates.
+fn serial_test(serials: &[Serial]) -> (f64, f64) {
+    if serials.is_empty() {
+        return (0.0, 1.0);
+    }
+    let m = serials.len() as f64;
+    let coefficient = serials.iter().map(Serial::coefficient).sum::<f64>() / m;
+    let z = serials.iter().map(Serial::z).sum::<f64>() / m.sqrt();
+    (coefficient, erfc(z.abs() / std::f64::consts::SQRT_2))
+}
+
+/// Upper tail of the chi-squared distribution, by the Wilson-Hilferty
+/// cube-root normal approximation.
+fn chi_squared_tail(x: f64, degrees: usize) -> f64 {
+    let k = degrees as f64;
+    let spread = 2.0 / (9.0 * k);
+    let z = ((x / k).cbrt() - (1.0 - spread)) / spread.sqrt();
+    erfc(z / std::f64::consts::SQRT_2) / 2.0
+}
+
+/// Complementary error function, to a relative error under 1.2e-7.
+fn erfc(x: f64) -> f64 {
+    let t = 1.0 / (1.0 + 0.5 * x.abs());
+    let poly = -x * x - 1.265_512_23
+        + t * (1.000_023_68
+            + t * (0.374_091_96
+                + t * (0.096_784_18
+                    + t * (-0.186_288_06
+                        + t * (0.278_868_07
+                            + t * (-1.135_203_98
+                                + t * (1.488_515_87 + t * (-0.822_152_23 + t * 0.170_872_77))))))));
+    let tail = t * poly.exp();
+    if x >= 0.0 {
+        tail
+    } else {
+        2.0 - tail
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Charset, Encoder};
+
+    #[test]
+    fn strength_and_crack_times() {
+        let generator = PasswordGenerator::builder()
+            .key([7; 16])
+            .count(1)
+            .encoder(Encoder::new(&Charset::Hex, 10))
+            .build()
+            .unwrap();
+        assert_eq!(entropy_bits(&generator), 40.0);
+        // 2^39 guesses at 10^10 a second
+        assert_eq!(format_duration(crack_seconds(40.0, 1e10)), "55.0 seconds");
+        assert_eq!(format_duration(crack_seconds(40.0, 1e2)), "174.2 years");
+        assert_eq!(format_duration(crack_seconds(128.0, 1e12)), "5.4e18 years");
+        assert_eq!(format_duration(0.5), "under a second");
+        assert!((erfc(1.0) - 0.157_299_207).abs() < 1e-7);
+        assert!((chi_squared_tail(61.0, 61) - 0.476).abs() < 0.01);
+    }
+
+    #[test]
+    fn audits_catch_biased_encodings() {
+        let generator = PasswordGenerator::builder()
+            .key([7; 16])
+            .count(20_000)
+            .encoder(Encoder::new(&Charset::Alnum, 12))
+            .build()
+            .unwrap();
+        let audit = audit(&generator, 10_000).unwrap();
+        assert_eq!(
+            (audit.records, audit.symbols, audit.positions.len()),
+            (10_000, 62, 12)
+        );
+        assert!(audit.passed(), "{:?}", audit);
+
+        // Reducing keystream bytes modulo 62 without rejection favours the
+        // first 8 symbols
+        let raw = PasswordGenerator::builder()
+            .key([7; 16])
+            .count(100_000)
+            .build()
+            .unwrap();
+        let alphabet = Charset::Alnum.alphabet();
+        let biased = raw
+            .records()
+            .map(|block| block.iter().map(|&b| alphabet[b as usize % 62]).collect());
+        let audit = audit_records(biased, &alphabet, false);
+        assert!(audit.worst_position().1.p_value < audit.threshold());
+        assert!(!audit.passed());
+
+        let runs = (0..10_000).map(|i| vec![alphabet[i / 10 % 62]; 12]);
+        assert!(!audit_records(runs, &alphabet, true).passed());
+    }
+}
@@ -0,0 +1,1273 @@
+//! Long-term archives: a corpus, its manifest and a self-verification stub
+//! in one file.
+//!
+//! An `--archive-format v1` archive is laid out as
+//!
+//! ```text
+//! MPGARCH 0x01                     8 bytes: magic and archive version
+//! shard bytes ...                  the output, exactly as written without
+//!                                  an archive
+//! index                            JSON, an [`ArchiveIndex`]
+//! index length                     8 bytes, little-endian
+//! MPGARCH 0x01                     the magic again
+//! ```
+//!
+//! The index comes last so that the output can be streamed into the archive
+//! as it is generated. It holds a corpus [`Manifest`] whose shards are byte
+//! ranges of the archive itself, each with its SHA-256, and a [`Stub`]: the
+//! generation scheme spelled out, and known-answer vectors computed under a
+//! published key. Anyone who can reproduce the vectors has understood the
+//! scheme, with or without this tool; [`verify`] checks them, the hashes
+//! and every record, for each archive version it has ever written.
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::corpus::{
+    self, Compression, HostInfo, Manifest, Record, Routes, Shard, MANIFEST_VERSION,
+};
+use crate::layout::RawLayout;
+use crate::{
+    hex, key_fingerprint, Capitalization, Charset, Cipher, CorpusReader, Encoder, Format,
+    IvStrategy, Key, LengthDist, Passphrase, PassphraseStyle, PasswordGenerator, PasswordPolicy,
+    Template, Wordlist,
+};
+
+/// Archive layout version this crate writes.
+pub const ARCHIVE_VERSION: u8 = 1;
+
+/// Archive versions [`verify`] and [`open`] understand.
+pub const SUPPORTED_VERSIONS: [u8; 1] = [1];
+
+const MAGIC: &[u8; 7] = b"MPGARCH";
+
+/// Bytes of the magic and version at either end.
+const MAGIC_LEN: u64 = 8;
+
+/// Records between the routes of a shard's routing index.
+pub const ROUTE_EVERY: usize = 1 << 16;
+
+/// The generation scheme of this crate's records, as named in the stub.
+pub const SCHEME: &str = "mass_password_gen_optimized/records-v1";
+
+/// Published key the stub's known-answer vectors are computed under.
+pub const KAT_KEY: [u8; 16] = *b"archive KAT key!";
+
+/// The published key for runs under aes256, which needs a 256-bit one.
+pub const KAT_KEY_256: [u8; 32] = *b"archive KAT key, 256 bits wide!!";
+
+/// The published key the vectors of a run under `cipher` use.
+pub fn kat_key(cipher: Cipher) -> Key {
+    match cipher {
+        Cipher::Aes256 => KAT_KEY_256.into(),
+        Cipher::Aes128 | Cipher::ChaCha20 => KAT_KEY.into(),
+    }
+}
+
+const LAYOUT: &str = "Records are cut from a keystream. aes128 is AES-128-CTR with a \
+128-bit big-endian counter whose IV holds the nonce's low 63 bits little-endian in bytes \
+8..16 and its top bit as the top bit of byte 0; chacha20 is \
+ChaCha20 with a 64-bit nonce and the key SHA-256(\"mass_password_gen chacha20 key\" || \
+key). Chunk c holds records c*chunk_size onwards. Under iv chunk-index it uses nonce c and \
+starts its keystream at 0; under continuous it uses nonce 0 and starts at the chunk's byte \
+offset in the run. Raw records are consecutive 16-byte blocks. Text records each take a \
+fixed budget of keystream bytes: bytes b below 256 - 256 % n become alphabet[b % n] in turn. \
+Passphrases read the budget as little-endian 16-bit draws, rejecting values at or above \
+65536 - 65536 % n. A passphrase style draws, after the number's position, one 16-bit value \
+per 16 words whose bits pick the words to title-case (twice at most while none are, then the \
+first), a symbol before each token after the first, and the appended digits last. Templates give each position its own alphabet (or a literal, which \
+takes no bytes) and sample it the same way from the next bytes of the budget. Under a length \
+distribution, text records first read 8 bytes of the budget as a little-endian u64 and take \
+the first length whose cumulative weight times 2^64 exceeds it (or else the last). Under a \
+policy, a record's candidate takes its usual budget; while the policy turns candidates down, \
+the next come from the subkey HKDF-SHA256(key, info \"mass_password_gen_optimized/lane/resample\") \
+of the key's width, under the same cipher, with the record's index as the nonce and each \
+budget after the last. The \
+vectors are records of this run's configuration under kat.key: reproduce them to confirm a \
+reading of the scheme.";
+
+/// The trailing index of an archive.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ArchiveIndex {
+    pub archive_version: u32,
+    /// Tool and version that wrote the archive
+    pub created_by: String,
+    /// The corpus; shards with an empty path are stored in the archive
+    pub manifest: Manifest,
+    pub stub: Stub,
+}
+
+/// How the archived records were generated, and vectors to check that
+/// understanding against.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Stub {
+    pub scheme: String,
+    /// The scheme in prose
+    pub layout: String,
+    pub cipher: String,
+    pub iv_strategy: String,
+    pub chunk_size: usize,
+    pub count: usize,
+    pub records: RecordSpec,
+    /// Whether the records carry an owner watermark; the vectors do not
+    pub watermarked: bool,
+    /// Fingerprint of the run's key, which identifies the run without
+    /// revealing the key; absent from archives written before it was kept
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_fingerprint: Option<String>,
+    pub kat: Kat,
+}
+
+/// What each record is.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum RecordSpec {
+    /// 16-byte keystream blocks
+    Raw,
+    /// `length` characters of `alphabet`
+    Text {
+        alphabet: String,
+        length: usize,
+        /// Lengths drawn per record instead, in `--length-dist` syntax;
+        /// `length` is then the longest
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        lengths: Option<String>,
+        /// In policy file syntax, settings separated by `; `
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        policy: Option<String>,
+    },
+    /// Positions of a template, in its own syntax
+    Template { template: String },
+    /// Words of a wordlist: the EFF long wordlist unless another is named
+    Passphrase {
+        words: usize,
+        separator: String,
+        capitalization: String,
+        digits: usize,
+        /// The built-in list, or the path a list was loaded from
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        wordlist: Option<String>,
+        /// SHA-256 of the list's words, each followed by a newline
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        wordlist_sha256: Option<String>,
+        /// The words of a list that is not built in, so the stub still
+        /// describes the run
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        wordlist_words: Option<Vec<String>>,
+        /// Extras in `--passphrase-style` syntax
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        style: Option<String>,
+    },
+}
+
+/// Known-answer vectors.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Kat {
+    /// The key, as 32 hex characters
+    pub key: String,
+    pub vectors: Vec<Vector>,
+}
+
+/// One record of the run under the known-answer key.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Vector {
+    pub index: usize,
+    /// The record's native bytes, as hex
+    pub record: String,
+}
+
+impl Stub {
+    /// Describes `generator`'s run.
+    pub fn new(generator: &PasswordGenerator) -> Self {
+        let records = match (generator.encoder(), generator.passphrase()) {
+            (_, Some(pass)) => RecordSpec::Passphrase {
+                words: pass.words(),
+                separator: pass.separator().to_string(),
+                capitalization: pass.capitalization().to_string(),
+                digits: pass.digits(),
+                wordlist: Some(pass.wordlist().name().to_string()),
+                wordlist_sha256: Some(pass.wordlist().sha256().to_string()),
+                wordlist_words: (!pass.wordlist().is_built_in())
+                    .then(|| pass.wordlist().words().to_vec()),
+                style: (!pass.style().is_plain()).then(|| pass.style().to_string()),
+            },
+            (Some(enc), None) => match enc.template() {
+                Some(template) => RecordSpec::Template {
+                    template: template.to_string(),
+                },
+                None => RecordSpec::Text {
+                    alphabet: String::from_utf8_lossy(enc.alphabet()).into_owned(),
+                    length: enc.length(),
+                    lengths: enc.lengths().map(|l| l.to_string()),
+                    policy: generator.policy().map(|p| p.to_string()),
+                },
+            },
+            (None, None) => RecordSpec::Raw,
+        };
+        // The watermark key is not published, so the vectors go without, and
+        // they are no records of the run's reference
+        let kat_key = kat_key(generator.cipher());
+        let kat = PasswordGenerator {
+            key: kat_key,
+            watermark: None,
+            reference: None,
+            resampled: Default::default(),
+            ..generator.clone()
+        };
+        let count = generator.count();
+        let last_chunk = (generator.num_chunks() - 1) * generator.chunk_size();
+        let mut indices = vec![0, 1, 2, last_chunk, count - 1];
+        indices.retain(|&i| i < count);
+        indices.sort_unstable();
+        indices.dedup();
+        Stub {
+            scheme: SCHEME.to_string(),
+            layout: LAYOUT.to_string(),
+            cipher: generator.cipher().to_string(),
+            iv_strategy: generator.iv_strategy().to_string(),
+            chunk_size: generator.chunk_size(),
+            count,
+            records,
+            watermarked: generator.watermark.is_some(),
+            key_fingerprint: Some(key_fingerprint(&generator.key)),
+            kat: Kat {
+                key: hex(&kat_key),
+                vectors: indices
+                    .into_iter()
+                    .map(|index| Vector {
+                        index,
+                        record: hex(&record_at(&kat, index)),
+                    })
+                    .collect(),
+            },
+        }
+    }
+
+    /// The described run under `key`, without any watermark.
+    pub fn generator(&self, key: Key) -> Result<PasswordGenerator, String> {
+        let cipher = Cipher::parse(&self.cipher)?;
+        let iv_strategy = IvStrategy::ALL
+            .into_iter()
+            .find(|s| s.to_string() == self.iv_strategy)
+            .ok_or_else(|| format!("unknown IV scheme `{}`", self.iv_strategy))?;
+        let mut builder = PasswordGenerator::builder()
+            .key(key)
+            .cipher(cipher)
+            .iv_strategy(iv_strategy)
+            .chunk_size(self.chunk_size)
+            .count(self.count);
+        match &self.records {
+            RecordSpec::Raw => {}
+            RecordSpec::Text {
+                alphabet,
+                length,
+                lengths,
+                policy,
+            } => {
+                let charset = Charset::Custom(alphabet.as_bytes().to_vec());
+                builder = builder.encoder(match lengths {
+                    Some(lengths) => Encoder::with_lengths(&charset, LengthDist::parse(lengths)?),
+                    None => Encoder::new(&charset, *length),
+                });
+                if let Some(policy) = policy {
+                    builder = builder.policy(PasswordPolicy::parse(&policy.replace("; ", "\n"))?);
+                }
+            }
+            RecordSpec::Template { template } => {
+                builder = builder.encoder(Encoder::from_template(&Template::parse(template)?));
+            }
+            RecordSpec::Passphrase {
+                words,
+                separator,
+                capitalization,
+                digits,
+                wordlist,
+                wordlist_sha256,
+                wordlist_words,
+                style,
+            } => {
+                let capitalization = Capitalization::parse(capitalization)?;
+                let list = match (wordlist.as_deref(), wordlist_words) {
+                    (None, _) => Wordlist::eff(),
+                    (Some(name), Some(words)) => Wordlist::from_words(name, words)?,
+                    (Some(name), None) => Wordlist::parse(name)?,
+                };
+                if wordlist_sha256
+                    .as_ref()
+                    .is_some_and(|sha256| sha256 != list.sha256())
+                {
+                    return Err(format!(
+                        "the wordlist {} does not have the recorded SHA-256",
+                        list.name()
+                    ));
+                }
+                let mut passphrase =
+                    Passphrase::with_wordlist(list, *words, separator, capitalization, *digits)?;
+                if let Some(style) = style {
+                    passphrase = passphrase.styled(PassphraseStyle::parse(style)?)?;
+                }
+                builder = builder.passphrase(passphrase);
+            }
+        }
+        builder.build().map_err(|e| e.to_string())
+    }
+}
+
+/// Record `index` of `generator`'s run.
+fn record_at(generator: &PasswordGenerator, index: usize) -> Vec<u8> {
+    let chunk = generator.chunk(index / generator.chunk_size());
+    let (nth, mut i) = (index % generator.chunk_size(), 0);
+    let mut found = Vec::new();
+    generator.for_each_record(&chunk.data, |record| {
+        if i == nth {
+            found = record.to_vec();
+        }
+        i += 1;
+    });
+    found
+}
+
+fn magic(version: u8) -> [u8; MAGIC_LEN as usize] {
+    let mut magic = [version; MAGIC_LEN as usize];
+    magic[..MAGIC.len()].copy_from_slice(MAGIC);
+    magic
+}
+
+/// Streams a run's output into an archive; [`finish`](Self::finish) writes
+/// the index.
+pub struct ArchiveWriter<W: Write> {
+    out: W,
+    hasher: Sha256,
+    bytes: u64,
+    host: Option<HostInfo>,
+}
+
+impl<W: Write> ArchiveWriter<W> {
+    /// Starts an archive in `out`.
+    pub fn new(mut out: W) -> io::Result<Self> {
+        out.write_all(&magic(ARCHIVE_VERSION))?;
+        Ok(ArchiveWriter {
+            out,
+            hasher: Sha256::new(),
+            bytes: 0,
+            host: None,
+        })
+    }
+
+    /// Records `host` in the archive's manifest.
+    pub fn with_host(mut self, host: HostInfo) -> Self {
+        self.host = Some(host);
+        self
+    }
+
+    /// Ends the archive of `generator`'s run, written in `format` with
+    /// `compression`, and returns its index.
+    pub fn finish(
+        mut self,
+        generator: &PasswordGenerator,
+        format: Format,
+        compression: Option<Compression>,
+    ) -> io::Result<ArchiveIndex> {
+        let index = ArchiveIndex {
+            archive_version: ARCHIVE_VERSION as u32,
+            created_by: format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+            manifest: Manifest {
+                version: MANIFEST_VERSION,
+                format,
+                binary: generator.is_binary(),
+                compression,
+                shards: vec![Shard {
+                    path: Default::default(),
+                    first: 0,
+                    count: Some(generator.count()),
+                    offset: MAGIC_LEN,
+                    length: Some(self.bytes),
+                    sha256: Some(hex(&self.hasher.finalize_reset())),
+                    routes: None,
+                    encrypted_to: None,
+                }],
+                parameters: None,
+                run_id: None,
+                host: self.host.take(),
+                layout: None,
+                seal: None,
+            },
+            stub: Stub::new(generator),
+        };
+        let json = serde_json::to_vec_pretty(&index).map_err(io::Error::other)?;
+        self.out.write_all(&json)?;
+        self.out.write_all(&(json.len() as u64).to_le_bytes())?;
+        self.out.write_all(&magic(ARCHIVE_VERSION))?;
+        self.out.flush()?;
+        Ok(index)
+    }
+}
+
+impl<W: Write> Write for ArchiveWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.out.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.bytes += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// The manifest of `generator`'s run written in `format` across `shards`,
+/// one file of `shard_size` records each (the last possibly shorter) under
+/// `compression` if given, with paths relative to `dir`, its raw records
+/// padded in `layout` if given. Every shard is hashed, and the run's stub
+/// is kept as the manifest's parameters, so that [`verify`] can check the
+/// shards as it does an archive.
+pub fn shard_manifest(
+    generator: &PasswordGenerator,
+    format: Format,
+    layout: Option<&RawLayout>,
+    shard_size: usize,
+    compression: Option<&Compression>,
+    dir: &Path,
+    shards: &[PathBuf],
+) -> io::Result<Manifest> {
+    let compressed = compression.is_some();
+    let shards = shards
+        .par_iter()
+        .enumerate()
+        .map(|(n, path)| shard_entry(generator, format, shard_size, compressed, dir, n, path))
+        .collect::<io::Result<_>>()?;
+    let stub = Stub::new(generator);
+    Ok(Manifest {
+        version: MANIFEST_VERSION,
+        format,
+        binary: generator.is_binary(),
+        compression: compression.cloned(),
+        shards,
+        run_id: Some(address(&stub, format, layout, shard_size, compression)),
+        parameters: Some(stub),
+        host: None,
+        layout: layout.cloned(),
+        seal: None,
+    })
+}
+
+/// The content address of `generator`'s run written in `format`, in
+/// `layout` if given, in shards of `shard_size` records under
+/// `compression` if given: the SHA-256 of everything that decides the
+/// shards' records, its parameters and key fingerprint included. Runs with
+/// the same ID write the same records to the same shards.
+pub fn run_id(
+    generator: &PasswordGenerator,
+    format: Format,
+    layout: Option<&RawLayout>,
+    shard_size: usize,
+    compression: Option<&Compression>,
+) -> String {
+    address(
+        &Stub::new(generator),
+        format,
+        layout,
+        shard_size,
+        compression,
+    )
+}
+
+fn address(
+    stub: &Stub,
+    format: Format,
+    layout: Option<&RawLayout>,
+    shard_size: usize,
+    compression: Option<&Compression>,
+) -> String {
+    let mut config = serde_json::json!({
+        "parameters": stub,
+        "format": format.to_string(),
+        "shard_size": shard_size,
+    });
+    // Unpadded and uncompressed runs keep the IDs they had before either
+    if let Some(layout) = layout {
+        config["layout"] = serde_json::json!(layout);
+    }
+    if let Some(compression) = compression {
+        config["compression"] = serde_json::json!(compression);
+    }
+    hex(&Sha256::digest(config.to_string()))
+}
+
+/// The manifest entry of shard `n`, at `path` relative to `dir`, of a run
+/// written as [`shard_manifest`] describes, hashed as it is on disk, and
+/// routed unless `compressed`: compressed bytes have no line offsets.
+pub fn shard_entry(
+    generator: &PasswordGenerator,
+    format: Format,
+    shard_size: usize,
+    compressed: bool,
+    dir: &Path,
+    n: usize,
+    path: &Path,
+) -> io::Result<Shard> {
+    let first = n * shard_size;
+    let mut shard = Shard {
+        path: path.to_path_buf(),
+        first,
+        count: Some(shard_size.min(generator.count() - first)),
+        offset: 0,
+        length: None,
+        sha256: None,
+        routes: None,
+        encrypted_to: None,
+    };
+    let header = routed(format, generator.is_binary()).filter(|_| !compressed);
+    let (sha256, routes) = shard_digest(&dir.join(path), &shard, header)?;
+    (shard.sha256, shard.routes) = (Some(sha256), routes);
+    Ok(shard)
+}
+
+/// Whether the file at `path` starts like an archive.
+pub fn is_archive(path: &Path) -> io::Result<bool> {
+    let mut head = [0; MAGIC.len()];
+    match File::open(path)?.read_exact(&mut head) {
+        Ok(()) => Ok(&head == MAGIC),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Reads the index of the archive at `path`, of any supported version.
+pub fn read_index(path: &Path) -> io::Result<ArchiveIndex> {
+    let mut file = File::open(path)?;
+    let mut head = [0; MAGIC_LEN as usize];
+    file.read_exact(&mut head)?;
+    let version = head[MAGIC.len()];
+    if &head[..MAGIC.len()] != MAGIC {
+        return Err(invalid("not an archive".to_string()));
+    }
+    if !SUPPORTED_VERSIONS.contains(&version) {
+        return Err(invalid(format!(
+            "archive version {} is not supported (this build reads {:?})",
+            version, SUPPORTED_VERSIONS
+        )));
+    }
+    // Every version so far ends in the index, its length and the magic
+    let mut tail = [0; 8 + MAGIC_LEN as usize];
+    let end = file.seek(SeekFrom::End(-(tail.len() as i64)))?;
+    file.read_exact(&mut tail)?;
+    if tail[8..] != head {
+        return Err(invalid("the archive is truncated".to_string()));
+    }
+    let len = u64::from_le_bytes(tail[..8].try_into().unwrap());
+    let start = end
+        .checked_sub(len)
+        .filter(|&start| start >= MAGIC_LEN)
+        .ok_or_else(|| invalid("the archive's index length is wrong".to_string()))?;
+    file.seek(SeekFrom::Start(start))?;
+    let mut json = vec![0; len as usize];
+    file.read_exact(&mut json)?;
+    let index: ArchiveIndex = match version {
+        1 => serde_json::from_slice(&json).map_err(|e| invalid(e.to_string()))?,
+        _ => unreachable!("checked above"),
+    };
+    if index.archive_version != version as u32 {
+        return Err(invalid(format!(
+            "the index says version {}, the header {}",
+            index.archive_version, version
+        )));
+    }
+    Ok(index)
+}
+
+/// Opens the corpus stored in the archive at `path`.
+pub fn open(path: &Path) -> io::Result<CorpusReader> {
+    let mut manifest = read_index(path)?.manifest;
+    let name = path.file_name().unwrap_or_default();
+    for shard in &mut manifest.shards {
+        if shard.path.as_os_str().is_empty() {
+            shard.path = name.into();
+        }
+    }
+    let base = path.parent().unwrap_or(Path::new("")).to_path_buf();
+    CorpusReader::from_manifest(manifest, base)
+}
+
+/// What [`verify`] found.
+#[derive(Clone, Debug)]
+pub struct Verification {
+    pub manifest: Manifest,
+    pub stub: Stub,
+    /// For an archive, its version and what wrote it
+    pub archive_version: Option<u32>,
+    pub created_by: Option<String>,
+    /// Shards whose hash matched
+    pub intact_shards: usize,
+    /// Known-answer vectors this build reproduced
+    pub vectors_passed: usize,
+    /// Records read back
+    pub records: usize,
+    /// Everything that failed; empty for a sound archive
+    pub problems: Vec<String>,
+}
+
+/// The manifest and stub of the archive at `path`, or of the corpus whose
+/// manifest, with parameters, is at `path`; the manifest without its
+/// parameters.
+pub fn read_run(path: &Path) -> io::Result<(Manifest, Stub)> {
+    read_described(path).map(|(manifest, stub, _)| (manifest, stub))
+}
+
+/// A run's manifest and stub, with an archive's version and what wrote it.
+type Described = (Manifest, Stub, Option<(u32, String)>);
+
+/// [`read_run`], with an archive's version and what wrote it.
+fn read_described(path: &Path) -> io::Result<Described> {
+    if is_archive(path)? {
+        let index = read_index(path)?;
+        let written = Some((index.archive_version, index.created_by));
+        return Ok((index.manifest, index.stub, written));
+    }
+    let mut manifest = Manifest::load(path)?;
+    let stub = manifest.parameters.take().ok_or_else(|| {
+        invalid(format!(
+            "{} records no generation parameters to verify against",
+            path.display()
+        ))
+    })?;
+    Ok((manifest, stub, None))
+}
+
+/// Checks the archive at `path`, or the corpus whose manifest, with
+/// parameters, is at `path`: every shard against its hash, the stub's
+/// vectors against this build's generator, and every record against the
+/// manifest's framing and count. Only an unreadable index is an error;
+/// everything else is reported.
+pub fn verify(path: &Path) -> io::Result<Verification> {
+    let (manifest, stub, written) = read_described(path)?;
+    let (archive_version, created_by) = written.unzip();
+    let mut problems = Vec::new();
+    let mut intact_shards = 0;
+    for n in 0..manifest.shards.len() {
+        match check_shard(path, &manifest, n) {
+            Ok(()) => intact_shards += 1,
+            Err(problem) => problems.push(problem),
+        }
+    }
+
+    let mut vectors_passed = 0;
+    if stub.scheme != SCHEME {
+        problems.push(format!("unknown scheme `{}`", stub.scheme));
+    } else {
+        match Cipher::parse(&stub.cipher).and_then(|c| stub.generator(kat_key(c))) {
+            Ok(generator) => {
+                for vector in &stub.kat.vectors {
+                    if vector.index < generator.count()
+                        && hex(&record_at(&generator, vector.index)) == vector.record
+                    {
+                        vectors_passed += 1;
+                    } else {
+                        problems.push(format!("vector {} not reproduced", vector.index));
+                    }
+                }
+            }
+            Err(e) => problems.push(format!("the stub does not describe a run: {}", e)),
+        }
+    }
+
+    let mut records = 0;
+    match CorpusReader::open(path) {
+        Ok(reader) => {
+            for record in reader.records() {
+                match record {
+                    Ok(_) => records += 1,
+                    Err(e) => {
+                        problems.push(e.to_string());
+                        break;
+                    }
+                }
+            }
+        }
+        Err(e) => problems.push(e.to_string()),
+    }
+    if records != stub.count {
+        problems.push(format!(
+            "{} records read back, {} expected",
+            records, stub.count
+        ));
+    }
+    Ok(Verification {
+        manifest,
+        stub,
+        archive_version,
+        created_by,
+        intact_shards,
+        vectors_passed,
+        records,
+        problems,
+    })
+}
+
+/// Checks shard `n` of the corpus at `path`, read as `manifest`, against
+/// its hash and routing index.
+fn check_shard(path: &Path, manifest: &Manifest, n: usize) -> Result<(), String> {
+    let shard = &manifest.shards[n];
+    let file = match shard.path.as_os_str().is_empty() {
+        true => path.to_path_buf(),
+        false => path.parent().unwrap_or(Path::new("")).join(&shard.path),
+    };
+    let header = shard
+        .routes
+        .as_ref()
+        .and_then(|_| routed(manifest.format, manifest.binary));
+    match (shard_digest(&file, shard, header), &shard.sha256) {
+        (Ok((_, routes)), Some(_)) if routes != shard.routes => {
+            Err(format!("shard {}: routing index does not match", n))
+        }
+        (Ok((actual, _)), Some(expected)) if actual == *expected => Ok(()),
+        (Ok(_), Some(_)) => Err(format!("shard {}: SHA-256 mismatch", n)),
+        (Ok(_), None) => Err(format!("shard {}: no hash recorded", n)),
+        (Err(e), _) => Err(format!("shard {}: {}", n, shard.missing(&file, e))),
+    }
+}
+
+/// What [`verify_record`] found.
+#[derive(Clone, Debug)]
+pub struct RecordCheck {
+    pub manifest: Manifest,
+    pub stub: Stub,
+    /// The shard holding the record
+    pub shard: usize,
+    /// Whether its hash and routing index matched
+    pub shard_intact: bool,
+    /// The record, read back through the shard's routing index
+    pub record: Option<Record>,
+    /// Everything that failed; empty for a sound record
+    pub problems: Vec<String>,
+}
+
+/// Checks record `index` of the corpus at `path`, as [`verify`] does the
+/// whole corpus but reading only what holds it: its shard against the
+/// shard's hash and routing index, and the record itself, found through
+/// the index. Only an unreadable index, or an index past the end of the
+/// run, is an error; everything else is reported.
+pub fn verify_record(path: &Path, index: usize) -> io::Result<RecordCheck> {
+    let (manifest, stub, _) = read_described(path)?;
+    let shard = manifest
+        .shards
+        .iter()
+        .position(|shard| shard.count.is_none_or(|count| index < shard.first + count))
+        .filter(|_| index < stub.count)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("the run has {} records, not record {}", stub.count, index),
+            )
+        })?;
+    let mut problems = Vec::new();
+    let shard_intact = check_shard(path, &manifest, shard)
+        .map_err(|problem| problems.push(problem))
+        .is_ok();
+    let record = match CorpusReader::open(path).map(|reader| reader.range(index..index + 1).next())
+    {
+        Ok(Some(Ok(record))) => Some(record),
+        Ok(None) => {
+            problems.push(format!("record {} is missing from its shard", index));
+            None
+        }
+        Ok(Some(Err(e))) | Err(e) => {
+            problems.push(e.to_string());
+            None
+        }
+    };
+    Ok(RecordCheck {
+        manifest,
+        stub,
+        shard,
+        shard_intact,
+        record,
+        problems,
+    })
+}
+
+/// What [`rederive`] found.
+#[derive(Clone, Debug)]
+pub struct Rederivation {
+    /// Records read back that the key makes again
+    pub records: usize,
+    /// Everything that failed; empty when the key makes the whole corpus
+    pub problems: Vec<String>,
+}
+
+/// Most records [`rederive`] names that the key does not make.
+const MISMATCHES_SHOWN: usize = 10;
+
+/// Generates the run `stub` describes again under `key`, the run's own, and
+/// compares it record by record with the corpus at `path` (an archive or a
+/// manifest): the check that the corpus is what the key makes, for someone
+/// who holds the key but did not make the corpus. Only an unreadable corpus
+/// is an error; everything else is reported.
+pub fn rederive(path: &Path, stub: &Stub, key: Key) -> io::Result<Rederivation> {
+    let generator = match regenerator(stub, key) {
+        Ok(generator) => generator,
+        Err(problems) => {
+            return Ok(Rederivation {
+                records: 0,
+                problems,
+            })
+        }
+    };
+    let mut problems = Vec::new();
+    let reader = CorpusReader::open(path)?;
+    let (mut records, mut mismatches) = (0, 0);
+    let mut expected = generator.records();
+    for record in reader.records() {
+        let record = match record {
+            Ok(record) => record,
+            Err(e) => {
+                problems.push(e.to_string());
+                break;
+            }
+        };
+        match expected.next() {
+            Some(password) if password == record.password => records += 1,
+            Some(_) => {
+                mismatches += 1;
+                if mismatches <= MISMATCHES_SHOWN {
+                    problems.push(format!("record {} is not what the key makes", record.index));
+                }
+            }
+            None => {
+                problems.push(format!(
+                    "more records than the {} the run has",
+                    generator.count()
+                ));
+                break;
+            }
+        }
+    }
+    if mismatches > MISMATCHES_SHOWN {
+        problems.push(format!(
+            "and {} more records the key does not make",
+            mismatches - MISMATCHES_SHOWN
+        ));
+    }
+    Ok(Rederivation { records, problems })
+}
+
+/// The generator of the run `stub` describes under `key`, or why the key
+/// cannot make it again.
+fn regenerator(stub: &Stub, key: Key) -> Result<PasswordGenerator, Vec<String>> {
+    let mut problems = Vec::new();
+    let fingerprint = key_fingerprint(&key);
+    if stub
+        .key_fingerprint
+        .as_ref()
+        .is_some_and(|f| *f != fingerprint)
+    {
+        problems.push(format!(
+            "the key has fingerprint {}, the run's key {}",
+            fingerprint,
+            stub.key_fingerprint.as_deref().unwrap_or_default()
+        ));
+    }
+    if stub.watermarked {
+        problems
+            .push("the records carry a watermark, whose key the stub does not record".to_string());
+    }
+    match stub.generator(key) {
+        Ok(generator) if problems.is_empty() => Ok(generator),
+        Ok(_) => Err(problems),
+        Err(e) => {
+            problems.push(format!("the stub does not describe a run: {}", e));
+            Err(problems)
+        }
+    }
+}
+
+/// Generates record `record.index` of the run `stub` describes again
+/// under `key` and compares it with `record`, as [`rederive`] does every
+/// record; returns what failed.
+pub fn rederive_record(stub: &Stub, key: Key, record: &Record) -> Vec<String> {
+    match regenerator(stub, key) {
+        Ok(generator) if record.index >= generator.count() => {
+            vec![format!("the run has no record {}", record.index)]
+        }
+        Ok(generator) if record_at(&generator, record.index) != record.password => {
+            vec![format!("record {} is not what the key makes", record.index)]
+        }
+        Ok(_) => Vec::new(),
+        Err(problems) => problems,
+    }
+}
+
+/// The bytes of header before the records of a shard of `format`, if its
+/// records are lines a routing index can find; raw blocks need none.
+fn routed(format: Format, binary: bool) -> Option<u64> {
+    let lines = !(format == Format::Raw && binary);
+    lines.then(|| corpus::header(format, binary).len() as u64)
+}
+
+/// The SHA-256 of `shard` in `file` and, given the bytes of `header` its
+/// records of lines follow, its routes every [`ROUTE_EVERY`] records.
+fn shard_digest(
+    file: &Path,
+    shard: &Shard,
+    header: Option<u64>,
+) -> io::Result<(String, Option<Routes>)> {
+    let mut file = File::open(file)?;
+    file.seek(SeekFrom::Start(shard.offset))?;
+    let mut input = file.take(shard.length.unwrap_or(u64::MAX));
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 1 << 16];
+    let (mut at, mut lines, mut offsets) = (0, 0, Vec::new());
+    loop {
+        let n = match input.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        hasher.update(&buf[..n]);
+        if let Some(header) = header {
+            for (i, _) in buf[..n].iter().enumerate().filter(|(_, &b)| b == b'\n') {
+                let end = at + i as u64 + 1;
+                if end > header {
+                    lines += 1;
+                    if lines % ROUTE_EVERY == 0 {
+                        offsets.push(end);
+                    }
+                }
+            }
+        }
+        at += n as u64;
+    }
+    // No route past the last record
+    let records = shard.count.unwrap_or(lines);
+    offsets.truncate(records.saturating_sub(1) / ROUTE_EVERY);
+    let routes = (!offsets.is_empty()).then_some(Routes {
+        every: ROUTE_EVERY,
+        offsets,
+    });
+    Ok((hex(&hasher.finalize()), routes))
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn archive(generator: &PasswordGenerator, format: Format, name: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("mpg_archive_{}", name));
+        let mut writer = ArchiveWriter::new(File::create(&path).unwrap())
+            .unwrap()
+            .with_host(host());
+        let sink = format.sink(generator);
+        generator
+            .write_formatted(sink.as_deref().unwrap(), &mut writer, |_, _| ())
+            .unwrap();
+        writer.finish(generator, format, None).unwrap();
+        path
+    }
+
+    fn host() -> HostInfo {
+        HostInfo {
+            cpu: "Test CPU".to_string(),
+            microcode: None,
+            cpu_features: vec!["aes".to_string()],
+            os: "Test OS".to_string(),
+            kernel: "1.0".to_string(),
+            crate_version: env!("CARGO_PKG_VERSION").to_string(),
+            git_commit: None,
+            rustc: None,
+        }
+    }
+
+    #[test]
+    fn archives_verify_and_read_back() {
+        let pass = Passphrase::new(4, " ", Capitalization::Upper, 2).unwrap();
+        let builders = [
+            PasswordGenerator::builder(),
+            PasswordGenerator::builder().encoder(Encoder::new(&Charset::AlnumSymbols, 10)),
+            PasswordGenerator::builder().passphrase(pass),
+        ];
+        for (n, builder) in builders.into_iter().enumerate() {
+            let g = builder
+                .key([9; 16])
+                .count(1000)
+                .chunk_size(300)
+                .build()
+                .unwrap();
+            let path = archive(&g, Format::Jsonl, &n.to_string());
+            let report = verify(&path).unwrap();
+            assert!(report.problems.is_empty(), "{:?}", report.problems);
+            assert_eq!(report.vectors_passed, 5);
+            assert_eq!(report.manifest.host, Some(host()));
+            let reader = CorpusReader::open(&path).unwrap();
+            let records: Vec<_> = reader.records().map(|r| r.unwrap().password).collect();
+            assert_eq!(records, g.records().collect::<Vec<_>>());
+            // The run's key makes every record again, and no other key does
+            let (_, stub) = read_run(&path).unwrap();
+            let rederived = rederive(&path, &stub, Key::from([9; 16])).unwrap();
+            assert!(rederived.problems.is_empty(), "{:?}", rederived.problems);
+            assert_eq!(rederived.records, 1000);
+            let other = rederive(&path, &stub, Key::from([8; 16])).unwrap();
+            assert!(other.problems[0].contains("fingerprint"), "{:?}", other);
+            let unchecked = Stub {
+                key_fingerprint: None,
+                ..stub
+            };
+            let other = rederive(&path, &unchecked, Key::from([8; 16])).unwrap();
+            assert_eq!(other.records, 0);
+            assert_eq!(other.problems.len(), MISMATCHES_SHOWN + 1);
+
+            // One flipped byte in the payload
+            let mut bytes = std::fs::read(&path).unwrap();
+            bytes[MAGIC_LEN as usize + 20] ^= 1;
+            std::fs::write(&path, bytes).unwrap();
+            let report = verify(&path).unwrap();
+            assert_eq!(report.intact_shards, 0);
+            assert!(!report.problems.is_empty());
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn shards_verify_like_archives() {
+        let dir = std::env::temp_dir();
+        let g = PasswordGenerator::builder()
+            .key([7; 16])
+            .count(1050)
+            .chunk_size(100)
+            .encoder(Encoder::new(&Charset::Alnum, 12))
+            .build()
+            .unwrap();
+        for format in [Format::Raw, Format::Csv] {
+            let names: Vec<PathBuf> = (0..g.num_shards(300))
+                .map(|n| format!("mpg_shard_{}_{}", format, n).into())
+                .collect();
+            assert_eq!(names.len(), 4);
+            let files: Vec<File> = names
+                .iter()
+                .map(|name| File::create(dir.join(name)).unwrap())
+                .collect();
+            let sink = format.sink(&g);
+            g.write_sharded(sink.as_deref(), &files, 300, |_, _| (), |_| ())
+                .unwrap();
+            assert!(g
+                .write_sharded(None, &files, 250, |_, _| (), |_| ())
+                .is_err());
+            let manifest = shard_manifest(&g, format, None, 300, None, &dir, &names).unwrap();
+            assert_eq!(manifest.shards[3].first, 900);
+            assert_eq!(manifest.shards[3].count, Some(150));
+            // The run is addressed by what decides its bytes
+            assert_eq!(manifest.run_id, Some(run_id(&g, format, None, 300, None)));
+            assert_ne!(
+                run_id(&g, format, None, 600, None),
+                run_id(&g, format, None, 300, None)
+            );
+            let path = dir.join(format!("mpg_shard_{}.json", format));
+            std::fs::write(&path, serde_json::to_vec(&manifest).unwrap()).unwrap();
+            let report = verify(&path).unwrap();
+            assert!(report.problems.is_empty(), "{:?}", report.problems);
+            assert_eq!((report.intact_shards, report.records), (4, 1050));
+            let reader = CorpusReader::open(&path).unwrap();
+            let records: Vec<_> = reader.records().map(|r| r.unwrap().password).collect();
+            assert_eq!(records, g.records().collect::<Vec<_>>());
+
+            // A shard swapped for another
+            std::fs::copy(dir.join(&names[0]), dir.join(&names[1])).unwrap();
+            let report = verify(&path).unwrap();
+            assert_eq!(report.intact_shards, 3);
+            for name in &names {
+                std::fs::remove_file(dir.join(name)).unwrap();
+            }
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn single_records_verify_through_their_shard() {
+        let dir = std::env::temp_dir();
+        let g = PasswordGenerator::builder()
+            .key([6; 16])
+            .count(1050)
+            .chunk_size(100)
+            .encoder(Encoder::new(&Charset::Alnum, 12))
+            .build()
+            .unwrap();
+        let names: Vec<PathBuf> = (0..4)
+            .map(|n| format!("mpg_record_shard_{}", n).into())
+            .collect();
+        let files: Vec<File> = names
+            .iter()
+            .map(|name| File::create(dir.join(name)).unwrap())
+            .collect();
+        let sink = Format::Csv.sink(&g);
+        g.write_sharded(sink.as_deref(), &files, 300, |_, _| (), |_| ())
+            .unwrap();
+        let manifest = shard_manifest(&g, Format::Csv, None, 300, None, &dir, &names).unwrap();
+        let path = dir.join("mpg_record_shards.json");
+        std::fs::write(&path, serde_json::to_vec(&manifest).unwrap()).unwrap();
+
+        let check = verify_record(&path, 950).unwrap();
+        assert!(check.problems.is_empty(), "{:?}", check.problems);
+        assert_eq!((check.shard, check.shard_intact), (3, true));
+        let record = check.record.unwrap();
+        assert_eq!(record.index, 950);
+        assert_eq!(Some(record.password.clone()), g.records().nth(950));
+        assert_eq!(
+            rederive_record(&check.stub, Key::from([6; 16]), &record),
+            Vec::<String>::new()
+        );
+        let other = rederive_record(&check.stub, Key::from([5; 16]), &record);
+        assert!(other[0].contains("fingerprint"), "{:?}", other);
+        let forged = Record {
+            index: 951,
+            ..record
+        };
+        assert_eq!(
+            rederive_record(&check.stub, Key::from([6; 16]), &forged),
+            ["record 951 is not what the key makes"]
+        );
+        assert_eq!(
+            verify_record(&path, 1050).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+
+        // Only the shard holding the record is checked
+        std::fs::copy(dir.join(&names[0]), dir.join(&names[1])).unwrap();
+        let check = verify_record(&path, 400).unwrap();
+        assert!(!check.shard_intact);
+        assert_eq!(check.problems[0], "shard 1: SHA-256 mismatch");
+        assert!(verify_record(&path, 950).unwrap().problems.is_empty());
+        for name in &names {
+            std::fs::remove_file(dir.join(name)).unwrap();
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[cfg(feature = "compress-gzip")]
+    #[test]
+    fn compressed_shards_verify_and_read_back() {
+        use crate::{Codec, Compressor, ShardWrite};
+        let dir = std::env::temp_dir();
+        let g = PasswordGenerator::builder()
+            .key([8; 16])
+            .count(1050)
+            .chunk_size(100)
+            .encoder(Encoder::new(&Charset::Alnum, 12))
+            .build()
+            .unwrap();
+        let names: Vec<PathBuf> = (0..4)
+            .map(|n| format!("mpg_gz_shard_{}", n).into())
+            .collect();
+        let files: Vec<_> = names
+            .iter()
+            .map(|n| File::create(dir.join(n)).ok())
+            .collect();
+        let sink = Format::Csv.sink(&g);
+        let gzip = Compressor::new(Codec::Gzip, 6);
+        let shards = ShardWrite {
+            sink: sink.as_deref(),
+            codec: Some(&gzip),
+            files: &files,
+            shard_size: 300,
+            from: 0,
+            spare: None,
+        };
+        g.write_shards(&shards, |_, _| (), |_, _| ()).unwrap();
+        let compression = Compression::Gzip;
+        let manifest =
+            shard_manifest(&g, Format::Csv, None, 300, Some(&compression), &dir, &names).unwrap();
+        assert_eq!(manifest.compression, Some(Compression::Gzip));
+        assert!(manifest.shards.iter().all(|shard| shard.routes.is_none()));
+        // Compressed shards are another run's bytes
+        assert_ne!(
+            manifest.run_id,
+            Some(run_id(&g, Format::Csv, None, 300, None))
+        );
+
+        let path = dir.join("mpg_gz_shards.json");
+        std::fs::write(&path, serde_json::to_vec(&manifest).unwrap()).unwrap();
+        let report = verify(&path).unwrap();
+        assert!(report.problems.is_empty(), "{:?}", report.problems);
+        assert_eq!((report.intact_shards, report.records), (4, 1050));
+        let reader = CorpusReader::open(&path).unwrap();
+        let records: Vec<_> = reader.records().map(|r| r.unwrap().password).collect();
+        assert_eq!(records, g.records().collect::<Vec<_>>());
+        for name in &names {
+            std::fs::remove_file(dir.join(name)).unwrap();
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn routes_find_records_partway() {
+        let dir = std::env::temp_dir();
+        let g = PasswordGenerator::builder()
+            .key([6; 16])
+            .count(200_000)
+            .chunk_size(1000)
+            .build()
+            .unwrap();
+        let names: Vec<PathBuf> = (0..2).map(|n| format!("mpg_routes_{}", n).into()).collect();
+        let files: Vec<File> = names
+            .iter()
+            .map(|name| File::create(dir.join(name)).unwrap())
+            .collect();
+        let sink = Format::Csv.sink(&g);
+        g.write_sharded(sink.as_deref(), &files, 150_000, |_, _| (), |_| ())
+            .unwrap();
+        let manifest = shard_manifest(&g, Format::Csv, None, 150_000, None, &dir, &names).unwrap();
+        let routes = manifest.shards[0].routes.as_ref().unwrap();
+        assert_eq!((routes.every, routes.offsets.len()), (ROUTE_EVERY, 2));
+        // Too short a shard has none
+        assert_eq!(manifest.shards[1].routes, None);
+
+        let path = dir.join("mpg_routes.json");
+        std::fs::write(&path, serde_json::to_vec(&manifest).unwrap()).unwrap();
+        let reader = CorpusReader::open(&path).unwrap();
+        for range in [
+            140_000..140_003,
+            ROUTE_EVERY - 1..ROUTE_EVERY + 1,
+            149_999..150_001,
+        ] {
+            let records: Vec<_> = reader
+                .range(range.clone())
+                .map(|r| r.unwrap().password)
+                .collect();
+            assert_eq!(
+                records,
+                g.records()
+                    .skip(range.start)
+                    .take(range.len())
+                    .collect::<Vec<_>>()
+            );
+        }
+        assert!(verify(&path).unwrap().problems.is_empty());
+        for name in &names {
+            std::fs::remove_file(dir.join(name)).unwrap();
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+
+    /// An archive written by the first release of the format must keep
+    /// verifying, whatever the current defaults.
+    #[test]
+    fn version_1_archives_still_verify() {
+        let path = Path::new(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/data/archive_v1.mpga"
+        ));
+        let report = verify(path).unwrap();
+        assert!(report.problems.is_empty(), "{:?}", report.problems);
+        assert_eq!(report.archive_version, Some(1));
+        assert_eq!(report.records, 300);
+    }
+}
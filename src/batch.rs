@@ -0,0 +1,354 @@
+//! `--run-dir` and `status`: runs launched by cron or systemd keep a status
+//! file an operator can read without attaching to them.
+//!
+//! Once a second the run rewrites `status.json` in its run directory, with
+//! its progress, rate, ETA, and the last progress its checkpoint saved,
+//! replacing the file whole. It marks the file complete when it finishes,
+//! or failed if it stops first. `status --run-dir DIR` prints it, and says
+//! so when a run that claims to be running has gone.
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::checkpoint::Checkpoint;
+use crate::progress::{self, Meter, Rate};
+use crate::{permissions, unix_now};
+
+/// The status file in a run directory.
+pub const STATUS_FILE: &str = "status.json";
+
+/// How often the status file is rewritten.
+const INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long a running run may go without an update before `status` calls
+/// it stale.
+const STALE: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum State {
+    Running,
+    Complete,
+    Failed,
+}
+
+/// What `status.json` holds. Times are Unix seconds.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Status {
+    pub pid: u32,
+    pub state: State,
+    pub started_at: u64,
+    pub updated_at: u64,
+    pub records: usize,
+    pub total: usize,
+    /// Output bytes so far, before compression
+    pub bytes: u64,
+    pub records_per_sec: f64,
+    pub eta_secs: Option<f64>,
+    pub output: Option<PathBuf>,
+    pub checkpoint: Option<Saved>,
+    pub error: Option<String>,
+}
+
+/// The last progress a checkpoint saved.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Saved {
+    pub path: PathBuf,
+    pub records: usize,
+    pub saved_at: u64,
+}
+
+/// A run's status file, kept up to date until [`finish`](Self::finish);
+/// dropped first, it is marked failed.
+pub struct StatusFile {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+struct Shared {
+    path: PathBuf,
+    meter: Arc<Meter>,
+    checkpoint: Option<PathBuf>,
+    status: Mutex<Status>,
+    done: AtomicBool,
+}
+
+impl StatusFile {
+    /// Starts keeping `dir`'s status file on the run `meter` follows, into
+    /// `output` and checkpointed to `checkpoint` if given.
+    pub fn start(
+        dir: &Path,
+        meter: Arc<Meter>,
+        output: Option<PathBuf>,
+        checkpoint: Option<PathBuf>,
+    ) -> io::Result<Self> {
+        fs::create_dir_all(dir)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", dir.display(), e)))?;
+        let now = unix_now();
+        let status = Status {
+            pid: std::process::id(),
+            state: State::Running,
+            started_at: now,
+            updated_at: now,
+            records: meter.records(),
+            total: meter.total(),
+            bytes: 0,
+            records_per_sec: 0.0,
+            eta_secs: None,
+            output,
+            checkpoint: None,
+            error: None,
+        };
+        let shared = Arc::new(Shared {
+            path: dir.join(STATUS_FILE),
+            meter,
+            checkpoint,
+            status: Mutex::new(status),
+            done: AtomicBool::new(false),
+        });
+        shared.save()?;
+        status!("Status in {}", shared.path.display());
+        let thread = {
+            let shared = Arc::clone(&shared);
+            std::thread::spawn(move || {
+                let mut rate = Rate::new(shared.meter.records());
+                while !shared.done.load(Ordering::Relaxed) {
+                    std::thread::park_timeout(INTERVAL);
+                    let records_per_sec = rate.sample(shared.meter.records());
+                    shared.update(|status| status.records_per_sec = records_per_sec);
+                    // A status that cannot be written is tried again next time
+                    let _ = shared.save();
+                }
+            })
+        };
+        Ok(StatusFile {
+            shared,
+            thread: Some(thread),
+        })
+    }
+
+    /// Marks the run complete.
+    pub fn finish(mut self) -> io::Result<()> {
+        self.stop(State::Complete, None)
+    }
+
+    fn stop(&mut self, state: State, error: Option<String>) -> io::Result<()> {
+        self.shared.done.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+        self.shared.update(|status| {
+            status.state = state;
+            status.error = error;
+            status.eta_secs = None;
+        });
+        self.shared.save()
+    }
+}
+
+impl Drop for StatusFile {
+    fn drop(&mut self) {
+        if self.thread.is_some() {
+            let why = "the run stopped before it finished; its log says why";
+            let _ = self.stop(State::Failed, Some(why.to_string()));
+        }
+    }
+}
+
+impl Shared {
+    /// Brings the status up to date with the meter and checkpoint, then
+    /// applies `change`.
+    fn update(&self, change: impl FnOnce(&mut Status)) {
+        let saved = self.checkpoint.as_deref().and_then(saved);
+        let mut status = self.status.lock().unwrap();
+        status.updated_at = unix_now();
+        status.records = self.meter.records();
+        status.bytes = self.meter.bytes();
+        if saved.is_some() {
+            status.checkpoint = saved;
+        }
+        change(&mut status);
+        let left = status.total - status.records;
+        status.eta_secs =
+            (status.records_per_sec > 0.0).then(|| left as f64 / status.records_per_sec);
+    }
+
+    /// Replaces the status file, so a reader never sees half of one.
+    fn save(&self) -> io::Result<()> {
+        let json = serde_json::to_string_pretty(&*self.status.lock().unwrap())?;
+        let mut tmp = self.path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        let mut file = permissions::create(&tmp, permissions::DEFAULT_MODE)?;
+        file.write_all(json.as_bytes())?;
+        file.write_all(b"\n")?;
+        fs::rename(&tmp, &self.path)
+    }
+}
+
+/// What the checkpoint at `path` last saved, if it is there.
+fn saved(path: &Path) -> Option<Saved> {
+    let checkpoint = Checkpoint::load(path).ok()?;
+    let modified = fs::metadata(path).and_then(|meta| meta.modified()).ok()?;
+    let progress = checkpoint.progress.chunks * checkpoint.chunk_size;
+    Some(Saved {
+        path: path.to_path_buf(),
+        records: progress.min(checkpoint.count),
+        saved_at: unix_secs(modified),
+    })
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+/// Reads the status file of the run in `dir`.
+pub fn read(dir: &Path) -> io::Result<Status> {
+    let path = dir.join(STATUS_FILE);
+    let text = fs::read_to_string(&path)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+    serde_json::from_str(&text).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: not a status file ({})", path.display(), e),
+        )
+    })
+}
+
+/// Why a status that says it is running cannot be believed, if it cannot.
+pub fn gone(status: &Status, now: u64) -> Option<String> {
+    if status.state != State::Running {
+        return None;
+    }
+    if !alive(status.pid) {
+        return Some(format!(
+            "process {} is gone; it stopped without a final status",
+            status.pid
+        ));
+    }
+    let quiet = now.saturating_sub(status.updated_at);
+    (quiet > STALE.as_secs()).then(|| format!("no update for {}s; the run may be hung", quiet))
+}
+
+/// Whether process `pid` exists.
+fn alive(pid: u32) -> bool {
+    #[cfg(unix)]
+    {
+        // SAFETY: signal 0 only checks that the process exists and may be
+        // signalled
+        let sent = unsafe { libc::kill(pid as libc::pid_t, 0) };
+        sent == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = pid;
+        true
+    }
+}
+
+/// Prints `status`, read in `dir`, for an operator.
+pub fn show(dir: &Path, status: &Status) {
+    let now = unix_now();
+    let ago = |at: u64| now.saturating_sub(at);
+    let state = match status.state {
+        State::Running => "running",
+        State::Complete => "complete",
+        State::Failed => "failed",
+    };
+    println!(
+        "Run in {}: {} (pid {}), started {}s ago, updated {}s ago",
+        dir.display(),
+        state,
+        status.pid,
+        ago(status.started_at),
+        ago(status.updated_at)
+    );
+    if let Some(why) = gone(status, now) {
+        println!("  WARNING: {}", why);
+    }
+    println!(
+        "  Progress:   {:5.1}%  {}/{} records, {:.2} GiB",
+        status.records as f64 * 100.0 / status.total.max(1) as f64,
+        status.records,
+        status.total,
+        status.bytes as f64 / (1024.0 * 1024.0 * 1024.0)
+    );
+    if status.state == State::Running {
+        let eta = status
+            .eta_secs
+            .map_or("--:--:--".to_string(), progress::clock);
+        println!(
+            "  Rate:       {:.1} M/s, ETA {}",
+            status.records_per_sec / 1e6,
+            eta
+        );
+    }
+    if let Some(output) = &status.output {
+        println!("  Output:     {}", output.display());
+    }
+    if let Some(saved) = &status.checkpoint {
+        println!(
+            "  Checkpoint: {}, {} records saved {}s ago",
+            saved.path.display(),
+            saved.records,
+            ago(saved.saved_at)
+        );
+    }
+    if let Some(error) = &status.error {
+        println!("  Error:      {}", error);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_status_file_follows_the_run() {
+        let dir = std::env::temp_dir().join(format!("mpg_batch_{}", std::process::id()));
+        let meter = Arc::new(Meter::new(1000, 100));
+        let file = StatusFile::start(&dir, Arc::clone(&meter), None, None).unwrap();
+        let status = read(&dir).unwrap();
+        assert_eq!(
+            (status.state, status.records, status.total),
+            (State::Running, 100, 1000)
+        );
+        assert_eq!(status.pid, std::process::id());
+        assert_eq!(gone(&status, status.updated_at), None);
+        assert!(gone(&status, status.updated_at + 60)
+            .unwrap()
+            .contains("hung"));
+
+        meter.chunk_done(900, 9000);
+        file.finish().unwrap();
+        let status = read(&dir).unwrap();
+        assert_eq!(
+            (status.state, status.records, status.bytes),
+            (State::Complete, 1000, 9000)
+        );
+        assert_eq!(status.error, None);
+
+        // A run that stops early says so
+        let file = StatusFile::start(&dir, Arc::new(Meter::new(10, 0)), None, None).unwrap();
+        drop(file);
+        let status = read(&dir).unwrap();
+        assert_eq!(status.state, State::Failed);
+        assert!(status.error.is_some());
+        // As does one whose process is gone
+        let dead = Status {
+            pid: u32::MAX >> 1,
+            state: State::Running,
+            ..status
+        };
+        assert!(gone(&dead, dead.updated_at).unwrap().contains("gone"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,560 @@
+//! `bench`: timed runs at several thread counts.
+//!
+//! Every iteration generates (and, with a `--format`, serializes) the whole
+//! run into per-worker buffers that are reused and never written anywhere,
+//! so everything but the I/O is measured. Each thread count gets its own
+//! rayon pool, warm-up iterations first, then measured ones. Its iterations
+//! together, from the pool's start, are split into a cold start and a
+//! steady state as a run's are (see [`throughput`](crate::throughput)).
+//!
+//! `--naive-baseline` also times the obvious way to do the same job: one
+//! thread asking `rand::thread_rng` for every character (or word, or
+//! 16-byte block) of every password, each collected into its own `Vec`.
+//!
+//! `--compare baseline.json` reads an earlier `--json` report and shows the
+//! change in throughput at each thread count both runs measured;
+//! `--max-regression PCT` makes a drop of more than PCT percent an error,
+//! for CI. Per-stage microbenchmarks are in `benches/pipeline.rs`.
+
+use mass_password_gen_optimized::{OutputSink, PasswordGenerator};
+use rand::seq::SliceRandom;
+use rand::Rng;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+use crate::throughput::{Span, Split};
+
+const GIB: f64 = 1024.0 * 1024.0 * 1024.0;
+
+/// Most records the naive baseline generates per iteration; it is slow
+/// enough that a sample gives its rate.
+pub const NAIVE_MAX_RECORDS: usize = 10_000_000;
+
+/// A whole benchmark, as written by `--json`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Report {
+    pub records: usize,
+    pub encoding: String,
+    pub cipher: String,
+    pub chunk_size: usize,
+    pub warmup: usize,
+    /// Seconds from each pool's start counted as its cold start
+    #[serde(default)]
+    pub warmup_seconds: f64,
+    pub iterations: usize,
+    pub results: Vec<Measurement>,
+    /// With `--naive-baseline`
+    pub naive: Option<Baseline>,
+}
+
+/// The naive generator's results, generating only (no `--format`).
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Baseline {
+    pub records: usize,
+    /// Median wall time of the measured iterations
+    pub seconds: f64,
+    pub records_per_sec: f64,
+}
+
+/// Results at one thread count.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Measurement {
+    pub threads: usize,
+    /// Median wall time of the measured iterations
+    pub seconds: f64,
+    pub records_per_sec: f64,
+    pub keystream_gib_per_sec: f64,
+    pub output_gib_per_sec: f64,
+    /// Records per second of each worker, over all measured iterations
+    pub per_thread: Vec<f64>,
+    pub chunk_ms: Percentiles,
+    /// Records per second over the warm-up seconds, over the rest, and in
+    /// the best whole second, across all iterations from the pool's start
+    #[serde(default)]
+    pub cold_records_per_sec: Option<f64>,
+    #[serde(default)]
+    pub steady_records_per_sec: Option<f64>,
+    #[serde(default)]
+    pub peak_records_per_sec: Option<f64>,
+    /// Throughput relative to the first thread count measured
+    pub speedup: f64,
+    /// Speedup per added thread: 1.0 is perfect scaling
+    pub efficiency: f64,
+}
+
+/// Latencies, in milliseconds.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct Percentiles {
+    pub p50: f64,
+    pub p90: f64,
+    pub p99: f64,
+    pub max: f64,
+}
+
+impl Percentiles {
+    /// Nearest-rank percentiles of `samples`, of which there is at least
+    /// one.
+    pub fn of(samples: &mut [Duration]) -> Self {
+        samples.sort_unstable();
+        let rank = |p: f64| {
+            let i = ((p * samples.len() as f64).ceil() as usize).clamp(1, samples.len());
+            samples[i - 1].as_secs_f64() * 1e3
+        };
+        Percentiles {
+            p50: rank(0.5),
+            p90: rank(0.9),
+            p99: rank(0.99),
+            max: rank(1.0),
+        }
+    }
+}
+
+/// 1, 2, 4, ... below `cpus`, then `cpus` itself.
+pub fn default_thread_counts(cpus: usize) -> Vec<usize> {
+    let mut counts: Vec<usize> = (0..).map(|i| 1 << i).take_while(|&n| n < cpus).collect();
+    counts.push(cpus.max(1));
+    counts
+}
+
+/// Runs the benchmark at each of `thread_counts`, the first `warmup_time`
+/// of each the cold start.
+pub fn run(
+    generator: &PasswordGenerator,
+    sink: Option<&dyn OutputSink>,
+    thread_counts: &[usize],
+    warmup: usize,
+    iterations: usize,
+    warmup_time: Duration,
+) -> Result<Vec<Measurement>, rayon::ThreadPoolBuildError> {
+    let mut results: Vec<Measurement> = Vec::new();
+    for &threads in thread_counts {
+        let mut m = measure(generator, sink, threads, warmup, iterations, warmup_time)?;
+        if let Some(base) = results.first() {
+            m.speedup = m.records_per_sec / base.records_per_sec;
+            m.efficiency = m.speedup * base.threads as f64 / threads as f64;
+        }
+        results.push(m);
+    }
+    Ok(results)
+}
+
+fn measure(
+    generator: &PasswordGenerator,
+    sink: Option<&dyn OutputSink>,
+    threads: usize,
+    warmup: usize,
+    iterations: usize,
+    warmup_time: Duration,
+) -> Result<Measurement, rayon::ThreadPoolBuildError> {
+    let epoch = Instant::now();
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build()?;
+    let mut spans = Vec::new();
+    for _ in 0..warmup {
+        let (_, chunks) = iteration(generator, sink, &pool, epoch);
+        spans.extend(chunks.iter().map(|chunk| chunk.span));
+    }
+    let mut walls = Vec::with_capacity(iterations);
+    let mut latencies = Vec::new();
+    let mut per_thread = vec![0usize; threads];
+    let mut output = 0;
+    for _ in 0..iterations {
+        let (wall, chunks) = iteration(generator, sink, &pool, epoch);
+        walls.push(wall);
+        output = 0;
+        for chunk in chunks {
+            spans.push(chunk.span);
+            latencies.push(chunk.span.end - chunk.span.start);
+            per_thread[chunk.thread] += chunk.span.records;
+            output += chunk.bytes;
+        }
+    }
+    let total: Duration = walls.iter().sum();
+    let split = Split::of(&spans, warmup_time, epoch.elapsed());
+    walls.sort_unstable();
+    let seconds = walls[walls.len() / 2].as_secs_f64();
+    let keystream: usize = (0..generator.num_chunks())
+        .map(|i| generator.keystream_len(i))
+        .sum();
+    Ok(Measurement {
+        threads,
+        seconds,
+        records_per_sec: generator.count() as f64 / seconds,
+        keystream_gib_per_sec: keystream as f64 / GIB / seconds,
+        output_gib_per_sec: output as f64 / GIB / seconds,
+        per_thread: per_thread
+            .iter()
+            .map(|&records| records as f64 / total.as_secs_f64())
+            .collect(),
+        chunk_ms: Percentiles::of(&mut latencies),
+        cold_records_per_sec: split.cold,
+        steady_records_per_sec: split.steady,
+        peak_records_per_sec: split.peak,
+        speedup: 1.0,
+        efficiency: 1.0,
+    })
+}
+
+/// How one chunk went.
+struct ChunkTiming {
+    /// The worker that made it
+    thread: usize,
+    /// When, from the pool's start
+    span: Span,
+    /// Output bytes, after serialization
+    bytes: usize,
+}
+
+/// Generates the whole run once on `pool` and returns the wall time, with
+/// each chunk timed from `epoch`.
+fn iteration(
+    generator: &PasswordGenerator,
+    sink: Option<&dyn OutputSink>,
+    pool: &rayon::ThreadPool,
+    epoch: Instant,
+) -> (Duration, Vec<ChunkTiming>) {
+    let width = generator.record_width();
+    let start = Instant::now();
+    let chunks = pool.install(|| {
+        (0..generator.num_chunks())
+            .into_par_iter()
+            .map_init(
+                || (Vec::new(), Vec::new(), Vec::new()),
+                |(scratch, out, serialized), chunk_idx| {
+                    let begun = epoch.elapsed();
+                    let records = generator.chunk_len(chunk_idx);
+                    out.clear();
+                    out.resize(records * width, 0);
+                    let mut bytes = generator.fill_chunk(chunk_idx, scratch, out);
+                    if let Some(sink) = sink {
+                        serialized.clear();
+                        let first = chunk_idx * generator.chunk_size();
+                        sink.write_chunk(generator, first, &out[..bytes], serialized);
+                        bytes = serialized.len();
+                    }
+                    ChunkTiming {
+                        thread: rayon::current_thread_index().unwrap_or(0),
+                        span: Span {
+                            start: begun,
+                            end: epoch.elapsed(),
+                            records,
+                        },
+                        bytes,
+                    }
+                },
+            )
+            .collect()
+    });
+    (start.elapsed(), chunks)
+}
+
+/// Times the naive generator on (up to [`NAIVE_MAX_RECORDS`] of) the same
+/// records as `generator`: the same charset and length, policy, or word
+/// count and separator, though passphrases keep the wordlist's case.
+pub fn naive_baseline(generator: &PasswordGenerator, warmup: usize, iterations: usize) -> Baseline {
+    let records = generator.count().min(NAIVE_MAX_RECORDS);
+    for _ in 0..warmup {
+        std::hint::black_box(naive_run(generator, records));
+    }
+    let mut walls: Vec<Duration> = (0..iterations)
+        .map(|_| {
+            let start = Instant::now();
+            std::hint::black_box(naive_run(generator, records));
+            start.elapsed()
+        })
+        .collect();
+    walls.sort_unstable();
+    let seconds = walls[walls.len() / 2].as_secs_f64();
+    Baseline {
+        records,
+        seconds,
+        records_per_sec: records as f64 / seconds,
+    }
+}
+
+/// `records` passwords, one RNG call per character.
+fn naive_run(generator: &PasswordGenerator, records: usize) -> Vec<Vec<u8>> {
+    let mut rng = rand::thread_rng();
+    let mut passwords = Vec::new();
+    while passwords.len() < records {
+        let password = if let Some(pass) = generator.passphrase() {
+            let words: Vec<&str> = (0..pass.words())
+                .map(|_| pass.wordlist().words().choose(&mut rng).unwrap().as_str())
+                .collect();
+            let mut phrase = words.join(pass.separator());
+            if pass.digits() > 0 {
+                let number = rng.gen_range(0..10u64.pow(pass.digits() as u32));
+                phrase += &format!(
+                    "{}{:0width$}",
+                    pass.separator(),
+                    number,
+                    width = pass.digits()
+                );
+            }
+            phrase.into_bytes()
+        } else if let Some(enc) = generator.encoder() {
+            let length = enc
+                .lengths()
+                .map_or(enc.length(), |lengths| lengths.pick(rng.gen()));
+            (0..length)
+                .map(|i| match enc.template() {
+                    Some(template) => template.alphabet_at(i).map_or_else(
+                        || template.literal_at(i).unwrap(),
+                        |alphabet| *alphabet.choose(&mut rng).unwrap(),
+                    ),
+                    None => *enc.alphabet().choose(&mut rng).unwrap(),
+                })
+                .collect()
+        } else {
+            rng.gen::<[u8; 16]>().to_vec()
+        };
+        if generator
+            .policy()
+            .is_none_or(|policy| policy.check(&password))
+        {
+            passwords.push(password);
+        }
+    }
+    passwords
+}
+
+/// The optimized generator against the naive baseline: single-threaded, and
+/// at the fastest thread count measured.
+pub fn comparison(baseline: &Baseline, results: &[Measurement]) -> String {
+    let mut lines = vec![format!(
+        "{:<36} {:>14.0} records/s",
+        "Naive (rand::thread_rng, 1 thread)", baseline.records_per_sec
+    )];
+    let fastest = results
+        .iter()
+        .max_by(|a, b| a.records_per_sec.total_cmp(&b.records_per_sec));
+    let single = results.iter().find(|m| m.threads == 1);
+    for m in single.into_iter().chain(fastest.filter(|m| m.threads != 1)) {
+        lines.push(format!(
+            "{:<36} {:>14.0} records/s  {:>8.1}x",
+            format!(
+                "This generator, {} thread{}",
+                m.threads,
+                if m.threads == 1 { "" } else { "s" }
+            ),
+            m.records_per_sec,
+            m.records_per_sec / baseline.records_per_sec
+        ));
+    }
+    lines.join("\n")
+}
+
+/// Throughput at one thread count, before and after.
+#[derive(Debug, PartialEq)]
+pub struct Change {
+    pub threads: usize,
+    pub before: f64,
+    pub after: f64,
+}
+
+impl Change {
+    /// The change in records per second, in percent; negative is slower.
+    pub fn percent(&self) -> f64 {
+        (self.after / self.before - 1.0) * 100.0
+    }
+}
+
+/// `current` against `baseline` at each thread count both measured.
+pub fn compare(baseline: &Report, current: &Report) -> Vec<Change> {
+    current
+        .results
+        .iter()
+        .filter_map(|m| {
+            let before = baseline.results.iter().find(|b| b.threads == m.threads)?;
+            Some(Change {
+                threads: m.threads,
+                before: before.records_per_sec,
+                after: m.records_per_sec,
+            })
+        })
+        .collect()
+}
+
+/// How the two runs were set up differently, which makes the comparison
+/// one of configurations as much as of builds.
+pub fn mismatches(baseline: &Report, current: &Report) -> Vec<String> {
+    let mut differences = Vec::new();
+    let mut check = |what: &str, before: String, after: String| {
+        if before != after {
+            differences.push(format!(
+                "{}: {} in the baseline, {} now",
+                what, before, after
+            ));
+        }
+    };
+    check(
+        "records",
+        baseline.records.to_string(),
+        current.records.to_string(),
+    );
+    check(
+        "encoding",
+        baseline.encoding.clone(),
+        current.encoding.clone(),
+    );
+    check("cipher", baseline.cipher.clone(), current.cipher.clone());
+    check(
+        "chunk size",
+        baseline.chunk_size.to_string(),
+        current.chunk_size.to_string(),
+    );
+    differences
+}
+
+/// The changes as a table, one line per thread count.
+pub fn change_table(changes: &[Change]) -> String {
+    let mut table = format!(
+        "{:>7} {:>14} {:>14} {:>8}",
+        "THREADS", "BASELINE/S", "NOW/S", "CHANGE"
+    );
+    for c in changes {
+        table += &format!(
+            "\n{:>7} {:>14.0} {:>14.0} {:>+7.1}%",
+            c.threads,
+            c.before,
+            c.after,
+            c.percent()
+        );
+    }
+    table
+}
+
+/// The cold-start, steady-state and peak rates as a table, one line per
+/// thread count.
+pub fn split_table(results: &[Measurement], warmup_seconds: f64) -> String {
+    let rate = |rate: Option<f64>| rate.map_or("n/a".to_string(), |r| format!("{:.0}", r));
+    let mut table = format!(
+        "{:>7} {:>18} {:>18} {:>18}",
+        "THREADS",
+        format!("COLD ({:.1}s)/S", warmup_seconds),
+        "STEADY/S",
+        "PEAK 1s/S"
+    );
+    for m in results {
+        table += &format!(
+            "\n{:>7} {:>18} {:>18} {:>18}",
+            m.threads,
+            rate(m.cold_records_per_sec),
+            rate(m.steady_records_per_sec),
+            rate(m.peak_records_per_sec)
+        );
+    }
+    table
+}
+
+/// The results as a table, one line per thread count.
+pub fn table(results: &[Measurement]) -> String {
+    let mut table = format!(
+        "{:>7} {:>14} {:>23} {:>10} {:>10} {:>28} {:>8} {:>6}",
+        "THREADS",
+        "RECORDS/S",
+        "PER THREAD (MIN-MAX)",
+        "KS GiB/s",
+        "OUT GiB/s",
+        "CHUNK ms p50/p90/p99/max",
+        "SPEEDUP",
+        "EFF"
+    );
+    for m in results {
+        let (low, high) = m
+            .per_thread
+            .iter()
+            .fold((f64::INFINITY, 0f64), |(lo, hi), &r| (lo.min(r), hi.max(r)));
+        table += &format!(
+            "\n{:>7} {:>14.0} {:>23} {:>10.2} {:>10.2} {:>28} {:>7.2}x {:>5.0}%",
+            m.threads,
+            m.records_per_sec,
+            format!("{:.0}-{:.0}", low, high),
+            m.keystream_gib_per_sec,
+            m.output_gib_per_sec,
+            format!(
+                "{:.2}/{:.2}/{:.2}/{:.2}",
+                m.chunk_ms.p50, m.chunk_ms.p90, m.chunk_ms.p99, m.chunk_ms.max
+            ),
+            m.speedup,
+            m.efficiency * 100.0
+        );
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn thread_counts_and_percentiles() {
+        assert_eq!(default_thread_counts(1), [1]);
+        assert_eq!(default_thread_counts(4), [1, 2, 4]);
+        assert_eq!(default_thread_counts(12), [1, 2, 4, 8, 12]);
+        let mut samples: Vec<_> = (1..=100).map(Duration::from_millis).collect();
+        let p = Percentiles::of(&mut samples);
+        assert_eq!((p.p50, p.p90, p.p99, p.max), (50.0, 90.0, 99.0, 100.0));
+    }
+
+    fn report(cipher: &str, rates: &[(usize, f64)]) -> Report {
+        let results = rates
+            .iter()
+            .map(|&(threads, records_per_sec)| Measurement {
+                threads,
+                seconds: 1.0,
+                records_per_sec,
+                keystream_gib_per_sec: 0.0,
+                output_gib_per_sec: 0.0,
+                per_thread: vec![records_per_sec / threads as f64; threads],
+                chunk_ms: Percentiles {
+                    p50: 1.0,
+                    p90: 1.0,
+                    p99: 1.0,
+                    max: 1.0,
+                },
+                cold_records_per_sec: None,
+                steady_records_per_sec: None,
+                peak_records_per_sec: None,
+                speedup: 1.0,
+                efficiency: 1.0,
+            })
+            .collect();
+        Report {
+            records: 1000,
+            encoding: "raw".into(),
+            cipher: cipher.into(),
+            chunk_size: 100,
+            warmup: 1,
+            warmup_seconds: 1.0,
+            iterations: 3,
+            results,
+            naive: None,
+        }
+    }
+
+    #[test]
+    fn reports_compare_at_shared_thread_counts() {
+        let baseline = report("aes128", &[(1, 100.0), (2, 200.0), (4, 400.0)]);
+        let current = report("chacha20", &[(1, 110.0), (4, 300.0), (8, 500.0)]);
+        let changes = compare(&baseline, &current);
+        assert_eq!(
+            changes.iter().map(|c| c.threads).collect::<Vec<_>>(),
+            [1, 4]
+        );
+        assert!((changes[0].percent() - 10.0).abs() < 1e-9);
+        assert!((changes[1].percent() + 25.0).abs() < 1e-9);
+        assert!(change_table(&changes).contains("-25.0%"));
+        assert_eq!(
+            mismatches(&baseline, &current),
+            ["cipher: aes128 in the baseline, chacha20 now"]
+        );
+
+        // A report survives the round trip through its JSON
+        let json = serde_json::to_string(&baseline).unwrap();
+        let read: Report = serde_json::from_str(&json).unwrap();
+        assert!(compare(&baseline, &read).iter().all(|c| c.percent() == 0.0));
+    }
+}
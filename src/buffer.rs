@@ -0,0 +1,267 @@
+//! Output buffering sized from the destination's observed behaviour.
+//!
+//! No single write size suits every destination: tmpfs takes anything at
+//! memory speed, while NFS pays a round trip per write. An
+//! [`AdaptiveBuffer`] starts by timing writes of several sizes for the
+//! first couple of seconds of a run. It fits each write's time as a fixed
+//! latency plus bytes over throughput and then settles on writes large
+//! enough that the latency costs about 5% of the transfer time.
+//!
+//! The same timings decide how many chunks are generated ahead of the
+//! writer. Steady writes need one chunk per worker in flight; writes whose
+//! time jitters get more, so a slow write does not stall the workers.
+
+use std::io::{self, Write};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How long write sizes are probed before one is chosen.
+const CALIBRATION: Duration = Duration::from_secs(2);
+
+/// Write sizes timed during calibration, in turn.
+pub const PROBE_SIZES: [usize; 5] = [64 << 10, 256 << 10, 1 << 20, 4 << 20, 16 << 20];
+
+/// Bounds on the chosen write size.
+const MIN_WRITE: usize = 64 << 10;
+const MAX_WRITE: usize = 64 << 20;
+
+/// Fraction of each write's time that may go to its fixed latency.
+const LATENCY_SHARE: f64 = 0.05;
+
+/// Most chunks per worker generated ahead of the writer.
+const MAX_AHEAD: usize = 4;
+
+/// What an [`AdaptiveBuffer`] settled on, and why.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BufferReport {
+    /// Bytes per write to the destination
+    pub write_size: usize,
+    /// Chunks per worker generated ahead of the writer
+    pub chunks_ahead: usize,
+    /// Writes timed during calibration
+    pub samples: usize,
+    /// Fitted fixed cost of one write
+    pub latency: Duration,
+    /// Fitted bytes per second once a write is under way
+    pub throughput: f64,
+    /// 90th over 50th percentile of the timed writes' time relative to the
+    /// fit
+    pub jitter: f64,
+    /// Whether calibration finished; short runs keep the defaults
+    pub calibrated: bool,
+    /// Bytes written through the buffer so far
+    pub bytes: u64,
+}
+
+struct State {
+    report: BufferReport,
+    started: Option<Instant>,
+    /// (bytes, seconds) of each timed write
+    samples: Vec<(usize, f64)>,
+    pending: Vec<u8>,
+}
+
+/// Buffers a run's output and tunes the buffer from timed writes; pass it
+/// to [`PasswordGenerator::write_adaptive`](crate::PasswordGenerator::write_adaptive).
+pub struct AdaptiveBuffer {
+    state: Mutex<State>,
+}
+
+impl Default for AdaptiveBuffer {
+    fn default() -> Self {
+        AdaptiveBuffer::new()
+    }
+}
+
+impl AdaptiveBuffer {
+    pub fn new() -> Self {
+        AdaptiveBuffer {
+            state: Mutex::new(State {
+                report: BufferReport {
+                    write_size: 1 << 20,
+                    chunks_ahead: 1,
+                    samples: 0,
+                    latency: Duration::ZERO,
+                    throughput: 0.0,
+                    jitter: 1.0,
+                    calibrated: false,
+                    bytes: 0,
+                },
+                started: None,
+                samples: Vec::new(),
+                pending: Vec::new(),
+            }),
+        }
+    }
+
+    /// A buffer settled from the start on `report`, as from a probe of the
+    /// destination, instead of calibrating on the run's first writes.
+    pub fn settled(report: BufferReport) -> Self {
+        let buffer = AdaptiveBuffer::new();
+        buffer.state.lock().unwrap().report = BufferReport { bytes: 0, ..report };
+        buffer
+    }
+
+    /// The settings in use.
+    pub fn report(&self) -> BufferReport {
+        self.state.lock().unwrap().report
+    }
+
+    /// Chunks per worker to generate ahead of the writer.
+    pub(crate) fn chunks_ahead(&self) -> usize {
+        self.state.lock().unwrap().report.chunks_ahead
+    }
+
+    /// Buffers `buf`, writing whole writes of the current size to `out`.
+    pub(crate) fn write(&self, out: &mut dyn Write, buf: &[u8]) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.started.get_or_insert_with(Instant::now);
+        state.pending.extend_from_slice(buf);
+        let mut written = 0;
+        loop {
+            let size = state.next_write_size();
+            if state.pending.len() - written < size {
+                break;
+            }
+            let begun = Instant::now();
+            out.write_all(&state.pending[written..written + size])?;
+            written += size;
+            state.report.bytes += size as u64;
+            if !state.report.calibrated {
+                state.samples.push((size, begun.elapsed().as_secs_f64()));
+                state.calibrate();
+            }
+        }
+        state.pending.drain(..written);
+        Ok(())
+    }
+
+    /// Writes out whatever is buffered.
+    pub(crate) fn flush(&self, out: &mut dyn Write) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        out.write_all(&state.pending)?;
+        state.report.bytes += state.pending.len() as u64;
+        state.pending.clear();
+        out.flush()
+    }
+}
+
+impl State {
+    fn next_write_size(&self) -> usize {
+        if self.report.calibrated {
+            self.report.write_size
+        } else {
+            PROBE_SIZES[self.samples.len() % PROBE_SIZES.len()]
+        }
+    }
+
+    /// Settles on a write size and look-ahead once calibration has run its
+    /// course.
+    fn calibrate(&mut self) {
+        let elapsed = self.started.map_or(Duration::ZERO, |s| s.elapsed());
+        if elapsed < CALIBRATION || self.samples.len() < 2 * PROBE_SIZES.len() {
+            return;
+        }
+        self.report = BufferReport {
+            bytes: self.report.bytes,
+            ..settle(&self.samples)
+        };
+        self.samples = Vec::new();
+    }
+}
+
+/// The settings timed writes of (bytes, seconds) call for: writes large
+/// enough that the fitted latency costs about 5% of the transfer time, and
+/// as many chunks ahead as the writes' times jitter.
+pub fn settle(samples: &[(usize, f64)]) -> BufferReport {
+    let (latency, per_byte) = fit(samples);
+    let write_size = if per_byte > 0.0 {
+        (latency / LATENCY_SHARE / per_byte) as usize
+    } else {
+        MAX_WRITE
+    };
+    // How far writes stray from the fit
+    let mut ratios: Vec<f64> = samples
+        .iter()
+        .map(|&(b, s)| s / (latency + b as f64 * per_byte).max(f64::MIN_POSITIVE))
+        .collect();
+    ratios.sort_by(f64::total_cmp);
+    let percentile = |p: f64| ratios[((ratios.len() - 1) as f64 * p).round() as usize];
+    let jitter = percentile(0.9) / percentile(0.5).max(f64::MIN_POSITIVE);
+    BufferReport {
+        write_size: write_size.clamp(MIN_WRITE, MAX_WRITE).next_power_of_two(),
+        chunks_ahead: (jitter.round() as usize).clamp(1, MAX_AHEAD),
+        samples: samples.len(),
+        latency: Duration::from_secs_f64(latency),
+        throughput: if per_byte > 0.0 {
+            1.0 / per_byte
+        } else {
+            f64::INFINITY
+        },
+        jitter,
+        calibrated: true,
+        bytes: 0,
+    }
+}
+
+/// Least-squares fit of `seconds = latency + bytes * per_byte`, with both
+/// terms kept non-negative.
+fn fit(samples: &[(usize, f64)]) -> (f64, f64) {
+    let n = samples.len() as f64;
+    let mean_x = samples.iter().map(|&(b, _)| b as f64).sum::<f64>() / n;
+    let mean_y = samples.iter().map(|&(_, s)| s).sum::<f64>() / n;
+    let (mut sxy, mut sxx) = (0.0, 0.0);
+    for &(b, s) in samples {
+        let dx = b as f64 - mean_x;
+        sxy += dx * (s - mean_y);
+        sxx += dx * dx;
+    }
+    let per_byte = if sxx > 0.0 { (sxy / sxx).max(0.0) } else { 0.0 };
+    ((mean_y - per_byte * mean_x).max(0.0), per_byte)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fit_recovers_latency_and_throughput() {
+        // 1 ms per write plus 1 GB/s
+        let samples: Vec<_> = PROBE_SIZES
+            .iter()
+            .map(|&b| (b, 1e-3 + b as f64 * 1e-9))
+            .collect();
+        let (latency, per_byte) = fit(&samples);
+        assert!((latency - 1e-3).abs() < 1e-9);
+        assert!((per_byte - 1e-9).abs() < 1e-15);
+
+        let mut state = AdaptiveBuffer::new().state.into_inner().unwrap();
+        state.started = Some(Instant::now() - CALIBRATION);
+        state.samples = [samples.clone(), samples].concat();
+        state.calibrate();
+        // 5% of the time in latency: 1 ms against 20 ms of transfer
+        assert_eq!(state.report.write_size, 32 << 20);
+        assert_eq!(state.report.chunks_ahead, 1);
+
+        // Every fourth write takes three times as long
+        let mut state = AdaptiveBuffer::new().state.into_inner().unwrap();
+        state.started = Some(Instant::now() - CALIBRATION);
+        state.samples = (0..20)
+            .map(|i| (1 << 20, if i % 4 == 0 { 3e-3 } else { 1e-3 }))
+            .collect();
+        state.calibrate();
+        assert_eq!(state.report.chunks_ahead, 3);
+    }
+
+    #[test]
+    fn output_is_unchanged() {
+        let buffer = AdaptiveBuffer::new();
+        let data: Vec<u8> = (0..3_000_000u32).map(|i| i as u8).collect();
+        let mut out = Vec::new();
+        for piece in data.chunks(77_777) {
+            buffer.write(&mut out, piece).unwrap();
+        }
+        buffer.flush(&mut out).unwrap();
+        assert!(out == data);
+    }
+}
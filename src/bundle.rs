@@ -0,0 +1,356 @@
+//! `export-replay-bundle`: everything an auditor needs to re-derive a
+//! corpus from its key, on a network this tool cannot reach.
+//!
+//! A bundle is a directory:
+//!
+//! ```text
+//! bundle.json          what the bundle is, and how verify is to check it
+//! manifest.json        the corpus's manifest, its parameters included
+//! run.key.age          the run's key, encrypted to the auditor (or .gpg)
+//! ```
+//!
+//! `verify --bundle DIR` checks the corpus as `verify` does (shard hashes,
+//! known-answer vectors, records read back), then decrypts the key with the
+//! auditor's own tool and generates every record again to compare with the
+//! corpus. The checks are pinned in `bundle.json`, beside the SHA-256 of the
+//! manifest, so a build that cannot run one of them refuses the bundle
+//! rather than passing it on fewer checks.
+
+use mass_password_gen_optimized::archive::{self, SCHEME};
+use mass_password_gen_optimized::{hex, key_fingerprint, parse_key_hex, Key, Manifest};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::permissions;
+use crate::recipient::{self, Recipient};
+
+/// Bundle layout version this build writes.
+pub const BUNDLE_VERSION: u32 = 1;
+
+pub const BUNDLE_FILE: &str = "bundle.json";
+pub const MANIFEST_FILE: &str = "manifest.json";
+/// The key's file before the recipient's tool names it
+const KEY_FILE: &str = "run.key";
+/// Mode of `bundle.json` and `manifest.json`, which hold nothing secret
+/// and are for handing over
+const SHARED_MODE: u32 = 0o644;
+
+/// The checks a bundle pins, all of which this build runs.
+pub const CHECKS: [&str; 4] = ["shard-hashes", "vectors", "records", "rederive"];
+
+/// What `bundle.json` holds.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Bundle {
+    pub bundle_version: u32,
+    /// Tool and version that wrote the bundle
+    pub created_by: String,
+    pub scheme: String,
+    /// The corpus, an archive or a manifest, where it was when exported;
+    /// `verify --bundle DIR CORPUS` finds it elsewhere
+    pub corpus: PathBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub run_id: Option<String>,
+    pub key_fingerprint: String,
+    /// The encrypted key, relative to the bundle
+    pub key: PathBuf,
+    /// Whom the key is encrypted to
+    pub recipient: String,
+    pub verification: Pinned,
+}
+
+/// How `verify --bundle` is to check the corpus.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Pinned {
+    /// SHA-256 of the bundle's manifest
+    pub manifest_sha256: String,
+    /// Checks that must all pass, from [`CHECKS`]
+    pub checks: Vec<String>,
+}
+
+/// Writes the bundle of the corpus at `corpus`, whose key is `key`, into
+/// the new or empty directory `dir`, with the key encrypted to `to`.
+pub fn export(
+    corpus: &Path,
+    key: &Key,
+    to: &Recipient,
+    dir: &Path,
+) -> Result<Bundle, Box<dyn std::error::Error>> {
+    let (manifest, stub) = archive::read_run(corpus)?;
+    let fingerprint = key_fingerprint(key);
+    match &stub.key_fingerprint {
+        Some(run) if *run != fingerprint => {
+            return Err(format!(
+                "the key has fingerprint {}, but {} was made with key {}",
+                fingerprint,
+                corpus.display(),
+                run
+            )
+            .into())
+        }
+        Some(_) => {}
+        None => {
+            return Err(format!(
+                "{} was written before runs kept their key's fingerprint, so the key \
+                 cannot be confirmed as its own",
+                corpus.display()
+            )
+            .into())
+        }
+    }
+    if stub.watermarked {
+        return Err("the run's records carry a watermark, which the bundle \
+                    cannot re-derive without the watermark key"
+            .into());
+    }
+    stub.generator(*key)?;
+    to.check()?;
+    fs::create_dir_all(dir)?;
+    if fs::read_dir(dir)?.next().is_some() {
+        return Err(format!("{} is not empty", dir.display()).into());
+    }
+
+    let manifest = Manifest {
+        parameters: Some(stub.clone()),
+        ..manifest
+    };
+    let manifest_json = serde_json::to_vec_pretty(&manifest)?;
+    permissions::create(&dir.join(MANIFEST_FILE), SHARED_MODE)?.write_all(&manifest_json)?;
+    let key_path = to.encrypted_path(&dir.join(KEY_FILE));
+    to.encrypt(format!("{}\n", key.to_hex()).as_bytes(), &key_path)?;
+    let bundle = Bundle {
+        bundle_version: BUNDLE_VERSION,
+        created_by: format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+        scheme: stub.scheme.clone(),
+        corpus: std::path::absolute(corpus)?,
+        run_id: manifest.run_id.clone(),
+        key_fingerprint: fingerprint,
+        key: key_path.strip_prefix(dir).unwrap().to_path_buf(),
+        recipient: to.to_string(),
+        verification: Pinned {
+            manifest_sha256: sha256_hex(&manifest_json),
+            checks: CHECKS.iter().map(|check| check.to_string()).collect(),
+        },
+    };
+    let mut json = serde_json::to_vec_pretty(&bundle)?;
+    json.push(b'\n');
+    permissions::create(&dir.join(BUNDLE_FILE), SHARED_MODE)?.write_all(&json)?;
+    Ok(bundle)
+}
+
+/// The bundle in `dir` and its manifest, parameters and all, once its pins
+/// check out.
+pub fn open(dir: &Path) -> io::Result<(Bundle, Manifest)> {
+    let invalid = |e: String| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: {}", dir.display(), e),
+        )
+    };
+    let bundle: Bundle = serde_json::from_slice(&fs::read(dir.join(BUNDLE_FILE))?)
+        .map_err(|e| invalid(format!("{}: {}", BUNDLE_FILE, e)))?;
+    if bundle.bundle_version != BUNDLE_VERSION {
+        return Err(invalid(format!(
+            "bundle version {} is not supported (this build reads {})",
+            bundle.bundle_version, BUNDLE_VERSION
+        )));
+    }
+    if bundle.scheme != SCHEME {
+        return Err(invalid(format!("unknown scheme `{}`", bundle.scheme)));
+    }
+    if let Some(check) = bundle
+        .verification
+        .checks
+        .iter()
+        .find(|check| !CHECKS.contains(&check.as_str()))
+    {
+        return Err(invalid(format!(
+            "the bundle pins check `{}`, which this build does not run",
+            check
+        )));
+    }
+    let manifest_json = fs::read(dir.join(MANIFEST_FILE))?;
+    if sha256_hex(&manifest_json) != bundle.verification.manifest_sha256 {
+        return Err(invalid(format!(
+            "{} does not have the SHA-256 the bundle pins",
+            MANIFEST_FILE
+        )));
+    }
+    let manifest: Manifest = serde_json::from_slice(&manifest_json)
+        .map_err(|e| invalid(format!("{}: {}", MANIFEST_FILE, e)))?;
+    if manifest.parameters.is_none() {
+        return Err(invalid(format!("{} has no parameters", MANIFEST_FILE)));
+    }
+    Ok((bundle, manifest))
+}
+
+/// Checks the corpus that `report` verified against the bundle in `dir`,
+/// opened with its `manifest`: that it is the bundle's run, shard for
+/// shard, and that the key, decrypted with `identity` where age needs one,
+/// makes every record of it again. Gives the records re-derived and every
+/// problem found.
+pub fn check(
+    dir: &Path,
+    bundle: &Bundle,
+    manifest: &Manifest,
+    corpus: &Path,
+    report: &archive::Verification,
+    identity: Option<&Path>,
+) -> io::Result<(usize, Vec<String>)> {
+    let read = (&report.stub, &report.manifest);
+    let key = match run_key(dir, bundle, manifest, corpus, read, identity)? {
+        Ok(key) => key,
+        Err(problem) => return Ok((0, vec![problem])),
+    };
+    let stub = manifest
+        .parameters
+        .as_ref()
+        .expect("opened with parameters");
+    let rederived = archive::rederive(corpus, stub, key)?;
+    Ok((rederived.records, rederived.problems))
+}
+
+/// Checks the record `found` read back against the bundle in `dir`, as
+/// [`check`] does the whole corpus, generating only that record again.
+/// Gives every problem found.
+pub fn check_record(
+    dir: &Path,
+    bundle: &Bundle,
+    manifest: &Manifest,
+    corpus: &Path,
+    found: &archive::RecordCheck,
+    identity: Option<&Path>,
+) -> io::Result<Vec<String>> {
+    let read = (&found.stub, &found.manifest);
+    let key = match run_key(dir, bundle, manifest, corpus, read, identity)? {
+        Ok(key) => key,
+        Err(problem) => return Ok(vec![problem]),
+    };
+    let stub = manifest
+        .parameters
+        .as_ref()
+        .expect("opened with parameters");
+    Ok(found.record.as_ref().map_or_else(Vec::new, |record| {
+        archive::rederive_record(stub, key, record)
+    }))
+}
+
+/// The bundle's key, decrypted with `identity`, if `corpus`, whose stub and
+/// manifest read back as `read`, is the run the bundle in `dir` was
+/// exported for; otherwise why not.
+fn run_key(
+    dir: &Path,
+    bundle: &Bundle,
+    manifest: &Manifest,
+    corpus: &Path,
+    read: (&archive::Stub, &Manifest),
+    identity: Option<&Path>,
+) -> io::Result<Result<Key, String>> {
+    let stub = manifest
+        .parameters
+        .as_ref()
+        .expect("opened with parameters");
+    let hashes = |manifest: &Manifest| -> Vec<Option<String>> {
+        manifest.shards.iter().map(|s| s.sha256.clone()).collect()
+    };
+    if read.0 != stub || hashes(read.1) != hashes(manifest) {
+        return Ok(Err(format!(
+            "{} is not the run the bundle was exported for",
+            corpus.display()
+        )));
+    }
+    let text = recipient::decrypt(&dir.join(&bundle.key), identity)?;
+    let key = std::str::from_utf8(&text)
+        .ok()
+        .and_then(|text| parse_key_hex(text.trim()).ok())
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} does not decrypt to a key", bundle.key.display()),
+            )
+        })?;
+    Ok(Ok(key))
+}
+
+fn sha256_hex(bytes: &[u8]) -> String {
+    hex(&Sha256::digest(bytes))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use mass_password_gen_optimized::archive::ArchiveWriter;
+    use mass_password_gen_optimized::{Charset, Encoder, Format, PasswordGenerator};
+
+    #[test]
+    fn bundles_rederive_their_corpus() {
+        let Some(auditor) = recipient::test_key() else {
+            // Nothing to encrypt the key with on this machine
+            return;
+        };
+        let dir = std::env::temp_dir().join(format!("mpg_bundle_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let key = Key::from([4; 16]);
+        let g = PasswordGenerator::builder()
+            .key(key)
+            .count(500)
+            .chunk_size(200)
+            .encoder(Encoder::new(&Charset::Alnum, 12))
+            .build()
+            .unwrap();
+        let corpus = dir.join("corpus.mpga");
+        let mut writer = ArchiveWriter::new(fs::File::create(&corpus).unwrap()).unwrap();
+        let sink = Format::Csv.sink(&g);
+        g.write_formatted(sink.as_deref().unwrap(), &mut writer, |_, _| ())
+            .unwrap();
+        writer.finish(&g, Format::Csv, None).unwrap();
+
+        let out = dir.join("bundle");
+        assert!(export(&corpus, &Key::from([5; 16]), &auditor, &out)
+            .unwrap_err()
+            .to_string()
+            .contains("fingerprint"));
+        let bundle = export(&corpus, &key, &auditor, &out).unwrap();
+        assert_eq!(bundle.key, Path::new("run.key.gpg"));
+        assert!(export(&corpus, &key, &auditor, &out).is_err());
+        let (opened, manifest) = open(&out).unwrap();
+        assert_eq!(opened, bundle);
+        let report = archive::verify(&opened.corpus).unwrap();
+        let (records, problems) = check(&out, &opened, &manifest, &corpus, &report, None).unwrap();
+        assert!(problems.is_empty(), "{:?}", problems);
+        assert_eq!(records, 500);
+        let found = archive::verify_record(&opened.corpus, 321).unwrap();
+        let problems = check_record(&out, &opened, &manifest, &corpus, &found, None).unwrap();
+        assert!(problems.is_empty(), "{:?}", problems);
+
+        // Another run's corpus is not the bundle's
+        let other = PasswordGenerator::builder()
+            .key(key)
+            .count(400)
+            .build()
+            .unwrap();
+        let elsewhere = dir.join("other.mpga");
+        let mut writer = ArchiveWriter::new(fs::File::create(&elsewhere).unwrap()).unwrap();
+        let sink = Format::Hex.sink(&other);
+        other
+            .write_formatted(sink.as_deref().unwrap(), &mut writer, |_, _| ())
+            .unwrap();
+        writer.finish(&other, Format::Hex, None).unwrap();
+        let report = archive::verify(&elsewhere).unwrap();
+        let (_, problems) = check(&out, &opened, &manifest, &elsewhere, &report, None).unwrap();
+        assert!(problems[0].contains("not the run"), "{:?}", problems);
+        let found = archive::verify_record(&elsewhere, 321).unwrap();
+        let problems = check_record(&out, &opened, &manifest, &elsewhere, &found, None).unwrap();
+        assert!(problems[0].contains("not the run"), "{:?}", problems);
+
+        // A changed manifest breaks the pin
+        let manifest = out.join(MANIFEST_FILE);
+        let mut bytes = fs::read(&manifest).unwrap();
+        bytes.push(b'\n');
+        fs::write(&manifest, bytes).unwrap();
+        assert!(open(&out).unwrap_err().to_string().contains("SHA-256"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,183 @@
+//! The chunk cache of `serve`: encoded chunks of the `--run-key` run, kept
+//! so clients paging over the same records do not generate them again.
+//!
+//! Entries are keyed by the run key's fingerprint, the chunk and the
+//! format, and the least recently used go first once the cache holds more
+//! than its capacity in bytes. Chunks are encoded outside the lock, so two
+//! clients missing the same chunk at once may both encode it; the second
+//! copy just replaces the first.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+use mass_password_gen_optimized::Format;
+use serde::Serialize;
+
+use crate::cursor::Encoded;
+
+/// What a cached chunk is.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ChunkKey {
+    pub fingerprint: String,
+    pub chunk: usize,
+    /// Another format than the run's own
+    pub format: Option<Format>,
+}
+
+/// Encoded chunks, least recently used first out.
+pub struct ChunkCache {
+    capacity: usize,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    entries: HashMap<ChunkKey, (Arc<Encoded>, u64)>,
+    /// Keys by when they were last used
+    order: BTreeMap<u64, ChunkKey>,
+    clock: u64,
+    bytes: usize,
+    hits: u64,
+    misses: u64,
+    evictions: u64,
+}
+
+/// The cache's counters, for `GET /stats`.
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Stats {
+    pub capacity_bytes: usize,
+    pub bytes: usize,
+    pub chunks: usize,
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    /// Hits over lookups, or 0 before the first
+    pub hit_rate: f64,
+}
+
+impl ChunkCache {
+    /// A cache of at most `capacity` bytes; 0 caches nothing.
+    pub fn new(capacity: usize) -> Self {
+        ChunkCache {
+            capacity,
+            state: Mutex::default(),
+        }
+    }
+
+    /// The chunk `key`, from the cache or else from `encode`.
+    pub fn get(&self, key: ChunkKey, encode: impl FnOnce() -> Encoded) -> Arc<Encoded> {
+        {
+            let mut state = self.lock();
+            let state = &mut *state;
+            state.clock += 1;
+            if let Some((encoded, used)) = state.entries.get_mut(&key) {
+                let encoded = encoded.clone();
+                let key = state.order.remove(used).expect("every entry is ordered");
+                *used = state.clock;
+                state.order.insert(state.clock, key);
+                state.hits += 1;
+                return encoded;
+            }
+            state.misses += 1;
+        }
+        let encoded = Arc::new(encode());
+        let size = encoded.size();
+        if size > self.capacity {
+            return encoded;
+        }
+        let mut state = self.lock();
+        state.clock += 1;
+        let clock = state.clock;
+        if let Some((old, used)) = state.entries.insert(key.clone(), (encoded.clone(), clock)) {
+            state.order.remove(&used);
+            state.bytes -= old.size();
+        }
+        state.order.insert(clock, key);
+        state.bytes += size;
+        while state.bytes > self.capacity {
+            let (_, oldest) = state.order.pop_first().expect("bytes are held by entries");
+            let (old, _) = state
+                .entries
+                .remove(&oldest)
+                .expect("every key has an entry");
+            state.bytes -= old.size();
+            state.evictions += 1;
+        }
+        encoded
+    }
+
+    pub fn stats(&self) -> Stats {
+        let state = self.lock();
+        let lookups = state.hits + state.misses;
+        Stats {
+            capacity_bytes: self.capacity,
+            bytes: state.bytes,
+            chunks: state.entries.len(),
+            hits: state.hits,
+            misses: state.misses,
+            evictions: state.evictions,
+            hit_rate: if lookups == 0 {
+                0.0
+            } else {
+                state.hits as f64 / lookups as f64
+            },
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        // The state is consistent between statements; a panicking page
+        // leaves nothing half-done
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(chunk: usize, format: Option<Format>) -> ChunkKey {
+        ChunkKey {
+            fingerprint: "f".to_string(),
+            chunk,
+            format,
+        }
+    }
+
+    fn chunk(bytes: usize) -> Encoded {
+        Encoded::new(vec![7; bytes], vec![bytes])
+    }
+
+    #[test]
+    fn keeps_the_recently_used_within_capacity() {
+        let size = chunk(1000).size();
+        let cache = ChunkCache::new(2 * size);
+        let encodes = std::cell::Cell::new(0);
+        let get = |k: ChunkKey| {
+            cache.get(k, || {
+                encodes.set(encodes.get() + 1);
+                chunk(1000)
+            });
+        };
+        get(key(0, None));
+        get(key(1, None));
+        get(key(0, None));
+        // Chunk 1 is the least recently used, so chunk 2 pushes it out
+        get(key(2, None));
+        get(key(0, None));
+        get(key(1, None));
+        assert_eq!(encodes.get(), 4);
+        // Another format of a cached chunk is another entry
+        get(key(1, Some(Format::Hex)));
+        assert_eq!(encodes.get(), 5);
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.evictions), (2, 5, 3));
+        assert_eq!((stats.chunks, stats.bytes), (2, 2 * size));
+        assert!((stats.hit_rate - 2.0 / 7.0).abs() < 1e-9);
+
+        // Chunks larger than the whole cache are handed out, not kept
+        let small = ChunkCache::new(size / 2);
+        small.get(key(0, None), || chunk(1000));
+        small.get(key(0, None), || chunk(1000));
+        assert_eq!((small.stats().misses, small.stats().chunks), (2, 0));
+    }
+}
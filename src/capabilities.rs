@@ -0,0 +1,109 @@
+//! `--list-capabilities`: which optional parts this binary was built with.
+//!
+//! Each is a cargo feature (see Cargo.toml); all but `fault-injection` are
+//! on by default, and `--no-default-features` builds the bare generator.
+
+use mass_password_gen_optimized::aes_hardware_available;
+
+/// An optional part of the build.
+pub struct Capability {
+    /// The cargo feature
+    pub feature: &'static str,
+    pub enabled: bool,
+    /// What it adds
+    pub provides: &'static str,
+}
+
+/// Every cargo feature, in Cargo.toml's order.
+pub const ALL: [Capability; 9] = [
+    Capability {
+        feature: "compress-zstd",
+        enabled: cfg!(feature = "compress-zstd"),
+        provides: "--compress zstd, --zstd-dict, reading zstd corpora",
+    },
+    Capability {
+        feature: "compress-gzip",
+        enabled: cfg!(feature = "compress-gzip"),
+        provides: "--compress gzip, reading gzip corpora",
+    },
+    Capability {
+        feature: "hash",
+        enabled: cfg!(feature = "hash"),
+        provides: "--emit-hash (bcrypt, argon2id, sha512-crypt)",
+    },
+    Capability {
+        feature: "derive",
+        enabled: cfg!(feature = "derive"),
+        provides: "the derive subcommand",
+    },
+    Capability {
+        feature: "keyring",
+        enabled: cfg!(feature = "keyring"),
+        provides: "--key keyring:<name> and the key subcommands",
+    },
+    Capability {
+        feature: "reservations",
+        enabled: cfg!(feature = "reservations"),
+        provides: "the reserve subcommand and serve --reserve-from",
+    },
+    Capability {
+        feature: "tls",
+        enabled: cfg!(feature = "tls"),
+        provides: "serve over HTTPS, with client certificates",
+    },
+    Capability {
+        feature: "pkcs11",
+        enabled: cfg!(feature = "pkcs11"),
+        provides: "--key pkcs11:... (HSMs and tokens)",
+    },
+    Capability {
+        feature: "fault-injection",
+        enabled: cfg!(feature = "fault-injection"),
+        provides: "--fault-inject (developer builds)",
+    },
+];
+
+/// The build's capabilities as a table, then what the CPU offers.
+pub fn table() -> String {
+    let yes = |enabled: bool| if enabled { "yes" } else { "no" };
+    let mut table = format!(
+        "{} {} capabilities:\n{:<16} {:<4} PROVIDES",
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION"),
+        "FEATURE",
+        "ON"
+    );
+    for c in &ALL {
+        table += &format!("\n{:<16} {:<4} {}", c.feature, yes(c.enabled), c.provides);
+    }
+    table += &format!(
+        "\n\nAES instructions: {} (AES-128 runs in software without them)",
+        yes(aes_hardware_available())
+    );
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_feature_is_listed() {
+        let manifest = include_str!("../Cargo.toml");
+        let features = manifest
+            .split("\n[features]\n")
+            .nth(1)
+            .unwrap()
+            .split("\n[")
+            .next()
+            .unwrap();
+        let declared: Vec<&str> = features
+            .lines()
+            .filter(|line| !line.starts_with('#') && !line.starts_with("default "))
+            .filter_map(|line| line.split_once(" = ").map(|(name, _)| name))
+            .collect();
+        let listed: Vec<&str> = ALL.iter().map(|c| c.feature).collect();
+        assert_eq!(listed, declared);
+        assert!(table().contains("reservations"));
+    }
+}
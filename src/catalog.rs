@@ -0,0 +1,263 @@
+//! `catalog`: an inventory of the corpora under one or more storage roots.
+//!
+//! Roots are searched recursively for archives (recognized by their magic,
+//! whatever their name) and corpus manifests (JSON files that parse as one,
+//! such as those `--shard-size` writes). Each run found is summarized from
+//! its index or manifest and, unless told not to, checked with the same
+//! verification as `verify`, runs in parallel.
+//!
+//! Roots are local paths only. Object storage (`s3://` and the like) is
+//! left out on purpose: verifying a run reads every shard back in full, so
+//! cataloging a bucket in place would mean downloading it anyway, and an
+//! object store client with its credential chain would be this build's
+//! only network dependency besides `serve`. Mount the bucket (s3fs,
+//! mountpoint-s3, rclone) or sync it (`aws s3 sync`) and catalog that;
+//! `--no-verify` keeps a mounted catalog to the indexes and manifests.
+
+use mass_password_gen_optimized::archive;
+use mass_password_gen_optimized::timestamp::format_rfc3339;
+use mass_password_gen_optimized::Manifest;
+use rayon::prelude::*;
+use serde::Serialize;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use crate::usage::format_timestamp;
+
+/// One run found under a root.
+#[derive(Debug, Serialize)]
+pub struct Run {
+    pub path: PathBuf,
+    /// `archive` or `manifest`
+    pub kind: &'static str,
+    pub records: Option<usize>,
+    pub format: String,
+    pub shards: usize,
+    /// When the archive or manifest was last written, RFC 3339
+    pub modified: Option<String>,
+    #[serde(skip)]
+    pub modified_secs: Option<u64>,
+    pub key_fingerprint: Option<String>,
+    /// Bytes of every shard found
+    pub bytes: u64,
+    pub verification: Verdict,
+    /// What failed verification, or why there was none
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub problems: Vec<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Verdict {
+    Ok,
+    Failed,
+    /// A manifest that records no generation parameters to check against
+    Unverifiable,
+    /// Not checked (`--no-verify`)
+    Skipped,
+}
+
+/// Rejects roots this build cannot read, such as object storage URLs.
+pub fn parse_root(s: &str) -> Result<PathBuf, String> {
+    match s.split_once("://") {
+        Some((scheme, _)) => Err(format!(
+            "{}:// roots are not supported: this build reads local storage only, so sync or \
+             mount the bucket (e.g. `aws s3 sync`) and catalog the local copy",
+            scheme
+        )),
+        None => Ok(PathBuf::from(s)),
+    }
+}
+
+/// Every archive and corpus manifest under `root`, in path order.
+pub fn discover(root: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut found = Vec::new();
+    let mut dirs = vec![root.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            let kind = entry.file_type()?;
+            if kind.is_dir() {
+                dirs.push(path);
+            } else if kind.is_file() && (is_manifest(&path) || archive::is_archive(&path)?) {
+                found.push(path);
+            }
+        }
+    }
+    found.sort();
+    Ok(found)
+}
+
+fn is_manifest(path: &Path) -> bool {
+    path.extension().is_some_and(|e| e == "json") && Manifest::load(path).is_ok()
+}
+
+/// Summarizes the run at `path`, which [`discover`] found, verifying it if
+/// `verify`.
+pub fn summarize(path: &Path, verify: bool) -> io::Result<Run> {
+    let (kind, manifest, stub) = if archive::is_archive(path)? {
+        let index = archive::read_index(path)?;
+        ("archive", index.manifest, Some(index.stub))
+    } else {
+        let mut manifest = Manifest::load(path)?;
+        let stub = manifest.parameters.take();
+        ("manifest", manifest, stub)
+    };
+    let base = path.parent().unwrap_or(Path::new(""));
+    let bytes = manifest
+        .shards
+        .iter()
+        .map(|shard| match shard.length {
+            Some(length) => length,
+            None => fs::metadata(base.join(&shard.path))
+                .map_or(0, |m| m.len().saturating_sub(shard.offset)),
+        })
+        .sum();
+    let records = manifest
+        .shards
+        .last()
+        .and_then(|last| last.count.map(|count| last.first + count))
+        .or(stub.as_ref().map(|stub| stub.count));
+    let modified_secs = fs::metadata(path)?
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs());
+    let (verification, problems) = match (&stub, verify) {
+        (None, _) => (
+            Verdict::Unverifiable,
+            vec!["the manifest records no generation parameters".to_string()],
+        ),
+        (Some(_), false) => (Verdict::Skipped, Vec::new()),
+        (Some(_), true) => match archive::verify(path) {
+            Ok(report) if report.problems.is_empty() => (Verdict::Ok, Vec::new()),
+            Ok(report) => (Verdict::Failed, report.problems),
+            Err(e) => (Verdict::Failed, vec![e.to_string()]),
+        },
+    };
+    Ok(Run {
+        path: path.to_path_buf(),
+        kind,
+        records,
+        format: manifest.format.to_string(),
+        shards: manifest.shards.len(),
+        modified: modified_secs.map(format_rfc3339),
+        modified_secs,
+        key_fingerprint: stub.and_then(|stub| stub.key_fingerprint),
+        bytes,
+        verification,
+        problems,
+    })
+}
+
+/// Summarizes every run under `roots`, in parallel.
+pub fn collect(roots: &[PathBuf], verify: bool) -> io::Result<Vec<Run>> {
+    let mut paths = Vec::new();
+    for root in roots {
+        paths.extend(
+            discover(root)
+                .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", root.display(), e)))?,
+        );
+    }
+    paths
+        .par_iter()
+        .map(|path| {
+            summarize(path, verify)
+                .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+        })
+        .collect()
+}
+
+/// The runs as a table, one row each.
+pub fn table(runs: &[Run]) -> String {
+    let mut table = format!(
+        "{:<8} {:>14} {:<14} {:>6} {:<16} {:<16} {:>14}  {:<12}  PATH",
+        "KIND", "RECORDS", "FORMAT", "SHARDS", "MODIFIED (UTC)", "KEY", "BYTES", "VERIFIED"
+    );
+    for run in runs {
+        let verdict = match run.verification {
+            Verdict::Ok => "ok",
+            Verdict::Failed => "FAILED",
+            Verdict::Unverifiable => "unverifiable",
+            Verdict::Skipped => "skipped",
+        };
+        table += &format!(
+            "\n{:<8} {:>14} {:<14} {:>6} {:<16} {:<16} {:>14}  {:<12}  {}",
+            run.kind,
+            run.records.map_or("?".to_string(), |n| n.to_string()),
+            run.format,
+            run.shards,
+            run.modified_secs.map_or("?".to_string(), format_timestamp),
+            run.key_fingerprint.as_deref().unwrap_or("-"),
+            run.bytes,
+            verdict,
+            run.path.display()
+        );
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mass_password_gen_optimized::{ArchiveWriter, Format, PasswordGenerator};
+    use std::fs::File;
+
+    #[test]
+    fn catalogs_find_and_check_every_run() {
+        let root = std::env::temp_dir().join("mpg_catalog_test");
+        fs::remove_dir_all(&root).ok();
+        fs::create_dir_all(root.join("nested")).unwrap();
+        let g = PasswordGenerator::builder()
+            .key([8; 16])
+            .count(500)
+            .chunk_size(100)
+            .build()
+            .unwrap();
+
+        let mut writer = ArchiveWriter::new(File::create(root.join("run.bin")).unwrap()).unwrap();
+        g.write_to(&mut writer, |_, _| ()).unwrap();
+        writer.finish(&g, Format::Raw, None).unwrap();
+
+        let names = vec![PathBuf::from("a-00001"), PathBuf::from("a-00002")];
+        let dir = root.join("nested");
+        let files: Vec<File> = names
+            .iter()
+            .map(|name| File::create(dir.join(name)).unwrap())
+            .collect();
+        g.write_sharded(None, &files, 300, |_, _| (), |_| ())
+            .unwrap();
+        let manifest =
+            archive::shard_manifest(&g, Format::Raw, None, 300, None, &dir, &names).unwrap();
+        let json = serde_json::to_vec(&manifest).unwrap();
+        fs::write(dir.join("a.manifest.json"), &json).unwrap();
+        // Not a corpus
+        fs::write(dir.join("bench.json"), b"{\"records\": 5}").unwrap();
+
+        let runs = collect(std::slice::from_ref(&root), true).unwrap();
+        assert_eq!(runs.len(), 2);
+        let fingerprint = mass_password_gen_optimized::key_fingerprint(&[8; 16]);
+        for run in &runs {
+            assert_eq!(run.verification, Verdict::Ok, "{:?}", run.problems);
+            assert_eq!(run.records, Some(500));
+            assert_eq!(run.bytes, 500 * 16);
+            assert_eq!(run.key_fingerprint.as_deref(), Some(fingerprint.as_str()));
+        }
+        assert_eq!((runs[0].kind, runs[0].shards), ("manifest", 2));
+        assert_eq!((runs[1].kind, runs[1].shards), ("archive", 1));
+
+        fs::write(dir.join("a-00002"), b"truncated").unwrap();
+        let runs = collect(std::slice::from_ref(&root), true).unwrap();
+        assert_eq!(runs[0].verification, Verdict::Failed);
+        assert_eq!(
+            collect(std::slice::from_ref(&root), false).unwrap()[0].verification,
+            Verdict::Skipped
+        );
+        assert!(table(&runs).lines().count() == 3);
+        assert!(parse_root("s3://bucket/corpora").is_err());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,369 @@
+//! Checkpoints for resuming an interrupted run into a file.
+//!
+//! While a run is checkpointed, a sidecar file records everything needed to
+//! regenerate it — the key, cipher, IV scheme and chunk size — along with
+//! the record options it was started with and how far it got. The sidecar
+//! is plain `name=value` text. It holds the key, so it is created readable
+//! by its owner only and removed once the run completes.
+//!
+//! A `--shard-size` run is checkpointed a shard at a time: its shards are
+//! written in order, and as each is finished the run's manifest, listing
+//! the shards so far, is saved beside them before the checkpoint moves
+//! past it. Resuming writes the shards after the last one listed.
+
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use mass_password_gen_optimized::{
+    key_fingerprint, parse_key_hex, Cipher, IvStrategy, Key, OutputSink, PasswordGenerator,
+    Progress,
+};
+
+use crate::permissions;
+
+const HEADER: &str = "# mass_password_gen checkpoint: holds the run's key, keep it private";
+
+/// How often a checkpointed run saves its progress.
+const INTERVAL: Duration = Duration::from_secs(1);
+
+/// One run's checkpoint.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checkpoint {
+    pub key: Key,
+    pub cipher: Cipher,
+    pub iv_strategy: IvStrategy,
+    pub chunk_size: usize,
+    pub count: usize,
+    /// Full description of the record options, compared on resume
+    pub records: String,
+    pub format: String,
+    /// Whether the run was watermarked; the watermark key itself is not kept
+    pub watermark: bool,
+    pub output: PathBuf,
+    /// `--shard-size`, for a run checkpointed a shard at a time
+    pub shard_size: Option<usize>,
+    pub progress: Progress,
+}
+
+impl Checkpoint {
+    /// Writes the checkpoint to `path` atomically, so a crash mid-save
+    /// leaves the previous one in place.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let text = format!(
+            "{}\nkey={}\nfingerprint={}\ncipher={}\niv={}\nchunk_size={}\ncount={}\n\
+             records={}\nformat={}\nwatermark={}\noutput={}\nchunks={}\nbytes={}\n",
+            HEADER,
+            self.key.to_hex(),
+            key_fingerprint(&self.key),
+            self.cipher,
+            self.iv_strategy,
+            self.chunk_size,
+            self.count,
+            self.records,
+            self.format,
+            self.watermark,
+            self.output.display(),
+            self.progress.chunks,
+            self.progress.bytes,
+        );
+        let shards = self
+            .shard_size
+            .map_or(String::new(), |n| format!("shard_size={}\n", n));
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let mut file = private_file(Path::new(&tmp))?;
+        file.write_all((text + &shards).as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)
+    }
+
+    /// Reads a checkpoint written by [`save`](Self::save).
+    pub fn load(path: &Path) -> io::Result<Checkpoint> {
+        let text = fs::read_to_string(path)?;
+        parse(&text).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: malformed checkpoint ({})", path.display(), e),
+            )
+        })
+    }
+}
+
+fn parse(text: &str) -> Result<Checkpoint, String> {
+    let field = |name: &str| {
+        text.lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix('='))
+            .ok_or_else(|| format!("no `{}`", name))
+    };
+    let number = |name: &str| {
+        field(name)?
+            .parse::<u64>()
+            .map_err(|_| format!("bad `{}`", name))
+    };
+    let cipher = field("cipher")?;
+    let iv = field("iv")?;
+    Ok(Checkpoint {
+        key: parse_key_hex(field("key")?)?,
+        cipher: Cipher::parse(cipher)?,
+        iv_strategy: IvStrategy::ALL
+            .into_iter()
+            .find(|s| s.to_string() == iv)
+            .ok_or_else(|| format!("unknown IV scheme `{}`", iv))?,
+        chunk_size: number("chunk_size")? as usize,
+        count: number("count")? as usize,
+        records: field("records")?.to_string(),
+        format: field("format")?.to_string(),
+        watermark: field("watermark")? == "true",
+        output: PathBuf::from(field("output")?),
+        // Absent from the checkpoints of unsharded runs
+        shard_size: match field("shard_size") {
+            Ok(_) => Some(number("shard_size")? as usize),
+            Err(_) => None,
+        },
+        progress: Progress {
+            chunks: number("chunks")? as usize,
+            bytes: number("bytes")?,
+        },
+    })
+}
+
+/// A run being checkpointed to its sidecar.
+pub struct Checkpointing {
+    pub sidecar: PathBuf,
+    /// Where the run stands
+    pub checkpoint: Checkpoint,
+    /// Whether the run picks up from `checkpoint` rather than starting
+    pub resuming: bool,
+    /// Why saving stopped, if it did; the run itself carries on
+    error: Option<io::Error>,
+}
+
+impl Checkpointing {
+    pub fn new(sidecar: PathBuf, checkpoint: Checkpoint, resuming: bool) -> Self {
+        Checkpointing {
+            sidecar,
+            checkpoint,
+            resuming,
+            error: None,
+        }
+    }
+
+    /// Saves the starting point, which also proves the sidecar writable,
+    /// and returns how many records are already written.
+    pub fn start(&self) -> io::Result<usize> {
+        let ckpt = &self.checkpoint;
+        ckpt.save(&self.sidecar)?;
+        let every = match ckpt.shard_size {
+            Some(_) => "after each shard".to_string(),
+            None => format!("every {:?}", INTERVAL),
+        };
+        status!(
+            "Checkpointing to {} {}; it holds the key, so keep it private",
+            self.sidecar.display(),
+            every
+        );
+        let done = (ckpt.progress.chunks * ckpt.chunk_size).min(ckpt.count);
+        if let (true, Some(shard_size)) = (self.resuming, ckpt.shard_size) {
+            status!(
+                "Resuming after shard {} of {}: {} records already written",
+                done.div_ceil(shard_size),
+                ckpt.count.div_ceil(shard_size),
+                done
+            );
+        } else if self.resuming {
+            status!(
+                "Resuming after chunk {} of {}: {} records already written",
+                ckpt.progress.chunks,
+                ckpt.count.div_ceil(ckpt.chunk_size),
+                done
+            );
+        }
+        Ok(done)
+    }
+
+    /// Writes the rest of the run to `path`, with `mode`, saving how far it
+    /// got every [`INTERVAL`].
+    pub fn write<T, F>(
+        &mut self,
+        generator: &PasswordGenerator,
+        sink: Option<&dyn OutputSink>,
+        path: &Path,
+        mode: u32,
+        on_chunk: F,
+    ) -> io::Result<Vec<T>>
+    where
+        T: Send,
+        F: Fn(usize, usize) -> T + Sync,
+    {
+        let file = if self.resuming {
+            permissions::open(File::options().write(true), path, mode)?
+        } else {
+            permissions::create(path, mode)?
+        };
+        let mut last_save = Instant::now();
+        let from = self.checkpoint.progress;
+        generator.write_resumable(sink, &file, from, on_chunk, |progress| {
+            self.checkpoint.progress = progress;
+            if self.error.is_some() || last_save.elapsed() < INTERVAL {
+                return;
+            }
+            last_save = Instant::now();
+            // Never claim more than what has reached the disk
+            if let Err(e) = file
+                .sync_data()
+                .and_then(|()| self.checkpoint.save(&self.sidecar))
+            {
+                self.error = Some(e);
+            }
+        })
+    }
+
+    /// Moves the run on to `progress` once `durable` has put everything up
+    /// to it on disk, and saves the checkpoint there. A failure stops the
+    /// saving, as in [`write`](Self::write), but not the run.
+    pub fn advance(&mut self, progress: Progress, durable: impl FnOnce() -> io::Result<()>) {
+        if self.error.is_some() {
+            return;
+        }
+        self.checkpoint.progress = progress;
+        if let Err(e) = durable().and_then(|()| self.checkpoint.save(&self.sidecar)) {
+            self.error = Some(e);
+        }
+    }
+
+    /// Removes the sidecar of a completed run, which holds its key.
+    pub fn finish(self) -> io::Result<()> {
+        if let Some(e) = &self.error {
+            status!(
+                "WARNING: checkpointing to {} stopped: {}",
+                self.sidecar.display(),
+                e
+            );
+        }
+        fs::remove_file(&self.sidecar)?;
+        status!(
+            "Run complete; removed checkpoint {}",
+            self.sidecar.display()
+        );
+        Ok(())
+    }
+}
+
+/// Creates (or truncates) a file only its owner can read.
+pub fn private_file(path: &Path) -> io::Result<File> {
+    permissions::create(path, permissions::DEFAULT_MODE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checkpoints_round_trip() {
+        let checkpoint = Checkpoint {
+            key: Key::from([7; 16]),
+            cipher: Cipher::ChaCha20,
+            iv_strategy: IvStrategy::ChunkIndex,
+            chunk_size: 1000,
+            count: 123_456,
+            records: "passphrase/6/\"-\"/title/2".to_string(),
+            format: "csv".to_string(),
+            watermark: true,
+            output: PathBuf::from("/data/run 1.csv"),
+            shard_size: None,
+            progress: Progress {
+                chunks: 42,
+                bytes: 1_234_567,
+            },
+        };
+        let path = std::env::temp_dir().join("mpg_checkpoint_test.ckpt");
+        checkpoint.save(&path).unwrap();
+        assert_eq!(Checkpoint::load(&path).unwrap(), checkpoint);
+        let sharded = Checkpoint {
+            shard_size: Some(50_000),
+            ..checkpoint
+        };
+        sharded.save(&path).unwrap();
+        assert_eq!(Checkpoint::load(&path).unwrap(), sharded);
+        fs::remove_file(path).unwrap();
+        assert!(parse("key=00").is_err());
+    }
+
+    #[test]
+    fn a_saved_checkpoint_resumes_into_the_last_partial_chunk() {
+        use mass_password_gen_optimized::PasswordGenerator;
+
+        let dir = std::env::temp_dir();
+        let (output, sidecar) = (
+            dir.join("mpg_checkpoint_resume.out"),
+            dir.join("mpg_checkpoint_resume.ckpt"),
+        );
+        let generator = |checkpoint: &Checkpoint| {
+            PasswordGenerator::builder()
+                .key(checkpoint.key)
+                .cipher(checkpoint.cipher)
+                .iv_strategy(checkpoint.iv_strategy)
+                .chunk_size(checkpoint.chunk_size)
+                .count(checkpoint.count)
+                .build()
+                .unwrap()
+        };
+        // 15 whole chunks and one of 40 records
+        let mut checkpoint = Checkpoint {
+            key: Key::from([3; 32]),
+            cipher: Cipher::Aes256,
+            iv_strategy: IvStrategy::Continuous,
+            chunk_size: 64,
+            count: 1000,
+            records: "raw".to_string(),
+            format: "raw".to_string(),
+            watermark: false,
+            output: output.clone(),
+            shard_size: None,
+            progress: Progress::default(),
+        };
+        let g = generator(&checkpoint);
+        let expected = g.generate();
+
+        // The run saves its checkpoint as it goes, then dies with the last
+        // chunk written only in part
+        let mut saved = Vec::new();
+        g.write_resumable(
+            None,
+            &File::create(&output).unwrap(),
+            Progress::default(),
+            |_, _| (),
+            |progress| {
+                checkpoint.progress = progress;
+                checkpoint.save(&sidecar).unwrap();
+                saved.push(progress);
+            },
+        )
+        .unwrap();
+        let before_last = *saved.iter().find(|p| p.chunks == 15).unwrap();
+        let mut torn = expected[..before_last.bytes as usize].to_vec();
+        torn.extend_from_slice(&expected[torn.len()..torn.len() + 100]);
+        fs::write(&output, &torn).unwrap();
+        checkpoint.progress = before_last;
+        checkpoint.save(&sidecar).unwrap();
+
+        let loaded = Checkpoint::load(&sidecar).unwrap();
+        assert_eq!(loaded, checkpoint);
+        let out = File::options().write(true).open(&loaded.output).unwrap();
+        let done = generator(&loaded)
+            .write_resumable(None, &out, loaded.progress, |_, records| records, |_| ())
+            .unwrap();
+        assert_eq!(done, [40]);
+        assert_eq!(fs::read(&output).unwrap(), expected);
+
+        // Neither an unknown cipher nor a stray IV scheme loads
+        let text = fs::read_to_string(&sidecar).unwrap();
+        let unknown = parse(&text.replace("cipher=aes256", "cipher=rc4")).unwrap_err();
+        assert!(unknown.contains("unknown cipher"), "{}", unknown);
+        assert!(parse(&text.replace("iv=continuous", "iv=random")).is_err());
+        fs::remove_file(output).unwrap();
+        fs::remove_file(sidecar).unwrap();
+    }
+}
@@ -0,0 +1,479 @@
+//! The client side of [`serve`](crate::serve)'s HTTP, for `fetch`, and
+//! of any HTTP API for `loadtest`.
+//!
+//! One request per connection, as the server takes them, over TCP, a Unix
+//! socket or vsock, and with the `tls` feature over HTTPS. Bodies are read
+//! as they arrive; a chunked body that ends before its last chunk, or one
+//! shorter than its `Content-Length`, reads as an error rather than as a
+//! short body, so a reply cut off by a dropped connection is never taken
+//! for a whole one.
+
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::time::Duration;
+
+use crate::listen::Address;
+
+/// Longest reply head (status line and headers) accepted.
+const MAX_HEAD: usize = 16 << 10;
+
+/// How long a server may take to send anything.
+const READ_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long a server may leave a request unread.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A server: `http://HOST:PORT`, `https://HOST:PORT`, or any address
+/// `serve --bind` takes for plain HTTP over it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Server {
+    address: Address,
+    /// For the `Host` header, and the name the certificate must have
+    host: String,
+    https: bool,
+}
+
+impl Server {
+    /// Parses `http://HOST[:PORT][/]`, `https://HOST[:PORT][/]`,
+    /// `unix:PATH` or `vsock:CID:PORT`.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let (https, rest) = if let Some(rest) = s.strip_prefix("https://") {
+            (true, rest)
+        } else if let Some(rest) = s.strip_prefix("http://") {
+            (false, rest)
+        } else {
+            if !s.starts_with("unix:") && !s.starts_with("vsock:") {
+                return Err(format!(
+                    "invalid server `{}` (expected http://HOST:PORT, https://HOST:PORT, \
+                     unix:PATH or vsock:CID:PORT)",
+                    s
+                ));
+            }
+            return Ok(Server {
+                address: Address::parse(s)?,
+                host: "localhost".to_string(),
+                https: false,
+            });
+        };
+        #[cfg(not(feature = "tls"))]
+        if https {
+            return Err("https:// needs a build with the `tls` feature".to_string());
+        }
+        let authority = rest.strip_suffix('/').unwrap_or(rest);
+        if authority.is_empty() || authority.contains(['/', '?', '#', '@']) {
+            return Err(format!(
+                "`{}`: give the server alone, without a path or credentials",
+                s
+            ));
+        }
+        // A port is whatever follows the last colon outside brackets
+        let host = match authority.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => {
+                port.parse::<u16>()
+                    .map_err(|_| format!("`{}`: invalid port `{}`", s, port))?;
+                host
+            }
+            _ => authority,
+        };
+        let address = if host.len() == authority.len() {
+            format!("{}:{}", authority, if https { 443 } else { 80 })
+        } else {
+            authority.to_string()
+        };
+        Ok(Server {
+            address: Address::Tcp(address),
+            host: host
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .to_string(),
+            https,
+        })
+    }
+
+    /// Parses an `http://` or `https://` URL into its server and its path
+    /// (with the query), `/` if it has none.
+    pub fn parse_url(s: &str) -> Result<(Self, String), String> {
+        let Some(scheme) = ["http://", "https://"]
+            .into_iter()
+            .find(|p| s.starts_with(p))
+        else {
+            return Err(format!(
+                "invalid URL `{}` (expected http:// or https://)",
+                s
+            ));
+        };
+        let (authority, path) = match s[scheme.len()..].find(['/', '?']) {
+            Some(i) => s.split_at(scheme.len() + i),
+            None => (s, "/"),
+        };
+        let path = match path.strip_prefix('?') {
+            Some(query) => format!("/?{}", query),
+            None => path.to_string(),
+        };
+        Ok((Server::parse(authority)?, path))
+    }
+
+    #[cfg(feature = "tls")]
+    pub fn is_https(&self) -> bool {
+        self.https
+    }
+}
+
+impl fmt::Display for Server {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.address {
+            Address::Tcp(address) => {
+                write!(
+                    f,
+                    "{}://{}",
+                    if self.https { "https" } else { "http" },
+                    address
+                )
+            }
+            address => write!(f, "{}", address),
+        }
+    }
+}
+
+/// Something requests and replies go over.
+trait Connection: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Connection for T {}
+
+/// A client of one server.
+pub struct Client {
+    server: Server,
+    /// Sent as `Authorization: Bearer <token>`
+    token: Option<String>,
+    #[cfg(feature = "tls")]
+    tls: Option<std::sync::Arc<rustls::ClientConfig>>,
+}
+
+/// A reply, its body not yet read.
+pub struct Response {
+    pub status: u16,
+    /// Names in lowercase
+    headers: Vec<(String, String)>,
+    body: Body,
+}
+
+enum Body {
+    Chunked {
+        input: BufReader<Box<dyn Connection>>,
+        /// Bytes left of the current chunk
+        left: usize,
+        done: bool,
+    },
+    Length {
+        input: BufReader<Box<dyn Connection>>,
+        left: u64,
+    },
+    /// Until the server closes the connection
+    Close(BufReader<Box<dyn Connection>>),
+}
+
+impl Client {
+    pub fn new(server: Server, token: Option<String>) -> Self {
+        Client {
+            server,
+            token,
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+
+    /// Speaks HTTPS to an `https://` server with this configuration.
+    #[cfg(feature = "tls")]
+    pub fn with_tls(mut self, config: std::sync::Arc<rustls::ClientConfig>) -> Self {
+        self.tls = Some(config);
+        self
+    }
+
+    pub fn server(&self) -> &Server {
+        &self.server
+    }
+
+    /// Sends `method target` with `headers` and `body`, and reads the head
+    /// of the reply.
+    pub fn request(
+        &self,
+        method: &str,
+        target: &str,
+        headers: &[(String, String)],
+        body: &[u8],
+    ) -> io::Result<Response> {
+        let stream = self.server.address.connect()?;
+        stream.set_timeouts(READ_TIMEOUT, WRITE_TIMEOUT)?;
+        let mut stream: Box<dyn Connection> = match self.server.https {
+            #[cfg(feature = "tls")]
+            true => {
+                let config = self.tls.as_ref().ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "https:// without a TLS setup")
+                })?;
+                Box::new(crate::tls::connect(config, &self.server.host, stream)?)
+            }
+            _ => Box::new(stream),
+        };
+        let mut head = format!(
+            "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n",
+            method, target, self.server.host
+        );
+        if let Some(token) = &self.token {
+            head.push_str(&format!("Authorization: Bearer {}\r\n", token));
+        }
+        for (name, value) in headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        if !body.is_empty() || method != "GET" {
+            head.push_str(&format!("Content-Length: {}\r\n", body.len()));
+        }
+        head.push_str("\r\n");
+        stream.write_all(head.as_bytes())?;
+        stream.write_all(body)?;
+        stream.flush()?;
+        read_response(BufReader::new(stream))
+    }
+}
+
+fn read_response(mut input: BufReader<Box<dyn Connection>>) -> io::Result<Response> {
+    let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidData, what.to_string());
+    let mut lines = Vec::new();
+    let mut read = 0;
+    loop {
+        let mut line = Vec::new();
+        let n = input
+            .by_ref()
+            .take((MAX_HEAD - read) as u64)
+            .read_until(b'\n', &mut line)?;
+        read += n;
+        if n == 0 || !line.ends_with(b"\n") {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "incomplete or oversized reply head",
+            ));
+        }
+        let line = String::from_utf8(line).map_err(|_| invalid("reply head is not UTF-8"))?;
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.is_empty() {
+            break;
+        }
+        lines.push(line.to_string());
+    }
+    let mut status_line = lines
+        .first()
+        .ok_or_else(|| invalid("empty reply head"))?
+        .splitn(3, ' ');
+    let (Some(version), Some(status)) = (status_line.next(), status_line.next()) else {
+        return Err(invalid("malformed status line"));
+    };
+    if !version.starts_with("HTTP/1.") {
+        return Err(invalid("not an HTTP/1.x reply"));
+    }
+    let status = status
+        .parse()
+        .map_err(|_| invalid("malformed status line"))?;
+    let headers: Vec<(String, String)> = lines[1..]
+        .iter()
+        .filter_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            Some((name.trim().to_ascii_lowercase(), value.trim().to_string()))
+        })
+        .collect();
+    let header = |name: &str| headers.iter().find(|(n, _)| n == name).map(|(_, v)| v);
+    let body = if header("transfer-encoding").is_some_and(|v| v.eq_ignore_ascii_case("chunked")) {
+        Body::Chunked {
+            input,
+            left: 0,
+            done: false,
+        }
+    } else if let Some(length) = header("content-length") {
+        let left = length
+            .parse()
+            .map_err(|_| invalid("malformed Content-Length"))?;
+        Body::Length { input, left }
+    } else {
+        Body::Close(input)
+    };
+    Ok(Response {
+        status,
+        headers,
+        body,
+    })
+}
+
+impl Response {
+    /// The value of header `name` (in lowercase).
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// The body as text, for error replies: whatever of it arrived.
+    pub fn text(mut self) -> String {
+        let mut body = Vec::new();
+        let _ = self.read_to_end(&mut body);
+        String::from_utf8_lossy(&body).trim().to_string()
+    }
+}
+
+fn cut_short() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "the reply was cut short")
+}
+
+impl Read for Response {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match &mut self.body {
+            Body::Chunked { done: true, .. } => Ok(0),
+            Body::Chunked { input, left, done } => {
+                if *left == 0 {
+                    let mut line = String::new();
+                    if input.read_line(&mut line)? == 0 {
+                        return Err(cut_short());
+                    }
+                    let size = line.trim_end().split(';').next().unwrap_or("");
+                    *left = usize::from_str_radix(size.trim(), 16).map_err(|_| {
+                        io::Error::new(io::ErrorKind::InvalidData, "malformed chunk size")
+                    })?;
+                    if *left == 0 {
+                        // The trailer, up to its empty line
+                        loop {
+                            line.clear();
+                            if input.read_line(&mut line)? == 0 {
+                                return Err(cut_short());
+                            }
+                            if line.trim_end().is_empty() {
+                                break;
+                            }
+                        }
+                        *done = true;
+                        return Ok(0);
+                    }
+                }
+                let want = buf.len().min(*left);
+                let n = input.read(&mut buf[..want])?;
+                if n == 0 {
+                    return Err(cut_short());
+                }
+                *left -= n;
+                if *left == 0 {
+                    let mut end = [0; 2];
+                    input.read_exact(&mut end).map_err(|_| cut_short())?;
+                    if &end != b"\r\n" {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "malformed chunk",
+                        ));
+                    }
+                }
+                Ok(n)
+            }
+            Body::Length { left: 0, .. } => Ok(0),
+            Body::Length { input, left } => {
+                let want = buf.len().min(usize::try_from(*left).unwrap_or(usize::MAX));
+                let n = input.read(&mut buf[..want])?;
+                if n == 0 {
+                    return Err(cut_short());
+                }
+                *left -= n as u64;
+                Ok(n)
+            }
+            Body::Close(input) => input.read(buf),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::listen::Listener;
+    use crate::serve::{run, Policy, Reply};
+
+    #[test]
+    fn servers_parse_into_addresses() {
+        let server = Server::parse("http://gen.internal").unwrap();
+        assert_eq!(server.address, Address::Tcp("gen.internal:80".to_string()));
+        assert_eq!(server.to_string(), "http://gen.internal:80");
+        let server = Server::parse("http://[::1]:8443/").unwrap();
+        assert_eq!(server.address, Address::Tcp("[::1]:8443".to_string()));
+        assert_eq!(server.host, "::1");
+        #[cfg(feature = "tls")]
+        assert_eq!(
+            Server::parse("https://gen.internal").unwrap().address,
+            Address::Tcp("gen.internal:443".to_string())
+        );
+        assert!(Server::parse("unix:/run/mpg.sock").is_ok());
+        let (server, path) = Server::parse_url("http://api:8080/v1/users?dry=1").unwrap();
+        assert_eq!(
+            (server.to_string(), path.as_str()),
+            ("http://api:8080".to_string(), "/v1/users?dry=1")
+        );
+        assert_eq!(Server::parse_url("http://api?x=1").unwrap().1, "/?x=1");
+        assert_eq!(Server::parse_url("http://api").unwrap().1, "/");
+        assert!(Server::parse_url("unix:/run/mpg.sock").is_err());
+        for bad in [
+            "gen.internal:8080",
+            "http://",
+            "http://gen.internal/passwords",
+            "http://user@gen.internal",
+            "http://gen.internal:http",
+        ] {
+            assert!(Server::parse(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn bodies_cut_short_are_errors() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = Server::parse(&format!("http://{}", listener.local_addr().unwrap())).unwrap();
+        let policy = Policy {
+            token: Some("t".to_string()),
+            ..Default::default()
+        };
+        std::thread::spawn(move || {
+            run(Listener::Tcp(listener), &policy, |request| {
+                match request.path.as_str() {
+                    "/whole" => Reply::Stream {
+                        content_type: "text/plain",
+                        headers: vec![("X-Records", "2".to_string())],
+                        pulled: None,
+                        body: Box::new(|out| out.write_all(b"a\nb\n")),
+                    },
+                    "/cut" => Reply::Stream {
+                        content_type: "text/plain",
+                        headers: Vec::new(),
+                        pulled: None,
+                        body: Box::new(|out| {
+                            out.write_all(b"a\n")?;
+                            out.flush()?;
+                            Err(io::Error::other("generator failed"))
+                        }),
+                    },
+                    _ => Reply::Error(404, "no such route".to_string()),
+                }
+            })
+        });
+
+        let client = Client::new(server.clone(), Some("t".to_string()));
+        let mut reply = client.request("GET", "/whole", &[], b"").unwrap();
+        assert_eq!((reply.status, reply.header("x-records")), (200, Some("2")));
+        let mut body = String::new();
+        reply.read_to_string(&mut body).unwrap();
+        assert_eq!(body, "a\nb\n");
+
+        let mut reply = client.request("GET", "/cut", &[], b"").unwrap();
+        let mut body = Vec::new();
+        let error = reply.read_to_end(&mut body).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(body, b"a\n");
+
+        let reply = client.request("GET", "/nowhere", &[], b"").unwrap();
+        assert_eq!(reply.status, 404);
+        assert_eq!(reply.text(), "no such route");
+        let reply = Client::new(server, None)
+            .request("GET", "/whole", &[], b"")
+            .unwrap();
+        assert_eq!(reply.status, 401);
+    }
+}
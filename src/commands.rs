@@ -0,0 +1,1325 @@
+//! The subcommands that read, check or manage what runs produce: `cat`,
+//! `pick`, `slice`, `reserve`, `fetch`, `rewrite`, `loadtest`, `derive`, `verify`,
+//! `export-replay-bundle`, `unlock`, `shred`, `rotation-plan`,
+//! `validate-token`, `detect-watermark`, `usage`, `catalog`,
+//! `probe-storage`, `status`, `run-hooks`, `verify-remote`, `key`,
+//! `completions`, `bench` and `soak`. Each prints its findings and fails
+//! with a [`Failure`](crate::failure::Failure) when they are bad.
+
+use std::fs::File;
+use std::io::{self, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+
+use clap::CommandFactory;
+
+use mass_password_gen_optimized::corpus::Record;
+use mass_password_gen_optimized::timestamp::format_rfc3339;
+use mass_password_gen_optimized::token::{self, TokenValidator};
+use mass_password_gen_optimized::{
+    analysis, archive, key_fingerprint, parse_key_hex, random_key, rewrite, rotation, Charset,
+    Cipher, CorpusReader, Format, Key, Manifest, PasswordGenerator, Watermark,
+};
+#[cfg(feature = "reservations")]
+use {crate::CorpusArgs, mass_password_gen_optimized::ReservationStore};
+#[cfg(feature = "derive")]
+use {
+    crate::Mode,
+    mass_password_gen_optimized::{hex, MasterKey, Site},
+    std::io::IsTerminal,
+};
+
+use crate::recipient::Recipient;
+use crate::{
+    batch, bench, bundle, catalog, client, failure, fetch, hooks, keyprovider, keyring, loadtest,
+    memory, permissions, seal, serve, shred, soak, storage, usage,
+};
+use crate::{
+    published_key, unix_now, BenchArgs, FetchArgs, KeyAction, LoadtestArgs, RecordArgs, SoakArgs,
+};
+use crate::{BENCHMARK_KEY, CIPHER_PROBE, STATUS_TO_STDERR};
+
+/// Least time between `soak`'s progress lines.
+const SOAK_REPORT_EVERY: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// Passwords per chunk for `loadtest`, which takes them one at a time.
+const LOADTEST_CHUNK: usize = 64 * 1024;
+
+/// Runs `cat`: writes the selected records to stdout in `format`.
+pub fn cat(
+    reader: &CorpusReader,
+    range: Option<std::ops::Range<usize>>,
+    format: Format,
+) -> Result<(), Box<dyn std::error::Error>> {
+    to_stdout(|out| cat_to(reader, range, format, out).map(drop))
+}
+
+/// Runs `pick`: writes the records at `indices`, in the order given, to
+/// stdout in `format`. Each is found through its shard's routing index.
+pub fn pick(
+    reader: &CorpusReader,
+    indices: &[usize],
+    format: Format,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let records = indices.iter().map(|&index| {
+        reader.range(index..index + 1).next().unwrap_or_else(|| {
+            Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("the corpus has no record {}", index),
+            ))
+        })
+    });
+    to_stdout(|out| write_records(reader, records, format, out).map(drop))
+}
+
+/// Runs `slice`: writes records `range` of `reader` in `format` to a new
+/// file at `out`, readable by its owner only.
+pub fn slice(
+    reader: &CorpusReader,
+    range: std::ops::Range<usize>,
+    format: Format,
+    out: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let file = permissions::create(out, permissions::DEFAULT_MODE)
+        .map_err(|e| format!("{}: {}", out.display(), e))?;
+    let mut file = io::BufWriter::new(file);
+    let written = cat_to(reader, Some(range.clone()), format, &mut file)
+        .and_then(|written| file.flush().map(|()| written))
+        .map_err(|e| format!("{}: {}", out.display(), e))?;
+    status!(
+        "Wrote {} records, from {}, to {}",
+        written,
+        range.start,
+        out.display()
+    );
+    Ok(())
+}
+
+/// Runs `write` on a buffered stdout, flushing it after.
+fn to_stdout(
+    write: impl FnOnce(&mut dyn Write) -> io::Result<()>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut out = io::BufWriter::new(io::stdout().lock());
+    match write(&mut out).and_then(|()| out.flush()) {
+        // Stopping early, as with `| head`, is not a failure
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        result => Ok(result.map_err(|e| e.to_string())?),
+    }
+}
+
+/// Writes records `range` (all, without one) of `reader` to `out` in
+/// `format`; gives how many.
+pub fn cat_to(
+    reader: &CorpusReader,
+    range: Option<std::ops::Range<usize>>,
+    format: Format,
+    out: &mut dyn Write,
+) -> io::Result<usize> {
+    let records = match range {
+        Some(range) => reader.range(range),
+        None => reader.records(),
+    };
+    write_records(reader, records, format, out)
+}
+
+/// Writes `records`, read from `reader`, to `out` in `format`; gives how
+/// many.
+fn write_records(
+    reader: &CorpusReader,
+    mut records: impl Iterator<Item = io::Result<Record>>,
+    format: Format,
+    out: &mut dyn Write,
+) -> io::Result<usize> {
+    let binary = reader.manifest().binary;
+    let sink = format.sink_for_records(binary);
+    let mut buf = sink.as_ref().map_or(Vec::new(), |s| s.header().to_vec());
+    let mut written = 0;
+    let result = records.try_for_each(|record| {
+        let record = record?;
+        written += 1;
+        match &sink {
+            Some(sink) => sink.write_record(record.index, &record.password, &mut buf),
+            None => {
+                buf.extend_from_slice(&record.password);
+                if !binary {
+                    buf.push(b'\n');
+                }
+            }
+        }
+        if buf.len() >= 1 << 16 {
+            out.write_all(&buf)?;
+            buf.clear();
+        }
+        io::Result::Ok(())
+    });
+    result.and_then(|()| out.write_all(&buf))?;
+    Ok(written)
+}
+
+/// Runs a `key` subcommand.
+pub fn manage_keys(action: &KeyAction) -> Result<(), Box<dyn std::error::Error>> {
+    let store = keyring::open()?;
+    match action {
+        KeyAction::Store {
+            name,
+            stdin,
+            bits,
+            force,
+        } => {
+            if !force {
+                match store.load(name) {
+                    Ok(_) => {
+                        return Err(format!(
+                            "a key named `{}` already exists in {} (use --force to replace it)",
+                            name,
+                            store.describe()
+                        )
+                        .into())
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            let key = if *stdin {
+                let mut line = String::new();
+                io::stdin().read_line(&mut line)?;
+                parse_key_hex(line.trim())?
+            } else {
+                Key::random(bits / 8)?
+            };
+            store.store(name, &key)?;
+            println!(
+                "Stored key `{}` (fingerprint {}) in {}",
+                name,
+                key_fingerprint(&key),
+                store.describe()
+            );
+        }
+        KeyAction::List => {
+            let mut names = store.list()?;
+            names.sort();
+            if names.is_empty() {
+                println!("No keys stored in {}", store.describe());
+            }
+            for name in names {
+                println!("{}", name);
+            }
+        }
+        KeyAction::Delete { name } => {
+            store.delete(name)?;
+            println!("Deleted key `{}` from {}", name, store.describe());
+        }
+    }
+    Ok(())
+}
+
+/// Runs `reserve`: claims `count` records and writes them to stdout.
+#[cfg(feature = "reservations")]
+pub fn reserve(
+    corpus: &CorpusArgs,
+    count: usize,
+    store: Option<&Path>,
+    claimant: Option<&str>,
+    format: Format,
+) -> Result<(), Box<dyn std::error::Error>> {
+    STATUS_TO_STDERR.store(true, Ordering::Relaxed);
+    let reader = corpus.open()?;
+    let default_store = PathBuf::from(format!("{}.reservations", corpus.input.display()));
+    let store = store.unwrap_or(&default_store);
+    // A lone file does not say how many records it holds
+    let total = match reader.count() {
+        Some(total) => total,
+        None => reader.records().try_fold(0, |n, r| r.map(|_| n + 1))?,
+    };
+    let mut store = ReservationStore::open(store, total).map_err(|e| e.to_string())?;
+    let claimed = store.reserve(count, claimant).map_err(|e| e.to_string())?;
+    status!(
+        "Claimed records {}..{} of {} ({} left)",
+        claimed.start,
+        claimed.end,
+        total,
+        store.remaining().map_err(|e| e.to_string())?
+    );
+    cat(&reader, Some(claimed.clone()), format)?;
+    store.delivered(claimed.start).map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+/// A client of `server`, sending the token of `token_file`.
+fn client(
+    server: &client::Server,
+    token_file: Option<&Path>,
+    #[cfg(feature = "tls")] tls: &crate::ClientTlsArgs,
+) -> Result<client::Client, String> {
+    let token = match token_file {
+        Some(path) => Some(serve::read_token(path)?),
+        None => None,
+    };
+    #[allow(unused_mut)]
+    let mut client = client::Client::new(server.clone(), token);
+    #[cfg(feature = "tls")]
+    if server.is_https() {
+        let ca = tls
+            .ca_cert
+            .as_deref()
+            .ok_or("https:// needs --ca-cert: no CAs are trusted but those given")?;
+        let identity = tls.client_cert.as_deref().zip(tls.client_key.as_deref());
+        client = client.with_tls(crate::tls::client_config(ca, identity)?);
+    }
+    Ok(client)
+}
+
+/// Runs `fetch`: pulls a `serve --run-key` server's run into a file.
+pub fn fetch(args: &FetchArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let client = client(
+        &args.server,
+        args.token_file.as_deref(),
+        #[cfg(feature = "tls")]
+        &args.tls,
+    )?;
+    let options = fetch::Options {
+        count: args.count,
+        page: args.page_size,
+        format: args.format,
+        retries: args.retries,
+        backoff: std::time::Duration::from_secs(1),
+        resume: args.resume,
+    };
+    let (records, fingerprint) = fetch::fetch(&client, &args.output, &options)?;
+    status!(
+        "Fetched {} records of run key {} from {} into {}",
+        records,
+        fingerprint,
+        args.server,
+        args.output.display()
+    );
+    Ok(())
+}
+
+/// Runs `rewrite`: copies the CSV `input` to `output` with a new
+/// password in column `column_name` of each row.
+pub fn rewrite(
+    input: &Path,
+    column_name: &str,
+    output: &Path,
+    key: Option<Key>,
+    records: &RecordArgs,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let to_stdout = output == Path::new("-");
+    STATUS_TO_STDERR.store(to_stdout, Ordering::Relaxed);
+    let open = || {
+        File::open(input)
+            .map(BufReader::new)
+            .map_err(|e| format!("{}: {}", input.display(), e))
+    };
+    let (column, rows) =
+        rewrite::survey(open()?, column_name).map_err(|e| format!("{}: {}", input.display(), e))?;
+    if !to_stdout && std::fs::canonicalize(output).ok() == Some(std::fs::canonicalize(input)?) {
+        return Err("--output would overwrite --input as it is read; write elsewhere".into());
+    }
+    if rows == 0 {
+        return Err(format!("{}: no rows to give passwords", input.display()).into());
+    }
+    let key = match key {
+        Some(key) => key,
+        None => random_key()?.into(),
+    };
+    let mut builder = PasswordGenerator::builder().key(key);
+    builder = records.configure(builder)?.count(rows);
+    let generator = builder.build()?;
+    if records.sink(&generator)?.is_some() {
+        return Err("rewrite writes the passwords as CSV fields; leave --format at raw".into());
+    }
+    let out: Box<dyn Write> = match to_stdout {
+        true => Box::new(io::stdout().lock()),
+        false => Box::new(
+            permissions::create(output, permissions::DEFAULT_MODE)
+                .map_err(|e| format!("{}: {}", output.display(), e))?,
+        ),
+    };
+    rewrite::rewrite(open()?, column, &generator, io::BufWriter::new(out))
+        .map_err(|e| format!("{}: {}", input.display(), e))?;
+    status!(
+        "Rewrote the {} column of {} rows, with passwords under key {}",
+        column_name,
+        rows,
+        key_fingerprint(key.as_bytes())
+    );
+    Ok(())
+}
+
+/// Runs `loadtest`: sends a request per record to the target, and prints
+/// how it held up.
+pub fn loadtest(
+    args: &LoadtestArgs,
+    records: &RecordArgs,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let json = args.json.as_deref();
+    STATUS_TO_STDERR.store(json == Some(Path::new("-")), Ordering::Relaxed);
+    let (server, path) = &args.target;
+    let template = std::fs::read_to_string(&args.body_template)
+        .map_err(|e| format!("{}: {}", args.body_template.display(), e))?;
+    loadtest::check_template(&template)
+        .map_err(|e| format!("{}: {}", args.body_template.display(), e))?;
+    let client = client(
+        server,
+        args.token_file.as_deref(),
+        #[cfg(feature = "tls")]
+        &args.tls,
+    )?;
+    let key = random_key()?;
+    let generator = records
+        .configure(
+            PasswordGenerator::builder()
+                .key(key)
+                .chunk_size(LOADTEST_CHUNK),
+        )?
+        .build()?;
+    if records.sink(&generator)?.is_some() {
+        return Err("loadtest sends the bare credentials; leave --format at raw".into());
+    }
+    let mut headers = vec![("Content-Type".to_string(), args.content_type.clone())];
+    headers.extend(args.headers.iter().cloned());
+    let options = loadtest::Options {
+        method: args.method.clone(),
+        path: path.clone(),
+        headers,
+        template,
+        concurrency: args.concurrency.into(),
+        rate: args.rate,
+    };
+    status!(
+        "Sending {} requests to {}{} from {} workers, with credentials under key {}",
+        generator.count(),
+        server,
+        path,
+        options.concurrency,
+        key_fingerprint(&key)
+    );
+    let report = loadtest::run(&client, &generator, &options);
+    status!(
+        "{} requests in {:.2}s ({:.0}/s): {} ok, {} errors ({:.2}%)",
+        report.requests,
+        report.seconds,
+        report.requests_per_sec,
+        report.ok,
+        report.requests - report.ok,
+        report.error_rate * 100.0
+    );
+    for (status, n) in &report.statuses {
+        status!("  {}: {}", status, n);
+    }
+    if report.failed > 0 {
+        status!(
+            "  no reply: {} (first: {})",
+            report.failed,
+            report.first_failure.as_deref().unwrap_or("")
+        );
+    }
+    if let Some(ms) = &report.latency_ms {
+        status!(
+            "Latency ms p50/p90/p99/max: {:.2} / {:.2} / {:.2} / {:.2}",
+            ms.p50,
+            ms.p90,
+            ms.p99,
+            ms.max
+        );
+    }
+    match json {
+        None => {}
+        Some(path) if path == Path::new("-") => {
+            println!("{}", serde_json::to_string_pretty(&report)?)
+        }
+        Some(path) => std::fs::write(path, serde_json::to_string_pretty(&report)? + "\n")?,
+    }
+    Ok(())
+}
+
+/// Runs `derive`: prints `site`'s password under the master passphrase.
+#[cfg(feature = "derive")]
+pub fn derive(
+    site: Site,
+    user: &str,
+    records: &RecordArgs,
+) -> Result<(), Box<dyn std::error::Error>> {
+    STATUS_TO_STDERR.store(true, Ordering::Relaxed);
+    let passphrase = if io::stdin().is_terminal() {
+        rpassword::prompt_password("Master passphrase: ")?
+    } else {
+        let mut line = String::new();
+        io::stdin().read_line(&mut line)?;
+        line.trim_end_matches(['\r', '\n']).to_string()
+    };
+    let master = MasterKey::from_passphrase(passphrase.as_bytes(), user)?;
+    status!(
+        "Master key fingerprint {} (it changes with any typo in the passphrase)",
+        key_fingerprint(master.key())
+    );
+    let password = master
+        .password(&site, records.configure(PasswordGenerator::builder())?)
+        .map_err(|e| e.to_string())?;
+    if records.charset.is_none() && records.template.is_none() && records.mode == Mode::Password {
+        println!("{}", hex(&password));
+    } else {
+        println!("{}", String::from_utf8_lossy(&password));
+    }
+    Ok(())
+}
+
+/// Runs `verify` and prints what it found.
+pub fn verify_archive(
+    path: Option<&Path>,
+    record: Option<usize>,
+    bundle_dir: Option<&Path>,
+    identity: Option<&Path>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let bundled = bundle_dir.map(bundle::open).transpose()?;
+    let path = match (path, &bundled) {
+        (Some(path), _) => path,
+        (None, Some((bundle, _))) => &bundle.corpus,
+        (None, None) => unreachable!("clap requires a corpus or a bundle"),
+    };
+    if let Some(index) = record {
+        return verify_record(path, index, bundle_dir.zip(bundled.as_ref()), identity);
+    }
+    let mut report = archive::verify(path)?;
+    let stub = &report.stub;
+    let records = match &stub.records {
+        archive::RecordSpec::Raw => "raw 16-byte blocks".to_string(),
+        archive::RecordSpec::Text {
+            alphabet,
+            length,
+            lengths,
+            ..
+        } => {
+            format!(
+                "{} characters of a {}-symbol alphabet",
+                lengths.as_deref().unwrap_or(&length.to_string()),
+                alphabet.len()
+            )
+        }
+        archive::RecordSpec::Template { template } => format!("passwords of template {}", template),
+        archive::RecordSpec::Passphrase { words, .. } => format!("{}-word passphrases", words),
+    };
+    match (report.archive_version, &report.created_by) {
+        (Some(version), Some(created_by)) => {
+            println!("Archive version {}, written by {}", version, created_by)
+        }
+        _ => println!("Sharded corpus, {} shards", report.manifest.shards.len()),
+    }
+    println!(
+        "Scheme: {}; {} with {} IVs, chunks of {}; {} records, {}",
+        stub.scheme, stub.cipher, stub.iv_strategy, stub.chunk_size, stub.count, records
+    );
+    println!(
+        "Shards intact:       {} of {}",
+        report.intact_shards,
+        report.manifest.shards.len()
+    );
+    println!(
+        "Vectors reproduced:  {} of {}",
+        report.vectors_passed,
+        stub.kat.vectors.len()
+    );
+    println!("Records read back:   {}", report.records);
+    if let (Some(dir), Some((bundle, manifest))) = (bundle_dir, &bundled) {
+        let (rederived, problems) = bundle::check(dir, bundle, manifest, path, &report, identity)?;
+        println!(
+            "Records re-derived:  {} of {}",
+            rederived, report.stub.count
+        );
+        report.problems.extend(problems);
+    }
+    verdict(path, &report.problems)
+}
+
+/// Runs `verify --record`: checks record `index` of the corpus at `path`
+/// and the shard holding it, and with a `bundle` opened from its directory,
+/// generates the record again.
+fn verify_record(
+    path: &Path,
+    index: usize,
+    bundle: Option<(&Path, &(bundle::Bundle, Manifest))>,
+    identity: Option<&Path>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut check = archive::verify_record(path, index)?;
+    let yes_no = |ok: bool| if ok { "yes" } else { "no" };
+    println!(
+        "Record {}, in shard {} of {}",
+        index,
+        check.shard,
+        check.manifest.shards.len()
+    );
+    println!("Shard intact:        {}", yes_no(check.shard_intact));
+    println!("Record read back:    {}", yes_no(check.record.is_some()));
+    if let Some((dir, (bundle, manifest))) = bundle {
+        let problems = bundle::check_record(dir, bundle, manifest, path, &check, identity)?;
+        let rederived = check.record.is_some() && problems.is_empty();
+        println!("Record re-derived:   {}", yes_no(rederived));
+        check.problems.extend(problems);
+    }
+    verdict(path, &check.problems)
+}
+
+/// Prints `verify`'s verdict on `path` from the `problems` found, failing
+/// if there are any.
+fn verdict(path: &Path, problems: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    if problems.is_empty() {
+        println!("Verdict: OK");
+        return Ok(());
+    }
+    for problem in problems {
+        println!("Problem: {}", problem);
+    }
+    Err(failure::Failure::Verification(format!(
+        "{} failed verification ({} problems)",
+        path.display(),
+        problems.len()
+    ))
+    .into())
+}
+
+/// Runs `export-replay-bundle`: writes the bundle of `corpus`, made with
+/// the key given, into `out` with the key encrypted to `to`.
+pub fn export_bundle(
+    corpus: &Path,
+    key_hex: Option<Key>,
+    key: Option<&keyprovider::KeyUri>,
+    to: &Recipient,
+    out: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let key = match (key_hex, key) {
+        (Some(key), _) => key,
+        (None, Some(uri)) => uri.load().map_err(failure::Failure::Key)?,
+        (None, None) => unreachable!("clap requires a key"),
+    };
+    let bundle = bundle::export(corpus, &key, to, out)?;
+    println!(
+        "Bundle written to {}: the run's key (fingerprint {}) encrypted to {} in {}, \
+         the manifest, and checks {} pinned",
+        out.display(),
+        bundle.key_fingerprint,
+        bundle.recipient,
+        bundle.key.display(),
+        bundle.verification.checks.join(", ")
+    );
+    println!(
+        "The auditor runs `verify --bundle {}`, naming the corpus if it is no longer at {}",
+        out.display(),
+        bundle.corpus.display()
+    );
+    Ok(())
+}
+
+/// Runs `unlock`.
+pub fn unlock(
+    manifest: &Path,
+    shares: &[PathBuf],
+    identity: Option<&Path>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let unlocked = seal::unlock(manifest, shares, identity)?;
+    println!(
+        "Unlocked with the shares of {}: {} shards decrypted{}",
+        unlocked.custodians.join(", "),
+        unlocked.opened,
+        match unlocked.already {
+            0 => String::new(),
+            n => format!(", {} already open", n),
+        }
+    );
+    println!("Check the run with `verify {}`", manifest.display());
+    Ok(())
+}
+
+/// Runs `shred`, and records what it destroyed in `audit_log`.
+pub fn shred(
+    manifest_path: &Path,
+    audit_log: Option<&Path>,
+    dry_run: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let manifest = Manifest::load(manifest_path)?;
+    let files = shred::artifacts(manifest_path, &manifest);
+    if dry_run {
+        for path in &files {
+            println!("{}", path.display());
+        }
+        println!("{} files would be destroyed", files.len());
+        return Ok(());
+    }
+    // Opened first, so a log that cannot be written stops the shred
+    let log = audit_log
+        .map(|path| serve::AuditLog::open(path).map_err(|e| format!("{}: {}", path.display(), e)))
+        .transpose()?;
+    let mut shredded = Vec::new();
+    let mut failed = None;
+    for path in &files {
+        match shred::shred_file(path) {
+            Ok(file) => {
+                println!(
+                    "{}: {} bytes {}{}",
+                    file.path.display(),
+                    file.bytes,
+                    match file.erasure {
+                        shred::Erasure::Overwritten => "overwritten and unlinked",
+                        shred::Erasure::UnlinkedOnly => "unlinked (copy-on-write filesystem)",
+                    },
+                    if file.trimmed { ", trimmed" } else { "" }
+                );
+                shredded.push(file);
+            }
+            Err(e) => {
+                failed = Some(format!("{}: {}", path.display(), e));
+                break;
+            }
+        }
+    }
+    if let Some(log) = log {
+        let at = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0.0, |d| d.as_secs_f64());
+        let mut entry = serde_json::json!({
+            "at": (at * 1000.0).round() / 1000.0,
+            "event": "shred",
+            "manifest": manifest_path,
+            "run_id": manifest.run_id,
+            "key_fingerprint": manifest.parameters.and_then(|stub| stub.key_fingerprint),
+            "files": shredded,
+        });
+        if let Some(error) = &failed {
+            entry["error"] = error.clone().into();
+        }
+        log.record(entry)?;
+    }
+    if let Some(error) = failed {
+        return Err(failure::Failure::Io(io::Error::other(format!(
+            "{}; run `shred` again to destroy the rest",
+            error
+        )))
+        .into());
+    }
+    let copy_on_write = shredded
+        .iter()
+        .filter(|file| file.erasure == shred::Erasure::UnlinkedOnly)
+        .count();
+    println!(
+        "Destroyed {} files of the run{}",
+        shredded.len(),
+        match copy_on_write {
+            0 => String::new(),
+            n => format!(
+                "; {} on copy-on-write filesystems were only unlinked, and their old blocks \
+                 remain until the filesystem reuses them",
+                n
+            ),
+        }
+    );
+    Ok(())
+}
+
+/// Runs `rotation-plan`: writes the plan from the `old` run to the `new`,
+/// each given as its manifest or archive and its key, to `output` or stdout.
+pub fn rotation_plan(
+    old: (&Path, &keyprovider::KeyUri),
+    new: (&Path, &keyprovider::KeyUri),
+    format: rotation::PlanFormat,
+    output: Option<&Path>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    STATUS_TO_STDERR.store(output.is_none(), Ordering::Relaxed);
+    let run = |(path, key): (&Path, &keyprovider::KeyUri)| {
+        let in_path = |e: io::Error| format!("{}: {}", path.display(), e);
+        let (stub, binary) = if archive::is_archive(path).map_err(in_path)? {
+            let index = archive::read_index(path).map_err(in_path)?;
+            (index.stub, index.manifest.binary)
+        } else {
+            let manifest = Manifest::load(path).map_err(|e| e.to_string())?;
+            let stub = manifest.parameters.ok_or_else(|| {
+                format!(
+                    "{}: the manifest does not record the run's parameters",
+                    path.display()
+                )
+            })?;
+            (stub, manifest.binary)
+        };
+        if stub.watermarked {
+            return Err(failure::Failure::Other(format!(
+                "{}: the records carry a watermark, whose key the run does not record",
+                path.display()
+            ))
+            .into());
+        }
+        let key = key.load().map_err(failure::Failure::Key)?;
+        let fingerprint = key_fingerprint(&key);
+        if let Some(expected) = stub.key_fingerprint.as_ref().filter(|f| **f != fingerprint) {
+            return Err(failure::Failure::Key(format!(
+                "{}: the key has fingerprint {}, the run's key {}",
+                path.display(),
+                fingerprint,
+                expected
+            ))
+            .into());
+        }
+        let generator = stub
+            .generator(key)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        Ok::<_, Box<dyn std::error::Error>>((generator, binary))
+    };
+    let (old_generator, old_binary) = run(old)?;
+    let (new_generator, new_binary) = run(new)?;
+    let mut out: Box<dyn Write> = match output {
+        Some(path) => Box::new(io::BufWriter::new(permissions::create(path, 0o600)?)),
+        None => Box::new(io::BufWriter::new(io::stdout().lock())),
+    };
+    let summary = rotation::write_plan(
+        &rotation::PlanRun {
+            generator: &old_generator,
+            binary: old_binary,
+        },
+        &rotation::PlanRun {
+            generator: &new_generator,
+            binary: new_binary,
+        },
+        format,
+        &mut out,
+    )
+    .and_then(|summary| out.flush().map(|()| summary))
+    .map_err(failure::Failure::Sink)?;
+    status!(
+        "Rotation plan: {} rotated, {} retired, {} issued",
+        summary.rotated,
+        summary.retired,
+        summary.issued
+    );
+    Ok(())
+}
+
+/// Runs `validate-token`.
+pub fn validate_tokens(
+    tokens: &[String],
+    validation_key: Option<[u8; 32]>,
+    key_hex: Option<Key>,
+    key: Option<&keyprovider::KeyUri>,
+    at: Option<u64>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let validation_key = match (validation_key, key_hex, key) {
+        (Some(validation_key), _, _) => validation_key,
+        (None, run_key, uri) => {
+            let key = match (run_key, uri) {
+                (Some(key), _) => key,
+                (None, Some(uri)) => uri.load().map_err(failure::Failure::Key)?,
+                (None, None) => unreachable!("clap requires a key"),
+            };
+            let validation_key = token::validation_key(&key);
+            status!(
+                "Validation key: {} (give services this, not the run's key)",
+                Key::from(validation_key).to_hex()
+            );
+            validation_key
+        }
+    };
+    let validator = TokenValidator::new(&validation_key);
+    let now = at.unwrap_or_else(unix_now);
+    let tokens: Box<dyn Iterator<Item = io::Result<String>>> = if tokens.is_empty() {
+        Box::new(io::stdin().lines())
+    } else {
+        Box::new(tokens.iter().cloned().map(Ok))
+    };
+    let (mut checked, mut rejected) = (0, 0);
+    for token in tokens {
+        let token = token?;
+        let token = token.trim();
+        if token.is_empty() {
+            continue;
+        }
+        checked += 1;
+        match validator.validate(token, now) {
+            Ok(token) => println!(
+                "valid: record {}, expires {}",
+                token.index,
+                format_rfc3339(token.expires_at)
+            ),
+            Err(e) => {
+                rejected += 1;
+                println!("rejected: {}", e);
+            }
+        }
+    }
+    if rejected > 0 {
+        let message = format!("{} of {} tokens rejected", rejected, checked);
+        return Err(failure::Failure::Verification(message).into());
+    }
+    Ok(())
+}
+
+/// Runs `detect-watermark` and prints the verdict.
+pub fn detect_watermark(
+    watermark: &Watermark,
+    input: &Path,
+    charset: Option<&Charset>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let alphabet = charset.map(Charset::alphabet);
+    let detection = if input == Path::new("-") {
+        watermark.detect(io::stdin().lock(), alphabet.as_deref())?
+    } else {
+        watermark.detect(BufReader::new(File::open(input)?), alphabet.as_deref())?
+    };
+
+    let expected = detection.selected as f64 * detection.chance;
+    let p_value = detection.p_value();
+    println!("Records checked:     {}", detection.checked);
+    println!("Selected by the key: {}", detection.selected);
+    println!(
+        "Carrying the mark:   {} (about {:.1} expected by chance)",
+        detection.matched, expected
+    );
+    println!("p-value:             {:.3e}", p_value);
+    if detection.selected == 0 {
+        println!("Verdict: inconclusive (no records selected)");
+    } else if p_value < 1e-9 {
+        println!("Verdict: watermark present");
+    } else {
+        println!("Verdict: no evidence of the watermark");
+    }
+    Ok(())
+}
+
+/// Runs `usage` and prints one row per tenant.
+pub fn show_usage(
+    ledger: Option<&Path>,
+    since: Option<u64>,
+    until: Option<u64>,
+    tenant: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let path = ledger.map_or_else(usage::default_ledger_path, Path::to_path_buf);
+    let ledger = usage::read(&path)?;
+    for line in &ledger.malformed {
+        eprintln!(
+            "Warning: {}:{}: skipped malformed ledger line",
+            path.display(),
+            line
+        );
+    }
+    let totals = usage::summarize(&ledger.entries, since, until, tenant);
+    if totals.is_empty() {
+        println!("No recorded runs in {}", path.display());
+        return Ok(());
+    }
+    println!(
+        "{:<24} {:>6} {:>16}  {:<16}  LAST (UTC)",
+        "TENANT", "RUNS", "RECORDS", "FIRST (UTC)"
+    );
+    for (name, summary) in &totals {
+        println!(
+            "{:<24} {:>6} {:>16}  {:<16}  {}",
+            name,
+            summary.runs,
+            summary.records,
+            usage::format_timestamp(summary.first),
+            usage::format_timestamp(summary.last)
+        );
+    }
+    Ok(())
+}
+
+/// Runs `catalog` and prints the inventory; fails if any run failed
+/// verification.
+/// `probe-storage`: measures the filesystem of `path`.
+pub fn probe_storage(
+    path: &Path,
+    threads: Option<usize>,
+    json: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    STATUS_TO_STDERR.store(json, Ordering::Relaxed);
+    let threads = threads.unwrap_or_else(rayon::current_num_threads);
+    let probe = storage::probe(path, threads)
+        .map_err(|e| format!("cannot probe {}: {}", path.display(), e))?;
+    probe.show();
+    if json {
+        println!("{}", serde_json::to_string_pretty(&probe)?);
+    }
+    Ok(())
+}
+
+/// Runs `completions`: prints the completion script for `shell`.
+pub fn completions(shell: clap_complete::Shell) -> Result<(), Box<dyn std::error::Error>> {
+    let mut command = crate::Args::command();
+    let name = command.get_name().to_string();
+    let mut script = Vec::new();
+    clap_complete::generate(shell, &mut command, name, &mut script);
+    match io::stdout().write_all(&script) {
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        result => Ok(result?),
+    }
+}
+
+pub fn show_status(run_dir: &Path, json: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let status = batch::read(run_dir)?;
+    match json {
+        true => println!("{}", serde_json::to_string_pretty(&status)?),
+        false => batch::show(run_dir, &status),
+    }
+    Ok(())
+}
+
+pub fn run_hooks(
+    manifest: &Path,
+    shards: &[usize],
+    failed: bool,
+    hook: &hooks::Hook,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let corpus = Manifest::load(manifest)?;
+    if corpus.run_id.is_none() && hook.keyed() {
+        return Err(format!(
+            "{} records no run ID to key the shards on; leave out {{key}}",
+            manifest.display()
+        )
+        .into());
+    }
+    let names: Vec<PathBuf> = corpus.shards.into_iter().map(|shard| shard.path).collect();
+    let shards = match (failed, shards.is_empty()) {
+        (true, _) => hooks::failed_shards(manifest)?,
+        (false, true) => (1..=names.len()).collect(),
+        (false, false) => shards.to_vec(),
+    };
+    if failed && shards.is_empty() {
+        status!(
+            "The hook has failed on no shard of {}; nothing to run",
+            manifest.display()
+        );
+        return Ok(());
+    }
+    if let Some(n) = shards.iter().find(|&&n| n == 0 || n > names.len()) {
+        return Err(format!("the run has shards 1 to {}, not {}", names.len(), n).into());
+    }
+    let (todo, to_hook) = std::sync::mpsc::channel();
+    for n in &shards {
+        todo.send((n - 1, names[n - 1].clone()))?;
+    }
+    drop(todo);
+    let dir = manifest.parent().unwrap_or(Path::new(""));
+    hook.shards(corpus.run_id.as_deref(), dir, to_hook);
+    hook.check(manifest, permissions::DEFAULT_MODE)
+        .map_err(failure::Failure::Other)?;
+    status!(
+        "Ran the hook on {} shard{}",
+        shards.len(),
+        if shards.len() == 1 { "" } else { "s" }
+    );
+    Ok(())
+}
+
+/// Runs `verify-remote`, and `--verify-remote` once a run's hooks are
+/// done: reads back each shard of the run with manifest `manifest_path`
+/// through `read_back` and checks it against the manifest's hash, removing
+/// the local shard once it matches if `remove_local`.
+pub fn verify_remote(
+    manifest_path: &Path,
+    read_back: &str,
+    remove_local: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let manifest = Manifest::load(manifest_path)?;
+    if manifest.run_id.is_none() && read_back.contains("{key}") {
+        return Err(format!(
+            "{} records no run ID to key the shards on; leave out {{key}}",
+            manifest_path.display()
+        )
+        .into());
+    }
+    // Their hashes are of the shards before they were encrypted
+    if let Some(n) = manifest
+        .shards
+        .iter()
+        .position(|s| s.encrypted_to.is_some())
+    {
+        return Err(format!(
+            "shard {} was encrypted after it was hashed, so its remote copy cannot be \
+             checked against the manifest",
+            n + 1
+        )
+        .into());
+    }
+    let dir = manifest_path.parent().unwrap_or(Path::new(""));
+    let mut problems = Vec::new();
+    let mut removed = 0;
+    for (n, shard) in manifest.shards.iter().enumerate() {
+        let path = dir.join(&shard.path);
+        let key = manifest
+            .run_id
+            .as_deref()
+            .map(|id| hooks::shard_key(id, n + 1));
+        let problem = match (
+            hooks::read_back(read_back, n + 1, &path, key.as_deref()),
+            &shard.sha256,
+        ) {
+            (Ok(actual), Some(expected)) if actual == *expected => None,
+            (Ok(_), Some(_)) => Some("its remote copy's SHA-256 does not match".to_string()),
+            (Ok(_), None) => Some("no hash recorded".to_string()),
+            (Err(e), _) => Some(e),
+        };
+        if let Some(problem) = problem {
+            status!("Shard {}: {}", n + 1, problem);
+            problems.push(format!("shard {}: {}", n + 1, problem));
+            continue;
+        }
+        status!("Shard {}: remote copy matches", n + 1);
+        if remove_local {
+            match std::fs::remove_file(&path) {
+                Ok(()) => removed += 1,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(format!("{}: {}", path.display(), e).into()),
+            }
+        }
+    }
+    let shards = manifest.shards.len();
+    status!(
+        "{} of {} remote shards match {}{}",
+        shards - problems.len(),
+        shards,
+        manifest_path.display(),
+        match remove_local {
+            true => format!("; removed {} local shards", removed),
+            false => String::new(),
+        }
+    );
+    if !problems.is_empty() {
+        return Err(failure::Failure::Verification(problems.join("; ")).into());
+    }
+    Ok(())
+}
+
+pub fn show_catalog(
+    roots: &[PathBuf],
+    json: Option<&Path>,
+    verify: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    STATUS_TO_STDERR.store(json == Some(Path::new("-")), Ordering::Relaxed);
+    let runs = catalog::collect(roots, verify)?;
+    status!("{}", catalog::table(&runs));
+    for run in &runs {
+        for problem in &run.problems {
+            status!("{}: {}", run.path.display(), problem);
+        }
+    }
+    match json {
+        None => {}
+        Some(path) if path == Path::new("-") => {
+            println!("{}", serde_json::to_string_pretty(&runs)?)
+        }
+        Some(path) => std::fs::write(path, serde_json::to_string_pretty(&runs)? + "\n")?,
+    }
+    let failed = runs
+        .iter()
+        .filter(|run| run.verification == catalog::Verdict::Failed)
+        .count();
+    if failed > 0 {
+        let message = format!("{} of {} runs failed verification", failed, runs.len());
+        return Err(failure::Failure::Verification(message).into());
+    }
+    Ok(())
+}
+
+/// Runs `bench` and prints the results.
+pub fn run_bench(
+    records: &RecordArgs,
+    options: &BenchArgs,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let BenchArgs {
+        chunk_size,
+        cipher,
+        warmup,
+        warmup_secs,
+        iterations,
+        ref threads,
+        ref json,
+        naive_baseline,
+        ref compare,
+        max_regression,
+    } = *options;
+    let baseline: Option<bench::Report> = match compare {
+        Some(path) => Some(
+            serde_json::from_str(&std::fs::read_to_string(path)?)
+                .map_err(|e| format!("{} is not a bench --json report: {}", path.display(), e))?,
+        ),
+        None => None,
+    };
+    let json = json.as_deref();
+    STATUS_TO_STDERR.store(json == Some(Path::new("-")), Ordering::Relaxed);
+    let cipher = cipher
+        .pinned()
+        .unwrap_or_else(|| Cipher::fastest(CIPHER_PROBE));
+    let generator = records
+        .configure(
+            PasswordGenerator::builder()
+                .key(published_key(BENCHMARK_KEY, cipher))
+                .cipher(cipher)
+                .chunk_size(chunk_size),
+        )?
+        .build()?;
+    let sink = records.sink(&generator)?;
+    let threads = match threads.as_slice() {
+        [] => bench::default_thread_counts(
+            std::thread::available_parallelism().map_or(1, |n| n.get()),
+        ),
+        _ => threads.to_vec(),
+    };
+    status!(
+        "Benchmarking {} records ({}, {} format) with {}, chunks of {}: {} warm-up and {} measured \
+         iterations per thread count\n",
+        generator.count(),
+        records.encoding(),
+        records.format,
+        cipher,
+        generator.chunk_size(),
+        warmup,
+        iterations
+    );
+    let results = bench::run(
+        &generator,
+        sink.as_deref(),
+        &threads,
+        warmup,
+        iterations,
+        warmup_secs,
+    )?;
+    status!("{}", bench::table(&results));
+    status!(
+        "\n{}",
+        bench::split_table(&results, warmup_secs.as_secs_f64())
+    );
+    let naive = naive_baseline.then(|| {
+        status!(
+            "\nTiming the naive baseline on {} records...",
+            generator.count().min(bench::NAIVE_MAX_RECORDS)
+        );
+        bench::naive_baseline(&generator, warmup, iterations)
+    });
+    if let Some(baseline) = &naive {
+        status!("{}", bench::comparison(baseline, &results));
+    }
+    let report = bench::Report {
+        records: generator.count(),
+        encoding: records.encoding(),
+        cipher: cipher.to_string(),
+        chunk_size: generator.chunk_size(),
+        warmup,
+        warmup_seconds: warmup_secs.as_secs_f64(),
+        iterations,
+        results,
+        naive,
+    };
+    match json {
+        Some(path) if path == Path::new("-") => {
+            println!("{}", serde_json::to_string_pretty(&report)?)
+        }
+        Some(path) => std::fs::write(path, serde_json::to_string_pretty(&report)? + "\n")?,
+        None => {}
+    }
+    if let Some(baseline) = &baseline {
+        for difference in bench::mismatches(baseline, &report) {
+            eprintln!("Warning: {}", difference);
+        }
+        let changes = bench::compare(baseline, &report);
+        if changes.is_empty() {
+            eprintln!("Warning: the baseline measured none of these thread counts");
+        }
+        status!("\nAgainst the baseline:\n{}", bench::change_table(&changes));
+        if let Some(limit) = max_regression {
+            let regressed: Vec<String> = changes
+                .iter()
+                .filter(|c| c.percent() < -limit)
+                .map(|c| format!("{} thread(s) {:+.1}%", c.threads, c.percent()))
+                .collect();
+            if !regressed.is_empty() {
+                return Err(failure::Failure::Verification(format!(
+                    "throughput regressed by more than {}%: {}",
+                    limit,
+                    regressed.join(", ")
+                ))
+                .into());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Runs `soak`: passes under fresh keys until the duration is up, then
+/// fails if any chunk did not match its recomputation.
+pub fn soak(records: &RecordArgs, options: &SoakArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let SoakArgs {
+        duration,
+        sample,
+        chunk_size,
+        cipher,
+        threads,
+    } = *options;
+    let cipher = cipher
+        .pinned()
+        .unwrap_or_else(|| Cipher::fastest(CIPHER_PROBE));
+    let next = || -> Result<PasswordGenerator, Box<dyn std::error::Error>> {
+        Ok(records
+            .configure(
+                PasswordGenerator::builder()
+                    .key(Key::random(cipher.key_len())?)
+                    .cipher(cipher)
+                    .chunk_size(chunk_size),
+            )?
+            .build()?)
+    };
+    let shape = next()?;
+    let threads =
+        threads.unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()));
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build()?;
+    status!(
+        "Soaking for {}: {} records ({}) with {} on {} thread{}, holding {}; {} of {} chunks \
+         recomputed after each pass",
+        analysis::format_duration(duration.as_secs_f64()),
+        shape.count(),
+        records.encoding(),
+        cipher,
+        threads,
+        if threads == 1 { "" } else { "s" },
+        memory::size(shape.output_len() as u64),
+        sample.min(shape.num_chunks()),
+        shape.num_chunks()
+    );
+    let (mut passes, mut verified, mut bad) = (0, 0, 0);
+    let mut reported = std::time::Instant::now();
+    soak::run(&pool, duration, sample, next, |pass| {
+        passes = pass.number;
+        verified += pass.verified;
+        bad += pass.mismatches.len();
+        for mismatch in &pass.mismatches {
+            eprintln!("Mismatch in pass {}, {}", pass.number, mismatch);
+        }
+        if pass.last || reported.elapsed() >= SOAK_REPORT_EVERY {
+            reported = std::time::Instant::now();
+            status!(
+                "Pass {}: {:.0} records/s; {} chunks compared so far, {} mismatched",
+                pass.number,
+                pass.records as f64 / pass.elapsed.as_secs_f64(),
+                verified,
+                bad
+            );
+        }
+    })?;
+    match bad {
+        0 => {
+            status!(
+                "{} pass{}; all {} chunks compared matched their recomputation",
+                passes,
+                if passes == 1 { "" } else { "es" },
+                verified
+            );
+            Ok(())
+        }
+        _ => Err(failure::Failure::Verification(format!(
+            "{} of {} chunks compared over {} pass{} did not match their recomputation",
+            bad,
+            verified,
+            passes,
+            if passes == 1 { "" } else { "es" }
+        ))
+        .into()),
+    }
+}
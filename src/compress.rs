@@ -0,0 +1,386 @@
+//! Compression of the output stream, one chunk at a time.
+//!
+//! A [`ChunkCodec`] turns a chunk of serialized output into a standalone
+//! frame on the worker thread that generated it, so compression runs in
+//! parallel like everything else; the frames are then written in order.
+//!
+//! Each codec is behind a cargo feature, `compress-zstd` and
+//! `compress-gzip`; [`Codec::parse`] refuses one this build lacks.
+
+use std::fmt;
+use std::io;
+#[cfg(any(feature = "compress-zstd", feature = "compress-gzip"))]
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[cfg(feature = "compress-zstd")]
+use zstd::dict::EncoderDictionary;
+
+#[cfg(feature = "compress-zstd")]
+use crate::{OutputSink, PasswordGenerator};
+
+/// Compresses chunks of output into self-contained frames.
+pub trait ChunkCodec: Sync {
+    /// Appends the frame for `chunk` to `out`.
+    fn encode(&self, chunk: &[u8], out: &mut Vec<u8>) -> io::Result<()>;
+}
+
+/// Compressed sizes seen so far.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CompressionStats {
+    pub input: u64,
+    pub output: u64,
+    /// Input of the chunks that were also compressed without the dictionary
+    pub baseline_input: u64,
+    /// What those chunks came to without it
+    pub baseline_output: u64,
+}
+
+impl CompressionStats {
+    /// Input bytes per output byte.
+    pub fn ratio(&self) -> f64 {
+        self.input as f64 / self.output.max(1) as f64
+    }
+
+    /// The same, for plain zstd at the same level.
+    pub fn baseline_ratio(&self) -> f64 {
+        self.baseline_input as f64 / self.baseline_output.max(1) as f64
+    }
+}
+
+/// A general-purpose format for [`Compressor`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Codec {
+    /// One zstd frame per chunk; `zstd -d` reads the frames back to back
+    Zstd,
+    /// One gzip member per chunk; `gzip -d` reads multi-member files
+    Gzip,
+}
+
+impl Codec {
+    /// Parses `zstd` or `gzip`, if this build has it.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let codec = match s {
+            "zstd" => Codec::Zstd,
+            "gzip" => Codec::Gzip,
+            _ => return Err(format!("unknown codec `{}` (expected zstd or gzip)", s)),
+        };
+        match codec.available() {
+            true => Ok(codec),
+            false => Err(format!(
+                "this build has no {} (built without the `{}` feature)",
+                codec,
+                codec.feature()
+            )),
+        }
+    }
+
+    /// Whether this build can write and read the codec.
+    pub fn available(self) -> bool {
+        match self {
+            Codec::Zstd => cfg!(feature = "compress-zstd"),
+            Codec::Gzip => cfg!(feature = "compress-gzip"),
+        }
+    }
+
+    /// The cargo feature that builds the codec in.
+    pub fn feature(self) -> &'static str {
+        match self {
+            Codec::Zstd => "compress-zstd",
+            Codec::Gzip => "compress-gzip",
+        }
+    }
+
+    /// The level used when none is given.
+    pub fn default_level(self) -> i32 {
+        match self {
+            Codec::Zstd => 3,
+            Codec::Gzip => 6,
+        }
+    }
+
+    /// Levels the codec accepts.
+    pub fn levels(self) -> std::ops::RangeInclusive<i32> {
+        match self {
+            #[cfg(feature = "compress-zstd")]
+            Codec::Zstd => zstd::compression_level_range(),
+            // zstd's regular levels; a build without it never uses them
+            #[cfg(not(feature = "compress-zstd"))]
+            Codec::Zstd => 1..=22,
+            Codec::Gzip => 0..=9,
+        }
+    }
+
+    /// The usual file extension, without the dot.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Codec::Zstd => "zst",
+            Codec::Gzip => "gz",
+        }
+    }
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Codec::Zstd => "zstd",
+            Codec::Gzip => "gzip",
+        })
+    }
+}
+
+/// Plain zstd or gzip at one level, every chunk a frame of its own.
+pub struct Compressor {
+    codec: Codec,
+    level: i32,
+    input: AtomicU64,
+    output: AtomicU64,
+}
+
+impl Compressor {
+    /// A compressor for `codec` at `level`, which must be in
+    /// [`Codec::levels`].
+    pub fn new(codec: Codec, level: i32) -> Self {
+        assert!(codec.available(), "built without {}", codec.feature());
+        assert!(
+            codec.levels().contains(&level),
+            "{} level out of range",
+            codec
+        );
+        Compressor {
+            codec,
+            level,
+            input: AtomicU64::new(0),
+            output: AtomicU64::new(0),
+        }
+    }
+
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    pub fn level(&self) -> i32 {
+        self.level
+    }
+
+    /// Sizes so far; there is no baseline to compare against.
+    pub fn stats(&self) -> CompressionStats {
+        CompressionStats {
+            input: self.input.load(Ordering::Relaxed),
+            output: self.output.load(Ordering::Relaxed),
+            ..CompressionStats::default()
+        }
+    }
+}
+
+impl ChunkCodec for Compressor {
+    // A build with neither codec has no Compressor to call this on
+    #[cfg_attr(
+        not(any(feature = "compress-zstd", feature = "compress-gzip")),
+        allow(unreachable_code, unused_variables)
+    )]
+    fn encode(&self, chunk: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+        let start = out.len();
+        match self.codec {
+            #[cfg(feature = "compress-zstd")]
+            Codec::Zstd => zstd::stream::copy_encode(chunk, &mut *out, self.level)?,
+            #[cfg(feature = "compress-gzip")]
+            Codec::Gzip => {
+                let level = flate2::Compression::new(self.level as u32);
+                let mut encoder = flate2::write::GzEncoder::new(&mut *out, level);
+                encoder.write_all(chunk)?;
+                encoder.finish()?;
+            }
+            #[allow(unreachable_patterns)]
+            _ => unreachable!("Compressor::new takes only built-in codecs"),
+        }
+        self.input.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+        self.output
+            .fetch_add((out.len() - start) as u64, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+/// zstd with a dictionary trained on the run's own output. Every chunk is a
+/// separate frame, so the output decompresses with `zstd -D <dictionary>`.
+///
+/// The dictionary is made of fragments of real records: keep it as private
+/// as the output itself.
+#[cfg(feature = "compress-zstd")]
+pub struct ZstdDictionary {
+    level: i32,
+    dictionary: Vec<u8>,
+    prepared: EncoderDictionary<'static>,
+    frames: AtomicU64,
+    input: AtomicU64,
+    output: AtomicU64,
+    baseline_input: AtomicU64,
+    baseline_output: AtomicU64,
+}
+
+#[cfg(feature = "compress-zstd")]
+impl ZstdDictionary {
+    /// Every this many frames is also compressed without the dictionary, to
+    /// measure what the dictionary gains.
+    pub const BASELINE_EVERY: u64 = 8;
+
+    /// Trains a dictionary of up to `max_size` bytes on the first chunks of
+    /// `generator`'s output as serialized by `sink`, using a sample of about
+    /// 100 times that size (zstd's rule of thumb). Each record is one
+    /// training sample.
+    pub fn train(
+        generator: &PasswordGenerator,
+        sink: Option<&dyn OutputSink>,
+        level: i32,
+        max_size: usize,
+    ) -> io::Result<Self> {
+        let budget = max_size * 100;
+        let mut samples = Vec::new();
+        let mut sizes = Vec::new();
+        let mut records = Vec::new();
+        let mut scratch = Vec::new();
+        let framed = !generator.is_binary() && sink.is_none();
+        for chunk_idx in 0..generator.num_chunks() {
+            if samples.len() >= budget {
+                break;
+            }
+            records.clear();
+            records.resize(generator.chunk_len(chunk_idx) * generator.record_width(), 0);
+            let used = generator.fill_chunk(chunk_idx, &mut scratch, &mut records);
+            records.truncate(used);
+            let mut index = chunk_idx * generator.chunk_size();
+            generator.for_each_record(&records, |record| {
+                let start = samples.len();
+                match sink {
+                    Some(sink) => sink.write_record(index, record, &mut samples),
+                    None => samples.extend_from_slice(record),
+                }
+                if framed {
+                    samples.push(b'\n');
+                }
+                sizes.push(samples.len() - start);
+                index += 1;
+            });
+        }
+        let dictionary = zstd::dict::from_continuous(&samples, &sizes, max_size)?;
+        Ok(ZstdDictionary {
+            level,
+            prepared: EncoderDictionary::copy(&dictionary, level),
+            dictionary,
+            frames: AtomicU64::new(0),
+            input: AtomicU64::new(0),
+            output: AtomicU64::new(0),
+            baseline_input: AtomicU64::new(0),
+            baseline_output: AtomicU64::new(0),
+        })
+    }
+
+    /// The trained dictionary, as `zstd -D` expects it.
+    pub fn dictionary(&self) -> &[u8] {
+        &self.dictionary
+    }
+
+    pub fn stats(&self) -> CompressionStats {
+        CompressionStats {
+            input: self.input.load(Ordering::Relaxed),
+            output: self.output.load(Ordering::Relaxed),
+            baseline_input: self.baseline_input.load(Ordering::Relaxed),
+            baseline_output: self.baseline_output.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(feature = "compress-zstd")]
+impl ChunkCodec for ZstdDictionary {
+    fn encode(&self, chunk: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+        let start = out.len();
+        let mut encoder =
+            zstd::stream::write::Encoder::with_prepared_dictionary(&mut *out, &self.prepared)?;
+        encoder.write_all(chunk)?;
+        encoder.finish()?;
+        self.input.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+        self.output
+            .fetch_add((out.len() - start) as u64, Ordering::Relaxed);
+
+        let frame = self.frames.fetch_add(1, Ordering::Relaxed);
+        if frame.is_multiple_of(Self::BASELINE_EVERY) {
+            let plain = zstd::bulk::compress(chunk, self.level)?;
+            self.baseline_input
+                .fetch_add(chunk.len() as u64, Ordering::Relaxed);
+            self.baseline_output
+                .fetch_add(plain.len() as u64, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "compress-zstd"))]
+mod tests {
+    use super::*;
+    use crate::{Charset, Encoder, Format, PasswordGenerator};
+
+    #[cfg(feature = "compress-gzip")]
+    #[test]
+    fn frames_decompress_back_to_back() {
+        let g = PasswordGenerator::builder()
+            .key([4; 16])
+            .count(5_000)
+            .chunk_size(700)
+            .encoder(Encoder::new(&Charset::Alnum, 16))
+            .build()
+            .unwrap();
+        let sink = Format::Jsonl.sink(&g).unwrap();
+        let mut plain = Vec::new();
+        g.write_formatted(sink.as_ref(), &mut plain, |_, _| ())
+            .unwrap();
+        for codec in [Codec::Zstd, Codec::Gzip] {
+            let compressor = Compressor::new(codec, codec.default_level());
+            let mut packed = Vec::new();
+            g.write_compressed(Some(sink.as_ref()), &compressor, &mut packed, |_, _| ())
+                .unwrap();
+            let mut unpacked = Vec::new();
+            match codec {
+                Codec::Zstd => zstd::stream::copy_decode(packed.as_slice(), &mut unpacked).unwrap(),
+                Codec::Gzip => {
+                    let mut decoder = flate2::read::MultiGzDecoder::new(packed.as_slice());
+                    io::Read::read_to_end(&mut decoder, &mut unpacked).unwrap();
+                }
+            }
+            assert_eq!(unpacked, plain, "{}", codec);
+            let stats = compressor.stats();
+            assert_eq!(stats.output, packed.len() as u64);
+            assert!(stats.ratio() > 1.0);
+        }
+    }
+
+    #[test]
+    fn frames_decompress_with_the_dictionary() {
+        let g = PasswordGenerator::builder()
+            .key([3; 16])
+            .count(20_000)
+            .chunk_size(1000)
+            .encoder(Encoder::new(&Charset::Hex, 20))
+            .build()
+            .unwrap();
+        let sink = Format::Csv.sink(&g).unwrap();
+        let zstd = ZstdDictionary::train(&g, Some(sink.as_ref()), 3, 4096).unwrap();
+        assert!(!zstd.dictionary().is_empty());
+
+        let mut plain = Vec::new();
+        g.write_formatted(sink.as_ref(), &mut plain, |_, _| ())
+            .unwrap();
+        let mut packed = Vec::new();
+        g.write_compressed(Some(sink.as_ref()), &zstd, &mut packed, |_, _| ())
+            .unwrap();
+        let mut decoder =
+            zstd::stream::read::Decoder::with_dictionary(packed.as_slice(), zstd.dictionary())
+                .unwrap();
+        let mut unpacked = Vec::new();
+        io::Read::read_to_end(&mut decoder, &mut unpacked).unwrap();
+        assert_eq!(unpacked, plain);
+        let stats = zstd.stats();
+        assert_eq!(stats.input, plain.len() as u64);
+        assert_eq!(stats.output, packed.len() as u64);
+        assert!(stats.baseline_input > 0);
+    }
+}
@@ -0,0 +1,212 @@
+//! Options files: the options of a run saved to a file, as `wizard` writes
+//! them, for `--config` to run again. Each line is a long option without
+//! its dashes and its value, a number or quoted text, or `true` for a
+//! switch:
+//!
+//! ```text
+//! # Written by `mass_password_gen_optimized wizard`
+//! count = 1000000
+//! charset = "alnum"
+//! length = 20
+//! deterministic = true
+//! key = "keyring:q3-rotation"
+//! output = "passwords.txt"
+//! ```
+//!
+//! The file's options go in front of those on the command line, so an
+//! option may be given in one place or the other but not both. A key is
+//! named by its provider (`key = "file:..."`), never written out: files
+//! get copied and attached to tickets.
+
+use std::ffi::OsString;
+use std::fmt;
+use std::path::Path;
+
+use clap::{CommandFactory, Parser};
+use indexmap::IndexMap;
+use mass_password_gen_optimized::settings;
+use toml::Spanned;
+
+use crate::Args;
+
+/// The options of a run, in order.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RunConfig {
+    /// Each option's long name and value, `None` for a switch
+    options: Vec<(String, Option<String>)>,
+}
+
+impl RunConfig {
+    /// Parses an options file, checking each name against the run's long
+    /// options.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let table: IndexMap<String, Spanned<toml::Value>> = settings::parse(text)?;
+        let mut config = RunConfig::default();
+        for (name, value) in table {
+            let span = value.span();
+            let err = |msg: String| settings::at(text, span.clone(), msg);
+            let value = match value.into_inner() {
+                toml::Value::Boolean(true) => None,
+                toml::Value::Boolean(false) => {
+                    return Err(err(format!("leave `{}` out to turn it off", name)))
+                }
+                toml::Value::String(text) => Some(text),
+                toml::Value::Integer(n) => Some(n.to_string()),
+                toml::Value::Float(x) => Some(x.to_string()),
+                _ => return Err(err(format!("`{}` takes a string, a number or true", name))),
+            };
+            match (takes_value(&name).map_err(err)?, &value) {
+                (true, None) => return Err(err(format!("`{}` needs a value", name))),
+                (false, Some(_)) => {
+                    return Err(err(format!("`{}` is a switch: `{} = true`", name, name)))
+                }
+                _ => {}
+            }
+            config.options.push((name, value));
+        }
+        Ok(config)
+    }
+
+    /// Sets option `name` to `value`.
+    pub fn set(&mut self, name: &str, value: impl Into<String>) {
+        self.options.push((name.to_string(), Some(value.into())));
+    }
+
+    /// Turns on switch `name`.
+    pub fn switch(&mut self, name: &str) {
+        self.options.push((name.to_string(), None));
+    }
+
+    /// The options as command-line arguments.
+    pub fn args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        for (name, value) in &self.options {
+            args.push(format!("--{}", name));
+            args.extend(value.clone());
+        }
+        args
+    }
+
+    /// The command line that runs with these options, quoted for the shell.
+    pub fn command_line(&self) -> String {
+        std::iter::once(Args::command().get_name().to_string())
+            .chain(self.args().iter().map(|arg| shell_quote(arg)))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// Parses the options as the command line of a run.
+    pub fn to_args(&self) -> Result<Args, clap::Error> {
+        let name = Args::command().get_name().to_string();
+        Args::try_parse_from(std::iter::once(name).chain(self.args()))
+    }
+}
+
+impl fmt::Display for RunConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (name, value) in &self.options {
+            match value {
+                // Numbers bare, as they would be typed
+                Some(value) if value.parse::<i64>().is_ok_and(|n| n.to_string() == *value) => {
+                    writeln!(f, "{} = {}", name, value)?
+                }
+                Some(value) => writeln!(f, "{} = {}", name, toml::Value::from(value.as_str()))?,
+                None => writeln!(f, "{} = true", name)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads the options file at `path`.
+pub fn read(path: &Path) -> Result<RunConfig, String> {
+    settings::read(path, RunConfig::parse)
+}
+
+/// The run's options again, with those of its `--config` file in front of
+/// the command line's.
+pub fn reparse(path: &Path) -> Result<Args, Box<dyn std::error::Error>> {
+    let config = read(path)?;
+    let mut argv: Vec<OsString> = std::env::args_os().collect();
+    let rest = argv.split_off(1.min(argv.len()));
+    argv.extend(config.args().into_iter().map(OsString::from));
+    argv.extend(rest);
+    Ok(Args::try_parse_from(argv).unwrap_or_else(|e| e.exit()))
+}
+
+/// Whether run option `name` takes a value, or why it cannot be in a file.
+fn takes_value(name: &str) -> Result<bool, String> {
+    match name {
+        "key-hex" => {
+            return Err("keys stay out of options files; name a provider with \
+                        `key = \"file:...\"` or `\"keyring:...\"`"
+                .to_string())
+        }
+        "config" => return Err("options files cannot name another".to_string()),
+        _ => {}
+    }
+    let command = Args::command();
+    let arg = command
+        .get_arguments()
+        .find(|arg| arg.get_long() == Some(name))
+        .ok_or_else(|| format!("unknown option `{}`", name))?;
+    Ok(arg.get_action().takes_values())
+}
+
+/// `s` as one word for a POSIX shell.
+fn shell_quote(s: &str) -> String {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "_-+=./:,@%".contains(c);
+    match !s.is_empty() && s.chars().all(plain) {
+        true => s.to_string(),
+        false => format!("'{}'", s.replace('\'', r"'\''")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn files_round_trip_to_a_run() {
+        let mut config = RunConfig::default();
+        config.set("count", "5000");
+        config.set("charset", "custom:ab\"c\\");
+        config.switch("deterministic");
+        config.set("key", "file:/keys/it's.hex");
+        config.set("output", "out.txt");
+        let text = format!("# saved\n{}", config);
+        assert_eq!(RunConfig::parse(&text).unwrap(), config);
+        assert!(config
+            .command_line()
+            .ends_with("--count 5000 --charset 'custom:ab\"c\\' --deterministic --key 'file:/keys/it'\\''s.hex' --output out.txt"));
+
+        let args = config.to_args().unwrap();
+        assert_eq!(args.records.count, 5000);
+        assert!(args.deterministic);
+        assert_eq!(args.output.as_deref(), Some(Path::new("out.txt")));
+    }
+
+    #[test]
+    fn numbers_are_bare_or_quoted() {
+        let bare = RunConfig::parse("count = 5000\nlength = 12\ncharset = \"hex\"").unwrap();
+        assert_eq!(bare, RunConfig::parse(&bare.to_string()).unwrap());
+        assert!(bare.to_string().starts_with("count = 5000\nlength = 12\n"));
+        let quoted = RunConfig::parse("count = \"5000\"\nlength = \"12\"\ncharset = \"hex\"");
+        assert_eq!(quoted.unwrap(), bare);
+        assert_eq!(bare.to_args().unwrap().records.count, 5000);
+    }
+
+    #[test]
+    fn bad_files_are_refused_by_line() {
+        let parse = |text: &str| RunConfig::parse(text).unwrap_err();
+        assert!(parse("count 5").starts_with("line 1: "));
+        assert!(parse("\ncount = [5]").starts_with("line 2: `count` takes a string, a number"));
+        assert!(parse("counts = \"5\"").contains("unknown option `counts`"));
+        assert!(parse("count = true").contains("needs a value"));
+        assert!(parse("deterministic = \"yes\"").contains("is a switch"));
+        assert!(parse("deterministic = false").contains("leave `deterministic` out"));
+        assert!(parse("count = 1\ncount = 2").starts_with("line 2: duplicate key"));
+        assert!(parse("key-hex = \"00\"").contains("stay out of options files"));
+        assert!(parse("config = \"other.toml\"").contains("cannot name another"));
+    }
+}
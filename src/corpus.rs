@@ -0,0 +1,966 @@
+//! Reading generated corpora back.
+//!
+//! A corpus is one or more output files (shards) holding consecutive ranges
+//! of a run's records, all in the same [`Format`] and optionally compressed.
+//! A [`Manifest`] describes where each shard's records start; a
+//! [`CorpusReader`] follows it to iterate records in index order, undoing the
+//! compression and the format framing on the way, so consumers get back
+//! exactly the records [`PasswordGenerator::records`](crate::PasswordGenerator::records)
+//! produced.
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::archive::Stub;
+use crate::layout::RawLayout;
+use crate::{Codec, Format};
+
+/// Manifest layout version written and understood by this crate.
+pub const MANIFEST_VERSION: u32 = 1;
+
+/// Width of a raw record.
+const BLOCK: usize = 16;
+
+/// Describes a corpus: its shards, their format and compression.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub version: u32,
+    /// Output format of every shard
+    #[serde(with = "format_name")]
+    pub format: Format,
+    /// Records are raw 16-byte blocks rather than text
+    pub binary: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression: Option<Compression>,
+    /// In index order, each starting where the previous one ends
+    pub shards: Vec<Shard>,
+    /// How the records were generated, for output split with `--shard-size`;
+    /// archives keep theirs in the index instead
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parameters: Option<Stub>,
+    /// The run's content address, from [`run_id`](crate::archive::run_id);
+    /// absent from manifests written before it was kept
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub run_id: Option<String>,
+    /// The machine and build that wrote the corpus
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<HostInfo>,
+    /// Where raw records sit in each shard, when padded to an alignment;
+    /// absent, they are back to back
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub layout: Option<RawLayout>,
+    /// How the shards were sealed with `--seal`, if they were
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seal: Option<Seal>,
+}
+
+impl Manifest {
+    /// Reads the manifest at `path`. Errors name `path`; one that is not a
+    /// manifest is `InvalidData`.
+    pub fn load(path: &Path) -> io::Result<Manifest> {
+        let in_path = |e: &dyn std::fmt::Display| format!("{}: {}", path.display(), e);
+        let json = std::fs::read(path).map_err(|e| io::Error::new(e.kind(), in_path(&e)))?;
+        serde_json::from_slice(&json).map_err(|e| invalid(in_path(&e)))
+    }
+}
+
+/// Shards encrypted under one key, which is split among custodians so that
+/// any `threshold` of them together can unlock the run and fewer learn
+/// nothing of it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Seal {
+    pub threshold: usize,
+    /// In share order: the first custodian holds share 1
+    pub custodians: Vec<Custodian>,
+    /// HMAC-SHA256 of a fixed label under the key, as lowercase hex, which
+    /// tells the right key from a wrong one
+    pub key_check: String,
+}
+
+/// Who holds a share of a [`Seal`]'s key.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Custodian {
+    pub name: String,
+    /// Whom the share is encrypted to, `age:...` or `gpg:...`
+    pub recipient: String,
+    /// The encrypted share, relative to the manifest's directory
+    pub share: PathBuf,
+}
+
+/// Where a corpus was written, for telling apart runs that should match
+/// but do not. Nothing in it names the machine.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HostInfo {
+    pub cpu: String,
+    /// Microcode revision, where the OS reports it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub microcode: Option<String>,
+    /// The instruction set extensions the ciphers can use that this CPU has
+    pub cpu_features: Vec<String>,
+    pub os: String,
+    pub kernel: String,
+    pub crate_version: String,
+    /// Commit the binary was built from, when built from a git checkout
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub git_commit: Option<String>,
+    /// The compiler that built it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rustc: Option<String>,
+}
+
+/// How shards are compressed.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "codec", rename_all = "lowercase")]
+pub enum Compression {
+    /// zstd frames, made with this dictionary if there is one (`--zstd-dict`)
+    Zstd {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        dictionary: Option<PathBuf>,
+    },
+    /// gzip members (`--compress gzip`)
+    Gzip,
+}
+
+impl Compression {
+    /// The codec that reads it back.
+    pub fn codec(&self) -> Codec {
+        match self {
+            Compression::Zstd { .. } => Codec::Zstd,
+            Compression::Gzip => Codec::Gzip,
+        }
+    }
+}
+
+/// One output file of a corpus.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Shard {
+    /// Relative paths are taken from the manifest's directory
+    pub path: PathBuf,
+    /// Index of the shard's first record
+    pub first: usize,
+    /// Records in the shard; unknown only for a lone file read without a
+    /// manifest, which then runs to its end
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub count: Option<usize>,
+    /// Where the shard starts within its file, for shards stored inside a
+    /// larger one such as an archive
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub offset: u64,
+    /// Bytes of the shard, when it does not run to the end of its file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub length: Option<u64>,
+    /// SHA-256 of the shard's bytes, as lowercase hex
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    /// Where records partway through a text shard start, so a read from
+    /// the middle seeks instead of reading up to it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub routes: Option<Routes>,
+    /// Whom the shard was encrypted to (`age:...` or `gpg:...`), which
+    /// leaves the file at `path` with `.age` or `.gpg` added, or
+    /// `seal:K-of-N` when it was [sealed](Seal) and left with `.sealed`
+    /// added; the hash and routes are of the shard decrypted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encrypted_to: Option<String>,
+}
+
+impl Shard {
+    /// `e`, from opening the shard at `path`, saying why it is not there
+    /// when the shard was encrypted.
+    pub(crate) fn missing(&self, path: &Path, e: io::Error) -> io::Error {
+        match (&self.encrypted_to, e.kind()) {
+            (Some(recipient), io::ErrorKind::NotFound) if recipient.starts_with("seal:") => {
+                io::Error::new(
+                    e.kind(),
+                    format!(
+                        "{} is sealed ({}): `unlock` the run with its custodians' shares to \
+                         read it",
+                        path.display(),
+                        &recipient["seal:".len()..]
+                    ),
+                )
+            }
+            (Some(recipient), io::ErrorKind::NotFound) => {
+                let tool = recipient.split(':').next().unwrap_or_default();
+                io::Error::new(
+                    e.kind(),
+                    format!(
+                        "{} is encrypted to {}: decrypt {}.{} beside it to read it",
+                        path.display(),
+                        recipient,
+                        path.display(),
+                        tool
+                    ),
+                )
+            }
+            _ => e,
+        }
+    }
+}
+
+/// A shard's routing index: the byte offsets, from the start of the shard,
+/// of records `first + every`, `first + 2 * every`, and so on.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Routes {
+    pub every: usize,
+    pub offsets: Vec<u64>,
+}
+
+/// What a shard of `format` starts with before its first record.
+pub(crate) fn header(format: Format, binary: bool) -> Vec<u8> {
+    match format {
+        // Tokens and hashes have no header, and no sink without an expiry
+        // or a mode
+        Format::ExpiringToken | Format::Hashcat => Vec::new(),
+        format => format
+            .sink_for_records(binary)
+            .map_or(Vec::new(), |sink| sink.header().to_vec()),
+    }
+}
+
+fn is_zero(n: &u64) -> bool {
+    *n == 0
+}
+
+/// A record read back from a corpus.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Record {
+    pub index: usize,
+    /// The record's native bytes, without framing
+    pub password: Vec<u8>,
+}
+
+/// Reads the records of a corpus in index order.
+pub struct CorpusReader {
+    manifest: Manifest,
+    base: PathBuf,
+    #[cfg_attr(not(feature = "compress-zstd"), allow(dead_code))]
+    dictionary: Option<Vec<u8>>,
+}
+
+impl CorpusReader {
+    /// Opens the corpus described by the manifest at `path`, or stored in
+    /// the [archive](crate::archive) at `path`.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        if crate::archive::is_archive(path)? {
+            return crate::archive::open(path);
+        }
+        let manifest = Manifest::load(path)?;
+        let base = path.parent().unwrap_or(Path::new("")).to_path_buf();
+        Self::from_manifest(manifest, base)
+    }
+
+    /// Opens a single output file, as if it were the only shard of a corpus.
+    /// `dictionary` is the file from `--zstd-dict`, for compressed output.
+    /// Raw output that starts with a [header](RawLayout::header) is read
+    /// as the header describes, binary or not.
+    pub fn file(
+        path: &Path,
+        format: Format,
+        binary: bool,
+        dictionary: Option<&Path>,
+    ) -> io::Result<Self> {
+        let mut described = None;
+        if format == Format::Raw && dictionary.is_none() {
+            let mut head = Vec::with_capacity(crate::layout::HEADER_LEN);
+            File::open(path)?
+                .take(crate::layout::HEADER_LEN as u64)
+                .read_to_end(&mut head)?;
+            described = RawLayout::from_header(&head)
+                .map_err(|e| invalid(format!("{}: {}", path.display(), e)))?;
+        }
+        let mut manifest = Manifest {
+            version: MANIFEST_VERSION,
+            format,
+            binary,
+            compression: dictionary.map(|d| Compression::Zstd {
+                dictionary: Some(d.to_path_buf()),
+            }),
+            shards: vec![Shard {
+                path: path.to_path_buf(),
+                first: 0,
+                count: None,
+                offset: 0,
+                length: None,
+                sha256: None,
+                routes: None,
+                encrypted_to: None,
+            }],
+            parameters: None,
+            run_id: None,
+            host: None,
+            layout: None,
+            seal: None,
+        };
+        if let Some((layout, count)) = described {
+            manifest.binary = true;
+            manifest.shards[0].offset = layout.header_len() as u64;
+            manifest.shards[0].count = Some(count);
+            manifest.layout = Some(layout);
+        }
+        Self::from_manifest(manifest, PathBuf::new())
+    }
+
+    /// Opens the corpus described by `manifest`, with relative paths taken
+    /// from `base`.
+    pub fn from_manifest(manifest: Manifest, base: PathBuf) -> io::Result<Self> {
+        if manifest.version != MANIFEST_VERSION {
+            return Err(invalid(format!(
+                "manifest version {} is not supported (expected {})",
+                manifest.version, MANIFEST_VERSION
+            )));
+        }
+        if manifest.layout.is_some() && !(manifest.format == Format::Raw && manifest.binary) {
+            return Err(invalid(
+                "a record layout only applies to raw binary records".to_string(),
+            ));
+        }
+        let mut next = Some(0);
+        for shard in &manifest.shards {
+            if next != Some(shard.first) {
+                return Err(invalid(format!(
+                    "shard {} starts at record {}, not where the previous one ends",
+                    shard.path.display(),
+                    shard.first
+                )));
+            }
+            next = shard.count.map(|count| shard.first + count);
+        }
+        let dictionary = match &manifest.compression {
+            Some(Compression::Zstd {
+                dictionary: Some(path),
+            }) => Some(std::fs::read(base.join(path))?),
+            _ => None,
+        };
+        Ok(CorpusReader {
+            manifest,
+            base,
+            dictionary,
+        })
+    }
+
+    pub fn manifest(&self) -> &Manifest {
+        &self.manifest
+    }
+
+    /// Reads raw records laid out in `layout`, for a single file opened with
+    /// [`file`](Self::file) from a run with `--record-align`.
+    pub fn with_layout(mut self, layout: RawLayout) -> io::Result<Self> {
+        self.manifest.layout = Some(layout);
+        let base = std::mem::take(&mut self.base);
+        Self::from_manifest(self.manifest, base)
+    }
+
+    /// Bytes from one raw binary record to the next.
+    fn stride(&self) -> usize {
+        self.manifest
+            .layout
+            .as_ref()
+            .map_or(BLOCK, |layout| layout.stride)
+    }
+
+    /// Records in the corpus, if the manifest says.
+    pub fn count(&self) -> Option<usize> {
+        let last = self.manifest.shards.last()?;
+        last.count.map(|count| last.first + count)
+    }
+
+    /// Iterates over every record.
+    pub fn records(&self) -> Records<'_> {
+        self.range(0..self.count().unwrap_or(usize::MAX))
+    }
+
+    /// Iterates over the records with indices in `range`. Shards before the
+    /// range are not opened, and uncompressed raw shards are seeked into
+    /// rather than read through.
+    pub fn range(&self, range: Range<usize>) -> Records<'_> {
+        Records {
+            reader: self,
+            shard: 0,
+            input: None,
+            at: 0,
+            next: range.start,
+            end: range.end,
+            line: Vec::new(),
+        }
+    }
+
+    /// Opens shard `shard` positioned at record `from`, which it holds;
+    /// returns the index of the record it will read first.
+    fn open_shard(&self, shard: &Shard, from: usize) -> io::Result<(Box<dyn BufRead + '_>, usize)> {
+        let path = self.base.join(&shard.path);
+        let mut file = File::open(&path).map_err(|e| shard.missing(&path, e))?;
+        let mut at = shard.first;
+        let mut skip = 0;
+        let compressed = self.manifest.compression.is_some();
+        if self.manifest.format == Format::Raw && self.manifest.binary && !compressed {
+            skip = ((from - at) * self.stride()) as u64;
+            at = from;
+        } else if let (Some(routes), false) = (&shard.routes, compressed) {
+            // The last route at or before `from`
+            let passed = ((from - at) / routes.every.max(1)).min(routes.offsets.len());
+            if passed > 0 {
+                skip = routes.offsets[passed - 1];
+                at += passed * routes.every;
+            }
+        }
+        file.seek(SeekFrom::Start(shard.offset + skip))?;
+        let file = file.take(
+            shard
+                .length
+                .map_or(u64::MAX, |len| len.saturating_sub(skip)),
+        );
+        let mut input: Box<dyn BufRead + '_> = match &self.manifest.compression {
+            None => Box::new(BufReader::new(file)),
+            #[cfg(feature = "compress-zstd")]
+            Some(Compression::Zstd { .. }) => {
+                let dictionary = self.dictionary.as_deref().unwrap_or(&[]);
+                let decoder =
+                    zstd::stream::read::Decoder::with_dictionary(BufReader::new(file), dictionary)?;
+                Box::new(BufReader::new(decoder))
+            }
+            #[cfg(feature = "compress-gzip")]
+            Some(Compression::Gzip) => Box::new(BufReader::new(
+                flate2::bufread::MultiGzDecoder::new(BufReader::new(file)),
+            )),
+            #[allow(unreachable_patterns)]
+            Some(compression) => {
+                let codec = compression.codec();
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    format!(
+                        "the corpus is {}-compressed, and this build has no {} (built \
+                         without the `{}` feature)",
+                        codec,
+                        codec,
+                        codec.feature()
+                    ),
+                ));
+            }
+        };
+        let header = header(self.manifest.format, self.manifest.binary);
+        if !header.is_empty() && at == shard.first {
+            let mut line = vec![0; header.len()];
+            input.read_exact(&mut line)?;
+            if line != header {
+                return Err(invalid(format!(
+                    "{}: missing the {} header",
+                    shard.path.display(),
+                    self.manifest.format
+                )));
+            }
+        }
+        Ok((input, at))
+    }
+}
+
+/// Iterator over the records of a corpus; see [`CorpusReader::range`].
+pub struct Records<'a> {
+    reader: &'a CorpusReader,
+    /// The shard being read, or the next to open
+    shard: usize,
+    input: Option<Box<dyn BufRead + 'a>>,
+    /// Index of the record `input` reads next
+    at: usize,
+    /// Index of the record to yield next
+    next: usize,
+    end: usize,
+    line: Vec<u8>,
+}
+
+impl Records<'_> {
+    /// Reads the next record of the open shard, or `None` at its end.
+    fn read(&mut self) -> io::Result<Option<Record>> {
+        let manifest = &self.reader.manifest;
+        let shard = &manifest.shards[self.shard];
+        let input = self.input.as_mut().expect("a shard is open");
+        self.line.clear();
+        if manifest.format == Format::Raw && manifest.binary {
+            let stride = self.reader.stride() as u64;
+            input.take(stride).read_to_end(&mut self.line)?;
+        } else {
+            input.read_until(b'\n', &mut self.line)?;
+        }
+        let fail = |msg: &str| {
+            invalid(format!(
+                "{}: record {}: {}",
+                shard.path.display(),
+                self.at,
+                msg
+            ))
+        };
+        if self.line.is_empty() {
+            if shard
+                .count
+                .is_some_and(|count| self.at != shard.first + count)
+            {
+                return Err(fail("the shard ends early"));
+            }
+            return Ok(None);
+        }
+        if shard
+            .count
+            .is_some_and(|count| self.at == shard.first + count)
+        {
+            return Err(fail("the shard holds more records than the manifest says"));
+        }
+        let (index, password) = match &manifest.layout {
+            Some(layout) => (
+                None,
+                layout
+                    .record(self.at, &self.line)
+                    .map_err(|e| fail(&e))?
+                    .to_vec(),
+            ),
+            None => decode(manifest.format, manifest.binary, &self.line).map_err(|e| fail(&e))?,
+        };
+        if index.is_some_and(|index| index != self.at) {
+            return Err(fail("out of order"));
+        }
+        let index = self.at;
+        self.at += 1;
+        Ok(Some(Record { index, password }))
+    }
+}
+
+impl Iterator for Records<'_> {
+    type Item = io::Result<Record>;
+
+    fn next(&mut self) -> Option<io::Result<Record>> {
+        let shards = &self.reader.manifest.shards;
+        while self.next < self.end {
+            if self.input.is_none() {
+                // Skip the shards that end before the next record
+                while shards
+                    .get(self.shard)?
+                    .count
+                    .is_some_and(|count| shards[self.shard].first + count <= self.next)
+                {
+                    self.shard += 1;
+                }
+                match self.reader.open_shard(&shards[self.shard], self.next) {
+                    Ok((input, at)) => {
+                        self.input = Some(input);
+                        self.at = at;
+                    }
+                    Err(e) => return self.fail(e),
+                }
+            }
+            match self.read() {
+                Ok(Some(record)) if record.index < self.next => {}
+                Ok(Some(record)) => {
+                    self.next += 1;
+                    return Some(Ok(record));
+                }
+                Ok(None) if shards[self.shard].count.is_none() => {
+                    // A shard of unknown length is the last one
+                    self.end = self.next;
+                }
+                Ok(None) => {
+                    self.input = None;
+                    self.shard += 1;
+                }
+                Err(e) => return self.fail(e),
+            }
+        }
+        None
+    }
+}
+
+impl Records<'_> {
+    /// Ends the iteration with `e`.
+    fn fail(&mut self, e: io::Error) -> Option<io::Result<Record>> {
+        self.end = self.next;
+        Some(Err(e))
+    }
+}
+
+/// Undoes `format`'s framing of one record; returns the index the record
+/// carries, if the format has one, and its native bytes.
+fn decode(format: Format, binary: bool, line: &[u8]) -> Result<(Option<usize>, Vec<u8>), String> {
+    if format == Format::Raw && binary {
+        // Blocks are not framed; any byte, newlines included, can occur
+        return match line.len() {
+            BLOCK => Ok((None, line.to_vec())),
+            _ => Err("truncated".into()),
+        };
+    }
+    let line = line
+        .strip_suffix(b"\n")
+        .ok_or("missing the final newline")?;
+    let field = |text: &[u8]| {
+        if binary {
+            decode_hex(text)
+        } else {
+            Ok(text.to_vec())
+        }
+    };
+    match format {
+        Format::Raw => Ok((None, line.to_vec())),
+        Format::Hex => Ok((None, decode_hex(line)?)),
+        Format::Base64 => Ok((None, decode_base64(line)?)),
+        Format::Csv => {
+            let (index, rest) = split_index(line, b",")?;
+            let text = match rest.strip_prefix(b"\"") {
+                Some(quoted) => {
+                    let quoted = quoted.strip_suffix(b"\"").ok_or("unterminated quotes")?;
+                    let mut text = Vec::with_capacity(quoted.len());
+                    let mut bytes = quoted.iter();
+                    while let Some(&b) = bytes.next() {
+                        if b == b'"' && bytes.next() != Some(&b'"') {
+                            return Err("stray quote".into());
+                        }
+                        text.push(b);
+                    }
+                    text
+                }
+                None => rest.to_vec(),
+            };
+            Ok((Some(index), field(&text)?))
+        }
+        Format::Jsonl => {
+            let line = line
+                .strip_prefix(br#"{"index":"#)
+                .ok_or("not a record object")?;
+            let (index, rest) = split_index(line, br#","password":""#)?;
+            let escaped = rest.strip_suffix(br#""}"#).ok_or("not a record object")?;
+            let mut text = Vec::with_capacity(escaped.len());
+            let mut bytes = escaped.iter();
+            while let Some(&b) = bytes.next() {
+                match b {
+                    b'\\' => text.push(*bytes.next().ok_or("dangling escape")?),
+                    b'"' => return Err("unescaped quote".into()),
+                    _ => text.push(b),
+                }
+            }
+            Ok((Some(index), field(&text)?))
+        }
+        Format::ExpiringToken => {
+            // INDEX.EXPIRY.MAC.PASSWORD; the MAC needs the key to check
+            let (index, rest) = split_index(line, b".")?;
+            let mut fields = rest.splitn(3, |&b| b == b'.');
+            let (Some(_), Some(_), Some(password)) = (fields.next(), fields.next(), fields.next())
+            else {
+                return Err("not a token".into());
+            };
+            Ok((Some(index), field(password)?))
+        }
+        Format::Hashcat => Err("hashcat lines hold hashes, which cannot be read back".into()),
+    }
+}
+
+/// Splits `line` into the decimal index before `separator` and the rest.
+fn split_index<'a>(line: &'a [u8], separator: &[u8]) -> Result<(usize, &'a [u8]), String> {
+    let digits = line.iter().take_while(|b| b.is_ascii_digit()).count();
+    let rest = line[digits..]
+        .strip_prefix(separator)
+        .ok_or("expected an index")?;
+    let index = std::str::from_utf8(&line[..digits])
+        .ok()
+        .and_then(|d| d.parse().ok())
+        .ok_or("expected an index")?;
+    Ok((index, rest))
+}
+
+fn decode_hex(text: &[u8]) -> Result<Vec<u8>, String> {
+    let digit = |c: u8| (c as char).to_digit(16).ok_or("not valid hex");
+    if !text.len().is_multiple_of(2) {
+        return Err("not valid hex".into());
+    }
+    text.chunks_exact(2)
+        .map(|pair| Ok(((digit(pair[0])? << 4) | digit(pair[1])?) as u8))
+        .collect()
+}
+
+fn decode_base64(text: &[u8]) -> Result<Vec<u8>, String> {
+    let value = |c: u8| match c {
+        b'A'..=b'Z' => Ok(c - b'A'),
+        b'a'..=b'z' => Ok(c - b'a' + 26),
+        b'0'..=b'9' => Ok(c - b'0' + 52),
+        b'+' => Ok(62),
+        b'/' => Ok(63),
+        _ => Err("not valid base64".to_string()),
+    };
+    if !text.len().is_multiple_of(4) {
+        return Err("not valid base64".into());
+    }
+    let mut out = Vec::with_capacity(text.len() / 4 * 3);
+    for group in text.chunks_exact(4) {
+        let padding = group.iter().rev().take_while(|&&c| c == b'=').count();
+        let mut n = 0u32;
+        for &c in &group[..4 - padding] {
+            n = (n << 6) | value(c)? as u32;
+        }
+        n <<= 6 * padding;
+        out.extend_from_slice(&n.to_be_bytes()[1..4 - padding]);
+    }
+    Ok(out)
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// (De)serializes a [`Format`] by its name.
+mod format_name {
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    use crate::Format;
+
+    pub fn serialize<S: Serializer>(format: &Format, s: S) -> Result<S::Ok, S::Error> {
+        s.collect_str(format)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Format, D::Error> {
+        Format::parse(&String::deserialize(d)?).map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Capitalization, Charset, Encoder, ExpiringTokens, Passphrase, PasswordGenerator};
+
+    /// Writes `g`'s output in `format` as shards of `per_shard` records, each
+    /// with its own header, and returns the manifest.
+    fn shard(g: &PasswordGenerator, format: Format, per_shard: usize, name: &str) -> Manifest {
+        let dir = std::env::temp_dir();
+        let sink = format.sink(g);
+        let records: Vec<_> = g.records().collect();
+        let mut shards = Vec::new();
+        for (n, part) in records.chunks(per_shard).enumerate() {
+            let first = n * per_shard;
+            let mut out = sink.as_ref().map_or(Vec::new(), |s| s.header().to_vec());
+            for (i, record) in part.iter().enumerate() {
+                match &sink {
+                    Some(sink) => sink.write_record(first + i, record, &mut out),
+                    None => {
+                        out.extend_from_slice(record);
+                        if !g.is_binary() {
+                            out.push(b'\n');
+                        }
+                    }
+                }
+            }
+            let path = PathBuf::from(format!("mpg_corpus_{}_{}", name, n));
+            std::fs::write(dir.join(&path), out).unwrap();
+            shards.push(Shard {
+                path,
+                first,
+                count: Some(part.len()),
+                offset: 0,
+                length: None,
+                sha256: None,
+                routes: None,
+                encrypted_to: None,
+            });
+        }
+        Manifest {
+            version: MANIFEST_VERSION,
+            format,
+            binary: g.is_binary(),
+            compression: None,
+            shards,
+            parameters: None,
+            run_id: None,
+            host: None,
+            layout: None,
+            seal: None,
+        }
+    }
+
+    #[test]
+    fn corpora_read_back_in_order() {
+        let raw = PasswordGenerator::builder()
+            .key([5; 16])
+            .count(1000)
+            .chunk_size(64);
+        let symbols = raw
+            .clone()
+            .encoder(Encoder::new(&Charset::AlnumSymbols, 12));
+        let pass = Passphrase::new(4, ",", Capitalization::Title, 2).unwrap();
+        let phrases = raw.clone().passphrase(pass);
+        let dir = std::env::temp_dir();
+        for (n, builder) in [raw, symbols, phrases].into_iter().enumerate() {
+            let g = builder.build().unwrap();
+            let expected: Vec<_> = g.records().collect();
+            for format in [
+                Format::Raw,
+                Format::Hex,
+                Format::Base64,
+                Format::Csv,
+                Format::Jsonl,
+            ] {
+                let manifest = shard(&g, format, 300, &format!("{}_{}", n, format));
+                // Through JSON, as a manifest on disk would be
+                let json = serde_json::to_string(&manifest).unwrap();
+                let manifest: Manifest = serde_json::from_str(&json).unwrap();
+                let reader = CorpusReader::from_manifest(manifest.clone(), dir.clone()).unwrap();
+                assert_eq!(reader.count(), Some(1000));
+                let all: Vec<_> = reader.records().map(|r| r.unwrap()).collect();
+                assert!(all.iter().enumerate().all(|(i, r)| r.index == i));
+                assert!(all.iter().map(|r| &r.password).eq(&expected), "{}", format);
+                let range: Vec<_> = reader
+                    .range(250..620)
+                    .map(|r| r.unwrap().password)
+                    .collect();
+                assert_eq!(range, expected[250..620]);
+                assert_eq!(reader.range(990..2000).count(), 10);
+                for shard in &manifest.shards {
+                    std::fs::remove_file(dir.join(&shard.path)).unwrap();
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn damage_is_reported() {
+        let g = PasswordGenerator::builder()
+            .key([6; 16])
+            .count(100)
+            .encoder(Encoder::new(&Charset::Hex, 8))
+            .build()
+            .unwrap();
+        let dir = std::env::temp_dir();
+        let mut manifest = shard(&g, Format::Csv, 60, "damaged");
+        let shard = dir.join(&manifest.shards[1].path);
+        let text = std::fs::read_to_string(&shard).unwrap();
+        std::fs::write(&shard, text.replacen("61,", "62,", 1)).unwrap();
+        let reader = CorpusReader::from_manifest(manifest.clone(), dir.clone()).unwrap();
+        let results: Vec<_> = reader.records().collect();
+        assert_eq!(results.len(), 62);
+        assert!(results[61].is_err());
+        // A short shard
+        std::fs::write(&shard, &text[..text.len() / 2]).unwrap();
+        let reader = CorpusReader::from_manifest(manifest.clone(), dir.clone()).unwrap();
+        assert!(reader.records().any(|r| r.is_err()));
+        // A gap between shards
+        manifest.shards[1].first += 1;
+        assert!(CorpusReader::from_manifest(manifest.clone(), dir.clone()).is_err());
+        for shard in &manifest.shards {
+            std::fs::remove_file(dir.join(&shard.path)).unwrap();
+        }
+    }
+
+    #[test]
+    fn framings_are_undone_exactly() {
+        let text = |format, line: &[u8]| decode(format, false, line);
+        assert_eq!(
+            text(Format::Csv, b"7,\"a,\"\"b\"\"\"\n"),
+            Ok((Some(7), b"a,\"b\"".to_vec()))
+        );
+        assert_eq!(
+            text(
+                Format::Jsonl,
+                b"{\"index\":12,\"password\":\"q\\\"\\\\\"}\n"
+            ),
+            Ok((Some(12), b"q\"\\".to_vec()))
+        );
+        assert_eq!(
+            text(Format::ExpiringToken, b"3.99.00ff.a.b\n"),
+            Ok((Some(3), b"a.b".to_vec()))
+        );
+        assert_eq!(
+            decode(Format::Csv, true, b"0,00ff\n"),
+            Ok((Some(0), vec![0, 0xff]))
+        );
+        for (encoded, block) in [
+            ("AAE=", &[0, 1][..]),
+            ("/w==", &[0xff]),
+            ("AAEC", &[0, 1, 2]),
+        ] {
+            assert_eq!(decode_base64(encoded.as_bytes()).unwrap(), block);
+        }
+        for (format, bad) in [
+            (Format::Csv, &b"7,abc"[..]),
+            (Format::Csv, b"x,abc\n"),
+            (Format::Csv, b"7,\"ab\n"),
+            (Format::Csv, b"7,\"a\"b\"\n"),
+            (Format::Jsonl, b"{\"index\":1,\"password\":\"a\"b\"}\n"),
+            (Format::Jsonl, b"{\"index\":1,\"password\":\"a\\"),
+            (Format::ExpiringToken, b"3.99.pw\n"),
+            (Format::Hex, b"abc\n"),
+            (Format::Base64, b"A*==\n"),
+        ] {
+            assert!(
+                text(format, bad).is_err(),
+                "{:?}",
+                String::from_utf8_lossy(bad)
+            );
+        }
+        assert!(decode(Format::Raw, true, &[0; 15]).is_err());
+        assert!(decode(Format::Csv, true, b"0,zz\n").is_err());
+    }
+
+    #[test]
+    fn tokens_and_unknown_manifests() {
+        let g = PasswordGenerator::builder()
+            .key([8; 16])
+            .count(200)
+            .encoder(Encoder::new(&Charset::AlnumSymbols, 10))
+            .build()
+            .unwrap();
+        let dir = std::env::temp_dir();
+        let path = dir.join("mpg_corpus_tokens");
+        let mut out = Vec::new();
+        g.write_formatted(&ExpiringTokens::new(&g, 1_800_000_000), &mut out, |_, _| ())
+            .unwrap();
+        std::fs::write(&path, out).unwrap();
+        let reader = CorpusReader::file(&path, Format::ExpiringToken, false, None).unwrap();
+        let all: Vec<_> = reader.records().map(|r| r.unwrap()).collect();
+        assert!(all.iter().enumerate().all(|(i, r)| r.index == i));
+        assert!(all
+            .iter()
+            .map(|r| &r.password)
+            .eq(&g.records().collect::<Vec<_>>()));
+        std::fs::remove_file(path).unwrap();
+
+        let mut manifest = shard(&g, Format::Csv, 90, "versioned");
+        manifest.version = MANIFEST_VERSION + 1;
+        let e = CorpusReader::from_manifest(manifest.clone(), dir.clone())
+            .err()
+            .unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert!(e.to_string().contains("manifest version 2"), "{}", e);
+        for shard in &manifest.shards {
+            std::fs::remove_file(dir.join(&shard.path)).unwrap();
+        }
+    }
+
+    #[cfg(feature = "compress-zstd")]
+    #[test]
+    fn compressed_files_read_back() {
+        use crate::ZstdDictionary;
+
+        let g = PasswordGenerator::builder()
+            .key([7; 16])
+            .count(5000)
+            .chunk_size(1000)
+            .encoder(Encoder::new(&Charset::Alnum, 16))
+            .build()
+            .unwrap();
+        let sink = Format::Jsonl.sink(&g);
+        let zstd = ZstdDictionary::train(&g, sink.as_deref(), 3, 4096).unwrap();
+        let dir = std::env::temp_dir();
+        let (output, dictionary) = (
+            dir.join("mpg_corpus_zstd"),
+            dir.join("mpg_corpus_zstd.dict"),
+        );
+        let mut out = Vec::new();
+        g.write_compressed(sink.as_deref(), &zstd, &mut out, |_, _| ())
+            .unwrap();
+        std::fs::write(&output, out).unwrap();
+        std::fs::write(&dictionary, zstd.dictionary()).unwrap();
+        let reader = CorpusReader::file(&output, Format::Jsonl, false, Some(&dictionary)).unwrap();
+        assert_eq!(reader.count(), None);
+        let all: Vec<_> = reader.records().map(|r| r.unwrap().password).collect();
+        assert_eq!(all, g.records().collect::<Vec<_>>());
+        assert_eq!(reader.range(4990..6000).count(), 10);
+        std::fs::remove_file(output).unwrap();
+        std::fs::remove_file(dictionary).unwrap();
+    }
+}
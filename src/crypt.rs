@@ -0,0 +1,475 @@
+//! Password hashes in the formats credential stores take: bcrypt,
+//! Argon2id and SHA-512-crypt, each as its usual string (`$2b$10$...`,
+//! `$argon2id$v=19$...`, `$6$...`).
+//!
+//! [`HashedPairs`] writes a run's records as `password<TAB>hash` lines, for
+//! seeding test user databases. Each record's salt is HMAC-SHA256 of its
+//! index under the run's key (in a [`Lane`] of its own), so no two records share a salt and a
+//! reproducible run reproduces its hashes too. Hashing is what such a run
+//! spends its time on, so each chunk's records are hashed across the rayon
+//! pool rather than one after another.
+
+use argon2::password_hash::{PasswordHasher, SaltString};
+use argon2::{Algorithm, Argon2, Params, Version};
+use hmac::{Hmac, Mac};
+use rayon::prelude::*;
+use sha2::Sha256;
+use sha_crypt::{Sha512Params, ROUNDS_DEFAULT, ROUNDS_MAX, ROUNDS_MIN};
+use std::fmt;
+
+use crate::format::push_text;
+use crate::{Lane, OutputSink, PasswordGenerator};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Alphabet of crypt(3)'s base64, and of SHA-512-crypt salts.
+const CRYPT_B64: &[u8; 64] = b"./0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+/// Bytes of a password bcrypt reads; the rest of a longer one is ignored.
+pub(crate) const BCRYPT_MAX_KEY: usize = 72;
+
+/// Bytes of an Argon2id hash.
+const ARGON2_LEN: usize = 32;
+
+/// A password hashing scheme and its cost.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    /// `$2b$`, with 2^cost rounds of key expansion
+    Bcrypt { cost: u32 },
+    /// Argon2id v1.3 with `memory_kib` KiB, `passes` passes and `lanes`
+    /// lanes
+    Argon2id {
+        memory_kib: u32,
+        passes: u32,
+        lanes: u32,
+    },
+    /// `$6$`
+    Sha512Crypt { rounds: u32 },
+}
+
+impl HashAlgorithm {
+    /// Parses `bcrypt`, `argon2id` or `sha512-crypt`, optionally followed by
+    /// costs: `bcrypt:cost=12`, `argon2id:m=19456,t=2,p=1` (memory in KiB,
+    /// passes, lanes; any of them), `sha512-crypt:rounds=656000`. The
+    /// defaults are cost 10, OWASP's m=19456,t=2,p=1, and 5000 rounds.
+    /// SHA-512-crypt rounds outside 1000 to 999999999 are clamped into that
+    /// range, as crypt(3) does.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let (name, costs) = s.split_once(':').unwrap_or((s, ""));
+        let mut algorithm = match name {
+            "bcrypt" => HashAlgorithm::Bcrypt { cost: 10 },
+            "argon2id" => HashAlgorithm::Argon2id {
+                memory_kib: 19 * 1024,
+                passes: 2,
+                lanes: 1,
+            },
+            "sha512-crypt" => HashAlgorithm::Sha512Crypt {
+                rounds: ROUNDS_DEFAULT as u32,
+            },
+            _ => {
+                return Err(format!(
+                    "unknown hash `{}` (expected bcrypt, argon2id or sha512-crypt)",
+                    name
+                ))
+            }
+        };
+        for cost in costs.split(',').filter(|c| !c.is_empty()) {
+            let (key, value) = cost
+                .split_once('=')
+                .ok_or_else(|| format!("expected name=value, got `{}`", cost))?;
+            let value: u32 = value
+                .parse()
+                .map_err(|_| format!("`{}` is not a whole number", value))?;
+            let slot = match (&mut algorithm, key) {
+                (HashAlgorithm::Bcrypt { cost }, "cost") => cost,
+                (HashAlgorithm::Argon2id { memory_kib, .. }, "m") => memory_kib,
+                (HashAlgorithm::Argon2id { passes, .. }, "t") => passes,
+                (HashAlgorithm::Argon2id { lanes, .. }, "p") => lanes,
+                (HashAlgorithm::Sha512Crypt { rounds }, "rounds") => rounds,
+                _ => return Err(format!("{} has no setting `{}`", name, key)),
+            };
+            *slot = value;
+        }
+        match algorithm {
+            HashAlgorithm::Bcrypt { cost } if !(4..=31).contains(&cost) => {
+                Err(format!("bcrypt costs run from 4 to 31, not {}", cost))
+            }
+            HashAlgorithm::Argon2id {
+                memory_kib,
+                passes,
+                lanes,
+            } => match Params::new(memory_kib, passes, lanes, Some(ARGON2_LEN)) {
+                Ok(_) => Ok(algorithm),
+                Err(e) => Err(format!("argon2id: {}", e)),
+            },
+            HashAlgorithm::Sha512Crypt { rounds } => Ok(HashAlgorithm::Sha512Crypt {
+                rounds: rounds.clamp(ROUNDS_MIN as u32, ROUNDS_MAX as u32),
+            }),
+            _ => Ok(algorithm),
+        }
+    }
+
+    /// The hash of `password` under a salt drawn from `salt`.
+    pub fn hash(&self, password: &[u8], salt: &[u8; 16]) -> String {
+        match *self {
+            HashAlgorithm::Bcrypt { cost } => bcrypt(password, cost, *salt),
+            HashAlgorithm::Argon2id {
+                memory_kib,
+                passes,
+                lanes,
+            } => {
+                let params = Params::new(memory_kib, passes, lanes, Some(ARGON2_LEN))
+                    .expect("checked when parsed");
+                let salt = SaltString::encode_b64(salt).expect("16 bytes is a valid salt");
+                Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+                    .hash_password(password, &salt)
+                    .expect("the salt and output have valid lengths")
+                    .to_string()
+            }
+            HashAlgorithm::Sha512Crypt { rounds } => {
+                let salt: Vec<u8> = salt.iter().map(|&b| CRYPT_B64[b as usize % 64]).collect();
+                sha512_crypt(password, &salt, rounds)
+            }
+        }
+    }
+
+    /// Length of every hash string.
+    pub fn hash_len(&self) -> usize {
+        match *self {
+            HashAlgorithm::Bcrypt { .. } => 7 + 22 + 31,
+            HashAlgorithm::Argon2id {
+                memory_kib,
+                passes,
+                lanes,
+            } => {
+                format!("$argon2id$v=19$m={},t={},p={}$", memory_kib, passes, lanes).len()
+                    + 22
+                    + 1
+                    + 43
+            }
+            HashAlgorithm::Sha512Crypt { rounds } => {
+                let prefix = match rounds as usize {
+                    ROUNDS_DEFAULT => 0,
+                    _ => format!("rounds={}$", rounds).len(),
+                };
+                3 + prefix + 16 + 1 + 86
+            }
+        }
+    }
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HashAlgorithm::Bcrypt { cost } => write!(f, "bcrypt:cost={}", cost),
+            HashAlgorithm::Argon2id {
+                memory_kib,
+                passes,
+                lanes,
+            } => write!(f, "argon2id:m={},t={},p={}", memory_kib, passes, lanes),
+            HashAlgorithm::Sha512Crypt { rounds } => write!(f, "sha512-crypt:rounds={}", rounds),
+        }
+    }
+}
+
+/// Writes `generator`'s records as `password<TAB>hash` lines (the password
+/// in hex for raw blocks).
+pub struct HashedPairs {
+    algorithm: HashAlgorithm,
+    mac: HmacSha256,
+    binary: bool,
+}
+
+impl HashedPairs {
+    /// Fails for bcrypt if records can be longer than the 72 bytes it reads.
+    pub fn new(generator: &PasswordGenerator, algorithm: HashAlgorithm) -> Result<Self, String> {
+        let binary = generator.is_binary();
+        let longest = match binary {
+            true => generator.record_width() * 2,
+            false => generator.record_width() - 1,
+        };
+        if matches!(algorithm, HashAlgorithm::Bcrypt { .. }) && longest > BCRYPT_MAX_KEY {
+            return Err(format!(
+                "bcrypt reads only the first {} bytes of a password, and these run to {}; \
+                 use argon2id or sha512-crypt",
+                BCRYPT_MAX_KEY, longest
+            ));
+        }
+        Ok(HashedPairs {
+            algorithm,
+            mac: Lane::HASH_SALT.mac(&generator.key),
+            binary,
+        })
+    }
+
+    fn salt(&self, index: usize) -> [u8; 16] {
+        let mut mac = self.mac.clone();
+        mac.update(&(index as u64).to_le_bytes());
+        mac.finalize().into_bytes()[..16].try_into().unwrap()
+    }
+}
+
+impl OutputSink for HashedPairs {
+    fn write_record(&self, index: usize, record: &[u8], out: &mut Vec<u8>) {
+        let start = out.len();
+        self.credential(index, record, out);
+        let hash = self.algorithm.hash(&out[start..], &self.salt(index));
+        out.push(b'\t');
+        out.extend_from_slice(hash.as_bytes());
+        out.push(b'\n');
+    }
+
+    fn record_len(&self, _index: usize, record_len: usize) -> Option<usize> {
+        let password = if self.binary {
+            record_len * 2
+        } else {
+            record_len
+        };
+        Some(password + 1 + self.algorithm.hash_len() + 1)
+    }
+
+    fn credential(&self, _index: usize, record: &[u8], out: &mut Vec<u8>) {
+        push_text(self.binary, record, out);
+    }
+
+    fn write_chunk(
+        &self,
+        generator: &PasswordGenerator,
+        first: usize,
+        data: &[u8],
+        out: &mut Vec<u8>,
+    ) {
+        let mut records = Vec::with_capacity(generator.chunk_size());
+        generator.for_each_record(data, |record| records.push(record));
+        let lines: Vec<Vec<u8>> = records
+            .par_iter()
+            .enumerate()
+            .map(|(i, record)| {
+                let mut line = Vec::new();
+                self.write_record(first + i, record, &mut line);
+                line
+            })
+            .collect();
+        for line in lines {
+            out.extend_from_slice(&line);
+        }
+    }
+}
+
+/// `$2b$` bcrypt of `password`, of which only the first 72 bytes count.
+pub(crate) fn bcrypt(password: &[u8], cost: u32, salt: [u8; 16]) -> String {
+    bcrypt::hash_with_salt(password, cost, salt)
+        .expect("the cost was checked when parsed")
+        .format_for_version(bcrypt::Version::TwoB)
+}
+
+/// `$6$` SHA-512-crypt of `password` under `salt` (crypt(3) characters, of
+/// which the first 16 count), with `rounds` (within the crypt(3) range)
+/// recorded unless it is the default.
+fn sha512_crypt(password: &[u8], salt: &[u8], rounds: u32) -> String {
+    let salt = &salt[..salt.len().min(16)];
+    let params = Sha512Params::new(rounds as usize).expect("rounds are clamped when parsed");
+    let checksum = sha_crypt::sha512_crypt_b64(password, salt, &params)
+        .expect("the salt is crypt(3) characters");
+    let salt = std::str::from_utf8(salt).expect("the salt is crypt(3) characters");
+    match rounds as usize {
+        ROUNDS_DEFAULT => format!("$6${}${}", salt, checksum),
+        _ => format!("$6$rounds={}${}${}", rounds, salt, checksum),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Charset, Encoder};
+
+    /// Decodes a bcrypt salt string (22 characters of bcrypt's base64).
+    fn bcrypt_salt(text: &str) -> [u8; 16] {
+        let alphabet = b"./ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+        let bits: Vec<bool> = text
+            .bytes()
+            .flat_map(|c| {
+                let n = alphabet.iter().position(|&x| x == c).unwrap();
+                (0..6).rev().map(move |i| n >> i & 1 == 1)
+            })
+            .collect();
+        let mut salt = [0; 16];
+        for (i, byte) in salt.iter_mut().enumerate() {
+            *byte = (0..8).fold(0, |b, j| b << 1 | bits[i * 8 + j] as u8);
+        }
+        salt
+    }
+
+    #[test]
+    fn bcrypt_matches_openbsd_vectors() {
+        // From the OpenBSD and Openwall crypt_blowfish test suites
+        let long = "0123456789abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789\
+                    chars after 72 are ignored";
+        for (password, expected) in [
+            (
+                "U*U",
+                "$2b$05$CCCCCCCCCCCCCCCCCCCCC.E5YPO9kmyuRGyh0XouQYb4YMJKvyOeW",
+            ),
+            (
+                "U*U*",
+                "$2b$05$CCCCCCCCCCCCCCCCCCCCC.VGOzA784oUp/Z0DY336zx7pLYAy0lwK",
+            ),
+            (
+                "",
+                "$2b$05$CCCCCCCCCCCCCCCCCCCCC.7uG0VCzI2bS7j6ymqJi9CdcdxiRTWNy",
+            ),
+            (
+                long,
+                "$2b$05$abcdefghijklmnopqrstuu5s2v8.iXieOjg/.AySBTTZIIVFJeBui",
+            ),
+        ] {
+            let salt = bcrypt_salt(&expected[7..29]);
+            let algorithm = HashAlgorithm::Bcrypt { cost: 5 };
+            let hash = algorithm.hash(password.as_bytes(), &salt);
+            assert_eq!(hash, expected, "{:?}", password);
+            assert_eq!(hash.len(), algorithm.hash_len());
+        }
+        // Only the first 72 bytes count
+        let salt = bcrypt_salt("abcdefghijklmnopqrstuu");
+        assert_eq!(
+            bcrypt(&long.as_bytes()[..72], 5, salt),
+            bcrypt(long.as_bytes(), 5, salt)
+        );
+    }
+
+    #[test]
+    fn sha512_crypt_matches_drepper_vectors() {
+        // From Ulrich Drepper's SHA-crypt specification
+        for (password, salt, rounds, expected) in [
+            (
+                "Hello world!",
+                "saltstring",
+                5000,
+                "$6$saltstring$svn8UoSVapNtMuq1ukKS4tPQd8iKwSMHWjl/O817G3uBnIFNjnQJuesI68u4OTL\
+                 iBFdcbYEdFCoEOfaS35inz1",
+            ),
+            (
+                "Hello world!",
+                "saltstringsaltstring",
+                10000,
+                "$6$rounds=10000$saltstringsaltst$OW1/O6BYHV6BcXZu8QVeXbDWra3Oeqh0sbHbbMCVNSnCM\
+                 /UrjmM0Dp8vOuZeHBy/YTBmSK6H9qs/y3RnOaw5v.",
+            ),
+            (
+                "This is just a test",
+                "toolongsaltstring",
+                5000,
+                "$6$toolongsaltstrin$lQ8jolhgVRVhY4b5pZKaysCLi0QBxGoNeKQzQ3glMhwllF7oGDZxUhx1yxd\
+                 Ycz/e1JSbq3y6JMxxl8audkUEm0",
+            ),
+        ] {
+            let hash = sha512_crypt(password.as_bytes(), salt.as_bytes(), rounds);
+            assert_eq!(hash, expected);
+        }
+
+        // rounds=10 is observed as the minimum
+        let HashAlgorithm::Sha512Crypt { rounds } =
+            HashAlgorithm::parse("sha512-crypt:rounds=10").unwrap()
+        else {
+            unreachable!()
+        };
+        assert_eq!(rounds, 1000);
+        assert_eq!(
+            sha512_crypt(
+                b"the minimum number is still observed",
+                b"roundstoolow",
+                rounds
+            ),
+            "$6$rounds=1000$roundstoolow$kUMsbe306n21p9R.FRkW3IGn.S9NPN0x50YhH1xhLsPuWGsUSklZt5\
+             8jaTfF4ZEQpyUNGc0dqbpBYYBaHHrsX."
+        );
+        assert_eq!(
+            HashAlgorithm::parse("sha512-crypt:rounds=4294967295").unwrap(),
+            HashAlgorithm::Sha512Crypt {
+                rounds: 999_999_999
+            }
+        );
+        assert_eq!(
+            HashAlgorithm::parse("sha512-crypt").unwrap().to_string(),
+            "sha512-crypt:rounds=5000"
+        );
+        let default = HashAlgorithm::Sha512Crypt { rounds: 5000 };
+        assert_eq!(default.hash(b"pw", &[1; 16]).len(), default.hash_len());
+        let custom = HashAlgorithm::Sha512Crypt { rounds: 1234 };
+        assert!(custom.hash(b"pw", &[1; 16]).starts_with("$6$rounds=1234$"));
+        assert_eq!(custom.hash(b"pw", &[1; 16]).len(), custom.hash_len());
+    }
+
+    #[test]
+    fn argon2id_writes_phc_strings() {
+        let algorithm = HashAlgorithm::parse("argon2id:m=64,t=1,p=1").unwrap();
+        assert_eq!(algorithm.to_string(), "argon2id:m=64,t=1,p=1");
+        let hash = algorithm.hash(b"password", &[7; 16]);
+        assert!(hash.starts_with("$argon2id$v=19$m=64,t=1,p=1$BwcHBwcHBwcHBwcHBwcHBw$"));
+        assert_eq!(hash.len(), algorithm.hash_len());
+        assert_ne!(hash, algorithm.hash(b"password", &[8; 16]));
+    }
+
+    #[test]
+    fn bad_specs_are_refused() {
+        assert_eq!(
+            HashAlgorithm::parse("bcrypt").unwrap(),
+            HashAlgorithm::Bcrypt { cost: 10 }
+        );
+        for bad in [
+            "md5",
+            "bcrypt:cost=3",
+            "bcrypt:cost=32",
+            "bcrypt:rounds=5",
+            "argon2id:m=1",
+            "argon2id:q=1",
+            "sha512-crypt:rounds=x",
+            "sha512-crypt:rounds",
+        ] {
+            assert!(HashAlgorithm::parse(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn pairs_hash_every_record_with_its_own_salt() {
+        let g = PasswordGenerator::builder()
+            .key([2; 16])
+            .count(40)
+            .chunk_size(16)
+            .encoder(Encoder::new(&Charset::Alnum, 12))
+            .build()
+            .unwrap();
+        let algorithm = HashAlgorithm::parse("sha512-crypt:rounds=1000").unwrap();
+        let sink = HashedPairs::new(&g, algorithm).unwrap();
+        let mut out = Vec::new();
+        g.write_formatted(&sink, &mut out, |_, _| ()).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        let mut salts = Vec::new();
+        for (i, (line, record)) in text.lines().zip(g.records()).enumerate() {
+            let (password, hash) = line.split_once('\t').unwrap();
+            assert_eq!(password.as_bytes(), record);
+            assert_eq!(hash, algorithm.hash(&record, &sink.salt(i)));
+            assert_eq!(sink.record_len(i, 12), Some(line.len() + 1));
+            salts.push(hash[..32].to_string());
+        }
+        salts.sort();
+        salts.dedup();
+        assert_eq!(salts.len(), 40);
+
+        // Records longer than bcrypt reads are refused
+        let long = PasswordGenerator::builder()
+            .key([2; 16])
+            .count(1)
+            .encoder(Encoder::new(&Charset::Alnum, 73))
+            .build()
+            .unwrap();
+        assert!(HashedPairs::new(&long, HashAlgorithm::Bcrypt { cost: 4 }).is_err());
+        let binary = PasswordGenerator::builder()
+            .key([2; 16])
+            .count(1)
+            .build()
+            .unwrap();
+        let sink = HashedPairs::new(&binary, HashAlgorithm::Bcrypt { cost: 4 }).unwrap();
+        assert_eq!(sink.record_len(0, 16), Some(32 + 1 + 60 + 1));
+    }
+}
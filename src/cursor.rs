@@ -0,0 +1,371 @@
+//! Paging through one fixed run: `GET /passwords?cursor=C&limit=N` under
+//! `serve --run-key`.
+//!
+//! The run is a key and record options fixed when the server starts, so a
+//! record's index names it for good: restart the server with the same
+//! options and index 1234 is the same password. A cursor is that absolute
+//! index and a tag, an HMAC of the index and the run's shape under the
+//! run key's cursor lane. A client can keep a cursor across disconnects
+//! and restarts and pick up exactly where it stopped, but cannot forge
+//! one, or carry one over to another run.
+//!
+//! Pages are the run's output cut at record boundaries: the first starts
+//! with the format's header, and the pages in order add up to the bytes a
+//! file run with the same options writes. Chunks come from the
+//! [`cache`](crate::cache), so clients paging over the same records share
+//! one encoding of them.
+
+use hmac::{Hmac, Mac};
+use mass_password_gen_optimized::{
+    hex, key_fingerprint, Format, Key, Lane, OutputSink, PasswordGenerator,
+};
+use sha2::Sha256;
+use std::sync::Arc;
+
+use crate::cache::{ChunkCache, ChunkKey};
+
+/// The lane of the run key that tags cursors.
+const CURSOR_LANE: &str = "serve-cursor";
+
+/// Hex digits of a cursor's tag.
+const TAG_HEX: usize = 16;
+
+/// The run `serve --run-key` pages through.
+pub struct PagedRun {
+    generator: PasswordGenerator,
+    format: Format,
+    /// The sink of the run's own --format
+    sink: Option<Box<dyn OutputSink>>,
+    cache: ChunkCache,
+    /// Everything that determines the records but the key
+    shape: String,
+    tags: [u8; 32],
+    fingerprint: String,
+}
+
+/// The format of a page: the run's own, or another one.
+pub struct PageSink {
+    format: Option<Format>,
+    sink: Option<Box<dyn OutputSink>>,
+}
+
+/// One chunk of the run as one format writes it, with where each record
+/// ends.
+pub struct Encoded {
+    bytes: Vec<u8>,
+    ends: Vec<usize>,
+}
+
+impl PagedRun {
+    /// The run of `generator`, under `key`, written through `sink` in
+    /// `format`, with chunks cached in `cache`; `shape` must tell apart
+    /// every pair of runs that differ in anything but the key.
+    pub fn new(
+        generator: PasswordGenerator,
+        format: Format,
+        sink: Option<Box<dyn OutputSink>>,
+        key: &Key,
+        shape: String,
+        cache: ChunkCache,
+    ) -> Self {
+        let shape = format!(
+            "{}/{}/{}/{}",
+            shape,
+            generator.count(),
+            generator.chunk_size(),
+            generator.cipher()
+        );
+        PagedRun {
+            generator,
+            format,
+            sink,
+            cache,
+            shape,
+            tags: Lane::new(CURSOR_LANE).subkey(key),
+            fingerprint: key_fingerprint(key),
+        }
+    }
+
+    pub fn generator(&self) -> &PasswordGenerator {
+        &self.generator
+    }
+
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    pub fn fingerprint(&self) -> &str {
+        &self.fingerprint
+    }
+
+    pub fn cache(&self) -> &ChunkCache {
+        &self.cache
+    }
+
+    /// The cursor of record `index`: `<index>.<tag>`.
+    pub fn cursor(&self, index: usize) -> String {
+        format!("{}.{}", index, hex(&self.tag(index)[..TAG_HEX / 2]))
+    }
+
+    /// The index a cursor from [`cursor`](Self::cursor) stands at; an empty
+    /// cursor is the start of the run.
+    pub fn parse(&self, cursor: &str) -> Result<usize, String> {
+        if cursor.is_empty() {
+            return Ok(0);
+        }
+        let invalid = || format!("`{}` is not a cursor of this run", cursor);
+        let (index, tag) = cursor.split_once('.').ok_or_else(invalid)?;
+        let digits = |s: &str, of: &[u8]| !s.is_empty() && s.bytes().all(|b| of.contains(&b));
+        if !digits(index, b"0123456789")
+            || tag.len() != TAG_HEX
+            || !digits(tag, b"0123456789abcdef")
+        {
+            return Err(invalid());
+        }
+        let index: usize = index.parse().map_err(|_| invalid())?;
+        if index > self.generator.count() {
+            return Err(invalid());
+        }
+        let tag: Vec<u8> = (0..TAG_HEX)
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&tag[i..i + 2], 16).expect("hex digits"))
+            .collect();
+        self.mac(index)
+            .verify_truncated_left(&tag)
+            .map_err(|_| invalid())?;
+        Ok(index)
+    }
+
+    fn mac(&self, index: usize) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.tags).expect("HMAC accepts any key length");
+        mac.update(self.shape.as_bytes());
+        mac.update(b"\n");
+        mac.update(&(index as u64).to_be_bytes());
+        mac
+    }
+
+    fn tag(&self, index: usize) -> [u8; 32] {
+        self.mac(index).finalize().into_bytes().into()
+    }
+
+    /// Pages in `format`, or the run's own without one; tokens cannot be
+    /// paged, since their expiry moves with every restart, and hashcat
+    /// lines only in the run's own hash mode.
+    pub fn sink_for(&self, format: Option<Format>) -> Result<PageSink, String> {
+        let format = format.filter(|&format| format != self.format);
+        let sink = match format {
+            None => None,
+            Some(Format::ExpiringToken) => {
+                return Err(
+                    "tokens expire from when they are minted, so a page of them \
+                            could never be fetched again"
+                        .to_string(),
+                )
+            }
+            Some(Format::Hashcat) => {
+                return Err("hashcat lines need a hash mode; serve the run with \
+                            --format hashcat and its --hash-mode instead"
+                    .to_string())
+            }
+            Some(format) => Some(format.sink(&self.generator).unwrap_or_else(|| {
+                Box::new(Native(self.generator.is_binary())) as Box<dyn OutputSink>
+            })),
+        };
+        Ok(PageSink { format, sink })
+    }
+
+    /// The records `range` of the run in `format`, the header first when
+    /// the range starts the run.
+    pub fn page(
+        &self,
+        format: &PageSink,
+        range: std::ops::Range<usize>,
+        out: &mut dyn std::io::Write,
+    ) -> std::io::Result<()> {
+        let sink = format.sink.as_deref().or(self.sink.as_deref());
+        if range.start == 0 {
+            out.write_all(sink.map_or(&b""[..], |sink| sink.header()))?;
+        }
+        let size = self.generator.chunk_size();
+        let mut index = range.start;
+        while index < range.end {
+            let chunk = index / size;
+            let key = ChunkKey {
+                fingerprint: self.fingerprint.clone(),
+                chunk,
+                format: format.format,
+            };
+            let encoded: Arc<Encoded> = self.cache.get(key, || self.encode(sink, chunk));
+            let first = chunk * size;
+            let last = range.end.min(first + self.generator.chunk_len(chunk));
+            out.write_all(encoded.records(index - first..last - first))?;
+            index = last;
+        }
+        Ok(())
+    }
+
+    /// Chunk `chunk` of the run through `sink`.
+    pub fn encode(&self, sink: Option<&dyn OutputSink>, chunk: usize) -> Encoded {
+        let generator = &self.generator;
+        let mut data = vec![0; generator.chunk_len(chunk) * generator.record_width()];
+        let used = generator.fill_chunk(chunk, &mut Vec::new(), &mut data);
+        data.truncate(used);
+        let native = Native(generator.is_binary());
+        let sink = sink.unwrap_or(&native);
+        let mut encoded = Encoded {
+            bytes: Vec::with_capacity(data.len()),
+            ends: Vec::with_capacity(generator.chunk_len(chunk)),
+        };
+        let mut index = chunk * generator.chunk_size();
+        generator.for_each_record(&data, |record| {
+            sink.write_record(index, record, &mut encoded.bytes);
+            encoded.ends.push(encoded.bytes.len());
+            index += 1;
+        });
+        encoded
+    }
+}
+
+impl Encoded {
+    #[cfg(test)]
+    pub fn new(bytes: Vec<u8>, ends: Vec<usize>) -> Self {
+        Encoded { bytes, ends }
+    }
+
+    /// The bytes of the records `range` of the chunk.
+    pub fn records(&self, range: std::ops::Range<usize>) -> &[u8] {
+        let start = match range.start {
+            0 => 0,
+            i => self.ends[i - 1],
+        };
+        &self.bytes[start..self.ends[range.end - 1]]
+    }
+
+    /// Heap bytes held.
+    pub fn size(&self) -> usize {
+        self.bytes.capacity() + self.ends.capacity() * std::mem::size_of::<usize>()
+    }
+}
+
+/// Records as a run without a sink writes them: raw blocks, or text lines.
+struct Native(bool);
+
+impl OutputSink for Native {
+    fn write_record(&self, _index: usize, record: &[u8], out: &mut Vec<u8>) {
+        out.extend_from_slice(record);
+        if !self.0 {
+            out.push(b'\n');
+        }
+    }
+
+    fn record_len(&self, _index: usize, record_len: usize) -> Option<usize> {
+        Some(record_len + usize::from(!self.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mass_password_gen_optimized::{Charset, Encoder};
+
+    fn run(key: Key, format: Format) -> PagedRun {
+        let generator = PasswordGenerator::builder()
+            .key(key)
+            .count(1000)
+            .chunk_size(64)
+            .encoder(Encoder::new(&Charset::parse("alnum").unwrap(), 12))
+            .build()
+            .unwrap();
+        let sink = format.sink(&generator);
+        let cache = ChunkCache::new(1 << 20);
+        PagedRun::new(generator, format, sink, &key, "alnum/12".into(), cache)
+    }
+
+    fn whole(run: &PagedRun) -> Vec<u8> {
+        let mut out = Vec::new();
+        match &run.sink {
+            Some(sink) => run
+                .generator
+                .write_formatted(sink.as_ref(), &mut out, |_, _| ()),
+            None => run.generator.write_to(&mut out, |_, _| ()),
+        }
+        .unwrap();
+        out
+    }
+
+    /// Follows cursors from `cursor` to the end, `limit` records a page.
+    fn pull(run: &PagedRun, mut cursor: String, limit: usize) -> Vec<u8> {
+        let mut out = Vec::new();
+        loop {
+            let start = run.parse(&cursor).unwrap();
+            let end = run.generator.count().min(start + limit);
+            run.page(&run.sink_for(None).unwrap(), start..end, &mut out)
+                .unwrap();
+            if end == run.generator.count() {
+                return out;
+            }
+            cursor = run.cursor(end);
+        }
+    }
+
+    #[test]
+    fn pages_add_up_to_the_run() {
+        for format in [Format::Raw, Format::Csv, Format::Jsonl] {
+            let run = run(Key::from([3; 16]), format);
+            let expected = whole(&run);
+            // Pages that straddle chunks, and pages of whole chunks
+            assert_eq!(pull(&run, String::new(), 100), expected);
+            assert_eq!(pull(&run, String::new(), 64), expected);
+            assert_eq!(pull(&run, String::new(), 1000), expected);
+        }
+    }
+
+    #[test]
+    fn cursors_resume_where_they_stopped() {
+        let run = run(Key::from([3; 16]), Format::Csv);
+        let expected = whole(&run);
+        let mut first = Vec::new();
+        run.page(&run.sink_for(None).unwrap(), 0..437, &mut first)
+            .unwrap();
+        let cursor = run.cursor(437);
+        // The server restarts: the same options give the same run, and the
+        // cursor picks up exactly where the first page stopped
+        let again = self::run(Key::from([3; 16]), Format::Csv);
+        let rest = pull(&again, cursor.clone(), 250);
+        assert_eq!([first, rest].concat(), expected);
+
+        // Another format of the same pages carries no header mid-run
+        let mut hex = Vec::new();
+        let sink = run.sink_for(Some(Format::Hex)).unwrap();
+        run.page(&sink, 437..438, &mut hex).unwrap();
+        assert_eq!(hex.len(), 2 * 12 + 1);
+        assert!(run.sink_for(Some(Format::ExpiringToken)).is_err());
+    }
+
+    #[test]
+    fn cursors_cannot_be_forged_or_carried_over() {
+        let run = run(Key::from([3; 16]), Format::Raw);
+        let cursor = run.cursor(500);
+        assert_eq!(run.parse(&cursor), Ok(500));
+        assert_eq!(run.parse(&run.cursor(1000)), Ok(1000));
+        let (_, tag) = cursor.split_once('.').unwrap();
+        for forged in [
+            format!("501.{}", tag),
+            "500".to_string(),
+            format!("500.{}", &tag[1..]),
+            format!("500.{}0", tag),
+            "500.zzzzzzzzzzzzzzzz".to_string(),
+            format!("1001.{}", run.cursor(1001).split_once('.').unwrap().1),
+        ] {
+            assert!(run.parse(&forged).is_err(), "{}", forged);
+        }
+        // Another key, or the same key with other record options
+        let other = self::run(Key::from([4; 16]), Format::Raw);
+        assert!(other.parse(&cursor).is_err());
+        let generator = run.generator.clone();
+        let (key, cache) = (Key::from([3; 16]), ChunkCache::new(0));
+        let longer = PagedRun::new(generator, Format::Raw, None, &key, "alnum/13".into(), cache);
+        assert!(longer.parse(&cursor).is_err());
+    }
+}
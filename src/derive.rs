@@ -0,0 +1,221 @@
+//! Stateless per-site passwords.
+//!
+//! A master passphrase is stretched with Argon2id into a generator key. A
+//! site's label (its name, account and generation) is then hashed with
+//! HMAC-SHA256 under that key into a record index, and the site's password
+//! is the record at that index of the key's [`IvStrategy::Continuous`] run,
+//! encoded with the usual charset, policy or passphrase options. Nothing is
+//! stored: the same passphrase and label always give the same password,
+//! and bumping the generation moves the site to an unrelated record.
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::{BuildError, Cipher, IvStrategy, PasswordGeneratorBuilder};
+
+/// Argon2id cost: 64 MiB and three passes, a fraction of a second per run.
+const KDF_MEMORY_KIB: u32 = 64 * 1024;
+const KDF_PASSES: u32 = 3;
+
+/// Prefix of the Argon2id salt; the user name follows it.
+const SALT_PREFIX: &[u8] = b"mass_password_gen_optimized/derive/v1\0";
+
+/// Sites are spread over this many records.
+pub const SITE_SPACE: u64 = 1 << 48;
+
+/// A site or service and the account on it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Site {
+    /// Site name, e.g. `example.com`; case does not matter
+    pub name: String,
+    /// Account on the site, if there is more than one
+    pub account: Option<String>,
+    /// Bumped to rotate the site's password; starts at 1
+    pub generation: u32,
+}
+
+impl Site {
+    /// The bytes hashed into the site's index. Every field is length-prefixed
+    /// so that no two sites share a label.
+    fn label(&self) -> Vec<u8> {
+        let mut label = Vec::new();
+        let name = self.name.trim().to_lowercase();
+        for field in [
+            name.as_bytes(),
+            self.account.as_deref().unwrap_or("").as_bytes(),
+        ] {
+            label.extend_from_slice(&(field.len() as u64).to_le_bytes());
+            label.extend_from_slice(field);
+        }
+        label.extend_from_slice(&self.generation.to_le_bytes());
+        label
+    }
+}
+
+/// The generator key stretched from a master passphrase.
+pub struct MasterKey {
+    key: [u8; 16],
+}
+
+impl MasterKey {
+    /// Stretches `passphrase` with Argon2id, salted with `user` (which may be
+    /// empty) so that two people with the same passphrase get different
+    /// keys.
+    pub fn from_passphrase(passphrase: &[u8], user: &str) -> Result<Self, String> {
+        Self::stretch(passphrase, user, KDF_MEMORY_KIB, KDF_PASSES)
+    }
+
+    fn stretch(passphrase: &[u8], user: &str, memory: u32, passes: u32) -> Result<Self, String> {
+        if passphrase.is_empty() {
+            return Err("the master passphrase is empty".to_string());
+        }
+        let params = Params::new(memory, passes, 1, Some(16)).map_err(|e| e.to_string())?;
+        let salt = [SALT_PREFIX, user.as_bytes()].concat();
+        let mut key = [0u8; 16];
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase, &salt, &mut key)
+            .map_err(|e| e.to_string())?;
+        Ok(MasterKey { key })
+    }
+
+    /// The stretched key, for [`key_fingerprint`](crate::key_fingerprint).
+    pub fn key(&self) -> &[u8; 16] {
+        &self.key
+    }
+
+    /// Index of `site`'s record, below [`SITE_SPACE`].
+    pub fn index(&self, site: &Site) -> usize {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC accepts any key length");
+        mac.update(&site.label());
+        let digest = mac.finalize().into_bytes();
+        (u64::from_le_bytes(digest[..8].try_into().unwrap()) % SITE_SPACE) as usize
+    }
+
+    /// `site`'s password, encoded as `records` says (its key, cipher, layout
+    /// and count are replaced). Text records lose their newline; raw ones
+    /// are the 16-byte block.
+    pub fn password(
+        &self,
+        site: &Site,
+        records: PasswordGeneratorBuilder,
+    ) -> Result<Vec<u8>, BuildError> {
+        let index = self.index(site);
+        // One record per chunk, so only the site's chunk is generated
+        let generator = records
+            .key(self.key)
+            .cipher(Cipher::Aes128)
+            .iv_strategy(IvStrategy::Continuous)
+            .count(index + 1)
+            .chunk_size(1)
+            .build()?;
+        let mut password = generator.chunk(index).data;
+        if !generator.is_binary() {
+            password.pop();
+        }
+        Ok(password)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Charset, Encoder, PasswordGenerator};
+
+    fn site(name: &str, account: Option<&str>, generation: u32) -> Site {
+        Site {
+            name: name.to_string(),
+            account: account.map(str::to_string),
+            generation,
+        }
+    }
+
+    #[test]
+    fn sites_get_stable_distinct_passwords() {
+        // Cheap parameters; the real ones take too long unoptimized
+        let master = MasterKey::stretch(b"correct horse", "alice", 64, 1).unwrap();
+        assert_eq!(
+            master.key,
+            MasterKey::stretch(b"correct horse", "alice", 64, 1)
+                .unwrap()
+                .key
+        );
+        assert_ne!(
+            master.key,
+            MasterKey::stretch(b"correct horse", "bob", 64, 1)
+                .unwrap()
+                .key
+        );
+        assert!(MasterKey::stretch(b"", "alice", 64, 1).is_err());
+
+        let records = || PasswordGenerator::builder().encoder(Encoder::new(&Charset::Alnum, 20));
+        let example = site("example.com", None, 1);
+        let password = master.password(&example, records()).unwrap();
+        assert_eq!(password.len(), 20);
+        assert_eq!(
+            master
+                .password(&site(" Example.COM", None, 1), records())
+                .unwrap(),
+            password
+        );
+        for other in [
+            site("example.org", None, 1),
+            site("example.com", Some("work"), 1),
+            site("example.com", None, 2),
+        ] {
+            assert_ne!(master.password(&other, records()).unwrap(), password);
+        }
+
+        // It is the record at the site's index of the master key's run
+        let index = master.index(&example);
+        let run = records()
+            .key(master.key)
+            .iv_strategy(IvStrategy::Continuous)
+            .count(index + 1)
+            .chunk_size(1 << 20)
+            .build()
+            .unwrap();
+        let chunk = run.chunk(index >> 20);
+        let offset = (index & ((1 << 20) - 1)) * 21;
+        assert_eq!(chunk.data[offset..offset + 20], password[..]);
+    }
+
+    #[test]
+    fn labels_and_layout_are_the_derivations_own() {
+        let master = MasterKey::stretch(b"correct horse", "", 64, 1).unwrap();
+        assert_ne!(
+            master.key,
+            MasterKey::stretch(b"correct horse", "alice", 64, 1)
+                .unwrap()
+                .key
+        );
+
+        // Length prefixes keep a name from running into its account
+        assert_ne!(
+            master.index(&site("ab", Some("c"), 1)),
+            master.index(&site("a", Some("bc"), 1))
+        );
+        assert_eq!(
+            master.index(&site("example.com", None, 1)),
+            master.index(&site("example.com", Some(""), 1))
+        );
+        for generation in 1..100 {
+            assert!((master.index(&site("example.com", None, generation)) as u64) < SITE_SPACE);
+        }
+
+        // The caller's key, cipher and count do not move the password
+        let example = site("example.com", None, 1);
+        let raw = master
+            .password(&example, PasswordGenerator::builder())
+            .unwrap();
+        assert_eq!(raw.len(), 16);
+        let overridden = PasswordGenerator::builder()
+            .key([9; 16])
+            .cipher(Cipher::ChaCha20)
+            .iv_strategy(IvStrategy::ChunkIndex)
+            .count(5)
+            .chunk_size(64);
+        assert_eq!(master.password(&example, overridden).unwrap(), raw);
+    }
+}
@@ -0,0 +1,231 @@
+//! Double generation: every chunk made twice and the copies compared before
+//! it is written, for hardware that has been caught corrupting corpora.
+//!
+//! A [`DoubleCheck`] makes each chunk
+//! [`fill_chunk`](crate::PasswordGenerator::fill_chunk) generates a second
+//! time into a buffer of its own: on the same thread, or, with
+//! [`Placement::OtherCores`], both copies at once on two threads pinned to
+//! different cores. When the copies agree the chunk goes out as made. When
+//! they do not, more copies are made until two agree, and that one goes
+//! out; a chunk no two of [`MAX_COPIES`] copies agree on goes out as first
+//! made and is reported, for the run to fail.
+//!
+//! Every chunk takes twice the work, so generation runs at about half speed.
+
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use core_affinity::CoreId;
+use serde::Serialize;
+
+use crate::PasswordGenerator;
+
+/// Most copies made of a chunk whose first two disagree.
+pub const MAX_COPIES: usize = 5;
+
+thread_local! {
+    /// The second copy and its keystream, reused from chunk to chunk.
+    static SPARE: RefCell<(Vec<u8>, Vec<u8>)> = RefCell::default();
+}
+
+/// Where the two copies of a chunk are made.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Placement {
+    /// One after the other, on the worker's thread
+    #[default]
+    SameThread,
+    /// At once, on two threads pinned to different cores
+    OtherCores,
+}
+
+impl Placement {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s {
+            "same-thread" => Ok(Placement::SameThread),
+            "other-cores" => Ok(Placement::OtherCores),
+            _ => Err(format!(
+                "unknown placement `{}` (expected same-thread or other-cores)",
+                s
+            )),
+        }
+    }
+}
+
+/// Compares two copies of every chunk; pass it to
+/// [`PasswordGenerator::with_double_check`](crate::PasswordGenerator::with_double_check).
+pub struct DoubleCheck {
+    placement: Placement,
+    cores: Vec<CoreId>,
+    chunks: AtomicUsize,
+    /// Chunks whose first two copies disagreed
+    disagreed: Mutex<Vec<usize>>,
+    /// Of those, chunks no two copies agreed on
+    unresolved: Mutex<Vec<usize>>,
+}
+
+/// What a [`DoubleCheck`] found.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct DoubleCheckReport {
+    /// Chunks compared
+    pub chunks: usize,
+    /// Chunks whose first two copies disagreed, in order
+    pub disagreed: Vec<usize>,
+    /// Of those, chunks no two of [`MAX_COPIES`] copies agreed on, written
+    /// as first made
+    pub unresolved: Vec<usize>,
+}
+
+impl DoubleCheck {
+    /// A check making the copies as `placement` says; other cores need at
+    /// least two the process may run on.
+    pub fn new(placement: Placement) -> Result<Arc<Self>, String> {
+        let cores = match placement {
+            Placement::SameThread => Vec::new(),
+            Placement::OtherCores => match core_affinity::get_core_ids() {
+                Some(cores) if cores.len() >= 2 => cores,
+                _ => {
+                    return Err("copies on other cores need at least two cores to pin \
+                                threads to"
+                        .to_string())
+                }
+            },
+        };
+        Ok(Arc::new(DoubleCheck {
+            placement,
+            cores,
+            chunks: AtomicUsize::new(0),
+            disagreed: Mutex::default(),
+            unresolved: Mutex::default(),
+        }))
+    }
+
+    pub fn report(&self) -> DoubleCheckReport {
+        let sorted = |chunks: &Mutex<Vec<usize>>| {
+            let mut chunks = chunks.lock().unwrap().clone();
+            chunks.sort_unstable();
+            chunks
+        };
+        DoubleCheckReport {
+            chunks: self.chunks.load(Ordering::Relaxed),
+            disagreed: sorted(&self.disagreed),
+            unresolved: sorted(&self.unresolved),
+        }
+    }
+
+    /// Fills `out` with chunk `chunk_idx` as two copies (or the most of
+    /// several) agree it is, and returns the bytes used.
+    pub(crate) fn fill(
+        &self,
+        generator: &PasswordGenerator,
+        chunk_idx: usize,
+        scratch: &mut Vec<u8>,
+        out: &mut [u8],
+    ) -> usize {
+        self.chunks.fetch_add(1, Ordering::Relaxed);
+        let make =
+            |scratch: &mut Vec<u8>, out: &mut [u8]| generator.fill_records(chunk_idx, scratch, out);
+        SPARE.with_borrow_mut(|(spare_scratch, spare)| {
+            spare.clear();
+            spare.resize(out.len(), 0);
+            let (used, again) = match self.placement {
+                Placement::SameThread => (make(scratch, out), make(spare_scratch, spare)),
+                Placement::OtherCores => {
+                    let first = self.cores[chunk_idx % self.cores.len()];
+                    let second = self.cores[(chunk_idx + 1) % self.cores.len()];
+                    let pinned = |core: CoreId, scratch: &mut Vec<u8>, out: &mut [u8]| {
+                        core_affinity::set_for_current(core);
+                        make(scratch, out)
+                    };
+                    let pinned = &pinned;
+                    let (scratch, out) = (&mut *scratch, &mut *out);
+                    let (spare_scratch, spare) = (&mut *spare_scratch, &mut spare[..]);
+                    std::thread::scope(|s| {
+                        let again = s.spawn(move || pinned(second, spare_scratch, spare));
+                        let used = s.spawn(move || pinned(first, scratch, out)).join();
+                        (used.unwrap(), again.join().unwrap())
+                    })
+                }
+            };
+            if out[..used] == spare[..again] {
+                return used;
+            }
+            self.disagreed.lock().unwrap().push(chunk_idx);
+            let copies = vec![out[..used].to_vec(), spare[..again].to_vec()];
+            let agreed = vote(copies, || {
+                spare.fill(0);
+                let len = make(spare_scratch, spare);
+                spare[..len].to_vec()
+            });
+            match agreed {
+                Some(copy) => {
+                    out[..copy.len()].copy_from_slice(&copy);
+                    out[copy.len()..].fill(0);
+                    copy.len()
+                }
+                None => {
+                    self.unresolved.lock().unwrap().push(chunk_idx);
+                    used
+                }
+            }
+        })
+    }
+}
+
+/// The first copy from `another` that matches one made before it, the
+/// `copies` so far included, while there are fewer than [`MAX_COPIES`].
+fn vote(mut copies: Vec<Vec<u8>>, mut another: impl FnMut() -> Vec<u8>) -> Option<Vec<u8>> {
+    while copies.len() < MAX_COPIES {
+        let copy = another();
+        if copies.contains(&copy) {
+            return Some(copy);
+        }
+        copies.push(copy);
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Charset, Encoder};
+
+    #[test]
+    fn checked_runs_match_unchecked_ones() {
+        let generator = PasswordGenerator::builder()
+            .key([3; 16])
+            .count(1000)
+            .chunk_size(300)
+            .encoder(Encoder::new(&Charset::Alnum, 12))
+            .build()
+            .unwrap();
+        for placement in [Placement::SameThread, Placement::OtherCores] {
+            let Ok(check) = DoubleCheck::new(placement) else {
+                continue;
+            };
+            let checked = generator.with_double_check(Arc::clone(&check));
+            assert!(checked.generate() == generator.generate());
+            assert_eq!(
+                check.report(),
+                DoubleCheckReport {
+                    chunks: 4,
+                    ..DoubleCheckReport::default()
+                }
+            );
+        }
+        assert!(Placement::parse("elsewhere").is_err());
+    }
+
+    #[test]
+    fn copies_are_made_until_two_agree() {
+        let votes = |first: &[u8], second: &[u8], more: &[&[u8]]| {
+            let mut more = more.iter();
+            vote(vec![first.to_vec(), second.to_vec()], || {
+                more.next().unwrap().to_vec()
+            })
+        };
+        assert_eq!(votes(b"a", b"b", &[b"b"]), Some(b"b".to_vec()));
+        assert_eq!(votes(b"a", b"b", &[b"c", b"a"]), Some(b"a".to_vec()));
+        assert_eq!(votes(b"a", b"b", &[b"c", b"d", b"e"]), None);
+    }
+}
@@ -0,0 +1,526 @@
+//! Maps raw keystream bytes onto printable character sets.
+//!
+//! Each password gets a fixed keystream budget. Bytes are turned into
+//! characters by rejection sampling: a byte is only used if it falls below the
+//! largest multiple of the alphabet size that fits in a byte, so every
+//! character is equally likely. The budget is sized so that running out of
+//! bytes before the password is complete has probability below 2^-64.
+
+use std::fmt;
+
+use crate::template::Template;
+
+pub(crate) const DIGITS: &[u8] = b"0123456789";
+pub(crate) const LOWER: &[u8] = b"abcdefghijklmnopqrstuvwxyz";
+pub(crate) const UPPER: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+pub(crate) const SYMBOLS: &[u8] = b"!\"#$%&'()*+,-./:;<=>?@[\\]^_`{|}~";
+
+/// Probability we accept of a password exhausting its keystream budget.
+const EXHAUSTION_BOUND: f64 = 5.421010862427522e-20; // 2^-64
+
+/// Keystream bytes at the start of a password's budget that draw its
+/// length from a [`LengthDist`].
+pub const LENGTH_DRAW: usize = 8;
+
+/// Longest password `--length` and `--length-dist` allow.
+const MAX_LENGTH: usize = 4096;
+
+/// A named or custom alphabet for `--charset`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Charset {
+    /// `[A-Za-z0-9]`, 62 symbols
+    Alnum,
+    /// Alphanumerics plus the 32 ASCII punctuation characters, 94 symbols
+    AlnumSymbols,
+    /// Lowercase hex digits, 16 symbols
+    Hex,
+    /// The URL-safe base64 alphabet (RFC 4648 §5), 64 symbols
+    Base64Url,
+    /// A user-supplied alphabet of distinct printable ASCII characters
+    Custom(Vec<u8>),
+}
+
+impl Charset {
+    /// Parses `alnum`, `alnum+symbols` (or `alnum-symbols`), `hex`,
+    /// `base64url`, or `custom:<characters>`.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let charset = match s {
+            "alnum" => Charset::Alnum,
+            "alnum+symbols" | "alnum-symbols" => Charset::AlnumSymbols,
+            "hex" => Charset::Hex,
+            "base64url" => Charset::Base64Url,
+            _ => match s.strip_prefix("custom:") {
+                Some(chars) => Charset::Custom(chars.as_bytes().to_vec()),
+                None => {
+                    return Err(format!(
+                        "unknown charset `{}` (expected alnum, alnum+symbols, hex, base64url or custom:<chars>)",
+                        s
+                    ))
+                }
+            },
+        };
+        charset.validate()?;
+        Ok(charset)
+    }
+
+    /// The characters of this set, in the order keystream values map onto them.
+    pub fn alphabet(&self) -> Vec<u8> {
+        match self {
+            Charset::Alnum => [UPPER, LOWER, DIGITS].concat(),
+            Charset::AlnumSymbols => [UPPER, LOWER, DIGITS, SYMBOLS].concat(),
+            Charset::Hex => b"0123456789abcdef".to_vec(),
+            Charset::Base64Url => [UPPER, LOWER, DIGITS, b"-_"].concat(),
+            Charset::Custom(chars) => chars.clone(),
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let alphabet = self.alphabet();
+        if alphabet.len() < 2 {
+            return Err("a charset needs at least 2 distinct characters".into());
+        }
+        if let Some(&c) = alphabet.iter().find(|c| !c.is_ascii_graphic()) {
+            return Err(format!(
+                "charset characters must be printable ASCII without spaces, found {:?}",
+                c as char
+            ));
+        }
+        let mut seen = [false; 256];
+        for &c in &alphabet {
+            if std::mem::replace(&mut seen[c as usize], true) {
+                return Err(format!("charset repeats the character {:?}", c as char));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Charset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Charset::Alnum => write!(f, "alnum"),
+            Charset::AlnumSymbols => write!(f, "alnum+symbols"),
+            Charset::Hex => write!(f, "hex"),
+            Charset::Base64Url => write!(f, "base64url"),
+            Charset::Custom(chars) => write!(f, "custom:{}", String::from_utf8_lossy(chars)),
+        }
+    }
+}
+
+/// How likely each password length is, for `--length-dist`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LengthDist {
+    /// Each length with its weight, as given
+    lengths: Vec<(usize, f64)>,
+    /// Draws below `thresholds[i]` (and not below those before it) give
+    /// `lengths[i]`
+    thresholds: Vec<u64>,
+}
+
+impl LengthDist {
+    /// Parses `LENGTH:WEIGHT` pairs separated by commas, e.g.
+    /// `8:0.2,10:0.3,12:0.3,16:0.2`. The weights are probabilities, and
+    /// add up to 1.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut lengths: Vec<(usize, f64)> = Vec::new();
+        for pair in s.split(',') {
+            let (length, weight) = pair
+                .split_once(':')
+                .ok_or_else(|| format!("expected LENGTH:WEIGHT, got `{}`", pair))?;
+            let length: usize = length
+                .trim()
+                .parse()
+                .ok()
+                .filter(|n| (1..=MAX_LENGTH).contains(n))
+                .ok_or_else(|| format!("lengths run from 1 to {}, not `{}`", MAX_LENGTH, length))?;
+            let weight: f64 = weight
+                .trim()
+                .parse()
+                .ok()
+                .filter(|w: &f64| w.is_finite() && *w > 0.0)
+                .ok_or_else(|| format!("`{}` is not a probability above 0", weight))?;
+            if lengths.iter().any(|&(l, _)| l == length) {
+                return Err(format!("length {} is given twice", length));
+            }
+            lengths.push((length, weight));
+        }
+        let total: f64 = lengths.iter().map(|&(_, w)| w).sum();
+        if (total - 1.0).abs() > 1e-6 {
+            return Err(format!("the weights add up to {}, not 1", total));
+        }
+        let mut cumulative = 0.0;
+        let mut thresholds: Vec<u64> = lengths
+            .iter()
+            .map(|&(_, w)| {
+                cumulative += w / total;
+                (cumulative * 2f64.powi(64)) as u64
+            })
+            .collect();
+        *thresholds.last_mut().expect("at least one pair") = u64::MAX;
+        Ok(LengthDist {
+            lengths,
+            thresholds,
+        })
+    }
+
+    /// The length `draw`, uniform over `u64`, picks.
+    pub fn pick(&self, draw: u64) -> usize {
+        let i = self.thresholds.partition_point(|&t| t <= draw);
+        self.lengths[i.min(self.lengths.len() - 1)].0
+    }
+
+    pub fn min(&self) -> usize {
+        self.lengths
+            .iter()
+            .map(|&(l, _)| l)
+            .min()
+            .expect("at least one pair")
+    }
+
+    pub fn max(&self) -> usize {
+        self.lengths
+            .iter()
+            .map(|&(l, _)| l)
+            .max()
+            .expect("at least one pair")
+    }
+
+    /// The expected length.
+    pub fn mean(&self) -> f64 {
+        self.lengths.iter().map(|&(l, w)| l as f64 * w).sum()
+    }
+}
+
+impl fmt::Display for LengthDist {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let pairs: Vec<String> = self
+            .lengths
+            .iter()
+            .map(|(length, weight)| format!("{}:{}", length, weight))
+            .collect();
+        f.write_str(&pairs.join(","))
+    }
+}
+
+/// Turns a fixed-size slice of keystream into one password.
+#[derive(Clone, Debug)]
+pub struct Encoder {
+    alphabet: Vec<u8>,
+    length: usize,
+    /// Bytes at or above this value are rejected (a multiple of the
+    /// alphabet size)
+    limit: u16,
+    /// Keystream bytes reserved per password
+    budget: usize,
+    /// Per-position classes, when built from a template
+    template: Option<Template>,
+    /// Lengths drawn per password, instead of always `length`
+    lengths: Option<LengthDist>,
+}
+
+impl Encoder {
+    pub fn new(charset: &Charset, length: usize) -> Self {
+        let alphabet = charset.alphabet();
+        let n = alphabet.len() as u16;
+        let limit = 256 - 256 % n;
+        let budget = keystream_budget(length, limit as f64 / 256.0);
+        Encoder {
+            alphabet,
+            length,
+            limit,
+            budget,
+            template: None,
+            lengths: None,
+        }
+    }
+
+    /// An encoder of passwords of `charset` whose lengths follow `lengths`:
+    /// the first [`LENGTH_DRAW`] bytes of each password's keystream pick its
+    /// length. [`length`](Self::length) is then the longest.
+    pub fn with_lengths(charset: &Charset, lengths: LengthDist) -> Self {
+        let mut enc = Encoder::new(charset, lengths.max());
+        enc.budget += LENGTH_DRAW;
+        enc.lengths = Some(lengths);
+        enc
+    }
+
+    /// An encoder that fills each position of `template` from its own
+    /// class. [`alphabet`](Self::alphabet) is then every character the
+    /// template can produce, and [`acceptance_rate`](Self::acceptance_rate)
+    /// that of its most wasteful class.
+    pub fn from_template(template: &Template) -> Self {
+        let acceptance = template.min_acceptance();
+        Encoder {
+            alphabet: template.characters(),
+            length: template.length(),
+            limit: (acceptance * 256.0) as u16,
+            budget: keystream_budget(template.random_positions(), acceptance),
+            template: Some(template.clone()),
+            lengths: None,
+        }
+    }
+
+    /// The template this encoder was built from, if any.
+    pub fn template(&self) -> Option<&Template> {
+        self.template.as_ref()
+    }
+
+    /// Characters per password; with a length distribution, the most.
+    pub fn length(&self) -> usize {
+        self.length
+    }
+
+    /// The distribution lengths are drawn from, if they vary.
+    pub fn lengths(&self) -> Option<&LengthDist> {
+        self.lengths.as_ref()
+    }
+
+    /// Characters of the password `stream` encodes: its length, or the one
+    /// the stream draws from the length distribution.
+    pub fn length_of(&self, stream: &[u8]) -> usize {
+        match &self.lengths {
+            Some(lengths) => {
+                let draw = stream[..LENGTH_DRAW].try_into().expect("8 bytes");
+                lengths.pick(u64::from_le_bytes(draw))
+            }
+            None => self.length,
+        }
+    }
+
+    /// Keystream bytes consumed per password.
+    pub fn budget(&self) -> usize {
+        self.budget
+    }
+
+    /// The alphabet characters are drawn from.
+    pub fn alphabet(&self) -> &[u8] {
+        &self.alphabet
+    }
+
+    /// Number of symbols in the alphabet.
+    pub fn alphabet_size(&self) -> usize {
+        self.alphabet.len()
+    }
+
+    /// Fraction of keystream bytes that rejection sampling keeps.
+    pub fn acceptance_rate(&self) -> f64 {
+        self.limit as f64 / 256.0
+    }
+
+    /// Entropy of one password in bits, assuming uniform characters; with
+    /// a length distribution, that of the shortest.
+    pub fn entropy_bits(&self) -> f64 {
+        if let Some(template) = &self.template {
+            return template.entropy_bits();
+        }
+        let length = self.lengths.as_ref().map_or(self.length, LengthDist::min);
+        length as f64 * (self.alphabet.len() as f64).log2()
+    }
+
+    /// Encodes `stream` (exactly `budget()` bytes) into `out` (exactly
+    /// [`length_of`](Self::length_of) the stream bytes).
+    pub fn encode(&self, stream: &[u8], out: &mut [u8]) {
+        if let Some(template) = &self.template {
+            return template.encode(stream, out);
+        }
+        let stream = match self.lengths {
+            Some(_) => &stream[LENGTH_DRAW..],
+            None => stream,
+        };
+        let n = self.alphabet.len();
+        let mut filled = 0;
+        for &b in stream {
+            if (b as u16) < self.limit {
+                out[filled] = self.alphabet[b as usize % n];
+                filled += 1;
+                if filled == out.len() {
+                    return;
+                }
+            }
+        }
+        // Budget exhausted, which the sizing makes a < 2^-64 event per
+        // password. Finish with a modular mapping of the same bytes: slightly
+        // biased, but deterministic and never observed in practice.
+        for (slot, &b) in out[filled..].iter_mut().zip(stream) {
+            *slot = self.alphabet[b as usize % n];
+        }
+    }
+}
+
+/// Smallest number of bytes `b` such that fewer than `length` of them being
+/// accepted (each independently with probability `p`) is below
+/// `EXHAUSTION_BOUND`.
+pub(crate) fn keystream_budget(length: usize, p: f64) -> usize {
+    if p >= 1.0 {
+        return length;
+    }
+    let mut budget = length;
+    while binomial_cdf_below(budget, length, p) > EXHAUSTION_BOUND {
+        budget += 1;
+    }
+    budget
+}
+
+/// P(X < k) for X ~ Binomial(n, p). Terms are tracked in log space because
+/// the first ones underflow for long passwords.
+fn binomial_cdf_below(n: usize, k: usize, p: f64) -> f64 {
+    let q = 1.0 - p;
+    let mut log_term = n as f64 * q.ln(); // ln P(X = 0)
+    let mut total = 0.0;
+    for i in 0..k.min(n + 1) {
+        total += log_term.exp();
+        log_term += ((n - i) as f64 / (i + 1) as f64 * p / q).ln();
+    }
+    total
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepted_bytes_map_uniformly() {
+        for charset in [
+            Charset::Alnum,
+            Charset::AlnumSymbols,
+            Charset::Hex,
+            Charset::Custom(b"xyz".to_vec()),
+        ] {
+            let enc = Encoder::new(&charset, 1);
+            let n = enc.alphabet_size();
+            let limit = enc.limit as usize;
+            assert_eq!(limit % n, 0);
+            assert!(256 - limit < n, "{} wastes a whole round", charset);
+            // Every byte below the limit lands, and each character gets the
+            // same number of them
+            let mut hits = vec![0; n];
+            let mut out = [0u8];
+            for b in 0..limit {
+                enc.encode(&[b as u8], &mut out);
+                hits[enc.alphabet().iter().position(|&c| c == out[0]).unwrap()] += 1;
+            }
+            assert!(
+                hits.iter().all(|&h| h == limit / n),
+                "{}: {:?}",
+                charset,
+                hits
+            );
+        }
+    }
+
+    #[test]
+    fn bytes_at_the_limit_are_skipped() {
+        // 62 symbols: 248 is the first byte rejected
+        let enc = Encoder::new(&Charset::Alnum, 4);
+        assert_eq!(enc.limit, 248);
+        let mut stream = vec![248, 0, 255, 1, 250, 2, 61];
+        stream.resize(enc.budget(), 0);
+        let mut out = [0u8; 4];
+        enc.encode(&stream, &mut out);
+        assert_eq!(&out, b"ABC9");
+        // Hex keeps every byte
+        let hex = Encoder::new(&Charset::Hex, 4);
+        assert_eq!((hex.limit, hex.budget()), (256, 4));
+    }
+
+    #[test]
+    fn budgets_meet_the_exhaustion_bound() {
+        for (charset, length) in [
+            (Charset::Alnum, 8),
+            (Charset::Alnum, 20),
+            (Charset::AlnumSymbols, 16),
+            (Charset::Custom(b"0123456789".to_vec()), 6),
+            (Charset::Custom(b"abc".to_vec()), 64),
+        ] {
+            let enc = Encoder::new(&charset, length);
+            let (budget, p) = (enc.budget(), enc.acceptance_rate());
+            assert!(binomial_cdf_below(budget, length, p) <= EXHAUSTION_BOUND);
+            // ...and is the smallest budget that does
+            assert!(
+                binomial_cdf_below(budget - 1, length, p) > EXHAUSTION_BOUND,
+                "{} x {}",
+                charset,
+                length
+            );
+        }
+        assert_eq!(keystream_budget(12, 1.0), 12);
+    }
+
+    #[test]
+    fn binomial_tail_matches_small_cases() {
+        let close = |a: f64, b: f64| (a - b).abs() < 1e-12;
+        assert!(close(binomial_cdf_below(2, 1, 0.5), 0.25));
+        assert!(close(binomial_cdf_below(3, 2, 0.5), 0.5));
+        assert!(close(binomial_cdf_below(4, 2, 0.25), 0.31640625 + 0.421875));
+        // Fewer trials than needed can never succeed
+        assert!(close(binomial_cdf_below(3, 5, 0.9), 1.0));
+        assert_eq!(binomial_cdf_below(10, 0, 0.5), 0.0);
+    }
+
+    #[test]
+    fn exhausted_budgets_fall_back_to_modular_mapping() {
+        let enc = Encoder::new(&Charset::Alnum, 3);
+        let mut stream = vec![255; enc.budget()];
+        stream[1] = 2;
+        let mut out = [0u8; 3];
+        enc.encode(&stream, &mut out);
+        // One accepted byte, then the first stream bytes taken modulo 62
+        let alphabet = enc.alphabet();
+        assert_eq!(out, [b'C', alphabet[255 % 62], alphabet[2]]);
+    }
+
+    #[test]
+    fn custom_charsets_are_validated() {
+        assert_eq!(
+            Charset::parse("custom:abc"),
+            Ok(Charset::Custom(b"abc".to_vec()))
+        );
+        for (spec, why) in [
+            ("custom:abca", "repeats the character 'a'"),
+            ("custom:ab c", "printable ASCII"),
+            ("custom:ab\t", "printable ASCII"),
+            ("custom:ab\u{e9}", "printable ASCII"),
+            ("custom:a", "at least 2"),
+            ("custom:", "at least 2"),
+            ("base32", "unknown charset"),
+        ] {
+            let err = Charset::parse(spec).unwrap_err();
+            assert!(err.contains(why), "{}: {}", spec, err);
+        }
+        for charset in [Charset::Alnum, Charset::AlnumSymbols, Charset::Base64Url] {
+            assert_eq!(Charset::parse(&charset.to_string()), Ok(charset));
+        }
+    }
+
+    #[test]
+    fn lengths_follow_their_distribution() {
+        let dist = LengthDist::parse("8:0.25, 12:0.5,16:0.25").unwrap();
+        assert_eq!((dist.min(), dist.max(), dist.mean()), (8, 16, 12.0));
+        assert_eq!(LengthDist::parse(&dist.to_string()), Ok(dist.clone()));
+        assert_eq!(dist.pick(0), 8);
+        assert_eq!(dist.pick((1 << 62) - 1), 8);
+        assert_eq!(dist.pick(1 << 62), 12);
+        assert_eq!(dist.pick(u64::MAX), 16);
+
+        let enc = Encoder::with_lengths(&Charset::Hex, dist);
+        assert_eq!(
+            enc.budget(),
+            Encoder::new(&Charset::Hex, 16).budget() + LENGTH_DRAW
+        );
+        let mut stream = vec![0u8; enc.budget()];
+        stream[LENGTH_DRAW - 1] = 0x80;
+        assert_eq!(enc.length_of(&stream), 16 - 4);
+        assert_eq!(enc.entropy_bits(), 32.0);
+
+        for (spec, why) in [
+            ("8:0.5,10:0.4", "add up to 0.9"),
+            ("8:0.5,8:0.5", "given twice"),
+            ("0:1", "from 1 to 4096"),
+            ("8:-1,10:2", "above 0"),
+            ("8", "LENGTH:WEIGHT"),
+        ] {
+            let err = LengthDist::parse(spec).unwrap_err();
+            assert!(err.contains(why), "{}: {}", spec, err);
+        }
+    }
+}
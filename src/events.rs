@@ -0,0 +1,163 @@
+//! `--events` and `--events-fd`: a line of JSON for each shard as soon as
+//! it is finished, so whatever uploads or indexes the shards can start on
+//! each while the run goes on.
+//!
+//! A shard's event has its number, its path, the records it holds and the
+//! SHA-256 of its bytes, the same entry the manifest gets at the end:
+//!
+//! ```text
+//! {"event":"shard","shard":3,"path":"/d1/pw-00003.txt","first":2000000,"count":1000000,"bytes":17000000,"sha256":"…"}
+//! {"event":"done","manifest":"/data/pw.manifest.json","shards":4}
+//! ```
+//!
+//! Shards are hashed on a thread of their own, so writing never waits for
+//! it, and every line is flushed as it is written.
+
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Mutex};
+
+use mass_password_gen_optimized::{archive, Format, PasswordGenerator};
+
+use crate::permissions;
+
+/// Where the events go.
+pub struct Events(Mutex<Box<dyn Write + Send>>);
+
+impl Events {
+    /// Events written to `path`, created with `mode`.
+    pub fn create(path: &Path, mode: u32) -> io::Result<Self> {
+        let file = permissions::create(path, mode)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        Ok(Self::to(file))
+    }
+
+    /// Events written to file descriptor `fd`, which the run inherited open
+    /// for writing.
+    #[cfg(unix)]
+    pub fn to_fd(fd: i32) -> io::Result<Self> {
+        use std::os::fd::FromRawFd;
+        // SAFETY: F_GETFL only reads the descriptor's flags, and fails on
+        // one that is not open
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if flags < 0 || flags & libc::O_ACCMODE == libc::O_RDONLY {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("--events-fd {} is not open for writing", fd),
+            ));
+        }
+        // SAFETY: the descriptor is open, and nothing else in the process
+        // uses it; the run takes it over until it exits
+        Ok(Self::to(unsafe { File::from_raw_fd(fd) }))
+    }
+
+    fn to(out: impl Write + Send + 'static) -> Self {
+        Events(Mutex::new(Box::new(out)))
+    }
+
+    fn emit(&self, event: serde_json::Value) -> io::Result<()> {
+        let mut out = self.0.lock().unwrap();
+        writeln!(out, "{}", event)?;
+        out.flush()
+    }
+
+    /// Announces each shard, written in `format` and `compressed` or not,
+    /// that `finished` gives the number and path (relative to `dir`) of,
+    /// until it closes.
+    pub fn shards(
+        &self,
+        generator: &PasswordGenerator,
+        format: Format,
+        shard_size: usize,
+        compressed: bool,
+        dir: &Path,
+        finished: mpsc::Receiver<(usize, PathBuf)>,
+    ) -> io::Result<()> {
+        for (n, name) in finished {
+            let shard =
+                archive::shard_entry(generator, format, shard_size, compressed, dir, n, &name)?;
+            let path = dir.join(&shard.path);
+            self.emit(serde_json::json!({
+                "event": "shard",
+                "shard": n + 1,
+                "path": path,
+                "first": shard.first,
+                "count": shard.count,
+                "bytes": std::fs::metadata(&path)?.len(),
+                "sha256": shard.sha256,
+            }))?;
+        }
+        Ok(())
+    }
+
+    /// Announces the end of the run, once the manifest is written.
+    pub fn done(&self, manifest: &Path, shards: usize) -> io::Result<()> {
+        self.emit(serde_json::json!({
+            "event": "done",
+            "manifest": manifest,
+            "shards": shards,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shards_are_announced_as_the_manifest_has_them() {
+        let dir = std::env::temp_dir().join(format!("mpg_events_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let g = PasswordGenerator::builder()
+            .key([4; 16])
+            .count(700)
+            .chunk_size(100)
+            .build()
+            .unwrap();
+        let names: Vec<_> = (1..=3)
+            .map(|n| PathBuf::from(format!("e-{:05}", n)))
+            .collect();
+        let files: Vec<File> = names
+            .iter()
+            .map(|name| File::create(dir.join(name)).unwrap())
+            .collect();
+        let log = dir.join("events.jsonl");
+        let events = Events::create(&log, permissions::DEFAULT_MODE).unwrap();
+        let (finished, to_announce) = mpsc::channel();
+        g.write_sharded(
+            None,
+            &files,
+            300,
+            |_, _| (),
+            |n| finished.send((n, names[n].clone())).unwrap(),
+        )
+        .unwrap();
+        drop(finished);
+        events
+            .shards(&g, Format::Raw, 300, false, &dir, to_announce)
+            .unwrap();
+        events.done(&dir.join("e.manifest.json"), 3).unwrap();
+
+        let manifest =
+            archive::shard_manifest(&g, Format::Raw, None, 300, None, &dir, &names).unwrap();
+        let lines: Vec<serde_json::Value> = std::fs::read_to_string(&log)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 4);
+        let mut shards: Vec<_> = lines[..3].iter().collect();
+        shards.sort_by_key(|event| event["shard"].as_u64());
+        for (event, shard) in shards.into_iter().zip(&manifest.shards) {
+            assert_eq!(event["event"], "shard");
+            assert_eq!(event["first"], shard.first);
+            assert_eq!(event["count"], shard.count.unwrap());
+            assert_eq!(event["sha256"], shard.sha256.as_deref().unwrap());
+            assert_eq!(event["path"], dir.join(&shard.path).to_str().unwrap());
+        }
+        assert_eq!(lines[3]["event"], "done");
+        assert_eq!(lines[3]["shards"], 3);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,429 @@
+//! `explain`: what a run will do, in words, for a change review to attach
+//! to its ticket: where the key comes from, what the records are and how
+//! likely one is to repeat another, the files the run writes, and the
+//! disk, memory and time it needs, with the warnings the run would print.
+//!
+//! The run's generator is built under a throwaway key, as `size` builds
+//! it, since none of this depends on the key: no key is loaded and nothing
+//! is generated but a short timing sample.
+
+use std::error::Error;
+use std::path::Path;
+use std::time::Instant;
+
+use clap::{CommandFactory, Parser};
+use mass_password_gen_optimized::{
+    aes_hardware_available, analysis, Cipher, Key, PasswordGenerator,
+};
+use sysinfo::{System, SystemExt};
+
+use crate::generate::{key_provider, run_sink};
+use crate::warnings::{self, KeySource};
+use crate::{config, memory, sizing, Args, CipherChoice, UniqueCheck};
+
+/// Most records timed for the estimate of how long the run takes.
+const TIMING_SAMPLE: usize = 1 << 16;
+
+/// Runs `explain`: describes the run of the `--config` file's options
+/// followed by `options`.
+pub fn explain(config: Option<&Path>, options: &[String]) -> Result<(), Box<dyn Error>> {
+    let mut argv = vec![Args::command().get_name().to_string()];
+    if let Some(path) = config {
+        argv.extend(config::read(path)?.args());
+    }
+    argv.extend(options.iter().cloned());
+    let args = Args::try_parse_from(argv).unwrap_or_else(|e| e.exit());
+    if args.command.is_some() {
+        return Err("explain describes a run; give the options of one, not a subcommand".into());
+    }
+    for (topic, text) in describe(&args)? {
+        println!("{:<12}{}", format!("{}:", topic), text);
+    }
+    Ok(())
+}
+
+/// What the run of `args` does, a line per topic.
+fn describe(args: &Args) -> Result<Vec<(&'static str, String)>, Box<dyn Error>> {
+    // No figure here depends on the key
+    let key = Key::from([0; 16]);
+    let cipher = args
+        .cipher
+        .and_then(CipherChoice::pinned)
+        .unwrap_or(Cipher::Aes128);
+    let mut builder = args.records.configure(
+        PasswordGenerator::builder()
+            .key(key)
+            .cipher(cipher)
+            .chunk_size(args.chunk_size),
+    )?;
+    if let Some(schema) = &args.schema {
+        builder = schema.configure(builder, &key);
+    }
+    let generator = builder.build()?;
+    let mut sys = System::new();
+    sys.refresh_memory();
+    let cpus = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let threads = args.threads.unwrap_or(cpus);
+    let bits = analysis::entropy_bits(&generator);
+
+    let mut lines = vec![
+        ("Records", records(args, &generator, bits)),
+        ("Key", key_origin(args)),
+        ("Cipher", cipher_choice(args)),
+        ("Uniqueness", uniqueness(args, &generator, bits)),
+        ("Output", layout(args, &generator)),
+    ];
+    let sink = run_sink(args, &generator, &key)?;
+    let bytes = sizing::approx_output_len(sink.as_deref(), &generator);
+    let exact = generator.fixed_width() && sink.is_none()
+        || sink.as_deref().is_some_and(|sink| {
+            mass_password_gen_optimized::format::serialized_len(sink, &generator).is_some()
+        });
+    lines.push((
+        "Size",
+        format!("{}{}", if exact { "" } else { "up to " }, size(bytes)),
+    ));
+    let in_memory = args.output.is_none() && !args.local_buffers;
+    let per_worker = generator.keystream_len(0) + generator.chunk_len(0) * generator.record_width();
+    lines.push((
+        "Memory",
+        match in_memory {
+            true => format!(
+                "{}, the whole run held at once (no --output), of {} on this machine",
+                size(bytes),
+                memory::size(sys.total_memory())
+            ),
+            false => format!(
+                "about {}: a chunk of keystream and records for each of {} workers",
+                size((per_worker * threads) as u64),
+                threads
+            ),
+        },
+    ));
+    lines.push(("Time", duration(args, &generator, threads)));
+
+    let warnings = warnings::evaluate(&warnings::Config {
+        generator: &generator,
+        key: key_source(args),
+        software_aes: cipher != Cipher::ChaCha20 && !aes_hardware_available(),
+        threads,
+        logical_cpus: cpus,
+        output: args
+            .output
+            .as_deref()
+            .filter(|path| *path != Path::new("-")),
+        output_mode: args.output_mode,
+        in_memory,
+        total_memory: sys.total_memory(),
+        unique_checked: args.verify_unique.is_some(),
+    });
+    lines.extend(
+        warnings
+            .iter()
+            .map(|w| ("Warning", format!("[{}] {}", w.category, w.message))),
+    );
+    Ok(lines)
+}
+
+/// How many records there are, of what, and in which format.
+fn records(args: &Args, generator: &PasswordGenerator, bits: f64) -> String {
+    let what = match &args.schema {
+        Some(schema) => {
+            let names: Vec<&str> = schema.fields().iter().map(|f| f.name()).collect();
+            format!("records of the fields {}", names.join(", "))
+        }
+        None => args.records.encoding(),
+    };
+    let policy = match generator.policy_acceptance() {
+        Some(share) => format!(
+            ", only those meeting the policy ({:.1}% of candidates do)",
+            share * 100.0
+        ),
+        None => String::new(),
+    };
+    let segment = match (args.total, args.segment) {
+        (Some(total), Some(n)) => format!(
+            " (segment {} of a job of {} records; --count is the segment size)",
+            n, total
+        ),
+        _ => String::new(),
+    };
+    format!(
+        "{} {}{}, {:.1} bits each, written as {}{}",
+        generator.count(),
+        what,
+        policy,
+        bits,
+        args.records.format,
+        segment
+    )
+}
+
+/// What the run's key is taken to be, without loading it.
+fn key_source(args: &Args) -> KeySource {
+    match (args.sink_dry_run, &args.resume) {
+        (true, _) => KeySource::Filler,
+        (false, Some(_)) => KeySource::Stored,
+        (false, None) => key_provider(args, None, Cipher::Aes128).source(),
+    }
+}
+
+/// Where the run's key comes from, and what follows for the output.
+fn key_origin(args: &Args) -> String {
+    let origin = match &args.resume {
+        Some(path) => format!("the key saved in the checkpoint {}", path.display()),
+        None => key_provider(args, None, Cipher::Aes128).describe(),
+    };
+    let origin = match args.segment {
+        Some(n) => format!("derived for segment {} from {}", n, origin),
+        None => origin,
+    };
+    let consequence = match key_source(args) {
+        KeySource::Random => "never stored, so the output cannot be made again",
+        KeySource::Stored => "loaded at the start; anyone holding it can make the output again",
+        KeySource::CommandLine => {
+            "on the command line, where shell history and process listings see it"
+        }
+        KeySource::Published | KeySource::Demo => "public, and so is the output",
+        KeySource::Filler => "unused: the run writes non-secret filler (--sink-dry-run)",
+    };
+    format!("{}; {}", origin, consequence)
+}
+
+fn cipher_choice(args: &Args) -> String {
+    match args.cipher.and_then(CipherChoice::pinned) {
+        Some(cipher) => format!("{} (--cipher)", cipher),
+        None if matches!(args.cipher, Some(CipherChoice::Auto)) || args.auto_backend => {
+            "the fastest here, picked by a short benchmark at the start".to_string()
+        }
+        None => "aes128, or aes256 for a 256-bit key".to_string(),
+    }
+}
+
+/// Whether a record can repeat another, and what is done about it.
+fn uniqueness(args: &Args, generator: &PasswordGenerator, bits: f64) -> String {
+    let check = match args.verify_unique {
+        Some(UniqueCheck::Report) => "; --verify-unique report counts any repeats",
+        Some(UniqueCheck::Remove) => "; --verify-unique remove leaves repeats out",
+        None => "",
+    };
+    let raw =
+        generator.encoder().is_none() && generator.passphrase().is_none() && args.schema.is_none();
+    let cipher = args.cipher.and_then(CipherChoice::pinned);
+    if raw && cipher != Some(Cipher::ChaCha20) {
+        return format!(
+            "no record repeats another: each is AES of a distinct counter block under one \
+             key{}",
+            check
+        );
+    }
+    let duplicates = analysis::expected_duplicates(generator.count() as f64, bits.exp2());
+    format!(
+        "records are drawn independently from 2^{:.1} values, so ~{:.2e} repeats are expected \
+         among {}{}",
+        bits,
+        duplicates,
+        generator.count(),
+        check
+    )
+}
+
+/// Where the records go, and what else the run writes.
+fn layout(args: &Args, generator: &PasswordGenerator) -> String {
+    let Some(output) = &args.output else {
+        return "held in memory and not written anywhere (no --output)".to_string();
+    };
+    let name = output.to_string_lossy();
+    let mut parts = vec![if let Some(segment) = name.strip_prefix("shm://") {
+        format!("the shared-memory segment {}", segment)
+    } else if name == "-" {
+        "standard output".to_string()
+    } else if let Some(shard_size) = args.shard_size {
+        let shards = generator.num_shards(shard_size);
+        let (names, manifest) = crate::output::shard_paths(output, shards);
+        let dirs = match args.output_dirs.len() {
+            0 => String::new(),
+            n => format!(", striped over {} directories", n),
+        };
+        format!(
+            "{} files of up to {} records ({} to {}{}), and the manifest {} with each one's \
+             SHA-256",
+            shards,
+            shard_size,
+            names[0].display(),
+            names[shards - 1].display(),
+            dirs,
+            manifest.display()
+        )
+    } else if args.archive_format.is_some() {
+        format!("the self-verifying archive {}", output.display())
+    } else {
+        format!("the file {}", output.display())
+    }];
+    if let Some(codec) = &args.compress {
+        parts.push(format!("compressed with {}", codec));
+    }
+    if let Some(dict) = &args.zstd_dict {
+        parts.push(format!(
+            "compressed with zstd and a dictionary saved to {}",
+            dict.display()
+        ));
+    }
+    if let Some(signer) = &args.sign_with {
+        parts.push(format!("signed with {}", signer));
+    }
+    if let Some(recipients) = &args.encrypt_records {
+        let slices: Vec<String> = recipients
+            .slices()
+            .iter()
+            .map(|slice| format!("[{}] to {}", slice.name, slice.recipient))
+            .collect();
+        parts.push(format!("encrypted {}", slices.join(", ")));
+    }
+    if let Some(custodians) = &args.seal {
+        let names: Vec<&str> = custodians
+            .holders
+            .iter()
+            .map(|(name, _)| &name[..])
+            .collect();
+        parts.push(format!(
+            "sealed until {} of {} `unlock` it",
+            custodians.threshold,
+            names.join(", ")
+        ));
+    }
+    if cfg!(unix) && name != "-" && !name.starts_with("shm://") {
+        parts.push(format!("created with mode {:04o}", args.output_mode));
+    }
+    if let Some(path) = &args.checkpoint {
+        parts.push(format!(
+            "checkpointed to {}, which holds the key until the run completes",
+            path.display()
+        ));
+    }
+    match (&args.fallback, args.shard_size) {
+        (Some(dir), Some(_)) => parts.push(format!(
+            "moving any shard whose file fails into {}",
+            dir.display()
+        )),
+        (Some(path), None) => parts.push(format!("falling back to {} if it fails", path.display())),
+        (None, _) => {}
+    }
+    if let Some(path) = &args.reference_output {
+        parts.push(format!(
+            "with a non-secret reference file {}",
+            path.display()
+        ));
+    }
+    parts.join("; ")
+}
+
+/// How long generating takes on this machine, from a timed sample.
+fn duration(args: &Args, generator: &PasswordGenerator, threads: usize) -> String {
+    let count = generator.count().min(TIMING_SAMPLE);
+    let sample = args
+        .records
+        .configure(
+            PasswordGenerator::builder()
+                .key([0; 16])
+                .cipher(generator.cipher())
+                .chunk_size(count),
+        )
+        .map(|builder| builder.count(count))
+        .and_then(|builder| builder.build().map_err(|e| e.to_string()));
+    let Ok(sample) = sample else {
+        return "unknown".to_string();
+    };
+    let mut scratch = Vec::new();
+    let mut out = vec![0; sample.chunk_len(0) * sample.record_width()];
+    let start = Instant::now();
+    sample.fill_chunk(0, &mut scratch, &mut out);
+    let per_record = start.elapsed().as_secs_f64() / count as f64;
+    let share = args.cpu_limit.unwrap_or(1.0);
+    let copies = if args.double_check.is_some() {
+        2.0
+    } else {
+        1.0
+    };
+    let secs = copies * per_record * generator.count() as f64 / (threads as f64 * share);
+    format!(
+        "{}{} to generate on {} thread{} here{}{}; writing may take longer",
+        if secs < 1.0 { "" } else { "about " },
+        analysis::format_duration(secs),
+        threads,
+        if threads == 1 { "" } else { "s" },
+        match args.cpu_limit {
+            Some(share) => format!(" at --cpu-limit {:.0}%", share * 100.0),
+            None => String::new(),
+        },
+        match args.double_check {
+            Some(_) => ", making every chunk twice for --double-check",
+            None => "",
+        }
+    )
+}
+
+/// `bytes`, and in MiB or GiB once there are enough of them.
+fn size(bytes: u64) -> String {
+    match bytes {
+        0..0x10_0000 => format!("{} bytes", bytes),
+        _ => format!("{} ({} bytes)", memory::size(bytes), bytes),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn explained(options: &[&str]) -> Vec<(&'static str, String)> {
+        let argv = std::iter::once("mpg").chain(options.iter().copied());
+        describe(&Args::try_parse_from(argv).unwrap()).unwrap()
+    }
+
+    fn topic<'a>(lines: &'a [(&str, String)], topic: &str) -> &'a str {
+        &lines.iter().find(|(t, _)| *t == topic).unwrap().1
+    }
+
+    #[test]
+    fn runs_are_described_by_topic() {
+        let lines = explained(&[
+            "--count",
+            "3000",
+            "--chunk-size",
+            "1000",
+            "--charset",
+            "hex",
+            "--length",
+            "8",
+            "--format",
+            "csv",
+            "--output",
+            "out/p.csv",
+            "--shard-size",
+            "1000",
+            "--deterministic",
+            "--key",
+            "env:RUN_KEY",
+        ]);
+        assert!(topic(&lines, "Records").starts_with("3000 hex/8, 32.0 bits each, written as csv"));
+        assert!(topic(&lines, "Key")
+            .starts_with("environment variable RUN_KEY; loaded at the start; anyone"));
+        assert!(topic(&lines, "Uniqueness").contains("from 2^32.0 values"));
+        assert!(topic(&lines, "Output").starts_with(
+            "3 files of up to 1000 records (p-00001.csv to p-00003.csv), and the manifest \
+             out/p.manifest.json"
+        ));
+        // The header, then index, password and a line break per record
+        assert_eq!(topic(&lines, "Size"), "40905 bytes");
+        assert!(lines
+            .iter()
+            .any(|(t, w)| *t == "Warning" && w.starts_with("[security] 32.0 bits")));
+
+        let lines = explained(&["--count", "10", "--deterministic"]);
+        assert!(topic(&lines, "Key").contains("public, and so is the output"));
+        assert!(topic(&lines, "Uniqueness").starts_with("no record repeats another"));
+        assert!(topic(&lines, "Output").starts_with("held in memory"));
+        assert_eq!(topic(&lines, "Size"), "160 bytes");
+        assert!(topic(&lines, "Memory").contains("the whole run held at once"));
+    }
+}
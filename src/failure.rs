@@ -0,0 +1,201 @@
+//! Why a run failed, as a stable code and exit status, so automation can
+//! tell a full disk from a bad key from a failed sink without parsing
+//! messages. `--log-format json` reports the failure on stderr as an object
+//! with its code, exit status, message and, where one helps, a hint.
+//!
+//! | status | code                  | meaning                                         |
+//! |--------|-----------------------|-------------------------------------------------|
+//! | 1      | `error`               | anything not listed below                       |
+//! | 2      | `usage`               | invalid arguments (reported by clap)            |
+//! | 3      | `bad-key`             | the key could not be loaded or parsed           |
+//! | 4      | `io`                  | reading or writing a file other than the output |
+//! | 5      | `disk-full`           | a device or quota ran out of space              |
+//! | 6      | `sink-write`          | writing the output failed                       |
+//! | 7      | `verification-failed` | a requested check failed                        |
+//! | 8      | `over-memory`         | an in-memory run over the memory budget         |
+
+use clap::ValueEnum;
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// How a failure is reported on stderr.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+/// A failure with a stable code.
+#[derive(Debug)]
+pub enum Failure {
+    /// The key could not be loaded or parsed
+    Key(String),
+    /// Reading or writing a file other than the output failed
+    Io(io::Error),
+    /// A device or quota ran out of space
+    DiskFull(io::Error),
+    /// Writing the output failed
+    Sink(io::Error),
+    /// A check asked for (verify, catalog, validate-token, ...) failed
+    Verification(String),
+    /// An in-memory run does not fit the memory budget
+    Memory(String),
+    /// Anything else
+    Other(String),
+}
+
+impl Failure {
+    /// A failure writing the output, or a full disk.
+    pub fn sink(e: io::Error) -> Self {
+        match is_full(&e) {
+            true => Failure::DiskFull(e),
+            false => Failure::Sink(e),
+        }
+    }
+
+    /// The failure behind `e`: itself if it is one, else by its type.
+    pub fn classify(e: Box<dyn Error>) -> Self {
+        let e = match e.downcast::<Failure>() {
+            Ok(failure) => return *failure,
+            Err(e) => e,
+        };
+        match e.downcast::<io::Error>() {
+            Ok(e) if is_full(&e) => Failure::DiskFull(*e),
+            Ok(e) => Failure::Io(*e),
+            Err(e) => Failure::Other(e.to_string()),
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            Failure::Key(_) => "bad-key",
+            Failure::Io(_) => "io",
+            Failure::DiskFull(_) => "disk-full",
+            Failure::Sink(_) => "sink-write",
+            Failure::Verification(_) => "verification-failed",
+            Failure::Memory(_) => "over-memory",
+            Failure::Other(_) => "error",
+        }
+    }
+
+    pub fn exit_status(&self) -> u8 {
+        match self {
+            Failure::Other(_) => 1,
+            Failure::Key(_) => 3,
+            Failure::Io(_) => 4,
+            Failure::DiskFull(_) => 5,
+            Failure::Sink(_) => 6,
+            Failure::Verification(_) => 7,
+            Failure::Memory(_) => 8,
+        }
+    }
+
+    /// What to try next, if there is an obvious remedy.
+    pub fn hint(&self) -> Option<&'static str> {
+        match self {
+            Failure::Key(_) => Some(
+                "--key takes keyring:<name> (see `key list`), or env:<VAR> or file:<path> \
+                 holding 32 or 64 hex characters",
+            ),
+            Failure::DiskFull(_) => Some(
+                "free space on the output device, compress with --compress, or split the run \
+                 across devices with --shard-size",
+            ),
+            Failure::Sink(_) => {
+                Some("check that --output is writable; --fallback PATH fails over to a spare")
+            }
+            Failure::Verification(_) => Some(
+                "the problems are listed above; check that the key and files are the ones the \
+                 run wrote",
+            ),
+            Failure::Memory(_) => Some(
+                "stream with --output, keep what fits with --over-memory cap, or raise \
+                 --max-memory",
+            ),
+            Failure::Io(_) | Failure::Other(_) => None,
+        }
+    }
+
+    /// Writes the failure to stderr in `format`.
+    pub fn report(&self, format: LogFormat) {
+        match format {
+            LogFormat::Text => eprintln!("Error: {}", self),
+            LogFormat::Json => eprintln!(
+                "{}",
+                serde_json::json!({
+                    "error": {
+                        "code": self.code(),
+                        "exit_status": self.exit_status(),
+                        "message": self.to_string(),
+                        "hint": self.hint(),
+                    }
+                })
+            ),
+        }
+    }
+}
+
+fn is_full(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::StorageFull | io::ErrorKind::QuotaExceeded
+    )
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Failure::Key(message)
+            | Failure::Verification(message)
+            | Failure::Memory(message)
+            | Failure::Other(message) => f.write_str(message),
+            Failure::Io(e) => write!(f, "{}", e),
+            Failure::DiskFull(e) => write!(f, "out of space: {}", e),
+            Failure::Sink(e) => write!(f, "cannot write the output: {}", e),
+        }
+    }
+}
+
+impl Error for Failure {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failures_keep_their_codes() {
+        let full = io::Error::from(io::ErrorKind::StorageFull);
+        assert_eq!(Failure::sink(full).exit_status(), 5);
+        let denied = io::Error::from(io::ErrorKind::PermissionDenied);
+        assert_eq!(Failure::sink(denied).code(), "sink-write");
+
+        let boxed: Box<dyn Error> = Failure::Key("no key".into()).into();
+        assert_eq!(Failure::classify(boxed).exit_status(), 3);
+        let boxed: Box<dyn Error> = io::Error::from(io::ErrorKind::NotFound).into();
+        assert_eq!(Failure::classify(boxed).code(), "io");
+        let boxed: Box<dyn Error> = io::Error::from(io::ErrorKind::QuotaExceeded).into();
+        assert_eq!(Failure::classify(boxed).code(), "disk-full");
+        let boxed: Box<dyn Error> = "bad".into();
+        let other = Failure::classify(boxed);
+        assert_eq!((other.exit_status(), other.to_string()), (1, "bad".into()));
+        assert!(other.hint().is_none());
+
+        // Every code is distinct, and none is clap's usage status
+        let all = [
+            Failure::Key(String::new()),
+            Failure::Io(io::Error::other("")),
+            Failure::DiskFull(io::Error::other("")),
+            Failure::Sink(io::Error::other("")),
+            Failure::Verification(String::new()),
+            Failure::Memory(String::new()),
+            Failure::Other(String::new()),
+        ];
+        let mut statuses: Vec<u8> = all.iter().map(Failure::exit_status).collect();
+        statuses.sort();
+        statuses.dedup();
+        assert_eq!(statuses.len(), all.len());
+        assert!(!statuses.contains(&2) && !statuses.contains(&0));
+    }
+}
@@ -0,0 +1,179 @@
+//! Fault injection for exercising the recovery paths (the `fault-injection`
+//! feature).
+//!
+//! Once a [`FaultPlan`] is [`install`]ed, every chunk write may fail with an
+//! I/O error, stall first, or be the point where the process dies — either
+//! cleanly between two chunks or halfway through writing one, which leaves
+//! a torn chunk behind. Failures and stalls are drawn per write attempt from
+//! the plan's seed, so a retried write is a fresh draw.
+
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+/// Which faults to inject.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FaultPlan {
+    /// Probability that a write fails
+    pub fail_writes: f64,
+    /// Fail this write attempt (counting from 0), whatever the draw
+    pub fail_at: Option<usize>,
+    /// Probability that a write stalls first, and for how long
+    pub delay: Option<(f64, Duration)>,
+    /// Abort the process once this many writes have completed
+    pub kill_after: Option<usize>,
+    /// Write half of the next chunk before aborting
+    pub tear: bool,
+    pub seed: u64,
+}
+
+impl FaultPlan {
+    /// Parses comma-separated faults: `fail-writes=P`, `fail-at=N`, `delay=P:MSms`,
+    /// `kill-after=N`, `tear-after=N` and `seed=S`, e.g.
+    /// `fail-writes=0.1,delay=0.5:20ms,tear-after=40`.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut plan = FaultPlan::default();
+        for fault in s.split(',') {
+            let (name, value) = fault
+                .split_once('=')
+                .ok_or_else(|| format!("`{}`: expected name=value", fault))?;
+            let invalid = || format!("`{}`: invalid value `{}`", name, value);
+            let probability = |p: &str| {
+                p.parse::<f64>()
+                    .ok()
+                    .filter(|p| (0.0..=1.0).contains(p))
+                    .ok_or_else(invalid)
+            };
+            match name {
+                "fail-writes" => plan.fail_writes = probability(value)?,
+                "fail-at" => plan.fail_at = Some(value.parse().map_err(|_| invalid())?),
+                "delay" => {
+                    let (p, ms) = value
+                        .split_once(':')
+                        .and_then(|(p, ms)| Some((p, ms.strip_suffix("ms")?.parse().ok()?)))
+                        .ok_or_else(invalid)?;
+                    plan.delay = Some((probability(p)?, Duration::from_millis(ms)));
+                }
+                "kill-after" | "tear-after" => {
+                    plan.kill_after = Some(value.parse().map_err(|_| invalid())?);
+                    plan.tear = name == "tear-after";
+                }
+                "seed" => plan.seed = value.parse().map_err(|_| invalid())?,
+                _ => {
+                    return Err(format!(
+                        "unknown fault `{}` (expected fail-writes, fail-at, delay, \
+                         kill-after, tear-after or seed)",
+                        name
+                    ))
+                }
+            }
+        }
+        Ok(plan)
+    }
+}
+
+impl fmt::Display for FaultPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:.1}% of writes fail", self.fail_writes * 100.0)?;
+        if let Some(n) = self.fail_at {
+            write!(f, ", write {} fails", n)?;
+        }
+        if let Some((p, delay)) = self.delay {
+            write!(f, ", {:.1}% stall for {:?}", p * 100.0, delay)?;
+        }
+        if let Some(n) = self.kill_after {
+            let how = if self.tear {
+                "halfway through the next one"
+            } else {
+                "right after"
+            };
+            write!(f, ", killed after {} writes ({})", n, how)?;
+        }
+        write!(f, ", seed {}", self.seed)
+    }
+}
+
+static PLAN: OnceLock<FaultPlan> = OnceLock::new();
+static ATTEMPTS: AtomicUsize = AtomicUsize::new(0);
+static COMPLETED: AtomicUsize = AtomicUsize::new(0);
+
+/// Injects `plan` into every later write, for the rest of the process. Only
+/// the first plan installed takes effect.
+pub fn install(plan: FaultPlan) {
+    let _ = PLAN.set(plan);
+}
+
+/// Writes one chunk, `buf`, through `write`, injecting the installed faults.
+pub(crate) fn write(buf: &[u8], mut write: impl FnMut(&[u8]) -> io::Result<()>) -> io::Result<()> {
+    let Some(plan) = PLAN.get() else {
+        return write(buf);
+    };
+    let attempt = ATTEMPTS.fetch_add(1, Ordering::Relaxed);
+    let draw = |stream: u64| {
+        let bits = splitmix64(plan.seed ^ splitmix64(attempt as u64 * 2 + stream));
+        (bits >> 11) as f64 / (1u64 << 53) as f64
+    };
+    if let Some((p, delay)) = plan.delay {
+        if draw(0) < p {
+            std::thread::sleep(delay);
+        }
+    }
+    if draw(1) < plan.fail_writes || plan.fail_at == Some(attempt) {
+        return Err(io::Error::other(format!(
+            "injected write failure (attempt {})",
+            attempt
+        )));
+    }
+    if plan.tear && plan.kill_after == Some(COMPLETED.load(Ordering::SeqCst)) {
+        let _ = write(&buf[..buf.len() / 2]);
+        die("halfway through a chunk");
+    }
+    write(buf)?;
+    let completed = COMPLETED.fetch_add(1, Ordering::SeqCst) + 1;
+    if !plan.tear && plan.kill_after == Some(completed) {
+        die("after a chunk");
+    }
+    Ok(())
+}
+
+fn die(when: &str) -> ! {
+    eprintln!(
+        "fault injection: killing the process {}, {} writes in",
+        when,
+        COMPLETED.load(Ordering::SeqCst)
+    );
+    std::process::abort()
+}
+
+fn splitmix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plans_parse() {
+        let plan = FaultPlan::parse("fail-writes=0.25,delay=1:20ms,tear-after=7,seed=3").unwrap();
+        assert_eq!(
+            plan,
+            FaultPlan {
+                fail_writes: 0.25,
+                fail_at: None,
+                delay: Some((1.0, Duration::from_millis(20))),
+                kill_after: Some(7),
+                tear: true,
+                seed: 3,
+            }
+        );
+        assert!(FaultPlan::parse("fail-writes=2").is_err());
+        assert!(FaultPlan::parse("delay=0.5:20").is_err());
+        assert!(FaultPlan::parse("explode=1").is_err());
+    }
+}
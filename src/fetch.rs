@@ -0,0 +1,456 @@
+//! `fetch`: pull the run of a `serve --run-key` server into a file, page
+//! by page.
+//!
+//! Each page is asked for with the cursor the page before named, and
+//! written after it, so the file ends up holding what a file run with the
+//! server's options writes. A page that fails (a dropped connection, a
+//! reply cut short, a 5xx or a 429) is asked for again after a backoff,
+//! the file cut back to where the page began. Progress is kept next to
+//! the output in `OUTPUT.fetch`, saved after each page once the page is on
+//! disk, so `fetch --resume` picks up after the last whole page of a fetch
+//! that was stopped, from the same server or another serving the same
+//! run. The run key's fingerprint must stay the same throughout: a server
+//! restarted with another run key cannot splice its records into the
+//! file.
+
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use mass_password_gen_optimized::Format;
+
+use crate::checkpoint;
+use crate::client::{Client, Response};
+
+const HEADER: &str = "# mass_password_gen fetch: where an interrupted fetch stopped";
+
+/// Longest wait between two tries of a page.
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// What to fetch, and how hard to try.
+pub struct Options {
+    /// Records from the start of the run; all of them if `None`
+    pub count: Option<usize>,
+    /// Records asked for at a time
+    pub page: usize,
+    /// Another format than the run's own
+    pub format: Option<Format>,
+    /// Tries of a page after its first, before giving up
+    pub retries: u32,
+    /// Wait before the first retry, doubling with each
+    pub backoff: Duration,
+    pub resume: bool,
+}
+
+/// How far a fetch got, as its sidecar keeps it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Progress {
+    format: String,
+    count: Option<usize>,
+    /// The run key's, from the first page
+    fingerprint: Option<String>,
+    /// Of the next page; empty for the first
+    cursor: String,
+    records: usize,
+    bytes: u64,
+}
+
+/// Why a page was not fetched.
+enum Failure {
+    /// Worth another try, after at least the wait given
+    Retry(String, Option<Duration>),
+    Fatal(String),
+}
+
+/// The sidecar of a fetch into `output`.
+pub fn sidecar(output: &Path) -> PathBuf {
+    let mut path = output.as_os_str().to_owned();
+    path.push(".fetch");
+    PathBuf::from(path)
+}
+
+/// Fetches into `output` from `client`'s server, returning the records
+/// fetched, and the run key's fingerprint.
+pub fn fetch(client: &Client, output: &Path, options: &Options) -> Result<(usize, String), String> {
+    let sidecar = sidecar(output);
+    let fresh = Progress {
+        format: options.format.map_or("run".to_string(), |f| f.to_string()),
+        count: options.count,
+        fingerprint: None,
+        cursor: String::new(),
+        records: 0,
+        bytes: 0,
+    };
+    let at = |e: io::Error| format!("{}: {}", output.display(), e);
+    let (mut progress, mut file) = if options.resume {
+        let saved =
+            Progress::load(&sidecar).map_err(|e| format!("{}: {}", sidecar.display(), e))?;
+        if saved.format != fresh.format {
+            return Err(format!(
+                "{}: the fetch was started with format {}",
+                sidecar.display(),
+                saved.format
+            ));
+        }
+        if saved.count != fresh.count {
+            return Err(format!(
+                "{}: the fetch was started with {}",
+                sidecar.display(),
+                saved
+                    .count
+                    .map_or("no --count".to_string(), |n| format!("--count {}", n))
+            ));
+        }
+        let file = File::options().write(true).open(output).map_err(at)?;
+        if file.metadata().map_err(at)?.len() < saved.bytes {
+            return Err(format!(
+                "{}: shorter than the {} bytes fetched into it",
+                output.display(),
+                saved.bytes
+            ));
+        }
+        status!(
+            "Resuming the fetch into {}: {} records already fetched",
+            output.display(),
+            saved.records
+        );
+        (saved, file)
+    } else {
+        if sidecar.exists() {
+            return Err(format!(
+                "{} holds a fetch that was stopped: --resume it, or remove it to start over",
+                sidecar.display()
+            ));
+        }
+        fresh
+            .save(&sidecar)
+            .map_err(|e| format!("{}: {}", sidecar.display(), e))?;
+        (fresh, checkpoint::private_file(output).map_err(at)?)
+    };
+
+    loop {
+        let limit = match options.count {
+            Some(count) if progress.records >= count => break,
+            Some(count) => options.page.min(count - progress.records),
+            None => options.page,
+        };
+        let next = with_retries(options, || page(client, &mut file, &mut progress, limit))?;
+        progress.cursor = next.clone().unwrap_or_default();
+        // Never claim more than what has reached the disk
+        file.sync_data().map_err(at)?;
+        progress
+            .save(&sidecar)
+            .map_err(|e| format!("{}: {}", sidecar.display(), e))?;
+        match next {
+            Some(_) => {}
+            None if options.count.is_some_and(|count| progress.records < count) => {
+                return Err(format!(
+                    "the server's run ends after {} records",
+                    progress.records
+                ))
+            }
+            None => break,
+        }
+    }
+    fs::remove_file(&sidecar).map_err(|e| format!("{}: {}", sidecar.display(), e))?;
+    Ok((progress.records, progress.fingerprint.unwrap_or_default()))
+}
+
+/// Runs `attempt` until it succeeds, fails for good, or has been retried
+/// `options.retries` times.
+fn with_retries<T>(
+    options: &Options,
+    mut attempt: impl FnMut() -> Result<T, Failure>,
+) -> Result<T, String> {
+    let mut wait = options.backoff;
+    for retry in 0.. {
+        match attempt() {
+            Ok(done) => return Ok(done),
+            Err(Failure::Fatal(e)) => return Err(e),
+            Err(Failure::Retry(e, _)) if retry == options.retries => {
+                return Err(format!("{} (gave up after {} retries)", e, retry))
+            }
+            Err(Failure::Retry(e, at_least)) => {
+                let pause = wait.max(at_least.unwrap_or_default()).min(MAX_BACKOFF);
+                status!("{}; retrying in {:.1?}", e, pause);
+                std::thread::sleep(pause);
+                wait = (wait * 2).min(MAX_BACKOFF);
+            }
+        }
+    }
+    unreachable!("retries are counted")
+}
+
+/// Fetches `limit` records at `progress.cursor` into `file`, after the
+/// bytes already fetched, and advances `progress` past them; returns the
+/// cursor of the next page, unless the run ends with this one.
+fn page(
+    client: &Client,
+    file: &mut File,
+    progress: &mut Progress,
+    limit: usize,
+) -> Result<Option<String>, Failure> {
+    let fatal = |e: io::Error| Failure::Fatal(format!("writing the output: {}", e));
+    file.set_len(progress.bytes).map_err(fatal)?;
+    file.seek(SeekFrom::Start(progress.bytes)).map_err(fatal)?;
+    let mut target = format!("/passwords?cursor={}&limit={}", progress.cursor, limit);
+    if progress.format != "run" {
+        target.push_str(&format!("&format={}", progress.format));
+    }
+    // A failed handshake or a garbled reply is no better the next time
+    let retry = |e: io::Error| match e.kind() {
+        io::ErrorKind::InvalidData => Failure::Fatal(format!("{}: {}", client.server(), e)),
+        _ => Failure::Retry(format!("{}: {}", client.server(), e), None),
+    };
+    let mut reply = client.request("GET", &target, &[], b"").map_err(retry)?;
+    match reply.status {
+        200 => {}
+        429 | 500..=599 => {
+            let wait = reply
+                .header("retry-after")
+                .and_then(|s| s.parse().ok())
+                .map(Duration::from_secs);
+            let status = reply.status;
+            return Err(Failure::Retry(
+                format!("{}: {} {}", client.server(), status, reply.text()),
+                wait,
+            ));
+        }
+        status => {
+            return Err(Failure::Fatal(format!(
+                "{}: {} {}",
+                client.server(),
+                status,
+                reply.text()
+            )))
+        }
+    }
+    let fingerprint = reply.header("x-key-fingerprint").unwrap_or_default();
+    match &progress.fingerprint {
+        Some(expected) if expected != fingerprint => {
+            return Err(Failure::Fatal(format!(
+                "{} now serves the run of key {}, not {}",
+                client.server(),
+                fingerprint,
+                expected
+            )))
+        }
+        _ => progress.fingerprint = Some(fingerprint.to_string()),
+    }
+    let header = |reply: &Response, name: &str| {
+        reply
+            .header(name)
+            .and_then(|v| v.parse::<usize>().ok())
+            .ok_or_else(|| {
+                Failure::Fatal(format!(
+                    "{} did not send {}: is it a --run-key server?",
+                    client.server(),
+                    name
+                ))
+            })
+    };
+    let (first, records) = (
+        header(&reply, "x-first-index")?,
+        header(&reply, "x-records")?,
+    );
+    if first != progress.records {
+        return Err(Failure::Fatal(format!(
+            "{} sent records from {} on, not {}",
+            client.server(),
+            first,
+            progress.records
+        )));
+    }
+    let next = reply.header("x-next-cursor").map(str::to_string);
+    let mut buf = vec![0; 64 << 10];
+    let mut written = 0u64;
+    loop {
+        let n = reply.read(&mut buf).map_err(retry)?;
+        if n == 0 {
+            break;
+        }
+        file.write_all(&buf[..n]).map_err(fatal)?;
+        written += n as u64;
+    }
+    progress.records += records;
+    progress.bytes += written;
+    Ok(next)
+}
+
+impl Progress {
+    /// Writes the progress to `path` atomically, so a crash mid-save
+    /// leaves the previous one in place.
+    fn save(&self, path: &Path) -> io::Result<()> {
+        let text = format!(
+            "{}\nformat={}\ncount={}\nfingerprint={}\ncursor={}\nrecords={}\nbytes={}\n",
+            HEADER,
+            self.format,
+            self.count.map_or(String::new(), |n| n.to_string()),
+            self.fingerprint.as_deref().unwrap_or(""),
+            self.cursor,
+            self.records,
+            self.bytes,
+        );
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let mut file = checkpoint::private_file(Path::new(&tmp))?;
+        file.write_all(text.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)
+    }
+
+    /// Reads progress written by [`save`](Self::save).
+    fn load(path: &Path) -> io::Result<Progress> {
+        let text = fs::read_to_string(path)?;
+        let field = |name: &str| {
+            text.lines()
+                .find_map(|line| line.strip_prefix(name)?.strip_prefix('='))
+                .ok_or_else(|| format!("no `{}`", name))
+        };
+        let number = |name: &str| {
+            field(name)?
+                .parse::<u64>()
+                .map_err(|_| format!("bad `{}`", name))
+        };
+        let parse = || -> Result<Progress, String> {
+            Ok(Progress {
+                format: field("format")?.to_string(),
+                count: match field("count")? {
+                    "" => None,
+                    _ => Some(number("count")? as usize),
+                },
+                fingerprint: Some(field("fingerprint")?)
+                    .filter(|f| !f.is_empty())
+                    .map(str::to_string),
+                cursor: field("cursor")?.to_string(),
+                records: number("records")? as usize,
+                bytes: number("bytes")?,
+            })
+        };
+        parse().map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("malformed fetch progress ({})", e),
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::Server;
+    use crate::listen::Listener;
+    use crate::serve::{run, Policy, Reply};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Records of the test run: `r<index>\n`.
+    const RECORDS: usize = 25;
+
+    /// A server paging through `RECORDS` records, with cursors of bare
+    /// indexes. The `fail`th request (counting from 1) is cut off after
+    /// its first record, and once `stop_after` pages are served it answers
+    /// 503.
+    fn server(fail: usize, stop_after: Option<usize>) -> Server {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = Server::parse(&format!("http://{}", listener.local_addr().unwrap())).unwrap();
+        let requests = AtomicUsize::new(0);
+        let served = AtomicUsize::new(0);
+        std::thread::spawn(move || {
+            run(Listener::Tcp(listener), &Policy::default(), |request| {
+                let n = requests.fetch_add(1, Ordering::Relaxed) + 1;
+                if stop_after.is_some_and(|stop| served.load(Ordering::Relaxed) >= stop) {
+                    return Reply::Error(503, "stopping".to_string());
+                }
+                let query = |name: &str| {
+                    let (_, value) = request.query.iter().find(|(n, _)| n == name).unwrap();
+                    value.clone()
+                };
+                let start: usize = query("cursor").parse().unwrap_or(0);
+                let end = RECORDS.min(start + query("limit").parse::<usize>().unwrap());
+                let mut headers = vec![
+                    ("X-Records", (end - start).to_string()),
+                    ("X-First-Index", start.to_string()),
+                    ("X-Key-Fingerprint", "f00d".to_string()),
+                ];
+                if end < RECORDS {
+                    headers.push(("X-Next-Cursor", end.to_string()));
+                }
+                if n != fail {
+                    served.fetch_add(1, Ordering::Relaxed);
+                }
+                Reply::Stream {
+                    content_type: "text/plain",
+                    headers,
+                    pulled: None,
+                    body: Box::new(move |out| {
+                        for i in start..end {
+                            writeln!(out, "r{}", i)?;
+                            if n == fail {
+                                out.flush()?;
+                                return Err(io::Error::other("cut off"));
+                            }
+                        }
+                        Ok(())
+                    }),
+                }
+            })
+        });
+        server
+    }
+
+    fn options(count: Option<usize>, retries: u32, resume: bool) -> Options {
+        Options {
+            count,
+            page: 10,
+            format: None,
+            retries,
+            backoff: Duration::from_millis(1),
+            resume,
+        }
+    }
+
+    fn expected(records: usize) -> String {
+        (0..records).map(|i| format!("r{}\n", i)).collect()
+    }
+
+    #[test]
+    fn fetches_retry_cut_pages_and_resume() {
+        let dir = std::env::temp_dir().join(format!("mpg_fetch_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let output = dir.join("out.txt");
+
+        // The second page is cut off once, and fetched again whole
+        let client = Client::new(server(2, None), None);
+        let (records, fingerprint) = fetch(&client, &output, &options(None, 2, false)).unwrap();
+        assert_eq!((records, fingerprint.as_str()), (RECORDS, "f00d"));
+        assert_eq!(fs::read_to_string(&output).unwrap(), expected(RECORDS));
+        assert!(!sidecar(&output).exists());
+
+        // --count stops mid-run
+        let (records, _) = fetch(&client, &output, &options(Some(13), 2, false)).unwrap();
+        assert_eq!(records, 13);
+        assert_eq!(fs::read_to_string(&output).unwrap(), expected(13));
+        let error = fetch(&client, &output, &options(Some(30), 0, false)).unwrap_err();
+        assert!(error.contains("ends after 25 records"), "{}", error);
+        fs::remove_file(sidecar(&output)).unwrap();
+
+        // A server that goes away after a page leaves a fetch to resume,
+        // from another server for the same run
+        let client = Client::new(server(0, Some(1)), None);
+        let error = fetch(&client, &output, &options(None, 1, false)).unwrap_err();
+        assert!(error.contains("503"), "{}", error);
+        assert!(fetch(&client, &output, &options(None, 1, false))
+            .unwrap_err()
+            .contains("--resume"));
+        assert_eq!(fs::read_to_string(&output).unwrap(), expected(10));
+        let other = Client::new(server(0, None), None);
+        let error = fetch(&other, &output, &options(Some(20), 1, true)).unwrap_err();
+        assert!(error.contains("was started with no --count"), "{}", error);
+        let (records, _) = fetch(&other, &output, &options(None, 1, true)).unwrap();
+        assert_eq!(records, RECORDS);
+        assert_eq!(fs::read_to_string(&output).unwrap(), expected(RECORDS));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,658 @@
+//! Output formats: how generated records are serialized on the way out.
+//!
+//! The generator produces records in their native form (raw 16-byte blocks,
+//! or text without framing). An [`OutputSink`] turns one chunk's records
+//! into bytes; chunks are serialized independently on the worker threads and
+//! the results are written out in order.
+
+use std::fmt;
+use std::ops::Range;
+
+use crate::record_id::UUID_LEN;
+use crate::timestamp::{Timestamps, TIMESTAMP_LEN};
+use crate::{PasswordGenerator, RecordIds, TimestampSpread};
+
+/// Serializes records for output.
+pub trait OutputSink: Send + Sync {
+    /// Written once before the first record, e.g. a CSV header.
+    fn header(&self) -> &[u8] {
+        b""
+    }
+
+    /// Appends record number `index` (its native bytes, without framing) to
+    /// `out`.
+    fn write_record(&self, index: usize, record: &[u8], out: &mut Vec<u8>);
+
+    /// Serialized length of a record of `record_len` bytes, if it does not
+    /// depend on the record's content. It may depend on `index` only through
+    /// its number of decimal digits.
+    fn record_len(&self, index: usize, record_len: usize) -> Option<usize>;
+
+    /// Appends the password of record `index` as this output shows it, once
+    /// unquoted and unescaped: what a reader of the output takes the
+    /// credential to be. Reference checksums cover this.
+    fn credential(&self, _index: usize, record: &[u8], out: &mut Vec<u8>) {
+        out.extend_from_slice(record);
+    }
+
+    /// Appends a chunk's records, numbered from `first`, given the chunk's
+    /// output `data`. Sinks that spend long on each record override this to
+    /// serialize the chunk in parallel.
+    fn write_chunk(
+        &self,
+        generator: &PasswordGenerator,
+        first: usize,
+        data: &[u8],
+        out: &mut Vec<u8>,
+    ) {
+        let mut index = first;
+        generator.for_each_record(data, |record| {
+            self.write_record(index, record, out);
+            index += 1;
+        });
+    }
+}
+
+/// The built-in output formats.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Format {
+    /// Native records: raw blocks, or newline-terminated text
+    #[default]
+    Raw,
+    /// One lowercase hex record per line
+    Hex,
+    /// One standard (padded) base64 record per line
+    Base64,
+    /// `index,password` rows with a header, quoted where needed
+    Csv,
+    /// `{"index":..,"password":".."}` objects, one per line
+    Jsonl,
+    /// Self-expiring tokens, one per line: see [`token`](crate::token). Its
+    /// sink needs an expiry, so it comes from
+    /// [`ExpiringTokens::new`](crate::token::ExpiringTokens::new) rather
+    /// than [`Format::sink`].
+    ExpiringToken,
+    /// Hashes in a hashcat input format, one per line: see
+    /// [`hashcat`](crate::hashcat). Its sink needs a hash mode, so it comes
+    /// from `HashcatLines::new` rather than [`Format::sink`].
+    Hashcat,
+}
+
+impl Format {
+    /// Parses `raw`, `hex`, `base64`, `csv`, `jsonl`, `expiring-token` or
+    /// `hashcat`.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s {
+            "raw" => Ok(Format::Raw),
+            "hex" => Ok(Format::Hex),
+            "base64" => Ok(Format::Base64),
+            "csv" => Ok(Format::Csv),
+            "jsonl" => Ok(Format::Jsonl),
+            "expiring-token" => Ok(Format::ExpiringToken),
+            "hashcat" => Ok(Format::Hashcat),
+            _ => Err(format!(
+                "unknown format `{}` (expected raw, hex, base64, csv, jsonl, expiring-token \
+                 or hashcat)",
+                s
+            )),
+        }
+    }
+
+    /// The sink for this format given what `generator` produces, or `None`
+    /// for [`Format::Raw`], which needs no serialization.
+    ///
+    /// # Panics
+    ///
+    /// For [`Format::ExpiringToken`], whose tokens need an expiry, and
+    /// [`Format::Hashcat`], whose hashes need a mode.
+    pub fn sink(self, generator: &PasswordGenerator) -> Option<Box<dyn OutputSink>> {
+        self.text_sink(TextField {
+            binary: generator.is_binary(),
+            may_need_escaping: may_need_escaping(generator),
+        })
+    }
+
+    /// The sink for records of unknown content, such as those read back by a
+    /// [`CorpusReader`](crate::CorpusReader): raw blocks if `binary`, text
+    /// otherwise. Panics for [`Format::ExpiringToken`] and
+    /// [`Format::Hashcat`], like [`sink`](Self::sink).
+    pub fn sink_for_records(self, binary: bool) -> Option<Box<dyn OutputSink>> {
+        self.text_sink(TextField {
+            binary,
+            may_need_escaping: true,
+        })
+    }
+
+    /// The sink for this format with each record's timestamp from `spread`
+    /// in a `created_at` field; only CSV and JSON Lines have fields.
+    pub fn timestamped_sink(
+        self,
+        generator: &PasswordGenerator,
+        spread: &TimestampSpread,
+    ) -> Result<Box<dyn OutputSink>, String> {
+        self.sink_with_fields(generator, Some(spread), None)
+    }
+
+    /// The sink for this format with an `id` field from `ids` and a
+    /// `created_at` field from `spread`, each where given; only CSV and
+    /// JSON Lines have fields.
+    pub fn sink_with_fields(
+        self,
+        generator: &PasswordGenerator,
+        spread: Option<&TimestampSpread>,
+        ids: Option<RecordIds>,
+    ) -> Result<Box<dyn OutputSink>, String> {
+        let text = TextField {
+            binary: generator.is_binary(),
+            may_need_escaping: may_need_escaping(generator),
+        };
+        let fields = Fields {
+            ids,
+            timestamps: spread.map(|spread| spread.for_run(generator)),
+        };
+        match self {
+            Format::Csv => Ok(Box::new(Csv(text, fields))),
+            Format::Jsonl => Ok(Box::new(Jsonl(text, fields))),
+            _ => Err(format!(
+                "{} output has no fields for {}",
+                self,
+                match spread {
+                    Some(_) => "timestamps",
+                    None => "identifiers",
+                }
+            )),
+        }
+    }
+
+    fn text_sink(self, text: TextField) -> Option<Box<dyn OutputSink>> {
+        match self {
+            Format::Raw => None,
+            Format::Hex => Some(Box::new(Hex)),
+            Format::Base64 => Some(Box::new(Base64)),
+            Format::Csv => Some(Box::new(Csv(text, Fields::default()))),
+            Format::Jsonl => Some(Box::new(Jsonl(text, Fields::default()))),
+            Format::ExpiringToken => panic!("expiring tokens need an expiry"),
+            Format::Hashcat => panic!("hashcat lines need a hash mode"),
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Format::Raw => "raw",
+            Format::Hex => "hex",
+            Format::Base64 => "base64",
+            Format::Csv => "csv",
+            Format::Jsonl => "jsonl",
+            Format::ExpiringToken => "expiring-token",
+            Format::Hashcat => "hashcat",
+        })
+    }
+}
+
+/// Puts a fixed marker, such as `--demo`'s `DEMO-`, in front of every
+/// password, then serializes it as text in a format. Raw blocks are written
+/// as hex first, so the marker is always readable.
+pub struct Marked {
+    prefix: Vec<u8>,
+    binary: bool,
+    inner: Option<Box<dyn OutputSink>>,
+}
+
+impl Marked {
+    /// Marks `generator`'s records with `prefix` in `format`, which must
+    /// show text as it is: raw, CSV or JSON Lines.
+    pub fn new(prefix: &str, format: Format, generator: &PasswordGenerator) -> Self {
+        assert!(
+            !matches!(format, Format::Hex | Format::Base64 | Format::ExpiringToken),
+            "{} would hide the marker",
+            format
+        );
+        let binary = generator.is_binary();
+        let special = prefix.bytes().any(|b| matches!(b, b',' | b'"' | b'\\'));
+        Marked {
+            prefix: prefix.as_bytes().to_vec(),
+            binary,
+            inner: format.text_sink(TextField {
+                binary: false,
+                may_need_escaping: special || (!binary && may_need_escaping(generator)),
+            }),
+        }
+    }
+}
+
+impl OutputSink for Marked {
+    fn header(&self) -> &[u8] {
+        self.inner.as_ref().map_or(b"", |sink| sink.header())
+    }
+
+    fn write_record(&self, index: usize, record: &[u8], out: &mut Vec<u8>) {
+        let mut marked = Vec::new();
+        self.credential(index, record, &mut marked);
+        match &self.inner {
+            Some(sink) => sink.write_record(index, &marked, out),
+            None => {
+                out.extend_from_slice(&marked);
+                out.push(b'\n');
+            }
+        }
+    }
+
+    fn credential(&self, _index: usize, record: &[u8], out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.prefix);
+        push_text(self.binary, record, out);
+    }
+
+    fn record_len(&self, index: usize, record_len: usize) -> Option<usize> {
+        let marked = self.prefix.len()
+            + if self.binary {
+                record_len * 2
+            } else {
+                record_len
+            };
+        match &self.inner {
+            Some(sink) => sink.record_len(index, marked),
+            None => Some(marked + 1),
+        }
+    }
+}
+
+/// Whether text records from `generator` can contain characters that CSV or
+/// JSON have to quote or escape.
+pub(crate) fn may_need_escaping(generator: &PasswordGenerator) -> bool {
+    let special = |b: &u8| matches!(b, b',' | b'"' | b'\\');
+    if let Some(enc) = generator.encoder() {
+        return enc.alphabet().iter().any(special);
+    }
+    if let Some(pass) = generator.passphrase() {
+        let symbols = pass.style().symbols.as_deref().unwrap_or("");
+        return pass
+            .separator()
+            .bytes()
+            .chain(symbols.bytes())
+            .any(|b| special(&b));
+    }
+    false
+}
+
+struct Hex;
+
+impl OutputSink for Hex {
+    fn write_record(&self, _index: usize, record: &[u8], out: &mut Vec<u8>) {
+        push_hex(record, out);
+        out.push(b'\n');
+    }
+
+    fn record_len(&self, _index: usize, record_len: usize) -> Option<usize> {
+        Some(record_len * 2 + 1)
+    }
+
+    fn credential(&self, _index: usize, record: &[u8], out: &mut Vec<u8>) {
+        push_hex(record, out);
+    }
+}
+
+struct Base64;
+
+impl OutputSink for Base64 {
+    fn write_record(&self, _index: usize, record: &[u8], out: &mut Vec<u8>) {
+        push_base64(record, out);
+        out.push(b'\n');
+    }
+
+    fn record_len(&self, _index: usize, record_len: usize) -> Option<usize> {
+        Some(record_len.div_ceil(3) * 4 + 1)
+    }
+
+    fn credential(&self, index: usize, record: &[u8], out: &mut Vec<u8>) {
+        self.write_record(index, record, out);
+        out.pop();
+    }
+}
+
+/// How the password field of CSV and JSON Lines is written.
+#[derive(Clone, Copy)]
+struct TextField {
+    /// Records are raw blocks, written as hex
+    binary: bool,
+    /// Text records may contain `,`, `"` or `\`
+    may_need_escaping: bool,
+}
+
+impl TextField {
+    fn fixed_len(&self, record_len: usize) -> Option<usize> {
+        match (self.binary, self.may_need_escaping) {
+            (true, _) => Some(record_len * 2),
+            (false, false) => Some(record_len),
+            (false, true) => None,
+        }
+    }
+}
+
+/// The fields CSV and JSON Lines records have besides the index and the
+/// password.
+#[derive(Clone, Copy, Default)]
+struct Fields {
+    /// An `id`
+    ids: Option<RecordIds>,
+    /// A `created_at` timestamp
+    timestamps: Option<Timestamps>,
+}
+
+struct Csv(TextField, Fields);
+
+impl OutputSink for Csv {
+    fn header(&self) -> &[u8] {
+        match (self.1.ids, self.1.timestamps) {
+            (Some(_), Some(_)) => b"index,password,id,created_at\n",
+            (Some(_), None) => b"index,password,id\n",
+            (None, Some(_)) => b"index,password,created_at\n",
+            (None, None) => b"index,password\n",
+        }
+    }
+
+    fn write_record(&self, index: usize, record: &[u8], out: &mut Vec<u8>) {
+        out.extend_from_slice(index.to_string().as_bytes());
+        out.push(b',');
+        push_csv_field(self.0.binary, record, out);
+        if let Some(ids) = &self.1.ids {
+            out.push(b',');
+            ids.push(index, out);
+        }
+        if let Some(timestamps) = &self.1.timestamps {
+            out.push(b',');
+            out.extend_from_slice(timestamps.format(index).as_bytes());
+        }
+        out.push(b'\n');
+    }
+
+    fn record_len(&self, index: usize, record_len: usize) -> Option<usize> {
+        let id = self.1.ids.map_or(0, |_| 1 + UUID_LEN);
+        let timestamp = self.1.timestamps.map_or(0, |_| 1 + TIMESTAMP_LEN);
+        Some(decimal_digits(index) + 1 + self.0.fixed_len(record_len)? + id + timestamp + 1)
+    }
+
+    fn credential(&self, _index: usize, record: &[u8], out: &mut Vec<u8>) {
+        push_text(self.0.binary, record, out);
+    }
+}
+
+struct Jsonl(TextField, Fields);
+
+impl OutputSink for Jsonl {
+    fn write_record(&self, index: usize, record: &[u8], out: &mut Vec<u8>) {
+        out.extend_from_slice(b"{\"index\":");
+        out.extend_from_slice(index.to_string().as_bytes());
+        out.extend_from_slice(b",\"password\":");
+        push_json_string(self.0.binary, record, out);
+        if let Some(ids) = &self.1.ids {
+            out.extend_from_slice(b",\"id\":\"");
+            ids.push(index, out);
+            out.push(b'"');
+        }
+        if let Some(timestamps) = &self.1.timestamps {
+            out.extend_from_slice(b",\"created_at\":\"");
+            out.extend_from_slice(timestamps.format(index).as_bytes());
+            out.push(b'"');
+        }
+        out.extend_from_slice(b"}\n");
+    }
+
+    fn record_len(&self, index: usize, record_len: usize) -> Option<usize> {
+        let fixed = r#"{"index":,"password":""}"#.len() + 1;
+        let id = self.1.ids.map_or(0, |_| r#","id":"""#.len() + UUID_LEN);
+        let timestamp = self
+            .1
+            .timestamps
+            .map_or(0, |_| r#","created_at":"""#.len() + TIMESTAMP_LEN);
+        Some(fixed + decimal_digits(index) + self.0.fixed_len(record_len)? + id + timestamp)
+    }
+
+    fn credential(&self, _index: usize, record: &[u8], out: &mut Vec<u8>) {
+        push_text(self.0.binary, record, out);
+    }
+}
+
+/// Appends a record as a CSV field: raw blocks as hex, text quoted if it
+/// holds `,` or `"`.
+pub(crate) fn push_csv_field(binary: bool, record: &[u8], out: &mut Vec<u8>) {
+    if binary {
+        push_hex(record, out);
+    } else if record.iter().any(|b| matches!(b, b',' | b'"')) {
+        out.push(b'"');
+        for &b in record {
+            if b == b'"' {
+                out.push(b'"');
+            }
+            out.push(b);
+        }
+        out.push(b'"');
+    } else {
+        out.extend_from_slice(record);
+    }
+}
+
+/// Appends a record as a JSON string: raw blocks as hex, text escaped.
+pub(crate) fn push_json_string(binary: bool, record: &[u8], out: &mut Vec<u8>) {
+    out.push(b'"');
+    if binary {
+        push_hex(record, out);
+    } else {
+        // Records never hold control characters, so these two are all
+        // JSON needs escaped
+        for &b in record {
+            if b == b'"' || b == b'\\' {
+                out.push(b'\\');
+            }
+            out.push(b);
+        }
+    }
+    out.push(b'"');
+}
+
+/// Appends a record as text: raw blocks as hex, text as it is.
+pub(crate) fn push_text(binary: bool, record: &[u8], out: &mut Vec<u8>) {
+    if binary {
+        push_hex(record, out);
+    } else {
+        out.extend_from_slice(record);
+    }
+}
+
+/// Appends `bytes` in standard, padded base64.
+pub(crate) fn push_base64(bytes: &[u8], out: &mut Vec<u8>) {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    for group in bytes.chunks(3) {
+        let b = [
+            group[0],
+            *group.get(1).unwrap_or(&0),
+            *group.get(2).unwrap_or(&0),
+        ];
+        let n = u32::from_be_bytes([0, b[0], b[1], b[2]]);
+        for i in 0..4 {
+            if i <= group.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i)) as usize & 63]);
+            } else {
+                out.push(b'=');
+            }
+        }
+    }
+}
+
+/// `bytes` as lowercase hex.
+pub fn hex(bytes: &[u8]) -> String {
+    let mut out = Vec::with_capacity(bytes.len() * 2);
+    push_hex(bytes, &mut out);
+    String::from_utf8(out).expect("hex digits are ASCII")
+}
+
+pub(crate) fn push_hex(bytes: &[u8], out: &mut Vec<u8>) {
+    const DIGITS: &[u8; 16] = b"0123456789abcdef";
+    for &b in bytes {
+        out.push(DIGITS[(b >> 4) as usize]);
+        out.push(DIGITS[(b & 15) as usize]);
+    }
+}
+
+pub(crate) fn decimal_digits(n: usize) -> usize {
+    n.checked_ilog10().map_or(1, |d| d as usize + 1)
+}
+
+/// Total serialized size of a run through `sink`, header included, if it does
+/// not depend on the records' content.
+pub fn serialized_len(sink: &dyn OutputSink, generator: &PasswordGenerator) -> Option<u128> {
+    let records = serialized_range_len(sink, generator, 0..generator.count())?;
+    Some(sink.header().len() as u128 + records)
+}
+
+/// Serialized size of records `range` of a run through `sink`, without the
+/// header, if it does not depend on the records' content.
+pub fn serialized_range_len(
+    sink: &dyn OutputSink,
+    generator: &PasswordGenerator,
+    range: Range<usize>,
+) -> Option<u128> {
+    if !generator.fixed_width() {
+        return None;
+    }
+    let record_len = generator.record_width() - usize::from(!generator.is_binary());
+    indexed_len(range, |index| sink.record_len(index, record_len))
+}
+
+/// Total of `line_len(i)` over `range`, for lines whose length depends on
+/// their index only through its number of decimal digits.
+pub(crate) fn indexed_len(
+    range: Range<usize>,
+    line_len: impl Fn(usize) -> Option<usize>,
+) -> Option<u128> {
+    let mut total = 0;
+    // Indices with the same number of digits serialize to the same length
+    let mut start = range.start;
+    while start < range.end {
+        let next_power = 10usize
+            .checked_pow(decimal_digits(start) as u32)
+            .unwrap_or(usize::MAX);
+        let end = next_power.min(range.end);
+        total += (end - start) as u128 * line_len(start)? as u128;
+        start = end;
+    }
+    Some(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Charset, Encoder};
+
+    fn serialize(sink: &dyn OutputSink, index: usize, record: &[u8]) -> String {
+        let mut out = Vec::new();
+        sink.write_record(index, record, &mut out);
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn base64_pads_like_rfc_4648() {
+        let cases = [
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("foobar", "Zm9vYmFy"),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(
+                serialize(&Base64, 0, input.as_bytes()),
+                format!("{}\n", expected)
+            );
+        }
+    }
+
+    #[test]
+    fn marked_records_are_prefixed_and_measured() {
+        let raw = PasswordGenerator::builder()
+            .key([1; 16])
+            .count(100)
+            .build()
+            .unwrap();
+        let text = PasswordGenerator::builder()
+            .key([1; 16])
+            .count(100)
+            .encoder(Encoder::new(&Charset::Alnum, 8))
+            .build()
+            .unwrap();
+        let cases: [(_, _, &[u8], _); 4] = [
+            (&raw, Format::Raw, &[0, 1, 2, 255], "DEMO-000102ff\n"),
+            (
+                &raw,
+                Format::Jsonl,
+                &[0, 1, 2, 255],
+                "{\"index\":3,\"password\":\"DEMO-000102ff\"}\n",
+            ),
+            (&text, Format::Raw, b"abcd", "DEMO-abcd\n"),
+            (&text, Format::Csv, b"abcd", "3,DEMO-abcd\n"),
+        ];
+        for (generator, format, record, expected) in cases {
+            let sink = Marked::new("DEMO-", format, generator);
+            assert_eq!(serialize(&sink, 3, record), expected);
+            assert_eq!(sink.record_len(3, record.len()), Some(expected.len()));
+        }
+    }
+
+    #[test]
+    fn csv_and_jsonl_quote_special_characters() {
+        let text = TextField {
+            binary: false,
+            may_need_escaping: true,
+        };
+        assert_eq!(
+            serialize(&Csv(text, Fields::default()), 7, b"a,\"b"),
+            "7,\"a,\"\"b\"\n"
+        );
+        assert_eq!(
+            serialize(&Csv(text, Fields::default()), 7, b"plain"),
+            "7,plain\n"
+        );
+        assert_eq!(
+            serialize(&Jsonl(text, Fields::default()), 12, br#"a"b\c"#),
+            "{\"index\":12,\"password\":\"a\\\"b\\\\c\"}\n"
+        );
+        let binary = TextField {
+            binary: true,
+            may_need_escaping: false,
+        };
+        assert_eq!(
+            serialize(&Csv(binary, Fields::default()), 0, &[0xab, 0x01]),
+            "0,ab01\n"
+        );
+    }
+
+    #[test]
+    fn serialized_len_matches_output() {
+        let raw = PasswordGenerator::builder()
+            .key([1; 16])
+            .count(1234)
+            .chunk_size(100);
+        let hex = raw.clone().encoder(Encoder::new(&Charset::Hex, 9));
+        let spread = TimestampSpread::parse("2024-01-01..2024-06-30").unwrap();
+        for builder in [raw, hex] {
+            let g = builder.build().unwrap();
+            let sinks = [Format::Hex, Format::Base64, Format::Csv, Format::Jsonl]
+                .map(|format| format.sink(&g).unwrap());
+            let ids = RecordIds::parse("uuidv5:dns").ok();
+            let fielded = [Format::Csv, Format::Jsonl].map(|format| {
+                [
+                    format.timestamped_sink(&g, &spread).unwrap(),
+                    format.sink_with_fields(&g, Some(&spread), ids).unwrap(),
+                    format.sink_with_fields(&g, None, ids).unwrap(),
+                ]
+            });
+            for sink in sinks.into_iter().chain(fielded.into_iter().flatten()) {
+                let mut out = Vec::new();
+                g.write_formatted(sink.as_ref(), &mut out, |_, _| ())
+                    .unwrap();
+                assert_eq!(serialized_len(sink.as_ref(), &g), Some(out.len() as u128));
+            }
+        }
+    }
+}
@@ -0,0 +1,1728 @@
+//! The main run: records generated under one key into memory, a file or
+//! stdout, with what the run did reported as it goes.
+//!
+//! [`run`] takes the options through each step in turn: checking them,
+//! surveying the machine, choosing a cipher and loading the key, building
+//! and describing the generator, writing the output (see
+//! [`output`](crate::output)) and reporting rates and memory.
+
+use std::error::Error;
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use clap::CommandFactory;
+#[cfg(feature = "hash")]
+use mass_password_gen_optimized::hashcat::AnswerKey;
+use mass_password_gen_optimized::{
+    aes_hardware_available, analysis, cpu_features, double_check, find_duplicates, format,
+    key_fingerprint,
+    layout::{PaddedSink, RawLayout},
+    pairs::{PairFormat, UsernamePairs},
+    random_key, segment, shm,
+    unique::WithoutRecords,
+    AdaptiveBuffer, Cipher, Compressor, CpuLimit, CpuReport, DoubleCheck, DoubleCheckReport,
+    Format, HostInfo, IvStrategy, Key, OutputSink, PasswordGenerator, Progress, ReferenceWriter,
+    SegmentPlan, WriteLimit,
+};
+use sysinfo::{CpuExt, Pid, ProcessExt, ProcessRefreshKind, System, SystemExt};
+
+use crate::batch::StatusFile;
+use crate::checkpoint::{self, Checkpointing};
+use crate::events::Events;
+use crate::hooks::Hook;
+use crate::notify::Notifier;
+use crate::output::{self, Target};
+use crate::{
+    commands, failure, keyprovider, perf, permissions, preview, progress, published_key, sizing,
+    storage, throughput, usage, warnings, Args, CipherChoice, Mode, PairOutput, Switch,
+    UniqueCheck, BENCHMARK_KEY, CIPHER_PROBE, GIB, STATUS_TO_STDERR,
+};
+
+/// How long --auto-backend spends timing all the backends.
+const CALIBRATION: Duration = Duration::from_millis(150);
+
+/// Published key used by `--demo`.
+const DEMO_KEY: [u8; 16] = *b"DEMO-DEMO-DEMO-!";
+
+/// Most records a `--demo` run generates.
+const DEMO_MAX_COUNT: usize = 1_000_000;
+
+/// Marker in front of every `--demo` password.
+const DEMO_MARK: &str = "DEMO-";
+
+/// Worker threads --pin-cores could not pin.
+static PIN_FAILURES: AtomicUsize = AtomicUsize::new(0);
+
+/// Runs the main command: generates `args.records.count` records.
+pub fn run(mut args: Args) -> Result<(), Box<dyn Error>> {
+    let segment = shm_output(&mut args)?;
+    let resume = resume(&mut args);
+    let to_stdout = check_options(&mut args);
+    if let (Some(total), Some(number)) = (args.total, args.segment) {
+        args.records.count = segment_count(total, number, args.records.count)?;
+    }
+    if args.perf_mode {
+        tune_chunks(&mut args);
+    }
+    if args.output.is_none() && !args.local_buffers {
+        sizing::plan_memory(&mut args)?;
+    }
+    if !args.insecure_output {
+        // shm_output made the segment's partial file safe in /dev/shm
+        check_directories(&args, to_stdout || segment.is_some())?;
+    }
+    let storage = match args.tune_storage {
+        true => Some(tune_storage(&mut args)?),
+        false => None,
+    };
+    let num_passwords = args.records.count;
+    let chunk_size = args.chunk_size;
+    if let Some(shard_size) = args.shard_size {
+        sizing::check_shard_size(shard_size, chunk_size)?;
+    }
+    // Found missing now, not once the run is over
+    if let Some(signer) = &args.sign_with {
+        signer.check()?;
+    }
+    if let (Some(recipients), Some(shard_size)) = (&args.encrypt_records, args.shard_size) {
+        recipients.assign(num_passwords, shard_size)?;
+        for recipient in recipients.recipients() {
+            recipient.check()?;
+        }
+    }
+    if let Some(custodians) = &args.seal {
+        custodians.check()?;
+    }
+
+    #[cfg(feature = "fault-injection")]
+    if let Some(plan) = args.fault_inject.clone() {
+        status!("FAULT INJECTION: {}", plan);
+        mass_password_gen_optimized::fault::install(plan);
+    }
+
+    let compressor = compressor(&args);
+    start_workers(&args)?;
+    let mut host = Host::survey(&args);
+    let pinned = args.cipher.and_then(CipherChoice::pinned);
+    let cipher = choose_cipher(&args, pinned);
+    let (key, key_source) = load_key(&args, resume.as_ref(), cipher)?;
+    // An unpinned AES run follows the key it was handed; a pinned cipher
+    // that cannot take it is refused when the generator is built
+    let cipher = match pinned {
+        None if !cipher.accepts(&key) => {
+            let cipher = cipher.for_key(&key);
+            status!("Cipher: {} (for the {}-bit key)", cipher, key.bits());
+            cipher
+        }
+        _ => cipher,
+    };
+    let iv_strategy = resume
+        .as_ref()
+        .map_or(IvStrategy::default(), |c| c.iv_strategy);
+    let generator = build(&args, key, cipher, iv_strategy)?;
+    check_warnings(&args, &generator, key_source, &host, to_stdout)?;
+    let layout = record_layout(&args, &generator)?;
+
+    // The sidecar and where the run stands, when checkpointing
+    let mut checkpointing = match (&args.resume, &args.checkpoint, resume) {
+        (Some(sidecar), _, Some(ckpt)) => Some(Checkpointing::new(sidecar.clone(), ckpt, true)),
+        (_, Some(sidecar), _) => {
+            let ckpt = checkpoint::Checkpoint {
+                key,
+                cipher,
+                iv_strategy: generator.iv_strategy(),
+                chunk_size,
+                count: num_passwords,
+                records: args.records.shape(),
+                format: args.records.format.to_string(),
+                watermark: args.watermark.is_some(),
+                output: args.output.clone().unwrap_or_default(),
+                shard_size: args.shard_size,
+                progress: Progress::default(),
+            };
+            Some(Checkpointing::new(sidecar.clone(), ckpt, false))
+        }
+        _ => None,
+    };
+    let resumed_records = match &checkpointing {
+        Some(checkpointing) => checkpointing.start()?,
+        None => 0,
+    };
+
+    describe(&args, &generator);
+    assess(&args, &generator)?;
+    if let (Some(check), Some(path), Some(shard_size)) =
+        (args.if_complete, &args.output, args.shard_size)
+    {
+        if output::already_complete(&args, &generator, layout.as_ref(), path, shard_size, check)? {
+            return Ok(());
+        }
+    }
+    if let (Some(spread), false) = (args.timestamp_spread, args.demo) {
+        let spread = spread.with_distribution(args.timestamp_distribution);
+        status!("Timestamps: {}, one per record, from the key\n", spread);
+    }
+    if let Some(ids) = args.with_id {
+        status!("Ids: {}, the UUIDv5 of each record's index\n", ids);
+    }
+    let (sink, removed) = output_sink(&args, &generator, &key)?;
+    let sink: Option<Arc<dyn OutputSink>> = sink.map(Arc::from);
+    #[cfg(feature = "hash")]
+    let answers = answer_key(&args)?;
+    #[cfg(feature = "hash")]
+    let sink = match &answers {
+        Some(answers) => {
+            let lines = args
+                .records
+                .hashcat_sink(&generator, Some(Arc::clone(answers)))?;
+            Some(Arc::new(lines) as Arc<dyn OutputSink>)
+        }
+        None => sink,
+    };
+    if args.mmap && sink.is_some() {
+        return Err(
+            "--mmap writes raw records in place; formatted, marked or filtered \
+                    output has no fixed offsets"
+                .into(),
+        );
+    }
+    let reference = match &args.reference_output {
+        Some(path) => Some(Arc::new(open_reference(
+            path,
+            args.reference_key.as_ref(),
+            &generator,
+            sink.clone(),
+            args.output_mode,
+        )?)),
+        None => None,
+    };
+    if reference.is_some() && args.verify_unique == Some(UniqueCheck::Remove) {
+        return Err("the reference would list the records --verify-unique remove drops".into());
+    }
+    // From here on, every chunk generated also writes its reference rows
+    let (generator, untapped) = match &reference {
+        Some(reference) => (
+            generator.with_reference(Arc::clone(reference)),
+            Some(generator),
+        ),
+        None => (generator, None),
+    };
+    let write_limit = args.write_limit.map(|rate| Arc::new(WriteLimit::new(rate)));
+    let generator = match &write_limit {
+        Some(limit) => generator.with_write_limit(Arc::clone(limit)),
+        None => generator,
+    };
+    let cpu_limit = args
+        .cpu_limit
+        .map(|share| CpuLimit::start(share, host.cpu_count));
+    let generator = match &cpu_limit {
+        Some(limit) => generator.with_cpu_limit(Arc::clone(limit)),
+        None => generator,
+    };
+    let double_check = args.double_check.map(DoubleCheck::new).transpose()?;
+    let generator = match &double_check {
+        Some(check) => generator.with_double_check(Arc::clone(check)),
+        None => generator,
+    };
+    let approx_bytes = sizing::approx_output_len(sink.as_deref(), &generator) as f64;
+    let mut passwords = allocate(&args, &generator, sink.is_some(), approx_bytes, to_stdout);
+
+    let clock = throughput::Clock::start();
+    let notifier = Notifier::from_env();
+    let meter = (args.progress.is_some() || args.run_dir.is_some() || notifier.is_some())
+        .then(|| Arc::new(progress::Meter::new(num_passwords, resumed_records)));
+    let reporter = args.progress.zip(meter.clone()).map(|(style, meter)| {
+        let mut sys = System::new();
+        let pid = host.pid;
+        let rss = move || pid.and_then(|pid| process_rss(&mut sys, pid));
+        // Only demo records are safe to put on screen
+        let preview = (style == progress::Style::Panel).then(|| progress::Preview {
+            title: format!(
+                "DEMO on {} ({:.0} GiB): published key, nothing here is secret",
+                host.brand, host.total_mem_gib
+            ),
+            records: preview::demo(untapped.as_ref().unwrap_or(&generator), sink.clone()),
+        });
+        progress::Reporter::start(meter, style, rss, preview)
+    });
+    let status_file = match (&args.run_dir, &meter) {
+        (Some(dir), Some(meter)) => Some(StatusFile::start(
+            dir,
+            Arc::clone(meter),
+            args.output.clone(),
+            checkpointing.as_ref().map(|c| c.sidecar.clone()),
+        )?),
+        _ => None,
+    };
+    let service = notifier.zip(meter.clone()).map(|(notifier, meter)| {
+        let mut last = meter.records();
+        let starting = format!("Generating {} records", num_passwords);
+        notifier.ready(starting, move || {
+            let records = meter.records();
+            let moved = records != last;
+            last = records;
+            let share = records as f64 * 100.0 / meter.total().max(1) as f64;
+            let status = format!("{} of {} records ({:.1}%)", records, meter.total(), share);
+            (status, moved)
+        })
+    });
+
+    // Each chunk reports when it was made and how many passwords it held, so
+    // we can separate the cold start from steady-state throughput.
+    let width = generator.record_width();
+    let on_chunk = |chunk_idx: usize, len| {
+        if let Some(meter) = &meter {
+            let first = chunk_idx * chunk_size;
+            // Exact unless records vary in length; then an upper bound
+            let bytes = sink
+                .as_deref()
+                .and_then(|sink| format::serialized_range_len(sink, &generator, first..first + len))
+                .unwrap_or((len * width) as u128);
+            meter.chunk_done(len, bytes as u64);
+        }
+        clock.finished(len)
+    };
+    let buffer = storage
+        .as_ref()
+        .map_or_else(AdaptiveBuffer::new, storage::Probe::buffer);
+    let events = open_events(&args)?;
+    // Nothing of a --demo run identifies the machine
+    let host_info = (!args.no_host_info && !args.demo).then(|| host.info());
+    let hook = args
+        .on_shard_complete
+        .clone()
+        .map(|command| Hook::new(command, args.hook_jobs, args.hook_retries));
+    let target = Target {
+        generator: &generator,
+        sink: sink.as_deref(),
+        buffer: &buffer,
+        compressor: compressor.as_ref(),
+        to_stdout,
+        events: events.as_ref(),
+        hook: hook.as_ref(),
+        signer: args.sign_with.as_ref(),
+        recipients: args.encrypt_records.as_ref(),
+        seal: args.seal.as_ref(),
+        host: host_info.as_ref(),
+        layout: layout.as_ref(),
+    };
+    let written = output::write(
+        &args,
+        &target,
+        checkpointing.as_mut(),
+        &mut passwords,
+        on_chunk,
+    );
+
+    let duration = clock.elapsed();
+    if let Some(reporter) = reporter {
+        reporter.finish();
+    }
+
+    if args.sink_dry_run {
+        return output::finish_dry_run(
+            args.output.as_deref(),
+            to_stdout,
+            written.map(|(chunks, _)| chunks),
+            duration,
+            approx_bytes,
+        );
+    }
+    let (chunk_done, outcome) = written.map_err(failure::Failure::sink)?;
+    if let (Some(segment), Some(partial)) = (&segment, &args.output) {
+        std::fs::rename(partial, segment)
+            .map_err(|e| format!("cannot put {} in place: {}", segment.display(), e))?;
+        status!("Shared-memory segment ready at {}", segment.display());
+    }
+    let num_generated = num_passwords - resumed_records;
+    let num_written = num_generated - removed;
+    if let Some(checkpointing) = checkpointing {
+        checkpointing.finish()?;
+    }
+    if let (Some(reference), Some(path)) = (&reference, &args.reference_output) {
+        reference
+            .finish()
+            .map_err(|e| format!("cannot write the reference {}: {}", path.display(), e))?;
+        status!("Reference written to {}", path.display());
+    }
+    #[cfg(feature = "hash")]
+    if let (Some(answers), Some(path)) = (&answers, &args.answer_key) {
+        answers
+            .finish(num_passwords)
+            .map_err(|e| format!("cannot write the answer key {}: {}", path.display(), e))?;
+        status!("Answer key written to {}", path.display());
+    }
+    output::report(&outcome, &target, args.shard_size);
+    let writes = write_limit.map(|limit| limit.report());
+    if let Some(writes) = &writes {
+        output::report_writes(writes);
+    }
+    let cpu = cpu_limit.map(|limit| limit.report());
+    if let Some(cpu) = &cpu {
+        report_cpu(cpu);
+    }
+    let double_checked = double_check.map(|check| check.report());
+    if let Some(checked) = &double_checked {
+        report_double_check(checked);
+    }
+
+    if let Some(tenant) = &args.tenant {
+        let ledger = args
+            .ledger
+            .clone()
+            .unwrap_or_else(usage::default_ledger_path);
+        usage::record(
+            &ledger,
+            tenant,
+            num_written as u64,
+            &args.records.encoding(),
+        )?;
+    }
+
+    let rates = Rates::measure(
+        &generator,
+        num_generated,
+        removed,
+        duration,
+        args.warmup,
+        &chunk_done,
+    );
+    let memory = host.memory_after(args.verbose);
+    if let Some(path) = &args.summary_json {
+        let summary = serde_json::json!({
+            "records": num_written,
+            "duplicates_removed": removed,
+            "encoding": args.records.encoding(),
+            "format": args.records.format.to_string(),
+            "cipher": cipher.to_string(),
+            "chunk_size": chunk_size,
+            "key_fingerprint": key_fingerprint(&key),
+            "output": segment.as_ref().or(args.output.as_ref()),
+            "seconds": duration.as_secs_f64(),
+            "records_per_sec": rates.overall,
+            "warmup_seconds": args.warmup.as_secs_f64(),
+            "cold_records_per_sec": rates.split.cold,
+            "steady_records_per_sec": rates.split.steady,
+            "peak_records_per_sec": rates.split.peak,
+            "memory": memory,
+            "writes": writes,
+            "cpu": cpu,
+            "double_check": double_checked,
+            "run_id": match &outcome {
+                output::Outcome::Sharded(sharding) => sharding.manifest.run_id.as_deref(),
+                _ => None,
+            },
+            "stripes": match &outcome {
+                output::Outcome::Sharded(sharding) if !sharding.stripes.is_empty() => {
+                    Some(&sharding.stripes)
+                }
+                _ => None,
+            },
+        });
+        let json = serde_json::to_string_pretty(&summary)?;
+        match path.as_path() == Path::new("-") {
+            true => println!("{}", json),
+            false => {
+                permissions::create(path, args.output_mode)?.write_all((json + "\n").as_bytes())?
+            }
+        }
+    }
+
+    if let Some(checked) = double_checked.filter(|checked| !checked.unresolved.is_empty()) {
+        return Err(failure::Failure::Verification(format!(
+            "no two of {} copies of chunks {} agreed, so the output holds them as first made and \
+             cannot be trusted",
+            double_check::MAX_COPIES,
+            chunk_list(&checked.unresolved)
+        ))
+        .into());
+    }
+
+    let sample = args.preview.unwrap_or(if args.demo { 5 } else { 0 });
+    let generator = untapped.as_ref().unwrap_or(&generator);
+    preview::sample(generator, sink.as_deref(), sample.min(num_passwords));
+    if let (Some(hook), output::Outcome::Sharded(sharding)) = (&hook, &outcome) {
+        hook.check(&sharding.path, args.output_mode)
+            .map_err(failure::Failure::Other)?;
+        if let Some(read_back) = &args.verify_remote {
+            commands::verify_remote(&sharding.path, read_back, args.remove_local)?;
+        }
+    }
+    if let Some(status_file) = status_file {
+        status_file.finish()?;
+    }
+    if let Some(service) = service {
+        service.stop(format!(
+            "Generated {} records in {:.1}s",
+            num_written,
+            duration.as_secs_f64()
+        ));
+    }
+    Ok(())
+}
+
+/// Exits with the usage error `message`, for options that conflict.
+fn usage_error(message: impl std::fmt::Display) -> ! {
+    Args::command()
+        .error(clap::error::ErrorKind::ArgumentConflict, message)
+        .exit()
+}
+
+/// Loads the checkpoint `--resume` names, checks that the options match
+/// the run it holds, and takes the run's output, chunk size, cipher and
+/// shard size from it.
+fn resume(args: &mut Args) -> Option<checkpoint::Checkpoint> {
+    let path = args.resume.as_deref()?;
+    let ckpt = checkpoint::Checkpoint::load(path).unwrap_or_else(|e| {
+        Args::command()
+            .error(
+                clap::error::ErrorKind::Io,
+                format!("cannot resume from {}: {}", path.display(), e),
+            )
+            .exit()
+    });
+    let mut mismatch = None;
+    if ckpt.count != args.records.count {
+        mismatch = Some(format!("--count {}", ckpt.count));
+    } else if ckpt.records != args.records.shape() {
+        mismatch = Some(format!("record options `{}`", ckpt.records));
+    } else if ckpt.format != args.records.format.to_string() {
+        mismatch = Some(format!("--format {}", ckpt.format));
+    } else if ckpt.watermark != args.watermark.is_some() {
+        mismatch = Some(format!("watermark: {}", ckpt.watermark));
+    } else if args.shard_size.is_some_and(|n| ckpt.shard_size != Some(n)) {
+        mismatch = Some(match ckpt.shard_size {
+            Some(n) => format!("--shard-size {}", n),
+            None => "no --shard-size".to_string(),
+        });
+    }
+    if let Some(expected) = mismatch {
+        usage_error(format!(
+            "the checkpointed run used {}; pass the same options to resume it",
+            expected
+        ));
+    }
+    args.output.get_or_insert_with(|| ckpt.output.clone());
+    args.chunk_size = ckpt.chunk_size;
+    args.shard_size = ckpt.shard_size;
+    args.cipher = Some(match ckpt.cipher {
+        Cipher::Aes128 => CipherChoice::Aes128,
+        Cipher::Aes256 => CipherChoice::Aes256,
+        Cipher::ChaCha20 => CipherChoice::Chacha20,
+    });
+    Some(ckpt)
+}
+
+/// `--tune-storage`: probes the output's filesystem and, with
+/// `--auto-shards`, shards the run for the writers it takes best.
+fn tune_storage(args: &mut Args) -> Result<storage::Probe, String> {
+    let output = args.output.as_deref().unwrap_or(Path::new(""));
+    let dir = match output.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let threads = args.threads.unwrap_or_else(rayon::current_num_threads);
+    let probe = storage::probe(dir, threads)
+        .map_err(|e| format!("cannot probe {}: {}", dir.display(), e))?;
+    probe.show();
+    if args.auto_shards {
+        match probe.shard_size(args.records.count, args.chunk_size) {
+            Some(size) => {
+                status!(
+                    "Sharding for {} parallel writers: --shard-size {}",
+                    probe.best_writers,
+                    size
+                );
+                args.shard_size = Some(size);
+            }
+            None => status!("Not sharding: one file writes as fast as several"),
+        }
+    }
+    status!();
+    Ok(probe)
+}
+
+/// Where `--events` or `--events-fd` send shard events, if anywhere.
+fn open_events(args: &Args) -> io::Result<Option<Events>> {
+    if let Some(path) = &args.events {
+        return Events::create(path, args.output_mode).map(Some);
+    }
+    match args.events_fd {
+        #[cfg(unix)]
+        Some(fd) => Events::to_fd(fd).map(Some),
+        #[cfg(not(unix))]
+        Some(_) => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "--events-fd needs a Unix system; use --events",
+        )),
+        None => Ok(None),
+    }
+}
+
+/// Refuses to write the output, or a file beside it, into a directory
+/// every user can write to.
+fn check_directories(args: &Args, to_stdout: bool) -> Result<(), String> {
+    let output = args.output.as_ref().filter(|_| !to_stdout);
+    let files = [
+        output,
+        args.fallback.as_ref().filter(|_| args.shard_size.is_none()),
+        args.checkpoint.as_ref(),
+        args.zstd_dict.as_ref(),
+        args.reference_output.as_ref(),
+        args.events.as_ref(),
+    ];
+    files
+        .into_iter()
+        .flatten()
+        .try_for_each(|path| permissions::check_directory(path))?;
+    // The shards of --output-dirs, and those --fallback takes in when
+    // sharding, go straight into each directory
+    let name = output.and_then(|path| path.file_name());
+    let name = name.unwrap_or(std::ffi::OsStr::new("shards"));
+    let spill = args.fallback.as_ref().filter(|_| args.shard_size.is_some());
+    args.output_dirs
+        .iter()
+        .chain(spill)
+        .try_for_each(|dir| permissions::check_directory(&dir.join(name)))
+}
+
+/// `--output shm://NAME`: the segment the run goes to, once it is written
+/// to a partial file beside it, which becomes the output. Segments hold
+/// raw records after a header.
+fn shm_output(args: &mut Args) -> Result<Option<PathBuf>, String> {
+    let name = args.output.as_deref().and_then(Path::to_str);
+    let Some(name) = name.and_then(|output| output.strip_prefix("shm://")) else {
+        return Ok(None);
+    };
+    let (segment, partial) = (shm::path(name)?, shm::partial_path(name)?);
+    let one_segment = [
+        (args.shard_size.is_some(), "--shard-size"),
+        (args.archive_format.is_some(), "--archive-format"),
+        (args.compress.is_some(), "--compress"),
+        (args.fallback.is_some(), "--fallback"),
+        (args.checkpoint.is_some(), "--checkpoint"),
+        (args.resume.is_some(), "--resume"),
+        (args.mmap, "--mmap"),
+        (args.schema.is_some(), "--schema"),
+        (args.demo, "--demo"),
+        (args.timestamp_spread.is_some(), "--timestamp-spread"),
+        (args.with_id.is_some(), "--with-id"),
+    ];
+    if let Some((_, flag)) = one_segment.iter().find(|(set, _)| *set) {
+        usage_error(format!(
+            "shm:// output is one segment of raw records behind a header; it takes no {}",
+            flag
+        ));
+    }
+    if args.records.encoding() != "raw" || args.records.format != Format::Raw {
+        usage_error(
+            "shm:// output holds raw 16-byte records; leave out --format, --charset, \
+             --template and --mode",
+        );
+    }
+    // Every user can write to /dev/shm, but its sticky bit keeps them from
+    // replacing a file of ours there: made here, afresh, the partial file
+    // stays the run's
+    match std::fs::remove_file(&partial) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => {
+            return Err(format!("{}: {}", partial.display(), e));
+        }
+        _ => {}
+    }
+    permissions::open(
+        std::fs::File::options().write(true).create_new(true),
+        &partial,
+        args.output_mode,
+    )
+    .map_err(|e| format!("{}: {}", partial.display(), e))?;
+    args.output = Some(partial);
+    args.header = Switch::On;
+    Ok(Some(segment))
+}
+
+/// Refuses combinations of options no run can honour, and applies what
+/// `--demo` implies. Returns whether the records go to stdout.
+fn check_options(args: &mut Args) -> bool {
+    let to_stdout = args.output.as_deref() == Some(Path::new("-"));
+    let summary_to_stdout = args.summary_json.as_deref() == Some(Path::new("-"));
+    STATUS_TO_STDERR.store(to_stdout || summary_to_stdout, Ordering::Relaxed);
+    let needs_file = [
+        (summary_to_stdout, "--summary-json -"),
+        (args.fallback.is_some(), "--fallback"),
+        (args.checkpoint.is_some(), "--checkpoint"),
+        (args.resume.is_some(), "--resume"),
+        (args.archive_format.is_some(), "--archive-format"),
+        (args.mmap, "--mmap"),
+        (args.shard_size.is_some(), "--shard-size"),
+        (args.tune_storage, "--tune-storage"),
+    ];
+    if let Some((_, flag)) = needs_file.iter().find(|(set, _)| to_stdout && *set) {
+        usage_error(format!("{} needs --output to be a file, not stdout", flag));
+    }
+
+    if args.archive_format.is_some() && args.verify_unique == Some(UniqueCheck::Remove) {
+        usage_error(
+            "an archive holds every record of the run, so it cannot be combined with \
+             --verify-unique remove",
+        );
+    }
+
+    if args.shard_size.is_some() && args.verify_unique == Some(UniqueCheck::Remove) {
+        usage_error(
+            "the manifest counts every record of each shard, so --shard-size cannot be \
+             combined with --verify-unique remove",
+        );
+    }
+
+    if args.records.format == Format::ExpiringToken {
+        let problem = if !args.deterministic {
+            Some(
+                "tokens can only be validated with the key they were minted under; pass \
+                  --deterministic with --key or --key-hex",
+            )
+        } else if args.checkpoint.is_some() || args.resume.is_some() {
+            Some("a resumed run would mint its tokens with a new expiry")
+        } else {
+            None
+        };
+        if let Some(problem) = problem {
+            usage_error(format!("--format expiring-token: {}", problem));
+        }
+    }
+
+    if args.records.format == Format::Hashcat
+        && (args.shard_size.is_some() || args.archive_format.is_some())
+    {
+        usage_error(
+            "a manifest is verified by reading its records back, which hashcat lines cannot \
+             be; write them without --shard-size or --archive-format",
+        );
+    }
+    #[cfg(feature = "hash")]
+    if args.answer_key.is_some() && args.records.format != Format::Hashcat {
+        usage_error("--answer-key holds the answers to --format hashcat lines");
+    }
+
+    if args.progress == Some(progress::Style::Panel) && !args.demo {
+        usage_error("--progress panel puts records on screen, which only --demo's are safe for");
+    }
+    if args.demo {
+        if matches!(
+            args.records.format,
+            Format::Hex | Format::Base64 | Format::ExpiringToken | Format::Hashcat
+        ) {
+            usage_error(format!(
+                "--demo marks passwords in plain text, which --format {} would hide",
+                args.records.format
+            ));
+        }
+        status!(
+            "DEMO MODE: published key, every password starts with `{}`; nothing here is secret",
+            DEMO_MARK
+        );
+        if args.progress.is_none() && io::stderr().is_terminal() {
+            args.progress = Some(progress::Style::Panel);
+        }
+        if args.records.count > DEMO_MAX_COUNT {
+            status!(
+                "DEMO MODE: --count capped at {} (asked for {})",
+                DEMO_MAX_COUNT,
+                args.records.count
+            );
+            args.records.count = DEMO_MAX_COUNT;
+        }
+    }
+    to_stdout
+}
+
+/// The records of segment `number` of a job of `total`, in segments of
+/// `count`.
+fn segment_count(total: u128, number: u128, count: usize) -> Result<usize, Box<dyn Error>> {
+    let plan = SegmentPlan::new(total, count as u64)?;
+    let segment = plan.segment(number).ok_or_else(|| {
+        format!(
+            "--segment {} is past the last segment of the job, {}",
+            number,
+            plan.segments() - 1
+        )
+    })?;
+    status!(
+        "Segment {} of {}: job records {}..{}",
+        number,
+        plan.segments(),
+        segment.first,
+        segment.first + segment.count as u128
+    );
+    Ok(segment.count as usize)
+}
+
+/// `--perf-mode`: sizes chunks to the caches from what one record costs,
+/// measured on a throwaway run.
+fn tune_chunks(args: &mut Args) {
+    let probe = args
+        .records
+        .configure(PasswordGenerator::builder().key([0; 16]).chunk_size(1024))
+        .and_then(|b| b.build().map_err(|e| e.to_string()));
+    let Ok(probe) = probe else {
+        return;
+    };
+    let caches = perf::Caches::detect();
+    let per_record = probe.keystream_len(0) / probe.chunk_len(0) + probe.record_width();
+    args.chunk_size = caches.chunk_size(per_record);
+    let kib =
+        |size: Option<usize>| size.map_or("unknown".to_string(), |s| format!("{} KiB", s >> 10));
+    status!(
+        "Performance mode: L2 {}, L3 {} => chunks of {} records ({} bytes of keystream \
+         and output each); pass --chunk-size {} to reproduce this output",
+        kib(caches.l2),
+        kib(caches.l3),
+        args.chunk_size,
+        per_record,
+        args.chunk_size
+    );
+}
+
+/// The `--compress` codec at its level, checked against the codec's range.
+fn compressor(args: &Args) -> Option<Compressor> {
+    let codec = args.compress?;
+    let level = args.compress_level.unwrap_or(codec.default_level());
+    if !codec.levels().contains(&level) {
+        Args::command()
+            .error(
+                clap::error::ErrorKind::ValueValidation,
+                format!(
+                    "{} levels run from {} to {}, not {}",
+                    codec,
+                    codec.levels().start(),
+                    codec.levels().end(),
+                    level
+                ),
+            )
+            .exit();
+    }
+    Some(Compressor::new(codec, level))
+}
+
+/// Sizes the worker pool for --threads (or --perf-mode) and pins it for
+/// --pin-cores.
+fn start_workers(args: &Args) -> Result<(), Box<dyn Error>> {
+    let threads = args.threads.or_else(|| {
+        args.perf_mode
+            .then(|| System::new().physical_core_count())
+            .flatten()
+    });
+    if threads.is_none() && !args.pin_cores {
+        return Ok(());
+    }
+    let mut pool = rayon::ThreadPoolBuilder::new();
+    if let Some(threads) = threads {
+        pool = pool.num_threads(threads);
+    }
+    if args.pin_cores {
+        pool = pool.start_handler(|index| {
+            if !perf::pin(index) {
+                PIN_FAILURES.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+    }
+    pool.build_global()?;
+    Ok(())
+}
+
+/// The machine the run is on, as the run reports it.
+struct Host {
+    sys: System,
+    pid: Option<Pid>,
+    cpu_count: usize,
+    brand: String,
+    total_mem_gib: f64,
+    aes_hw: bool,
+}
+
+impl Host {
+    /// Gathers the system details and prints them; under `--demo`, nothing
+    /// that identifies the machine.
+    fn survey(args: &Args) -> Self {
+        let mut sys = System::new_all();
+        sys.refresh_all();
+        let pid = sysinfo::get_current_pid().ok();
+
+        // CPU details
+        let cpu_count = sys.cpus().len();
+        // On many systems, sysinfo lumps hyperthreads into the CPU count.
+        let mut brand = if cpu_count > 0 {
+            sys.cpus()[0].brand().to_string()
+        } else {
+            "Unknown CPU".into()
+        };
+
+        // sysinfo reports memory in bytes
+        let mut total_mem_gib = sys.total_memory() as f64 / GIB;
+        let used_mem_gib = sys.used_memory() as f64 / GIB;
+
+        // OS name/version if available
+        let mut os_name = sys.name().unwrap_or_else(|| "Unknown OS".to_string());
+        let mut os_version = sys.os_version().unwrap_or_else(|| "Unknown".to_string());
+        let mut kernel_version = sys
+            .kernel_version()
+            .unwrap_or_else(|| "Unknown".to_string());
+        if args.demo {
+            // Nothing on screen should identify the machine
+            os_name = "Demo OS".to_string();
+            os_version = "1.0".to_string();
+            kernel_version = "demo".to_string();
+            brand = "Demo CPU".to_string();
+            total_mem_gib = 16.0;
+        }
+
+        status!("=== System Information ===");
+        status!(
+            "OS: {} (version: {}), kernel: {}",
+            os_name,
+            os_version,
+            kernel_version
+        );
+        status!("CPU Count: {}", cpu_count);
+        status!("CPU Brand: {}", brand);
+        status!("Total Memory: {:.2} GiB", total_mem_gib);
+        if args.verbose {
+            // System-wide figures include every other process, so they are noisy.
+            status!("Used Memory:  {:.2} GiB (system-wide)", used_mem_gib);
+        }
+        status!(
+            "Worker Threads: {}{}",
+            rayon::current_num_threads(),
+            match PIN_FAILURES.load(Ordering::Relaxed) {
+                _ if !args.pin_cores => "",
+                0 => " (each pinned to a core)",
+                _ => " (could not pin them all to cores)",
+            }
+        );
+        let aes_hw = aes_hardware_available();
+        status!(
+            "AES hardware acceleration: {}",
+            if aes_hw { "yes" } else { "no" }
+        );
+        status!("==========================\n");
+        Host {
+            sys,
+            pid,
+            cpu_count,
+            brand,
+            total_mem_gib,
+            aes_hw,
+        }
+    }
+
+    /// What manifests record of the machine and the build.
+    fn info(&self) -> HostInfo {
+        let unknown = || "Unknown".to_string();
+        HostInfo {
+            cpu: self.brand.clone(),
+            microcode: microcode(),
+            cpu_features: cpu_features().into_iter().map(String::from).collect(),
+            os: self.sys.long_os_version().unwrap_or_else(unknown),
+            kernel: self.sys.kernel_version().unwrap_or_else(unknown),
+            crate_version: env!("CARGO_PKG_VERSION").to_string(),
+            git_commit: option_env!("MPG_GIT_COMMIT").map(String::from),
+            rustc: option_env!("MPG_RUSTC").map(String::from),
+        }
+    }
+
+    /// Samples memory once the run is done and prints it, returning the
+    /// figures for `--summary-json`.
+    fn memory_after(&mut self, verbose: bool) -> serde_json::Value {
+        let rss_after = self.pid.and_then(|pid| process_rss(&mut self.sys, pid));
+        // Without a kernel high-water mark, any buffers are still live here, so
+        // the current RSS is the best peak estimate we have.
+        let rss_peak = peak_rss().or(rss_after);
+        status!("\n=== Memory Usage After ===");
+        match rss_after {
+            Some(rss) => status!("Process RSS:  {:.2} GiB", rss as f64 / GIB),
+            None => status!("Process RSS:  unavailable"),
+        }
+        if let Some(peak) = rss_peak {
+            status!("Peak RSS:     {:.2} GiB", peak as f64 / GIB);
+        }
+        let system_used = verbose.then(|| {
+            self.sys.refresh_memory();
+            self.sys.used_memory()
+        });
+        if let Some(used) = system_used {
+            status!("Used Memory:  {:.2} GiB (system-wide)", used as f64 / GIB);
+        }
+        status!();
+        serde_json::json!({
+            "rss_bytes": rss_after,
+            "peak_rss_bytes": rss_peak,
+            "system_used_bytes": system_used,
+        })
+    }
+}
+
+/// The CPU's microcode revision, where /proc/cpuinfo gives it.
+fn microcode() -> Option<String> {
+    let cpuinfo = std::fs::read_to_string("/proc/cpuinfo").ok()?;
+    cpuinfo.lines().find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        (name.trim() == "microcode").then(|| value.trim().to_string())
+    })
+}
+
+/// The cipher to run with: `pinned` by --cipher, the fastest by
+/// calibration or a short benchmark, or the default.
+fn choose_cipher(args: &Args, pinned: Option<Cipher>) -> Cipher {
+    let calibrated = args.auto_backend.then(|| {
+        let table = Cipher::calibrate(CALIBRATION);
+        status!(
+            "Backend calibration ({} ms, single-threaded):",
+            CALIBRATION.as_millis()
+        );
+        for (i, (cipher, rate)) in table.iter().enumerate() {
+            status!(
+                "  {:<10} {:>7.2} GiB/s{}",
+                cipher.to_string(),
+                rate / GIB,
+                if i == 0 { "  fastest" } else { "" }
+            );
+        }
+        table[0].0
+    });
+    match (pinned, calibrated) {
+        (Some(cipher), Some(_)) => {
+            status!("Cipher: {} (pinned with --cipher)\n", cipher);
+            cipher
+        }
+        (Some(cipher), None) => cipher,
+        (None, Some(fastest)) => {
+            status!(
+                "Cipher: {} (fastest here; pass --cipher {} to pin it)\n",
+                fastest,
+                fastest
+            );
+            fastest
+        }
+        (None, None) if args.cipher.is_none() => Cipher::default(),
+        (None, None) => {
+            let cipher = Cipher::fastest(CIPHER_PROBE);
+            status!(
+                "Cipher: {} (fastest in a {} ms benchmark; pass --cipher {} to pin it)",
+                cipher,
+                CIPHER_PROBE.as_millis(),
+                cipher
+            );
+            cipher
+        }
+    }
+}
+
+/// The run's key and where it came from: a fresh OS-random key per run
+/// unless reproducibility was asked for.
+fn load_key(
+    args: &Args,
+    resume: Option<&checkpoint::Checkpoint>,
+    cipher: Cipher,
+) -> Result<(Key, warnings::KeySource), Box<dyn Error>> {
+    if args.sink_dry_run {
+        // Filler never touches the key
+        status!("DRY RUN: writing non-secret filler; no passwords are generated");
+        let zero = Key::from_slice(&[0; 32][..cipher.key_len()])?;
+        return Ok((zero, warnings::KeySource::Filler));
+    }
+    let provider = key_provider(args, resume, cipher);
+    let key = provider.key().map_err(|e| {
+        failure::Failure::Key(format!(
+            "cannot load the key from {}: {}",
+            provider.describe(),
+            e
+        ))
+    })?;
+    // A segment runs under its own key; the checkpoint keeps that one
+    if let Some(number) = args.segment {
+        status!(
+            "Job key: {}, fingerprint {}",
+            provider.describe(),
+            key_fingerprint(&key)
+        );
+        let key = segment::segment_key(&key, number);
+        status!(
+            "Key: derived for segment {}, fingerprint {}",
+            number,
+            key_fingerprint(&key)
+        );
+        return Ok((key, provider.source()));
+    }
+    let note = match provider.source() {
+        warnings::KeySource::Random => "; not stored, so this output cannot be regenerated",
+        warnings::KeySource::Stored | warnings::KeySource::CommandLine if args.deterministic => {
+            "; output is reproducible by anyone holding the key"
+        }
+        _ => "",
+    };
+    status!(
+        "Key: {}, fingerprint {}{}",
+        provider.describe(),
+        key_fingerprint(&key),
+        note
+    );
+    Ok((key, provider.source()))
+}
+
+/// The run's generator; options no generator takes are a usage error.
+fn build(
+    args: &Args,
+    key: Key,
+    cipher: Cipher,
+    iv_strategy: IvStrategy,
+) -> Result<PasswordGenerator, Box<dyn Error>> {
+    let mut builder = args.records.configure(
+        PasswordGenerator::builder()
+            .key(key)
+            .cipher(cipher)
+            .iv_strategy(iv_strategy)
+            .chunk_size(args.chunk_size)
+            .filler(args.sink_dry_run),
+    )?;
+    if let Some(watermark) = &args.watermark {
+        builder = builder.watermark(watermark.clone());
+    }
+    if let Some(schema) = &args.schema {
+        let names: Vec<&str> = schema.fields().iter().map(|f| f.name()).collect();
+        status!(
+            "Schema: {} ({} fields, each from its own lane of the key)",
+            names.join(", "),
+            names.len()
+        );
+        builder = schema.configure(builder, &key);
+    }
+    Ok(builder.build().unwrap_or_else(|e| usage_error(e)))
+}
+
+/// Prints the warnings for this run; with --deny-warnings, any is an error.
+fn check_warnings(
+    args: &Args,
+    generator: &PasswordGenerator,
+    key: warnings::KeySource,
+    host: &Host,
+    to_stdout: bool,
+) -> Result<(), Box<dyn Error>> {
+    let warnings = warnings::evaluate(&warnings::Config {
+        generator,
+        key,
+        software_aes: generator.cipher() != Cipher::ChaCha20 && !host.aes_hw,
+        threads: rayon::current_num_threads(),
+        logical_cpus: host.cpu_count,
+        output: args.output.as_deref().filter(|_| !to_stdout),
+        output_mode: args.output_mode,
+        in_memory: args.output.is_none() && !args.local_buffers,
+        total_memory: host.sys.total_memory(),
+        unique_checked: args.verify_unique.is_some(),
+    });
+    for warning in &warnings {
+        status!("{}", warning);
+    }
+    if warnings.is_empty() {
+        return Ok(());
+    }
+    status!();
+    if args.deny_warnings {
+        return Err(format!(
+            "{} warning{} and --deny-warnings",
+            warnings.len(),
+            if warnings.len() == 1 { "" } else { "s" }
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Prints what the records are and how much entropy each carries.
+fn describe(args: &Args, generator: &PasswordGenerator) {
+    if let Some(pass) = generator.passphrase() {
+        status!(
+            "Passphrases: {} words of {} ({} words, sha256 {}...), {}-separated, {} case{} => \
+             {:.1} bits each",
+            pass.words(),
+            pass.wordlist().name(),
+            pass.wordlist().len(),
+            &pass.wordlist().sha256()[..16],
+            match pass.style().symbols {
+                Some(_) => "symbol".to_string(),
+                None => format!("`{}`", pass.separator()),
+            },
+            pass.capitalization(),
+            if pass.digits() > 0 {
+                format!(", plus a {}-digit number", pass.digits())
+            } else {
+                String::new()
+            },
+            pass.entropy_bits()
+        );
+        if !pass.style().is_plain() {
+            status!("Style: {}", pass.style());
+        }
+        status!(
+            "Up to {} bytes per passphrase; budget {} keystream bytes each\n",
+            generator.record_width(),
+            pass.budget()
+        );
+    }
+
+    let charset = match args.records.mode {
+        Mode::Pin => Some("PIN digits".to_string()),
+        _ => args.records.charset.as_ref().map(|c| c.to_string()),
+    };
+    if let (Some(charset), Some(enc)) = (charset, generator.encoder()) {
+        let (length, least) = match enc.lengths() {
+            Some(lengths) => (format!("lengths {}", lengths), "at least "),
+            None => (format!("length {}", enc.length()), ""),
+        };
+        status!(
+            "Charset: {} ({} symbols), {} => {}{:.1} bits per password",
+            charset,
+            enc.alphabet_size(),
+            length,
+            least,
+            enc.entropy_bits()
+        );
+        status!(
+            "Rejection sampling keeps {:.1}% of keystream bytes (unbiased); budget {} bytes per password\n",
+            enc.acceptance_rate() * 100.0,
+            enc.budget()
+        );
+    }
+    if let Some(enc) = generator.encoder() {
+        if let Some(template) = enc.template() {
+            status!(
+                "Template: {} ({} characters) => {:.1} bits per password",
+                template,
+                enc.length(),
+                enc.entropy_bits()
+            );
+            status!(
+                "Rejection sampling per position (unbiased); budget {} keystream bytes per password\n",
+                enc.budget()
+            );
+        }
+    }
+    if let (Some(policy), Some(acceptance)) = (generator.policy(), generator.policy_acceptance()) {
+        status!(
+            "Policy: {}\nAt least {:.1}% of candidates comply => at least {:.1} bits per password, \
+             ~{:.2}x the keystream\n",
+            policy,
+            acceptance * 100.0,
+            analysis::entropy_bits(generator),
+            1.0 / acceptance
+        );
+    }
+}
+
+/// `--strength` and `--audit-sample`: how long the records would take to
+/// guess, and whether a sample of them looks uniform. A failed audit stops
+/// the run before anything is written.
+fn assess(args: &Args, generator: &PasswordGenerator) -> Result<(), Box<dyn Error>> {
+    if args.strength || args.audit_sample.is_some() {
+        let bits = analysis::entropy_bits(generator);
+        status!(
+            "Strength: {:.1} bits per password; average time to guess one:",
+            bits
+        );
+        for (attack, rate) in analysis::GUESS_RATES {
+            status!(
+                "  {:<42} {}",
+                attack,
+                analysis::format_duration(analysis::crack_seconds(bits, rate))
+            );
+        }
+        status!();
+    }
+    let Some(sample) = args.audit_sample else {
+        return Ok(());
+    };
+    let started = Instant::now();
+    let audit = analysis::audit(generator, sample)?;
+    let (position, worst) = audit.worst_position();
+    status!(
+        "Audit of the first {} records: worst of {} positions is {}, chi-squared {:.1} on {} \
+         degrees of freedom against {} frequencies (p = {:.2e}); serial correlation {:.5} \
+         (p = {:.2e}); {} characters outside the alphabet; in {:.2?}",
+        audit.records,
+        audit.positions.len(),
+        position,
+        worst.chi_squared,
+        worst.degrees,
+        if audit.pooled { "pooled" } else { "uniform" },
+        worst.p_value,
+        audit.serial_correlation,
+        audit.serial_p_value,
+        audit.foreign,
+        started.elapsed()
+    );
+    if !audit.passed() {
+        return Err(format!(
+            "the audit found biased output (a test below p = {:.1e}); not generating",
+            audit.threshold()
+        )
+        .into());
+    }
+    status!(
+        "Audit passed (every test above p = {:.1e})\n",
+        audit.threshold()
+    );
+    Ok(())
+}
+
+/// A run's sink, and how many records it leaves out.
+type Screened = (Option<Box<dyn OutputSink>>, usize);
+
+/// The sink the run writes through, after `--verify-unique` has checked
+/// the records and, with `remove`, dropped the repeats; and how many it
+/// dropped.
+fn output_sink(
+    args: &Args,
+    generator: &PasswordGenerator,
+    key: &Key,
+) -> Result<Screened, Box<dyn Error>> {
+    let sink = run_sink(args, generator, key)?;
+    let Some(check) = args.verify_unique else {
+        return Ok((sink, 0));
+    };
+    status!("Checking that every record is unique...");
+    let started = Instant::now();
+    let dups = find_duplicates(generator, args.unique_fp_rate);
+    let bits = analysis::entropy_bits(generator);
+    status!(
+        "Uniqueness: {} duplicate record{} of {} repeated value{} (expected ~{:.3} at {:.1} bits); \
+         {} candidates from a {:.1} MiB filter at a {} false-positive rate, in {:.2?}",
+        dups.indices.len(),
+        if dups.indices.len() == 1 { "" } else { "s" },
+        dups.values,
+        if dups.values == 1 { "" } else { "s" },
+        analysis::expected_duplicates(generator.count() as f64, bits.exp2()),
+        bits,
+        dups.candidates,
+        dups.filter_bytes as f64 / (1024.0 * 1024.0),
+        args.unique_fp_rate,
+        started.elapsed()
+    );
+    if !dups.indices.is_empty() {
+        let shown: Vec<String> = dups.indices.iter().take(5).map(|i| i.to_string()).collect();
+        status!(
+            "Duplicate indices: {}{}",
+            shown.join(", "),
+            if dups.indices.len() > shown.len() {
+                ", ..."
+            } else {
+                ""
+            }
+        );
+    }
+    let removed = if check == UniqueCheck::Remove && !dups.indices.is_empty() {
+        status!(
+            "Removing them: {} records will be written",
+            generator.count() - dups.indices.len()
+        );
+        let removed = dups.indices.len();
+        let sink = WithoutRecords::new(sink, generator.is_binary(), dups.indices);
+        (Some(Box::new(sink) as _), removed)
+    } else {
+        (sink, 0)
+    };
+    status!();
+    Ok(removed)
+}
+
+/// Says where the records go, and reserves memory for them without
+/// --output. Returns the buffer a run without --output fills.
+fn allocate(
+    args: &Args,
+    generator: &PasswordGenerator,
+    formatted: bool,
+    approx_bytes: f64,
+    to_stdout: bool,
+) -> Vec<u8> {
+    let num_passwords = generator.count();
+    let approx_gib = approx_bytes / GIB;
+    let mut passwords = Vec::new();
+    match &args.output {
+        None if args.local_buffers => status!(
+            "Generating {} passwords into per-worker buffers of one chunk (~{:.1} MiB each); \
+             they are timed, not kept...",
+            num_passwords,
+            (generator.chunk_len(0) * generator.record_width()) as f64 / (1024.0 * 1024.0)
+        ),
+        None => {
+            status!(
+                "Allocating space for {} passwords (~{:.2} GiB)...",
+                num_passwords,
+                approx_gib
+            );
+            if generator.fixed_width() && !formatted {
+                // A zeroed allocation maps lazily-zeroed pages, so this costs
+                // no more than an uninitialized buffer; every byte gets
+                // overwritten.
+                passwords = vec![0u8; generator.output_len()];
+            } else {
+                // Grown in one step rather than by doubling, which could
+                // briefly take twice the planned memory
+                passwords.reserve_exact(approx_bytes as usize);
+            }
+        }
+        Some(path) => {
+            let dest = if to_stdout {
+                "stdout".to_string()
+            } else {
+                path.display().to_string()
+            };
+            status!(
+                "Streaming {} passwords (~{:.2} GiB) to {}...",
+                num_passwords,
+                approx_gib,
+                dest
+            );
+        }
+    }
+
+    // The last chunk picks up any remainder
+    let chunk_size = generator.chunk_size();
+    let num_chunks = num_passwords.div_ceil(chunk_size);
+    let remainder = num_passwords % chunk_size;
+    if remainder == 0 {
+        status!(
+            "Generating in {} parallel chunks of {} passwords each...\n",
+            num_chunks,
+            chunk_size
+        );
+    } else {
+        status!(
+            "Generating in {} parallel chunks of {} passwords each (last chunk: {})...\n",
+            num_chunks,
+            chunk_size,
+            remainder
+        );
+    }
+    passwords
+}
+
+/// How fast the run went: overall, and split into its warm-up, the steady
+/// state after it and its best whole second.
+struct Rates {
+    overall: f64,
+    split: throughput::Split,
+}
+
+impl Rates {
+    /// Measures and prints the rates of a run of `num_generated` records,
+    /// `removed` of them left out as duplicates, that took `duration`,
+    /// making chunks as `chunk_done` says; its first `warmup` is the cold
+    /// start.
+    fn measure(
+        generator: &PasswordGenerator,
+        num_generated: usize,
+        removed: usize,
+        duration: Duration,
+        warmup: Duration,
+        chunk_done: &[throughput::Span],
+    ) -> Self {
+        let num_written = num_generated - removed;
+        match generator.encoder() {
+            None => status!(
+                "Generated {} unique, 128-bit passwords in {:.2?}",
+                num_written,
+                duration
+            ),
+            Some(enc) => status!(
+                "Generated {} passwords of {} characters ({:.1} bits each) in {:.2?}",
+                num_written,
+                enc.length(),
+                analysis::entropy_bits(generator),
+                duration
+            ),
+        }
+        if removed > 0 {
+            status!(
+                "Removed {} duplicate record{} of {} generated",
+                removed,
+                if removed == 1 { "" } else { "s" },
+                num_generated
+            );
+        }
+        if let Some(stats) = generator.resample_stats() {
+            status!(
+                "Resampled {} of {} records ({:.2}%) the policy turned down: {} replacement \
+                 candidates, at most {} tries for one record (bound {})",
+                stats.resampled,
+                stats.records,
+                stats.rate() * 100.0,
+                stats.replacements,
+                stats.most_tries,
+                stats.bound
+            );
+        }
+        let secs = duration.as_secs_f64();
+        let rate = (num_generated as f64) / secs;
+        status!(
+            "Rate: ~{:.0} passwords/sec (~{:.1} million/sec)",
+            rate,
+            rate / 1_000_000.0
+        );
+
+        let split = throughput::Split::of(chunk_done, warmup, duration);
+        print_split(&split, warmup);
+        Rates {
+            overall: rate,
+            split,
+        }
+    }
+}
+
+/// Prints the cold-start, steady-state and peak rates of `split`, whose
+/// warm-up was `warmup`.
+fn print_split(split: &throughput::Split, warmup: Duration) {
+    let warmup = warmup.as_secs_f64();
+    if let Some(cold) = split.cold {
+        status!(
+            "Cold-start rate (first {:.1}s): ~{:.0} passwords/sec (~{:.1} million/sec)",
+            warmup,
+            cold,
+            cold / 1_000_000.0
+        );
+    }
+    match split.steady {
+        Some(steady) => status!(
+            "Steady-state rate (after {:.1}s warm-up): ~{:.0} passwords/sec (~{:.1} million/sec)",
+            warmup,
+            steady,
+            steady / 1_000_000.0
+        ),
+        None => status!(
+            "Steady-state rate: n/a (run shorter than the {:.1}s warm-up)",
+            warmup
+        ),
+    }
+    if let Some(peak) = split.peak {
+        status!(
+            "Peak 1-second rate: ~{:.0} passwords/sec (~{:.1} million/sec)",
+            peak,
+            peak / 1_000_000.0
+        );
+    }
+}
+
+/// Where the run's key comes from. `--key-hex` and `--key` require
+/// `--deterministic`; a fresh key has `cipher`'s native width.
+pub fn key_provider(
+    args: &Args,
+    resume: Option<&checkpoint::Checkpoint>,
+    cipher: Cipher,
+) -> Box<dyn keyprovider::KeyProvider> {
+    match (&args.key, args.key_hex, resume) {
+        _ if args.demo => Box::new(keyprovider::Published {
+            name: "demo",
+            key: published_key(DEMO_KEY, cipher),
+            source: warnings::KeySource::Demo,
+        }),
+        (Some(uri), _, _) => uri.provider(),
+        (None, Some(key), _) => Box::new(keyprovider::Hex(key)),
+        (None, None, Some(checkpoint)) => Box::new(keyprovider::Checkpoint(checkpoint.key)),
+        (None, None, None) if args.deterministic => Box::new(keyprovider::Published {
+            name: "benchmark",
+            key: published_key(BENCHMARK_KEY, cipher),
+            source: warnings::KeySource::Published,
+        }),
+        (None, None, None) => Box::new(keyprovider::Random(cipher.key_len())),
+    }
+}
+
+/// Creates the reference file for `--reference-output` with `mode`, keyed
+/// with the `key`, or else a fresh one saved next to it.
+fn open_reference(
+    path: &Path,
+    key: Option<&keyprovider::KeyUri>,
+    generator: &PasswordGenerator,
+    sink: Option<Arc<dyn OutputSink>>,
+    mode: u32,
+) -> Result<ReferenceWriter, Box<dyn std::error::Error>> {
+    let key = match key {
+        Some(uri) => uri.load().map_err(failure::Failure::Key)?,
+        None => {
+            let key = Key::from(random_key()?);
+            let mut key_path = path.as_os_str().to_owned();
+            key_path.push(".key");
+            let key_path = PathBuf::from(key_path);
+            writeln!(checkpoint::private_file(&key_path)?, "{}", key.to_hex())?;
+            status!(
+                "Reference key written to {}; keep it apart from the reference",
+                key_path.display()
+            );
+            key
+        }
+    };
+    status!(
+        "Reference: {}, checksums keyed with fingerprint {}\n",
+        path.display(),
+        key_fingerprint(&key)
+    );
+    Ok(ReferenceWriter::new(
+        generator,
+        sink,
+        &key,
+        permissions::create(path, mode)?,
+    )?)
+}
+
+/// The sink the main run, keyed with `key`, serializes through, if any.
+pub fn run_sink(
+    args: &Args,
+    generator: &PasswordGenerator,
+    key: &Key,
+) -> Result<Option<Box<dyn OutputSink>>, String> {
+    if let Some(schema) = &args.schema {
+        Ok(Some(Box::new(schema.sink(
+            args.records.format,
+            generator,
+            key,
+        )?)))
+    } else if args.demo {
+        Ok(Some(Box::new(format::Marked::new(
+            DEMO_MARK,
+            args.records.format,
+            generator,
+        ))))
+    } else if args.timestamp_spread.is_some() || args.with_id.is_some() {
+        let spread = args
+            .timestamp_spread
+            .map(|spread| spread.with_distribution(args.timestamp_distribution));
+        Ok(Some(args.records.format.sink_with_fields(
+            generator,
+            spread.as_ref(),
+            args.with_id,
+        )?))
+    } else if let Some(path) = &args.pair_with {
+        let pairs = UsernamePairs::open(path, pair_format(args)?, generator);
+        Ok(Some(Box::new(pairs.map_err(|e| e.to_string())?)))
+    } else if let Some(layout) = record_layout(args, generator)? {
+        Ok(Some(Box::new(PaddedSink::new(layout, generator.count()))))
+    } else {
+        args.records.sink(generator)
+    }
+}
+
+/// `--answer-key`: where the answers to `--format hashcat` lines go.
+#[cfg(feature = "hash")]
+fn answer_key(args: &Args) -> Result<Option<Arc<AnswerKey>>, String> {
+    let Some(path) = &args.answer_key else {
+        return Ok(None);
+    };
+    let file = permissions::create(path, args.output_mode)
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    Ok(Some(Arc::new(AnswerKey::new(file))))
+}
+
+/// How `--pair-with` writes its pairs.
+fn pair_format(args: &Args) -> Result<PairFormat, String> {
+    #[cfg(feature = "hash")]
+    if args.records.emit_hash.is_some() {
+        return Err("--pair-with writes its own lines; it takes no --emit-hash".to_string());
+    }
+    match (args.pair_format, &args.ldif_base) {
+        (PairOutput::Csv, None) => Ok(PairFormat::Csv),
+        (PairOutput::Csv, Some(_)) => Err("--ldif-base is for --pair-format ldif".to_string()),
+        (PairOutput::Ldif, Some(base)) => Ok(PairFormat::Ldif { base: base.clone() }),
+        (PairOutput::Ldif, None) => {
+            Err("--pair-format ldif needs --ldif-base, the DN the entries are under".to_string())
+        }
+    }
+}
+
+/// `--record-align`, `--header` and `--binary-layout`: where the run's raw
+/// records sit, if not simply back to back.
+fn record_layout(args: &Args, generator: &PasswordGenerator) -> Result<Option<RawLayout>, String> {
+    let header = args.header == Switch::On;
+    if args.record_align.is_none() && !header && args.binary_layout.is_none() {
+        return Ok(None);
+    }
+    #[cfg(feature = "hash")]
+    if args.records.emit_hash.is_some() {
+        return Err(
+            "--record-align, --header and --binary-layout lay out raw records; \
+             --emit-hash writes lines"
+                .to_string(),
+        );
+    }
+    if args.records.format != Format::Raw || !generator.is_binary() {
+        return Err(
+            "--record-align, --header and --binary-layout lay out raw 16-byte records; \
+             leave out --format, --charset, --template and --mode"
+                .to_string(),
+        );
+    }
+    let align = args.record_align.unwrap_or(1);
+    let layout = RawLayout::aligned(generator.record_width(), align, args.record_fill)
+        .map_err(|e| format!("--record-align: {}", e))?;
+    Ok(Some(RawLayout {
+        order: args.binary_layout.unwrap_or_default(),
+        header,
+        ..layout
+    }))
+}
+
+/// Prints what --cpu-limit held the run to.
+fn report_cpu(cpu: &CpuReport) {
+    match cpu.mean {
+        Some(mean) => status!(
+            "CPU: {:.0}% of the machine on average against a limit of {:.0}%; workers \
+             ended up busy {:.0}% of the time",
+            mean * 100.0,
+            cpu.limit * 100.0,
+            cpu.duty * 100.0
+        ),
+        None => status!("CPU: the run ended before its use was measured"),
+    }
+}
+
+fn report_double_check(checked: &DoubleCheckReport) {
+    match checked.disagreed.len() {
+        0 => status!(
+            "Double check: both copies of all {} chunks matched",
+            checked.chunks
+        ),
+        n => status!(
+            "Double check: the copies of {} of {} chunks differed (chunks {}); {} settled by vote",
+            n,
+            checked.chunks,
+            chunk_list(&checked.disagreed),
+            n - checked.unresolved.len()
+        ),
+    }
+}
+
+/// The first few of `chunks`, comma-separated.
+fn chunk_list(chunks: &[usize]) -> String {
+    const SHOWN: usize = 10;
+    let mut list: Vec<String> = chunks.iter().take(SHOWN).map(usize::to_string).collect();
+    if chunks.len() > SHOWN {
+        list.push(format!("and {} more", chunks.len() - SHOWN));
+    }
+    list.join(", ")
+}
+
+/// Resident set size of this process in bytes, as seen by sysinfo.
+fn process_rss(sys: &mut System, pid: Pid) -> Option<u64> {
+    sys.refresh_process_specifics(pid, ProcessRefreshKind::new());
+    sys.process(pid).map(|p| p.memory())
+}
+
+/// Peak resident set size of this process in bytes.
+///
+/// Linux exposes the high-water mark directly (`VmHWM`); elsewhere we return
+/// `None` and the caller falls back to the largest RSS it has sampled.
+fn peak_rss() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|l| l.starts_with("VmHWM:"))?;
+    let kib: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kib * 1024)
+}
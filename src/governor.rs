@@ -0,0 +1,166 @@
+//! A ceiling on the CPU a run takes, for hosts shared with other work.
+//!
+//! A [`CpuLimit`] paces chunk dispatch rather than cutting threads: each
+//! worker, before it starts a chunk, rests for a share of the time it
+//! just spent working, so it works for a fraction of the time, its duty.
+//! A sampler measures the process's CPU use (through sysinfo) four times a
+//! second and moves the duty towards what meets the limit: down when the
+//! run takes more, up when it takes less. Writers, compression and the
+//! rest of the process count as well as generation, and the run settles on
+//! the limit however the work in it varies.
+
+use std::cell::Cell;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use sysinfo::{ProcessExt, ProcessRefreshKind, System, SystemExt};
+
+/// How often the process's CPU use is measured.
+const SAMPLE: Duration = Duration::from_millis(250);
+
+/// Least duty the sampler sets: a worker always gets some work done.
+const MIN_DUTY: f64 = 0.01;
+
+/// Most the duty moves by, as a factor, in one sample.
+const MAX_STEP: f64 = 2.0;
+
+thread_local! {
+    /// When this worker last started a chunk.
+    static STARTED: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+/// A CPU limit as a share of the whole machine; pass it to
+/// [`PasswordGenerator::with_cpu_limit`](crate::PasswordGenerator::with_cpu_limit).
+pub struct CpuLimit {
+    share: f64,
+    cpus: usize,
+    state: Mutex<State>,
+}
+
+struct State {
+    duty: f64,
+    /// Share of the machine the process took in each sample
+    samples: Vec<f64>,
+}
+
+/// What a [`CpuLimit`] held the run to.
+#[derive(Clone, Debug, PartialEq, serde::Serialize)]
+pub struct CpuReport {
+    pub limit: f64,
+    /// Mean share of the machine over the samples taken
+    pub mean: Option<f64>,
+    /// The duty workers ended on
+    pub duty: f64,
+}
+
+impl CpuLimit {
+    /// Starts measuring, for a limit of `share` (up to 1) of `cpus` cores.
+    /// The sampler stops once the limit is dropped.
+    pub fn start(share: f64, cpus: usize) -> Arc<Self> {
+        let limit = Arc::new(CpuLimit {
+            share,
+            cpus: cpus.max(1),
+            state: Mutex::new(State {
+                duty: 1.0,
+                samples: Vec::new(),
+            }),
+        });
+        let weak = Arc::downgrade(&limit);
+        std::thread::spawn(move || sample(weak));
+        limit
+    }
+
+    /// Rests the calling worker for its share of the time since it last
+    /// started a chunk, then lets it start the next.
+    pub(crate) fn pace(&self) {
+        let duty = self.state.lock().unwrap().duty;
+        let now = Instant::now();
+        if let Some(started) = STARTED.get() {
+            std::thread::sleep(rest(now.duration_since(started), duty));
+        }
+        STARTED.set(Some(Instant::now()));
+    }
+
+    /// How the run was held.
+    pub fn report(&self) -> CpuReport {
+        let state = self.state.lock().unwrap();
+        let samples = &state.samples;
+        CpuReport {
+            limit: self.share,
+            mean: (!samples.is_empty()).then(|| samples.iter().sum::<f64>() / samples.len() as f64),
+            duty: state.duty,
+        }
+    }
+}
+
+/// Measures the process's CPU use every [`SAMPLE`] and steers the duty of
+/// `limit` by it, for as long as the limit lives.
+fn sample(limit: Weak<CpuLimit>) {
+    let Ok(pid) = sysinfo::get_current_pid() else {
+        return;
+    };
+    let mut sys = System::new();
+    let refresh = ProcessRefreshKind::new().with_cpu();
+    // The first refresh only sets the baseline to measure from
+    sys.refresh_process_specifics(pid, refresh);
+    loop {
+        std::thread::sleep(SAMPLE);
+        let Some(limit) = limit.upgrade() else {
+            return;
+        };
+        sys.refresh_process_specifics(pid, refresh);
+        let Some(process) = sys.process(pid) else {
+            continue;
+        };
+        // sysinfo gives percent of one core
+        let used = f64::from(process.cpu_usage()) / 100.0 / limit.cpus as f64;
+        let mut state = limit.state.lock().unwrap();
+        state.duty = steer(state.duty, used, limit.share);
+        state.samples.push(used);
+    }
+}
+
+/// The duty after a sample that found the process taking `used` of the
+/// machine, against a limit of `share`. The step is damped, so one noisy
+/// sample cannot swing it far.
+fn steer(duty: f64, used: f64, share: f64) -> f64 {
+    if used <= 0.0 {
+        return (duty * MAX_STEP).min(1.0);
+    }
+    let step = (share / used).sqrt().clamp(1.0 / MAX_STEP, MAX_STEP);
+    (duty * step).clamp(MIN_DUTY, 1.0)
+}
+
+/// How long a worker that just worked for `busy` rests to work for `duty`
+/// of its time.
+fn rest(busy: Duration, duty: f64) -> Duration {
+    busy.mul_f64((1.0 - duty) / duty)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_duty_settles_on_the_limit() {
+        // Four cores' worth of workers against a limit of one core: the
+        // duty goes where the use meets the limit and stays there
+        let (share, full) = (0.25, 1.0);
+        let mut duty = 1.0;
+        for _ in 0..30 {
+            duty = steer(duty, full * duty, share);
+        }
+        assert!((duty - share).abs() < 1e-3, "{}", duty);
+        assert_eq!(steer(duty, share, share), duty);
+        // Steps are bounded, and so is the duty
+        assert_eq!(steer(1.0, 1.0, 0.01), 0.5);
+        assert_eq!(steer(0.01, 1.0, 0.01), MIN_DUTY);
+        assert_eq!(steer(0.8, 0.0, 0.5), 1.0);
+
+        assert_eq!(
+            rest(Duration::from_millis(30), 0.25),
+            Duration::from_millis(90)
+        );
+        assert_eq!(rest(Duration::from_millis(30), 1.0), Duration::ZERO);
+    }
+}
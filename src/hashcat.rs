@@ -0,0 +1,471 @@
+//! `--format hashcat`: a run's records hashed into the files a cracking rig
+//! takes, for benchmarking it against passwords whose answers are known.
+//!
+//! Each record becomes one line in hashcat's input format for the chosen
+//! [`HashMode`]: an MD5 or NTLM digest in hex, MD5 with a salt as
+//! `hash:salt`, or a bcrypt string. John the Ripper reads the same lines
+//! for modes 0, 1000 and 3200 (`--format=raw-md5`, `nt` and `bcrypt`).
+//! Salts come from a lane of the run's key, as for `--emit-hash`, so a
+//! reproducible run reproduces its hashes too.
+//!
+//! The answers go to a separate [`AnswerKey`] as hashcat's potfile has
+//! them, `hash:password` (`hash:salt:password` when salted). Comparing it
+//! with the potfile of a cracking session shows what was cracked.
+
+use hmac::{Hmac, Mac};
+use rayon::prelude::*;
+use sha2::Sha256;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::sync::{Arc, Mutex};
+
+use crate::crypt::{bcrypt, BCRYPT_MAX_KEY};
+use crate::format::{push_hex, push_text};
+use crate::{Lane, OutputSink, PasswordGenerator};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Bytes of a salt for mode 10, written as twice as many hex digits.
+const MD5_SALT_LEN: usize = 8;
+
+/// The bcrypt cost of mode 3200 unless given: that of hashcat's example
+/// hash.
+pub const DEFAULT_BCRYPT_COST: u32 = 5;
+
+/// A hashcat hash mode, by its number.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HashMode {
+    /// 0: `md5($pass)`
+    Md5,
+    /// 10: `md5($pass.$salt)`, the salted form of mode 0
+    SaltedMd5,
+    /// 1000: NTLM, MD4 of the password in UTF-16LE
+    Ntlm,
+    /// 3200: bcrypt, `$2b$` with 2^cost rounds
+    Bcrypt { cost: u32 },
+}
+
+impl HashMode {
+    /// Parses `0`, `10`, `1000` or `3200`, the last optionally with its
+    /// cost: `3200:cost=12`.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let (number, setting) = s.split_once(':').unwrap_or((s, ""));
+        let mode = match number {
+            "0" => HashMode::Md5,
+            "10" => HashMode::SaltedMd5,
+            "1000" => HashMode::Ntlm,
+            "3200" => HashMode::Bcrypt {
+                cost: DEFAULT_BCRYPT_COST,
+            },
+            _ => {
+                return Err(format!(
+                    "unsupported hash mode `{}` (expected 0, 10, 1000 or 3200)",
+                    number
+                ))
+            }
+        };
+        match (mode, setting.split_once('=')) {
+            (_, None) if setting.is_empty() => Ok(mode),
+            (HashMode::Bcrypt { .. }, Some(("cost", cost))) => match cost.parse() {
+                Ok(cost @ 4..=31) => Ok(HashMode::Bcrypt { cost }),
+                _ => Err(format!("bcrypt costs run from 4 to 31, not `{}`", cost)),
+            },
+            _ => Err(format!("hash mode {} has no setting `{}`", number, setting)),
+        }
+    }
+
+    /// Length of each line's hash (and salt), without the newline.
+    fn hash_len(&self) -> usize {
+        match self {
+            HashMode::Md5 | HashMode::Ntlm => 32,
+            HashMode::SaltedMd5 => 32 + 1 + MD5_SALT_LEN * 2,
+            HashMode::Bcrypt { .. } => 60,
+        }
+    }
+}
+
+impl fmt::Display for HashMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HashMode::Md5 => f.write_str("0"),
+            HashMode::SaltedMd5 => f.write_str("10"),
+            HashMode::Ntlm => f.write_str("1000"),
+            HashMode::Bcrypt { cost } => write!(f, "3200:cost={}", cost),
+        }
+    }
+}
+
+/// Where the answers to a run's hashes go, in record order whatever order
+/// its chunks are hashed in.
+pub struct AnswerKey(Mutex<Answers>);
+
+struct Answers {
+    out: BufWriter<File>,
+    /// The record the next lines to write start at
+    next: usize,
+    /// Lines of chunks hashed ahead of `next`, by their first record, with
+    /// how many records they hold
+    waiting: BTreeMap<usize, (usize, Vec<u8>)>,
+    /// The first write that failed; later lines are not written
+    error: Option<io::Error>,
+}
+
+impl AnswerKey {
+    /// Answers written to `file`, which should be empty.
+    pub fn new(file: File) -> Self {
+        AnswerKey(Mutex::new(Answers {
+            out: BufWriter::new(file),
+            next: 0,
+            waiting: BTreeMap::new(),
+            error: None,
+        }))
+    }
+
+    /// Takes the lines of `records` records from record `first` on.
+    fn add(&self, first: usize, records: usize, lines: Vec<u8>) {
+        let mut guard = self.0.lock().unwrap();
+        let answers = &mut *guard;
+        answers.waiting.insert(first, (records, lines));
+        while let Some((records, lines)) = answers.waiting.remove(&answers.next) {
+            answers.next += records;
+            if answers.error.is_none() {
+                if let Err(e) = answers.out.write_all(&lines) {
+                    answers.error = Some(e);
+                }
+            }
+        }
+    }
+
+    /// Flushes the answers, and checks that all `count` records have one.
+    pub fn finish(&self, count: usize) -> io::Result<()> {
+        let mut answers = self.0.lock().unwrap();
+        if let Some(e) = answers.error.take() {
+            return Err(e);
+        }
+        if answers.next != count {
+            return Err(io::Error::other(format!(
+                "only the first {} of {} records were answered",
+                answers.next, count
+            )));
+        }
+        answers.out.flush()
+    }
+}
+
+/// Writes `generator`'s records as hashcat hash lines, and their answers to
+/// an [`AnswerKey`] if given one.
+pub struct HashcatLines {
+    mode: HashMode,
+    mac: HmacSha256,
+    binary: bool,
+    answers: Option<Arc<AnswerKey>>,
+}
+
+impl HashcatLines {
+    /// Fails for bcrypt if records can be longer than the 72 bytes it reads.
+    pub fn new(
+        generator: &PasswordGenerator,
+        mode: HashMode,
+        answers: Option<Arc<AnswerKey>>,
+    ) -> Result<Self, String> {
+        let binary = generator.is_binary();
+        let longest = match binary {
+            true => generator.record_width() * 2,
+            false => generator.record_width() - 1,
+        };
+        if matches!(mode, HashMode::Bcrypt { .. }) && longest > BCRYPT_MAX_KEY {
+            return Err(format!(
+                "bcrypt reads only the first {} bytes of a password, and these run to {}; \
+                 hash them with another mode",
+                BCRYPT_MAX_KEY, longest
+            ));
+        }
+        Ok(HashcatLines {
+            mode,
+            mac: Lane::HASH_SALT.mac(&generator.key),
+            binary,
+            answers,
+        })
+    }
+
+    fn salt(&self, index: usize) -> [u8; 16] {
+        let mut mac = self.mac.clone();
+        mac.update(&(index as u64).to_le_bytes());
+        mac.finalize().into_bytes()[..16].try_into().unwrap()
+    }
+
+    /// Appends the hash of record `index`, given as its `password`, as its
+    /// line has it without the newline.
+    fn push_hash(&self, index: usize, password: &[u8], out: &mut Vec<u8>) {
+        match self.mode {
+            HashMode::Md5 => push_hex(&md5(password), out),
+            HashMode::SaltedMd5 => {
+                let mut salt = Vec::with_capacity(MD5_SALT_LEN * 2);
+                push_hex(&self.salt(index)[..MD5_SALT_LEN], &mut salt);
+                push_hex(&md5(&[password, &salt].concat()), out);
+                out.push(b':');
+                out.extend_from_slice(&salt);
+            }
+            HashMode::Ntlm => {
+                let utf16: Vec<u8> = String::from_utf8_lossy(password)
+                    .encode_utf16()
+                    .flat_map(u16::to_le_bytes)
+                    .collect();
+                push_hex(&md4(&utf16), out);
+            }
+            HashMode::Bcrypt { cost } => {
+                out.extend_from_slice(bcrypt(password, cost, self.salt(index)).as_bytes())
+            }
+        }
+    }
+
+    /// The hash line and the answer line of record `index`.
+    fn lines(&self, index: usize, record: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        let mut password = Vec::with_capacity(record.len() * 2);
+        self.credential(index, record, &mut password);
+        let mut hash = Vec::with_capacity(self.mode.hash_len() + 1);
+        self.push_hash(index, &password, &mut hash);
+        let mut answer = Vec::new();
+        if self.answers.is_some() {
+            answer.reserve(hash.len() + 1 + password.len() + 1);
+            answer.extend_from_slice(&hash);
+            answer.push(b':');
+            answer.extend_from_slice(&password);
+            answer.push(b'\n');
+        }
+        hash.push(b'\n');
+        (hash, answer)
+    }
+}
+
+impl OutputSink for HashcatLines {
+    fn write_record(&self, index: usize, record: &[u8], out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.lines(index, record).0);
+    }
+
+    fn record_len(&self, _index: usize, _record_len: usize) -> Option<usize> {
+        Some(self.mode.hash_len() + 1)
+    }
+
+    fn credential(&self, _index: usize, record: &[u8], out: &mut Vec<u8>) {
+        push_text(self.binary, record, out);
+    }
+
+    fn write_chunk(
+        &self,
+        generator: &PasswordGenerator,
+        first: usize,
+        data: &[u8],
+        out: &mut Vec<u8>,
+    ) {
+        let mut records = Vec::with_capacity(generator.chunk_size());
+        generator.for_each_record(data, |record| records.push(record));
+        let lines: Vec<(Vec<u8>, Vec<u8>)> = records
+            .par_iter()
+            .enumerate()
+            .map(|(i, record)| self.lines(first + i, record))
+            .collect();
+        let mut answers = Vec::new();
+        for (hash, answer) in lines {
+            out.extend_from_slice(&hash);
+            answers.extend_from_slice(&answer);
+        }
+        if let Some(key) = &self.answers {
+            key.add(first, records.len(), answers);
+        }
+    }
+}
+
+/// `message` padded as MD4 and MD5 pad it, in 64-byte blocks of
+/// little-endian words.
+fn padded(message: &[u8]) -> Vec<[u32; 16]> {
+    let mut bytes = message.to_vec();
+    bytes.push(0x80);
+    // Room for the length in bits, a u64, at the end
+    let len = (message.len() + 1 + 8).next_multiple_of(64);
+    bytes.resize(len, 0);
+    bytes[len - 8..].copy_from_slice(&(message.len() as u64 * 8).to_le_bytes());
+    bytes
+        .chunks_exact(64)
+        .map(|block| {
+            std::array::from_fn(|i| u32::from_le_bytes(block[i * 4..][..4].try_into().unwrap()))
+        })
+        .collect()
+}
+
+const DIGEST_START: [u32; 4] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476];
+
+fn digest_bytes(state: [u32; 4]) -> [u8; 16] {
+    let mut digest = [0; 16];
+    for (bytes, word) in digest.chunks_exact_mut(4).zip(state) {
+        bytes.copy_from_slice(&word.to_le_bytes());
+    }
+    digest
+}
+
+/// MD5 (RFC 1321).
+fn md5(message: &[u8]) -> [u8; 16] {
+    const SHIFTS: [[u32; 4]; 4] = [
+        [7, 12, 17, 22],
+        [5, 9, 14, 20],
+        [4, 11, 16, 23],
+        [6, 10, 15, 21],
+    ];
+    // The RFC's table: the integer part of 2^32 * |sin(i + 1)|
+    let sines: [u32; 64] =
+        std::array::from_fn(|i| ((i as f64 + 1.0).sin().abs() * 4294967296.0) as u32);
+    let mut state = DIGEST_START;
+    for x in padded(message) {
+        let [mut a, mut b, mut c, mut d] = state;
+        for i in 0..64 {
+            let (f, k) = match i / 16 {
+                0 => ((b & c) | (!b & d), i),
+                1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                2 => (b ^ c ^ d, (3 * i + 5) % 16),
+                _ => (c ^ (b | !d), (7 * i) % 16),
+            };
+            let sum = a.wrapping_add(f).wrapping_add(x[k]).wrapping_add(sines[i]);
+            (a, b, c, d) = (
+                d,
+                b.wrapping_add(sum.rotate_left(SHIFTS[i / 16][i % 4])),
+                b,
+                c,
+            );
+        }
+        for (word, add) in state.iter_mut().zip([a, b, c, d]) {
+            *word = word.wrapping_add(add);
+        }
+    }
+    digest_bytes(state)
+}
+
+/// MD4 (RFC 1320).
+fn md4(message: &[u8]) -> [u8; 16] {
+    const SHIFTS: [[u32; 4]; 3] = [[3, 7, 11, 19], [3, 5, 9, 13], [3, 9, 11, 15]];
+    const ORDER: [[usize; 16]; 3] = [
+        [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15],
+        [0, 4, 8, 12, 1, 5, 9, 13, 2, 6, 10, 14, 3, 7, 11, 15],
+        [0, 8, 4, 12, 2, 10, 6, 14, 1, 9, 5, 13, 3, 11, 7, 15],
+    ];
+    let mut state = DIGEST_START;
+    for x in padded(message) {
+        let [mut a, mut b, mut c, mut d] = state;
+        for round in 0..3 {
+            for (i, &k) in ORDER[round].iter().enumerate() {
+                let (f, added) = match round {
+                    0 => ((b & c) | (!b & d), 0),
+                    1 => ((b & c) | (b & d) | (c & d), 0x5a827999),
+                    _ => (b ^ c ^ d, 0x6ed9eba1),
+                };
+                let sum = a.wrapping_add(f).wrapping_add(x[k]).wrapping_add(added);
+                (a, b, c, d) = (d, sum.rotate_left(SHIFTS[round][i % 4]), b, c);
+            }
+        }
+        for (word, add) in state.iter_mut().zip([a, b, c, d]) {
+            *word = word.wrapping_add(add);
+        }
+    }
+    digest_bytes(state)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{hex, Charset, Encoder};
+
+    #[test]
+    fn digests_match_the_rfc_vectors() {
+        let long = "1234567890".repeat(8);
+        for (message, md4_hex, md5_hex) in [
+            (
+                "",
+                "31d6cfe0d16ae931b73c59d7e0c089c0",
+                "d41d8cd98f00b204e9800998ecf8427e",
+            ),
+            (
+                "abc",
+                "a448017aaf21d8525fc10ae87aa6729d",
+                "900150983cd24fb0d6963f7d28e17f72",
+            ),
+            (
+                "message digest",
+                "d9130a8164549fe818874806e1c7014b",
+                "f96b697d7cb7938d525a2f31aaf161d0",
+            ),
+            (
+                &long,
+                "e33b4ddc9c38f2199c3e7b164fcc0536",
+                "57edf4a22be3c955ac49da2e2107b67a",
+            ),
+        ] {
+            assert_eq!(hex(&md4(message.as_bytes())), md4_hex, "{:?}", message);
+            assert_eq!(hex(&md5(message.as_bytes())), md5_hex, "{:?}", message);
+        }
+        // 55 and 56 bytes: the length still fits in the block, then it does not
+        assert_eq!(padded(&[0; 55]).len(), 1);
+        assert_eq!(padded(&[0; 56]).len(), 2);
+    }
+
+    #[test]
+    fn lines_and_answers_match() {
+        let g = PasswordGenerator::builder()
+            .key([3; 16])
+            .count(300)
+            .chunk_size(64)
+            .encoder(Encoder::new(&Charset::Alnum, 10))
+            .build()
+            .unwrap();
+        let path = std::env::temp_dir().join(format!("mpg_hashcat_{}", std::process::id()));
+        for mode in ["0", "10", "1000", "3200:cost=4"] {
+            let mode = HashMode::parse(mode).unwrap();
+            let answers = Arc::new(AnswerKey::new(File::create(&path).unwrap()));
+            let sink = HashcatLines::new(&g, mode, Some(Arc::clone(&answers))).unwrap();
+            let mut out = Vec::new();
+            g.write_formatted(&sink, &mut out, |_, _| ()).unwrap();
+            answers.finish(300).unwrap();
+            let hashes = String::from_utf8(out).unwrap();
+            let key = std::fs::read_to_string(&path).unwrap();
+            let records: Vec<Vec<u8>> = g.records().collect();
+            for (i, (hash, answer)) in hashes.lines().zip(key.lines()).enumerate() {
+                assert_eq!(hash.len(), mode.hash_len());
+                let password = String::from_utf8(records[i].clone()).unwrap();
+                assert_eq!(answer, format!("{}:{}", hash, password));
+                match mode {
+                    HashMode::Md5 => assert_eq!(hash, hex(&md5(password.as_bytes()))),
+                    HashMode::SaltedMd5 => {
+                        let (digest, salt) = hash.split_once(':').unwrap();
+                        assert_eq!(
+                            digest,
+                            hex(&md5(format!("{}{}", password, salt).as_bytes()))
+                        );
+                    }
+                    HashMode::Ntlm => {}
+                    HashMode::Bcrypt { .. } => assert!(bcrypt::verify(&password, hash).unwrap()),
+                }
+            }
+            assert_eq!(hashes.lines().count(), 300);
+            assert_eq!(key.lines().count(), 300);
+        }
+        std::fs::remove_file(&path).unwrap();
+
+        // NTLM's vector, from the password "password"
+        let ntlm = HashcatLines::new(&g, HashMode::Ntlm, None).unwrap();
+        let mut hash = Vec::new();
+        ntlm.push_hash(0, b"password", &mut hash);
+        assert_eq!(hash, b"8846f7eaee8fb117ad06bdd830b7586c");
+    }
+
+    #[test]
+    fn modes_parse() {
+        assert_eq!(HashMode::parse("3200"), Ok(HashMode::Bcrypt { cost: 5 }));
+        assert_eq!(
+            HashMode::parse("3200:cost=12").unwrap().to_string(),
+            "3200:cost=12"
+        );
+        for bad in ["1", "3200:cost=3", "3200:rounds=5", "0:cost=5", "md5"] {
+            assert!(HashMode::parse(bad).is_err(), "{}", bad);
+        }
+    }
+}
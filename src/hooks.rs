@@ -0,0 +1,375 @@
+//! `--on-shard-complete` and `run-hooks`: a command run on each shard as
+//! soon as it is finished, to sign, move or upload it while the run goes
+//! on.
+//!
+//! The command is run by the shell with `{path}` replaced by the shard's
+//! path, quoted, `{shard}` by its number and `{key}` by its idempotency
+//! key; `MPG_SHARD`, `MPG_SHARD_PATH` and `MPG_SHARD_KEY` hold the same.
+//! The key is the run ID and the shard's number, `RUNID-N`: the same on
+//! every try, in `run-hooks` and in a resumed run, and different for any
+//! other run, so an upload keyed on it lands once however often it is
+//! sent. Up to `--hook-jobs` run at once, and one that fails is tried
+//! again `--hook-retries` times, waiting twice as long each time from a
+//! second.
+//!
+//! Shards whose hook still fails do not stop the writing: the run finishes
+//! the shards and the manifest, then fails naming them. Their numbers are
+//! kept beside the manifest, in `MANIFEST.hook-failures`, and `run-hooks
+//! --failed` runs the command on those alone, taking off the ones it then
+//! succeeds on; the file goes once none are left.
+//!
+//! The manifest is hashed from the shards once they are all written, so a
+//! hook may read, copy or upload a shard but must leave it where it is.
+//! `--verify-remote`, once the hook has succeeded on every shard, or
+//! `verify-remote` later, then reads each uploaded copy back through a
+//! command of the same kind and checks it against the manifest's hash, and
+//! only then may the local shards go.
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::{mpsc, Mutex};
+use std::time::Duration;
+
+use mass_password_gen_optimized::hex;
+use sha2::{Digest, Sha256};
+
+use crate::permissions;
+
+/// Wait before the first retry of a failed hook.
+const FIRST_RETRY: Duration = Duration::from_secs(1);
+
+const LEDGER_HEADER: &str = "# mass_password_gen hook failures: shards to run the hook on again";
+
+/// A command run on finished shards.
+pub struct Hook {
+    command: String,
+    jobs: usize,
+    retries: u32,
+    /// Shards, numbered from 1, the hook was run on
+    tried: Mutex<Vec<usize>>,
+    /// Those of them it failed on
+    failed: Mutex<Vec<usize>>,
+}
+
+/// Where the shards the hook failed on are kept, beside the manifest at
+/// `manifest`.
+pub fn ledger(manifest: &Path) -> PathBuf {
+    let mut path = manifest.as_os_str().to_owned();
+    path.push(".hook-failures");
+    PathBuf::from(path)
+}
+
+/// The shards, numbered from 1, the hook has failed on in the run with
+/// manifest `manifest`; none without a ledger.
+pub fn failed_shards(manifest: &Path) -> Result<Vec<usize>, String> {
+    let path = ledger(manifest);
+    let text = match fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(format!("{}: {}", path.display(), e)),
+    };
+    let failed = text
+        .lines()
+        .find_map(|line| line.strip_prefix("failed="))
+        .ok_or_else(|| format!("{}: no `failed`", path.display()))?;
+    failed
+        .split(',')
+        .filter(|n| !n.is_empty())
+        .map(|n| {
+            n.parse()
+                .map_err(|_| format!("{}: bad shard `{}`", path.display(), n))
+        })
+        .collect()
+}
+
+impl Hook {
+    /// `command`, run on up to `jobs` shards at once and tried `retries`
+    /// more times on a shard it fails on.
+    pub fn new(command: String, jobs: usize, retries: u32) -> Self {
+        Hook {
+            command,
+            jobs: jobs.max(1),
+            retries,
+            tried: Mutex::new(Vec::new()),
+            failed: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Whether the command takes the shard's idempotency key.
+    pub fn keyed(&self) -> bool {
+        self.command.contains("{key}")
+    }
+
+    /// Runs the hook on each shard that `finished` gives the number and
+    /// path (relative to `dir`) of, until it closes, keyed on `run_id`
+    /// where the run has one.
+    pub fn shards(
+        &self,
+        run_id: Option<&str>,
+        dir: &Path,
+        finished: mpsc::Receiver<(usize, PathBuf)>,
+    ) {
+        let finished = Mutex::new(finished);
+        std::thread::scope(|scope| {
+            for _ in 0..self.jobs {
+                scope.spawn(|| loop {
+                    let next = finished.lock().unwrap().recv();
+                    let Ok((n, name)) = next else {
+                        return;
+                    };
+                    self.tried.lock().unwrap().push(n + 1);
+                    let key = run_id.map(|id| shard_key(id, n + 1));
+                    if let Err(e) = self.run(n + 1, &dir.join(name), key.as_deref()) {
+                        status!(
+                            "WARNING: --on-shard-complete failed on shard {}: {}",
+                            n + 1,
+                            e
+                        );
+                        self.failed.lock().unwrap().push(n + 1);
+                    }
+                });
+            }
+        });
+    }
+
+    /// Brings the ledger of the run with manifest `manifest` up to date,
+    /// the shards the hook was run on off it unless it failed on them
+    /// again, and fails naming the shards it failed on, if any. A ledger
+    /// written is created with `mode`.
+    pub fn check(&self, manifest: &Path, mode: u32) -> Result<(), String> {
+        let tried = self.tried.lock().unwrap().clone();
+        let mut failed = self.failed.lock().unwrap().clone();
+        let mut pending = failed_shards(manifest)?;
+        pending.retain(|n| !tried.contains(n));
+        pending.extend(&failed);
+        pending.sort_unstable();
+        pending.dedup();
+        let path = ledger(manifest);
+        let kept = match pending.is_empty() {
+            true => match fs::remove_file(&path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            },
+            false => {
+                let shards: Vec<_> = pending.iter().map(usize::to_string).collect();
+                let text = format!("{}\nfailed={}\n", LEDGER_HEADER, shards.join(","));
+                permissions::create(&path, mode)
+                    .and_then(|mut file| file.write_all(text.as_bytes()))
+            }
+        };
+        kept.map_err(|e| format!("{}: {}", path.display(), e))?;
+        if failed.is_empty() {
+            return Ok(());
+        }
+        failed.sort_unstable();
+        let shards: Vec<_> = failed.iter().map(usize::to_string).collect();
+        let (s, them) = match failed.len() {
+            1 => ("", "it"),
+            _ => ("s", "them"),
+        };
+        Err(format!(
+            "--on-shard-complete failed on shard{} {}; the shards and manifest are written, \
+             {} lists {}, and `run-hooks --manifest {} --failed --on-shard-complete ...` \
+             runs it on {} again",
+            s,
+            shards.join(", "),
+            path.display(),
+            them,
+            manifest.display(),
+            them
+        ))
+    }
+
+    /// Runs the hook on shard `shard` at `path`, with its retries.
+    fn run(&self, shard: usize, path: &Path, key: Option<&str>) -> Result<(), String> {
+        let mut wait = FIRST_RETRY;
+        let mut tries = 0;
+        loop {
+            let failed = match self.command(shard, path, key).status() {
+                Ok(status) if status.success() => return Ok(()),
+                Ok(status) => status.to_string(),
+                Err(e) => e.to_string(),
+            };
+            if tries == self.retries {
+                return Err(failed);
+            }
+            tries += 1;
+            status!(
+                "--on-shard-complete on shard {}: {}; trying again in {}s",
+                shard,
+                failed,
+                wait.as_secs()
+            );
+            std::thread::sleep(wait);
+            wait *= 2;
+        }
+    }
+
+    /// The shell running the hook on shard `shard` at `path`, with
+    /// idempotency key `key`. Its output goes to stderr, so it cannot mix
+    /// with records or JSON on stdout.
+    fn command(&self, shard: usize, path: &Path, key: Option<&str>) -> Command {
+        let mut command = shell(&self.command, shard, path, key);
+        command.stdout(io::stderr());
+        command
+    }
+}
+
+/// The idempotency key of shard `shard`, numbered from 1, of the run with
+/// ID `run_id`.
+pub fn shard_key(run_id: &str, shard: usize) -> String {
+    format!("{}-{}", run_id, shard)
+}
+
+/// The SHA-256, in hex, of what `command`, expanded as a hook is for shard
+/// `shard` at `path` with idempotency key `key`, writes to stdout: for
+/// `verify-remote`, the shard's remote copy read back. Fails if the
+/// command does.
+pub fn read_back(
+    command: &str,
+    shard: usize,
+    path: &Path,
+    key: Option<&str>,
+) -> Result<String, String> {
+    let mut child = shell(command, shard, path, key)
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| e.to_string())?;
+    let mut sha256 = Sha256::new();
+    let mut stdout = child.stdout.take().expect("piped above");
+    let copied = io::copy(&mut stdout, &mut sha256);
+    let status = child.wait().map_err(|e| e.to_string())?;
+    if !status.success() {
+        return Err(format!("reading it back failed: {}", status));
+    }
+    copied.map_err(|e| format!("reading it back failed: {}", e))?;
+    Ok(hex(&sha256.finalize()))
+}
+
+/// The shell running `command` on shard `shard` at `path`, with
+/// idempotency key `key`.
+fn shell(command: &str, shard: usize, path: &Path, key: Option<&str>) -> Command {
+    let script = expand(command, shard, path, key.unwrap_or_default());
+    #[cfg(unix)]
+    let mut shell = Command::new("sh");
+    #[cfg(unix)]
+    shell.arg("-c");
+    #[cfg(not(unix))]
+    let mut shell = Command::new("cmd");
+    #[cfg(not(unix))]
+    shell.arg("/C");
+    shell
+        .arg(script)
+        .env("MPG_SHARD", shard.to_string())
+        .env("MPG_SHARD_PATH", path)
+        .stdin(Stdio::null());
+    if let Some(key) = key {
+        shell.env("MPG_SHARD_KEY", key);
+    }
+    shell
+}
+
+/// `command` with `{shard}` replaced by `shard`, `{key}` by `key` and
+/// `{path}` by `path`, quoted for the shell.
+fn expand(command: &str, shard: usize, path: &Path, key: &str) -> String {
+    let path = path.to_string_lossy();
+    #[cfg(unix)]
+    let quoted = format!("'{}'", path.replace('\'', r"'\''"));
+    #[cfg(not(unix))]
+    let quoted = format!("\"{}\"", path);
+    command
+        .replace("{shard}", &shard.to_string())
+        .replace("{key}", key)
+        .replace("{path}", &quoted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn paths_are_quoted_for_the_shell() {
+        assert_eq!(
+            expand(
+                "sign {path} # {shard} {key}",
+                3,
+                Path::new("/d1/it's-00003.txt"),
+                "ab12-3"
+            ),
+            r"sign '/d1/it'\''s-00003.txt' # 3 ab12-3"
+        );
+        // A path is not expanded again
+        assert_eq!(
+            expand("mv {path} /done", 1, Path::new("/a/{shard}"), ""),
+            "mv '/a/{shard}' /done"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn failed_shards_are_retried_then_named() {
+        let dir = std::env::temp_dir().join(format!("mpg_hooks_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let names: Vec<_> = (1..=4)
+            .map(|n| PathBuf::from(format!("h-{:05}", n)))
+            .collect();
+        let manifest = dir.join("h.manifest.json");
+        // Writes each shard's key beside it, but fails on shard 3 every
+        // time
+        let run = |command: &str, shards: &[usize]| {
+            let hook = Hook::new(command.to_string(), 2, 1);
+            let (finished, to_hook) = mpsc::channel();
+            for &n in shards {
+                finished.send((n - 1, names[n - 1].clone())).unwrap();
+            }
+            drop(finished);
+            hook.shards(Some("ab12"), &dir, to_hook);
+            hook.check(&manifest, permissions::DEFAULT_MODE)
+        };
+        let failed = run(
+            "test $MPG_SHARD != 3 && echo {key} > {path}.done",
+            &[1, 2, 3, 4],
+        );
+        let failed = failed.unwrap_err();
+        assert!(failed.contains("shard 3;"), "{}", failed);
+        assert!(failed.contains("--failed"), "{}", failed);
+        for n in [1, 2, 4] {
+            let done = dir.join(format!("h-{:05}.done", n));
+            assert_eq!(
+                std::fs::read_to_string(done).unwrap(),
+                format!("ab12-{}\n", n)
+            );
+        }
+        assert!(!dir.join("h-00003.done").exists());
+        assert_eq!(failed_shards(&manifest).unwrap(), [3]);
+
+        // Run again on shard 4 alone, failing, shard 3 stays on the ledger
+        assert!(run("false", &[4]).is_err());
+        assert_eq!(failed_shards(&manifest).unwrap(), [3, 4]);
+        // and the ledger goes once the hook has done them all
+        run("test $MPG_SHARD_KEY = ab12-$MPG_SHARD", &[3, 4]).unwrap();
+        assert!(!ledger(&manifest).exists());
+        assert!(failed_shards(&manifest).unwrap().is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn copies_read_back_hash_as_their_shard_does_unless_they_differ() {
+        let dir = std::env::temp_dir().join(format!("mpg_read_back_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let shard = dir.join("h-00001");
+        std::fs::write(&shard, "the shard").unwrap();
+        let sha256 = hex(&Sha256::digest("the shard"));
+        let read = |command| read_back(command, 1, &shard, Some("ab12-1"));
+        assert_eq!(read("cat {path}").unwrap(), sha256);
+        assert_eq!(read("cat $MPG_SHARD_PATH").unwrap(), sha256);
+        assert_ne!(read("cat {path}; echo").unwrap(), sha256);
+        assert_ne!(read("echo {key}").unwrap(), sha256);
+        assert!(read("cat {path}; false").is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,132 @@
+//! Run keys: 128 bits for aes128, 256 for aes256, either for chacha20.
+//!
+//! A [`Key`] is just the bytes and their width; which ciphers take it is up
+//! to [`Cipher::accepts`](crate::Cipher::accepts). Everything that only
+//! feeds the key to HMAC (fingerprints, segment keys, reference and token
+//! keys) works with either width.
+
+use std::fmt;
+use std::io;
+use std::ops::Deref;
+
+/// The key of a run, 16 or 32 bytes.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Key {
+    bytes: [u8; 32],
+    len: u8,
+}
+
+impl Key {
+    /// Key lengths in bytes, narrowest first.
+    pub const LENGTHS: [usize; 2] = [16, 32];
+
+    /// A key of `bytes`, which must be 16 or 32 bytes long.
+    pub fn from_slice(bytes: &[u8]) -> Result<Self, String> {
+        if !Key::LENGTHS.contains(&bytes.len()) {
+            return Err(format!("a key is 128 or 256 bits, not {}", bytes.len() * 8));
+        }
+        let mut key = Key {
+            bytes: [0; 32],
+            len: bytes.len() as u8,
+        };
+        key.bytes[..bytes.len()].copy_from_slice(bytes);
+        Ok(key)
+    }
+
+    /// A fresh key of `len` bytes from the operating system's CSPRNG.
+    pub fn random(len: usize) -> io::Result<Self> {
+        let mut bytes = [0u8; 32];
+        let bytes = bytes.get_mut(..len).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "a key is 16 or 32 bytes")
+        })?;
+        getrandom::fill(bytes).map_err(io::Error::other)?;
+        Key::from_slice(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    }
+
+    /// The key's bytes.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len as usize]
+    }
+
+    /// 128 or 256.
+    pub fn bits(&self) -> usize {
+        self.len as usize * 8
+    }
+
+    /// The key as a 128-bit one, if it is one.
+    pub fn narrow(&self) -> Option<&[u8; 16]> {
+        self.as_bytes().try_into().ok()
+    }
+
+    /// The key as a 256-bit one, if it is one.
+    pub fn wide(&self) -> Option<&[u8; 32]> {
+        self.as_bytes().try_into().ok()
+    }
+
+    /// The key in lowercase hex, the form [`parse_key_hex`](crate::parse_key_hex)
+    /// reads back.
+    pub fn to_hex(&self) -> String {
+        crate::hex(self)
+    }
+}
+
+impl From<[u8; 16]> for Key {
+    fn from(bytes: [u8; 16]) -> Self {
+        Key::from_slice(&bytes).expect("16 bytes is a key length")
+    }
+}
+
+impl From<[u8; 32]> for Key {
+    fn from(bytes: [u8; 32]) -> Self {
+        Key::from_slice(&bytes).expect("32 bytes is a key length")
+    }
+}
+
+impl Deref for Key {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+impl AsRef<[u8]> for Key {
+    fn as_ref(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+/// Shows the width and fingerprint, never the key.
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Key({}-bit, {})",
+            self.bits(),
+            crate::key_fingerprint(self)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_keep_their_width() {
+        let narrow = Key::from([7; 16]);
+        let wide = Key::from([7; 32]);
+        assert_eq!((narrow.bits(), wide.bits()), (128, 256));
+        assert_eq!(narrow.narrow(), Some(&[7; 16]));
+        assert_eq!((narrow.wide(), wide.narrow()), (None, None));
+        // Width is part of the key: equal leading bytes are not enough
+        assert_eq!(Key::from_slice(&wide[..16]), Ok(narrow));
+        assert_ne!(narrow, wide);
+        assert_eq!(wide.to_hex(), "07".repeat(32));
+        assert!(Key::from_slice(&[0; 24]).is_err());
+        assert!(!format!("{:?}", narrow).contains("0707"));
+        assert_eq!(Key::random(32).unwrap().bits(), 256);
+        assert_ne!(Key::random(16).unwrap(), Key::random(16).unwrap());
+        assert!(Key::random(20).is_err());
+    }
+}
@@ -0,0 +1,428 @@
+//! Where a run's key comes from. `--key` takes a URI whose scheme picks the
+//! provider: `keyring:<name>` (see [`keyring`](crate::keyring)),
+//! `env:<VAR>`, `file:<path>`, `prompt`, `pkcs11:...` for a key kept in an
+//! HSM or token (see [`pkcs11`](crate::pkcs11)), or
+//! `derive:<master>/<label>`, a key derived from another provider's. Without `--key` the key is
+//! `--key-hex`, the checkpoint's on `--resume`, a published one, or fresh
+//! from the OS. Each source is a [`KeyProvider`] of its own, so a new one
+//! adds a type here and a scheme to [`KeyUri::parse`].
+//!
+//! Keys read from text are 32 hex characters for a 128-bit key or 64 for
+//! a 256-bit one, surrounding whitespace ignored.
+//!
+//! A derived key is lane `derive/<label>` of its master (see [`Lane`]):
+//! one stored master key gives every job its own key, as wide as the
+//! master's, without any of them revealing the master or each other.
+
+use mass_password_gen_optimized::{parse_key_hex, Key, Lane};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use crate::keyring;
+use crate::pkcs11;
+use crate::warnings::KeySource;
+
+/// A source of a run's key.
+pub trait KeyProvider {
+    /// Where the key comes from, for messages, e.g. "stored key `ci`".
+    fn describe(&self) -> String;
+
+    /// What the start-of-run warnings make of the source.
+    fn source(&self) -> KeySource;
+
+    /// Loads, reads or makes the key.
+    fn key(&self) -> io::Result<Key>;
+}
+
+/// A `--key` value.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum KeyUri {
+    /// `keyring:<name>`, stored with `key store`
+    Keyring(String),
+    /// `env:<VAR>`, in hex
+    Env(String),
+    /// `file:<path>`, whose contents are the key in hex
+    File(PathBuf),
+    /// `prompt`, typed in hex at the terminal without echo
+    Prompt,
+    /// `pkcs11:...`, an RFC 7512 URI naming a key on a token
+    Pkcs11(pkcs11::Locator),
+    /// `derive:<master>/<label>`, lane `derive/<label>` of `<master>`'s key
+    Derive(Box<KeyUri>, String),
+}
+
+impl KeyUri {
+    /// Parses `keyring:<name>`, `env:<VAR>`, `file:<path>`, `prompt`,
+    /// `pkcs11:...` or `derive:<master>/<label>`, `<master>` being any of these and the
+    /// label what follows its last `/`.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let (scheme, rest) = s.split_once(':').unwrap_or((s, ""));
+        match scheme {
+            "derive" => {
+                let (master, label) = rest
+                    .rsplit_once('/')
+                    .ok_or_else(|| format!("expected derive:<master>/<label>, got `{}`", s))?;
+                if label.is_empty() || label.chars().any(char::is_control) {
+                    return Err(format!("`{}` is not a label for a derived key", label));
+                }
+                let master = KeyUri::parse(master).map_err(|e| format!("derive: {}", e))?;
+                Ok(KeyUri::Derive(Box::new(master), label.to_string()))
+            }
+            "keyring" => keyring::validate_name(rest).map(KeyUri::Keyring),
+            "env" if valid_var(rest) => Ok(KeyUri::Env(rest.to_string())),
+            "env" => Err(format!("`{}` is not an environment variable name", rest)),
+            "file" if !rest.is_empty() => Ok(KeyUri::File(PathBuf::from(rest))),
+            "file" => Err("file: needs a path".to_string()),
+            "prompt" if rest.is_empty() => Ok(KeyUri::Prompt),
+            "pkcs11" => pkcs11::Locator::parse(rest).map(KeyUri::Pkcs11),
+            "hsm" => Err("hsm: keys are reached with a pkcs11: URI".to_string()),
+            "kms" => Err(
+                "kms: keys are not supported; export the key and pass it with env: or file:"
+                    .to_string(),
+            ),
+            _ => Err(format!(
+                "expected keyring:<name>, env:<VAR>, file:<path>, prompt, pkcs11:... or \
+                 derive:<master>/<label>, got `{}`",
+                s
+            )),
+        }
+    }
+
+    /// The provider for this URI.
+    pub fn provider(&self) -> Box<dyn KeyProvider> {
+        match self {
+            KeyUri::Keyring(name) => Box::new(Keyring(name.clone())),
+            KeyUri::Env(var) => Box::new(Env(var.clone())),
+            KeyUri::File(path) => Box::new(KeyFile(path.clone())),
+            KeyUri::Prompt => Box::new(Prompt),
+            KeyUri::Pkcs11(locator) => Box::new(Token(locator.clone())),
+            KeyUri::Derive(master, label) => Box::new(Derived {
+                master: master.provider(),
+                label: label.clone(),
+            }),
+        }
+    }
+
+    /// Loads the key, with the provider in any error.
+    pub fn load(&self) -> Result<Key, String> {
+        let provider = self.provider();
+        provider
+            .key()
+            .map_err(|e| format!("cannot load the key from {}: {}", provider.describe(), e))
+    }
+}
+
+fn valid_var(s: &str) -> bool {
+    !s.is_empty() && !s.contains('=') && !s.contains('\0')
+}
+
+/// A key in hex; `what` names where the text came from.
+fn from_hex(text: &str, what: &str) -> io::Result<Key> {
+    parse_key_hex(text.trim()).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} does not hold a key: {}", what, e),
+        )
+    })
+}
+
+/// A key in the platform's secret store.
+pub struct Keyring(pub String);
+
+impl KeyProvider for Keyring {
+    fn describe(&self) -> String {
+        format!("stored key `{}`", self.0)
+    }
+
+    fn source(&self) -> KeySource {
+        KeySource::Stored
+    }
+
+    fn key(&self) -> io::Result<Key> {
+        keyring::open()?.load(&self.0)
+    }
+}
+
+/// A key in an environment variable.
+pub struct Env(pub String);
+
+impl KeyProvider for Env {
+    fn describe(&self) -> String {
+        format!("environment variable {}", self.0)
+    }
+
+    fn source(&self) -> KeySource {
+        KeySource::Stored
+    }
+
+    fn key(&self) -> io::Result<Key> {
+        let text =
+            std::env::var(&self.0).map_err(|e| io::Error::new(io::ErrorKind::NotFound, e))?;
+        from_hex(&text, &self.0)
+    }
+}
+
+/// A key in a file.
+pub struct KeyFile(pub PathBuf);
+
+impl KeyProvider for KeyFile {
+    fn describe(&self) -> String {
+        format!("key file {}", self.0.display())
+    }
+
+    fn source(&self) -> KeySource {
+        KeySource::Stored
+    }
+
+    fn key(&self) -> io::Result<Key> {
+        from_hex(&fs::read_to_string(&self.0)?, "the file")
+    }
+}
+
+/// A key typed at the terminal.
+pub struct Prompt;
+
+impl KeyProvider for Prompt {
+    fn describe(&self) -> String {
+        "typed key".to_string()
+    }
+
+    fn source(&self) -> KeySource {
+        KeySource::Stored
+    }
+
+    fn key(&self) -> io::Result<Key> {
+        from_hex(
+            &rpassword::prompt_password("Key (32 or 64 hex characters): ")?,
+            "the input",
+        )
+    }
+}
+
+/// A key kept in an HSM or token.
+pub struct Token(pub pkcs11::Locator);
+
+impl KeyProvider for Token {
+    fn describe(&self) -> String {
+        self.0.describe()
+    }
+
+    fn source(&self) -> KeySource {
+        KeySource::Stored
+    }
+
+    fn key(&self) -> io::Result<Key> {
+        pkcs11::load(&self.0)
+    }
+}
+
+/// A key derived from another provider's.
+pub struct Derived {
+    pub master: Box<dyn KeyProvider>,
+    pub label: String,
+}
+
+impl KeyProvider for Derived {
+    fn describe(&self) -> String {
+        format!("`{}` derived from {}", self.label, self.master.describe())
+    }
+
+    fn source(&self) -> KeySource {
+        self.master.source()
+    }
+
+    fn key(&self) -> io::Result<Key> {
+        let master = self.master.key()?;
+        Ok(Lane::new(&format!("derive/{}", self.label)).key(&master))
+    }
+}
+
+/// A key given with `--key-hex`.
+pub struct Hex(pub Key);
+
+impl KeyProvider for Hex {
+    fn describe(&self) -> String {
+        "supplied via --key-hex".to_string()
+    }
+
+    fn source(&self) -> KeySource {
+        KeySource::CommandLine
+    }
+
+    fn key(&self) -> io::Result<Key> {
+        Ok(self.0)
+    }
+}
+
+/// The key of the run being resumed.
+pub struct Checkpoint(pub Key);
+
+impl KeyProvider for Checkpoint {
+    fn describe(&self) -> String {
+        "from the checkpoint".to_string()
+    }
+
+    fn source(&self) -> KeySource {
+        KeySource::Stored
+    }
+
+    fn key(&self) -> io::Result<Key> {
+        Ok(self.0)
+    }
+}
+
+/// A published key, `name` saying which.
+pub struct Published {
+    pub name: &'static str,
+    pub key: Key,
+    pub source: KeySource,
+}
+
+impl KeyProvider for Published {
+    fn describe(&self) -> String {
+        format!("the published {} key", self.name)
+    }
+
+    fn source(&self) -> KeySource {
+        self.source
+    }
+
+    fn key(&self) -> io::Result<Key> {
+        Ok(self.key)
+    }
+}
+
+/// A fresh key from the OS CSPRNG, of this many bytes.
+pub struct Random(pub usize);
+
+impl KeyProvider for Random {
+    fn describe(&self) -> String {
+        "random per run (OS CSPRNG)".to_string()
+    }
+
+    fn source(&self) -> KeySource {
+        KeySource::Random
+    }
+
+    fn key(&self) -> io::Result<Key> {
+        Key::random(self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uris_pick_providers() {
+        assert_eq!(
+            KeyUri::parse("keyring:ci"),
+            Ok(KeyUri::Keyring("ci".into()))
+        );
+        assert_eq!(
+            KeyUri::parse("env:PW_KEY"),
+            Ok(KeyUri::Env("PW_KEY".into()))
+        );
+        assert_eq!(
+            KeyUri::parse("file:/run/keys/a"),
+            Ok(KeyUri::File("/run/keys/a".into()))
+        );
+        assert_eq!(KeyUri::parse("prompt"), Ok(KeyUri::Prompt));
+        for bad in [
+            "",
+            "ci",
+            "keyring:",
+            "keyring:.x",
+            "env:",
+            "file:",
+            "prompt:x",
+        ] {
+            assert!(KeyUri::parse(bad).is_err(), "{:?}", bad);
+        }
+        let kms = KeyUri::parse("kms:arn:aws:kms:eu-west-1:1:key/x").unwrap_err();
+        assert!(kms.contains("not supported"));
+        assert!(KeyUri::parse("hsm:x").unwrap_err().contains("pkcs11:"));
+        let token = KeyUri::parse("pkcs11:object=k?module-path=p11.so").unwrap();
+        assert_eq!(token.provider().describe(), "PKCS#11 key `k`");
+        assert!(KeyUri::parse("pkcs11:object=k").is_err());
+
+        // The label is what follows the last slash, so masters keep theirs
+        assert_eq!(
+            KeyUri::parse("derive:file:/run/keys/a/ci-2026"),
+            Ok(KeyUri::Derive(
+                Box::new(KeyUri::File("/run/keys/a".into())),
+                "ci-2026".into()
+            ))
+        );
+        for bad in [
+            "derive:",
+            "derive:keyring:ci",
+            "derive:keyring:ci/",
+            "derive:ci/x",
+        ] {
+            assert!(KeyUri::parse(bad).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn derived_keys_are_lanes_of_their_master() {
+        let master = Key::from([4; 16]);
+        let derived = |label: &str| {
+            Derived {
+                master: Box::new(Hex(master)),
+                label: label.to_string(),
+            }
+            .key()
+            .unwrap()
+        };
+        assert_eq!(derived("a"), Lane::new("derive/a").key(&master));
+        assert_ne!(derived("a"), derived("b"));
+        assert_ne!(derived("a"), master);
+
+        std::env::set_var("MPG_KEYPROVIDER_MASTER", "cd".repeat(32));
+        let uri = KeyUri::parse("derive:derive:env:MPG_KEYPROVIDER_MASTER/job/7").unwrap();
+        let provider = uri.provider();
+        assert_eq!(
+            provider.describe(),
+            "`7` derived from `job` derived from environment variable MPG_KEYPROVIDER_MASTER"
+        );
+        assert_eq!(provider.source(), KeySource::Stored);
+        let wide = Key::from([0xcd; 32]);
+        let expected = Lane::new("derive/7").key(&Lane::new("derive/job").key(&wide));
+        assert_eq!(uri.load(), Ok(expected));
+        assert_eq!(expected.bits(), 256);
+        std::env::remove_var("MPG_KEYPROVIDER_MASTER");
+        assert!(uri.load().unwrap_err().contains("environment variable"));
+    }
+
+    #[test]
+    fn env_and_file_keys_are_read_as_hex() {
+        let hex = "000102030405060708090a0b0c0d0e0f";
+        let expected = Key::from(std::array::from_fn::<u8, 16, _>(|i| i as u8));
+        std::env::set_var("MPG_KEYPROVIDER_TEST", format!("{}\n", hex));
+        let env = KeyUri::parse("env:MPG_KEYPROVIDER_TEST").unwrap();
+        assert_eq!(env.load(), Ok(expected));
+        assert_eq!(env.provider().source(), KeySource::Stored);
+        std::env::set_var("MPG_KEYPROVIDER_TEST", "not hex");
+        assert!(env.load().unwrap_err().contains("environment variable"));
+        assert!(KeyUri::Env("MPG_KEYPROVIDER_UNSET".into()).load().is_err());
+
+        let path = std::env::temp_dir().join("mpg_keyprovider_test.key");
+        fs::write(&path, hex).unwrap();
+        let file = KeyUri::File(path.clone());
+        assert_eq!(file.load(), Ok(expected));
+        // 256-bit keys are twice as long
+        let wide = "ab".repeat(32);
+        fs::write(&path, format!("  {}\n", wide)).unwrap();
+        assert_eq!(file.load().map(|k| k.to_hex()), Ok(wide));
+        fs::write(&path, "ab".repeat(24)).unwrap();
+        assert!(file.load().unwrap_err().contains("32 or 64 hex characters"));
+        fs::remove_file(&path).unwrap();
+        assert!(file.load().is_err());
+
+        assert_eq!(Hex(expected).source(), KeySource::CommandLine);
+        assert_ne!(Random(16).key().unwrap(), Random(16).key().unwrap());
+        assert_eq!(Random(32).key().unwrap().bits(), 256);
+    }
+}
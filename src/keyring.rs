@@ -0,0 +1,442 @@
+//! Named run keys kept in the platform's secret store, for
+//! `--key keyring:<name>` and the `key` subcommands, so keys never have to
+//! appear on a command line.
+//!
+//! Linux uses the Secret Service (GNOME Keyring, KWallet, ...), macOS the
+//! login Keychain, and Windows DPAPI-protected files in the per-user local
+//! application data directory. Keys are stored as 32 or 64 hex characters
+//! under the service name [`SERVICE`]. Builds without the `keyring` feature
+//! have no store, and [`open`] says so.
+
+// Without a store the helpers its backends share go unused
+#![cfg_attr(not(feature = "keyring"), allow(dead_code))]
+
+use mass_password_gen_optimized::{parse_key_hex, Key};
+use std::io;
+
+/// What stored keys are filed under in the platform store.
+const SERVICE: &str = "mass_password_gen";
+
+/// A secret store holding named keys.
+pub trait KeyStore {
+    /// Human-readable name of the store, for messages.
+    fn describe(&self) -> &'static str;
+
+    /// Saves `key` as `name`, replacing any key of that name.
+    fn store(&self, name: &str, key: &Key) -> io::Result<()>;
+
+    /// The key saved as `name`; fails with `NotFound` if there is none.
+    fn load(&self, name: &str) -> io::Result<Key>;
+
+    /// Names of all saved keys.
+    fn list(&self) -> io::Result<Vec<String>>;
+
+    /// Removes the key saved as `name`; fails with `NotFound` if there is
+    /// none.
+    fn delete(&self, name: &str) -> io::Result<()>;
+}
+
+/// Opens this platform's store.
+pub fn open() -> io::Result<Box<dyn KeyStore>> {
+    platform::Store::open().map(|store| Box::new(store) as Box<dyn KeyStore>)
+}
+
+/// Key names are 1 to 64 ASCII letters, digits, `.`, `_` or `-`, not
+/// starting with `.`; they double as file names on Windows.
+pub fn validate_name(s: &str) -> Result<String, String> {
+    let valid = (1..=64).contains(&s.len())
+        && !s.starts_with('.')
+        && s.bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'.' | b'_' | b'-'));
+    if !valid {
+        return Err(format!(
+            "invalid key name `{}` (use up to 64 letters, digits, `.`, `_` or `-`)",
+            s
+        ));
+    }
+    Ok(s.to_string())
+}
+
+/// The stored form of a key.
+fn encode(key: &Key) -> String {
+    key.to_hex()
+}
+
+fn decode(name: &str, secret: &[u8]) -> io::Result<Key> {
+    std::str::from_utf8(secret)
+        .map_err(|e| e.to_string())
+        .and_then(|text| parse_key_hex(text.trim()))
+        .map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("stored key `{}` is damaged: {}", name, e),
+            )
+        })
+}
+
+fn not_found(name: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("no key named `{}`", name))
+}
+
+#[cfg(all(feature = "keyring", target_os = "linux"))]
+mod platform {
+    use super::{decode, encode, not_found, Key, KeyStore, SERVICE};
+    use secret_service::blocking::{Item, SecretService};
+    use secret_service::{EncryptionType, Error};
+    use std::collections::HashMap;
+    use std::io;
+
+    pub struct Store {
+        service: SecretService<'static>,
+    }
+
+    impl Store {
+        pub fn open() -> io::Result<Self> {
+            let service = SecretService::connect(EncryptionType::Dh).map_err(other)?;
+            Ok(Store { service })
+        }
+
+        /// The items for `name`, or for every key without one, unlocked.
+        fn items(&self, name: Option<&str>) -> io::Result<Vec<Item<'_>>> {
+            let mut attributes = HashMap::from([("application", SERVICE)]);
+            if let Some(name) = name {
+                attributes.insert("name", name);
+            }
+            let found = self.service.search_items(attributes).map_err(other)?;
+            for item in &found.locked {
+                item.unlock().map_err(other)?;
+            }
+            Ok(found.unlocked.into_iter().chain(found.locked).collect())
+        }
+    }
+
+    impl KeyStore for Store {
+        fn describe(&self) -> &'static str {
+            "the Secret Service"
+        }
+
+        fn store(&self, name: &str, key: &Key) -> io::Result<()> {
+            let collection = self.service.get_default_collection().map_err(other)?;
+            collection.ensure_unlocked().map_err(other)?;
+            let attributes = HashMap::from([("application", SERVICE), ("name", name)]);
+            collection
+                .create_item(
+                    &format!("{} key {}", SERVICE, name),
+                    attributes,
+                    encode(key).as_bytes(),
+                    true,
+                    "text/plain",
+                )
+                .map_err(other)?;
+            Ok(())
+        }
+
+        fn load(&self, name: &str) -> io::Result<Key> {
+            let items = self.items(Some(name))?;
+            let item = items.first().ok_or_else(|| not_found(name))?;
+            decode(name, &item.get_secret().map_err(other)?)
+        }
+
+        fn list(&self) -> io::Result<Vec<String>> {
+            let mut names = Vec::new();
+            for item in self.items(None)? {
+                let mut attributes = item.get_attributes().map_err(other)?;
+                names.extend(attributes.remove("name"));
+            }
+            Ok(names)
+        }
+
+        fn delete(&self, name: &str) -> io::Result<()> {
+            let items = self.items(Some(name))?;
+            if items.is_empty() {
+                return Err(not_found(name));
+            }
+            for item in items {
+                item.delete().map_err(other)?;
+            }
+            Ok(())
+        }
+    }
+
+    fn other(e: Error) -> io::Error {
+        io::Error::other(format!("Secret Service: {}", e))
+    }
+}
+
+#[cfg(all(feature = "keyring", target_os = "macos"))]
+mod platform {
+    use super::{decode, encode, not_found, Key, KeyStore, SERVICE};
+    use security_framework::base::Error;
+    use security_framework::item::{ItemClass, ItemSearchOptions, Limit};
+    use security_framework::passwords::{
+        delete_generic_password, get_generic_password, set_generic_password,
+    };
+    use std::io;
+
+    /// `errSecItemNotFound`
+    const ITEM_NOT_FOUND: i32 = -25300;
+
+    pub struct Store;
+
+    impl Store {
+        pub fn open() -> io::Result<Self> {
+            Ok(Store)
+        }
+    }
+
+    impl KeyStore for Store {
+        fn describe(&self) -> &'static str {
+            "the macOS Keychain"
+        }
+
+        fn store(&self, name: &str, key: &Key) -> io::Result<()> {
+            set_generic_password(SERVICE, name, encode(key).as_bytes()).map_err(|e| error(name, e))
+        }
+
+        fn load(&self, name: &str) -> io::Result<Key> {
+            let secret = get_generic_password(SERVICE, name).map_err(|e| error(name, e))?;
+            decode(name, &secret)
+        }
+
+        fn list(&self) -> io::Result<Vec<String>> {
+            let found = ItemSearchOptions::new()
+                .class(ItemClass::generic_password())
+                .service(SERVICE)
+                .load_attributes(true)
+                .limit(Limit::All)
+                .search();
+            match found {
+                Ok(results) => Ok(results
+                    .iter()
+                    .filter_map(|r| r.simplify_dict()?.remove("acct"))
+                    .collect()),
+                Err(e) if e.code() == ITEM_NOT_FOUND => Ok(Vec::new()),
+                Err(e) => Err(error("", e)),
+            }
+        }
+
+        fn delete(&self, name: &str) -> io::Result<()> {
+            delete_generic_password(SERVICE, name).map_err(|e| error(name, e))
+        }
+    }
+
+    fn error(name: &str, e: Error) -> io::Error {
+        match e.code() {
+            ITEM_NOT_FOUND => not_found(name),
+            _ => io::Error::other(format!("Keychain: {}", e)),
+        }
+    }
+}
+
+#[cfg(all(feature = "keyring", windows))]
+mod platform {
+    use super::{decode, encode, not_found, Key, KeyStore, SERVICE};
+    use std::io;
+    use std::path::PathBuf;
+    use std::ptr;
+    use windows_sys::Win32::Foundation::LocalFree;
+    use windows_sys::Win32::Security::Cryptography::{
+        CryptProtectData, CryptUnprotectData, CRYPTPROTECT_UI_FORBIDDEN, CRYPT_INTEGER_BLOB,
+    };
+
+    /// One DPAPI-protected file per key, readable only by this Windows user.
+    pub struct Store {
+        dir: PathBuf,
+    }
+
+    impl Store {
+        pub fn open() -> io::Result<Self> {
+            let base = std::env::var_os("LOCALAPPDATA").ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, "LOCALAPPDATA is not set")
+            })?;
+            Ok(Store {
+                dir: PathBuf::from(base).join(SERVICE).join("keys"),
+            })
+        }
+
+        fn path(&self, name: &str) -> PathBuf {
+            self.dir.join(format!("{}.dpapi", name))
+        }
+    }
+
+    impl KeyStore for Store {
+        fn describe(&self) -> &'static str {
+            "DPAPI-protected files"
+        }
+
+        fn store(&self, name: &str, key: &Key) -> io::Result<()> {
+            std::fs::create_dir_all(&self.dir)?;
+            std::fs::write(self.path(name), crypt(encode(key).as_bytes(), true)?)
+        }
+
+        fn load(&self, name: &str) -> io::Result<Key> {
+            let blob = std::fs::read(self.path(name)).map_err(|e| match e.kind() {
+                io::ErrorKind::NotFound => not_found(name),
+                _ => e,
+            })?;
+            decode(name, &crypt(&blob, false)?)
+        }
+
+        fn list(&self) -> io::Result<Vec<String>> {
+            let entries = match std::fs::read_dir(&self.dir) {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+                Err(e) => return Err(e),
+            };
+            let mut names = Vec::new();
+            for entry in entries {
+                let file_name = entry?.file_name();
+                let name = file_name.to_str().and_then(|n| n.strip_suffix(".dpapi"));
+                names.extend(name.map(str::to_string));
+            }
+            Ok(names)
+        }
+
+        fn delete(&self, name: &str) -> io::Result<()> {
+            std::fs::remove_file(self.path(name)).map_err(|e| match e.kind() {
+                io::ErrorKind::NotFound => not_found(name),
+                _ => e,
+            })
+        }
+    }
+
+    /// Protects (`protect`) or unprotects `data` with the user's DPAPI key.
+    fn crypt(data: &[u8], protect: bool) -> io::Result<Vec<u8>> {
+        let input = CRYPT_INTEGER_BLOB {
+            cbData: data.len() as u32,
+            pbData: data.as_ptr() as *mut u8,
+        };
+        let mut output = CRYPT_INTEGER_BLOB {
+            cbData: 0,
+            pbData: ptr::null_mut(),
+        };
+        // SAFETY: `input` points to `data`, which DPAPI only reads, and on
+        // success `output` is a LocalAlloc'ed buffer that we copy and free.
+        unsafe {
+            let ok = if protect {
+                CryptProtectData(
+                    &input,
+                    ptr::null(),
+                    ptr::null(),
+                    ptr::null(),
+                    ptr::null(),
+                    CRYPTPROTECT_UI_FORBIDDEN,
+                    &mut output,
+                )
+            } else {
+                CryptUnprotectData(
+                    &input,
+                    ptr::null_mut(),
+                    ptr::null(),
+                    ptr::null(),
+                    ptr::null(),
+                    CRYPTPROTECT_UI_FORBIDDEN,
+                    &mut output,
+                )
+            };
+            if ok == 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let out = std::slice::from_raw_parts(output.pbData, output.cbData as usize).to_vec();
+            LocalFree(output.pbData.cast());
+            Ok(out)
+        }
+    }
+}
+
+#[cfg(not(all(
+    feature = "keyring",
+    any(target_os = "linux", target_os = "macos", windows)
+)))]
+mod platform {
+    use super::{Key, KeyStore};
+    use std::io;
+
+    pub enum Store {}
+
+    impl Store {
+        pub fn open() -> io::Result<Self> {
+            Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                match cfg!(feature = "keyring") {
+                    true => "no key store is supported on this platform",
+                    false => "this build has no key store (built without the `keyring` feature)",
+                },
+            ))
+        }
+    }
+
+    impl KeyStore for Store {
+        fn describe(&self) -> &'static str {
+            match *self {}
+        }
+
+        fn store(&self, _: &str, _: &Key) -> io::Result<()> {
+            match *self {}
+        }
+
+        fn load(&self, _: &str) -> io::Result<Key> {
+            match *self {}
+        }
+
+        fn list(&self) -> io::Result<Vec<String>> {
+            match *self {}
+        }
+
+        fn delete(&self, _: &str) -> io::Result<()> {
+            match *self {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_and_keys_are_checked() {
+        assert_eq!(validate_name("prod-2026.q3").unwrap(), "prod-2026.q3");
+        for bad in ["", ".hidden", "a/b", "a b", &"x".repeat(65)] {
+            assert!(validate_name(bad).is_err(), "{:?}", bad);
+        }
+        for key in [Key::from([0xa5; 16]), Key::from([0x5a; 32])] {
+            assert_eq!(decode("k", encode(&key).as_bytes()).unwrap(), key);
+        }
+        assert!(decode("k", b"zz").is_err());
+    }
+
+    #[test]
+    fn stored_keys_are_lowercase_hex() {
+        assert!(validate_name(&"x".repeat(64)).is_ok());
+        let key = Key::from(std::array::from_fn::<u8, 16, _>(|i| (i * 17) as u8));
+        let stored = encode(&key);
+        assert_eq!(stored, "00112233445566778899aabbccddeeff");
+        // Tools that write the secret by hand often leave a newline
+        assert_eq!(
+            decode("k", format!("{}\n", stored).as_bytes()).unwrap(),
+            key
+        );
+        for damaged in [&stored.as_bytes()[..30], b"\xff\xfe", b""] {
+            let e = decode("ci", damaged).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+            assert!(
+                e.to_string().contains("stored key `ci` is damaged"),
+                "{}",
+                e
+            );
+        }
+        let e = not_found("ci");
+        assert_eq!(
+            (e.kind(), e.to_string()),
+            (io::ErrorKind::NotFound, "no key named `ci`".into())
+        );
+    }
+
+    #[cfg(not(feature = "keyring"))]
+    #[test]
+    fn builds_without_the_feature_have_no_store() {
+        let e = open().err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::Unsupported);
+        assert!(e.to_string().contains("`keyring` feature"), "{}", e);
+    }
+}
@@ -0,0 +1,464 @@
+//! The keystream (AES-128-CTR, AES-256-CTR or ChaCha20) and how it is laid
+//! out across chunks.
+//!
+//! Each cipher is a [`KeystreamBackend`]: given a 64-bit nonce and a byte
+//! offset it produces that stretch of keystream. The IV strategy decides the
+//! nonce and offset for a chunk, so every backend shares the same layout.
+
+use aes::{Aes128, Aes256};
+use chacha20::ChaCha20Legacy;
+use ctr::cipher::{KeyIvInit, StreamCipher, StreamCipherSeek};
+use ctr::Ctr128BE;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::Key;
+
+/// The stream cipher producing the keystream.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Cipher {
+    /// AES-128 in CTR mode. Fastest where the CPU has AES instructions, but
+    /// several times slower than ChaCha20 in software.
+    #[default]
+    Aes128,
+    /// AES-256 in CTR mode, for 256-bit keys. About 40% slower than
+    /// AES-128.
+    Aes256,
+    /// ChaCha20 with a 64-bit nonce and 64-bit block counter. Fast
+    /// everywhere, no hardware needed. A 256-bit key is used as it is; a
+    /// 128-bit one is stretched to ChaCha20's width by SHA-256, which adds
+    /// no strength: that keystream is no stronger than AES-128's.
+    ChaCha20,
+}
+
+impl Cipher {
+    pub const ALL: [Cipher; 3] = [Cipher::Aes128, Cipher::Aes256, Cipher::ChaCha20];
+
+    /// The cipher named `s`, as [`fmt::Display`] writes it.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        Cipher::ALL
+            .into_iter()
+            .find(|c| c.to_string() == s)
+            .ok_or_else(|| format!("unknown cipher `{}`", s))
+    }
+
+    /// The implementation of this cipher.
+    pub fn backend(self) -> &'static dyn KeystreamBackend {
+        match self {
+            Cipher::Aes128 => &Aes128Ctr,
+            Cipher::Aes256 => &Aes256Ctr,
+            Cipher::ChaCha20 => &ChaCha20,
+        }
+    }
+
+    /// Bytes of a fresh key for this cipher: its native width.
+    pub fn key_len(self) -> usize {
+        match self {
+            Cipher::Aes128 => 16,
+            Cipher::Aes256 | Cipher::ChaCha20 => 32,
+        }
+    }
+
+    /// Whether this cipher can run under `key`: the AES variants take keys
+    /// of their own width only, ChaCha20 either width.
+    pub fn accepts(self, key: &Key) -> bool {
+        match self {
+            Cipher::Aes128 => key.narrow().is_some(),
+            Cipher::Aes256 => key.wide().is_some(),
+            Cipher::ChaCha20 => true,
+        }
+    }
+
+    /// The AES variant that takes `key`, for a cipher picked before the key
+    /// was known; ChaCha20 stays as it is.
+    pub fn for_key(self, key: &Key) -> Cipher {
+        match self {
+            Cipher::Aes128 | Cipher::Aes256 if key.wide().is_some() => Cipher::Aes256,
+            Cipher::Aes128 | Cipher::Aes256 => Cipher::Aes128,
+            Cipher::ChaCha20 => Cipher::ChaCha20,
+        }
+    }
+
+    /// Keystream bytes per second this cipher sustains on the current CPU,
+    /// measured single-threaded for about `budget`.
+    pub fn throughput(self, budget: Duration) -> f64 {
+        let mut buf = vec![0u8; 64 * 1024];
+        let key = Key::from_slice(&[0; 32][..self.key_len()]).expect("a key length");
+        let start = Instant::now();
+        let mut bytes = 0u64;
+        loop {
+            apply_chunk_keystream(&key, self, IvStrategy::Continuous, 0, bytes, &mut buf);
+            bytes += buf.len() as u64;
+            let elapsed = start.elapsed();
+            if elapsed >= budget {
+                return bytes as f64 / elapsed.as_secs_f64();
+            }
+        }
+    }
+
+    /// The throughput of every cipher, fastest first, splitting `budget`
+    /// between them.
+    pub fn calibrate(budget: Duration) -> Vec<(Cipher, f64)> {
+        let each = budget / Cipher::ALL.len() as u32;
+        let mut table: Vec<(Cipher, f64)> = Cipher::ALL
+            .into_iter()
+            .map(|c| (c, c.throughput(each)))
+            .collect();
+        table.sort_by(|a, b| b.1.total_cmp(&a.1));
+        table
+    }
+
+    /// The faster of AES-128 and ChaCha20 on this CPU, splitting `budget`
+    /// between them. AES-256 is a choice of key width, never the faster.
+    pub fn fastest(budget: Duration) -> Cipher {
+        let candidates = [Cipher::Aes128, Cipher::ChaCha20];
+        let each = budget / candidates.len() as u32;
+        candidates
+            .into_iter()
+            .map(|c| (c, c.throughput(each)))
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .map_or(Cipher::default(), |(c, _)| c)
+    }
+}
+
+impl fmt::Display for Cipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Cipher::Aes128 => "aes128",
+            Cipher::Aes256 => "aes256",
+            Cipher::ChaCha20 => "chacha20",
+        })
+    }
+}
+
+/// Whether the CPU has AES instructions the `aes` crate will use. Without
+/// them AES runs as (constant-time) software, roughly 10x slower.
+pub fn aes_hardware_available() -> bool {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        std::arch::is_x86_feature_detected!("aes") && std::arch::is_x86_feature_detected!("sse2")
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        std::arch::is_aarch64_feature_detected!("aes")
+    }
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
+    {
+        false
+    }
+}
+
+/// The instruction set extensions the ciphers can make use of that the
+/// CPU has, by their `target_feature` names.
+pub fn cpu_features() -> Vec<&'static str> {
+    // Nothing is detected on other architectures
+    #[allow(unused_mut)]
+    let mut features = Vec::new();
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        macro_rules! detect {
+            ($($feature:tt),*) => {
+                $(if std::arch::is_x86_feature_detected!($feature) {
+                    features.push($feature);
+                })*
+            };
+        }
+        detect!(
+            "sse2",
+            "ssse3",
+            "sse4.1",
+            "avx",
+            "avx2",
+            "avx512f",
+            "aes",
+            "pclmulqdq",
+            "vaes"
+        );
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        macro_rules! detect {
+            ($($feature:tt),*) => {
+                $(if std::arch::is_aarch64_feature_detected!($feature) {
+                    features.push($feature);
+                })*
+            };
+        }
+        detect!("neon", "aes", "pmull", "sha2");
+    }
+    features
+}
+
+/// How each chunk's counter block (or ChaCha20 nonce) is derived.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IvStrategy {
+    /// Every chunk starts its own counter run from an IV holding the chunk
+    /// index little-endian in bytes 8..16. This is the original layout: each
+    /// chunk is independent, but output depends on the chunk size. ChaCha20
+    /// uses the chunk index as its nonce instead.
+    #[default]
+    ChunkIndex,
+    /// The whole run is one continuous counter run starting at zero, and a
+    /// chunk just seeks to its byte offset. Output is independent of the
+    /// chunk size.
+    Continuous,
+}
+
+impl IvStrategy {
+    pub const ALL: [IvStrategy; 2] = [IvStrategy::ChunkIndex, IvStrategy::Continuous];
+}
+
+impl fmt::Display for IvStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            IvStrategy::ChunkIndex => "chunk-index",
+            IvStrategy::Continuous => "continuous",
+        })
+    }
+}
+
+/// A stream cipher whose keystream can be entered at any point.
+pub trait KeystreamBackend: Sync {
+    /// XORs into `out` the keystream under `key` and `nonce`, starting
+    /// `offset` bytes in. `key` is of a width the cipher
+    /// [accepts](Cipher::accepts); the generator checks that when it is
+    /// built.
+    fn apply_keystream(&self, key: &Key, nonce: u64, offset: u64, out: &mut [u8]);
+}
+
+/// AES-128-CTR with a big-endian counter; the IV is laid out by
+/// [`nonce_iv`].
+struct Aes128Ctr;
+
+impl KeystreamBackend for Aes128Ctr {
+    fn apply_keystream(&self, key: &Key, nonce: u64, offset: u64, out: &mut [u8]) {
+        let key = key.narrow().expect("aes128 takes a 128-bit key");
+        let mut cipher = Ctr128BE::<Aes128>::new(key.into(), &nonce_iv(nonce).into());
+        cipher.seek(offset);
+        cipher.apply_keystream(out);
+    }
+}
+
+/// AES-256-CTR, with the counter block laid out as for [`Aes128Ctr`].
+struct Aes256Ctr;
+
+impl KeystreamBackend for Aes256Ctr {
+    fn apply_keystream(&self, key: &Key, nonce: u64, offset: u64, out: &mut [u8]) {
+        let key = key.wide().expect("aes256 takes a 256-bit key");
+        let mut cipher = Ctr128BE::<Aes256>::new(key.into(), &nonce_iv(nonce).into());
+        cipher.seek(offset);
+        cipher.apply_keystream(out);
+    }
+}
+
+/// ChaCha20 with the original 64-bit nonce.
+struct ChaCha20;
+
+impl KeystreamBackend for ChaCha20 {
+    fn apply_keystream(&self, key: &Key, nonce: u64, offset: u64, out: &mut [u8]) {
+        let wide_key = match (key.wide(), key.narrow()) {
+            (Some(wide), _) => *wide,
+            (None, Some(narrow)) => widen_key(b"mass_password_gen chacha20 key", narrow),
+            (None, None) => unreachable!("keys are 128 or 256 bits"),
+        };
+        let mut cipher = ChaCha20Legacy::new(&wide_key.into(), &nonce.to_le_bytes().into());
+        cipher.seek(offset);
+        cipher.apply_keystream(out);
+    }
+}
+
+/// The counter block a nonce starts from: its low 63 bits little-endian in
+/// bytes 8..16, and its top bit as the top bit of byte 0. Byte 15 is where
+/// the counter moves fastest, so a flag there would only start the stream a
+/// few blocks into another nonce's; up in byte 0 no counter run reaches it.
+fn nonce_iv(nonce: u64) -> [u8; 16] {
+    let mut iv = [0u8; 16];
+    iv[8..16].copy_from_slice(&(nonce & !(1 << 63)).to_le_bytes());
+    iv[0] = ((nonce >> 63) as u8) << 7;
+    iv
+}
+
+/// Stretches a 16-byte key for ChaCha20, which wants 256 bits, rather than
+/// zero-padding it.
+fn widen_key(label: &[u8], key: &[u8; 16]) -> [u8; 32] {
+    Sha256::new()
+        .chain_update(label)
+        .chain_update(key)
+        .finalize()
+        .into()
+}
+
+/// XORs the keystream for one chunk into `out`; callers pass a zeroed
+/// buffer, so `out` ends up holding the raw keystream.
+///
+/// `byte_offset` is where the chunk starts within the whole run's keystream;
+/// only [`IvStrategy::Continuous`] uses it.
+pub(crate) fn apply_chunk_keystream(
+    key: &Key,
+    cipher: Cipher,
+    strategy: IvStrategy,
+    chunk_idx: usize,
+    byte_offset: u64,
+    out: &mut [u8],
+) {
+    let (nonce, offset) = match strategy {
+        // Each chunk is its own counter run, selected by its index
+        IvStrategy::ChunkIndex => (chunk_idx as u64, 0),
+        IvStrategy::Continuous => (0, byte_offset),
+    };
+    cipher.backend().apply_keystream(key, nonce, offset, out);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aes::cipher::{BlockEncrypt, KeyInit};
+
+    const KEY: [u8; 16] = [
+        0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e,
+        0x0f,
+    ];
+
+    const WIDE_KEY: [u8; 32] = [
+        0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e,
+        0x0f, 0x10, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17, 0x18, 0x19, 0x1a, 0x1b, 0x1c, 0x1d,
+        0x1e, 0x1f,
+    ];
+
+    /// AES-128 of a single counter block, computed without CTR mode.
+    fn aes_block(counter: u128) -> [u8; 16] {
+        let mut block = counter.to_be_bytes().into();
+        Aes128::new(&KEY.into()).encrypt_block(&mut block);
+        block.into()
+    }
+
+    fn keystream(strategy: IvStrategy, chunk_idx: usize, offset: u64, len: usize) -> Vec<u8> {
+        cipher_keystream(Cipher::Aes128, strategy, chunk_idx, offset, len)
+    }
+
+    fn cipher_keystream(
+        cipher: Cipher,
+        strategy: IvStrategy,
+        chunk_idx: usize,
+        offset: u64,
+        len: usize,
+    ) -> Vec<u8> {
+        let key = match cipher {
+            Cipher::Aes256 => Key::from(WIDE_KEY),
+            _ => Key::from(KEY),
+        };
+        let mut out = vec![0u8; len];
+        apply_chunk_keystream(&key, cipher, strategy, chunk_idx, offset, &mut out);
+        out
+    }
+
+    #[test]
+    fn calibration_times_every_cipher() {
+        let table = Cipher::calibrate(Duration::from_millis(3));
+        assert_eq!(table.len(), Cipher::ALL.len());
+        assert!(Cipher::ALL
+            .iter()
+            .all(|c| table.iter().any(|(t, _)| t == c)));
+        assert!(table.windows(2).all(|w| w[0].1 >= w[1].1 && w[1].1 > 0.0));
+    }
+
+    #[test]
+    fn chunk_zero_starts_at_counter_zero() {
+        // AES-128(000102..0f, 0^128), a well-known vector
+        let expected = [
+            0xc6, 0xa1, 0x3b, 0x37, 0x87, 0x8f, 0x5b, 0x82, 0x6f, 0x4f, 0x81, 0x62, 0xa1, 0xc8,
+            0xd8, 0x79,
+        ];
+        assert_eq!(keystream(IvStrategy::ChunkIndex, 0, 0, 16), expected);
+        assert_eq!(keystream(IvStrategy::Continuous, 0, 0, 16), expected);
+    }
+
+    #[test]
+    fn chunk_index_iv_is_little_endian_in_low_half() {
+        // Chunk 0x0201 => IV bytes 8 and 9 are 0x01, 0x02
+        let base = 0x0102u128 << 48;
+        let out = keystream(IvStrategy::ChunkIndex, 0x0201, 0, 48);
+        for (i, block) in out.chunks_exact(16).enumerate() {
+            assert_eq!(block, aes_block(base + i as u128));
+        }
+    }
+
+    #[test]
+    fn continuous_seeks_to_byte_offset() {
+        let whole = keystream(IvStrategy::Continuous, 0, 0, 160);
+        // Offsets need not be block aligned
+        let tail = keystream(IvStrategy::Continuous, 7, 37, 123);
+        assert_eq!(tail, whole[37..]);
+        assert_eq!(&whole[16..32], aes_block(1));
+    }
+
+    #[test]
+    fn other_ciphers_seek_and_separate_chunks() {
+        for c in [Cipher::Aes256, Cipher::ChaCha20] {
+            let whole = cipher_keystream(c, IvStrategy::Continuous, 0, 0, 160);
+            assert_eq!(
+                cipher_keystream(c, IvStrategy::Continuous, 3, 37, 123),
+                whole[37..]
+            );
+            assert_ne!(whole, keystream(IvStrategy::Continuous, 0, 0, 160));
+            // Each chunk gets its own nonce
+            assert_ne!(
+                cipher_keystream(c, IvStrategy::ChunkIndex, 0, 0, 64),
+                cipher_keystream(c, IvStrategy::ChunkIndex, 1, 0, 64)
+            );
+        }
+    }
+
+    #[test]
+    fn aes256_runs_on_the_whole_key() {
+        // FIPS-197 C.3: AES-256(000102..1f, 00112233..ff)
+        let mut block = 0x00112233_44556677_8899aabb_ccddeeffu128
+            .to_be_bytes()
+            .into();
+        Aes256::new(&WIDE_KEY.into()).encrypt_block(&mut block);
+        assert_eq!(
+            block[..],
+            [
+                0x8e, 0xa2, 0xb7, 0xca, 0x51, 0x67, 0x45, 0xbf, 0xea, 0xfc, 0x49, 0x90, 0x4b, 0x49,
+                0x60, 0x89
+            ]
+        );
+        let mut first = [0u8; 16];
+        Aes256::new(&WIDE_KEY.into()).encrypt_block((&mut first).into());
+        assert_eq!(
+            cipher_keystream(Cipher::Aes256, IvStrategy::ChunkIndex, 0, 0, 16),
+            first
+        );
+    }
+
+    #[test]
+    fn ciphers_take_the_keys_they_can() {
+        let (narrow, wide) = (Key::from(KEY), Key::from(WIDE_KEY));
+        assert!(Cipher::Aes128.accepts(&narrow) && !Cipher::Aes128.accepts(&wide));
+        assert!(Cipher::Aes256.accepts(&wide) && !Cipher::Aes256.accepts(&narrow));
+        assert!(Cipher::ChaCha20.accepts(&narrow) && Cipher::ChaCha20.accepts(&wide));
+        assert_eq!(Cipher::Aes128.for_key(&wide), Cipher::Aes256);
+        assert_eq!(Cipher::Aes256.for_key(&narrow), Cipher::Aes128);
+        assert_eq!(Cipher::ChaCha20.for_key(&wide), Cipher::ChaCha20);
+        for c in Cipher::ALL {
+            let fresh = Key::from_slice(&[1; 32][..c.key_len()]).unwrap();
+            assert!(c.accepts(&fresh), "{}", c);
+        }
+
+        // A wide ChaCha20 key is used directly, not stretched
+        let mut direct = [0u8; 64];
+        ChaCha20Legacy::new(&WIDE_KEY.into(), &[0; 8].into()).apply_keystream(&mut direct);
+        let mut out = [0u8; 64];
+        ChaCha20.apply_keystream(&wide, 0, 0, &mut out);
+        assert_eq!(out, direct);
+    }
+
+    #[test]
+    fn ciphers_parse_by_name() {
+        for c in Cipher::ALL {
+            assert_eq!(Cipher::parse(&c.to_string()), Ok(c));
+        }
+        assert!(Cipher::parse("rc4").is_err());
+    }
+}
@@ -0,0 +1,124 @@
+//! Lanes: independent subkeys of one key.
+//!
+//! A run's key drives more than its records. Token MACs, hash salts,
+//! timestamp seeds, segment keys and the fields of a schema each draw on
+//! their own lane: a subkey derived from the key by HKDF-SHA256 with the
+//! lane's label as the info string. Different labels give independent
+//! subkeys, so nothing computed in one lane is valid in another, and a
+//! subkey reveals neither the key nor anything generated under it.
+//!
+//! ```
+//! use mass_password_gen_optimized::{Key, Lane};
+//!
+//! let key = Key::from([0x13; 16]);
+//! let field = Lane::new("field/api_key").key(&key);
+//! assert_eq!(field.bits(), 128);
+//! assert_ne!(field, key);
+//! assert_ne!(field, Lane::new("field/totp").key(&key));
+//! ```
+
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::Key;
+
+/// Start of every lane's HKDF info; the label follows.
+const INFO: &[u8] = b"mass_password_gen_optimized/lane/";
+
+/// Bytes of a subkey from [`Lane::subkey`].
+pub const SUBKEY_LEN: usize = 32;
+
+/// One use of a key, named by its label.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Lane<'a>(&'a str);
+
+impl Lane<'static> {
+    /// MACs of `--format expiring-token`
+    pub const TOKEN: Self = Lane("expiring-token");
+    /// Checksums of a reference file, under the reference key
+    pub const REFERENCE: Self = Lane("reference");
+    /// Salts of `--emit-hash` and `--format hashcat`
+    pub const HASH_SALT: Self = Lane("hash-salt");
+    /// The seed of `--timestamp-spread`
+    pub const TIMESTAMPS: Self = Lane("timestamps");
+    /// Replacements for records a filter turns down (see
+    /// [`resample`](crate::resample))
+    pub const RESAMPLE: Self = Lane("resample");
+}
+
+impl<'a> Lane<'a> {
+    pub fn new(label: &'a str) -> Self {
+        Lane(label)
+    }
+
+    pub fn label(&self) -> &'a str {
+        self.0
+    }
+
+    /// Fills `out`, at most 8160 bytes, with this lane's subkey of `key`.
+    pub fn fill(&self, key: &[u8], out: &mut [u8]) {
+        Hkdf::<Sha256>::new(None, key)
+            .expand_multi_info(&[INFO, self.0.as_bytes()], out)
+            .expect("HKDF-SHA256 gives up to 8160 bytes");
+    }
+
+    /// This lane's 256-bit subkey of `key`.
+    pub fn subkey(&self, key: &[u8]) -> [u8; SUBKEY_LEN] {
+        let mut subkey = [0; SUBKEY_LEN];
+        self.fill(key, &mut subkey);
+        subkey
+    }
+
+    /// This lane's subkey of `key` as a key of the same width, for
+    /// generating records of its own.
+    pub fn key(&self, key: &Key) -> Key {
+        let mut subkey = [0; 32];
+        self.fill(key, &mut subkey[..key.len()]);
+        Key::from_slice(&subkey[..key.len()]).expect("a key length")
+    }
+
+    /// HMAC-SHA256 keyed with this lane's subkey of `key`.
+    pub fn mac(&self, key: &[u8]) -> Hmac<Sha256> {
+        Hmac::new_from_slice(&self.subkey(key)).expect("HMAC accepts any key length")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lanes_are_independent() {
+        let key = Key::from([7; 16]);
+        let lanes = [
+            Lane::TOKEN,
+            Lane::REFERENCE,
+            Lane::HASH_SALT,
+            Lane::TIMESTAMPS,
+        ];
+        let subkeys: std::collections::HashSet<_> =
+            lanes.iter().map(|lane| lane.subkey(&key)).collect();
+        assert_eq!(subkeys.len(), lanes.len());
+        assert!(subkeys.iter().all(|subkey| subkey[..16] != key[..]));
+        // The same lane of another key, or of a wider one, differs too
+        assert_ne!(Lane::TOKEN.subkey(&[8; 16]), Lane::TOKEN.subkey(&key));
+        assert_ne!(Lane::TOKEN.subkey(&[7; 32]), Lane::TOKEN.subkey(&key));
+        // Labels count whole, not by prefix
+        assert_ne!(Lane::new("a").subkey(&key), Lane::new("ab").subkey(&key));
+
+        // Keys keep their width, and are a prefix of the lane's output
+        let wide = Key::from([7; 32]);
+        assert_eq!(Lane::new("x").key(&wide).bits(), 256);
+        assert_eq!(
+            Lane::new("x").key(&key)[..],
+            Lane::new("x").subkey(&key)[..16]
+        );
+        assert_eq!(Lane::new("x").key(&wide)[..], Lane::new("x").subkey(&wide));
+
+        let tag = |mac: Hmac<Sha256>| mac.chain_update(b"message").finalize().into_bytes();
+        let direct = Hmac::<Sha256>::new_from_slice(&Lane::TOKEN.subkey(&key)).unwrap();
+        assert_eq!(tag(Lane::TOKEN.mac(&key)), tag(direct));
+        assert_ne!(tag(Lane::TOKEN.mac(&key)), tag(Lane::REFERENCE.mac(&key)));
+    }
+}
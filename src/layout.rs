@@ -0,0 +1,385 @@
+//! Padded layouts of raw binary records, for consumers that map the output
+//! and want every record on an aligned boundary.
+//!
+//! Raw records are 16-byte keystream blocks written back to back. A
+//! [`RawLayout`] writes each one at the start of a slot of `stride` bytes,
+//! the width rounded up to the alignment, and fills the rest of the slot
+//! with zeros or with the record's index: record `i` then starts at byte
+//! `i * stride`. The layout goes into the manifest, so that the corpus can
+//! be read back and checked, filler included.
+//!
+//! Output can also start with a header, for readers that know nothing of
+//! this crate. Its numbers are in the layout's [`ByteOrder`]:
+//!
+//! ```text
+//! 0   6  magic "MPGRAW"
+//! 6   1  version, 1
+//! 7   1  byte order, 'l' or 'b'
+//! 8   4  header length: where the first record starts
+//! 12  4  record width
+//! 16  4  stride
+//! 20  4  filler: 0 for zeros, 1 for the record's index
+//! 24  8  record count
+//! ```
+//!
+//! The header is padded with zeros to a multiple of the stride, so records
+//! stay aligned from the start of the file. Records themselves are
+//! keystream bytes rather than numbers, and read the same either way; the
+//! byte order is that of the header and of index filler.
+
+use serde::{Deserialize, Serialize};
+
+use crate::format::OutputSink;
+
+/// Starts a raw output [header](RawLayout::header).
+pub const HEADER_MAGIC: &[u8; 6] = b"MPGRAW";
+
+/// Header version written and understood by this crate.
+pub const HEADER_VERSION: u8 = 1;
+
+/// Bytes of a header before its padding.
+pub const HEADER_LEN: usize = 32;
+
+/// What fills a slot after its record.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Fill {
+    /// Zero bytes
+    #[default]
+    Zero,
+    /// The record's index as a u64, repeated (and cut short) to fill the
+    /// slot, so a slot names its own record
+    Index,
+}
+
+/// Order of the bytes of the numbers a layout writes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ByteOrder {
+    #[default]
+    Le,
+    Be,
+}
+
+impl ByteOrder {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s {
+            "le" => Ok(ByteOrder::Le),
+            "be" => Ok(ByteOrder::Be),
+            _ => Err(format!("unknown byte order `{}` (expected le or be)", s)),
+        }
+    }
+
+    fn u32(self, n: usize) -> [u8; 4] {
+        match self {
+            ByteOrder::Le => (n as u32).to_le_bytes(),
+            ByteOrder::Be => (n as u32).to_be_bytes(),
+        }
+    }
+
+    fn u64(self, n: usize) -> [u8; 8] {
+        match self {
+            ByteOrder::Le => (n as u64).to_le_bytes(),
+            ByteOrder::Be => (n as u64).to_be_bytes(),
+        }
+    }
+
+    fn read_u32(self, bytes: &[u8]) -> usize {
+        let bytes = bytes.try_into().expect("4 bytes");
+        match self {
+            ByteOrder::Le => u32::from_le_bytes(bytes) as usize,
+            ByteOrder::Be => u32::from_be_bytes(bytes) as usize,
+        }
+    }
+
+    fn read_u64(self, bytes: &[u8]) -> usize {
+        let bytes = bytes.try_into().expect("8 bytes");
+        match self {
+            ByteOrder::Le => u64::from_le_bytes(bytes) as usize,
+            ByteOrder::Be => u64::from_be_bytes(bytes) as usize,
+        }
+    }
+}
+
+impl Fill {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s {
+            "zero" => Ok(Fill::Zero),
+            "index" => Ok(Fill::Index),
+            _ => Err(format!("unknown fill `{}` (expected zero or index)", s)),
+        }
+    }
+}
+
+/// Where raw records sit in the output.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RawLayout {
+    /// Bytes of each record
+    pub width: usize,
+    /// Bytes from the start of one record to the next
+    pub stride: usize,
+    pub fill: Fill,
+    /// Byte order of index filler and of the header
+    #[serde(default)]
+    pub order: ByteOrder,
+    /// Whether the output starts with a [header](Self::header)
+    #[serde(default)]
+    pub header: bool,
+}
+
+impl RawLayout {
+    /// Records of `width` bytes, each in a slot of `width` rounded up to a
+    /// multiple of `align`, a power of two.
+    pub fn aligned(width: usize, align: usize, fill: Fill) -> Result<Self, String> {
+        if !align.is_power_of_two() {
+            return Err(format!("record alignment {} is not a power of two", align));
+        }
+        Ok(RawLayout {
+            width,
+            stride: width.next_multiple_of(align),
+            fill,
+            order: ByteOrder::default(),
+            header: false,
+        })
+    }
+
+    /// Where the first record starts: past the header, if there is one.
+    pub fn header_len(&self) -> usize {
+        match self.header {
+            true => HEADER_LEN.next_multiple_of(self.stride),
+            false => 0,
+        }
+    }
+
+    /// The header of output of `count` records in this layout, or nothing
+    /// without one.
+    pub fn header(&self, count: usize) -> Vec<u8> {
+        if !self.header {
+            return Vec::new();
+        }
+        let order = self.order;
+        let mut out = Vec::with_capacity(self.header_len());
+        out.extend_from_slice(HEADER_MAGIC);
+        out.push(HEADER_VERSION);
+        out.push(match order {
+            ByteOrder::Le => b'l',
+            ByteOrder::Be => b'b',
+        });
+        out.extend_from_slice(&order.u32(self.header_len()));
+        out.extend_from_slice(&order.u32(self.width));
+        out.extend_from_slice(&order.u32(self.stride));
+        let fill = match self.fill {
+            Fill::Zero => 0,
+            Fill::Index => 1,
+        };
+        out.extend_from_slice(&order.u32(fill));
+        out.extend_from_slice(&order.u64(count));
+        out.resize(self.header_len(), 0);
+        out
+    }
+
+    /// Reads the layout and record count from `head`, the first bytes of a
+    /// file, if it starts with a header.
+    pub fn from_header(head: &[u8]) -> Result<Option<(Self, usize)>, String> {
+        if !head.starts_with(HEADER_MAGIC) {
+            return Ok(None);
+        }
+        if head.len() < HEADER_LEN {
+            return Err("the header is cut short".into());
+        }
+        if head[6] != HEADER_VERSION {
+            return Err(format!(
+                "header version {} is not supported (expected {})",
+                head[6], HEADER_VERSION
+            ));
+        }
+        let order = match head[7] {
+            b'l' => ByteOrder::Le,
+            b'b' => ByteOrder::Be,
+            other => {
+                return Err(format!(
+                    "unknown byte order {:?} in the header",
+                    other as char
+                ))
+            }
+        };
+        let fill = match order.read_u32(&head[20..24]) {
+            0 => Fill::Zero,
+            1 => Fill::Index,
+            other => return Err(format!("unknown filler {} in the header", other)),
+        };
+        let layout = RawLayout {
+            width: order.read_u32(&head[12..16]),
+            stride: order.read_u32(&head[16..20]),
+            fill,
+            order,
+            header: true,
+        };
+        if layout.width == 0 || layout.stride < layout.width {
+            return Err("the header's record width and stride do not fit".into());
+        }
+        if order.read_u32(&head[8..12]) != layout.header_len() {
+            return Err("the header's length does not match its stride".into());
+        }
+        Ok(Some((layout, order.read_u64(&head[24..32]))))
+    }
+
+    /// Appends the filler of record `index`'s slot to `out`.
+    pub fn pad(&self, index: usize, out: &mut Vec<u8>) {
+        let pad = self.stride.saturating_sub(self.width);
+        match self.fill {
+            Fill::Zero => out.resize(out.len() + pad, 0),
+            Fill::Index => out.extend(self.order.u64(index).iter().cycle().take(pad)),
+        }
+    }
+
+    /// Checks the slot `slot` read back for record `index`: its width, and
+    /// the filler after the record, which it returns without.
+    pub fn record<'a>(&self, index: usize, slot: &'a [u8]) -> Result<&'a [u8], String> {
+        if slot.len() != self.stride {
+            return Err("truncated".into());
+        }
+        let (record, filler) = slot.split_at(self.width);
+        let mut expected = Vec::with_capacity(filler.len());
+        self.pad(index, &mut expected);
+        match filler == expected {
+            true => Ok(record),
+            false => Err("the padding after the record does not match the layout".into()),
+        }
+    }
+}
+
+/// Sink writing raw records in a [`RawLayout`].
+pub struct PaddedSink {
+    layout: RawLayout,
+    header: Vec<u8>,
+}
+
+impl PaddedSink {
+    /// Writes output of `count` records in `layout`.
+    pub fn new(layout: RawLayout, count: usize) -> Self {
+        PaddedSink {
+            header: layout.header(count),
+            layout,
+        }
+    }
+}
+
+impl OutputSink for PaddedSink {
+    fn header(&self) -> &[u8] {
+        &self.header
+    }
+
+    fn write_record(&self, index: usize, record: &[u8], out: &mut Vec<u8>) {
+        out.extend_from_slice(record);
+        self.layout.pad(index, out);
+    }
+
+    fn record_len(&self, _: usize, _: usize) -> Option<usize> {
+        Some(self.layout.stride)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slots_are_aligned_and_filled() {
+        assert!(RawLayout::aligned(16, 48, Fill::Zero).is_err());
+        let narrow = RawLayout::aligned(16, 8, Fill::Zero).unwrap();
+        assert_eq!(narrow.stride, 16);
+
+        let layout = RawLayout::aligned(16, 32, Fill::Index).unwrap();
+        let sink = PaddedSink::new(layout.clone(), 1);
+        let mut out = Vec::new();
+        sink.write_record(0x0102, &[0xaa; 16], &mut out);
+        assert_eq!(out.len(), sink.record_len(0x0102, 16).unwrap());
+        assert_eq!(&out[16..24], &[2, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(out[16..24], out[24..32]);
+        assert_eq!(layout.record(0x0102, &out), Ok(&[0xaa; 16][..]));
+        assert!(layout.record(0x0103, &out).is_err());
+        assert!(layout.record(0x0102, &out[..20]).is_err());
+    }
+
+    #[test]
+    fn padded_records_read_back() {
+        use crate::{CorpusReader, Format, PasswordGenerator};
+
+        let g = PasswordGenerator::builder()
+            .key([9; 16])
+            .count(300)
+            .chunk_size(100)
+            .build()
+            .unwrap();
+        let layout = RawLayout::aligned(16, 64, Fill::Index).unwrap();
+        let mut out = Vec::new();
+        g.write_formatted(&PaddedSink::new(layout.clone(), 300), &mut out, |_, _| ())
+            .unwrap();
+        assert_eq!(out.len(), 300 * 64);
+        let path = std::env::temp_dir().join(format!("mpg_layout_{}", std::process::id()));
+        std::fs::write(&path, &out).unwrap();
+        let reader = CorpusReader::file(&path, Format::Raw, true, None)
+            .unwrap()
+            .with_layout(layout)
+            .unwrap();
+        let all: Vec<_> = reader.records().map(|r| r.unwrap().password).collect();
+        assert_eq!(all, g.records().collect::<Vec<_>>());
+        let some: Vec<_> = reader
+            .range(250..252)
+            .map(|r| r.unwrap().password)
+            .collect();
+        assert_eq!(some, all[250..252]);
+        // Read as zero-filled, the index filler does not check out
+        let zeros = RawLayout::aligned(16, 64, Fill::Zero).unwrap();
+        let reader = CorpusReader::file(&path, Format::Raw, true, None)
+            .unwrap()
+            .with_layout(zeros)
+            .unwrap();
+        assert!(reader.records().nth(1).unwrap().is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn headers_describe_the_file() {
+        use crate::{CorpusReader, Format, PasswordGenerator};
+
+        let layout = RawLayout {
+            order: ByteOrder::Be,
+            header: true,
+            ..RawLayout::aligned(16, 64, Fill::Index).unwrap()
+        };
+        let header = layout.header(0x0102);
+        assert_eq!(header.len(), 64);
+        assert_eq!(&header[..8], b"MPGRAW\x01b");
+        assert_eq!(&header[16..20], &[0, 0, 0, 64]);
+        assert_eq!(
+            RawLayout::from_header(&header),
+            Ok(Some((layout.clone(), 0x0102)))
+        );
+        assert_eq!(RawLayout::from_header(b"f1rst password\n"), Ok(None));
+        assert!(RawLayout::from_header(&header[..20]).is_err());
+
+        // Read back with nothing but the format to go on
+        let g = PasswordGenerator::builder()
+            .key([3; 16])
+            .count(250)
+            .chunk_size(50)
+            .build()
+            .unwrap();
+        let mut out = Vec::new();
+        g.write_formatted(&PaddedSink::new(layout, 250), &mut out, |_, _| ())
+            .unwrap();
+        assert_eq!(out.len(), 64 + 250 * 64);
+        let path = std::env::temp_dir().join(format!("mpg_header_{}", std::process::id()));
+        std::fs::write(&path, &out).unwrap();
+        let reader = CorpusReader::file(&path, Format::Raw, false, None).unwrap();
+        assert_eq!(reader.count(), Some(250));
+        let last: Vec<_> = reader
+            .range(240..250)
+            .map(|r| r.unwrap().password)
+            .collect();
+        assert_eq!(last, g.records().skip(240).collect::<Vec<_>>());
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,1738 @@
+//! Fast bulk password generation from an AES-CTR (or ChaCha20) keystream.
+//!
+//! A run of `count` passwords is split into fixed-size chunks that are
+//! generated independently, which is what makes the work embarrassingly
+//! parallel: each chunk derives its own counter block from its index (see
+//! [`IvStrategy`]) and fills its records in one keystream pass.
+//!
+//! Records are either raw 16-byte keystream blocks or text passwords produced
+//! by an [`Encoder`]; either way every record has the same width, so the byte
+//! offset of any chunk is known up front. [`Passphrase`] records are the
+//! exception: they vary in length, so they are written out in order.
+//!
+//! ```
+//! use mass_password_gen_optimized::{Charset, Encoder, PasswordGenerator};
+//!
+//! let generator = PasswordGenerator::builder()
+//!     .key([0x13; 16])
+//!     .count(1_000)
+//!     .chunk_size(256)
+//!     .encoder(Encoder::new(&Charset::Alnum, 20))
+//!     .build()
+//!     .unwrap();
+//!
+//! let passwords: Vec<Vec<u8>> = generator.records().collect();
+//! assert_eq!(passwords.len(), 1_000);
+//! assert!(passwords.iter().all(|p| p.len() == 20));
+//! ```
+
+use rayon::prelude::*;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Write};
+use std::sync::Arc;
+
+pub mod analysis;
+pub mod archive;
+pub mod buffer;
+pub mod compress;
+pub mod corpus;
+#[cfg(feature = "hash")]
+pub mod crypt;
+#[cfg(feature = "derive")]
+pub mod derive;
+pub mod double_check;
+pub mod encoder;
+#[cfg(feature = "fault-injection")]
+pub mod fault;
+pub mod format;
+pub mod governor;
+#[cfg(feature = "hash")]
+pub mod hashcat;
+pub mod key;
+mod keystream;
+pub mod lane;
+pub mod layout;
+mod mmap;
+pub mod pairs;
+pub mod passphrase;
+pub mod pin;
+pub mod policy;
+pub mod record_id;
+pub mod reference;
+pub mod resample;
+#[cfg(feature = "reservations")]
+pub mod reserve;
+pub mod rewrite;
+pub mod rotation;
+pub mod schema;
+pub mod segment;
+pub mod settings;
+pub mod shm;
+mod stream;
+pub mod template;
+pub mod throttle;
+pub mod timestamp;
+pub mod token;
+pub mod unique;
+pub mod watermark;
+
+pub use archive::ArchiveWriter;
+pub use buffer::{AdaptiveBuffer, BufferReport};
+#[cfg(feature = "compress-zstd")]
+pub use compress::ZstdDictionary;
+pub use compress::{ChunkCodec, Codec, Compressor};
+pub use corpus::{Compression, CorpusReader, Custodian, HostInfo, Manifest, Seal};
+#[cfg(feature = "hash")]
+pub use crypt::{HashAlgorithm, HashedPairs};
+#[cfg(feature = "derive")]
+pub use derive::{MasterKey, Site};
+pub use double_check::{DoubleCheck, DoubleCheckReport};
+pub use encoder::{Charset, Encoder, LengthDist};
+pub use format::{hex, Format, OutputSink};
+pub use governor::{CpuLimit, CpuReport};
+pub use key::Key;
+pub use keystream::{aes_hardware_available, cpu_features, Cipher, IvStrategy, KeystreamBackend};
+pub use lane::Lane;
+pub use passphrase::{Capitalization, Passphrase, PassphraseStyle, Wordlist};
+pub use policy::PasswordPolicy;
+pub use record_id::RecordIds;
+pub use reference::{ReferenceKey, ReferenceWriter};
+pub use resample::ResampleStats;
+#[cfg(feature = "reservations")]
+pub use reserve::{ReservationError, ReservationStore};
+pub use schema::{Schema, SchemaSink};
+pub use segment::{Segment, SegmentPlan};
+pub use stream::{DeviceWrites, FailoverReport, Landing, Progress, ShardReport, ShardWrite};
+pub use template::Template;
+pub use throttle::{WriteLimit, WriteReport};
+pub use timestamp::{Distribution, TimestampSpread, Timestamps};
+pub use token::{ExpiringTokens, TokenValidator};
+pub use unique::{find_duplicates, Duplicates};
+pub use watermark::Watermark;
+
+/// Chunk size used when the builder is not given one.
+pub const DEFAULT_CHUNK_SIZE: usize = 1_000_000;
+
+/// Why a [`PasswordGeneratorBuilder`] could not build a generator.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BuildError {
+    /// No key was set
+    MissingKey,
+    /// The cipher cannot run under a key of this width
+    KeyWidth(Cipher, usize),
+    /// The count was not set or is zero
+    ZeroCount,
+    /// The chunk size is zero
+    ZeroChunkSize,
+    /// A passphrase was combined with an encoder or a watermark
+    PassphraseConflict,
+    /// A template encoder was combined with a policy or a watermark, both
+    /// of which assume every position shares one alphabet
+    TemplateConflict,
+    /// An encoder of varying lengths was combined with a policy or a
+    /// watermark, both of which assume one length
+    LengthsConflict,
+    /// A policy was given without an encoder
+    PolicyNeedsEncoder,
+    /// No password from the encoder can (practically) satisfy the policy
+    UnsatisfiablePolicy(String),
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::MissingKey => write!(f, "no key was given"),
+            BuildError::KeyWidth(cipher, bits) => write!(
+                f,
+                "{} cannot run under a {}-bit key (aes128 takes 128 bits, aes256 256, \
+                 chacha20 either)",
+                cipher, bits
+            ),
+            BuildError::ZeroCount => write!(f, "count must be greater than zero"),
+            BuildError::ZeroChunkSize => write!(f, "chunk size must be greater than zero"),
+            BuildError::PassphraseConflict => {
+                write!(
+                    f,
+                    "passphrases cannot be combined with a charset or watermark"
+                )
+            }
+            BuildError::TemplateConflict => {
+                write!(
+                    f,
+                    "templates cannot be combined with a password policy or watermark"
+                )
+            }
+            BuildError::LengthsConflict => {
+                write!(
+                    f,
+                    "a length distribution cannot be combined with a password policy or \
+                     watermark"
+                )
+            }
+            BuildError::PolicyNeedsEncoder => {
+                write!(f, "a password policy needs a charset")
+            }
+            BuildError::UnsatisfiablePolicy(reason) => {
+                write!(f, "the password policy cannot be met: {}", reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+/// Builder for [`PasswordGenerator`]; start with [`PasswordGenerator::builder`].
+#[derive(Clone, Default)]
+pub struct PasswordGeneratorBuilder {
+    key: Option<Key>,
+    cipher: Option<Cipher>,
+    iv_strategy: IvStrategy,
+    count: usize,
+    chunk_size: Option<usize>,
+    encoder: Option<Encoder>,
+    passphrase: Option<Passphrase>,
+    watermark: Option<Watermark>,
+    policy: Option<PasswordPolicy>,
+    filler: bool,
+}
+
+impl PasswordGeneratorBuilder {
+    /// The 128- or 256-bit key. Required.
+    pub fn key(mut self, key: impl Into<Key>) -> Self {
+        self.key = Some(key.into());
+        self
+    }
+
+    /// Which stream cipher produces the keystream; defaults to the AES of
+    /// the key's width. Different ciphers give different output.
+    pub fn cipher(mut self, cipher: Cipher) -> Self {
+        self.cipher = Some(cipher);
+        self
+    }
+
+    /// How chunk counter blocks are derived; defaults to
+    /// [`IvStrategy::ChunkIndex`].
+    pub fn iv_strategy(mut self, strategy: IvStrategy) -> Self {
+        self.iv_strategy = strategy;
+        self
+    }
+
+    /// Number of passwords in the run. Required.
+    pub fn count(mut self, count: usize) -> Self {
+        self.count = count;
+        self
+    }
+
+    /// Passwords per chunk; the last chunk holds the remainder. Defaults to
+    /// [`DEFAULT_CHUNK_SIZE`].
+    pub fn chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = Some(chunk_size);
+        self
+    }
+
+    /// Encode passwords as text instead of raw 16-byte blocks.
+    pub fn encoder(mut self, encoder: Encoder) -> Self {
+        self.encoder = Some(encoder);
+        self
+    }
+
+    /// Generate word passphrases instead of passwords.
+    pub fn passphrase(mut self, passphrase: Passphrase) -> Self {
+        self.passphrase = Some(passphrase);
+        self
+    }
+
+    /// Mark a fraction of records with an owner watermark.
+    pub fn watermark(mut self, watermark: Watermark) -> Self {
+        self.watermark = Some(watermark);
+        self
+    }
+
+    /// Only emit encoded passwords that satisfy `policy`; see
+    /// [`policy`](crate::policy) for how. Requires an encoder.
+    pub fn policy(mut self, policy: PasswordPolicy) -> Self {
+        self.policy = Some(policy);
+        self
+    }
+
+    /// Skip the cipher and build records from an all-zero keystream: the
+    /// output has the real run's size and shape but is public. For testing
+    /// output paths.
+    pub fn filler(mut self, filler: bool) -> Self {
+        self.filler = filler;
+        self
+    }
+
+    pub fn build(self) -> Result<PasswordGenerator, BuildError> {
+        let key = self.key.ok_or(BuildError::MissingKey)?;
+        let cipher = self.cipher.unwrap_or(Cipher::Aes128.for_key(&key));
+        if !cipher.accepts(&key) {
+            return Err(BuildError::KeyWidth(cipher, key.bits()));
+        }
+        if self.count == 0 {
+            return Err(BuildError::ZeroCount);
+        }
+        let chunk_size = self.chunk_size.unwrap_or(DEFAULT_CHUNK_SIZE);
+        if chunk_size == 0 {
+            return Err(BuildError::ZeroChunkSize);
+        }
+        if self.passphrase.is_some() && (self.encoder.is_some() || self.watermark.is_some()) {
+            return Err(BuildError::PassphraseConflict);
+        }
+        if self
+            .encoder
+            .as_ref()
+            .is_some_and(|enc| enc.template().is_some())
+            && (self.policy.is_some() || self.watermark.is_some())
+        {
+            return Err(BuildError::TemplateConflict);
+        }
+        if self
+            .encoder
+            .as_ref()
+            .is_some_and(|enc| enc.lengths().is_some())
+            && (self.policy.is_some() || self.watermark.is_some())
+        {
+            return Err(BuildError::LengthsConflict);
+        }
+        let screen = match (self.policy, &self.encoder) {
+            (None, _) => None,
+            (Some(_), None) => return Err(BuildError::PolicyNeedsEncoder),
+            (Some(policy), Some(enc)) => {
+                Some(policy::Screen::new(policy, enc).map_err(BuildError::UnsatisfiablePolicy)?)
+            }
+        };
+        Ok(PasswordGenerator {
+            key,
+            cipher,
+            iv_strategy: self.iv_strategy,
+            count: self.count,
+            chunk_size,
+            encoder: self.encoder,
+            passphrase: self.passphrase,
+            watermark: self.watermark,
+            screen,
+            resampled: Arc::default(),
+            filler: self.filler,
+            reference: None,
+            write_limit: None,
+            cpu_limit: None,
+            double_check: None,
+        })
+    }
+}
+
+/// A configured run: key, layout, count and encoding.
+///
+/// Generation is deterministic: the same configuration always produces the
+/// same records, whichever API produces them and however many threads run.
+#[derive(Clone)]
+pub struct PasswordGenerator {
+    key: Key,
+    cipher: Cipher,
+    iv_strategy: IvStrategy,
+    count: usize,
+    chunk_size: usize,
+    encoder: Option<Encoder>,
+    passphrase: Option<Passphrase>,
+    watermark: Option<Watermark>,
+    screen: Option<policy::Screen>,
+    /// How often records were resampled, shared by every copy of the run
+    resampled: Arc<resample::Counters>,
+    filler: bool,
+    /// Sees every chunk [`fill_chunk`](Self::fill_chunk) generates
+    reference: Option<Arc<ReferenceWriter>>,
+    /// Paces every chunk written to a file or writer
+    write_limit: Option<Arc<WriteLimit>>,
+    /// Paces every chunk [`fill_chunk`](Self::fill_chunk) starts
+    cpu_limit: Option<Arc<CpuLimit>>,
+    /// Makes every chunk [`fill_chunk`](Self::fill_chunk) generates twice
+    double_check: Option<Arc<DoubleCheck>>,
+}
+
+/// One generated chunk from [`PasswordGenerator::par_chunks`].
+#[derive(Debug, Clone)]
+pub struct Chunk {
+    /// Chunk index within the run
+    pub index: usize,
+    /// Index of the chunk's first record within the run
+    pub first_record: usize,
+    /// The chunk's records, each [`PasswordGenerator::record_width`] bytes
+    /// (newline-terminated and of varying length for passphrases)
+    pub data: Vec<u8>,
+}
+
+impl PasswordGenerator {
+    pub fn builder() -> PasswordGeneratorBuilder {
+        PasswordGeneratorBuilder::default()
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    pub fn cipher(&self) -> Cipher {
+        self.cipher
+    }
+
+    pub fn iv_strategy(&self) -> IvStrategy {
+        self.iv_strategy
+    }
+
+    pub fn encoder(&self) -> Option<&Encoder> {
+        self.encoder.as_ref()
+    }
+
+    pub fn policy(&self) -> Option<&PasswordPolicy> {
+        self.screen.as_ref().map(|s| &s.policy)
+    }
+
+    /// How often the records generated so far were resampled, by this
+    /// generator and its copies, if there is a policy.
+    pub fn resample_stats(&self) -> Option<ResampleStats> {
+        let screen = self.screen.as_ref()?;
+        Some(self.resampled.stats(resample::bound(screen.acceptance)))
+    }
+
+    /// Estimated fraction of encoded candidates the policy accepts (a
+    /// conservative lower bound), if there is a policy.
+    pub fn policy_acceptance(&self) -> Option<f64> {
+        self.screen.as_ref().map(|s| s.acceptance)
+    }
+
+    pub fn passphrase(&self) -> Option<&Passphrase> {
+        self.passphrase.as_ref()
+    }
+
+    /// Number of chunks in the run, including a short final one.
+    pub fn num_chunks(&self) -> usize {
+        self.count.div_ceil(self.chunk_size)
+    }
+
+    /// Number of passwords in chunk `chunk_idx`; only the last one can be short.
+    pub fn chunk_len(&self, chunk_idx: usize) -> usize {
+        self.chunk_size
+            .min(self.count - chunk_idx * self.chunk_size)
+    }
+
+    /// Output bytes per password: 16 raw bytes, or the encoded text plus a
+    /// newline. Every record has the same width, so chunk offsets are fixed.
+    /// For passphrases and lengths drawn from a distribution this is the
+    /// longest possible record instead.
+    pub fn record_width(&self) -> usize {
+        match (&self.encoder, &self.passphrase) {
+            (_, Some(pass)) => pass.max_len() + 1,
+            (Some(enc), None) => enc.length() + 1,
+            (None, None) => 16,
+        }
+    }
+
+    /// Whether records are raw 16-byte blocks rather than text.
+    pub fn is_binary(&self) -> bool {
+        self.encoder.is_none() && self.passphrase.is_none()
+    }
+
+    /// Whether every record is exactly [`record_width`](Self::record_width)
+    /// bytes; false for passphrases and lengths drawn from a distribution.
+    pub fn fixed_width(&self) -> bool {
+        self.passphrase.is_none()
+            && self
+                .encoder
+                .as_ref()
+                .is_none_or(|enc| enc.lengths().is_none())
+    }
+
+    /// Keystream bytes consumed by a chunk of `records` passwords; under a
+    /// policy, those of their first candidates.
+    fn chunk_keystream_len(&self, records: usize) -> usize {
+        match (&self.encoder, &self.passphrase) {
+            (_, Some(pass)) => records * pass.budget(),
+            (Some(enc), None) => records * enc.budget(),
+            (None, None) => records * 16,
+        }
+    }
+
+    /// Keystream bytes generated for chunk `chunk_idx`.
+    pub fn keystream_len(&self, chunk_idx: usize) -> usize {
+        self.chunk_keystream_len(self.chunk_len(chunk_idx))
+    }
+
+    /// Total output size of the run in bytes (an upper bound for
+    /// passphrases).
+    pub fn output_len(&self) -> usize {
+        self.count * self.record_width()
+    }
+
+    /// XORs chunk `chunk_idx`'s keystream, starting `offset` bytes into the
+    /// run, into `out`. Filler runs leave `out` zeroed.
+    fn keystream(&self, chunk_idx: usize, offset: u64, out: &mut [u8]) {
+        if !self.filler {
+            keystream::apply_chunk_keystream(
+                &self.key,
+                self.cipher,
+                self.iv_strategy,
+                chunk_idx,
+                offset,
+                out,
+            );
+        }
+    }
+
+    /// Fills `out` (zeroed, a whole number of records) with the passwords of
+    /// chunk `chunk_idx` and returns how many bytes it used: all of them,
+    /// except for passphrases, which are packed at the front. `scratch` holds
+    /// the keystream for encoded output and is reused between calls.
+    pub fn fill_chunk(&self, chunk_idx: usize, scratch: &mut Vec<u8>, out: &mut [u8]) -> usize {
+        if let Some(limit) = &self.cpu_limit {
+            limit.pace();
+        }
+        let used = match &self.double_check {
+            Some(check) => check.fill(self, chunk_idx, scratch, out),
+            None => self.fill_records(chunk_idx, scratch, out),
+        };
+        if let Some(reference) = &self.reference {
+            reference.write_chunk(self, chunk_idx * self.chunk_size, &out[..used]);
+        }
+        used
+    }
+
+    /// This run with `reference` writing the rows of each chunk as it is
+    /// generated, by whichever method.
+    pub fn with_reference(&self, reference: Arc<ReferenceWriter>) -> Self {
+        PasswordGenerator {
+            reference: Some(reference),
+            ..self.clone()
+        }
+    }
+
+    /// This run with every chunk it writes paced by `limit`, by whichever
+    /// method but a memory map.
+    pub fn with_write_limit(&self, limit: Arc<WriteLimit>) -> Self {
+        PasswordGenerator {
+            write_limit: Some(limit),
+            ..self.clone()
+        }
+    }
+
+    /// This run with every chunk it generates, by whichever method, paced
+    /// to keep under `limit`.
+    pub fn with_cpu_limit(&self, limit: Arc<CpuLimit>) -> Self {
+        PasswordGenerator {
+            cpu_limit: Some(limit),
+            ..self.clone()
+        }
+    }
+
+    /// This run with every chunk it generates, by whichever method, made
+    /// twice and compared by `check` before it is used.
+    pub fn with_double_check(&self, check: Arc<DoubleCheck>) -> Self {
+        PasswordGenerator {
+            double_check: Some(check),
+            ..self.clone()
+        }
+    }
+
+    pub(crate) fn write_limit(&self) -> Option<&WriteLimit> {
+        self.write_limit.as_deref()
+    }
+
+    fn fill_records(&self, chunk_idx: usize, scratch: &mut Vec<u8>, out: &mut [u8]) -> usize {
+        let offset = (chunk_idx * self.chunk_keystream_len(self.chunk_size)) as u64;
+        if let Some(pass) = &self.passphrase {
+            let records = out.len() / self.record_width();
+            scratch.clear();
+            scratch.resize(records * pass.budget(), 0);
+            self.keystream(chunk_idx, offset, scratch);
+            let mut used = 0;
+            for stream in scratch.chunks_exact(pass.budget()) {
+                used += pass.encode(stream, &mut out[used..]);
+                out[used] = b'\n';
+                used += 1;
+            }
+            return used;
+        }
+        let Some(enc) = &self.encoder else {
+            self.keystream(chunk_idx, offset, out);
+            if let Some(wm) = &self.watermark {
+                for record in out.chunks_exact_mut(16) {
+                    wm.apply(record, None);
+                }
+            }
+            return out.len();
+        };
+        let width = self.record_width();
+        if enc.lengths().is_some() {
+            scratch.clear();
+            scratch.resize(self.chunk_keystream_len(out.len() / width), 0);
+            self.keystream(chunk_idx, offset, scratch);
+            let mut used = 0;
+            for stream in scratch.chunks_exact(enc.budget()) {
+                let length = enc.length_of(stream);
+                enc.encode(stream, &mut out[used..used + length]);
+                out[used + length] = b'\n';
+                used += length + 1;
+            }
+            return used;
+        }
+        if let Some(screen) = &self.screen {
+            scratch.clear();
+            scratch.resize(self.chunk_keystream_len(out.len() / width), 0);
+            self.keystream(chunk_idx, offset, scratch);
+            let budget = enc.budget();
+            let bound = resample::bound(screen.acceptance);
+            let mut resampler =
+                resample::Resampler::new(&self.key, self.cipher, budget, bound, &self.resampled);
+            let first = chunk_idx * self.chunk_size;
+            for (i, (stream, record)) in scratch
+                .chunks_exact(budget)
+                .zip(out.chunks_exact_mut(width))
+                .enumerate()
+            {
+                let candidate = &mut record[..width - 1];
+                resampler.draw(first + i, stream, |stream| {
+                    enc.encode(stream, candidate);
+                    if let Some(wm) = &self.watermark {
+                        wm.apply(candidate, Some(enc.alphabet()));
+                    }
+                    // Filler runs accept every candidate
+                    self.filler || screen.policy.check(candidate)
+                });
+                record[width - 1] = b'\n';
+            }
+            return out.len();
+        }
+        scratch.clear();
+        scratch.resize(self.chunk_keystream_len(out.len() / width), 0);
+        self.keystream(chunk_idx, offset, scratch);
+        for (stream, record) in scratch
+            .chunks_exact(enc.budget())
+            .zip(out.chunks_exact_mut(width))
+        {
+            enc.encode(stream, &mut record[..width - 1]);
+            if let Some(wm) = &self.watermark {
+                wm.apply(&mut record[..width - 1], Some(enc.alphabet()));
+            }
+            record[width - 1] = b'\n';
+        }
+        out.len()
+    }
+
+    /// Generates one whole chunk into a fresh buffer.
+    pub fn chunk(&self, chunk_idx: usize) -> Chunk {
+        let mut data = vec![0u8; self.chunk_len(chunk_idx) * self.record_width()];
+        let len = self.fill_chunk(chunk_idx, &mut Vec::new(), &mut data);
+        data.truncate(len);
+        Chunk {
+            index: chunk_idx,
+            first_record: chunk_idx * self.chunk_size,
+            data,
+        }
+    }
+
+    /// Fills `out` (zeroed, exactly [`output_len`](Self::output_len) bytes)
+    /// with the whole run in parallel. `on_chunk(chunk_idx, records)` runs as
+    /// each chunk completes; its results come back in chunk order.
+    ///
+    /// Needs fixed-width records; for passphrases, [`write_to`](Self::write_to)
+    /// a `Vec<u8>` instead.
+    pub fn generate_into<T, F>(&self, out: &mut [u8], on_chunk: F) -> Vec<T>
+    where
+        T: Send,
+        F: Fn(usize, usize) -> T + Sync,
+    {
+        assert!(
+            self.fixed_width(),
+            "generate_into needs fixed-width records"
+        );
+        assert_eq!(
+            out.len(),
+            self.output_len(),
+            "output buffer has the wrong size"
+        );
+        let width = self.record_width();
+        out.par_chunks_mut(self.chunk_size * width)
+            .enumerate()
+            .map_init(Vec::new, |scratch, (chunk_idx, chunk)| {
+                self.fill_chunk(chunk_idx, scratch, chunk);
+                on_chunk(chunk_idx, chunk.len() / width)
+            })
+            .collect()
+    }
+
+    /// Generates the whole run into memory.
+    pub fn generate(&self) -> Vec<u8> {
+        if !self.fixed_width() {
+            let mut out = Vec::with_capacity(self.output_len());
+            self.write_to(&mut out, |_, _| ())
+                .expect("writing to a Vec cannot fail");
+            return out;
+        }
+        let mut out = vec![0u8; self.output_len()];
+        self.generate_into(&mut out, |_, _| ());
+        out
+    }
+
+    /// Streams the run into `file` with positioned writes (sequential ones
+    /// for passphrases), so memory stays bounded by about
+    /// `chunk_size * threads` records. See
+    /// [`generate_into`](Self::generate_into) for `on_chunk`.
+    pub fn write_to_file<T, F>(&self, file: &File, on_chunk: F) -> io::Result<Vec<T>>
+    where
+        T: Send,
+        F: Fn(usize, usize) -> T + Sync,
+    {
+        stream::to_file(self, file, on_chunk)
+    }
+
+    /// Like [`write_to_file`](Self::write_to_file), but generates straight
+    /// into a memory map of `file`, which must be open for reading and
+    /// writing and is resized to [`output_len`](Self::output_len). Falls
+    /// back to positioned writes where the file cannot be mapped or records
+    /// are variable-width, returning the reason alongside the results.
+    pub fn write_mapped<T, F>(
+        &self,
+        file: &File,
+        on_chunk: F,
+    ) -> io::Result<(Vec<T>, Option<io::Error>)>
+    where
+        T: Send,
+        F: Fn(usize, usize) -> T + Sync,
+    {
+        stream::to_mapped_file(self, file, on_chunk)
+    }
+
+    /// Streams the run, in order, into a sequential writer such as stdout.
+    pub fn write_to<T, F>(&self, out: &mut (dyn Write + Send), on_chunk: F) -> io::Result<Vec<T>>
+    where
+        T: Send,
+        F: Fn(usize, usize) -> T + Sync,
+    {
+        stream::to_writer(self, None, None, None, out, on_chunk)
+    }
+
+    /// Like [`write_to`](Self::write_to), but serializes each chunk through
+    /// `sink` on the worker threads first.
+    pub fn write_formatted<T, F>(
+        &self,
+        sink: &dyn OutputSink,
+        out: &mut (dyn Write + Send),
+        on_chunk: F,
+    ) -> io::Result<Vec<T>>
+    where
+        T: Send,
+        F: Fn(usize, usize) -> T + Sync,
+    {
+        stream::to_writer(self, Some(sink), None, None, out, on_chunk)
+    }
+
+    /// Like [`write_formatted`](Self::write_formatted) (or
+    /// [`write_to`](Self::write_to) without a sink), but also compresses
+    /// each chunk through `codec` on the worker threads. A header gets a
+    /// frame of its own.
+    pub fn write_compressed<T, F>(
+        &self,
+        sink: Option<&dyn OutputSink>,
+        codec: &dyn ChunkCodec,
+        out: &mut (dyn Write + Send),
+        on_chunk: F,
+    ) -> io::Result<Vec<T>>
+    where
+        T: Send,
+        F: Fn(usize, usize) -> T + Sync,
+    {
+        stream::to_writer(self, sink, Some(codec), None, out, on_chunk)
+    }
+
+    /// Like [`write_compressed`](Self::write_compressed), with the codec
+    /// optional, but writes through `buffer`, which sizes its writes (and
+    /// how many chunks are generated ahead) from how the first seconds of
+    /// writing to `out` go. Unbuffered destinations such as files and
+    /// stdout need no other buffering.
+    pub fn write_adaptive<T, F>(
+        &self,
+        sink: Option<&dyn OutputSink>,
+        codec: Option<&dyn ChunkCodec>,
+        buffer: &AdaptiveBuffer,
+        out: &mut (dyn Write + Send),
+        on_chunk: F,
+    ) -> io::Result<Vec<T>>
+    where
+        T: Send,
+        F: Fn(usize, usize) -> T + Sync,
+    {
+        stream::to_writer(self, sink, codec, Some(buffer), out, on_chunk)
+    }
+
+    /// Streams the run into `primary`, switching to `fallback` for the rest
+    /// of the run if a write to `primary` fails (or right away if there is
+    /// no `primary`). Raw fixed-width output keeps its positioned writes, so
+    /// each chunk sits at its own offset in whichever file it landed in;
+    /// sequential output is split between the two files in order. The
+    /// report says which chunks went where.
+    pub fn write_with_fallback<T, F>(
+        &self,
+        sink: Option<&dyn OutputSink>,
+        primary: Option<&File>,
+        fallback: &File,
+        on_chunk: F,
+    ) -> io::Result<(Vec<T>, FailoverReport)>
+    where
+        T: Send,
+        F: Fn(usize, usize) -> T + Sync,
+    {
+        stream::to_files_with_fallback(self, sink, primary, fallback, on_chunk)
+    }
+
+    /// Splits the run across `files`, one shard of `shard_size` records
+    /// (a multiple of the chunk size) each, the last possibly shorter. Each
+    /// shard is a complete file of its own, header included, and the shards
+    /// concatenated in order hold every record exactly once. `on_shard` is
+    /// called with each shard's number once all of it is written, which
+    /// need not be in order.
+    pub fn write_sharded<T, F, S>(
+        &self,
+        sink: Option<&dyn OutputSink>,
+        files: &[File],
+        shard_size: usize,
+        on_chunk: F,
+        on_shard: S,
+    ) -> io::Result<Vec<T>>
+    where
+        T: Send,
+        F: Fn(usize, usize) -> T + Sync,
+        S: Fn(usize) + Sync,
+    {
+        self.check_shard_size(shard_size)?;
+        let shard_chunks = shard_size / self.chunk_size;
+        stream::to_shards(self, sink, files, shard_chunks, on_chunk, on_shard)
+    }
+
+    /// Splits the run into shards as [`write_sharded`](Self::write_sharded)
+    /// does, each compressed through `shards.codec` if given, as
+    /// [`write_adaptive`](Self::write_adaptive) compresses a whole run.
+    /// Each shard can move onto a spare: the first write its own file fails
+    /// (or its having none) sends the whole shard to the file
+    /// `shards.spare` opens for it, its chunks so far written again there,
+    /// and the run carries on with the next shard on its own file. Shards
+    /// are written in order, one at a time, from `shards.from`, which lets
+    /// a run that stopped between shards resume. `on_shard` is also told
+    /// where each landed; so is the report, with why each one moved.
+    pub fn write_shards<T, F, S>(
+        &self,
+        shards: &ShardWrite,
+        on_chunk: F,
+        on_shard: S,
+    ) -> io::Result<(Vec<T>, ShardReport)>
+    where
+        T: Send,
+        F: Fn(usize, usize) -> T + Sync,
+        S: Fn(usize, Landing) + Sync,
+    {
+        self.check_shard_size(shards.shard_size)?;
+        let shard_chunks = shards.shard_size / self.chunk_size;
+        stream::to_shards_with_spares(self, shards, shard_chunks, on_chunk, on_shard)
+    }
+
+    /// Splits the run into shards as [`write_shards`](Self::write_shards)
+    /// does, with the files spread over `devices` devices: shard `n` is on
+    /// device `n % devices`. Each device gets a writer thread of its own, so
+    /// the devices write at once, each at its own pace. Every shard needs a
+    /// file, none has a spare and all of them are written. Returns what each device took as well.
+    pub fn write_striped<T, F, S>(
+        &self,
+        shards: &ShardWrite,
+        devices: usize,
+        on_chunk: F,
+        on_shard: S,
+    ) -> io::Result<(Vec<T>, Vec<DeviceWrites>)>
+    where
+        T: Send,
+        F: Fn(usize, usize) -> T + Sync,
+        S: Fn(usize) + Sync,
+    {
+        self.check_shard_size(shards.shard_size)?;
+        let shard_chunks = shards.shard_size / self.chunk_size;
+        stream::to_stripes(self, shards, devices, shard_chunks, on_chunk, on_shard)
+    }
+
+    fn check_shard_size(&self, shard_size: usize) -> io::Result<()> {
+        if shard_size == 0 || !shard_size.is_multiple_of(self.chunk_size) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "the shard size ({}) must be a multiple of the chunk size ({})",
+                    shard_size, self.chunk_size
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    /// The number of shards of `shard_size` records the run splits into.
+    pub fn num_shards(&self, shard_size: usize) -> usize {
+        self.count.div_ceil(shard_size.max(1))
+    }
+
+    /// Streams the chunks after `from` into `file`, for runs that may have to
+    /// be resumed; pass `Progress::default()` to start a run. `on_progress`
+    /// is called each time the gapless prefix of fully written chunks grows,
+    /// and from its last value a later call picks up where this one stopped.
+    /// The file ends up exactly as [`write_to_file`](Self::write_to_file)
+    /// (or [`write_formatted`](Self::write_formatted)) would have left it.
+    pub fn write_resumable<T, F, P>(
+        &self,
+        sink: Option<&dyn OutputSink>,
+        file: &File,
+        from: Progress,
+        on_chunk: F,
+        on_progress: P,
+    ) -> io::Result<Vec<T>>
+    where
+        T: Send,
+        F: Fn(usize, usize) -> T + Sync,
+        P: FnMut(Progress) + Send,
+    {
+        stream::to_file_resumable(self, sink, file, from, on_chunk, on_progress)
+    }
+
+    /// Calls `f` with each record of `data`, a chunk's output, without the
+    /// newline used for text framing.
+    pub fn for_each_record<'a>(&self, data: &'a [u8], mut f: impl FnMut(&'a [u8])) {
+        if self.is_binary() {
+            data.chunks_exact(16).for_each(f);
+        } else {
+            let body = data.strip_suffix(b"\n").unwrap_or(data);
+            if !body.is_empty() {
+                body.split(|&b| b == b'\n').for_each(&mut f);
+            }
+        }
+    }
+
+    /// A rayon producer of whole chunks, for consumers that want to handle
+    /// chunks themselves (compress, upload, ...) as they are generated.
+    pub fn par_chunks(&self) -> impl IndexedParallelIterator<Item = Chunk> + '_ {
+        (0..self.num_chunks())
+            .into_par_iter()
+            .map(|i| self.chunk(i))
+    }
+
+    /// Iterates over passwords in order, without the trailing newline used
+    /// for text framing. Generates one chunk at a time.
+    pub fn records(&self) -> Records<'_> {
+        Records {
+            generator: self,
+            next_chunk: 0,
+            buf: Vec::new(),
+            pos: 0,
+        }
+    }
+}
+
+/// Sequential iterator over the passwords of a run; see
+/// [`PasswordGenerator::records`].
+pub struct Records<'a> {
+    generator: &'a PasswordGenerator,
+    next_chunk: usize,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl Iterator for Records<'_> {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Vec<u8>> {
+        if self.pos == self.buf.len() {
+            if self.next_chunk == self.generator.num_chunks() {
+                return None;
+            }
+            self.buf = self.generator.chunk(self.next_chunk).data;
+            self.next_chunk += 1;
+            self.pos = 0;
+        }
+        let rest = &self.buf[self.pos..];
+        // Text records never contain a newline, so it delimits them
+        let (password, width) = if self.generator.is_binary() {
+            (&rest[..16], 16)
+        } else {
+            let end = rest.iter().position(|&b| b == b'\n').unwrap_or(rest.len());
+            (&rest[..end], end + 1)
+        };
+        self.pos += width;
+        Some(password.to_vec())
+    }
+}
+
+/// Draws a fresh key from the operating system's CSPRNG.
+pub fn random_key() -> io::Result<[u8; 16]> {
+    let mut key = [0u8; 16];
+    getrandom::fill(&mut key).map_err(io::Error::other)?;
+    Ok(key)
+}
+
+/// Short public identifier for a key: the first 8 bytes of its SHA-256, in
+/// hex. Safe to log; it reveals nothing useful about the key itself.
+pub fn key_fingerprint(key: &[u8]) -> String {
+    use sha2::{Digest, Sha256};
+    hex(&Sha256::digest(key)[..8])
+}
+
+/// Parses a 128-bit key given as 32 hex characters, or a 256-bit one as 64.
+pub fn parse_key_hex(s: &str) -> Result<Key, String> {
+    if !Key::LENGTHS.iter().any(|&len| s.len() == len * 2) || !s.is_ascii() {
+        return Err(format!("expected 32 or 64 hex characters, got `{}`", s));
+    }
+    let bytes = (0..s.len() / 2)
+        .map(|i| u8::from_str_radix(&s[i * 2..i * 2 + 2], 16))
+        .collect::<Result<Vec<u8>, _>>()
+        .map_err(|_| format!("invalid hex in key `{}`", s))?;
+    Key::from_slice(&bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn builder(count: usize, chunk_size: usize) -> PasswordGeneratorBuilder {
+        PasswordGenerator::builder()
+            .key([0x13; 16])
+            .count(count)
+            .chunk_size(chunk_size)
+    }
+
+    #[test]
+    fn build_rejects_missing_fields() {
+        let err = |b: PasswordGeneratorBuilder| b.build().err();
+        assert_eq!(
+            err(PasswordGenerator::builder().count(1)),
+            Some(BuildError::MissingKey)
+        );
+        assert_eq!(err(builder(0, 10)), Some(BuildError::ZeroCount));
+        assert_eq!(err(builder(10, 0)), Some(BuildError::ZeroChunkSize));
+    }
+
+    #[test]
+    fn remainder_goes_into_last_chunk() {
+        let g = builder(25, 10).build().unwrap();
+        assert_eq!(g.num_chunks(), 3);
+        assert_eq!((g.chunk_len(0), g.chunk_len(2)), (10, 5));
+        assert_eq!(g.generate().len(), 25 * 16);
+    }
+
+    #[test]
+    fn raw_records_are_consecutive_keystream_blocks() {
+        let g = builder(8, 4).build().unwrap();
+        let out = g.generate();
+        let mut expected = vec![0u8; 4 * 16];
+        keystream::apply_chunk_keystream(
+            &[0x13; 16].into(),
+            Cipher::Aes128,
+            IvStrategy::ChunkIndex,
+            1,
+            0,
+            &mut expected,
+        );
+        assert_eq!(&out[4 * 16..], &expected[..]);
+    }
+
+    #[test]
+    fn continuous_layout_ignores_chunk_size() {
+        let alnum = Encoder::new(&Charset::Alnum, 12);
+        let policy = PasswordPolicy {
+            min_digits: 3,
+            ..Default::default()
+        };
+        for (encoder, policy) in [
+            (None, None),
+            (Some(alnum.clone()), None),
+            (Some(alnum), Some(policy)),
+        ] {
+            let run = |chunk_size| {
+                let mut b = builder(1000, chunk_size).iv_strategy(IvStrategy::Continuous);
+                if let Some(enc) = &encoder {
+                    b = b.encoder(enc.clone());
+                }
+                if let Some(policy) = &policy {
+                    b = b.policy(policy.clone());
+                }
+                b.build().unwrap().generate()
+            };
+            let whole = run(1000);
+            for chunk_size in [7, 256, 300] {
+                assert_eq!(run(chunk_size), whole, "chunk size {}", chunk_size);
+            }
+        }
+    }
+
+    #[test]
+    fn chunk_index_layout_depends_on_chunk_size() {
+        let a = builder(100, 7).build().unwrap().generate();
+        let b = builder(100, 100).build().unwrap().generate();
+        // Chunk 0 is shared; everything after it starts from a different IV
+        assert_eq!(a[..7 * 16], b[..7 * 16]);
+        assert_ne!(a[7 * 16..], b[7 * 16..]);
+    }
+
+    #[test]
+    fn all_apis_agree() {
+        let g = builder(1000, 64)
+            .encoder(Encoder::new(&Charset::Hex, 10))
+            .build()
+            .unwrap();
+        let whole = g.generate();
+
+        let chunks: Vec<u8> = g.par_chunks().flat_map_iter(|c| c.data).collect();
+        assert_eq!(chunks, whole);
+
+        let records: Vec<u8> = g
+            .records()
+            .flat_map(|mut r| {
+                r.push(b'\n');
+                r
+            })
+            .collect();
+        assert_eq!(records, whole);
+
+        let mut streamed = Vec::new();
+        g.write_to(&mut streamed, |_, _| ()).unwrap();
+        assert_eq!(streamed, whole);
+    }
+
+    #[test]
+    fn passphrases_are_variable_width_records() {
+        let pass = Passphrase::new(4, " ", Capitalization::Title, 3).unwrap();
+        let g = builder(500, 64).passphrase(pass).build().unwrap();
+        assert!(!g.fixed_width());
+        let whole = g.generate();
+        assert!(whole.len() < g.output_len());
+
+        let chunks: Vec<u8> = g.par_chunks().flat_map_iter(|c| c.data).collect();
+        assert_eq!(chunks, whole);
+        let records: Vec<Vec<u8>> = g.records().collect();
+        assert_eq!(records.len(), 500);
+        let joined: Vec<u8> = records
+            .iter()
+            .flat_map(|r| [&r[..], b"\n"].concat())
+            .collect();
+        assert_eq!(joined, whole);
+
+        for record in &records {
+            let tokens: Vec<&str> = std::str::from_utf8(record).unwrap().split(' ').collect();
+            assert_eq!(tokens.len(), 5);
+            let numbers = tokens
+                .iter()
+                .filter(|t| t.len() == 3 && t.bytes().all(|b| b.is_ascii_digit()))
+                .count();
+            assert_eq!(numbers, 1);
+            for word in &tokens {
+                if word.bytes().all(|b| b.is_ascii_digit()) {
+                    continue;
+                }
+                assert!(word.as_bytes()[0].is_ascii_uppercase());
+                let lower = word.to_ascii_lowercase();
+                assert!(passphrase::wordlist().contains(&lower.as_str()));
+            }
+        }
+
+        let file = std::env::temp_dir().join("mpg_passphrase_test.txt");
+        g.write_to_file(&File::create(&file).unwrap(), |_, _| ())
+            .unwrap();
+        assert_eq!(std::fs::read(&file).unwrap(), whole);
+        std::fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn drawn_lengths_are_variable_width_records() {
+        let dist = LengthDist::parse("8:0.2,10:0.3,12:0.3,16:0.2").unwrap();
+        for iv_strategy in IvStrategy::ALL {
+            let g = builder(2000, 64)
+                .iv_strategy(iv_strategy)
+                .encoder(Encoder::with_lengths(&Charset::Alnum, dist.clone()))
+                .build()
+                .unwrap();
+            assert!(!g.fixed_width());
+            let whole = g.generate();
+            let chunks: Vec<u8> = g.par_chunks().flat_map_iter(|c| c.data).collect();
+            assert_eq!(chunks, whole);
+            let records: Vec<Vec<u8>> = g.records().collect();
+            assert_eq!(records.len(), 2000);
+            let joined: Vec<u8> = records
+                .iter()
+                .flat_map(|r| [&r[..], b"\n"].concat())
+                .collect();
+            assert_eq!(joined, whole);
+            let mut seen = [0usize; 17];
+            for record in &records {
+                seen[record.len()] += 1;
+            }
+            let total: usize = seen.iter().sum();
+            assert_eq!(seen[8] + seen[10] + seen[12] + seen[16], total);
+            assert!((300..500).contains(&seen[8]), "{:?}", seen);
+            assert!((500..700).contains(&seen[12]), "{:?}", seen);
+        }
+        let err = builder(10, 10)
+            .encoder(Encoder::with_lengths(&Charset::Alnum, dist))
+            .policy(PasswordPolicy::default())
+            .build()
+            .err();
+        assert_eq!(err, Some(BuildError::LengthsConflict));
+    }
+
+    #[test]
+    fn shards_start_where_the_last_one_stopped() {
+        let dir = std::env::temp_dir();
+        let pass = Passphrase::new(3, "-", Capitalization::Lower, 0).unwrap();
+        let runs = [
+            (builder(1000, 50).build().unwrap(), Format::Raw),
+            (
+                builder(1000, 50)
+                    .encoder(Encoder::new(&Charset::Alnum, 9))
+                    .build()
+                    .unwrap(),
+                Format::Raw,
+            ),
+            (builder(1000, 50).build().unwrap(), Format::Csv),
+            (
+                builder(1000, 50).passphrase(pass).build().unwrap(),
+                Format::Raw,
+            ),
+        ];
+        for (g, format) in runs {
+            // Three shards of 300 records and one of 100
+            let paths: Vec<_> = (0..g.num_shards(300))
+                .map(|n| dir.join(format!("mpg_shard_offsets_{}", n)))
+                .collect();
+            assert_eq!(paths.len(), 4);
+            let files: Vec<File> = paths.iter().map(|p| File::create(p).unwrap()).collect();
+            let sink = format.sink(&g);
+            // Each shard is reported once, when all of it is there
+            let finished = std::sync::Mutex::new(Vec::new());
+            let on_shard = |n: usize| {
+                let len = std::fs::metadata(&paths[n]).unwrap().len();
+                finished.lock().unwrap().push((n, len));
+            };
+            g.write_sharded(sink.as_deref(), &files, 300, |_, _| (), on_shard)
+                .unwrap();
+            let sharded: Vec<_> = paths.iter().map(|p| std::fs::read(p).unwrap()).collect();
+            let lens: Vec<_> = sharded.iter().map(|s| s.len() as u64).enumerate().collect();
+            let mut reported = std::mem::take(&mut *finished.lock().unwrap());
+            reported.sort_unstable();
+            assert_eq!(reported, lens);
+            // Striped over devices, the shards come out the same
+            let striped: Vec<_> = paths.iter().map(|p| File::create(p).ok()).collect();
+            let shards = ShardWrite {
+                sink: sink.as_deref(),
+                codec: None,
+                files: &striped,
+                shard_size: 300,
+                from: 0,
+                spare: None,
+            };
+            let (chunks, devices) = g.write_striped(&shards, 3, |idx, _| idx, on_shard).unwrap();
+            let mut reported = std::mem::take(&mut *finished.lock().unwrap());
+            reported.sort_unstable();
+            assert_eq!(reported, lens);
+            assert_eq!(chunks, (0..g.num_chunks()).collect::<Vec<_>>());
+            assert_eq!(
+                devices.iter().map(|d| d.shards).collect::<Vec<_>>(),
+                [2, 1, 1]
+            );
+            let bytes: u64 = sharded.iter().map(|s| s.len() as u64).sum();
+            assert_eq!(devices.iter().map(|d| d.bytes).sum::<u64>(), bytes);
+            let records: Vec<Vec<u8>> = g.records().collect();
+            for (n, path) in paths.iter().enumerate() {
+                assert_eq!(
+                    std::fs::read(path).unwrap(),
+                    sharded[n],
+                    "striped shard {}",
+                    n
+                );
+                let first = n * 300;
+                let shard = &records[first..(first + 300).min(1000)];
+                let mut expected = sink.as_ref().map_or(Vec::new(), |s| s.header().to_vec());
+                for (i, record) in shard.iter().enumerate() {
+                    match &sink {
+                        Some(sink) => sink.write_record(first + i, record, &mut expected),
+                        None => {
+                            expected.extend_from_slice(record);
+                            if !g.is_binary() {
+                                expected.push(b'\n');
+                            }
+                        }
+                    }
+                }
+                assert_eq!(std::fs::read(path).unwrap(), expected, "shard {}", n);
+            }
+            // One file per shard, no more and no fewer
+            assert!(g
+                .write_sharded(None, &files[..3], 300, |_, _| (), |_| ())
+                .is_err());
+            assert!(g.write_sharded(None, &files, 0, |_, _| (), |_| ()).is_err());
+            for path in paths {
+                std::fs::remove_file(path).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn failover_keeps_every_chunk() {
+        let dir = std::env::temp_dir();
+        let primary = dir.join("mpg_failover_primary.bin");
+        let spare = dir.join("mpg_failover_spare.bin");
+        // A read-only handle fails every write, like a dead mount would
+        File::create(&primary).unwrap();
+        let broken = File::open(&primary).unwrap();
+        let pass = Passphrase::new(3, "-", Capitalization::Lower, 0).unwrap();
+        for g in [
+            builder(1000, 64).build().unwrap(),
+            builder(300, 64).passphrase(pass).build().unwrap(),
+        ] {
+            let (_, report) = g
+                .write_with_fallback(
+                    None,
+                    Some(&broken),
+                    &File::create(&spare).unwrap(),
+                    |_, _| (),
+                )
+                .unwrap();
+            assert!(report.primary_error.is_some());
+            assert_eq!(report.positioned, g.fixed_width());
+            assert!(report.landings.iter().all(|&l| l == Landing::Fallback));
+            assert_eq!(report.landings.len(), g.num_chunks());
+            assert_eq!(std::fs::read(&spare).unwrap(), g.generate());
+
+            // Without trouble the fallback stays empty
+            let (_, report) = g
+                .write_with_fallback(
+                    None,
+                    Some(&File::create(&primary).unwrap()),
+                    &File::create(&spare).unwrap(),
+                    |_, _| (),
+                )
+                .unwrap();
+            assert!(report.primary_error.is_none());
+            assert!(report.landings.iter().all(|&l| l == Landing::Primary));
+            assert_eq!(std::fs::read(&primary).unwrap(), g.generate());
+            assert!(std::fs::read(&spare).unwrap().is_empty());
+        }
+        std::fs::remove_file(primary).unwrap();
+        std::fs::remove_file(spare).unwrap();
+    }
+
+    #[test]
+    fn failed_shards_move_whole_onto_their_spares() {
+        let dir = std::env::temp_dir();
+        let path = |name: &str, n: usize| dir.join(format!("mpg_spares_{}_{}", name, n));
+        let g = builder(1000, 50).build().unwrap();
+        let sink = Format::Csv.sink(&g);
+        let files: Vec<File> = (0..4)
+            .map(|n| File::create(path("whole", n)).unwrap())
+            .collect();
+        g.write_sharded(sink.as_deref(), &files, 300, |_, _| (), |_| ())
+            .unwrap();
+
+        // Shard 2's file fails every write, and shard 3 has none
+        let mut files: Vec<Option<File>> = (0..4)
+            .map(|n| Some(File::create(path("own", n)).unwrap()))
+            .collect();
+        files[1] = Some(File::open(path("own", 1)).unwrap());
+        files[2] = None;
+        let open = |n: usize| File::create(path("spare", n));
+        let shards = ShardWrite {
+            sink: sink.as_deref(),
+            codec: None,
+            files: &files,
+            shard_size: 300,
+            from: 0,
+            spare: Some(&open),
+        };
+        let finished = std::sync::Mutex::new(Vec::new());
+        let (chunks, report) = g
+            .write_shards(
+                &shards,
+                |idx, _| idx,
+                |n, landing| finished.lock().unwrap().push((n, landing)),
+            )
+            .unwrap();
+        assert_eq!(chunks, (0..g.num_chunks()).collect::<Vec<_>>());
+        let (own, spare) = (Landing::Primary, Landing::Fallback);
+        assert_eq!(report.landings, [own, spare, spare, own]);
+        assert_eq!(
+            report.errors.iter().map(|(n, _)| *n).collect::<Vec<_>>(),
+            [1]
+        );
+        let finished = finished.into_inner().unwrap();
+        assert_eq!(finished, [(0, own), (1, spare), (2, spare), (3, own)]);
+        for (n, landing) in report.landings.iter().enumerate() {
+            let landed = match landing {
+                Landing::Primary => path("own", n),
+                Landing::Fallback => path("spare", n),
+            };
+            assert_eq!(
+                std::fs::read(landed).unwrap(),
+                std::fs::read(path("whole", n)).unwrap(),
+                "shard {}",
+                n
+            );
+        }
+
+        // Without a spare, the shard's error fails the run
+        let shards = ShardWrite {
+            spare: None,
+            ..shards
+        };
+        assert!(g.write_shards(&shards, |_, _| (), |_, _| ()).is_err());
+
+        // Resumed from shard 3, the shards before it are left alone
+        let files: Vec<Option<File>> = (0..4)
+            .map(|n| (n >= 2).then(|| File::create(path("resumed", n)).unwrap()))
+            .collect();
+        let shards = ShardWrite {
+            files: &files,
+            from: 2,
+            ..shards
+        };
+        let finished = std::sync::Mutex::new(Vec::new());
+        let (chunks, report) = g
+            .write_shards(
+                &shards,
+                |idx, _| idx,
+                |n, _| finished.lock().unwrap().push(n),
+            )
+            .unwrap();
+        assert_eq!(chunks, (12..g.num_chunks()).collect::<Vec<_>>());
+        assert_eq!(report.landings, [own; 4]);
+        assert_eq!(finished.into_inner().unwrap(), [2, 3]);
+        for n in 2..4 {
+            assert_eq!(
+                std::fs::read(path("resumed", n)).unwrap(),
+                std::fs::read(path("whole", n)).unwrap()
+            );
+        }
+        for name in ["whole", "own", "spare", "resumed"] {
+            for n in 0..4 {
+                let _ = std::fs::remove_file(path(name, n));
+            }
+        }
+    }
+
+    #[test]
+    fn mapped_output_matches_or_falls_back() {
+        let path = std::env::temp_dir().join("mpg_mapped_test.bin");
+        let read_write = || {
+            File::options()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&path)
+                .unwrap()
+        };
+        let pass = Passphrase::new(3, "-", Capitalization::Lower, 0).unwrap();
+        let raw = builder(1000, 64).build().unwrap();
+        // Old, longer contents must not survive into the XORed output
+        std::fs::write(&path, vec![0xa5; 40_000]).unwrap();
+        let (chunks, why) = raw.write_mapped(&read_write(), |i, _| i).unwrap();
+        assert!(why.is_none());
+        assert_eq!(chunks, (0..raw.num_chunks()).collect::<Vec<_>>());
+        assert_eq!(std::fs::read(&path).unwrap(), raw.generate());
+
+        // A write-only handle cannot be mapped, nor can passphrases be
+        let passphrases = builder(300, 64).passphrase(pass).build().unwrap();
+        for (g, file) in [
+            (&raw, File::create(&path).unwrap()),
+            (&passphrases, read_write()),
+        ] {
+            let (_, why) = g.write_mapped(&file, |_, _| ()).unwrap();
+            assert!(why.is_some());
+            assert_eq!(std::fs::read(&path).unwrap(), g.generate());
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn resuming_gives_identical_output() {
+        let file = std::env::temp_dir().join("mpg_resume_test.out");
+        let pass = Passphrase::new(3, ",", Capitalization::Title, 2).unwrap();
+        let raw = builder(1000, 64).build().unwrap();
+        let phrases = builder(300, 64).passphrase(pass).build().unwrap();
+        for (g, format) in [(&raw, Format::Raw), (&phrases, Format::Csv)] {
+            let sink = format.sink(g);
+            let sink = sink.as_deref();
+            let mut expected = Vec::new();
+            match sink {
+                None => g.write_to(&mut expected, |_, _| ()).unwrap(),
+                Some(sink) => g.write_formatted(sink, &mut expected, |_, _| ()).unwrap(),
+            };
+
+            let mut checkpoints = Vec::new();
+            let out = File::create(&file).unwrap();
+            g.write_resumable(
+                sink,
+                &out,
+                Progress::default(),
+                |_, _| (),
+                |p| checkpoints.push(p),
+            )
+            .unwrap();
+            assert_eq!(checkpoints.last().unwrap().chunks, g.num_chunks());
+            assert_eq!(checkpoints.last().unwrap().bytes, expected.len() as u64);
+
+            // Crash after a checkpoint, with the next chunk partly written
+            let from = checkpoints[checkpoints.len() / 2];
+            let mut damaged = expected[..from.bytes as usize].to_vec();
+            damaged.extend_from_slice(b"torn chunk");
+            std::fs::write(&file, damaged).unwrap();
+            let out = File::options().write(true).open(&file).unwrap();
+            g.write_resumable(sink, &out, from, |_, _| (), |_| ())
+                .unwrap();
+            assert_eq!(std::fs::read(&file).unwrap(), expected);
+        }
+        std::fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn policies_screen_every_record() {
+        let policy = PasswordPolicy {
+            min_upper: 1,
+            min_digits: 2,
+            min_symbols: 1,
+            max_repeat: Some(1),
+            ..Default::default()
+        };
+        let enc = Encoder::new(&Charset::AlnumSymbols, 10);
+        for strategy in [IvStrategy::ChunkIndex, IvStrategy::Continuous] {
+            let g = builder(3000, 256)
+                .iv_strategy(strategy)
+                .encoder(enc.clone())
+                .policy(policy.clone())
+                .build()
+                .unwrap();
+            let whole = g.generate();
+            let stats = g.resample_stats().unwrap();
+            let mut records = 0;
+            g.for_each_record(&whole, |record| {
+                assert!(policy.check(record), "{:?}", record);
+                records += 1;
+            });
+            assert_eq!(records, 3000);
+            let chunks: Vec<u8> = g.par_chunks().flat_map_iter(|c| c.data).collect();
+            assert_eq!(chunks, whole);
+
+            // Compliant records are the unscreened run's, and the others
+            // are resampled in place
+            let plain = builder(3000, 256)
+                .iv_strategy(strategy)
+                .encoder(enc.clone())
+                .build()
+                .unwrap();
+            let kept = plain
+                .records()
+                .zip(g.records())
+                .filter(|(plain, screened)| plain == screened)
+                .count();
+            let compliant = plain.records().filter(|r| policy.check(r)).count();
+            assert_eq!(kept, compliant);
+            assert_eq!(stats.records, 3000);
+            assert_eq!(stats.resampled as usize, 3000 - compliant);
+            assert!(stats.replacements >= stats.resampled);
+            assert!(stats.most_tries <= stats.bound);
+        }
+
+        // Under continuous IVs, records do not depend on the chunk size
+        let continuous = |chunk_size| {
+            builder(1000, chunk_size)
+                .iv_strategy(IvStrategy::Continuous)
+                .encoder(enc.clone())
+                .policy(policy.clone())
+                .build()
+                .unwrap()
+                .generate()
+        };
+        assert_eq!(continuous(64), continuous(333));
+
+        let err = builder(10, 10).policy(policy.clone()).build().err();
+        assert_eq!(err, Some(BuildError::PolicyNeedsEncoder));
+        let hex = builder(10, 10).encoder(Encoder::new(&Charset::Hex, 10));
+        assert!(matches!(
+            hex.policy(policy).build().err(),
+            Some(BuildError::UnsatisfiablePolicy(_))
+        ));
+    }
+
+    #[test]
+    fn passphrases_exclude_encoders_and_watermarks() {
+        let pass = || Passphrase::new(6, "-", Capitalization::Lower, 0).unwrap();
+        let enc = Encoder::new(&Charset::Hex, 8);
+        let err = builder(10, 10)
+            .passphrase(pass())
+            .encoder(enc)
+            .build()
+            .err();
+        assert_eq!(err, Some(BuildError::PassphraseConflict));
+        assert_eq!(passphrase::wordlist().len(), 7776);
+    }
+
+    #[test]
+    fn passphrase_styles_add_their_extras() {
+        let style = PassphraseStyle::parse("Capitalize+digit:3+symbol:+@").unwrap();
+        assert_eq!(style.to_string(), "capitalize+digit:3+symbol:+@");
+        assert_eq!(
+            PassphraseStyle::parse(&style.to_string()),
+            Ok(style.clone())
+        );
+        let pass = Passphrase::new(4, "-", Capitalization::Lower, 0)
+            .unwrap()
+            .styled(style.clone())
+            .unwrap();
+        let plain = Passphrase::new(4, "-", Capitalization::Lower, 0).unwrap();
+        let extra = pass.entropy_bits() - plain.entropy_bits();
+        assert!((extra - (15f64.log2() + 3.0 + 3.0 * 10f64.log2())).abs() < 1e-9);
+        let g = builder(500, 64).passphrase(pass.clone()).build().unwrap();
+        for record in g.records() {
+            assert!(record.len() <= pass.max_len());
+            let (words, digits) = record.split_at(record.len() - 3);
+            assert!(digits.iter().all(u8::is_ascii_digit));
+            let words: Vec<&[u8]> = words.split(|b| b"+@".contains(b)).collect();
+            assert_eq!(words.len(), 4);
+            assert!(words.iter().any(|w| w[0].is_ascii_uppercase()));
+            assert!(words
+                .iter()
+                .all(|w| w[1..].iter().all(u8::is_ascii_lowercase)));
+        }
+        // The all-zero keystream of a filler run still capitalizes a word
+        let filler = builder(3, 64)
+            .passphrase(pass)
+            .filler(true)
+            .build()
+            .unwrap();
+        assert!(filler.records().all(|r| r[0].is_ascii_uppercase()));
+
+        let title = Passphrase::new(4, "-", Capitalization::Title, 0).unwrap();
+        assert!(title.styled(style).is_err());
+        for spec in [
+            "",
+            "digit:17",
+            "symbol:ab c",
+            "capitalize+capitalize",
+            "bold",
+        ] {
+            assert!(PassphraseStyle::parse(spec).is_err(), "{}", spec);
+        }
+    }
+
+    #[test]
+    fn wordlists_load_and_check() {
+        let short = Wordlist::parse("eff-short").unwrap();
+        assert_eq!(short.len(), 1296);
+        assert_eq!(Wordlist::eff().words(), passphrase::wordlist());
+        assert!(Wordlist::parse("de").unwrap_err().contains("file:PATH"));
+
+        let path = std::env::temp_dir().join(format!("mpg_wordlist_{}", std::process::id()));
+        std::fs::write(&path, "# a comment\n\n11 straße\n12 öl\n13 Äpfel\n").unwrap();
+        let list = Wordlist::parse(&format!("file:{}", path.display())).unwrap();
+        assert_eq!(list.words(), ["straße", "öl", "Äpfel"]);
+        assert_eq!(Wordlist::from_words("copy", list.words()), Ok(list.clone()));
+        let pass =
+            Passphrase::with_wordlist(list.clone(), 3, " ", Capitalization::Upper, 0).unwrap();
+        assert_eq!(pass.max_len(), "STRASSE".len() * 3 + 2);
+        let g = builder(200, 64).passphrase(pass).build().unwrap();
+        for record in g.records() {
+            let text = String::from_utf8(record).unwrap();
+            assert!(text
+                .split(' ')
+                .all(|w| ["STRASSE", "ÖL", "ÄPFEL"].contains(&w)));
+        }
+        for (text, why) in [
+            ("a\nb\nA\n", "line 3: `A` repeats line 1"),
+            ("a b c\n", "expected one word"),
+            ("only\n", "1 words"),
+        ] {
+            std::fs::write(&path, text).unwrap();
+            let err = Wordlist::load(&path).unwrap_err();
+            assert!(err.contains(why), "{}", err);
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn localized_wordlists_give_eleven_bits_a_word() {
+        for (name, sha256) in [
+            (
+                "es",
+                "0c639b0d58b6e56c18dcf418017ff341418a129e45fbf303361e8569edb02efe",
+            ),
+            (
+                "fr",
+                "431c1d074225d2b7e82db857d7c3ea58051df546e7c8b74c1f6dcab36351fd56",
+            ),
+            (
+                "it",
+                "d392c49fdb700a24cd1fceb237c1f65dcc128f6b34a8aacb58b59384b5c648c2",
+            ),
+            (
+                "pt",
+                "2685e9c194c82ae67e10ba59d9ea5345a23dc093e92276fc5361f6667d79cd3f",
+            ),
+        ] {
+            let list = Wordlist::parse(name).unwrap();
+            assert_eq!((list.len(), list.sha256()), (2048, sha256), "{}", name);
+            assert!(list.is_built_in());
+            // Checked as a loaded list would be: no repeats, one word a line
+            assert_eq!(Wordlist::from_words(name, list.words()), Ok(list.clone()));
+            // Accents are precomposed, with no combining marks
+            assert!(list
+                .words()
+                .iter()
+                .flat_map(|w| w.chars())
+                .all(|c| !('\u{300}'..='\u{36f}').contains(&c)));
+
+            let lower = Passphrase::with_wordlist(list.clone(), 6, " ", Capitalization::Lower, 0);
+            assert_eq!(lower.unwrap().entropy_bits(), 66.0);
+            let upper =
+                Passphrase::with_wordlist(list.clone(), 6, " ", Capitalization::Upper, 0).unwrap();
+            let g = builder(200, 64).passphrase(upper).build().unwrap();
+            for record in g.records() {
+                let text = String::from_utf8(record).unwrap();
+                let words: Vec<&str> = text.split(' ').collect();
+                assert_eq!(words.len(), 6);
+                assert!(words
+                    .iter()
+                    .all(|w| list.words().contains(&w.to_lowercase())));
+            }
+        }
+    }
+
+    #[test]
+    fn filler_has_the_shape_but_not_the_content() {
+        let b = builder(300, 64).encoder(Encoder::new(&Charset::Alnum, 12));
+        let real = b.clone().build().unwrap().generate();
+        let filler = b.filler(true).build().unwrap().generate();
+        assert_eq!(filler.len(), real.len());
+        assert_ne!(filler, real);
+        assert!(filler.chunks(13).all(|r| r == b"AAAAAAAAAAAA\n"));
+    }
+
+    #[test]
+    fn random_keys_differ() {
+        let (a, b) = (random_key().unwrap(), random_key().unwrap());
+        assert_ne!(a, b);
+        assert_ne!(key_fingerprint(&a), key_fingerprint(&b));
+        assert_eq!(key_fingerprint(&a).len(), 16);
+    }
+
+    #[test]
+    fn parse_key_hex_round_trips() {
+        let key = parse_key_hex("000102030405060708090a0b0c0d0e0f").unwrap();
+        assert_eq!(key.narrow(), Some(&std::array::from_fn(|i| i as u8)));
+        let wide = "00".repeat(31) + "ff";
+        assert_eq!(parse_key_hex(&wide).unwrap().to_hex(), wide);
+        assert!(parse_key_hex("00").is_err());
+        assert!(parse_key_hex(&"00".repeat(24)).is_err());
+        assert!(parse_key_hex("zz0102030405060708090a0b0c0d0e0f").is_err());
+    }
+}
@@ -0,0 +1,343 @@
+//! Where `serve` listens, and `fetch` connects: a TCP address, a Unix
+//! socket, or a vsock port.
+//!
+//! The same HTTP runs over each. A Unix socket serves clients on the same
+//! machine, with the socket file's permissions as its access control; a
+//! vsock port serves the host of the virtual machine it runs in (or its
+//! guests), so credentials generated in a VM reach the host agent without
+//! a network or a disk in between.
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+#[cfg(unix)]
+use std::{
+    os::unix::net::{UnixListener, UnixStream},
+    path::PathBuf,
+};
+
+#[cfg(target_os = "linux")]
+use crate::vsock;
+
+/// An address for `serve --bind`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Address {
+    /// `HOST:PORT`
+    Tcp(String),
+    /// `unix:PATH`
+    #[cfg(unix)]
+    Unix(PathBuf),
+    /// `vsock:CID:PORT`, CID `any` for whatever this machine has
+    #[cfg(target_os = "linux")]
+    Vsock { cid: u32, port: u32 },
+}
+
+impl Address {
+    /// Parses `HOST:PORT`, `unix:PATH` or `vsock:CID:PORT`.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            #[cfg(unix)]
+            return match path {
+                "" => Err("unix: needs the path of the socket".to_string()),
+                path => Ok(Address::Unix(PathBuf::from(path))),
+            };
+            #[cfg(not(unix))]
+            return Err(format!("`{}`: Unix sockets need a Unix system", path));
+        }
+        if let Some(rest) = s.strip_prefix("vsock:") {
+            #[cfg(target_os = "linux")]
+            {
+                let invalid = || format!("invalid vsock address `{}` (expected vsock:CID:PORT)", s);
+                let (cid, port) = rest.split_once(':').ok_or_else(invalid)?;
+                let cid = match cid {
+                    "any" => vsock::CID_ANY,
+                    cid => cid.parse().map_err(|_| invalid())?,
+                };
+                let port = port.parse().map_err(|_| invalid())?;
+                return Ok(Address::Vsock { cid, port });
+            }
+            #[cfg(not(target_os = "linux"))]
+            return Err(format!("`{}`: vsock is only supported on Linux", rest));
+        }
+        if !s.contains(':') {
+            return Err(format!(
+                "invalid address `{}` (expected HOST:PORT, unix:PATH or vsock:CID:PORT)",
+                s
+            ));
+        }
+        Ok(Address::Tcp(s.to_string()))
+    }
+
+    /// Connects to a server listening here.
+    pub fn connect(&self) -> io::Result<Stream> {
+        match self {
+            Address::Tcp(address) => TcpStream::connect(address).map(Stream::Tcp),
+            #[cfg(unix)]
+            Address::Unix(path) => UnixStream::connect(path).map(Stream::Unix),
+            #[cfg(target_os = "linux")]
+            Address::Vsock { cid, port } => vsock::Stream::connect(*cid, *port).map(Stream::Vsock),
+        }
+    }
+
+    /// Starts listening. A Unix socket is created readable and writable by
+    /// its owner only, replacing a stale one no server listens on.
+    pub fn bind(&self) -> io::Result<Listener> {
+        match self {
+            Address::Tcp(address) => TcpListener::bind(address).map(Listener::Tcp),
+            #[cfg(unix)]
+            Address::Unix(path) => {
+                use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+                let stale = std::fs::symlink_metadata(path)
+                    .is_ok_and(|meta| meta.file_type().is_socket())
+                    && UnixStream::connect(path)
+                        .is_err_and(|e| e.kind() == io::ErrorKind::ConnectionRefused);
+                if stale {
+                    std::fs::remove_file(path)?;
+                }
+                let listener = UnixListener::bind(path)?;
+                std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+                Ok(Listener::Unix(listener, path.clone()))
+            }
+            #[cfg(target_os = "linux")]
+            Address::Vsock { cid, port } => vsock::Listener::bind(*cid, *port).map(Listener::Vsock),
+        }
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Address::Tcp(address) => write!(f, "{}", address),
+            #[cfg(unix)]
+            Address::Unix(path) => write!(f, "unix:{}", path.display()),
+            #[cfg(target_os = "linux")]
+            Address::Vsock { cid, port } if *cid == vsock::CID_ANY => {
+                write!(f, "vsock:any:{}", port)
+            }
+            #[cfg(target_os = "linux")]
+            Address::Vsock { cid, port } => write!(f, "vsock:{}:{}", cid, port),
+        }
+    }
+}
+
+/// A listening socket of any kind.
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+    #[cfg(target_os = "linux")]
+    Vsock(vsock::Listener),
+}
+
+/// A connection of any kind.
+pub enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    #[cfg(target_os = "linux")]
+    Vsock(vsock::Stream),
+}
+
+/// Where a connection comes from.
+pub struct Peer {
+    /// For the request log: the socket address
+    pub address: String,
+    /// Who it is, for rate limits and the audit log: `<ip>`, `uid:<uid>`
+    /// on a Unix socket, or `vsock:<cid>`
+    pub client: String,
+}
+
+impl Listener {
+    /// The next connection.
+    pub fn accept(&self) -> io::Result<(Stream, Peer)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, address) = listener.accept()?;
+                let peer = Peer {
+                    address: address.to_string(),
+                    client: address.ip().to_string(),
+                };
+                Ok((Stream::Tcp(stream), peer))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener, path) => {
+                let (stream, _) = listener.accept()?;
+                let client = peer_uid(&stream)
+                    .map_or_else(|| "unix".to_string(), |uid| format!("uid:{}", uid));
+                let peer = Peer {
+                    address: format!("unix:{}", path.display()),
+                    client,
+                };
+                Ok((Stream::Unix(stream), peer))
+            }
+            #[cfg(target_os = "linux")]
+            Listener::Vsock(listener) => {
+                let (stream, cid) = listener.accept()?;
+                let peer = Peer {
+                    address: format!("vsock:{}", cid),
+                    client: format!("vsock:{}", cid),
+                };
+                Ok((Stream::Vsock(stream), peer))
+            }
+        }
+    }
+
+    /// Where it listens, as `--bind` takes it.
+    pub fn local(&self) -> io::Result<Address> {
+        match self {
+            Listener::Tcp(listener) => Ok(Address::Tcp(listener.local_addr()?.to_string())),
+            #[cfg(unix)]
+            Listener::Unix(_, path) => Ok(Address::Unix(path.clone())),
+            #[cfg(target_os = "linux")]
+            Listener::Vsock(listener) => {
+                let (cid, port) = listener.local()?;
+                Ok(Address::Vsock { cid, port })
+            }
+        }
+    }
+}
+
+/// The user at the other end of a Unix socket.
+#[cfg(unix)]
+fn peer_uid(stream: &UnixStream) -> Option<u32> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::fd::AsRawFd;
+        // SAFETY: ucred is plain integers, for which zero is valid
+        let mut cred: libc::ucred = unsafe { std::mem::zeroed() };
+        let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+        // SAFETY: `cred` has room for the `len` bytes written
+        let result = unsafe {
+            libc::getsockopt(
+                stream.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_PEERCRED,
+                &mut cred as *mut libc::ucred as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        (result == 0).then_some(cred.uid)
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = stream;
+        None
+    }
+}
+
+impl Stream {
+    /// Sets how long reads and writes may block.
+    pub fn set_timeouts(&self, read: Duration, write: Duration) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => {
+                stream.set_read_timeout(Some(read))?;
+                stream.set_write_timeout(Some(write))
+            }
+            #[cfg(unix)]
+            Stream::Unix(stream) => {
+                stream.set_read_timeout(Some(read))?;
+                stream.set_write_timeout(Some(write))
+            }
+            #[cfg(target_os = "linux")]
+            Stream::Vsock(stream) => stream.set_timeouts(read, write),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.read(buf),
+            #[cfg(target_os = "linux")]
+            Stream::Vsock(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.write(buf),
+            #[cfg(target_os = "linux")]
+            Stream::Vsock(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.flush(),
+            #[cfg(target_os = "linux")]
+            Stream::Vsock(stream) => stream.flush(),
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn addresses_parse_and_show_as_given() {
+        for address in [
+            "127.0.0.1:8080",
+            "[::1]:0",
+            "unix:/run/mpg.sock",
+            "vsock:any:5000",
+            "vsock:3:5000",
+        ] {
+            assert_eq!(Address::parse(address).unwrap().to_string(), address);
+        }
+        assert_eq!(
+            Address::parse("vsock:any:5000"),
+            Ok(Address::Vsock {
+                cid: vsock::CID_ANY,
+                port: 5000
+            })
+        );
+        for bad in [
+            "8080",
+            "unix:",
+            "vsock:5000",
+            "vsock:host:1",
+            "vsock:2:port",
+        ] {
+            assert!(Address::parse(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn unix_sockets_are_private_and_replace_stale_ones() {
+        use std::os::unix::fs::PermissionsExt;
+        let path = std::env::temp_dir().join(format!("mpg_listen_{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let address = Address::Unix(path.clone());
+        let listener = address.bind().unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let mut client = address.connect().unwrap();
+        let (mut stream, peer) = listener.accept().unwrap();
+        // SAFETY: getuid cannot fail
+        assert_eq!(peer.client, format!("uid:{}", unsafe { libc::getuid() }));
+        client.write_all(b"ping").unwrap();
+        let mut ping = [0; 4];
+        stream.read_exact(&mut ping).unwrap();
+        assert_eq!(&ping, b"ping");
+        // A live socket is not taken over
+        assert!(address.bind().is_err());
+
+        // Once nothing listens, the socket file left behind is replaced
+        drop((listener, stream));
+        assert!(path.exists());
+        let listener = address.bind().unwrap();
+        assert_eq!(listener.local().unwrap(), address);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
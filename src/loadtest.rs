@@ -0,0 +1,245 @@
+//! `loadtest`: send freshly generated credentials to an HTTP API, one per
+//! request, and report how it held up.
+//!
+//! Each request's body is the template with the next record of the run in
+//! it, so every request carries a credential no other request has. Workers
+//! send at once up to `--concurrency`, and with a `--rate` each request
+//! waits for its slot on a fixed schedule from the start, so a slow reply
+//! delays only its own worker. Replies are read to the end; a request
+//! counts as an error if it got no reply, or one outside 2xx.
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use mass_password_gen_optimized::{hex, PasswordGenerator, Records};
+use serde::Serialize;
+
+use crate::bench::Percentiles;
+use crate::client::Client;
+use crate::serve::RateLimit;
+
+/// Placeholders a template may have.
+const PASSWORD: &str = "{password}";
+const PASSWORD_JSON: &str = "{password_json}";
+const INDEX: &str = "{index}";
+
+/// What each request is, and how fast they go.
+pub struct Options {
+    pub method: String,
+    /// Path and query of every request
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub template: String,
+    pub concurrency: usize,
+    pub rate: Option<RateLimit>,
+}
+
+/// How the target held up.
+#[derive(Debug, Default, Serialize, PartialEq)]
+pub struct Report {
+    pub requests: usize,
+    /// Replies in 2xx
+    pub ok: usize,
+    /// Replies outside 2xx, by status
+    pub statuses: BTreeMap<u16, usize>,
+    /// Requests that got no whole reply
+    pub failed: usize,
+    pub first_failure: Option<String>,
+    /// Errors (replies outside 2xx, and failures) over requests
+    pub error_rate: f64,
+    pub seconds: f64,
+    pub requests_per_sec: f64,
+    /// From sending each request to the end of its reply, for the requests
+    /// that got one
+    pub latency_ms: Option<Percentiles>,
+}
+
+/// Checks that `template` puts the credential somewhere.
+pub fn check_template(template: &str) -> Result<(), String> {
+    if !template.contains(PASSWORD) && !template.contains(PASSWORD_JSON) {
+        return Err(format!(
+            "the template never uses the credential: put {} or {} (as a JSON \
+             string) where it goes",
+            PASSWORD, PASSWORD_JSON
+        ));
+    }
+    Ok(())
+}
+
+/// The body of the request for record `index`, `password`.
+pub fn render(template: &str, index: usize, password: &str) -> String {
+    let json = serde_json::Value::from(password).to_string();
+    template
+        .replace(PASSWORD_JSON, &json)
+        .replace(PASSWORD, password)
+        .replace(INDEX, &index.to_string())
+}
+
+/// One request's outcome.
+enum Outcome {
+    Reply(u16, Duration),
+    Failed(String),
+}
+
+/// Sends one request per record of `generator` to `client`'s server.
+pub fn run(client: &Client, generator: &PasswordGenerator, options: &Options) -> Report {
+    let records: Mutex<(usize, Records)> = Mutex::new((0, generator.records()));
+    let binary = generator.is_binary();
+    let next = || {
+        let mut records = records.lock().unwrap_or_else(|e| e.into_inner());
+        let record = records.1.next()?;
+        let index = records.0;
+        records.0 += 1;
+        let password = match binary {
+            true => hex(&record),
+            false => String::from_utf8_lossy(&record).into_owned(),
+        };
+        Some((index, password))
+    };
+    let interval = options
+        .rate
+        .map(|rate| rate.per.div_f64(f64::from(rate.requests)));
+    let started = Instant::now();
+    let outcomes: Vec<Vec<Outcome>> = std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..options.concurrency.max(1))
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut outcomes = Vec::new();
+                    while let Some((index, password)) = next() {
+                        if let Some(interval) = interval {
+                            let due = started + interval.mul_f64(index as f64);
+                            std::thread::sleep(due.saturating_duration_since(Instant::now()));
+                        }
+                        let body = render(&options.template, index, &password);
+                        outcomes.push(send(client, options, body.as_bytes()));
+                    }
+                    outcomes
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .map(|worker| worker.join().expect("workers do not panic"))
+            .collect()
+    });
+    let seconds = started.elapsed().as_secs_f64();
+
+    let mut report = Report::default();
+    let mut latencies = Vec::new();
+    for outcome in outcomes.into_iter().flatten() {
+        report.requests += 1;
+        match outcome {
+            Outcome::Reply(status, latency) => {
+                latencies.push(latency);
+                match status {
+                    200..=299 => report.ok += 1,
+                    status => *report.statuses.entry(status).or_default() += 1,
+                }
+            }
+            Outcome::Failed(e) => {
+                report.failed += 1;
+                report.first_failure.get_or_insert(e);
+            }
+        }
+    }
+    if report.requests > 0 {
+        report.error_rate = (report.requests - report.ok) as f64 / report.requests as f64;
+    }
+    report.seconds = seconds;
+    report.requests_per_sec = report.requests as f64 / seconds.max(f64::MIN_POSITIVE);
+    if !latencies.is_empty() {
+        report.latency_ms = Some(Percentiles::of(&mut latencies));
+    }
+    report
+}
+
+fn send(client: &Client, options: &Options, body: &[u8]) -> Outcome {
+    let sent = Instant::now();
+    let mut reply = match client.request(&options.method, &options.path, &options.headers, body) {
+        Ok(reply) => reply,
+        Err(e) => return Outcome::Failed(e.to_string()),
+    };
+    match std::io::copy(&mut reply, &mut std::io::sink()) {
+        Ok(_) => Outcome::Reply(reply.status, sent.elapsed()),
+        Err(e) => Outcome::Failed(format!("reading the reply: {}", e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::Server;
+    use crate::listen::Listener;
+    use crate::serve::{Policy, Reply};
+    use mass_password_gen_optimized::{Charset, Encoder};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn templates_take_the_credential() {
+        let template = r#"{"user":"u{index}","password":{password_json},"raw":"{password}"}"#;
+        assert!(check_template(template).is_ok());
+        assert!(check_template(r#"{"user":"u{index}"}"#).is_err());
+        let body = render(template, 7, r#"a"b\c"#);
+        assert_eq!(body, r#"{"user":"u7","password":"a\"b\\c","raw":"a"b\c"}"#);
+    }
+
+    #[test]
+    fn reports_errors_and_latencies() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = Server::parse(&format!("http://{}", listener.local_addr().unwrap())).unwrap();
+        let seen = AtomicUsize::new(0);
+        std::thread::spawn(move || {
+            crate::serve::run(Listener::Tcp(listener), &Policy::default(), |request| {
+                assert_eq!(
+                    (request.method.as_str(), request.path.as_str()),
+                    ("POST", "/users")
+                );
+                // Every fifth request is turned away
+                match seen.fetch_add(1, Ordering::Relaxed) % 5 {
+                    4 => Reply::Error(503, "busy".to_string()),
+                    _ => Reply::Stream {
+                        content_type: "application/json",
+                        headers: Vec::new(),
+                        pulled: None,
+                        body: Box::new(|out| std::io::Write::write_all(out, b"{}")),
+                    },
+                }
+            })
+        });
+        let generator = PasswordGenerator::builder()
+            .key([7; 16])
+            .count(50)
+            .chunk_size(16)
+            .encoder(Encoder::new(&Charset::Alnum, 12))
+            .build()
+            .unwrap();
+        let options = Options {
+            method: "POST".to_string(),
+            path: "/users".to_string(),
+            headers: vec![("Content-Type".to_string(), "application/json".to_string())],
+            template: r#"{"password":{password_json}}"#.to_string(),
+            concurrency: 4,
+            rate: Some(RateLimit::parse("500/s").unwrap()),
+        };
+        let report = run(&Client::new(server, None), &generator, &options);
+        assert_eq!((report.requests, report.ok, report.failed), (50, 40, 0));
+        assert_eq!(report.statuses, BTreeMap::from([(503, 10)]));
+        assert!((report.error_rate - 0.2).abs() < 1e-9);
+        // 50 requests at 500/s take at least 98ms
+        assert!(report.seconds >= 0.098, "{}", report.seconds);
+        assert!(report.latency_ms.is_some());
+
+        // A target that is not there fails every request
+        let gone = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = Server::parse(&format!("http://{}", gone.local_addr().unwrap())).unwrap();
+        drop(gone);
+        let options = Options {
+            rate: None,
+            ..options
+        };
+        let report = run(&Client::new(server, None), &generator, &options);
+        assert_eq!((report.failed, report.error_rate), (50, 1.0));
+        assert!(report.first_failure.is_some() && report.latency_ms.is_none());
+    }
+}
@@ -1,132 +1,2308 @@
-use aes::Aes128;
-use ctr::cipher::{KeyIvInit, StreamCipher};
-use ctr::Ctr128BE;
-use rayon::prelude::*;
-use std::time::Instant;
-use sysinfo::{CpuExt, System, SystemExt};
-
-// Each password is 16 bytes
-type PasswordBlock = [u8; 16];
-
-// Our AES-CTR type: 128-bit block size, big-endian counter
-type Aes128Ctr = Ctr128BE<Aes128>;
-
-// Adjust these if desired
-const NUM_PASSWORDS: usize = 4_000_000_000; // e.g. 1 billion
-const CHUNK_SIZE: usize = 1_000_000;       // 1 million => 16 MB per chunk
-
-fn main() {
-    // ------------------------------------------------------------------
-    // 1. Gather system details before we begin
-    // ------------------------------------------------------------------
-    let mut sys = System::new_all();
-    sys.refresh_all();
-
-    // CPU details
-    let cpu_count = sys.cpus().len();
-    // On many systems, sysinfo lumps hyperthreads into the CPU count.
-    let brand = if cpu_count > 0 {
-        sys.cpus()[0].brand().to_string()
+use clap::{Parser, Subcommand, ValueEnum};
+use mass_password_gen_optimized::double_check;
+use mass_password_gen_optimized::rotation;
+use mass_password_gen_optimized::timestamp;
+use mass_password_gen_optimized::token::{self, ExpiringTokens};
+#[cfg(feature = "derive")]
+use mass_password_gen_optimized::Site;
+#[cfg(feature = "hash")]
+use mass_password_gen_optimized::{
+    hashcat::{AnswerKey, HashMode, HashcatLines},
+    HashAlgorithm, HashedPairs,
+};
+use mass_password_gen_optimized::{
+    layout::{ByteOrder, Fill, RawLayout},
+    parse_key_hex,
+    pin::{self, WeakPattern},
+    settings, unique, Capitalization, Charset, Cipher, Codec, CorpusReader, Distribution, Encoder,
+    Format, Key, LengthDist, OutputSink, Passphrase, PassphraseStyle, PasswordGenerator,
+    PasswordGeneratorBuilder, PasswordPolicy, RecordIds, Schema, Template, TimestampSpread,
+    Watermark, Wordlist,
+};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::atomic::AtomicBool;
+use std::time::Duration;
+
+const GIB: f64 = 1024.0 * 1024.0 * 1024.0;
+
+/// Published key used by `--deterministic` without `--key-hex`; anything
+/// generated with it is public knowledge.
+const BENCHMARK_KEY: [u8; 16] = [0x13; 16];
+
+/// A published key as `cipher` takes it: for aes256, twice over.
+fn published_key(key: [u8; 16], cipher: Cipher) -> Key {
+    match cipher {
+        Cipher::Aes256 => Key::from_slice(&[key, key].concat()).expect("32 bytes"),
+        Cipher::Aes128 | Cipher::ChaCha20 => key.into(),
+    }
+}
+
+/// Time spent benchmarking ciphers for `--cipher auto`.
+const CIPHER_PROBE: Duration = Duration::from_millis(100);
+
+/// Status output goes to stdout, except when stdout carries the passwords.
+static STATUS_TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+macro_rules! status {
+    ($($arg:tt)*) => {
+        if $crate::STATUS_TO_STDERR.load(std::sync::atomic::Ordering::Relaxed) {
+            eprintln!($($arg)*)
+        } else {
+            println!($($arg)*)
+        }
+    };
+}
+
+mod batch;
+mod bench;
+mod bundle;
+mod cache;
+mod capabilities;
+mod catalog;
+mod checkpoint;
+mod client;
+mod commands;
+mod config;
+mod cursor;
+mod events;
+mod explain;
+mod failure;
+mod fetch;
+mod generate;
+mod hooks;
+mod keyprovider;
+mod keyring;
+mod listen;
+mod loadtest;
+mod memory;
+mod notify;
+mod output;
+mod perf;
+mod permissions;
+mod pkcs11;
+mod preview;
+mod progress;
+mod recipient;
+mod routes;
+mod seal;
+mod serve;
+mod shred;
+mod signing;
+mod sizing;
+mod soak;
+mod storage;
+mod throughput;
+#[cfg(feature = "tls")]
+mod tls;
+mod usage;
+#[cfg(target_os = "linux")]
+mod vsock;
+mod warnings;
+mod wizard;
+
+/// Bulk 128-bit password generator built on AES-CTR keystream.
+#[derive(Parser)]
+#[command(version, about, args_conflicts_with_subcommands = true)]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// How a failure is reported on stderr: text, or json for an object
+    /// with a stable code, the exit status and a hint
+    #[arg(long, global = true, value_enum, default_value = "text")]
+    log_format: failure::LogFormat,
+
+    /// Take the run's options from this file, as `wizard` saves them: a
+    /// line per long option, `name = "value"`, `name = 20` or `name = true`
+    /// for a switch. Options on the command line are added to the file's
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+
+    #[command(flatten)]
+    records: RecordArgs,
+
+    /// Passwords per parallel chunk; the last chunk may be shorter
+    #[arg(long, default_value = "1_000_000", value_parser = parse_chunk_size)]
+    chunk_size: usize,
+
+    /// Reproducible output: use --key or --key-hex, or the published
+    /// benchmark key, instead of a fresh random key per run
+    #[arg(long)]
+    deterministic: bool,
+
+    /// Key as 32 hex characters, or 64 for a 256-bit key (requires
+    /// --deterministic)
+    #[arg(long, value_parser = parse_key_hex, requires = "deterministic")]
+    key_hex: Option<Key>,
+
+    /// Key from a provider (requires --deterministic): `keyring:<name>`
+    /// (stored with `key store`), `env:<VAR>` or `file:<path>` holding 32
+    /// or 64 hex characters, `prompt` to type it, an RFC 7512 `pkcs11:`
+    /// URI for a key kept in an HSM or token, or
+    /// `derive:<master>/<label>` for a key derived from one of these
+    #[arg(long, value_parser = keyprovider::KeyUri::parse, requires = "deterministic",
+          conflicts_with = "key_hex")]
+    key: Option<keyprovider::KeyUri>,
+
+    /// Generate one segment of a job of this many records (up to
+    /// 2^128 - 1), split into runs of --count records; see `plan`
+    #[arg(long, value_parser = parse_total, requires_all = ["segment", "deterministic"])]
+    total: Option<u128>,
+
+    /// Which segment of --total to generate, from 0. Each segment is keyed
+    /// with its own key, derived from the job's key and the segment number.
+    #[arg(long, requires = "total", conflicts_with_all = ["resume", "demo", "sink_dry_run"])]
+    segment: Option<u128>,
+
+    /// Keystream cipher (default aes128, or aes256 under a 256-bit key);
+    /// `auto` benchmarks AES-128 and ChaCha20 briefly and picks the faster
+    #[arg(long, value_enum)]
+    cipher: Option<CipherChoice>,
+
+    /// Time every compiled keystream backend at startup, print the
+    /// comparison and run on the fastest. An explicit --cipher still
+    /// decides.
+    #[arg(long)]
+    auto_backend: bool,
+
+    /// Safe for screens and recordings: use a published key, generate at
+    /// most 1,000,000 records, put `DEMO-` in front of every password and
+    /// show made-up system details
+    #[arg(long, conflicts_with_all = [
+        "deterministic", "resume", "checkpoint", "sink_dry_run", "tenant",
+    ])]
+    demo: bool,
+
+    /// Once the run is done, show its first N records on stderr, in the
+    /// output format. Off by default, since they are live credentials;
+    /// --demo runs show 5 unless told otherwise.
+    #[arg(long, value_name = "N")]
+    preview: Option<usize>,
+
+    /// Worker threads (defaults to one per logical CPU, or per physical core
+    /// with --perf-mode)
+    #[arg(long)]
+    threads: Option<usize>,
+
+    /// Keep the run's CPU use under this share of the machine, e.g. `50%`,
+    /// by pacing each worker's chunks from measured use rather than by
+    /// running fewer workers
+    #[arg(long, value_parser = parse_cpu_limit)]
+    cpu_limit: Option<f64>,
+
+    /// Generate every chunk twice and compare the copies before writing it,
+    /// making more until two agree when they differ; the run fails if no
+    /// two of five do. Halves generation throughput. The copies are made on
+    /// the worker's thread (same-thread, the default) or at once on two
+    /// threads pinned to different cores (other-cores)
+    #[arg(long, value_name = "PLACEMENT", num_args = 0..=1,
+          default_missing_value = "same-thread", value_parser = double_check::Placement::parse)]
+    double_check: Option<double_check::Placement>,
+
+    /// Pin each worker thread to a core of its own
+    #[arg(long)]
+    pin_cores: bool,
+
+    /// Performance mode: size chunks to the L2 cache, overriding
+    /// --chunk-size, and default to one worker per physical core. With
+    /// chunk-index IVs the records depend on the chunk size, so rerun with
+    /// the size it reports as --chunk-size to reproduce the output.
+    #[arg(long, conflicts_with = "resume")]
+    perf_mode: bool,
+
+    /// Without --output, have each worker generate into a reused buffer of
+    /// its own instead of one allocation holding the whole run. The
+    /// passwords are timed, not kept.
+    #[arg(long, conflicts_with = "output")]
+    local_buffers: bool,
+
+    /// Memory a run without --output may take: a size such as 8GiB or
+    /// 512M, or a share of the available memory such as 50% (default 80%).
+    /// Checked before anything is generated.
+    #[arg(long, value_parser = memory::Limit::parse,
+          conflicts_with_all = ["output", "local_buffers"])]
+    max_memory: Option<memory::Limit>,
+
+    /// What to do with a run without --output that exceeds --max-memory:
+    /// `abort` with suggestions (the default), `cap` it to the first records
+    /// that fit, or `stream:PATH` to write it to PATH as --output would
+    #[arg(long, value_parser = memory::OverMemory::parse,
+          conflicts_with_all = ["output", "local_buffers"])]
+    over_memory: Option<memory::OverMemory>,
+
+    /// Pair each record with a line of this file, in order (the first line
+    /// with the first record, and so on), e.g. usernames that need new
+    /// passwords. --count must be the file's number of lines
+    #[arg(long, value_name = "FILE", conflicts_with_all = [
+        "format", "schema", "demo", "timestamp_spread", "with_id", "record_align", "header",
+        "binary_layout", "shard_size", "archive_format", "mmap", "checkpoint", "resume",
+    ])]
+    pair_with: Option<PathBuf>,
+
+    /// How --pair-with writes the pairs: csv (username,password rows) or
+    /// ldif (changes replacing each entry's userPassword)
+    #[arg(long, value_enum, default_value = "csv", requires = "pair_with")]
+    pair_format: PairOutput,
+
+    /// Base DN of the entries --pair-format ldif changes: each line of
+    /// --pair-with is the uid of one, uid=LINE,DN
+    #[arg(long, value_name = "DN", requires = "pair_with")]
+    ldif_base: Option<String>,
+
+    /// Stream the generated passwords to this file (`-` for stdout)
+    /// instead of holding them all in memory, or with shm://NAME into the
+    /// shared-memory segment NAME, raw records after a --header, for a
+    /// consumer on the same host to map
+    #[arg(long)]
+    output: Option<PathBuf>,
+
+    /// Permissions, in octal, of the output and every file written beside
+    /// it; existing files are set to them too
+    #[arg(long, value_parser = permissions::parse_mode, default_value = "0600")]
+    output_mode: u32,
+
+    /// Write into directories every user can write to, such as /tmp, which
+    /// is refused otherwise: another user could replace or read the output
+    #[arg(long)]
+    insecure_output: bool,
+
+    /// Check the output destination instead of generating: write public
+    /// filler of the run's exact size and shape, report the throughput,
+    /// then delete it
+    #[arg(long, requires = "output")]
+    sink_dry_run: bool,
+
+    /// Hot spare for --output: if the output file cannot be written, the
+    /// rest of the run goes here, followed by a report of which chunks
+    /// landed where. With --shard-size, an existing directory: a shard whose file
+    /// fails is written whole under its own name there instead, and the
+    /// manifest gives its path
+    #[arg(long, requires = "output", conflicts_with = "sink_dry_run")]
+    fallback: Option<PathBuf>,
+
+    /// Keep a checkpoint of the run in this sidecar file, so that a crashed
+    /// or interrupted run can be finished with --resume. The sidecar holds
+    /// the key and is removed when the run completes.
+    #[arg(long, requires = "output", conflicts_with_all = ["sink_dry_run", "fallback"])]
+    checkpoint: Option<PathBuf>,
+
+    /// Finish the run recorded in this checkpoint, with identical output.
+    /// Give the same record options again; the key, cipher, chunk size and
+    /// output file come from the checkpoint.
+    #[arg(long, conflicts_with_all = [
+        "checkpoint", "sink_dry_run", "fallback", "deterministic", "cipher", "chunk_size",
+    ])]
+    resume: Option<PathBuf>,
+
+    /// Compress the output with zstd, one frame per chunk, using a dictionary
+    /// trained on the run's first chunks. The dictionary is saved here and
+    /// is needed to decompress (`zstd -d -D <dict>`); it holds fragments of
+    /// the output, so keep it as private.
+    #[arg(long, requires = "output", value_parser = parse_zstd_dict, conflicts_with_all = [
+        "sink_dry_run", "fallback", "checkpoint", "resume",
+    ])]
+    zstd_dict: Option<PathBuf>,
+
+    /// Compress the output with zstd or gzip, each chunk on the worker that
+    /// generated it, into one frame (gzip member) per chunk; `zstd -d` and
+    /// `gzip -d` read the frames back as one stream
+    #[arg(long, value_parser = Codec::parse, requires = "output", conflicts_with_all = [
+        "sink_dry_run", "fallback", "checkpoint", "resume", "zstd_dict",
+    ])]
+    compress: Option<Codec>,
+
+    /// Level for --compress (zstd: 1-22, default 3; gzip: 0-9, default 6)
+    #[arg(long, requires = "compress", allow_negative_numbers = true)]
+    compress_level: Option<i32>,
+
+    /// Write --output as a self-describing archive: the output, a manifest
+    /// with SHA-256 hashes, and a description of the generation scheme with
+    /// known-answer vectors, checked by `verify`
+    #[arg(long, value_enum, requires = "output", conflicts_with_all = [
+        "sink_dry_run", "fallback", "checkpoint", "resume", "zstd_dict",
+    ])]
+    archive_format: Option<ArchiveFormat>,
+
+    /// Generate straight into a memory map of --output, sized to the run up
+    /// front, instead of writing each chunk from a buffer. Raw records
+    /// only; where the file cannot be mapped, positioned writes are used.
+    #[arg(long, requires = "output", conflicts_with_all = [
+        "sink_dry_run", "fallback", "checkpoint", "resume", "zstd_dict", "compress",
+        "archive_format",
+    ])]
+    mmap: bool,
+
+    /// Pad each raw 16-byte record out to a multiple of this many bytes, a
+    /// power of two (--record-align 64), so that every record starts on an
+    /// aligned boundary for a consumer that maps the output. The manifest
+    /// of a --shard-size run records the layout
+    #[arg(long, value_name = "N", conflicts_with_all = [
+        "mmap", "archive_format", "checkpoint", "resume", "schema", "demo", "timestamp_spread", "with_id",
+    ])]
+    record_align: Option<usize>,
+
+    /// What pads a record out to --record-align: zero bytes, or the
+    /// record's index as a u64 (in the --binary-layout order), repeated
+    #[arg(long, default_value = "zero", value_parser = Fill::parse, requires = "record_align")]
+    record_fill: Fill,
+
+    /// Start raw output with a header that describes it to readers that
+    /// know nothing of this tool: the magic "MPGRAW", a version, the byte
+    /// order, where the records start, their width, stride and filler,
+    /// and how many there are. `cat --read-format raw` reads it
+    #[arg(long, value_enum, default_value = "off", conflicts_with_all = [
+        "mmap", "archive_format", "checkpoint", "resume", "schema", "demo", "timestamp_spread", "with_id",
+        "shard_size",
+    ])]
+    header: Switch,
+
+    /// Byte order of the numbers in the --header and of --record-fill
+    /// index filler: le (the default) or be. The records themselves are
+    /// keystream bytes, not numbers, and are the same either way
+    #[arg(long, value_name = "ORDER", value_parser = ByteOrder::parse, conflicts_with_all = [
+        "mmap", "archive_format", "checkpoint", "resume", "schema", "demo", "timestamp_spread", "with_id",
+    ])]
+    binary_layout: Option<ByteOrder>,
+
+    /// Probe the output's filesystem before the run (as `probe-storage`
+    /// does) and write with the write size and look-ahead it suggests,
+    /// instead of calibrating on the run's first writes
+    #[arg(long, requires = "output", conflicts_with_all = ["mmap", "sink_dry_run"])]
+    tune_storage: bool,
+
+    /// With --tune-storage, shard the output (as --shard-size would) into
+    /// as many files as the filesystem writes fastest at once, if more
+    /// than one
+    #[arg(long, requires = "tune_storage", conflicts_with_all = [
+        "shard_size", "fallback", "checkpoint", "resume", "zstd_dict", "compress",
+        "archive_format",
+    ])]
+    auto_shards: bool,
+
+    /// Write the output no faster than this, e.g. `500MiB/s`, smoothing
+    /// the run's bursts into a steady rate for storage shared with others.
+    /// The run reports the rate it wrote at and its busiest second
+    #[arg(long, value_parser = parse_write_limit, requires = "output", conflicts_with = "mmap")]
+    write_limit: Option<u64>,
+
+    /// Split --output into numbered files of this many records each
+    /// (passwords-00001.txt, ...), written in parallel, plus a manifest
+    /// (passwords.manifest.json) with each shard's range, its SHA-256 and
+    /// the run's parameters, checked by `verify`. A multiple of the chunk
+    /// size. With --compress or --zstd-dict each shard is compressed on its
+    /// own, and the manifest says how. With --checkpoint the shards are
+    /// written in order, each added to the manifest as it is finished, and
+    /// --resume writes those after the last one there
+    #[arg(long, value_name = "N", value_parser = parse_count, requires = "output",
+          conflicts_with_all = ["sink_dry_run", "archive_format", "mmap"])]
+    shard_size: Option<usize>,
+
+    /// Stripe the --shard-size files over these directories, one per
+    /// device (/d1,/d2,...): shard n goes to the nth in turn, each
+    /// directory gets a writer thread of its own, and the manifest (next to
+    /// --output) gives each shard's full path
+    #[arg(
+        long,
+        value_name = "DIR,...",
+        value_delimiter = ',',
+        requires = "shard_size",
+        conflicts_with_all = ["tune_storage", "fallback", "checkpoint", "resume"]
+    )]
+    output_dirs: Vec<PathBuf>,
+
+    /// Write a line of JSON to this file as each --shard-size file is
+    /// finished (its path, records and SHA-256), and one when the manifest
+    /// is, so shards can be picked up while the run goes on
+    #[arg(long, value_name = "PATH", requires = "shard_size")]
+    events: Option<PathBuf>,
+
+    /// As --events, to this inherited file descriptor (--events-fd 3)
+    #[arg(
+        long,
+        value_name = "FD",
+        requires = "shard_size",
+        conflicts_with = "events"
+    )]
+    events_fd: Option<i32>,
+
+    /// Run this shell command on each --shard-size file as soon as it is
+    /// finished, with {path} replaced by its path, {shard} by its number
+    /// and {key} by an idempotency key for uploads, the run ID and the
+    /// shard number (--on-shard-complete 'gpg --detach-sign {path}'). The
+    /// shards stay where they are until the manifest is written; a run
+    /// whose hook keeps failing on a shard fails at the end, naming it and
+    /// listing it beside the manifest for `run-hooks --failed`
+    #[arg(long, value_name = "CMD", requires = "shard_size")]
+    on_shard_complete: Option<String>,
+
+    /// Most --on-shard-complete commands run at once
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 1,
+        requires = "on_shard_complete"
+    )]
+    hook_jobs: usize,
+
+    /// Retries of an --on-shard-complete command that failed on a shard;
+    /// waits double from 1s
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 2,
+        requires = "on_shard_complete"
+    )]
+    hook_retries: u32,
+
+    /// Once --on-shard-complete has succeeded on every shard, read each
+    /// uploaded copy back through this shell command, with {path}, {shard}
+    /// and {key} as for the hook, and check its SHA-256 against the
+    /// manifest's, as `verify-remote` does; the run fails if any copy is
+    /// missing or differs (--verify-remote 'aws s3 cp s3://bucket/{key} -')
+    #[arg(long, value_name = "CMD", requires = "on_shard_complete")]
+    verify_remote: Option<String>,
+
+    /// With --verify-remote, remove each local shard once its copy
+    /// matches; the manifest stays
+    #[arg(long, requires = "verify_remote")]
+    remove_local: bool,
+
+    /// Sign each --shard-size file and the manifest once the manifest is
+    /// written, with a gpg secret key (gpg:KEYID) or an SSH private key
+    /// (ssh:KEYFILE): each gets a detached signature, FILE.sig, for `gpg
+    /// --verify` or `ssh-keygen -Y verify -n mass-password-gen`
+    #[arg(long, value_name = "KEY", value_parser = signing::Signer::parse,
+          requires = "shard_size")]
+    sign_with: Option<signing::Signer>,
+
+    /// Encrypt the --shard-size files to the recipients this file gives
+    /// slices of the run to, once the manifest is written: a `[name]` table
+    /// per slice with `recipient = "age:..."` or `"gpg:KEYID"` and `records
+    /// = "START..END"` (or "START.."), on shard boundaries. Each shard is
+    /// left as FILE.age or FILE.gpg for its recipient alone to decrypt
+    #[arg(long, value_name = "PATH", value_parser = read_recipients,
+          requires = "shard_size",
+          conflicts_with_all = ["events", "events_fd", "on_shard_complete", "if_complete"])]
+    encrypt_records: Option<recipient::Recipients>,
+
+    /// Seal the run until enough of its custodians agree to open it: once
+    /// the manifest is written, every --shard-size file is encrypted under
+    /// one fresh key, left as FILE.sealed, and the key is split so that any
+    /// `threshold` custodians' shares give it back. This file gives
+    /// `threshold = K`, then a `[name]` table per custodian with their
+    /// `recipient = "age:..."` or `"gpg:KEYID"`; each share is encrypted to
+    /// its custodian beside the manifest. `unlock` opens the run
+    #[arg(long, value_name = "PATH", value_parser = read_custodians,
+          requires = "shard_size",
+          conflicts_with_all = ["encrypt_records", "events", "events_fd", "on_shard_complete",
+                                "if_complete"])]
+    seal: Option<seal::Custodians>,
+
+    /// When the manifest beside --output is of this very run, finished
+    /// (its run ID, a hash of the options and key fingerprint, matches),
+    /// exit without writing it again: `skip` once its shards are all
+    /// there, `verify` once they check out as `verify` has them. Otherwise
+    /// the run is written as usual, so a retried run is safe
+    #[arg(long, value_enum, value_name = "CHECK", requires = "shard_size")]
+    if_complete: Option<IfComplete>,
+
+    /// Leave the machine and build out of the manifest: CPU model,
+    /// microcode and features, OS and kernel, and the version, commit and
+    /// compiler of this binary, kept otherwise to tell runs apart
+    #[arg(long)]
+    no_host_info: bool,
+
+    /// Also write a non-secret reference file in the same pass: CSV rows of
+    /// index, credential id and a checksum keyed separately from the run,
+    /// so it can be shared while the passwords stay locked down
+    #[arg(long, conflicts_with_all = ["sink_dry_run", "checkpoint", "resume"])]
+    reference_output: Option<PathBuf>,
+
+    /// Key the reference checksums with a key from a provider, as for
+    /// --key. Without it, a fresh key is written to the reference path plus
+    /// `.key`, readable by its owner only.
+    #[arg(long, requires = "reference_output", value_parser = keyprovider::KeyUri::parse)]
+    reference_key: Option<keyprovider::KeyUri>,
+
+    /// With --format hashcat, also write the answers to this file as
+    /// hashcat's potfile has them: `hash:password` for each record, in
+    /// order
+    #[cfg(feature = "hash")]
+    #[arg(long, value_name = "PATH",
+          conflicts_with_all = ["sink_dry_run", "checkpoint", "resume", "verify_unique"])]
+    answer_key: Option<PathBuf>,
+
+    /// Check that no record repeats an earlier one once encoded, at the cost
+    /// of two extra passes over the run: `report` counts the duplicates,
+    /// `remove` also leaves them out of the output (the remaining records
+    /// keep their indices)
+    #[arg(long, value_enum, conflicts_with_all = ["sink_dry_run", "checkpoint", "resume"])]
+    verify_unique: Option<UniqueCheck>,
+
+    /// Print the records' entropy and how long guessing one would take at
+    /// common attack rates
+    #[arg(long)]
+    strength: bool,
+
+    /// Before generating, audit the first N records for encoding bias: a
+    /// chi-squared test per character position and a serial correlation
+    /// test, failing the run if either is significant (implies --strength)
+    #[arg(long, value_name = "N", value_parser = parse_count)]
+    audit_sample: Option<usize>,
+
+    /// Fail the run, before generating anything, if the options draw any
+    /// security, performance or capacity warning
+    #[arg(long)]
+    deny_warnings: bool,
+
+    /// False-positive rate of the filter --verify-unique screens records
+    /// with; lower takes more memory, higher leaves more candidates to
+    /// confirm
+    #[arg(long, default_value_t = 1e-3, value_parser = parse_fp_rate,
+          requires = "verify_unique")]
+    unique_fp_rate: f64,
+
+    /// Generate records of several fields described by this schema file
+    /// (TOML, a `[name]` table per field with `charset` and `length`,
+    /// `template`, passphrase settings, or nothing for a raw block), each
+    /// from its own lane of the key; written as --format csv or jsonl
+    #[arg(long, value_name = "PATH", value_parser = read_schema, conflicts_with_all = [
+        "charset", "template", "policy", "exclude", "ttl", "demo", "sink_dry_run",
+        "timestamp_spread", "with_id", "watermark", "reference_output", "verify_unique", "archive_format",
+        "mmap", "shard_size", "checkpoint", "resume", "segment",
+    ])]
+    schema: Option<Schema>,
+
+    /// Mark a small fraction of records with an owner watermark derived
+    /// from `key:<32 hex chars>` (see `detect-watermark`)
+    #[arg(long, value_parser = Watermark::parse)]
+    watermark: Option<Watermark>,
+
+    /// Give every record a synthetic `created_at` timestamp in this window,
+    /// e.g. `2024-01-01..2024-06-30` (dates, or `YYYY-MM-DDTHH:MM:SSZ`),
+    /// derived from the key and the record's index (CSV and JSON Lines
+    /// output only)
+    #[arg(long, value_name = "START..END", value_parser = TimestampSpread::parse,
+          conflicts_with_all = ["demo", "archive_format", "checkpoint", "resume"])]
+    timestamp_spread: Option<TimestampSpread>,
+
+    /// How --timestamp-spread places timestamps in the window: uniform,
+    /// ordered (evenly spaced by index), ramp (denser towards the end) or
+    /// normal (around the middle)
+    #[arg(long, default_value = "uniform", value_parser = Distribution::parse,
+          requires = "timestamp_spread")]
+    timestamp_distribution: Distribution,
+
+    /// Give every record an `id` field, the UUIDv5 of its index under a
+    /// namespace: `uuidv5:NAMESPACE`, a UUID or dns, url, oid or x500.
+    /// The same namespace always gives a record the same id, and keeping
+    /// it private keeps the index from being worked out (CSV and JSON
+    /// Lines output only)
+    #[arg(long, value_name = "SCHEME", value_parser = RecordIds::parse,
+          conflicts_with_all = ["demo", "archive_format", "checkpoint", "resume"])]
+    with_id: Option<RecordIds>,
+
+    /// Record this run under the given tenant in the local usage ledger
+    #[arg(long, value_parser = usage::validate_tenant)]
+    tenant: Option<String>,
+
+    /// Usage ledger file (defaults to the per-user state directory)
+    #[arg(long)]
+    ledger: Option<PathBuf>,
+
+    /// Report progress on stderr while generating: a bar, JSON lines with
+    /// records, bytes, rate, ETA and memory use, or (with --demo, and its
+    /// default on a terminal) a panel previewing the records
+    #[arg(long, value_enum)]
+    progress: Option<progress::Style>,
+
+    /// Also write the run's results as JSON to this file (`-` for stdout):
+    /// records, timings, rates, and the process's RSS and peak RSS (plus
+    /// system-wide memory with -v)
+    #[arg(long, value_name = "PATH")]
+    summary_json: Option<PathBuf>,
+
+    /// Seconds at the start of the run counted as its cold start (thread
+    /// pool start-up, page faults, turbo ramp-up), reported apart from the
+    /// steady-state rate after them
+    #[arg(long, value_name = "SECS", default_value = "1", value_parser = parse_warmup)]
+    warmup: Duration,
+
+    /// Keep a status file (status.json) in this directory, rewritten every
+    /// second with the run's progress, rate, ETA and last checkpoint, and
+    /// marked complete or failed at the end; read it with `status`
+    #[arg(long, value_name = "DIR")]
+    run_dir: Option<PathBuf>,
+
+    /// Also show system-wide memory figures
+    #[arg(short, long)]
+    verbose: bool,
+
+    /// Print the optional features this binary was built with, and exit
+    #[arg(long)]
+    list_capabilities: bool,
+
+    /// Inject faults into chunk writes, for testing recovery: e.g.
+    /// `fail-writes=0.1,delay=0.5:20ms,kill-after=40,seed=7`, or
+    /// `tear-after=40` to die halfway through a write
+    #[cfg(feature = "fault-injection")]
+    #[arg(long, value_parser = mass_password_gen_optimized::fault::FaultPlan::parse)]
+    fault_inject: Option<mass_password_gen_optimized::fault::FaultPlan>,
+}
+
+/// What each record looks like; shared by generation and `size`.
+#[derive(clap::Args)]
+struct RecordArgs {
+    /// Number of passwords to generate (e.g. 4000000000, 4_000_000_000 or 4e9)
+    #[arg(long, default_value = "4_000_000_000", value_parser = parse_count)]
+    count: usize,
+
+    /// Encode passwords as text: alnum, alnum+symbols, hex, base64url or
+    /// custom:<chars>. Without it, passwords are raw 16-byte blocks.
+    #[arg(long, value_parser = Charset::parse)]
+    charset: Option<Charset>,
+
+    /// Characters per password when using --charset
+    #[arg(long, default_value_t = 16, requires = "charset",
+          value_parser = clap::value_parser!(u16).range(1..=4096))]
+    length: u16,
+
+    /// Draw each password's length instead, as LENGTH:PROBABILITY pairs,
+    /// e.g. 8:0.2,10:0.3,12:0.3,16:0.2
+    #[arg(long, requires = "charset", conflicts_with_all = ["length", "policy"],
+          value_parser = LengthDist::parse)]
+    length_dist: Option<LengthDist>,
+
+    /// Build each password from a template instead, e.g. Cvcc-####-llll:
+    /// c/C consonant, v/V vowel, l/L letter, # digit, s symbol; anything
+    /// else is literal, and literal letters are escaped (\x)
+    #[arg(long, value_parser = Template::parse, conflicts_with_all = ["charset", "policy"])]
+    template: Option<Template>,
+
+    /// What to generate: passwords, passphrases of --wordlist words, or numeric
+    /// PINs of --digits digits
+    #[arg(long, value_enum, default_value_t = Mode::Password)]
+    mode: Mode,
+
+    /// The list passphrase words are drawn from: eff (the EFF large
+    /// wordlist), eff-short, the BIP-39 lists es, fr, it or pt, or
+    /// file:PATH for a list of your own, one word (or `<dice> <word>`) a
+    /// line
+    #[arg(long, value_parser = Wordlist::parse)]
+    wordlist: Option<Wordlist>,
+
+    /// Words per passphrase
+    #[arg(long, default_value_t = 6,
+          value_parser = clap::value_parser!(u16).range(1..=256))]
+    words: u16,
+
+    /// Text between passphrase words
+    #[arg(long, default_value = "-")]
+    separator: String,
+
+    /// Passphrase capitalization: lower, title or upper
+    #[arg(long, default_value = "lower", value_parser = Capitalization::parse)]
+    capitalize: Capitalization,
+
+    /// Extras for sites that refuse plain passphrases, joined by `+`:
+    /// capitalize (title-case a random choice of words), digit or digit:N
+    /// (append N digits, 2 by default) and symbol or symbol:SET (separate
+    /// words with symbols drawn from SET), e.g. capitalize+digit+symbol
+    #[arg(long, value_parser = PassphraseStyle::parse)]
+    passphrase_style: Option<PassphraseStyle>,
+
+    /// Insert a random number with this many digits into each passphrase;
+    /// with --mode pin, digits per PIN (4 to 12)
+    #[arg(long, default_value_t = 0,
+          value_parser = clap::value_parser!(u16).range(0..=64))]
+    digits: u16,
+
+    /// With --mode pin, never emit PINs that show these patterns:
+    /// repeats, sequences, years, or common:N (the N most common PINs),
+    /// comma-separated
+    #[arg(long, value_delimiter = ',', value_parser = WeakPattern::parse)]
+    exclude: Vec<WeakPattern>,
+
+    /// Only emit passwords that satisfy the policy in this file (flat TOML:
+    /// min_length, max_length, min_lower, min_upper, min_digits,
+    /// min_symbols, max_repeat, forbidden = ["..."])
+    #[arg(long, requires = "charset", value_parser = read_policy)]
+    policy: Option<PasswordPolicy>,
+
+    /// Output format: raw (native records), hex, base64, csv, jsonl,
+    /// expiring-token (needs --ttl), which `validate-token` checks, or
+    /// hashcat (needs --hash-mode), hashes for a cracking rig
+    #[arg(long, default_value = "raw", value_parser = Format::parse)]
+    format: Format,
+
+    /// How long --format expiring-token tokens stay valid from now, e.g.
+    /// 90d, 12h or 30m
+    #[arg(long, value_parser = token::parse_ttl)]
+    ttl: Option<u64>,
+
+    /// Write `password<TAB>hash` lines, hashing each password with bcrypt,
+    /// argon2id or sha512-crypt, optionally at a given cost:
+    /// bcrypt:cost=12, argon2id:m=19456,t=2,p=1 (memory in KiB),
+    /// sha512-crypt:rounds=5000. Salts derive from the key
+    #[cfg(feature = "hash")]
+    #[arg(long, conflicts_with = "ttl", value_parser = HashAlgorithm::parse)]
+    emit_hash: Option<HashAlgorithm>,
+
+    /// The hashcat mode --format hashcat hashes with: 0 (MD5), 10 (MD5 with
+    /// a salt), 1000 (NTLM) or 3200 (bcrypt, at cost 5 unless given as
+    /// 3200:cost=N). Salts derive from the key
+    #[cfg(feature = "hash")]
+    #[arg(long, value_parser = HashMode::parse)]
+    hash_mode: Option<HashMode>,
+}
+
+impl RecordArgs {
+    /// The sink for --format, minting tokens that expire --ttl from now, or
+    /// for --emit-hash.
+    fn sink(&self, generator: &PasswordGenerator) -> Result<Option<Box<dyn OutputSink>>, String> {
+        #[cfg(feature = "hash")]
+        if let Some(algorithm) = self.emit_hash {
+            if self.format != Format::Raw {
+                return Err("--emit-hash writes its own lines; leave --format at raw".to_string());
+            }
+            let sink = HashedPairs::new(generator, algorithm)?;
+            return Ok(Some(Box::new(sink)));
+        }
+        #[cfg(feature = "hash")]
+        if self.format == Format::Hashcat || self.hash_mode.is_some() {
+            return Ok(Some(Box::new(self.hashcat_sink(generator, None)?)));
+        }
+        #[cfg(not(feature = "hash"))]
+        if self.format == Format::Hashcat {
+            return Err("--format hashcat needs a build with the `hash` feature".to_string());
+        }
+        match (self.format, self.ttl) {
+            (Format::ExpiringToken, Some(ttl)) => Ok(Some(Box::new(ExpiringTokens::new(
+                generator,
+                unix_now() + ttl,
+            )))),
+            (Format::ExpiringToken, None) => {
+                Err("--format expiring-token needs a --ttl".to_string())
+            }
+            (_, Some(_)) => Err("--ttl only applies to --format expiring-token".to_string()),
+            (format, None) => Ok(format.sink(generator)),
+        }
+    }
+
+    /// The sink for --format hashcat, writing the answers to `answers` if
+    /// given.
+    #[cfg(feature = "hash")]
+    fn hashcat_sink(
+        &self,
+        generator: &PasswordGenerator,
+        answers: Option<std::sync::Arc<AnswerKey>>,
+    ) -> Result<HashcatLines, String> {
+        match (self.format, self.hash_mode) {
+            (Format::Hashcat, Some(mode)) => HashcatLines::new(generator, mode, answers),
+            (Format::Hashcat, None) => Err("--format hashcat needs a --hash-mode".to_string()),
+            (_, _) => Err("--hash-mode only applies to --format hashcat".to_string()),
+        }
+    }
+
+    /// Applies the count and record encoding to `builder`.
+    fn configure(
+        &self,
+        mut builder: PasswordGeneratorBuilder,
+    ) -> Result<PasswordGeneratorBuilder, String> {
+        builder = builder.count(self.count);
+        match (&self.charset, &self.length_dist) {
+            (Some(charset), Some(lengths)) => {
+                builder = builder.encoder(Encoder::with_lengths(charset, lengths.clone()));
+            }
+            (Some(charset), None) => {
+                builder = builder.encoder(Encoder::new(charset, self.length as usize));
+            }
+            (None, _) => {}
+        }
+        if let Some(template) = &self.template {
+            builder = builder.encoder(Encoder::from_template(template));
+        }
+        if let Some(policy) = &self.policy {
+            builder = builder.policy(policy.clone());
+        }
+        if !self.exclude.is_empty() && self.mode != Mode::Pin {
+            return Err(
+                "--exclude applies to --mode pin; policy files take an `exclude` \
+                        setting for other passwords"
+                    .to_string(),
+            );
+        }
+        if self.mode == Mode::Pin {
+            if self.charset.is_some() || self.template.is_some() {
+                return Err("PINs are digits; --mode pin takes no --charset or --template".into());
+            }
+            let encoder =
+                pin::encoder(self.digits as usize).map_err(|e| format!("{} (set --digits)", e))?;
+            builder = builder.encoder(encoder);
+            if !self.exclude.is_empty() {
+                builder = builder.policy(PasswordPolicy {
+                    exclude: self.exclude.clone(),
+                    ..Default::default()
+                });
+            }
+        }
+        if self.wordlist.is_some() && self.mode != Mode::Passphrase {
+            return Err("--wordlist applies to --mode passphrase".to_string());
+        }
+        if let Some(style) = &self.passphrase_style {
+            if self.mode != Mode::Passphrase {
+                return Err("--passphrase-style applies to --mode passphrase".to_string());
+            }
+            if style.symbols.is_some() && self.separator != "-" {
+                return Err(
+                    "--passphrase-style symbol picks the separators; drop --separator".into(),
+                );
+            }
+            if style.capitalize && self.capitalize != Capitalization::Lower {
+                return Err(
+                    "--passphrase-style capitalize picks the words to capitalize; drop \
+                     --capitalize"
+                        .into(),
+                );
+            }
+        }
+        if self.mode == Mode::Passphrase {
+            let passphrase = Passphrase::with_wordlist(
+                self.wordlist.clone().unwrap_or_else(Wordlist::eff),
+                self.words as usize,
+                &self.separator,
+                self.capitalize,
+                self.digits as usize,
+            )?;
+            builder = builder.passphrase(match &self.passphrase_style {
+                Some(style) => passphrase.styled(style.clone())?,
+                None => passphrase,
+            });
+        }
+        Ok(builder)
+    }
+
+    /// Everything that determines the records, for checkpoints.
+    fn shape(&self) -> String {
+        match (&self.charset, self.mode) {
+            (_, Mode::Passphrase) => {
+                let words = match &self.wordlist {
+                    Some(list) if *list != Wordlist::eff() => format!("/{}", list.sha256()),
+                    _ => String::new(),
+                };
+                let style = match &self.passphrase_style {
+                    Some(style) => format!("/style({})", style),
+                    None => String::new(),
+                };
+                format!(
+                    "passphrase/{}/{:?}/{}/{}{}{}",
+                    self.words, self.separator, self.capitalize, self.digits, words, style
+                )
+            }
+            (_, Mode::Pin) => {
+                let exclude: Vec<String> = self.exclude.iter().map(|p| p.to_string()).collect();
+                format!("pin/{}/exclude({})", self.digits, exclude.join(","))
+            }
+            (None, Mode::Password) => match &self.template {
+                None => "raw".to_string(),
+                Some(template) => format!("template/{:?}", template.to_string()),
+            },
+            (Some(charset), Mode::Password) => match &self.policy {
+                None => match &self.length_dist {
+                    None => format!("{:?}/{}", charset.to_string(), self.length),
+                    Some(lengths) => format!("{:?}/lengths({})", charset.to_string(), lengths),
+                },
+                Some(policy) => format!(
+                    "{:?}/{}/policy({})",
+                    charset.to_string(),
+                    self.length,
+                    policy
+                ),
+            },
+        }
+    }
+
+    /// Short description of the encoding for the usage ledger.
+    fn encoding(&self) -> String {
+        match (&self.charset, self.mode) {
+            (_, Mode::Passphrase) => match &self.wordlist {
+                Some(list) if list.is_built_in() => {
+                    format!("passphrase/{}/{}", self.words, list.name())
+                }
+                Some(_) => format!("passphrase/{}/file", self.words),
+                None => format!("passphrase/{}", self.words),
+            },
+            (_, Mode::Pin) => format!("pin/{}", self.digits),
+            (None, Mode::Password) => match &self.template {
+                None => "raw".to_string(),
+                Some(template) => format!("template/{}", template),
+            },
+            (Some(charset), Mode::Password) => match &self.length_dist {
+                None => format!("{}/{}", charset, self.length),
+                Some(lengths) => format!("{}/{}", charset, lengths),
+            },
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Mode {
+    Password,
+    Passphrase,
+    Pin,
+}
+
+/// How `bench` measures.
+#[derive(clap::Args)]
+struct BenchArgs {
+    /// Passwords per parallel chunk; the last chunk may be shorter
+    #[arg(long, default_value = "1_000_000", value_parser = parse_chunk_size)]
+    chunk_size: usize,
+
+    /// Keystream cipher; `auto` picks the faster of AES-128 and ChaCha20
+    #[arg(long, value_enum, default_value_t = CipherChoice::Aes128)]
+    cipher: CipherChoice,
+
+    /// Unmeasured iterations per thread count
+    #[arg(long, default_value_t = 1)]
+    warmup: usize,
+
+    /// Seconds from the start of each thread count's first iteration,
+    /// warm-up iterations included, reported as its cold start apart from
+    /// the steady state after them
+    #[arg(long, value_name = "SECS", default_value = "1", value_parser = parse_warmup)]
+    warmup_secs: Duration,
+
+    /// Measured iterations per thread count
+    #[arg(long, default_value = "3", value_parser = parse_count)]
+    iterations: usize,
+
+    /// Thread counts to measure, e.g. 1,2,4 (defaults to 1, 2, 4, ...
+    /// up to the number of logical CPUs)
+    #[arg(long, value_delimiter = ',', value_parser = parse_count)]
+    threads: Vec<usize>,
+
+    /// Also write the results as JSON to this file (`-` for stdout)
+    #[arg(long)]
+    json: Option<PathBuf>,
+
+    /// Also time a naive generator calling `rand::thread_rng` per
+    /// character on one thread, and show the speedup over it
+    #[arg(long)]
+    naive_baseline: bool,
+
+    /// Compare the results with an earlier `--json` report
+    #[arg(long, value_name = "BASELINE")]
+    compare: Option<PathBuf>,
+
+    /// With --compare, fail if throughput at any thread count dropped by
+    /// more than this many percent
+    #[arg(long, value_name = "PCT", requires = "compare")]
+    max_regression: Option<f64>,
+}
+
+/// How long `soak` runs, and how hard it checks.
+#[derive(clap::Args)]
+struct SoakArgs {
+    /// How long to keep generating, such as 12h or 30m; the pass under way
+    /// when it is up is finished and checked in full
+    #[arg(long, value_parser = parse_soak_duration)]
+    duration: Duration,
+
+    /// Chunks recomputed and compared after each pass
+    #[arg(long, default_value = "16", value_parser = parse_count)]
+    sample: usize,
+
+    /// Passwords per parallel chunk; the last chunk may be shorter
+    #[arg(long, default_value = "1_000_000", value_parser = parse_chunk_size)]
+    chunk_size: usize,
+
+    /// Keystream cipher; `auto` picks the faster of AES-128 and ChaCha20
+    #[arg(long, value_enum, default_value_t = CipherChoice::Aes128)]
+    cipher: CipherChoice,
+
+    /// Worker threads (defaults to the number of logical CPUs)
+    #[arg(long, value_parser = parse_count)]
+    threads: Option<usize>,
+}
+
+/// How `serve` listens, and what it serves besides fresh batches.
+#[derive(clap::Args)]
+struct ServeArgs {
+    /// Address to listen on: HOST:PORT, unix:PATH for a Unix socket
+    /// (created 0600), or on Linux vsock:CID:PORT for a VM's vsock port
+    /// (CID `any`, or the CID to take connections on)
+    #[arg(long, default_value = "127.0.0.1:8080", value_parser = listen::Address::parse)]
+    bind: listen::Address,
+
+    /// File holding the token clients must send as `Authorization:
+    /// Bearer <token>` (everything but /health)
+    #[arg(long)]
+    token_file: Option<PathBuf>,
+
+    /// Most records one request may ask for
+    #[arg(long, default_value = "1_000_000", value_parser = parse_count)]
+    max_count: usize,
+
+    /// Serve HTTPS with this PEM certificate chain (and --tls-key)
+    #[cfg(feature = "tls")]
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key of --tls-cert
+    #[cfg(feature = "tls")]
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// PEM certificates of the CA for client certificates: every client
+    /// must present one it issued (mutual TLS), and is known by its
+    /// certificate's fingerprint in --rate-limit and --audit-log
+    #[cfg(feature = "tls")]
+    #[arg(long, requires = "tls_cert")]
+    client_ca: Option<PathBuf>,
+
+    /// Requests each client may make, as N/s, N/min or N/h, in a burst or
+    /// spread out; a client is its certificate, or else its IP address.
+    /// Requests over it get 429 with Retry-After
+    #[arg(long, value_parser = serve::RateLimit::parse)]
+    rate_limit: Option<serve::RateLimit>,
+
+    /// Append a JSON line per request to this file (created 0600): the
+    /// client, route and status, and which records under which key it
+    /// pulled, and whether they were all sent
+    #[arg(long)]
+    audit_log: Option<PathBuf>,
+
+    /// Key of a fixed run for `GET /passwords?cursor=&limit=` to page
+    /// through; the record options, --chunk-size and --cipher describe the
+    /// run, as they would for a file run with --deterministic --key
+    #[arg(long, value_parser = keyprovider::KeyUri::parse)]
+    run_key: Option<keyprovider::KeyUri>,
+
+    /// Passwords per chunk of the --run-key run
+    #[arg(long, default_value = "1_000_000", value_parser = parse_chunk_size,
+          requires = "run_key")]
+    chunk_size: usize,
+
+    /// Keystream cipher of the --run-key run (defaults to AES for the key's
+    /// width)
+    #[arg(long, value_enum, requires = "run_key")]
+    cipher: Option<CipherChoice>,
+
+    /// Memory for encoded chunks of the --run-key run, so clients paging
+    /// over the same records share them: a size such as 512MiB, or a share
+    /// of the available memory such as 10%
+    #[arg(long, default_value = "256MiB", value_parser = memory::Limit::parse,
+          requires = "run_key")]
+    cache_memory: memory::Limit,
+
+    /// Corpus (manifest or archive) for `POST /reserve` to claim records
+    /// from
+    #[cfg(feature = "reservations")]
+    #[arg(long)]
+    reserve_from: Option<PathBuf>,
+
+    /// Reservation database for --reserve-from (defaults to it with
+    /// `.reservations` appended)
+    #[cfg(feature = "reservations")]
+    #[arg(long, requires = "reserve_from")]
+    store: Option<PathBuf>,
+}
+
+/// What `fetch` pulls, and how.
+#[derive(clap::Args)]
+struct FetchArgs {
+    /// The server: http://HOST:PORT, https://HOST:PORT, unix:PATH or
+    /// vsock:CID:PORT
+    #[arg(long, value_parser = client::Server::parse)]
+    server: client::Server,
+
+    /// File to fetch into; progress is kept next to it in OUTPUT.fetch
+    /// until the fetch completes
+    #[arg(long)]
+    output: PathBuf,
+
+    /// Records to fetch from the start of the run (defaults to all of it)
+    #[arg(long, value_parser = parse_count)]
+    count: Option<usize>,
+
+    /// Records asked for at a time; a failed page is fetched again whole
+    #[arg(long, default_value = "100_000", value_parser = parse_count)]
+    page_size: usize,
+
+    /// Format the server writes the records in (defaults to the run's own)
+    #[arg(long, value_parser = parse_record_format)]
+    format: Option<Format>,
+
+    /// File holding the token to send as `Authorization: Bearer <token>`
+    #[arg(long)]
+    token_file: Option<PathBuf>,
+
+    #[cfg(feature = "tls")]
+    #[command(flatten)]
+    tls: ClientTlsArgs,
+
+    /// Retries of a page that failed (a dropped connection, a reply cut
+    /// short, a 5xx or a 429) before giving up; waits double from 1s, and a server's
+    /// Retry-After is respected
+    #[arg(long, default_value_t = 8)]
+    retries: u32,
+
+    /// Carry on with a fetch into OUTPUT that was stopped, after its last
+    /// whole page
+    #[arg(long)]
+    resume: bool,
+}
+
+/// How `fetch` and `loadtest` check the servers they speak HTTPS to, and
+/// prove who they are.
+#[cfg(feature = "tls")]
+#[derive(clap::Args)]
+struct ClientTlsArgs {
+    /// PEM certificates of the CAs to trust for https:// (required with it)
+    #[arg(long)]
+    ca_cert: Option<PathBuf>,
+
+    /// PEM certificate chain to present to a server that asks for one
+    /// (and --client-key)
+    #[arg(long, requires = "client_key", requires = "ca_cert")]
+    client_cert: Option<PathBuf>,
+
+    /// PEM private key of --client-cert
+    #[arg(long, requires = "client_cert")]
+    client_key: Option<PathBuf>,
+}
+
+/// Where `loadtest` sends its requests, and how fast.
+#[derive(clap::Args)]
+struct LoadtestArgs {
+    /// URL every request goes to, e.g. https://api.internal/v1/users
+    #[arg(long, value_parser = client::Server::parse_url)]
+    target: (client::Server, String),
+
+    /// HTTP method of the requests
+    #[arg(long, default_value = "POST")]
+    method: String,
+
+    /// File with the body of a request: {password} is replaced with the
+    /// credential, {password_json} with it as a JSON string, and {index}
+    /// with its index in the run
+    #[arg(long)]
+    body_template: PathBuf,
+
+    /// Content-Type of the bodies
+    #[arg(long, default_value = "application/json")]
+    content_type: String,
+
+    /// Another header for every request, as `NAME: VALUE`; repeat for more
+    #[arg(long = "header", value_name = "HEADER", value_parser = parse_header)]
+    headers: Vec<(String, String)>,
+
+    /// File holding the token to send as `Authorization: Bearer <token>`
+    #[arg(long)]
+    token_file: Option<PathBuf>,
+
+    #[cfg(feature = "tls")]
+    #[command(flatten)]
+    tls: ClientTlsArgs,
+
+    /// Requests in flight at once
+    #[arg(long, default_value_t = 16,
+          value_parser = clap::value_parser!(u16).range(1..=4096))]
+    concurrency: u16,
+
+    /// Requests to send, as N/s, N/min or N/h, evenly spaced; without it,
+    /// as fast as the workers go
+    #[arg(long, value_parser = serve::RateLimit::parse)]
+    rate: Option<serve::RateLimit>,
+
+    /// Also write the report as JSON to this file (`-` for stdout)
+    #[arg(long)]
+    json: Option<PathBuf>,
+}
+
+/// `GET /passwords` parameters: the record options, by their long names.
+#[derive(Parser)]
+#[command(no_binary_name = true, mut_arg("count", |arg| arg.default_value("1")))]
+struct PasswordQuery {
+    #[command(flatten)]
+    records: RecordArgs,
+}
+
+/// Where a corpus is read from, for `cat` and `reserve`.
+#[derive(clap::Args)]
+struct CorpusArgs {
+    /// Manifest of the corpus, or with --read-format a single output file
+    input: PathBuf,
+
+    /// Read INPUT as one output file in this format instead of a manifest
+    #[arg(long, value_parser = Format::parse)]
+    read_format: Option<Format>,
+
+    /// The file holds raw 16-byte records (a run without --charset or
+    /// --mode passphrase); raw output with a --header says so itself
+    #[arg(long, requires = "read_format")]
+    binary: bool,
+
+    /// zstd dictionary the file was compressed with (--zstd-dict)
+    #[arg(long, requires = "read_format")]
+    dictionary: Option<PathBuf>,
+
+    /// The file's records are padded to this alignment (--record-align)
+    #[arg(long, value_name = "N", requires = "binary")]
+    record_align: Option<usize>,
+
+    /// What pads them (--record-fill)
+    #[arg(long, default_value = "zero", value_parser = Fill::parse, requires = "record_align")]
+    record_fill: Fill,
+}
+
+impl CorpusArgs {
+    fn open(&self) -> Result<CorpusReader, String> {
+        let reader = match self.read_format {
+            Some(format) => {
+                CorpusReader::file(&self.input, format, self.binary, self.dictionary.as_deref())
+                    .and_then(|reader| match self.record_align {
+                        Some(align) => {
+                            // --binary records are 16-byte blocks
+                            let layout = RawLayout::aligned(16, align, self.record_fill)
+                                .map_err(std::io::Error::other)?;
+                            reader.with_layout(layout)
+                        }
+                        None => Ok(reader),
+                    })
+            }
+            None => CorpusReader::open(&self.input),
+        };
+        reader.map_err(|e| format!("{}: {}", self.input.display(), e))
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ArchiveFormat {
+    V1,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum PairOutput {
+    Csv,
+    Ldif,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Switch {
+    On,
+    Off,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum UniqueCheck {
+    Report,
+    Remove,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum IfComplete {
+    Skip,
+    Verify,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum CipherChoice {
+    Auto,
+    Aes128,
+    Aes256,
+    Chacha20,
+}
+
+impl CipherChoice {
+    /// The cipher asked for by name, if not left to a benchmark.
+    fn pinned(self) -> Option<Cipher> {
+        match self {
+            CipherChoice::Auto => None,
+            CipherChoice::Aes128 => Some(Cipher::Aes128),
+            CipherChoice::Aes256 => Some(Cipher::Aes256),
+            CipherChoice::Chacha20 => Some(Cipher::ChaCha20),
+        }
+    }
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Test a candidate file for a watermark added with --watermark
+    DetectWatermark {
+        /// The watermark key used at generation time, as `key:<hex>`
+        #[arg(long, value_parser = Watermark::parse)]
+        watermark: Watermark,
+
+        /// File to test (`-` for stdin); records may be shuffled or partial
+        #[arg(long)]
+        input: PathBuf,
+
+        /// Charset the corpus was generated with; omit for raw 16-byte blocks
+        #[arg(long, value_parser = Charset::parse)]
+        charset: Option<Charset>,
+    },
+
+    /// Print the records of a corpus in index order, decompressed and
+    /// decoded from their format
+    Cat {
+        #[command(flatten)]
+        corpus: CorpusArgs,
+
+        /// Only records START..END (END excluded, and optional), e.g. 1000..2000
+        #[arg(long, value_parser = parse_range)]
+        range: Option<std::ops::Range<usize>>,
+
+        /// Output format: raw (native records), hex, base64, csv or jsonl
+        #[arg(long, default_value = "raw", value_parser = parse_record_format)]
+        format: Format,
+    },
+
+    /// Print the records at the given indices, in the order given, each
+    /// found through its shard's routing index rather than by reading the
+    /// corpus up to it
+    Pick {
+        #[command(flatten)]
+        corpus: CorpusArgs,
+
+        /// Record indices, from 0
+        #[arg(value_name = "INDEX", required = true)]
+        indices: Vec<usize>,
+
+        /// Output format: raw (native records), hex, base64, csv or jsonl
+        #[arg(long, default_value = "raw", value_parser = parse_record_format)]
+        format: Format,
+    },
+
+    /// Write records START..END of a corpus to a new file, readable by its
+    /// owner only, starting at the routing index entry nearest START
+    Slice {
+        #[command(flatten)]
+        corpus: CorpusArgs,
+
+        /// The records, START..END (END excluded, and optional), e.g. 1000..2000
+        #[arg(long, value_parser = parse_range)]
+        range: std::ops::Range<usize>,
+
+        /// The file to write
+        #[arg(long, value_name = "FILE")]
+        out: PathBuf,
+
+        /// Output format: raw (native records), hex, base64, csv or jsonl
+        #[arg(long, default_value = "raw", value_parser = parse_record_format)]
+        format: Format,
+    },
+
+    /// Atomically claim the next unused records of a corpus and print them.
+    /// Claims are tracked in a SQLite database next to the corpus, so
+    /// concurrent issuers never receive the same record.
+    #[cfg(feature = "reservations")]
+    Reserve {
+        #[command(flatten)]
+        corpus: CorpusArgs,
+
+        /// Records to claim
+        #[arg(long, value_parser = parse_count)]
+        count: usize,
+
+        /// Reservation database (defaults to INPUT with `.reservations`
+        /// appended)
+        #[arg(long)]
+        store: Option<PathBuf>,
+
+        /// Who is claiming, kept in the claim log
+        #[arg(long)]
+        claimant: Option<String>,
+
+        /// Output format: raw (native records), hex, base64, csv or jsonl
+        #[arg(long, default_value = "raw", value_parser = parse_record_format)]
+        format: Format,
+    },
+
+    /// Serve passwords over HTTP: `GET /passwords?count=1000&charset=alnum&length=20`
+    /// takes the record options (but --policy) as parameters and streams a
+    /// batch under a fresh random key; `POST /reserve?count=K` claims
+    /// records of --reserve-from, and a claim whose reply was cut short can
+    /// be fetched again with `POST /reserve?refetch=FIRST` (FIRST from its
+    /// `X-Reserved` header); with --run-key, `GET
+    /// /passwords?cursor=C&limit=N` pages through one fixed run, each page
+    /// naming the next in `X-Next-Cursor`, and `GET /stats` reports its
+    /// chunk cache's hit rate; `GET /health` answers `ok`
+    Serve {
+        #[command(flatten)]
+        options: ServeArgs,
+
+        #[command(flatten)]
+        run: RecordArgs,
+    },
+
+    /// Give every row of a CSV export a new password: copies --input with
+    /// the field of its --replace-column column replaced by a fresh record
+    /// in each data row, the other columns as they were
+    #[command(mut_arg("count", |arg| arg.hide(true)),
+              mut_arg("format", |arg| arg.hide(true)))]
+    Rewrite {
+        /// The CSV file, with a header row naming its columns
+        #[arg(long)]
+        input: PathBuf,
+
+        /// Name of the column to fill with new passwords, e.g. password
+        #[arg(long, value_name = "NAME")]
+        replace_column: String,
+
+        /// Where to write the rewritten CSV (`-` for stdout)
+        #[arg(long, default_value = "-")]
+        output: PathBuf,
+
+        /// Key as 32 or 64 hex characters, for passwords that can be made
+        /// again; without it, a fresh random key
+        #[arg(long, value_parser = parse_key_hex)]
+        key_hex: Option<Key>,
+
+        #[command(flatten)]
+        records: RecordArgs,
+    },
+
+    /// Pull the run of a `serve --run-key` server into a file, a page at a
+    /// time: failed pages are retried with backoff, and a fetch that was
+    /// stopped carries on with --resume
+    Fetch(FetchArgs),
+
+    /// Load-test an HTTP API with fresh credentials: one request per
+    /// record (--count of them), its body the --body-template with the
+    /// record in it; reports the latency percentiles and the error rate
+    #[command(mut_arg("count", |arg| arg.default_value("10_000")),
+              mut_arg("format", |arg| arg.hide(true)))]
+    Loadtest {
+        #[command(flatten)]
+        options: LoadtestArgs,
+
+        #[command(flatten)]
+        records: RecordArgs,
+    },
+
+    /// Print the password for one site, derived from a master passphrase
+    /// (read from stdin, or prompted for) and the site's name; the same
+    /// inputs always give the same password, so nothing needs storing
+    #[cfg(feature = "derive")]
+    #[command(mut_arg("count", |arg| arg.hide(true)),
+              mut_arg("format", |arg| arg.hide(true)))]
+    Derive {
+        /// Site or service the password is for, e.g. example.com
+        site: String,
+
+        /// Account on the site, when you have more than one
+        #[arg(long)]
+        account: Option<String>,
+
+        /// Bump to rotate the site's password
+        #[arg(long, default_value_t = 1,
+              value_parser = clap::value_parser!(u32).range(1..))]
+        generation: u32,
+
+        /// Your name or e-mail address, mixed into the master key so that
+        /// two people with the same passphrase get different passwords
+        #[arg(long)]
+        user: Option<String>,
+
+        #[command(flatten)]
+        records: RecordArgs,
+    },
+
+    /// Check an archive written with --archive-format, or the manifest of
+    /// output split with --shard-size: its hashes, its known-answer vectors
+    /// against this build, and every record
+    Verify {
+        /// The archive or manifest; with --bundle, where the corpus is now
+        /// if it has moved since the bundle was exported
+        #[arg(required_unless_present = "bundle")]
+        archive: Option<PathBuf>,
+
+        /// Check only this record and the shard holding it, found through
+        /// the routing index; with --bundle, generate just it again
+        #[arg(long, value_name = "N")]
+        record: Option<usize>,
+
+        /// Also check the corpus against a replay bundle from
+        /// `export-replay-bundle`: decrypt the run's key and generate every
+        /// record again to compare
+        #[arg(long, value_name = "DIR")]
+        bundle: Option<PathBuf>,
+
+        /// The age identity file that decrypts the bundle's key (gpg finds
+        /// its own key)
+        #[arg(long, value_name = "FILE", requires = "bundle")]
+        identity: Option<PathBuf>,
+    },
+
+    /// Write a replay bundle of a corpus for an auditor, who can re-derive
+    /// it without this machine: a directory with the run's key encrypted to
+    /// the auditor, the manifest, the scheme version and the checks `verify
+    /// --bundle DIR` is to run
+    ExportReplayBundle {
+        /// The corpus: an archive, or the manifest of a --shard-size run
+        #[arg(long)]
+        corpus: PathBuf,
+
+        /// The run's key as 32 or 64 hex characters
+        #[arg(long, value_parser = parse_key_hex, required_unless_present = "key")]
+        key_hex: Option<Key>,
+
+        /// The run's key from a provider, as for --key
+        #[arg(long, value_parser = keyprovider::KeyUri::parse, conflicts_with = "key_hex")]
+        key: Option<keyprovider::KeyUri>,
+
+        /// Whom to encrypt the key to: age:RECIPIENT (age1... or an SSH
+        /// public key) or gpg:KEYID
+        #[arg(long, value_name = "RECIPIENT", value_parser = recipient::Recipient::parse)]
+        to: recipient::Recipient,
+
+        /// The bundle's directory, new or empty
+        #[arg(long, value_name = "DIR")]
+        out: PathBuf,
+    },
+
+    /// Open a run written with --seal: put enough custodians' shares
+    /// together to give back its key, check the key against the manifest,
+    /// and decrypt every FILE.sealed back to FILE. The manifest is left as
+    /// it is, for `verify` once the run is open
+    Unlock {
+        /// The sealed run's manifest
+        #[arg(long, value_name = "PATH")]
+        manifest: PathBuf,
+
+        /// A custodian's share, as they decrypted it or still as FILE.age
+        /// or FILE.gpg; once per custodian, as many as the threshold
+        #[arg(long = "share", value_name = "FILE", required = true)]
+        shares: Vec<PathBuf>,
+
+        /// The age identity file that decrypts .age shares (gpg finds its
+        /// own key)
+        #[arg(long, value_name = "FILE")]
+        identity: Option<PathBuf>,
+    },
+
+    /// Destroy a run split with --shard-size once its retention is up:
+    /// every shard, as written or left encrypted or sealed, its signatures
+    /// and seal shares, its reservation ledger, and last the manifest. Each
+    /// file is overwritten with random bytes before it is unlinked, except
+    /// on copy-on-write filesystems, and on Linux its blocks are punched out
+    /// for a TRIM
+    Shred {
+        /// The run's manifest
+        #[arg(long, value_name = "PATH")]
+        manifest: PathBuf,
+
+        /// Append the destruction, with each file destroyed and how, as a
+        /// JSON line to this file (created 0600), as `serve --audit-log` does
+        #[arg(long, value_name = "FILE")]
+        audit_log: Option<PathBuf>,
+
+        /// List the files that would be destroyed, and destroy nothing
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Set one run's credentials against the next's, index by index, for
+    /// rotation automation: each index that both runs have is rotated from
+    /// the old credential to the new, and the rest are retired or issued.
+    /// Both runs are generated again from their keys; neither corpus need be
+    /// on hand. The plan holds both runs' credentials, so keep it private
+    RotationPlan {
+        /// The old run's manifest, or its archive
+        old: PathBuf,
+
+        /// The new run's manifest, or its archive
+        new: PathBuf,
+
+        /// The old run's key, as for --key
+        #[arg(long, value_parser = keyprovider::KeyUri::parse)]
+        old_key: keyprovider::KeyUri,
+
+        /// The new run's key, as for --key
+        #[arg(long, value_parser = keyprovider::KeyUri::parse)]
+        new_key: keyprovider::KeyUri,
+
+        /// `csv` (index,change,old,new) or `jsonl`
+        #[arg(long, value_parser = rotation::PlanFormat::parse, default_value = "csv")]
+        format: rotation::PlanFormat,
+
+        /// Write the plan to this file (created 0600) instead of stdout
+        #[arg(long, value_name = "PATH")]
+        output: Option<PathBuf>,
+    },
+
+    /// Check tokens written with --format expiring-token: their MAC under
+    /// the run's validation key, then their expiry. Prints a verdict per
+    /// token and fails if any is rejected. Given the run's key, also prints
+    /// the validation key: services that check tokens need only that, and
+    /// cannot regenerate the passwords from it.
+    ValidateToken {
+        /// Tokens to check; without any, one per line from stdin
+        tokens: Vec<String>,
+
+        /// The validation key as 64 hex characters
+        #[arg(long, value_parser = parse_validation_key,
+              required_unless_present_any = ["key_hex", "key"])]
+        validation_key: Option<[u8; 32]>,
+
+        /// The run's key as 32 or 64 hex characters
+        #[arg(long, value_parser = parse_key_hex, conflicts_with = "validation_key")]
+        key_hex: Option<Key>,
+
+        /// The run's key from a provider, as for --key
+        #[arg(long, value_parser = keyprovider::KeyUri::parse,
+              conflicts_with_all = ["key_hex", "validation_key"])]
+        key: Option<keyprovider::KeyUri>,
+
+        /// Judge expiry at this time (`YYYY-MM-DD` or `YYYY-MM-DDTHH:MM:SSZ`)
+        /// instead of now
+        #[arg(long, value_parser = timestamp::parse_time)]
+        at: Option<u64>,
+    },
+
+    /// Benchmark generation (without output) at several thread counts
+    #[command(mut_arg("count", |arg| arg.default_value("10_000_000")))]
+    Bench {
+        #[command(flatten)]
+        records: RecordArgs,
+
+        #[command(flatten)]
+        options: BenchArgs,
+    },
+
+    /// Burn in hardware: generate continuously, holding every chunk in
+    /// memory and recomputing samples of them to catch bytes that changed
+    /// in memory or were computed wrong. `--count` sets the memory held
+    #[command(mut_arg("count", |arg| arg.default_value("10_000_000")))]
+    Soak {
+        #[command(flatten)]
+        records: RecordArgs,
+
+        #[command(flatten)]
+        options: SoakArgs,
+    },
+
+    /// Manage named keys in the platform's secret store (Secret Service,
+    /// macOS Keychain or Windows DPAPI) for use with --key keyring:<name>
+    Key {
+        #[command(subcommand)]
+        action: KeyAction,
+    },
+
+    /// Print the exact output size of a run without generating anything
+    Size {
+        #[command(flatten)]
+        records: RecordArgs,
+
+        /// Passwords per parallel chunk, as the run will be given it
+        #[arg(long, default_value = "1_000_000", value_parser = parse_chunk_size)]
+        chunk_size: usize,
+
+        /// Also show the size of each shard the run writes with this
+        /// --shard-size (a multiple of the chunk size)
+        #[arg(long, value_name = "N", value_parser = parse_count)]
+        shard_size: Option<usize>,
+
+        /// Also show the size of each shard when the run is split into
+        /// this many, and the --shard-size that splits it so: the
+        /// smallest multiple of the chunk size that needs no more
+        #[arg(long, value_name = "N", value_parser = parse_count, conflicts_with = "shard_size")]
+        shards: Option<usize>,
+    },
+
+    /// Split a job too large for one run, up to 2^128 - 1 records, into
+    /// segments: runs of --segment-size records, each generated with
+    /// `--total`, `--segment` and a key of its own derived from the job's
+    Plan {
+        /// Records in the whole job
+        #[arg(long, value_parser = parse_total)]
+        total: u128,
+
+        /// Records per segment, but for the last
+        #[arg(long, value_parser = parse_count)]
+        segment_size: usize,
+
+        /// Segments to list from each end of the plan
+        #[arg(long, default_value_t = 3)]
+        show: u128,
+    },
+
+    /// List the corpora under storage roots: every archive and corpus
+    /// manifest found, with its record count, format, date, key
+    /// fingerprint, size and verification status
+    Catalog {
+        /// Directory to search, recursively; repeat for several. Local
+        /// paths only: mount or sync object storage (s3://...) first
+        #[arg(long, required = true, value_parser = catalog::parse_root)]
+        root: Vec<PathBuf>,
+
+        /// Write the inventory as JSON to this file (`-` for stdout) as well
+        /// as printing the table
+        #[arg(long)]
+        json: Option<PathBuf>,
+
+        /// Only read the indexes and manifests; do not hash shards or read
+        /// records back
+        #[arg(long)]
+        no_verify: bool,
+    },
+
+    /// Measure the filesystem at PATH, a directory, with a few seconds of
+    /// scratch writes: block size, direct I/O, write latency and
+    /// throughput, and how parallel writers scale, with the write size,
+    /// look-ahead and writers a run there should use
+    ProbeStorage {
+        path: PathBuf,
+
+        /// Most parallel writers to try (defaults to the logical CPUs)
+        #[arg(long)]
+        threads: Option<usize>,
+
+        /// Print the findings as JSON on stdout
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Run an --on-shard-complete command again on the shards of a
+    /// sharded run, such as those its hook failed on
+    RunHooks {
+        /// The run's manifest (passwords.manifest.json)
+        #[arg(long)]
+        manifest: PathBuf,
+
+        /// Shards to run the command on, numbered from 1 (defaults to all)
+        #[arg(long, value_name = "N,...", value_delimiter = ',')]
+        shards: Vec<usize>,
+
+        /// Run the command only on the shards the run's hook failed on, as
+        /// kept beside the manifest (MANIFEST.hook-failures); each it now
+        /// succeeds on is taken off
+        #[arg(long, conflicts_with = "shards")]
+        failed: bool,
+
+        /// The command, as for --on-shard-complete
+        #[arg(long, value_name = "CMD")]
+        on_shard_complete: String,
+
+        /// Most commands run at once
+        #[arg(long, value_name = "N", default_value_t = 1)]
+        hook_jobs: usize,
+
+        /// Retries of a command that failed on a shard; waits double from 1s
+        #[arg(long, value_name = "N", default_value_t = 2)]
+        hook_retries: u32,
+    },
+
+    /// Check the copies of a sharded run's shards that were uploaded, by
+    /// reading each back through a command and comparing its SHA-256 with
+    /// the manifest's, before any local shard is removed
+    VerifyRemote {
+        /// The run's manifest (passwords.manifest.json)
+        #[arg(long)]
+        manifest: PathBuf,
+
+        /// Shell command that writes a shard's remote copy to stdout, with
+        /// {path}, {shard} and {key} as for --on-shard-complete
+        /// (--read-back 'aws s3 cp s3://bucket/{key} -')
+        #[arg(long, value_name = "CMD")]
+        read_back: String,
+
+        /// Remove each local shard once its remote copy matches; the
+        /// manifest stays, to verify the remote copies by again
+        #[arg(long)]
+        remove_local: bool,
+    },
+
+    /// Show how a run started with --run-dir is doing, from its status
+    /// file: progress, rate, ETA and last checkpoint, and whether it is
+    /// still alive
+    Status {
+        /// The run's --run-dir
+        #[arg(long)]
+        run_dir: PathBuf,
+
+        /// Print the status file as JSON instead
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Ask for the options of a run one at a time, checking each answer,
+    /// then print the command line that runs it and save the options for
+    /// --config
+    Wizard {
+        /// File to save the options to (without it, the wizard asks)
+        #[arg(long, value_name = "PATH")]
+        save: Option<PathBuf>,
+    },
+
+    /// Describe what a run would do, without starting it: where its key
+    /// comes from, what its records are and how likely one is to repeat
+    /// another, the files it writes, the disk, memory and time it needs,
+    /// and its warnings. For change reviews
+    Explain {
+        /// Options file of the run, as `wizard` saves it
+        #[arg(long, value_name = "PATH")]
+        config: Option<PathBuf>,
+
+        /// Options of the run, after `--` (added to those of --config)
+        #[arg(last = true, value_name = "OPTIONS")]
+        options: Vec<String>,
+    },
+
+    /// Print a completion script for SHELL: bash, zsh, fish, elvish or
+    /// powershell (e.g. `completions bash > /etc/bash_completion.d/mpg`)
+    Completions {
+        #[arg(value_enum)]
+        shell: clap_complete::Shell,
+    },
+
+    /// Summarize records generated per tenant from the local usage ledger
+    Usage {
+        /// Usage ledger file (defaults to the per-user state directory)
+        #[arg(long)]
+        ledger: Option<PathBuf>,
+
+        /// Only count runs at or after this date (YYYY-MM-DD or unix seconds)
+        #[arg(long, value_parser = usage::parse_date)]
+        since: Option<u64>,
+
+        /// Only count runs before this date (YYYY-MM-DD or unix seconds)
+        #[arg(long, value_parser = usage::parse_date)]
+        until: Option<u64>,
+
+        /// Only show this tenant
+        #[arg(long)]
+        tenant: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum KeyAction {
+    /// Store a fresh random key under NAME, or with --stdin the 32 or 64
+    /// hex characters read from standard input
+    Store {
+        #[arg(value_parser = keyring::validate_name)]
+        name: String,
+
+        /// Read the key from stdin instead of generating one
+        #[arg(long)]
+        stdin: bool,
+
+        /// Width of a generated key: 128, or 256 for aes256 and chacha20
+        #[arg(long, default_value_t = 128, value_parser = parse_key_bits,
+              conflicts_with = "stdin")]
+        bits: usize,
+
+        /// Replace an existing key of that name
+        #[arg(long)]
+        force: bool,
+    },
+
+    /// List the names of stored keys
+    List,
+
+    /// Delete the key stored under NAME
+    Delete {
+        #[arg(value_parser = keyring::validate_name)]
+        name: String,
+    },
+}
+
+/// Reads a `--schema` file.
+fn read_schema(path: &str) -> Result<Schema, String> {
+    settings::read(Path::new(path), Schema::parse)
+}
+
+/// Reads an `--encrypt-records` file.
+fn read_recipients(path: &str) -> Result<recipient::Recipients, String> {
+    settings::read(Path::new(path), recipient::Recipients::parse)
+}
+
+/// Reads a `--seal` custodians file.
+fn read_custodians(path: &str) -> Result<seal::Custodians, String> {
+    settings::read(Path::new(path), seal::Custodians::parse)
+}
+
+/// Reads a `--policy` file.
+fn read_policy(path: &str) -> Result<PasswordPolicy, String> {
+    settings::read(Path::new(path), PasswordPolicy::parse)
+}
+
+fn parse_fp_rate(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(rate) if (unique::MIN_FP_RATE..1.0).contains(&rate) => Ok(rate),
+        _ => Err(format!(
+            "expected a rate from {} up to (not including) 1",
+            unique::MIN_FP_RATE
+        )),
+    }
+}
+
+/// Parses a positive count, allowing `_` separators and `1e6` / `2.5e6`
+/// notation as long as the result is a whole number.
+fn parse_count(s: &str) -> Result<usize, String> {
+    parse_total(s)?.try_into().map_err(|_| {
+        format!(
+            "count `{}` is too large for one run; split it with `plan` into segments",
+            s
+        )
+    })
+}
+
+/// Parses a `loadtest --header`, `NAME: VALUE`.
+fn parse_header(s: &str) -> Result<(String, String), String> {
+    let (name, value) = s
+        .split_once(':')
+        .ok_or_else(|| format!("invalid header `{}` (expected NAME: VALUE)", s))?;
+    let name = name.trim();
+    if name.is_empty() || !name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-') {
+        return Err(format!("invalid header name `{}`", name));
+    }
+    if value.contains(['\r', '\n']) {
+        return Err(format!("the value of header {} has a line break", name));
+    }
+    Ok((name.to_string(), value.trim().to_string()))
+}
+
+/// Parses a key width for `key store --bits`.
+fn parse_key_bits(s: &str) -> Result<usize, String> {
+    match s {
+        "128" => Ok(128),
+        "256" => Ok(256),
+        _ => Err("keys are 128 or 256 bits".to_string()),
+    }
+}
+
+/// Parses `soak --duration` as `--ttl` is parsed.
+fn parse_soak_duration(s: &str) -> Result<Duration, String> {
+    token::parse_ttl(s)
+        .map(Duration::from_secs)
+        .map_err(|e| e.replace("the time to live", "the duration"))
+}
+
+/// Parses a warm-up, in seconds; fractions are allowed.
+fn parse_warmup(s: &str) -> Result<Duration, String> {
+    s.parse::<f64>()
+        .ok()
+        .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+        .ok_or_else(|| {
+            format!(
+                "invalid warm-up `{}` (expected seconds, such as 2 or 0.5)",
+                s
+            )
+        })
+}
+
+/// Parses a chunk size as [`parse_count`] parses counts, but names the
+/// chunk size in its complaints.
+fn parse_chunk_size(s: &str) -> Result<usize, String> {
+    match parse_total(s) {
+        Ok(size) => size
+            .try_into()
+            .map_err(|_| format!("chunk size `{}` is too large", s)),
+        Err(_) if s.replace('_', "").parse::<u128>() == Ok(0) => {
+            Err("a chunk must hold at least one record".into())
+        }
+        Err(_) => Err(format!(
+            "invalid chunk size `{}`: expected a number of records such as 65536 or 1e6",
+            s
+        )),
+    }
+}
+
+/// Parses a positive count up to 2^128 - 1, as [`parse_count`] does.
+fn parse_total(s: &str) -> Result<u128, String> {
+    let invalid = || format!("invalid count `{}`", s);
+    let cleaned = s.replace('_', "");
+    let value = match cleaned.split_once(['e', 'E']) {
+        Some((mantissa, exp)) => {
+            // Fold the fractional digits into the mantissa: 2.5e6 == 25e5
+            let (int, frac) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+            let frac = frac.trim_end_matches('0');
+            let digits: u128 = format!("{}{}", int, frac).parse().map_err(|_| invalid())?;
+            let exp: u32 = exp.parse().map_err(|_| invalid())?;
+            let exp = exp
+                .checked_sub(frac.len() as u32)
+                .ok_or_else(|| format!("count `{}` is not a whole number", s))?;
+            10u128
+                .checked_pow(exp)
+                .and_then(|p| p.checked_mul(digits))
+                .ok_or_else(|| format!("count `{}` is too large", s))?
+        }
+        None => cleaned.parse().map_err(|_| invalid())?,
+    };
+    if value == 0 {
+        return Err("count must be greater than zero".into());
+    }
+    Ok(value)
+}
+
+/// Parses a share of the machine such as `50%` into a fraction.
+fn parse_cpu_limit(s: &str) -> Result<f64, String> {
+    match s.strip_suffix('%').map(|p| p.trim().parse::<f64>()) {
+        Some(Ok(p)) if p > 0.0 && p <= 100.0 => Ok(p / 100.0),
+        _ => Err(format!(
+            "`{}` is not a percentage from 0 to 100, like 50%",
+            s
+        )),
+    }
+}
+
+/// Parses a rate such as `500MiB/s` into bytes per second.
+fn parse_write_limit(s: &str) -> Result<u64, String> {
+    let size = s.strip_suffix("/s").ok_or_else(|| {
+        format!(
+            "`{}` is not a rate (expected a size per second, like 500MiB/s)",
+            s
+        )
+    })?;
+    match memory::Limit::parse(size)? {
+        memory::Limit::Bytes(bytes) => Ok(bytes),
+        memory::Limit::Share(_) => Err(format!("`{}`: a write limit is a size, not a share", s)),
+    }
+}
+
+/// Parses the --zstd-dict path, in a build that has zstd.
+fn parse_zstd_dict(s: &str) -> Result<PathBuf, String> {
+    match Codec::Zstd.available() {
+        true => Ok(PathBuf::from(s)),
+        false => Err(Codec::parse("zstd").unwrap_err()),
+    }
+}
+
+/// Parses a format for records read back from a corpus, which have lost
+/// the key that tokens are minted with.
+fn parse_record_format(s: &str) -> Result<Format, String> {
+    match Format::parse(s)? {
+        Format::ExpiringToken => {
+            Err("expiring tokens are minted when generating, with the run's key".to_string())
+        }
+        Format::Hashcat => Err("records read back are written as they are, not hashed; \
+                                hash them when generating"
+            .to_string()),
+        format => Ok(format),
+    }
+}
+
+/// Seconds since the unix epoch.
+fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// Parses `START..END` or `START..`.
+fn parse_range(s: &str) -> Result<std::ops::Range<usize>, String> {
+    let invalid = || format!("invalid range `{}` (expected START..END)", s);
+    let (start, end) = s.split_once("..").ok_or_else(invalid)?;
+    let number = |n: &str| n.replace('_', "").parse::<usize>().map_err(|_| invalid());
+    let start = number(start)?;
+    let end = if end.is_empty() {
+        usize::MAX
     } else {
-        "Unknown CPU".into()
+        number(end)?
     };
+    if end < start {
+        return Err(invalid());
+    }
+    Ok(start..end)
+}
+
+/// Parses a `--validation-key`: a 256-bit key in hex.
+fn parse_validation_key(s: &str) -> Result<[u8; 32], String> {
+    match parse_key_hex(s)?.wide() {
+        Some(key) => Ok(*key),
+        None => Err("a validation key is 64 hex characters".to_string()),
+    }
+}
 
-    // Memory in KiB -> GiB conversion (1 GiB = 1024 * 1024 KiB)
-    let total_mem_gib = sys.total_memory() as f64 / (1024.0 * 1024.0);
-    let used_mem_gib = sys.used_memory() as f64 / (1024.0 * 1024.0);
-
-    // OS name/version if available
-    let os_name = sys.name().unwrap_or_else(|| "Unknown OS".to_string());
-    let os_version = sys.os_version().unwrap_or_else(|| "Unknown".to_string());
-    let kernel_version = sys.kernel_version().unwrap_or_else(|| "Unknown".to_string());
-
-    println!("=== System Information ===");
-    println!("OS: {} (version: {}), kernel: {}", os_name, os_version, kernel_version);
-    println!("CPU Count: {}", cpu_count);
-    println!("CPU Brand: {}", brand);
-    println!("Total Memory: {:.2} GiB", total_mem_gib);
-    println!("Used Memory:  {:.2} GiB", used_mem_gib);
-    println!("==========================\n");
-
-    // ------------------------------------------------------------------
-    // 2. Prepare to generate N passwords
-    // ------------------------------------------------------------------
-    if NUM_PASSWORDS % CHUNK_SIZE != 0 {
-        panic!(
-            "NUM_PASSWORDS ({}) must be divisible by CHUNK_SIZE ({})",
-            NUM_PASSWORDS, CHUNK_SIZE
-        );
-    }
-
-    println!(
-        "Allocating space for {} passwords (~{:.2} GiB)...",
-        NUM_PASSWORDS,
-        (NUM_PASSWORDS as f64 * 16.0) / (1024.0 * 1024.0 * 1024.0)
-    );
-
-    let mut passwords = Vec::<PasswordBlock>::with_capacity(NUM_PASSWORDS);
-    // We will overwrite every byte, so skip zero init:
-    unsafe { passwords.set_len(NUM_PASSWORDS); }
-
-    let num_chunks = NUM_PASSWORDS / CHUNK_SIZE;
-    println!(
-        "Generating in {} parallel chunks of {} passwords each...\n",
-        num_chunks, CHUNK_SIZE
-    );
-
-    // Example key (use randomness in production)
-    let key = [0x13_u8; 16];
-
-    // ------------------------------------------------------------------
-    // 3. Time the generation
-    // ------------------------------------------------------------------
-    let start_time = Instant::now();
-
-    passwords
-        .par_chunks_mut(CHUNK_SIZE)
-        .enumerate()
-        .for_each(|(chunk_idx, chunk)| {
-            // chunk is &mut [[u8;16]]
-            let byte_len = chunk.len() * 16;
-            let chunk_ptr = chunk.as_mut_ptr() as *mut u8;
-            let chunk_bytes = unsafe { std::slice::from_raw_parts_mut(chunk_ptr, byte_len) };
-
-            // Construct a unique IV for each chunk to avoid overlap
-            let mut iv = [0u8; 16];
-            // For example, embed chunk_idx in the last 8 bytes, little-endian:
-            iv[8..16].copy_from_slice(&chunk_idx.to_le_bytes());
-
-            // Create AES-CTR instance
-            let mut cipher = Aes128Ctr::new(&key.into(), &iv.into());
-            // Fill chunk in one shot
-            cipher.apply_keystream(chunk_bytes);
-        });
-
-    let duration = start_time.elapsed();
-
-    // ------------------------------------------------------------------
-    // 4. Refresh system info again (optional) and print stats
-    // ------------------------------------------------------------------
-    sys.refresh_all();
-    let used_mem_after_gib = sys.used_memory() as f64 / (1024.0 * 1024.0);
-
-    println!(
-        "Generated {} unique, 128-bit passwords in {:.2?}",
-        NUM_PASSWORDS, duration
-    );
-    let secs = duration.as_secs_f64();
-    let rate = (NUM_PASSWORDS as f64) / secs;
-    println!(
-        "Rate: ~{:.0} passwords/sec (~{:.1} million/sec)",
-        rate,
-        rate / 1_000_000.0
-    );
-
-    println!("\n=== Memory Usage After ===");
-    println!("Used Memory:  {:.2} GiB\n", used_mem_after_gib);
-
-    // Optional: show a few sample passwords
-    for i in 0..5.min(NUM_PASSWORDS) {
-        println!("Password[{}] = {:02x?}", i, passwords[i]);
+fn main() -> ExitCode {
+    let args = Args::parse();
+    let log_format = args.log_format;
+    let args = match &args.config {
+        Some(path) => config::reparse(path),
+        None => Ok(args),
+    };
+    match args.and_then(run) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            let failure = failure::Failure::classify(e);
+            failure.report(log_format);
+            ExitCode::from(failure.exit_status())
+        }
     }
 }
+
+fn run(args: Args) -> Result<(), Box<dyn std::error::Error>> {
+    if args.list_capabilities {
+        println!("{}", capabilities::table());
+        return Ok(());
+    }
+    if let Some(command) = &args.command {
+        return match command {
+            Command::DetectWatermark {
+                watermark,
+                input,
+                charset,
+            } => commands::detect_watermark(watermark, input, charset.as_ref()),
+            Command::Usage {
+                ledger,
+                since,
+                until,
+                tenant,
+            } => commands::show_usage(ledger.as_deref(), *since, *until, tenant.as_deref()),
+            Command::Cat {
+                corpus,
+                range,
+                format,
+            } => commands::cat(&corpus.open()?, range.clone(), *format),
+            Command::Pick {
+                corpus,
+                indices,
+                format,
+            } => commands::pick(&corpus.open()?, indices, *format),
+            Command::Slice {
+                corpus,
+                range,
+                out,
+                format,
+            } => commands::slice(&corpus.open()?, range.clone(), *format, out),
+            #[cfg(feature = "reservations")]
+            Command::Reserve {
+                corpus,
+                count,
+                store,
+                claimant,
+                format,
+            } => commands::reserve(
+                corpus,
+                *count,
+                store.as_deref(),
+                claimant.as_deref(),
+                *format,
+            ),
+            #[cfg(feature = "derive")]
+            Command::Derive {
+                site,
+                account,
+                generation,
+                user,
+                records,
+            } => commands::derive(
+                Site {
+                    name: site.clone(),
+                    account: account.clone(),
+                    generation: *generation,
+                },
+                user.as_deref().unwrap_or(""),
+                records,
+            ),
+            Command::Verify {
+                archive,
+                record,
+                bundle,
+                identity,
+            } => commands::verify_archive(
+                archive.as_deref(),
+                *record,
+                bundle.as_deref(),
+                identity.as_deref(),
+            ),
+            Command::ExportReplayBundle {
+                corpus,
+                key_hex,
+                key,
+                to,
+                out,
+            } => commands::export_bundle(corpus, *key_hex, key.as_ref(), to, out),
+            Command::Unlock {
+                manifest,
+                shares,
+                identity,
+            } => commands::unlock(manifest, shares, identity.as_deref()),
+            Command::Shred {
+                manifest,
+                audit_log,
+                dry_run,
+            } => commands::shred(manifest, audit_log.as_deref(), *dry_run),
+            Command::RotationPlan {
+                old,
+                new,
+                old_key,
+                new_key,
+                format,
+                output,
+            } => {
+                commands::rotation_plan((old, old_key), (new, new_key), *format, output.as_deref())
+            }
+            Command::ValidateToken {
+                tokens,
+                validation_key,
+                key_hex,
+                key,
+                at,
+            } => commands::validate_tokens(tokens, *validation_key, *key_hex, key.as_ref(), *at),
+            Command::Serve { options, run } => routes::serve(options, run),
+            Command::Rewrite {
+                input,
+                replace_column,
+                output,
+                key_hex,
+                records,
+            } => commands::rewrite(input, replace_column, output, *key_hex, records),
+            Command::Fetch(options) => commands::fetch(options),
+            Command::Loadtest { options, records } => commands::loadtest(options, records),
+            Command::Bench { records, options } => commands::run_bench(records, options),
+            Command::Soak { records, options } => commands::soak(records, options),
+            Command::Key { action } => {
+                commands::manage_keys(action).map_err(|e| e.to_string().into())
+            }
+            Command::Size {
+                records,
+                chunk_size,
+                shard_size,
+                shards,
+            } => sizing::show_size(records, *chunk_size, *shard_size, *shards),
+            Command::Plan {
+                total,
+                segment_size,
+                show,
+            } => sizing::show_plan(*total, *segment_size, *show),
+            Command::Catalog {
+                root,
+                json,
+                no_verify,
+            } => commands::show_catalog(root, json.as_deref(), !no_verify),
+            Command::RunHooks {
+                manifest,
+                shards,
+                failed,
+                on_shard_complete,
+                hook_jobs,
+                hook_retries,
+            } => {
+                let hook = hooks::Hook::new(on_shard_complete.clone(), *hook_jobs, *hook_retries);
+                commands::run_hooks(manifest, shards, *failed, &hook)
+            }
+            Command::VerifyRemote {
+                manifest,
+                read_back,
+                remove_local,
+            } => commands::verify_remote(manifest, read_back, *remove_local),
+            Command::Status { run_dir, json } => commands::show_status(run_dir, *json),
+            Command::Wizard { save } => wizard::run(save.as_deref()),
+            Command::Explain { config, options } => explain::explain(config.as_deref(), options),
+            Command::Completions { shell } => commands::completions(*shell),
+            Command::ProbeStorage {
+                path,
+                threads,
+                json,
+            } => commands::probe_storage(path, *threads, *json),
+        };
+    }
+
+    generate::run(args)
+}
@@ -0,0 +1,208 @@
+//! `--max-memory`: planning in-memory runs against the memory there is.
+//!
+//! Without `--output` the whole run is held in memory. Before anything is
+//! generated, its output size (exact, or an estimate where records vary in
+//! length) is compared with a budget: `--max-memory`, or by default a share
+//! of the memory the OS reports available. A run over budget is refused
+//! with suggestions, cut down to the records that fit, or streamed to a
+//! file instead, as `--over-memory` says.
+
+use std::path::PathBuf;
+
+/// Share of the available memory the default budget allows.
+const DEFAULT_SHARE: f64 = 0.8;
+
+/// A memory budget: bytes, or a share of what is available.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Limit {
+    Bytes(u64),
+    Share(f64),
+}
+
+impl Default for Limit {
+    fn default() -> Self {
+        Limit::Share(DEFAULT_SHARE)
+    }
+}
+
+impl Limit {
+    /// Parses `512MiB`, `8G`, `1.5GiB`, `1000000` (bytes) or `50%` (of the
+    /// available memory). Units are binary either way.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        if let Some(percent) = s.strip_suffix('%') {
+            return match percent.trim().parse::<f64>() {
+                Ok(p) if p > 0.0 && p <= 100.0 => Ok(Limit::Share(p / 100.0)),
+                _ => Err(format!("`{}` is not a percentage from 0 to 100", s)),
+            };
+        }
+        let split = s.find(|c: char| !c.is_ascii_digit() && c != '.');
+        let (number, unit) = s.split_at(split.unwrap_or(s.len()));
+        let shift = match unit.trim().to_ascii_lowercase().as_str() {
+            "" | "b" => 0,
+            "k" | "kib" | "kb" => 10,
+            "m" | "mib" | "mb" => 20,
+            "g" | "gib" | "gb" => 30,
+            "t" | "tib" | "tb" => 40,
+            _ => return Err(format!("unknown unit in `{}` (expected K, M, G or T)", s)),
+        };
+        match number.parse::<f64>() {
+            Ok(n) if n > 0.0 => Ok(Limit::Bytes((n * (1u64 << shift) as f64) as u64)),
+            _ => Err(format!("`{}` is not a memory size", s)),
+        }
+    }
+
+    /// The budget in bytes, given `available` bytes of memory.
+    pub fn bytes(&self, available: u64) -> u64 {
+        match *self {
+            Limit::Bytes(bytes) => bytes,
+            Limit::Share(share) => (available as f64 * share) as u64,
+        }
+    }
+}
+
+/// What to do with a run that does not fit.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum OverMemory {
+    /// Refuse it, suggesting what would work
+    #[default]
+    Abort,
+    /// Generate only the first records, as many as fit
+    Cap,
+    /// Stream it to this file, as `--output` would
+    Stream(PathBuf),
+}
+
+impl OverMemory {
+    /// Parses `abort`, `cap` or `stream:PATH`.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s {
+            "abort" => Ok(OverMemory::Abort),
+            "cap" => Ok(OverMemory::Cap),
+            _ => match s.strip_prefix("stream:") {
+                Some(path) if !path.is_empty() => Ok(OverMemory::Stream(PathBuf::from(path))),
+                _ => Err(format!(
+                    "unknown action `{}` (expected abort, cap or stream:PATH)",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+/// What an in-memory run will do.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Plan {
+    /// Go ahead as asked
+    Fits,
+    /// Generate only this many records
+    Cap(usize),
+    /// Stream to this file instead
+    Stream(PathBuf),
+}
+
+/// Plans a run of `count` records taking `need` bytes in memory, under a
+/// budget of `budget` bytes. The error says why a run cannot go ahead and
+/// which options would let it.
+pub fn plan(need: u64, count: usize, budget: u64, over: &OverMemory) -> Result<Plan, String> {
+    if need <= budget {
+        return Ok(Plan::Fits);
+    }
+    // Every record costs the same, up to the header
+    let fits = (budget as u128 * count as u128 / need as u128) as usize;
+    match over {
+        OverMemory::Stream(path) => Ok(Plan::Stream(path.clone())),
+        OverMemory::Cap if fits > 0 => Ok(Plan::Cap(fits)),
+        OverMemory::Cap => Err(format!(
+            "not even one record fits in {} of memory",
+            size(budget)
+        )),
+        OverMemory::Abort => Err(format!(
+            "the run needs {} in memory, over the budget of {}. Stream it instead with \
+             --output FILE (add --shard-size N to split it into files), keep the first {} \
+             records with --count {} or --over-memory cap, time it without keeping it with \
+             --local-buffers, or raise --max-memory",
+            size(need),
+            size(budget),
+            fits,
+            fits
+        )),
+    }
+}
+
+/// `bytes` in GiB, or MiB below one GiB, for messages.
+pub fn size(bytes: u64) -> String {
+    match bytes {
+        0..0x4000_0000 => format!("{:.1} MiB", bytes as f64 / (1u64 << 20) as f64),
+        _ => format!("{:.2} GiB", bytes as f64 / (1u64 << 30) as f64),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runs_over_budget_are_planned() {
+        assert_eq!(Limit::parse("512MiB"), Ok(Limit::Bytes(512 << 20)));
+        assert_eq!(Limit::parse("1.5G"), Ok(Limit::Bytes(3 << 29)));
+        assert_eq!(Limit::parse("4096"), Ok(Limit::Bytes(4096)));
+        assert_eq!(Limit::parse("50%"), Ok(Limit::Share(0.5)));
+        for bad in ["", "0", "12 parsecs", "150%", "G"] {
+            assert!(Limit::parse(bad).is_err(), "{:?}", bad);
+        }
+        assert_eq!(Limit::Share(0.5).bytes(1000), 500);
+        assert_eq!(
+            OverMemory::parse("stream:out.txt"),
+            Ok(OverMemory::Stream("out.txt".into()))
+        );
+        assert!(OverMemory::parse("stream:").is_err());
+
+        // 1000 records of 16 bytes
+        assert_eq!(
+            plan(16_000, 1000, 16_000, &OverMemory::Abort),
+            Ok(Plan::Fits)
+        );
+        assert_eq!(
+            plan(16_000, 1000, 8_000, &OverMemory::Cap),
+            Ok(Plan::Cap(500))
+        );
+        assert_eq!(
+            plan(16_000, 1000, 8_000, &OverMemory::Stream("f".into())),
+            Ok(Plan::Stream("f".into()))
+        );
+        assert!(plan(16_000, 1000, 10, &OverMemory::Cap).is_err());
+        let refused = plan(16_000, 1000, 8_000, &OverMemory::Abort).unwrap_err();
+        assert!(refused.contains("--output") && refused.contains("--count 500"));
+    }
+
+    #[test]
+    fn sizes_read_and_print_in_binary_units() {
+        assert_eq!(Limit::parse("8 gb"), Ok(Limit::Bytes(8 << 30)));
+        assert_eq!(Limit::parse("2k"), Ok(Limit::Bytes(2048)));
+        assert_eq!(Limit::parse("1TiB"), Ok(Limit::Bytes(1 << 40)));
+        assert_eq!(Limit::parse("100%"), Ok(Limit::Share(1.0)));
+        for bad in ["0%", "-5%", "1.5.2G", "5P"] {
+            assert!(Limit::parse(bad).is_err(), "{:?}", bad);
+        }
+        assert_eq!(Limit::default().bytes(10_000), 8_000);
+        assert_eq!(Limit::Bytes(7).bytes(10_000), 7);
+        assert_eq!(OverMemory::parse("abort"), Ok(OverMemory::Abort));
+        assert_eq!(OverMemory::parse("cap"), Ok(OverMemory::Cap));
+        assert!(OverMemory::parse("Cap").is_err());
+
+        assert_eq!(size(3 << 19), "1.5 MiB");
+        assert_eq!(size((1 << 30) - 1), "1024.0 MiB");
+        assert_eq!(size(3 << 29), "1.50 GiB");
+
+        // Sizes past what can be held are planned without overflow
+        assert_eq!(
+            plan(u64::MAX, usize::MAX, u64::MAX / 4, &OverMemory::Cap),
+            Ok(Plan::Cap(usize::MAX / 4))
+        );
+        // Streaming needs no memory, so it goes ahead whatever the budget
+        assert_eq!(
+            plan(16_000, 1000, 0, &OverMemory::Stream("f".into())),
+            Ok(Plan::Stream("f".into()))
+        );
+    }
+}
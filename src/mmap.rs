@@ -0,0 +1,191 @@
+//! A shared, writable memory map of a whole file, for generating output in
+//! place: each chunk's keystream goes straight into the page cache with no
+//! intermediate buffer or write call.
+
+use std::fs::File;
+use std::io;
+
+/// `len` bytes of a file mapped read-write and shared, so stores reach the
+/// file. Unmapped on drop.
+pub(crate) struct Mapping {
+    ptr: *mut u8,
+    len: usize,
+}
+
+// SAFETY: the mapping is plain memory owned by this value; callers of
+// `slice` promise that concurrent slices never overlap.
+unsafe impl Send for Mapping {}
+unsafe impl Sync for Mapping {}
+
+impl Mapping {
+    /// Maps the first `len` bytes of `file`, which must be open for reading
+    /// and writing and at least `len` bytes long.
+    #[cfg(unix)]
+    pub(crate) fn new(file: &File, len: usize) -> io::Result<Self> {
+        use std::os::unix::io::AsRawFd;
+
+        if len == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "an empty file cannot be mapped",
+            ));
+        }
+        reserve(file, len)?;
+        // SAFETY: a fresh mapping that aliases no Rust memory; the kernel
+        // checks the descriptor and length.
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Mapping {
+            ptr: ptr.cast(),
+            len,
+        })
+    }
+
+    #[cfg(not(unix))]
+    pub(crate) fn new(_file: &File, _len: usize) -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "memory-mapped output needs a unix system",
+        ))
+    }
+
+    /// The mapped bytes `start..start + len`.
+    ///
+    /// # Safety
+    ///
+    /// No two slices alive at once may overlap.
+    #[allow(clippy::mut_from_ref)]
+    pub(crate) unsafe fn slice(&self, start: usize, len: usize) -> &mut [u8] {
+        assert!(start + len <= self.len, "slice past the end of the mapping");
+        std::slice::from_raw_parts_mut(self.ptr.add(start), len)
+    }
+
+    /// Writes the mapped pages back to the file.
+    #[cfg(unix)]
+    pub(crate) fn flush(&self) -> io::Result<()> {
+        // SAFETY: `ptr..ptr + len` is our own mapping.
+        if unsafe { libc::msync(self.ptr.cast(), self.len, libc::MS_SYNC) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    pub(crate) fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        // SAFETY: unmapping our own mapping, which no slice outlives.
+        #[cfg(unix)]
+        unsafe {
+            libc::munmap(self.ptr.cast(), self.len);
+        }
+    }
+}
+
+/// A whole file mapped read-only and shared, for reading records out of it
+/// by offset without holding it in memory. Unmapped on drop.
+pub(crate) struct ReadMapping {
+    ptr: *const u8,
+    len: usize,
+}
+
+// SAFETY: the mapping is read-only memory owned by this value.
+unsafe impl Send for ReadMapping {}
+unsafe impl Sync for ReadMapping {}
+
+impl ReadMapping {
+    /// Maps the first `len` bytes of `file`, which must be open for reading
+    /// and at least `len` bytes long. A file cut shorter while it is mapped
+    /// kills the process (SIGBUS) on the next read past its end.
+    #[cfg(unix)]
+    pub(crate) fn new(file: &File, len: usize) -> io::Result<Self> {
+        use std::os::unix::io::AsRawFd;
+
+        if len == 0 {
+            return Ok(ReadMapping {
+                ptr: std::ptr::NonNull::dangling().as_ptr(),
+                len,
+            });
+        }
+        // SAFETY: a fresh read-only mapping that aliases no Rust memory;
+        // the kernel checks the descriptor and length.
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(ReadMapping {
+            ptr: ptr.cast(),
+            len,
+        })
+    }
+
+    #[cfg(not(unix))]
+    pub(crate) fn new(_file: &File, _len: usize) -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "memory-mapped input needs a unix system",
+        ))
+    }
+
+    pub(crate) fn bytes(&self) -> &[u8] {
+        // SAFETY: `ptr..ptr + len` is our own mapping (or empty), which
+        // lives as long as `self`
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl Drop for ReadMapping {
+    fn drop(&mut self) {
+        // SAFETY: unmapping our own mapping, which no slice outlives.
+        #[cfg(unix)]
+        if self.len > 0 {
+            unsafe {
+                libc::munmap(self.ptr as *mut libc::c_void, self.len);
+            }
+        }
+    }
+}
+
+/// Allocates the file's blocks up front where the filesystem can, so a full
+/// disk is an error here rather than a SIGBUS on some later store.
+#[cfg(target_os = "linux")]
+fn reserve(file: &File, len: usize) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    // SAFETY: no memory is involved, only the descriptor.
+    match unsafe { libc::posix_fallocate(file.as_raw_fd(), 0, len as libc::off_t) } {
+        0 => Ok(()),
+        libc::ENOSPC => Err(io::Error::from_raw_os_error(libc::ENOSPC)),
+        // Not every filesystem can; the sparse file still works
+        _ => Ok(()),
+    }
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn reserve(_file: &File, _len: usize) -> io::Result<()> {
+    Ok(())
+}
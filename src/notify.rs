@@ -0,0 +1,196 @@
+//! systemd notifications (sd_notify) for runs and servers started by a
+//! unit of `Type=notify`: READY=1 once work starts, STATUS= with how it is
+//! going, WATCHDOG=1 within the unit's `WatchdogSec=`, and STOPPING=1 at
+//! the end.
+//!
+//! systemd passes the socket in `NOTIFY_SOCKET` and the watchdog interval
+//! in `WATCHDOG_USEC`; without them nothing is sent. A run only pings the
+//! watchdog while records keep being written, so one that hangs stops
+//! pinging and systemd restarts it; `WatchdogSec=` has to be longer than
+//! the slowest chunk takes. A server pings for as long as it runs.
+
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// Most time between status updates.
+const STATUS_EVERY: Duration = Duration::from_secs(1);
+
+/// The socket systemd listens for notifications on.
+pub struct Notifier {
+    #[cfg(unix)]
+    socket: std::os::unix::net::UnixDatagram,
+    #[cfg(unix)]
+    addr: std::os::unix::net::SocketAddr,
+    watchdog: Option<Duration>,
+}
+
+impl Notifier {
+    /// The notifier systemd set up for this process, if any.
+    pub fn from_env() -> Option<Self> {
+        let path = std::env::var_os("NOTIFY_SOCKET")?;
+        match Self::connect(&path) {
+            Ok(notifier) => Some(notifier),
+            Err(e) => {
+                status!("WARNING: cannot notify systemd at {:?}: {}", path, e);
+                None
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    fn connect(path: &std::ffi::OsStr) -> io::Result<Self> {
+        use std::os::unix::ffi::OsStrExt;
+        use std::os::unix::net::{SocketAddr, UnixDatagram};
+        let addr = match path.as_bytes().strip_prefix(b"@") {
+            #[cfg(target_os = "linux")]
+            Some(name) => {
+                use std::os::linux::net::SocketAddrExt;
+                SocketAddr::from_abstract_name(name)?
+            }
+            #[cfg(not(target_os = "linux"))]
+            Some(_) => return Err(io::ErrorKind::Unsupported.into()),
+            None => SocketAddr::from_pathname(path)?,
+        };
+        Ok(Notifier {
+            socket: UnixDatagram::unbound()?,
+            addr,
+            watchdog: watchdog(
+                std::env::var("WATCHDOG_USEC").ok().as_deref(),
+                std::env::var("WATCHDOG_PID").ok().as_deref(),
+                std::process::id(),
+            ),
+        })
+    }
+
+    #[cfg(not(unix))]
+    fn connect(_: &std::ffi::OsStr) -> io::Result<Self> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    /// Sends `state`, newline-separated `NAME=value` assignments.
+    pub fn send(&self, state: &str) -> io::Result<()> {
+        #[cfg(unix)]
+        self.socket.send_to_addr(state.as_bytes(), &self.addr)?;
+        #[cfg(not(unix))]
+        let _ = state;
+        Ok(())
+    }
+
+    /// Says the work has started, then keeps systemd told until
+    /// [`Service::stop`]: `status` gives the STATUS= text and whether the
+    /// work has moved on since it was last asked, without which the
+    /// watchdog goes unpinged.
+    pub fn ready(
+        self,
+        status: String,
+        mut progress: impl FnMut() -> (String, bool) + Send + 'static,
+    ) -> Service {
+        let _ = self.send(&format!("READY=1\nSTATUS={}", status));
+        let every = self
+            .watchdog
+            .map_or(STATUS_EVERY, |watchdog| STATUS_EVERY.min(watchdog / 2));
+        let done = Arc::new(AtomicBool::new(false));
+        let stop = Arc::clone(&done);
+        let thread = std::thread::spawn(move || {
+            while !done.load(Ordering::Relaxed) {
+                std::thread::park_timeout(every);
+                let (status, moved) = progress();
+                let ping = match self.watchdog.is_some() && moved {
+                    true => "WATCHDOG=1\n",
+                    false => "",
+                };
+                let _ = self.send(&format!("{}STATUS={}", ping, status));
+            }
+            self
+        });
+        Service { done: stop, thread }
+    }
+}
+
+/// The watchdog interval from `WATCHDOG_USEC`, if it is meant for process
+/// `pid`: systemd names the process in `WATCHDOG_PID` when it may not be
+/// the one it started.
+fn watchdog(usec: Option<&str>, for_pid: Option<&str>, pid: u32) -> Option<Duration> {
+    let usec = usec?.parse::<u64>().ok().filter(|&usec| usec > 0)?;
+    match for_pid.map(str::parse::<u32>) {
+        Some(Ok(for_pid)) if for_pid != pid => None,
+        Some(Err(_)) => None,
+        _ => Some(Duration::from_micros(usec)),
+    }
+}
+
+/// Work systemd is being kept told about.
+pub struct Service {
+    done: Arc<AtomicBool>,
+    thread: JoinHandle<Notifier>,
+}
+
+impl Service {
+    /// Stops the updates and tells systemd the work is over, with `status`.
+    pub fn stop(self, status: String) {
+        self.done.store(true, Ordering::Relaxed);
+        self.thread.thread().unpark();
+        if let Ok(notifier) = self.thread.join() {
+            let _ = notifier.send(&format!("STOPPING=1\nSTATUS={}", status));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_watchdog_is_for_this_process_only() {
+        assert_eq!(
+            watchdog(Some("30000000"), None, 7),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            watchdog(Some("30000000"), Some("7"), 7),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(watchdog(Some("30000000"), Some("8"), 7), None);
+        assert_eq!(watchdog(Some("0"), None, 7), None);
+        assert_eq!(watchdog(None, Some("7"), 7), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn notifications_reach_the_socket() {
+        use std::os::unix::net::UnixDatagram;
+        let path = std::env::temp_dir().join(format!("mpg_notify_{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let systemd = UnixDatagram::bind(&path).unwrap();
+        systemd
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut notifier = Notifier::connect(path.as_os_str()).unwrap();
+        notifier.watchdog = Some(Duration::from_millis(100));
+        let mut ticks = 0;
+        let service = notifier.ready("Starting".to_string(), move || {
+            ticks += 1;
+            // Stalled on the second tick: no ping
+            (format!("tick {}", ticks), ticks != 2)
+        });
+        let mut buf = [0; 256];
+        let mut next = || {
+            let n = systemd.recv(&mut buf).unwrap();
+            String::from_utf8(buf[..n].to_vec()).unwrap()
+        };
+        assert_eq!(next(), "READY=1\nSTATUS=Starting");
+        assert_eq!(next(), "WATCHDOG=1\nSTATUS=tick 1");
+        assert_eq!(next(), "STATUS=tick 2");
+        assert_eq!(next(), "WATCHDOG=1\nSTATUS=tick 3");
+        service.stop("Done".to_string());
+        let mut last = next();
+        while last.starts_with("WATCHDOG") || last.starts_with("STATUS=tick") {
+            last = next();
+        }
+        assert_eq!(last, "STOPPING=1\nSTATUS=Done");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,1138 @@
+//! Where the main run's records go: memory, stdout or a file, and how the
+//! file is written — through a checkpoint, a trained dictionary, an archive,
+//! shards, a fallback or a memory map.
+//!
+//! [`write`] picks the way from the options and returns what it found on
+//! the way, which [`report`] prints once the progress display is gone.
+
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::Duration;
+
+#[cfg(feature = "compress-zstd")]
+use mass_password_gen_optimized::ZstdDictionary;
+use mass_password_gen_optimized::{
+    archive::{self, ArchiveIndex},
+    corpus::Shard,
+    layout::RawLayout,
+    AdaptiveBuffer, ArchiveWriter, ChunkCodec, Codec, Compression, Compressor, DeviceWrites,
+    FailoverReport, HostInfo, Landing, Manifest, OutputSink, PasswordGenerator, Progress,
+    ShardReport, ShardWrite, WriteReport,
+};
+
+use crate::checkpoint::{Checkpoint, Checkpointing};
+use crate::events::Events;
+use crate::hooks::Hook;
+use crate::recipient::Recipients;
+use crate::seal::{Custodians, Sealing};
+use crate::signing::Signer;
+use crate::throughput::Span;
+use crate::{failure, perf, permissions, Args, IfComplete};
+
+/// zstd level for --zstd-dict (zstd's own default).
+#[cfg(feature = "compress-zstd")]
+const ZSTD_LEVEL: i32 = 3;
+
+/// Largest dictionary --zstd-dict trains, as with `zstd --train`.
+#[cfg(feature = "compress-zstd")]
+const ZSTD_DICT_SIZE: usize = 110 * 1024;
+
+/// What the main run writes with.
+pub struct Target<'a> {
+    pub generator: &'a PasswordGenerator,
+    pub sink: Option<&'a dyn OutputSink>,
+    /// Sizes the writes of sequential output to the destination
+    pub buffer: &'a AdaptiveBuffer,
+    pub compressor: Option<&'a Compressor>,
+    pub to_stdout: bool,
+    /// `--events`: where finished shards are announced
+    pub events: Option<&'a Events>,
+    /// `--on-shard-complete`: what is run on finished shards
+    pub hook: Option<&'a Hook>,
+    /// `--sign-with`: what the shards and manifest are signed with
+    pub signer: Option<&'a Signer>,
+    /// `--encrypt-records`: whom the shards are encrypted to
+    pub recipients: Option<&'a Recipients>,
+    /// `--seal`: who holds the shares of the key the shards are sealed under
+    pub seal: Option<&'a Custodians>,
+    /// What the manifest records of the machine, unless `--no-host-info`
+    pub host: Option<&'a HostInfo>,
+    /// `--record-align`: where the raw records sit, when padded
+    pub layout: Option<&'a RawLayout>,
+}
+
+/// What writing the output found, for [`report`].
+pub enum Outcome<'a> {
+    /// Nothing beyond the records
+    Written,
+    /// `--mmap`, and why the output was written instead, if it was
+    Mapped(Option<io::Error>),
+    /// `--zstd-dict`: where the dictionary went, and how it did
+    #[cfg(feature = "compress-zstd")]
+    Dictionary(&'a Path, ZstdDictionary),
+    /// `--archive-format`
+    Archived(Box<ArchiveIndex>),
+    /// `--shard-size`
+    Sharded(Box<Sharding>),
+    /// `--fallback`: the primary, the fallback and where each chunk landed
+    Failover(&'a Path, &'a Path, FailoverReport),
+}
+
+/// What `--shard-size` wrote.
+pub struct Sharding {
+    /// Where the manifest was written
+    pub path: PathBuf,
+    pub manifest: Manifest,
+    /// With `--output-dirs`, what each directory took
+    pub stripes: Vec<Stripe>,
+    /// With `--fallback`, the directory shards could move to, and where
+    /// each landed
+    pub spilled: Option<(PathBuf, ShardReport)>,
+    /// With `--zstd-dict`, where the dictionary went, and how it did
+    #[cfg(feature = "compress-zstd")]
+    pub dictionary: Option<(PathBuf, ZstdDictionary)>,
+}
+
+/// One directory of `--output-dirs` and what was written to it.
+#[derive(Debug, serde::Serialize)]
+pub struct Stripe {
+    pub dir: PathBuf,
+    #[serde(flatten)]
+    pub writes: DeviceWrites,
+    pub bytes_per_sec: f64,
+}
+
+/// `--if-complete`: whether `generator`'s run, in `layout` to `path` in
+/// shards of `shard_size`, is already there and finished, its manifest of
+/// the same run ID and its shards passing `check`.
+pub fn already_complete(
+    args: &Args,
+    generator: &PasswordGenerator,
+    layout: Option<&RawLayout>,
+    path: &Path,
+    shard_size: usize,
+    check: IfComplete,
+) -> io::Result<bool> {
+    let (_, manifest_path) = shard_paths(path, 0);
+    let manifest = match Manifest::load(&manifest_path) {
+        Ok(manifest) => Some(manifest),
+        Err(e) if e.kind() == io::ErrorKind::InvalidData => None,
+        Err(_) => return Ok(false),
+    };
+    let dir = manifest_path.parent().unwrap_or(Path::new(""));
+    let compression = shard_compression(args, dir)?;
+    let run_id = archive::run_id(
+        generator,
+        args.records.format,
+        layout,
+        shard_size,
+        compression.as_ref(),
+    );
+    if manifest.as_ref().and_then(|m| m.run_id.as_deref()) != Some(run_id.as_str()) {
+        status!(
+            "{} is not of this run; writing it over",
+            manifest_path.display()
+        );
+        return Ok(false);
+    }
+    let manifest = manifest.expect("matched above");
+    // A checkpointed run that stopped lists only the shards it finished
+    let shards = generator.num_shards(shard_size);
+    let problem = match check {
+        _ if manifest.shards.len() < shards => Some(format!(
+            "only {} of its {} shards are written",
+            manifest.shards.len(),
+            shards
+        )),
+        IfComplete::Skip => manifest
+            .shards
+            .iter()
+            .find(|shard| !dir.join(&shard.path).is_file())
+            .map(|shard| format!("{} is missing", dir.join(&shard.path).display())),
+        IfComplete::Verify => {
+            let report = archive::verify(&manifest_path)?;
+            (!report.problems.is_empty()).then(|| report.problems.join("; "))
+        }
+    };
+    if let Some(problem) = problem {
+        status!(
+            "Run {} is in {}, but {}; writing it again",
+            &run_id[..16],
+            manifest_path.display(),
+            problem
+        );
+        return Ok(false);
+    }
+    status!(
+        "Run {} is already complete in {}{}; nothing to write",
+        &run_id[..16],
+        manifest_path.display(),
+        if check == IfComplete::Verify {
+            ", and verified"
+        } else {
+            ""
+        }
+    );
+    Ok(true)
+}
+
+/// Writes the run as `args` say: into `memory` without --output, else to
+/// the file or stdout, checkpointed when `checkpointing`. Returns what
+/// `on_chunk` gave for each chunk.
+pub fn write<'a, F>(
+    args: &'a Args,
+    target: &Target,
+    checkpointing: Option<&mut Checkpointing>,
+    memory: &mut Vec<u8>,
+    on_chunk: F,
+) -> io::Result<(Vec<Span>, Outcome<'a>)>
+where
+    F: Fn(usize, usize) -> Span + Sync,
+{
+    let Target {
+        generator, sink, ..
+    } = *target;
+    let written = |chunks| Ok((chunks, Outcome::Written));
+    let Some(path) = &args.output else {
+        return match sink {
+            _ if args.local_buffers => written(perf::generate_locally(generator, on_chunk)),
+            None if generator.fixed_width() => written(generator.generate_into(memory, on_chunk)),
+            None => written(generator.write_to(memory, on_chunk)?),
+            Some(sink) => written(generator.write_formatted(sink, memory, on_chunk)?),
+        };
+    };
+    // A sharded run is checkpointed a shard at a time, below
+    let checkpointing = match (checkpointing, args.shard_size) {
+        (Some(checkpointing), None) => {
+            let chunks = checkpointing.write(generator, sink, path, args.output_mode, on_chunk)?;
+            return written(chunks);
+        }
+        (checkpointing, _) => checkpointing,
+    };
+    #[cfg(feature = "compress-zstd")]
+    if let Some(dict_path) = &args.zstd_dict {
+        return with_dictionary(args, target, path, dict_path, on_chunk);
+    }
+    if args.archive_format.is_some() {
+        return archived(args, target, create(args, path)?, on_chunk);
+    }
+    if let Some(shard_size) = args.shard_size {
+        let codec = target.compressor.map(|c| c as &dyn ChunkCodec);
+        let (chunks, sharding) = sharded(
+            args,
+            target,
+            path,
+            shard_size,
+            codec,
+            checkpointing,
+            on_chunk,
+        )?;
+        return Ok((chunks, Outcome::Sharded(Box::new(sharding))));
+    }
+    if let Some(compressor) = target.compressor {
+        return written(sequential(args, target, Some(compressor), path, on_chunk)?);
+    }
+    if let Some(fallback) = &args.fallback {
+        return with_fallback(args, target, path, fallback, on_chunk);
+    }
+    match sink {
+        None if args.mmap => {
+            // Mapping for writing needs the file open for reading too
+            let mut options = File::options();
+            options.read(true).write(true).create(true).truncate(true);
+            let file = permissions::open(&mut options, path, args.output_mode)?;
+            let (chunks, why) = generator.write_mapped(&file, on_chunk)?;
+            Ok((chunks, Outcome::Mapped(why)))
+        }
+        None if !target.to_stdout && generator.fixed_width() => {
+            written(generator.write_to_file(&create(args, path)?, on_chunk)?)
+        }
+        // Formatted records have no fixed offsets, so files are written in order
+        _ => written(sequential(args, target, None, path, on_chunk)?),
+    }
+}
+
+/// Creates an output file with --output-mode; filler from --sink-dry-run
+/// never clobbers real output.
+fn create(args: &Args, path: &Path) -> io::Result<File> {
+    if args.sink_dry_run {
+        let mut options = File::options();
+        options.write(true).create_new(true);
+        permissions::open(&mut options, path, args.output_mode)
+    } else {
+        permissions::create(path, args.output_mode)
+    }
+}
+
+/// Writes the run in order to `path`, or stdout, through `codec` if any.
+fn sequential<F>(
+    args: &Args,
+    target: &Target,
+    codec: Option<&dyn ChunkCodec>,
+    path: &Path,
+    on_chunk: F,
+) -> io::Result<Vec<Span>>
+where
+    F: Fn(usize, usize) -> Span + Sync,
+{
+    let Target {
+        generator,
+        sink,
+        buffer,
+        ..
+    } = *target;
+    if target.to_stdout {
+        generator.write_adaptive(sink, codec, buffer, &mut io::stdout(), on_chunk)
+    } else {
+        let mut file = create(args, path)?;
+        generator.write_adaptive(sink, codec, buffer, &mut file, on_chunk)
+    }
+}
+
+/// `--zstd-dict`: trains a dictionary on the run, saves it to `dict_path`
+/// and compresses the output with it, or each `--shard-size` shard.
+#[cfg(feature = "compress-zstd")]
+fn with_dictionary<'a, F>(
+    args: &Args,
+    target: &Target,
+    path: &Path,
+    dict_path: &'a Path,
+    on_chunk: F,
+) -> io::Result<(Vec<Span>, Outcome<'a>)>
+where
+    F: Fn(usize, usize) -> Span + Sync,
+{
+    let zstd = ZstdDictionary::train(target.generator, target.sink, ZSTD_LEVEL, ZSTD_DICT_SIZE)?;
+    permissions::create(dict_path, args.output_mode)?.write_all(zstd.dictionary())?;
+    if let Some(shard_size) = args.shard_size {
+        let (chunks, mut sharding) =
+            sharded(args, target, path, shard_size, Some(&zstd), None, on_chunk)?;
+        sharding.dictionary = Some((dict_path.to_path_buf(), zstd));
+        return Ok((chunks, Outcome::Sharded(Box::new(sharding))));
+    }
+    let chunks = sequential(args, target, Some(&zstd), path, on_chunk)?;
+    Ok((chunks, Outcome::Dictionary(dict_path, zstd)))
+}
+
+/// `--archive-format`: the records and, after them, their index.
+fn archived<'a, F>(
+    args: &Args,
+    target: &Target,
+    file: File,
+    on_chunk: F,
+) -> io::Result<(Vec<Span>, Outcome<'a>)>
+where
+    F: Fn(usize, usize) -> Span + Sync,
+{
+    let compression = target.compressor.map(|c| compression(c.codec()));
+    let codec = target.compressor.map(|c| c as &dyn ChunkCodec);
+    let mut out = ArchiveWriter::new(file)?;
+    if let Some(host) = target.host {
+        out = out.with_host(host.clone());
+    }
+    let chunks =
+        target
+            .generator
+            .write_adaptive(target.sink, codec, target.buffer, &mut out, on_chunk)?;
+    let index = out.finish(target.generator, args.records.format, compression)?;
+    Ok((chunks, Outcome::Archived(Box::new(index))))
+}
+
+/// What a manifest records of output compressed with `codec` alone.
+fn compression(codec: Codec) -> Compression {
+    match codec {
+        Codec::Zstd => Compression::Zstd { dictionary: None },
+        Codec::Gzip => Compression::Gzip,
+    }
+}
+
+/// How `--compress` or `--zstd-dict` compresses `--shard-size` shards, as
+/// the manifest in `dir` records it: the dictionary's path relative to
+/// `dir`, or in full if it is elsewhere.
+fn shard_compression(args: &Args, dir: &Path) -> io::Result<Option<Compression>> {
+    if let Some(dict_path) = &args.zstd_dict {
+        let dir = std::path::absolute(if dir.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            dir
+        })?;
+        let dict_path = std::path::absolute(dict_path)?;
+        let dictionary = match dict_path.strip_prefix(&dir) {
+            Ok(relative) => relative.to_path_buf(),
+            Err(_) => dict_path,
+        };
+        return Ok(Some(Compression::Zstd {
+            dictionary: Some(dictionary),
+        }));
+    }
+    Ok(args.compress.map(compression))
+}
+
+/// `--shard-size`: whole chunks to each shard, and a manifest of them.
+/// With `--output-dirs` the shards go round the directories in turn, and
+/// with `--fallback` a shard whose file fails moves to that directory; the
+/// manifest gives the full paths of either. Checkpointed, the shards are
+/// written in order and each is put in the manifest as it is finished.
+fn sharded<F>(
+    args: &Args,
+    target: &Target,
+    path: &Path,
+    shard_size: usize,
+    codec: Option<&dyn ChunkCodec>,
+    checkpointing: Option<&mut Checkpointing>,
+    on_chunk: F,
+) -> io::Result<(Vec<Span>, Sharding)>
+where
+    F: Fn(usize, usize) -> Span + Sync,
+{
+    let generator = target.generator;
+    let dir = path.parent().unwrap_or(Path::new(""));
+    let (mut names, manifest_path) = shard_paths(path, generator.num_shards(shard_size));
+    let stripes = args
+        .output_dirs
+        .iter()
+        .map(|dir| canonical(dir))
+        .collect::<io::Result<Vec<_>>>()?;
+    for (n, name) in names.iter_mut().enumerate() {
+        if !stripes.is_empty() {
+            *name = stripes[n % stripes.len()].join(&*name);
+        }
+    }
+    let compression = match codec {
+        Some(_) => shard_compression(args, dir)?,
+        None => None,
+    };
+    let compressed = compression.is_some();
+    let format = args.records.format;
+    let describe = |n, name: &Path| {
+        archive::shard_entry(generator, format, shard_size, compressed, dir, n, name)
+    };
+    let ledger = checkpointing
+        .map(|checkpointing| {
+            let manifest = archive::shard_manifest(
+                generator,
+                format,
+                target.layout,
+                shard_size,
+                compression.as_ref(),
+                dir,
+                &[],
+            )?;
+            Ledger::open(
+                checkpointing,
+                manifest,
+                &manifest_path,
+                args.output_mode,
+                &describe,
+            )
+        })
+        .transpose()?;
+    // A resumed run leaves the shards it has alone
+    let from = ledger
+        .as_ref()
+        .map_or(0, |ledger| ledger.manifest.shards.len());
+    let spill = args.fallback.as_deref().map(canonical).transpose()?;
+    let mut unopened = Vec::new();
+    let mut files = Vec::with_capacity(names.len());
+    for (n, name) in names.iter().enumerate() {
+        if n < from {
+            files.push(None);
+            continue;
+        }
+        match permissions::create(&dir.join(name), args.output_mode) {
+            Ok(file) => files.push(Some(file)),
+            // A shard whose file cannot even be created moves at once
+            Err(e) if spill.is_some() => {
+                unopened.push((n, e));
+                files.push(None);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    let open_spare = |n: usize| {
+        let spare = spill.as_ref().expect("only spilled with --fallback");
+        permissions::create(&spare.join(&names[n]), args.output_mode)
+    };
+    // Where shard `n` is, relative to `dir`, once written
+    let landed = |n: usize, landing| match (landing, &spill) {
+        (Landing::Fallback, Some(spill)) => spill.join(&names[n]),
+        _ => names[n].clone(),
+    };
+    // Uncompressed shards with no spares, unless checkpointed, land all
+    // at once where they can
+    let plain = (codec.is_none() && spill.is_none() && stripes.is_empty() && ledger.is_none())
+        .then(|| files.drain(..).flatten().collect::<Vec<_>>());
+    // Finished shards go to the --events announcer, the
+    // --on-shard-complete hook and the --checkpoint ledger, if there are
+    // any, which stop once the writing is over and `on_shard` gone
+    // What keys the hook's uploads, so that each lands once
+    let run_id = archive::run_id(
+        generator,
+        format,
+        target.layout,
+        shard_size,
+        compression.as_ref(),
+    );
+    let (finished, to_announce) = mpsc::channel();
+    let (hookable, to_hook) = mpsc::channel();
+    let (recordable, to_record) = mpsc::channel();
+    let on_shard = move |n, landing| {
+        // An announcer that stopped has its error to give below
+        let _ = finished.send((n, landed(n, landing)));
+        let _ = hookable.send((n, landed(n, landing)));
+        let _ = recordable.send((n, landed(n, landing)));
+    };
+    let (written, announced) = std::thread::scope(|scope| {
+        let announcer = target.events.map(|events| {
+            scope.spawn(move || {
+                events.shards(generator, format, shard_size, compressed, dir, to_announce)
+            })
+        });
+        if let Some(hook) = target.hook {
+            let run_id = run_id.as_str();
+            scope.spawn(move || hook.shards(Some(run_id), dir, to_hook));
+        }
+        if let Some(ledger) = ledger {
+            let describe = &describe;
+            scope.spawn(move || ledger.record(dir, describe, to_record));
+        }
+        let shards = ShardWrite {
+            sink: target.sink,
+            codec,
+            files: &files,
+            shard_size,
+            from,
+            spare: spill.as_ref().map(|_| &open_spare as _),
+        };
+        let written = match (plain, stripes.len()) {
+            (Some(plain), _) => generator
+                .write_sharded(target.sink, &plain, shard_size, on_chunk, move |n| {
+                    on_shard(n, Landing::Primary)
+                })
+                .map(|chunks| (chunks, Vec::new(), None)),
+            (None, 0) => generator
+                .write_shards(&shards, on_chunk, on_shard)
+                .map(|(chunks, report)| (chunks, Vec::new(), Some(report))),
+            (None, devices) => generator
+                .write_striped(&shards, devices, on_chunk, move |n| {
+                    on_shard(n, Landing::Primary)
+                })
+                .map(|(chunks, writes)| {
+                    let stripes = stripes.into_iter().zip(writes).map(|(dir, writes)| Stripe {
+                        bytes_per_sec: writes.bytes as f64 / writes.seconds.max(f64::MIN_POSITIVE),
+                        dir,
+                        writes,
+                    });
+                    (chunks, stripes.collect(), None)
+                }),
+        };
+        let announced = announcer.map_or(Ok(()), |announcer| {
+            announcer.join().expect("the announcer does not panic")
+        });
+        (written, announced)
+    });
+    let (chunks, stripes, spilled) = written?;
+    announced.map_err(|e| io::Error::new(e.kind(), format!("--events: {}", e)))?;
+    let spilled = spill.zip(spilled).map(|(spill, mut report)| {
+        for (name, landing) in names.iter_mut().zip(&report.landings) {
+            if *landing == Landing::Fallback {
+                // Whatever the shard's own file took before it failed is of
+                // no use; the manifest gives the shard's path on the spare
+                let _ = std::fs::remove_file(dir.join(&*name));
+                *name = spill.join(&*name);
+            }
+        }
+        report.errors.extend(unopened);
+        report.errors.sort_by_key(|(n, _)| *n);
+        (spill, report)
+    });
+    let mut manifest = archive::shard_manifest(
+        generator,
+        format,
+        target.layout,
+        shard_size,
+        compression.as_ref(),
+        dir,
+        &names,
+    )?;
+    manifest.host = target.host.cloned();
+    let slices = match target.recipients {
+        Some(recipients) => recipients
+            .assign(generator.count(), shard_size)
+            .map_err(io::Error::other)?,
+        None => Vec::new(),
+    };
+    for (shard, slice) in manifest.shards.iter_mut().zip(&slices) {
+        shard.encrypted_to = Some(slice.recipient.to_string());
+    }
+    let sealing = target
+        .seal
+        .map(|custodians| Sealing::new(custodians, &manifest_path))
+        .transpose()?;
+    if let Some(sealing) = &sealing {
+        manifest.seal = Some(sealing.seal.clone());
+        for shard in &mut manifest.shards {
+            shard.encrypted_to = Some(sealing.label());
+        }
+    }
+    save_manifest(&manifest, &manifest_path, args.output_mode)?;
+    if let Some(signer) = target.signer {
+        let mut signed: Vec<_> = names.iter().map(|name| dir.join(name)).collect();
+        signed.push(manifest_path.clone());
+        signer.sign_all(&signed)?;
+        status!(
+            "Signed {} shards and the manifest with {}",
+            names.len(),
+            signer
+        );
+    }
+    // Last, so the hashes and signatures are of the shards as decrypted
+    for (name, slice) in names.iter().zip(&slices) {
+        slice.recipient.encrypt_file(&dir.join(name))?;
+    }
+    for slice in target.recipients.map_or(&[][..], Recipients::slices) {
+        let shards = slices.iter().filter(|s| std::ptr::eq(**s, slice)).count();
+        status!(
+            "Encrypted {} shards of [{}] to {}",
+            shards,
+            slice.name,
+            slice.recipient
+        );
+    }
+    if let Some(sealing) = sealing {
+        let label = sealing.label();
+        let shards: Vec<_> = names.iter().map(|name| dir.join(name)).collect();
+        sealing.finish(dir, &shards)?;
+        status!(
+            "Sealed {} shards ({}); each custodian's share is beside {}",
+            names.len(),
+            label,
+            manifest_path.display()
+        );
+    }
+    if let Some(events) = target.events {
+        events.done(&manifest_path, manifest.shards.len())?;
+    }
+    let sharding = Sharding {
+        path: manifest_path,
+        manifest,
+        stripes,
+        spilled,
+        #[cfg(feature = "compress-zstd")]
+        dictionary: None,
+    };
+    Ok((chunks, sharding))
+}
+
+/// `--checkpoint` of a `--shard-size` run: the manifest of the shards
+/// written so far, saved at `path` as each one is finished, before the
+/// checkpoint moves past it.
+struct Ledger<'a> {
+    checkpointing: &'a mut Checkpointing,
+    manifest: Manifest,
+    path: &'a Path,
+    mode: u32,
+}
+
+impl<'a> Ledger<'a> {
+    /// The ledger of a run whose manifest, with no shards yet, is
+    /// `manifest`. A resumed run takes the shards its checkpoint has
+    /// passed from the manifest at `path`, each checked against what
+    /// `describe` makes of it on disk; a new one saves `manifest` there,
+    /// with `mode`, at once, over any earlier run's.
+    fn open(
+        checkpointing: &'a mut Checkpointing,
+        mut manifest: Manifest,
+        path: &'a Path,
+        mode: u32,
+        describe: &dyn Fn(usize, &Path) -> io::Result<Shard>,
+    ) -> io::Result<Self> {
+        let checkpoint = &checkpointing.checkpoint;
+        if checkpointing.resuming {
+            let shard_chunks = shard_chunks(checkpoint);
+            let done = checkpoint.progress.chunks.div_ceil(shard_chunks);
+            let cannot = |why: String| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("cannot resume from {}: {}", path.display(), why),
+                )
+            };
+            let recorded = Manifest::load(path)
+                .map_err(|e| io::Error::new(e.kind(), format!("cannot resume: {}", e)))?;
+            if recorded.run_id != manifest.run_id {
+                return Err(cannot("it is the manifest of another run".to_string()));
+            }
+            if recorded.shards.len() < done {
+                return Err(cannot(format!(
+                    "it lists {} shards, not the {} the checkpoint has passed",
+                    recorded.shards.len(),
+                    done
+                )));
+            }
+            for (n, shard) in recorded.shards.into_iter().take(done).enumerate() {
+                if describe(n, &shard.path)? != shard {
+                    return Err(cannot(format!(
+                        "shard {} ({}) is not as it was written",
+                        n + 1,
+                        shard.path.display()
+                    )));
+                }
+                manifest.shards.push(shard);
+            }
+        }
+        save_manifest(&manifest, path, mode)?;
+        Ok(Ledger {
+            checkpointing,
+            manifest,
+            path,
+            mode,
+        })
+    }
+
+    /// Puts each shard `finished` names (relative to `dir`) on disk and in
+    /// the manifest, as `describe` makes it, and moves the checkpoint past
+    /// it.
+    fn record(
+        mut self,
+        dir: &Path,
+        describe: &dyn Fn(usize, &Path) -> io::Result<Shard>,
+        finished: mpsc::Receiver<(usize, PathBuf)>,
+    ) {
+        for (n, name) in finished {
+            let checkpoint = &self.checkpointing.checkpoint;
+            let chunks = checkpoint.count.div_ceil(checkpoint.chunk_size);
+            let bytes = std::fs::metadata(dir.join(&name)).map_or(0, |m| m.len());
+            let progress = Progress {
+                chunks: ((n + 1) * shard_chunks(checkpoint)).min(chunks),
+                bytes: checkpoint.progress.bytes + bytes,
+            };
+            let (manifest, path, mode) = (&mut self.manifest, self.path, self.mode);
+            self.checkpointing.advance(progress, || {
+                let file = File::open(dir.join(&name))?;
+                file.sync_all()?;
+                manifest.shards.push(describe(n, &name)?);
+                save_manifest(manifest, path, mode)
+            });
+        }
+    }
+}
+
+/// Chunks per shard of a run checkpointed a shard at a time.
+fn shard_chunks(checkpoint: &Checkpoint) -> usize {
+    checkpoint
+        .shard_size
+        .expect("checkpointed a shard at a time")
+        / checkpoint.chunk_size
+}
+
+/// Writes `manifest` to `path` with `mode` atomically, so a crash mid-save
+/// leaves the previous one in place.
+fn save_manifest(manifest: &Manifest, path: &Path, mode: u32) -> io::Result<()> {
+    let json = serde_json::to_string_pretty(manifest).map_err(io::Error::other)?;
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let mut file = permissions::create(Path::new(&tmp), mode)?;
+    file.write_all((json + "\n").as_bytes())?;
+    file.sync_all()?;
+    std::fs::rename(&tmp, path)
+}
+
+/// The full path of `dir`, which has to exist.
+fn canonical(dir: &Path) -> io::Result<PathBuf> {
+    dir.canonicalize()
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", dir.display(), e)))
+}
+
+/// `--fallback`: chunks the primary cannot take go to the fallback.
+fn with_fallback<'a, F>(
+    args: &Args,
+    target: &Target,
+    path: &'a Path,
+    fallback: &'a Path,
+    on_chunk: F,
+) -> io::Result<(Vec<Span>, Outcome<'a>)>
+where
+    F: Fn(usize, usize) -> Span + Sync,
+{
+    // A primary that cannot even be created counts as failed at once
+    let primary = permissions::create(path, args.output_mode);
+    let spare = permissions::create(fallback, args.output_mode)?;
+    let (chunks, mut report) = target.generator.write_with_fallback(
+        target.sink,
+        primary.as_ref().ok(),
+        &spare,
+        on_chunk,
+    )?;
+    if let Err(e) = primary {
+        report.primary_error = Some(e);
+    }
+    Ok((chunks, Outcome::Failover(path, fallback, report)))
+}
+
+/// Prints how fast the output was written under --write-limit.
+pub fn report_writes(writes: &WriteReport) {
+    let mib = |bytes: f64| bytes / (1024.0 * 1024.0);
+    let peak = writes.peak_bytes_per_sec.map_or(String::new(), |peak| {
+        format!(", {:.0} MiB/s in the busiest second", mib(peak as f64))
+    });
+    status!(
+        "Writes: {:.0} MiB/s against a limit of {:.0} MiB/s{}; writes waited {:.2}s in all",
+        mib(writes.bytes_per_sec),
+        mib(writes.limit_bytes_per_sec as f64),
+        peak,
+        writes.throttled_seconds
+    );
+}
+
+/// Prints what writing the output found, after the records are out.
+pub fn report(outcome: &Outcome, target: &Target, shard_size: Option<usize>) {
+    match outcome {
+        Outcome::Mapped(None) => status!("Output generated in place through a memory map"),
+        Outcome::Mapped(Some(e)) => {
+            status!("Output not memory-mapped ({}); wrote it instead", e)
+        }
+        Outcome::Failover(primary, fallback, report) => {
+            reconciliation_report(primary, fallback, target.generator, report)
+        }
+        _ => {}
+    }
+    let buffering = target.buffer.report();
+    if buffering.bytes > 0 {
+        if buffering.calibrated {
+            status!(
+                "Output buffering: {} KiB writes, {} chunk{} per worker ahead \
+                 (from {} timed writes: {:.3} ms latency, {:.0} MiB/s, jitter {:.2}x)",
+                buffering.write_size >> 10,
+                buffering.chunks_ahead,
+                if buffering.chunks_ahead == 1 { "" } else { "s" },
+                buffering.samples,
+                buffering.latency.as_secs_f64() * 1e3,
+                buffering.throughput / (1024.0 * 1024.0),
+                buffering.jitter
+            );
+        } else {
+            status!(
+                "Output buffering: run too short to calibrate; kept {} KiB writes, \
+                 {} chunk per worker ahead",
+                buffering.write_size >> 10,
+                buffering.chunks_ahead
+            );
+        }
+    }
+    if let Outcome::Archived(index) = outcome {
+        status!(
+            "Archive (format v{}): {} bytes of output, SHA-256 {}, and {} known-answer vectors",
+            index.archive_version,
+            index.manifest.shards[0].length.unwrap_or(0),
+            index.manifest.shards[0].sha256.as_deref().unwrap_or(""),
+            index.stub.kat.vectors.len()
+        );
+    }
+    if let Outcome::Sharded(sharding) = outcome {
+        let Sharding {
+            path,
+            manifest,
+            stripes,
+            spilled,
+            ..
+        } = &**sharding;
+        status!(
+            "Sharded into {} files of up to {} records; manifest with SHA-256 per shard \
+             written to {}",
+            manifest.shards.len(),
+            shard_size.unwrap_or(0),
+            path.display()
+        );
+        let mib = |bytes: f64| bytes / (1024.0 * 1024.0);
+        for stripe in stripes {
+            status!(
+                "  {}: {} shard{}, {:.0} MiB/s",
+                stripe.dir.display(),
+                stripe.writes.shards,
+                if stripe.writes.shards == 1 { "" } else { "s" },
+                mib(stripe.bytes_per_sec)
+            );
+        }
+        if let Some(total) = striped_rate(stripes) {
+            status!(
+                "  {:.0} MiB/s over {} directories in all",
+                mib(total),
+                stripes.len()
+            );
+        }
+        if let Some((spill, report)) = spilled {
+            let dir = path.parent().unwrap_or(Path::new(""));
+            let shard_size = shard_size.unwrap_or(0);
+            shard_reconciliation(dir, spill, target.generator, shard_size, report);
+        }
+    }
+    if let Some(compressor) = target.compressor {
+        let stats = compressor.stats();
+        status!(
+            "{} level {}, one frame per chunk: {} -> {} bytes, ratio {:.3}",
+            compressor.codec(),
+            compressor.level(),
+            stats.input,
+            stats.output,
+            stats.ratio()
+        );
+    }
+    #[cfg(feature = "compress-zstd")]
+    match outcome {
+        Outcome::Dictionary(dict_path, zstd) => report_dictionary(dict_path, zstd),
+        Outcome::Sharded(sharding) => {
+            if let Some((dict_path, zstd)) = &sharding.dictionary {
+                report_dictionary(dict_path, zstd)
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Prints how `--zstd-dict`'s dictionary, saved to `dict_path`, did.
+#[cfg(feature = "compress-zstd")]
+fn report_dictionary(dict_path: &Path, zstd: &ZstdDictionary) {
+    let stats = zstd.stats();
+    status!(
+        "zstd level {} with a {}-byte trained dictionary ({}): {} -> {} bytes, ratio {:.3}",
+        ZSTD_LEVEL,
+        zstd.dictionary().len(),
+        dict_path.display(),
+        stats.input,
+        stats.output,
+        stats.ratio()
+    );
+    status!(
+        "Without the dictionary: ratio {:.3} (measured on every {}th chunk); the dictionary's gain: {:+.1}%",
+        stats.baseline_ratio(),
+        ZstdDictionary::BASELINE_EVERY,
+        (stats.ratio() / stats.baseline_ratio() - 1.0) * 100.0
+    );
+}
+
+/// Bytes per second written over all of `stripes` at once, from the start
+/// to the last directory's last write.
+pub fn striped_rate(stripes: &[Stripe]) -> Option<f64> {
+    let bytes: u64 = stripes.iter().map(|s| s.writes.bytes).sum();
+    let seconds = stripes.iter().map(|s| s.writes.seconds).fold(0.0, f64::max);
+    (!stripes.is_empty()).then(|| bytes as f64 / seconds.max(f64::MIN_POSITIVE))
+}
+
+/// The file names of `shards` shards of `--shard-size` output to `output`,
+/// and the path of their manifest: `passwords.txt` is split into
+/// `passwords-00001.txt`, ..., described by `passwords.manifest.json`, all
+/// next to where it would have been. A compressed `passwords.txt.zst` keeps
+/// both extensions: `passwords-00001.txt.zst`.
+pub fn shard_paths(output: &Path, shards: usize) -> (Vec<PathBuf>, PathBuf) {
+    let name = output.file_name().unwrap_or_default().to_string_lossy();
+    let compressed = [Codec::Zstd, Codec::Gzip].into_iter().find_map(|codec| {
+        let inner = name.strip_suffix(&format!(".{}", codec.extension()))?;
+        Path::new(inner).extension().map(|_| inner.len())
+    });
+    let end = compressed.unwrap_or(name.len());
+    let stem = Path::new(&name[..end]).file_stem().unwrap_or_default();
+    let stem = stem.to_string_lossy();
+    let extension = &name[stem.len()..];
+    let names = (1..=shards)
+        .map(|n| PathBuf::from(format!("{}-{:05}{}", stem, n, extension)))
+        .collect();
+    (
+        names,
+        output.with_file_name(format!("{}.manifest.json", stem)),
+    )
+}
+
+/// Reports the outcome of `--sink-dry-run` and removes the filler file.
+pub fn finish_dry_run(
+    output: Option<&Path>,
+    to_stdout: bool,
+    written: io::Result<Vec<Span>>,
+    duration: Duration,
+    approx_bytes: f64,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let dest = match output {
+        Some(path) if !to_stdout => path,
+        _ => Path::new("stdout"),
+    };
+    let cleanup = || {
+        if !to_stdout {
+            // Only remove what we created, i.e. not on `AlreadyExists`
+            let _ = std::fs::remove_file(dest);
+        }
+    };
+    let chunks = match written {
+        Ok(chunks) => chunks,
+        Err(e) => {
+            if e.kind() != io::ErrorKind::AlreadyExists {
+                cleanup();
+            }
+            eprintln!("Sink check FAILED for {}: {}", dest.display(), e);
+            return Err(failure::Failure::sink(e).into());
+        }
+    };
+    let bytes = match std::fs::metadata(dest) {
+        Ok(meta) if !to_stdout => meta.len() as f64,
+        _ => approx_bytes,
+    };
+    cleanup();
+
+    let records: usize = chunks.iter().map(|span| span.records).sum();
+    let secs = duration.as_secs_f64();
+    status!("Sink check OK for {}", dest.display());
+    status!(
+        "Wrote {:.0} bytes ({} records) in {:.2?}: {:.1} MiB/s, ~{:.0} records/sec",
+        bytes,
+        records,
+        duration,
+        bytes / secs / (1024.0 * 1024.0),
+        records as f64 / secs
+    );
+    if !to_stdout {
+        status!("The filler file has been removed");
+    }
+    Ok(())
+}
+
+/// Prints which shards of a `--fallback` run landed in their own files, in
+/// `dir`, and which moved to `spill`.
+fn shard_reconciliation(
+    dir: &Path,
+    spill: &Path,
+    generator: &PasswordGenerator,
+    shard_size: usize,
+    report: &ShardReport,
+) {
+    status!("\n=== Output Reconciliation ===");
+    for (n, e) in &report.errors {
+        status!("Shard {}'s own file failed: {}", n + 1, e);
+    }
+    let mut start = 0;
+    while start < report.landings.len() {
+        let landing = report.landings[start];
+        let end = report.landings[start..]
+            .iter()
+            .position(|&l| l != landing)
+            .map_or(report.landings.len(), |n| start + n);
+        let path = match landing {
+            Landing::Primary if dir.as_os_str().is_empty() => Path::new("."),
+            Landing::Primary => dir,
+            Landing::Fallback => spill,
+        };
+        let shards = match end - start {
+            1 => format!("Shard {}", end),
+            _ => format!("Shards {} to {}", start + 1, end),
+        };
+        status!(
+            "{} (records {}..{}) -> {}",
+            shards,
+            start * shard_size,
+            (end * shard_size).min(generator.count()),
+            path.display()
+        );
+        start = end;
+    }
+    if report.landings.contains(&Landing::Fallback) {
+        status!(
+            "Each shard is whole where it landed, and the manifest gives its path there; \
+             `verify` checks the run as it is"
+        );
+    } else {
+        status!(
+            "Every shard landed in its own file; {} is unused",
+            spill.display()
+        );
+    }
+    status!();
+}
+
+/// Prints which chunks of a `--fallback` run landed in which file.
+fn reconciliation_report(
+    primary: &Path,
+    fallback: &Path,
+    generator: &PasswordGenerator,
+    report: &FailoverReport,
+) {
+    status!("\n=== Output Reconciliation ===");
+    if let Some(e) = &report.primary_error {
+        status!("Primary {} failed: {}", primary.display(), e);
+    }
+    let chunk_size = generator.chunk_size();
+    let mut start = 0;
+    while start < report.landings.len() {
+        let landing = report.landings[start];
+        let end = report.landings[start..]
+            .iter()
+            .position(|&l| l != landing)
+            .map_or(report.landings.len(), |n| start + n);
+        let path = match landing {
+            Landing::Primary => primary,
+            Landing::Fallback => fallback,
+        };
+        status!(
+            "Chunks {}..{} (records {}..{}) -> {}",
+            start,
+            end,
+            start * chunk_size,
+            (end * chunk_size).min(generator.count()),
+            path.display()
+        );
+        start = end;
+    }
+    if report.landings.contains(&Landing::Fallback) {
+        if report.positioned {
+            status!(
+                "Each chunk sits at its own offset in the file it landed in; \
+                 splice the fallback's ranges into the primary to reassemble"
+            );
+        } else {
+            status!(
+                "Each file holds its chunks in order; \
+                 concatenate primary then fallback to reassemble"
+            );
+        }
+    } else {
+        status!(
+            "Every chunk landed on the primary; {} is unused",
+            fallback.display()
+        );
+    }
+    status!();
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use mass_password_gen_optimized::Format;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn manifests_are_saved_with_the_output_mode() {
+        let dir = std::env::temp_dir().join(format!("mpg_output_mode_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("pw.manifest.json");
+        let manifest = Manifest {
+            version: 1,
+            format: Format::Raw,
+            binary: false,
+            compression: None,
+            shards: Vec::new(),
+            parameters: None,
+            run_id: Some("run".to_string()),
+            host: None,
+            layout: None,
+            seal: None,
+        };
+        let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        // An earlier run's manifest, left readable by all, is replaced
+        std::fs::write(&path, "{}").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        save_manifest(&manifest, &path, 0o640).unwrap();
+        assert_eq!(mode(&path), 0o640);
+        save_manifest(&manifest, &path, permissions::DEFAULT_MODE).unwrap();
+        assert_eq!(mode(&path), 0o600);
+        assert_eq!(Manifest::load(&path).unwrap(), manifest);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
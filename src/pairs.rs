@@ -0,0 +1,302 @@
+//! `--pair-with`: each record paired with a line of an input file, in
+//! index order, for giving an existing export of accounts new passwords.
+//!
+//! Line `i` of the file, without its line ending, goes with record `i`, and
+//! the run must have as many records as the file has lines. The file is
+//! mapped rather than read into memory, with the offset of every
+//! [`EVERY`]th line kept, so the lines of any chunk are found at once and
+//! pairs are serialized in parallel like any other output.
+//!
+//! Pairs are written as CSV (`username,password`) or as LDIF changes that
+//! replace the `userPassword` of each entry under a base DN:
+//!
+//! ```text
+//! version: 1
+//!
+//! dn: uid=alice,ou=people,dc=example,dc=com
+//! changetype: modify
+//! replace: userPassword
+//! userPassword: 7hQz...
+//! -
+//! ```
+//!
+//! Raw records are written as hex, and LDIF values that cannot be written
+//! as they are (RFC 2849) are written in base64.
+
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+use crate::format::{push_base64, push_csv_field, push_text};
+use crate::mmap::ReadMapping;
+use crate::{OutputSink, PasswordGenerator};
+
+/// Lines between the offsets kept of the input.
+pub const EVERY: usize = 64;
+
+/// How pairs are written.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PairFormat {
+    /// `username,password` rows with a header
+    Csv,
+    /// LDIF changes to the entries `uid=USERNAME,BASE`
+    Ldif { base: String },
+}
+
+/// Sink pairing records with the lines of a file.
+pub struct UsernamePairs {
+    map: ReadMapping,
+    /// Offset of line `n * EVERY`, for each `n`
+    starts: Vec<usize>,
+    format: PairFormat,
+    binary: bool,
+}
+
+impl UsernamePairs {
+    /// Pairs `generator`'s records with the lines of the file at `path`,
+    /// which must have one for each record.
+    pub fn open(
+        path: &Path,
+        format: PairFormat,
+        generator: &PasswordGenerator,
+    ) -> io::Result<Self> {
+        let fail = |kind: io::ErrorKind, e: String| {
+            io::Error::new(kind, format!("{}: {}", path.display(), e))
+        };
+        let file = File::open(path).map_err(|e| fail(e.kind(), e.to_string()))?;
+        let len = file.metadata()?.len() as usize;
+        let map = ReadMapping::new(&file, len)?;
+        let bytes = map.bytes();
+        let mut starts = vec![0];
+        let (mut lines, mut start) = (0, 0);
+        let ends = bytes.iter().enumerate().filter(|(_, &b)| b == b'\n');
+        for end in ends
+            .map(|(end, _)| end)
+            .chain((len > 0 && bytes[len - 1] != b'\n').then_some(len))
+        {
+            if trim_cr(&bytes[start..end]).is_empty() {
+                return Err(fail(
+                    io::ErrorKind::InvalidData,
+                    format!("line {} is empty", lines + 1),
+                ));
+            }
+            lines += 1;
+            start = end + 1;
+            if lines % EVERY == 0 {
+                starts.push(start);
+            }
+        }
+        if lines != generator.count() {
+            return Err(fail(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} lines to pair with {} records; each record needs a line, \
+                     so pass --count {}",
+                    lines,
+                    generator.count(),
+                    lines
+                ),
+            ));
+        }
+        Ok(UsernamePairs {
+            map,
+            starts,
+            format,
+            binary: generator.is_binary(),
+        })
+    }
+
+    /// The lines from line `index` on.
+    fn lines_from(&self, index: usize) -> impl Iterator<Item = &[u8]> {
+        let from = self.starts[index / EVERY];
+        self.map.bytes()[from..]
+            .split(|&b| b == b'\n')
+            .map(trim_cr)
+            .skip(index % EVERY)
+    }
+
+    fn pair(&self, username: &[u8], record: &[u8], out: &mut Vec<u8>) {
+        match &self.format {
+            PairFormat::Csv => {
+                push_csv_field(false, username, out);
+                out.push(b',');
+                push_csv_field(self.binary, record, out);
+                out.push(b'\n');
+            }
+            PairFormat::Ldif { base } => {
+                let mut dn = b"uid=".to_vec();
+                push_dn_value(username, &mut dn);
+                dn.push(b',');
+                dn.extend_from_slice(base.as_bytes());
+                push_ldif_line(b"dn", &dn, out);
+                out.extend_from_slice(b"changetype: modify\nreplace: userPassword\n");
+                let mut password = Vec::with_capacity(record.len() * 2);
+                push_text(self.binary, record, &mut password);
+                push_ldif_line(b"userPassword", &password, out);
+                out.extend_from_slice(b"-\n\n");
+            }
+        }
+    }
+}
+
+impl OutputSink for UsernamePairs {
+    fn header(&self) -> &[u8] {
+        match self.format {
+            PairFormat::Csv => b"username,password\n",
+            PairFormat::Ldif { .. } => b"version: 1\n\n",
+        }
+    }
+
+    fn write_record(&self, index: usize, record: &[u8], out: &mut Vec<u8>) {
+        let username = self
+            .lines_from(index)
+            .next()
+            .expect("a line for each record");
+        self.pair(username, record, out);
+    }
+
+    fn record_len(&self, _: usize, _: usize) -> Option<usize> {
+        None
+    }
+
+    fn credential(&self, _index: usize, record: &[u8], out: &mut Vec<u8>) {
+        push_text(self.binary, record, out);
+    }
+
+    fn write_chunk(
+        &self,
+        generator: &PasswordGenerator,
+        first: usize,
+        data: &[u8],
+        out: &mut Vec<u8>,
+    ) {
+        let mut usernames = self.lines_from(first);
+        generator.for_each_record(data, |record| {
+            let username = usernames.next().expect("a line for each record");
+            self.pair(username, record, out);
+        });
+    }
+}
+
+fn trim_cr(line: &[u8]) -> &[u8] {
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+/// Appends `value` as the value of a DN's attribute (RFC 4514), escaped.
+fn push_dn_value(value: &[u8], out: &mut Vec<u8>) {
+    for (i, &b) in value.iter().enumerate() {
+        let edge = (i == 0 && matches!(b, b' ' | b'#')) || (i == value.len() - 1 && b == b' ');
+        if edge || matches!(b, b',' | b'+' | b'"' | b'\\' | b'<' | b'>' | b';' | b'=') {
+            out.push(b'\\');
+        }
+        out.push(b);
+    }
+}
+
+/// Appends the LDIF line `name: value`, or `name:: BASE64` when `value`
+/// is not a safe string (RFC 2849).
+fn push_ldif_line(name: &[u8], value: &[u8], out: &mut Vec<u8>) {
+    let safe = value
+        .iter()
+        .all(|&b| b.is_ascii() && !matches!(b, 0 | b'\n' | b'\r'))
+        && !matches!(value.first(), Some(b' ' | b':' | b'<'))
+        && value.last() != Some(&b' ');
+    out.extend_from_slice(name);
+    match safe {
+        true => {
+            out.extend_from_slice(b": ");
+            out.extend_from_slice(value);
+        }
+        false => {
+            out.extend_from_slice(b":: ");
+            push_base64(value, out);
+        }
+    }
+    out.push(b'\n');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Charset, Encoder};
+
+    #[test]
+    fn records_pair_with_lines_in_order() {
+        let g = PasswordGenerator::builder()
+            .key([6; 16])
+            .count(150)
+            .chunk_size(50)
+            .encoder(Encoder::new(&Charset::Alnum, 12))
+            .build()
+            .unwrap();
+        let path = std::env::temp_dir().join(format!("mpg_pairs_{}", std::process::id()));
+        let users: Vec<String> = (0..150).map(|i| format!("user{}", i)).collect();
+        // CRLF endings, and no newline after the last line
+        std::fs::write(&path, users.join("\r\n")).unwrap();
+        let pairs = UsernamePairs::open(&path, PairFormat::Csv, &g).unwrap();
+        let mut out = Vec::new();
+        g.write_formatted(&pairs, &mut out, |_, _| ()).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        let rows: Vec<&str> = text.lines().collect();
+        assert_eq!(rows.len(), 151);
+        for (i, password) in g.records().enumerate() {
+            let row = format!("user{},{}", i, String::from_utf8(password).unwrap());
+            assert_eq!(rows[i + 1], row);
+        }
+        // One record at a time finds the same lines
+        let mut one = Vec::new();
+        pairs.write_record(130, b"pw", &mut one);
+        assert_eq!(one, b"user130,pw\n");
+
+        let short = PasswordGenerator::builder()
+            .key([6; 16])
+            .count(151)
+            .build()
+            .unwrap();
+        let e = UsernamePairs::open(&path, PairFormat::Csv, &short)
+            .err()
+            .unwrap();
+        assert!(e.to_string().contains("--count 150"), "{}", e);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn ldif_escapes_what_it_must() {
+        let g = PasswordGenerator::builder()
+            .key([6; 16])
+            .count(2)
+            .build()
+            .unwrap();
+        let path = std::env::temp_dir().join(format!("mpg_pairs_ldif_{}", std::process::id()));
+        std::fs::write(&path, "smith, j\n#ops\n").unwrap();
+        let format = PairFormat::Ldif {
+            base: "ou=people,dc=example,dc=com".to_string(),
+        };
+        let pairs = UsernamePairs::open(&path, format, &g).unwrap();
+        let mut out = Vec::new();
+        pairs.write_record(0, &[0xab; 2], &mut out);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "dn: uid=smith\\, j,ou=people,dc=example,dc=com\nchangetype: modify\n\
+             replace: userPassword\nuserPassword: abab\n-\n\n"
+        );
+        let mut line = Vec::new();
+        push_ldif_line(b"userPassword", b":x", &mut line);
+        assert_eq!(line, b"userPassword:: Ong=\n");
+        let mut dn = Vec::new();
+        push_dn_value(b"#ops", &mut dn);
+        assert_eq!(dn, b"\\#ops");
+
+        std::fs::write(&path, "a\n\nb\n").unwrap();
+        let g = PasswordGenerator::builder()
+            .key([6; 16])
+            .count(3)
+            .build()
+            .unwrap();
+        let e = UsernamePairs::open(&path, PairFormat::Csv, &g)
+            .err()
+            .unwrap();
+        assert!(e.to_string().contains("line 2 is empty"), "{}", e);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,685 @@
+//! Diceware-style passphrases drawn from the EFF large wordlist, or from
+//! another [`Wordlist`].
+//!
+//! The keystream is read as 16-bit values and each word index is picked by
+//! rejection sampling, so all 7776 words are equally likely (12.9 bits per
+//! word). As with [`Encoder`](crate::Encoder), each passphrase gets a fixed
+//! keystream budget sized so that running out has probability below 2^-64.
+//!
+//! Unlike encoded passwords, passphrases vary in length, so runs using them
+//! are written out sequentially rather than at fixed offsets.
+
+use std::fmt;
+use std::path::Path;
+use std::sync::{Arc, OnceLock};
+
+use sha2::{Digest, Sha256};
+
+use crate::encoder::keystream_budget;
+use crate::format::hex;
+
+/// The EFF large wordlist (CC BY 3.0, Electronic Frontier Foundation), in its
+/// published `<dice>\t<word>` form.
+const EFF_LARGE_WORDLIST: &str = include_str!("eff_large_wordlist.txt");
+
+/// The EFF short wordlist of 1296 words for four dice (CC BY 3.0), in the
+/// same form.
+const EFF_SHORT_WORDLIST: &str = include_str!("eff_short_wordlist.txt");
+
+/// The BIP-39 wordlists (bitcoin/bips, bip-0039) for Spanish, French,
+/// Italian and Portuguese: 2048 words each, 11 bits a word, chosen so that
+/// no word is a prefix of another or easily mistaken for one. Accents are
+/// precomposed (NFC), as a keyboard types them, rather than decomposed as
+/// BIP-39 hashes them.
+const BIP39_SPANISH_WORDLIST: &str = include_str!("bip39_spanish_wordlist.txt");
+const BIP39_FRENCH_WORDLIST: &str = include_str!("bip39_french_wordlist.txt");
+const BIP39_ITALIAN_WORDLIST: &str = include_str!("bip39_italian_wordlist.txt");
+const BIP39_PORTUGUESE_WORDLIST: &str = include_str!("bip39_portuguese_wordlist.txt");
+
+/// The wordlists built in, by the names `--wordlist` knows them by.
+pub const BUILT_IN: [&str; 6] = ["eff", "eff-short", "es", "fr", "it", "pt"];
+
+/// Most words a list can hold: words are drawn with 16-bit values.
+pub const MAX_WORDS: usize = 1 << 16;
+
+/// The embedded EFF large wordlist, in dice order.
+pub fn wordlist() -> &'static [&'static str] {
+    static WORDS: OnceLock<Vec<&'static str>> = OnceLock::new();
+    WORDS.get_or_init(|| {
+        EFF_LARGE_WORDLIST
+            .lines()
+            .filter_map(|line| line.split_once('\t').map(|(_, word)| word))
+            .collect()
+    })
+}
+
+/// The words passphrases are drawn from: built in, or loaded from a file.
+///
+/// A list of any size from 2 to [`MAX_WORDS`] works, a power of two or not:
+/// rejection sampling keeps every word equally likely. A list is known by
+/// the SHA-256 of its words, one per line, which runs record so they can
+/// be reproduced with the same list.
+#[derive(Clone, Debug)]
+pub struct Wordlist {
+    name: String,
+    words: Arc<Vec<String>>,
+    sha256: String,
+}
+
+impl Wordlist {
+    /// The EFF large wordlist.
+    pub fn eff() -> Self {
+        static EFF: OnceLock<Wordlist> = OnceLock::new();
+        EFF.get_or_init(|| Self::built_in("eff", EFF_LARGE_WORDLIST))
+            .clone()
+    }
+
+    /// The list `list`, a word a line, each after its dice and a tab for
+    /// the EFF lists.
+    fn built_in(name: &str, list: &str) -> Self {
+        let words = list
+            .lines()
+            .map(|line| line.rsplit_once('\t').map_or(line, |(_, word)| word))
+            .map(str::to_string)
+            .collect();
+        Wordlist::new(name, words)
+    }
+
+    fn new(name: &str, words: Vec<String>) -> Self {
+        let mut hasher = Sha256::new();
+        for word in &words {
+            hasher.update(word.as_bytes());
+            hasher.update(b"\n");
+        }
+        let sha256 = hex(&hasher.finalize());
+        Wordlist {
+            name: name.to_string(),
+            words: Arc::new(words),
+            sha256,
+        }
+    }
+
+    /// Parses `--wordlist`: the name of a built-in list, or `file:PATH` for
+    /// one to [`load`](Self::load).
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s {
+            "eff" => Ok(Wordlist::eff()),
+            "eff-short" => Ok(Self::built_in("eff-short", EFF_SHORT_WORDLIST)),
+            "es" => Ok(Self::built_in("es", BIP39_SPANISH_WORDLIST)),
+            "fr" => Ok(Self::built_in("fr", BIP39_FRENCH_WORDLIST)),
+            "it" => Ok(Self::built_in("it", BIP39_ITALIAN_WORDLIST)),
+            "pt" => Ok(Self::built_in("pt", BIP39_PORTUGUESE_WORDLIST)),
+            _ => match s.strip_prefix("file:") {
+                Some(path) => Wordlist::load(Path::new(path)),
+                None => Err(format!(
+                    "no `{}` wordlist is built in (only {}); give the path of a vetted \
+                     list as file:PATH",
+                    s,
+                    BUILT_IN.join(", ")
+                )),
+            },
+        }
+    }
+
+    /// Reads a list of words from the UTF-8 file at `path`: one word a line,
+    /// or diceware's `<dice> <word>`, with blank lines and `#` comments
+    /// skipped. A word may not hold whitespace or control characters, and
+    /// none may repeat another, ignoring case, since repeats would make
+    /// some passphrases likelier than others.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let fail = |e: String| format!("{}: {}", path.display(), e);
+        let text = std::fs::read(path).map_err(|e| fail(e.to_string()))?;
+        let text = String::from_utf8(text).map_err(|_| fail("not UTF-8 text".to_string()))?;
+        Self::from_text(&path.display().to_string(), &text).map_err(fail)
+    }
+
+    fn from_text(name: &str, text: &str) -> Result<Self, String> {
+        let mut words = Vec::new();
+        for (n, line) in text.lines().enumerate() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let word = match fields[..] {
+                [] => continue,
+                [first, ..] if first.starts_with('#') => continue,
+                [word] => word,
+                [dice, word] if dice.bytes().all(|b| b.is_ascii_digit()) => word,
+                _ => return Err(format!("line {}: expected one word", n + 1)),
+            };
+            words.push((format!("line {}", n + 1), word));
+        }
+        Self::checked(name, words)
+    }
+
+    /// The list `name` of `words`, in order, checked as [`load`](Self::load)
+    /// checks a file's.
+    pub fn from_words(name: &str, words: &[String]) -> Result<Self, String> {
+        let words = words
+            .iter()
+            .enumerate()
+            .map(|(i, word)| (format!("word {}", i + 1), word.as_str()))
+            .collect();
+        Self::checked(name, words)
+    }
+
+    /// Checks each word, given with where it was found, and builds the list.
+    fn checked(name: &str, found: Vec<(String, &str)>) -> Result<Self, String> {
+        let mut seen = std::collections::HashMap::new();
+        let mut words = Vec::with_capacity(found.len());
+        for (at, word) in &found {
+            if word.is_empty() || word.chars().any(|c| c.is_whitespace() || c.is_control()) {
+                return Err(format!("{}: {:?} is not a word", at, word));
+            }
+            if let Some(first) = seen.insert(word.to_lowercase(), at) {
+                return Err(format!("{}: `{}` repeats {}", at, word, first));
+            }
+            words.push(word.to_string());
+        }
+        if !(2..=MAX_WORDS).contains(&words.len()) {
+            return Err(format!(
+                "{} words; a wordlist holds 2 to {}",
+                words.len(),
+                MAX_WORDS
+            ));
+        }
+        Ok(Wordlist::new(name, words))
+    }
+
+    /// The built-in name, or the path the list was loaded from.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn words(&self) -> &[String] {
+        &self.words
+    }
+
+    pub fn len(&self) -> usize {
+        self.words.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.words.is_empty()
+    }
+
+    /// Whether this is one of the lists in [`BUILT_IN`].
+    pub fn is_built_in(&self) -> bool {
+        BUILT_IN.contains(&self.name.as_str())
+    }
+
+    /// SHA-256 of the words, each followed by a newline, as hex.
+    pub fn sha256(&self) -> &str {
+        &self.sha256
+    }
+}
+
+impl PartialEq for Wordlist {
+    fn eq(&self, other: &Self) -> bool {
+        self.sha256 == other.sha256
+    }
+}
+
+/// How words are capitalized.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Capitalization {
+    /// `correct horse`: words as the list has them, which for the built-in
+    /// lists is lowercase
+    #[default]
+    Lower,
+    /// `Correct Horse`
+    Title,
+    /// `CORRECT HORSE`
+    Upper,
+}
+
+impl Capitalization {
+    /// Parses `lower`, `title` or `upper`.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s {
+            "lower" => Ok(Capitalization::Lower),
+            "title" => Ok(Capitalization::Title),
+            "upper" => Ok(Capitalization::Upper),
+            _ => Err(format!(
+                "unknown capitalization `{}` (expected lower, title or upper)",
+                s
+            )),
+        }
+    }
+
+    /// Appends `word` capitalized this way to `out`, from `len` on, and
+    /// gives the new length.
+    fn put(self, word: &str, out: &mut [u8], mut len: usize) -> usize {
+        let start = len;
+        if word.is_ascii() {
+            out[len..len + word.len()].copy_from_slice(word.as_bytes());
+            len += word.len();
+            match self {
+                Capitalization::Lower => {}
+                Capitalization::Title => out[start].make_ascii_uppercase(),
+                Capitalization::Upper => out[start..len].make_ascii_uppercase(),
+            }
+            return len;
+        }
+        for (i, c) in word.chars().enumerate() {
+            let upper = self == Capitalization::Upper || (self == Capitalization::Title && i == 0);
+            let mut put = |c: char| {
+                len += c.encode_utf8(&mut out[len..]).len();
+            };
+            match upper {
+                true => c.to_uppercase().for_each(&mut put),
+                false => put(c),
+            }
+        }
+        len
+    }
+
+    /// Bytes `word` takes capitalized this way; uppercase letters can take
+    /// more than lowercase ones outside ASCII.
+    fn len(self, word: &str) -> usize {
+        if word.is_ascii() || self == Capitalization::Lower {
+            return word.len();
+        }
+        let mut out = vec![0; word.len() * 3 + 4];
+        self.put(word, &mut out, 0)
+    }
+}
+
+impl fmt::Display for Capitalization {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Capitalization::Lower => "lower",
+            Capitalization::Title => "title",
+            Capitalization::Upper => "upper",
+        })
+    }
+}
+
+/// Most words a style that capitalizes can pick from.
+const MAX_CAPITALIZED: usize = 256;
+
+/// Symbols `--passphrase-style symbol` separates words with by default:
+/// none that CSV, JSON or a shell would need to escape.
+pub const DEFAULT_SYMBOLS: &str = "!#$%&*+-.:=?@^_~";
+
+/// Extras drawn from the keystream for sites that refuse plain
+/// passphrases, as `--passphrase-style capitalize+digit+symbol` asks.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PassphraseStyle {
+    /// Title-case each word or not by a keystream bit, with at least one
+    /// capitalized
+    pub capitalize: bool,
+    /// Digits appended to the passphrase
+    pub digits: usize,
+    /// Separate words with symbols drawn from this set, one per gap,
+    /// instead of the separator
+    pub symbols: Option<String>,
+}
+
+impl PassphraseStyle {
+    /// Parses components joined by `+`: `capitalize`, `digit` (two digits)
+    /// or `digit:N`, and `symbol` (from [`DEFAULT_SYMBOLS`]) or
+    /// `symbol:SET`, which comes last since the set may hold a `+`.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut style = PassphraseStyle::default();
+        let mut rest = s;
+        while !rest.is_empty() {
+            let (part, next) = match rest.to_ascii_lowercase().starts_with("symbol:") {
+                true => (rest, ""),
+                false => rest.split_once('+').unwrap_or((rest, "")),
+            };
+            let (name, value) = match part.split_once(':') {
+                Some((name, value)) => (name, Some(value)),
+                None => (part, None),
+            };
+            match (name.to_ascii_lowercase().as_str(), value) {
+                ("capitalize", None) if !style.capitalize => style.capitalize = true,
+                ("digit" | "digits", _) if style.digits == 0 => {
+                    style.digits = match value {
+                        None => 2,
+                        Some(n) => n
+                            .parse()
+                            .ok()
+                            .filter(|n| (1..=16).contains(n))
+                            .ok_or_else(|| format!("`{}`: 1 to 16 digits", part))?,
+                    }
+                }
+                ("symbol" | "symbols", _) if style.symbols.is_none() => {
+                    let set = value.unwrap_or(DEFAULT_SYMBOLS);
+                    check_symbols(set)?;
+                    style.symbols = Some(set.to_string());
+                }
+                _ => {
+                    return Err(format!(
+                        "`{}` is not a style (expected capitalize, digit[:N] or \
+                         symbol[:SET], each once)",
+                        part
+                    ))
+                }
+            }
+            rest = next;
+        }
+        if style == PassphraseStyle::default() {
+            return Err("an empty passphrase style".to_string());
+        }
+        Ok(style)
+    }
+
+    /// Whether the style changes nothing.
+    pub fn is_plain(&self) -> bool {
+        *self == PassphraseStyle::default()
+    }
+}
+
+/// Checks that `set` is two or more distinct printable ASCII symbols.
+fn check_symbols(set: &str) -> Result<(), String> {
+    let bytes = set.as_bytes();
+    if bytes.len() < 2 || !bytes.iter().all(|b| b.is_ascii_graphic()) {
+        return Err(format!(
+            "`{}`: a symbol set is at least 2 printable ASCII characters",
+            set
+        ));
+    }
+    if let Some(b) = bytes
+        .iter()
+        .find(|b| bytes.iter().filter(|c| c == b).count() > 1)
+    {
+        return Err(format!("`{}` repeats the symbol {:?}", set, *b as char));
+    }
+    Ok(())
+}
+
+impl fmt::Display for PassphraseStyle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = Vec::new();
+        if self.capitalize {
+            parts.push("capitalize".to_string());
+        }
+        if self.digits > 0 {
+            parts.push(format!("digit:{}", self.digits));
+        }
+        if let Some(symbols) = &self.symbols {
+            parts.push(format!("symbol:{}", symbols));
+        }
+        f.write_str(&parts.join("+"))
+    }
+}
+
+/// Turns a fixed-size slice of keystream into one passphrase.
+#[derive(Clone, Debug)]
+pub struct Passphrase {
+    wordlist: Wordlist,
+    words: usize,
+    separator: String,
+    capitalization: Capitalization,
+    digits: usize,
+    style: PassphraseStyle,
+    /// 16-bit keystream values reserved per passphrase
+    budget: usize,
+    max_len: usize,
+}
+
+impl Passphrase {
+    /// `words` words of the EFF large wordlist joined by `separator`. With
+    /// `digits > 0`, a number of that many random digits is inserted as an
+    /// extra token at a random position.
+    pub fn new(
+        words: usize,
+        separator: &str,
+        capitalization: Capitalization,
+        digits: usize,
+    ) -> Result<Self, String> {
+        Self::with_wordlist(Wordlist::eff(), words, separator, capitalization, digits)
+    }
+
+    /// Like [`new`](Self::new), with words drawn from `wordlist`.
+    pub fn with_wordlist(
+        wordlist: Wordlist,
+        words: usize,
+        separator: &str,
+        capitalization: Capitalization,
+        digits: usize,
+    ) -> Result<Self, String> {
+        if words == 0 {
+            return Err("a passphrase needs at least one word".into());
+        }
+        if separator.chars().any(|c| c.is_control()) {
+            return Err("the separator cannot contain control characters".into());
+        }
+        let passphrase = Passphrase {
+            wordlist,
+            words,
+            separator: separator.to_string(),
+            capitalization,
+            digits,
+            style: PassphraseStyle::default(),
+            budget: 0,
+            max_len: 0,
+        };
+        Ok(passphrase.sized())
+    }
+
+    /// This passphrase with the extras of `style`, which replaces the
+    /// separator if it draws symbols and needs lowercase capitalization if
+    /// it capitalizes.
+    pub fn styled(mut self, style: PassphraseStyle) -> Result<Self, String> {
+        if style.capitalize && self.capitalization != Capitalization::Lower {
+            return Err(format!(
+                "the style capitalizes words itself, so they cannot also be {} case",
+                self.capitalization
+            ));
+        }
+        if style.capitalize && self.words > MAX_CAPITALIZED {
+            return Err(format!(
+                "the style capitalizes passphrases of up to {} words",
+                MAX_CAPITALIZED
+            ));
+        }
+        if let Some(symbols) = &style.symbols {
+            check_symbols(symbols)?;
+        }
+        self.style = style;
+        Ok(self.sized())
+    }
+
+    /// Sets the budget and the longest length for the settings.
+    fn sized(mut self) -> Self {
+        let style = &self.style;
+        let groups = self.words.div_ceil(16);
+        let symbols = style.symbols.as_ref().map_or(0, |s| s.len());
+        let tokens = self.tokens();
+        let draws = self.words
+            + if self.digits > 0 { self.digits + 1 } else { 0 }
+            // A second try at the capitalization, for the mask of none
+            + if style.capitalize { 2 * groups } else { 0 }
+            + if symbols > 0 { tokens - 1 } else { 0 }
+            + style.digits;
+        // Size the budget as if every draw were as wasteful as the worst one
+        let p = [self.wordlist.len(), 10, self.words + 1, symbols.max(1)]
+            .into_iter()
+            .map(|n| limit(n) as f64 / 65536.0)
+            .fold(1.0, f64::min);
+        let (capitalization, capitalize) = (self.capitalization, style.capitalize);
+        let longest = self
+            .wordlist
+            .words()
+            .iter()
+            .map(|w| match capitalize {
+                true => Capitalization::Title.len(w).max(w.len()),
+                false => capitalization.len(w),
+            })
+            .max()
+            .unwrap_or(0);
+        self.budget = keystream_budget(draws, p);
+        self.max_len =
+            self.words * longest + self.digits + (tokens - 1) * self.gap_len() + style.digits;
+        self
+    }
+
+    /// Words, and the inserted number if there is one.
+    fn tokens(&self) -> usize {
+        self.words + usize::from(self.digits > 0)
+    }
+
+    /// Bytes between two tokens.
+    fn gap_len(&self) -> usize {
+        match self.style.symbols {
+            Some(_) => 1,
+            None => self.separator.len(),
+        }
+    }
+
+    pub fn words(&self) -> usize {
+        self.words
+    }
+
+    /// The list words are drawn from.
+    pub fn wordlist(&self) -> &Wordlist {
+        &self.wordlist
+    }
+
+    pub fn separator(&self) -> &str {
+        &self.separator
+    }
+
+    pub fn capitalization(&self) -> Capitalization {
+        self.capitalization
+    }
+
+    pub fn digits(&self) -> usize {
+        self.digits
+    }
+
+    pub fn style(&self) -> &PassphraseStyle {
+        &self.style
+    }
+
+    /// Keystream bytes consumed per passphrase.
+    pub fn budget(&self) -> usize {
+        self.budget * 2
+    }
+
+    /// Length in bytes of the longest passphrase this can produce.
+    pub fn max_len(&self) -> usize {
+        self.max_len
+    }
+
+    /// Average passphrase length in bytes, over all possible passphrases.
+    pub fn mean_len(&self) -> f64 {
+        let list = self.wordlist.words();
+        // A capitalized word is as long as a lowercase one in ASCII
+        let letters: usize = list.iter().map(|w| self.capitalization.len(w)).sum();
+        self.words as f64 * letters as f64 / list.len() as f64
+            + (self.digits + (self.tokens() - 1) * self.gap_len() + self.style.digits) as f64
+    }
+
+    /// Entropy of one passphrase in bits. A style that capitalizes adds
+    /// the choice of words to capitalize, for lists of lowercase words.
+    pub fn entropy_bits(&self) -> f64 {
+        let mut bits = self.words as f64 * (self.wordlist.len() as f64).log2();
+        if self.digits > 0 {
+            bits += self.digits as f64 * 10f64.log2() + ((self.words + 1) as f64).log2();
+        }
+        if self.style.capitalize {
+            // Any set of words but the empty one
+            bits += ((self.words as f64).exp2() - 1.0).log2();
+        }
+        if let Some(symbols) = &self.style.symbols {
+            bits += (self.tokens() - 1) as f64 * (symbols.len() as f64).log2();
+        }
+        bits + self.style.digits as f64 * 10f64.log2()
+    }
+
+    /// Writes the passphrase for `stream` (exactly `budget()` bytes) to the
+    /// front of `out` (at least `max_len()` bytes) and returns its length.
+    pub fn encode(&self, stream: &[u8], out: &mut [u8]) -> usize {
+        let list = self.wordlist.words();
+        let mut draws = Draws {
+            stream,
+            next: 0,
+            fallback: 0,
+        };
+        // Where the number goes among the words, if there is one
+        let slot = (self.digits > 0).then(|| draws.below(self.words + 1));
+        // Bit i says whether word i is capitalized; drawn again if none is,
+        // and after two tries (or from the all-zero keystream of a filler
+        // run) the first word is
+        let mut mask = [0u16; MAX_CAPITALIZED / 16];
+        let groups = self.words.div_ceil(16) * usize::from(self.style.capitalize);
+        let groups = &mut mask[..groups];
+        // Bits past the last word are not words
+        let spare = (16 * groups.len()).saturating_sub(self.words);
+        for _ in 0..2 {
+            if groups.iter().any(|&g| g != 0) {
+                break;
+            }
+            for group in groups.iter_mut() {
+                *group = draws.below(1 << 16) as u16;
+            }
+            if let Some(last) = groups.last_mut() {
+                *last &= u16::MAX >> spare;
+            }
+        }
+        if !groups.is_empty() && groups.iter().all(|&g| g == 0) {
+            groups[0] = 1;
+        }
+        let mut len = 0;
+        let put = |out: &mut [u8], len: &mut usize, bytes: &[u8]| {
+            out[*len..*len + bytes.len()].copy_from_slice(bytes);
+            *len += bytes.len();
+        };
+        let symbols = self.style.symbols.as_ref().map(|s| s.as_bytes());
+        let (tokens, mut word_index) = (self.tokens(), 0);
+        for t in 0..tokens {
+            if t > 0 {
+                match symbols {
+                    Some(symbols) => put(out, &mut len, &[symbols[draws.below(symbols.len())]]),
+                    None => put(out, &mut len, self.separator.as_bytes()),
+                }
+            }
+            if slot == Some(t) {
+                for _ in 0..self.digits {
+                    put(out, &mut len, &[b'0' + draws.below(10) as u8]);
+                }
+                continue;
+            }
+            let word = &list[draws.below(list.len())];
+            let capitalization = match mask[word_index / 16] >> (word_index % 16) & 1 {
+                1 => Capitalization::Title,
+                _ => self.capitalization,
+            };
+            len = capitalization.put(word, out, len);
+            word_index += 1;
+        }
+        for _ in 0..self.style.digits {
+            put(out, &mut len, &[b'0' + draws.below(10) as u8]);
+        }
+        len
+    }
+}
+
+/// 16-bit values at or above this are rejected when drawing from `n` choices.
+fn limit(n: usize) -> u32 {
+    65536 - 65536 % n as u32
+}
+
+/// Uniform draws from a passphrase's keystream budget.
+struct Draws<'a> {
+    stream: &'a [u8],
+    next: usize,
+    fallback: usize,
+}
+
+impl Draws<'_> {
+    fn below(&mut self, n: usize) -> usize {
+        while self.next + 2 <= self.stream.len() {
+            let v = u16::from_le_bytes([self.stream[self.next], self.stream[self.next + 1]]);
+            self.next += 2;
+            if (v as u32) < limit(n) {
+                return v as usize % n;
+            }
+        }
+        // Budget exhausted (< 2^-64 per passphrase): reuse the values with a
+        // modular mapping, like the encoder does
+        let i = self.fallback * 2 % self.stream.len();
+        self.fallback += 1;
+        u16::from_le_bytes([self.stream[i], self.stream[i + 1]]) as usize % n
+    }
+}
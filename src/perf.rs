@@ -0,0 +1,142 @@
+//! `--perf-mode`: worker placement and chunk sizing for big machines.
+//!
+//! One shared output buffer and megabyte-sized chunks leave each worker
+//! streaming through memory that may sit on another socket. Performance
+//! mode instead sizes chunks so that a chunk's keystream and records stay in
+//! the worker's L2 cache, can pin each worker to its own core, and can have
+//! every worker generate into a buffer of its own, first touched (and so
+//! placed) by the core that uses it.
+
+use std::fs;
+
+use mass_password_gen_optimized::PasswordGenerator;
+use rayon::prelude::*;
+
+/// Fraction of the L2 cache a chunk's working set may take; the rest is
+/// left for the cipher state, the stack and the neighbours.
+const L2_SHARE: f64 = 0.5;
+
+/// Assumed when the cache sizes cannot be read.
+const DEFAULT_L2: usize = 1 << 20;
+
+/// Bounds on a cache-sized chunk, in records.
+const MIN_CHUNK: usize = 1024;
+const MAX_CHUNK: usize = 1 << 20;
+
+/// Per-core caches, from the first CPU.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Caches {
+    pub l2: Option<usize>,
+    pub l3: Option<usize>,
+}
+
+impl Caches {
+    /// Reads the cache sizes the OS reports (Linux only for now).
+    pub fn detect() -> Self {
+        let mut caches = Caches::default();
+        let Ok(entries) = fs::read_dir("/sys/devices/system/cpu/cpu0/cache") else {
+            return caches;
+        };
+        for entry in entries.flatten() {
+            let read = |name: &str| fs::read_to_string(entry.path().join(name)).ok();
+            let (Some(level), Some(kind), Some(size)) = (read("level"), read("type"), read("size"))
+            else {
+                continue;
+            };
+            if kind.trim() == "Instruction" {
+                continue;
+            }
+            let size = parse_size(size.trim());
+            match level.trim() {
+                "2" => caches.l2 = size,
+                "3" => caches.l3 = size,
+                _ => {}
+            }
+        }
+        caches
+    }
+
+    /// Records per chunk such that `bytes_per_record` bytes of keystream and
+    /// output each fit the L2 cache (rounded down to a multiple of
+    /// [`MIN_CHUNK`]).
+    pub fn chunk_size(&self, bytes_per_record: usize) -> usize {
+        let budget = self.l2.unwrap_or(DEFAULT_L2) as f64 * L2_SHARE;
+        let records = (budget / bytes_per_record.max(1) as f64) as usize;
+        (records / MIN_CHUNK * MIN_CHUNK).clamp(MIN_CHUNK, MAX_CHUNK)
+    }
+}
+
+/// Parses sysfs cache sizes such as `48K` or `2048K`.
+fn parse_size(s: &str) -> Option<usize> {
+    let (digits, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, ""),
+    };
+    let shift = match unit {
+        "" => 0,
+        "K" => 10,
+        "M" => 20,
+        "G" => 30,
+        _ => return None,
+    };
+    Some(digits.parse::<usize>().ok()? << shift)
+}
+
+/// Pins the calling thread, worker `index` of the pool, to a core of its
+/// own (round robin once there are more workers than cores). Returns
+/// whether it could.
+pub fn pin(index: usize) -> bool {
+    match core_affinity::get_core_ids() {
+        Some(cores) if !cores.is_empty() => {
+            core_affinity::set_for_current(cores[index % cores.len()])
+        }
+        _ => false,
+    }
+}
+
+/// Generates the whole run with each worker filling a buffer of its own,
+/// reused from chunk to chunk, and throws the records away.
+/// `on_chunk(chunk_idx, records)` runs as each chunk completes; its results
+/// come back in chunk order.
+pub fn generate_locally<T, F>(generator: &PasswordGenerator, on_chunk: F) -> Vec<T>
+where
+    T: Send,
+    F: Fn(usize, usize) -> T + Sync,
+{
+    let width = generator.record_width();
+    (0..generator.num_chunks())
+        .into_par_iter()
+        .map_init(
+            || (Vec::new(), Vec::new()),
+            |(scratch, out), chunk_idx| {
+                let len = generator.chunk_len(chunk_idx);
+                out.clear();
+                out.resize(len * width, 0);
+                generator.fill_chunk(chunk_idx, scratch, out);
+                on_chunk(chunk_idx, len)
+            },
+        )
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunks_fit_the_cache() {
+        assert_eq!(parse_size("48K"), Some(48 << 10));
+        assert_eq!(parse_size("2M"), Some(2 << 20));
+        assert_eq!(parse_size("huge"), None);
+        let caches = Caches {
+            l2: Some(2 << 20),
+            l3: None,
+        };
+        // 1 MiB of a 2 MiB cache at 32 bytes a record
+        assert_eq!(caches.chunk_size(32), 32 * 1024);
+        assert_eq!(caches.chunk_size(45), 22 * 1024);
+        assert_eq!(caches.chunk_size(1 << 20), MIN_CHUNK);
+        assert_eq!(caches.chunk_size(1), MAX_CHUNK);
+        assert_eq!(Caches::default().chunk_size(1), 512 * 1024);
+    }
+}
@@ -0,0 +1,126 @@
+//! Who may read what the run writes: `--output-mode` and
+//! `--insecure-output`.
+//!
+//! Output files are created with `--output-mode` (0600 unless given), and
+//! a file that already exists is set to it too, so neither the umask nor an
+//! earlier, looser file decides. Devices and pipes are left as they are.
+//! A directory every user may write to lets any of them replace the output
+//! or read it before the mode is set, so the run refuses one unless
+//! `--insecure-output` accepts the risk. On systems other than Unix, files
+//! get the system's default permissions.
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::Path;
+
+/// `--output-mode` unless given: readable and writable by the owner only.
+pub const DEFAULT_MODE: u32 = 0o600;
+
+/// Parses an octal mode such as `600` or `0640`.
+pub fn parse_mode(s: &str) -> Result<u32, String> {
+    let mode = u32::from_str_radix(s.trim_start_matches("0o"), 8)
+        .map_err(|_| format!("invalid mode `{}` (expected octal, like 0600)", s))?;
+    if mode > 0o777 {
+        return Err(format!(
+            "invalid mode `{}`: only permission bits, up to 0777",
+            s
+        ));
+    }
+    Ok(mode)
+}
+
+/// Creates (or truncates) `path` with `mode`.
+pub fn create(path: &Path, mode: u32) -> io::Result<File> {
+    open(
+        File::options().write(true).create(true).truncate(true),
+        path,
+        mode,
+    )
+}
+
+/// Opens `path` with `options`, which may create it, and gives a regular
+/// file `mode` whether it was created or already there.
+pub fn open(options: &mut OpenOptions, path: &Path, mode: u32) -> io::Result<File> {
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(options, mode);
+    let file = options.open(path)?;
+    #[cfg(unix)]
+    if file.metadata()?.is_file() {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(std::fs::Permissions::from_mode(mode))?;
+    }
+    #[cfg(not(unix))]
+    let _ = mode;
+    Ok(file)
+}
+
+/// The mode `path` has, to give a file made from it; [`DEFAULT_MODE`] on
+/// systems other than Unix.
+pub fn mode_of(path: &Path) -> io::Result<u32> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        Ok(std::fs::metadata(path)?.permissions().mode() & 0o777)
+    }
+    #[cfg(not(unix))]
+    {
+        std::fs::metadata(path)?;
+        Ok(DEFAULT_MODE)
+    }
+}
+
+/// Refuses `path` if the directory it goes in is writable by every user.
+pub fn check_directory(path: &Path) -> Result<(), String> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let meta = std::fs::metadata(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+        if meta.permissions().mode() & 0o002 != 0 {
+            return Err(format!(
+                "{} is in {}, which every user can write to, so another user could \
+                 replace or read it; write it somewhere private, or pass \
+                 --insecure-output to accept that",
+                path.display(),
+                dir.display()
+            ));
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn outputs_get_the_mode_and_shared_directories_are_refused() {
+        assert_eq!(parse_mode("600"), Ok(0o600));
+        assert_eq!(parse_mode("0640"), Ok(0o640));
+        assert!(parse_mode("0800").is_err() && parse_mode("1777").is_err());
+
+        let dir = std::env::temp_dir().join(format!("mpg_permissions_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o700)).unwrap();
+        let path = dir.join("out");
+        let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        create(&path, DEFAULT_MODE).unwrap();
+        assert_eq!(mode(&path), 0o600);
+        // An existing file is not left as it was
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o666)).unwrap();
+        create(&path, 0o640).unwrap();
+        assert_eq!(mode(&path), 0o640);
+
+        assert!(check_directory(&path).is_ok());
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o1777)).unwrap();
+        let refused = check_directory(&path).unwrap_err();
+        assert!(refused.contains("--insecure-output"), "{}", refused);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,183 @@
+//! Numeric PINs and the weak patterns they can be screened for.
+//!
+//! A PIN is a password over the ten digits, 4 to 12 of them, sampled like any
+//! other charset. Weak patterns are excluded by the policy machinery: they
+//! are a policy's `exclude` setting, so a PIN matching one is rejected and
+//! the next candidate drawn, and the survivors stay equally likely.
+
+use std::fmt;
+
+use crate::encoder::DIGITS;
+use crate::{Charset, Encoder};
+
+/// Shortest PIN generated.
+pub const MIN_DIGITS: usize = 4;
+
+/// Longest PIN generated.
+pub const MAX_DIGITS: usize = 12;
+
+/// Frequently chosen PINs, most common first, one per line.
+const COMMON_PINS: &str = include_str!("common_pins.txt");
+
+/// A pattern that makes a PIN (or any password) easy to guess.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WeakPattern {
+    /// One short block repeated throughout: 1111, 1212, 123123
+    Repeats,
+    /// Consecutive ascending or descending digits: 1234, 9876
+    Sequences,
+    /// Starting or ending with a year from 1900 to 2099: 1987, 198704
+    Years,
+    /// Among the first `n` entries of the embedded list of common PINs
+    Common(usize),
+}
+
+impl WeakPattern {
+    /// Parses `repeats`, `sequences`, `years` or `common:N`.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s {
+            "repeats" => Ok(WeakPattern::Repeats),
+            "sequences" => Ok(WeakPattern::Sequences),
+            "years" => Ok(WeakPattern::Years),
+            _ => {
+                let n = s.strip_prefix("common:").ok_or_else(|| {
+                    format!(
+                        "unknown pattern `{}` (expected repeats, sequences, years or common:N)",
+                        s
+                    )
+                })?;
+                let available = common_pins().count();
+                match n.parse::<usize>() {
+                    Ok(n) if (1..=available).contains(&n) => Ok(WeakPattern::Common(n)),
+                    _ => Err(format!(
+                        "common:N takes N from 1 to {}, the PINs listed",
+                        available
+                    )),
+                }
+            }
+        }
+    }
+
+    /// Whether `pin` shows the pattern.
+    pub fn matches(&self, pin: &[u8]) -> bool {
+        match *self {
+            WeakPattern::Repeats => (1..=pin.len() / 2).any(|block| {
+                pin.len().is_multiple_of(block) && pin.chunks(block).all(|c| c == &pin[..block])
+            }),
+            WeakPattern::Sequences => {
+                pin.len() > 1
+                    && [1i16, -1]
+                        .into_iter()
+                        .any(|step| pin.windows(2).all(|w| w[1] as i16 - w[0] as i16 == step))
+            }
+            WeakPattern::Years => {
+                let year = |digits: &[u8]| {
+                    std::str::from_utf8(digits)
+                        .ok()
+                        .and_then(|s| s.parse::<u16>().ok())
+                        .is_some_and(|y| (1900..=2099).contains(&y))
+                };
+                pin.len() >= 4 && (year(&pin[..4]) || year(&pin[pin.len() - 4..]))
+            }
+            WeakPattern::Common(n) => common_pins().take(n).any(|common| common == pin),
+        }
+    }
+}
+
+impl fmt::Display for WeakPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WeakPattern::Repeats => f.write_str("repeats"),
+            WeakPattern::Sequences => f.write_str("sequences"),
+            WeakPattern::Years => f.write_str("years"),
+            WeakPattern::Common(n) => write!(f, "common:{}", n),
+        }
+    }
+}
+
+/// An encoder for PINs of `digits` digits.
+pub fn encoder(digits: usize) -> Result<Encoder, String> {
+    if !(MIN_DIGITS..=MAX_DIGITS).contains(&digits) {
+        return Err(format!(
+            "PINs have {} to {} digits, not {}",
+            MIN_DIGITS, MAX_DIGITS, digits
+        ));
+    }
+    Ok(Encoder::new(&Charset::Custom(DIGITS.to_vec()), digits))
+}
+
+fn common_pins() -> impl Iterator<Item = &'static [u8]> + Clone {
+    COMMON_PINS.lines().map(str::as_bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{PasswordGenerator, PasswordPolicy};
+
+    #[test]
+    fn weak_pins_are_screened_out() {
+        let cases: [(WeakPattern, &[&str], &[&str]); 4] = [
+            (
+                WeakPattern::Repeats,
+                &["1111", "1212", "123123", "000000"],
+                &["1123", "12312", "4096"],
+            ),
+            (
+                WeakPattern::Sequences,
+                &["1234", "9876", "0123456789", "4321"],
+                &["1235", "0987", "2468"],
+            ),
+            (
+                WeakPattern::Years,
+                &["1987", "2024", "198704", "04122001"],
+                &["1899", "2100", "819870"],
+            ),
+            (
+                WeakPattern::Common(20),
+                &["1234", "1010", "6969"],
+                &["2580", "8351"],
+            ),
+        ];
+        for (pattern, weak, fine) in cases {
+            assert_eq!(WeakPattern::parse(&pattern.to_string()), Ok(pattern));
+            for pin in weak {
+                assert!(pattern.matches(pin.as_bytes()), "{} {}", pattern, pin);
+            }
+            for pin in fine {
+                assert!(!pattern.matches(pin.as_bytes()), "{} {}", pattern, pin);
+            }
+        }
+        assert!(WeakPattern::parse("common:0").is_err());
+        assert!(WeakPattern::parse("common:100000").is_err());
+        assert!(WeakPattern::parse("dates").is_err());
+        assert!(encoder(3).is_err() && encoder(13).is_err());
+
+        let exclude = vec![
+            WeakPattern::Repeats,
+            WeakPattern::Sequences,
+            WeakPattern::Years,
+            WeakPattern::Common(84),
+        ];
+        let g = PasswordGenerator::builder()
+            .key([4; 16])
+            .count(20_000)
+            .chunk_size(1000)
+            .encoder(encoder(4).unwrap())
+            .policy(PasswordPolicy {
+                exclude: exclude.clone(),
+                ..Default::default()
+            })
+            .build()
+            .unwrap();
+        let mut seen = vec![false; 10_000];
+        for pin in g.records() {
+            assert_eq!(pin.len(), 4);
+            assert!(pin.iter().all(u8::is_ascii_digit));
+            assert!(!exclude.iter().any(|p| p.matches(&pin)));
+            seen[std::str::from_utf8(&pin).unwrap().parse::<usize>().unwrap()] = true;
+        }
+        // Nearly every allowed PIN turns up in twice as many draws
+        assert!(seen.iter().filter(|&&s| s).count() > 8000);
+    }
+}
@@ -0,0 +1,280 @@
+//! Run keys from an HSM or token, for `--key pkcs11:...`.
+//!
+//! The URI is the RFC 7512 form, of which `object` (the key's label),
+//! `token` (the token's label), `slot-id`, and the query attributes
+//! `module-path` (the vendor's PKCS#11 library, loaded at run time) and
+//! `pin-source` (a file holding the user PIN) are understood:
+//!
+//! ```text
+//! pkcs11:token=ci;object=run-master?module-path=/usr/lib/softhsm/libsofthsm2.so
+//! ```
+//!
+//! The key never leaves the token. It must be a secret key the token can
+//! use for SHA256-HMAC (a generic secret, say), and the run's key is the
+//! 256-bit HMAC of [`MESSAGE`] under it, so the same stored key always
+//! gives the same run key. Without `pin-source` the PIN is asked for at
+//! the terminal. Builds without the `pkcs11` feature still take the URI
+//! but cannot load the key, and say so.
+
+// Without the feature nothing signs the message
+#![cfg_attr(not(feature = "pkcs11"), allow(dead_code))]
+
+use mass_password_gen_optimized::Key;
+use std::io;
+use std::path::PathBuf;
+
+/// What the token's key signs to give the run's key.
+pub const MESSAGE: &[u8] = b"mass_password_gen run key";
+
+/// Where a key is: the part of a `pkcs11:` URI after the scheme.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Locator {
+    /// The PKCS#11 library
+    pub module: PathBuf,
+    /// The token's label
+    pub token: Option<String>,
+    pub slot: Option<u64>,
+    /// The key's label
+    pub object: String,
+    /// A file holding the PIN
+    pub pin_source: Option<PathBuf>,
+}
+
+impl Locator {
+    /// Parses `<path attributes>?<query attributes>`, as after `pkcs11:`.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let (path, query) = s.split_once('?').unwrap_or((s, ""));
+        let (mut token, mut slot, mut object) = (None, None, None);
+        for attribute in path.split(';').filter(|a| !a.is_empty()) {
+            let (name, value) = split(attribute)?;
+            match name {
+                "token" => token = Some(value),
+                "object" => object = Some(value),
+                "slot-id" => {
+                    slot = Some(
+                        value
+                            .parse()
+                            .map_err(|_| format!("`{}` is not a slot-id", value))?,
+                    )
+                }
+                _ => return Err(format!("pkcs11: `{}` is not supported", name)),
+            }
+        }
+        let (mut module, mut pin_source) = (None, None);
+        for attribute in query.split('&').filter(|a| !a.is_empty()) {
+            let (name, value) = split(attribute)?;
+            match name {
+                "module-path" => module = Some(PathBuf::from(value)),
+                // A file: URI or a plain path
+                "pin-source" => {
+                    let path = value.strip_prefix("file:").unwrap_or(&value);
+                    pin_source = Some(PathBuf::from(path))
+                }
+                _ => return Err(format!("pkcs11: `{}` is not supported", name)),
+            }
+        }
+        Ok(Locator {
+            module: module.ok_or("pkcs11: needs ?module-path=<library>")?,
+            token,
+            slot,
+            object: object.ok_or("pkcs11: needs object=<key label>")?,
+            pin_source,
+        })
+    }
+
+    /// The key and token, for messages.
+    pub fn describe(&self) -> String {
+        match (&self.token, self.slot) {
+            (Some(token), _) => format!("PKCS#11 key `{}` on token `{}`", self.object, token),
+            (None, Some(slot)) => format!("PKCS#11 key `{}` in slot {}", self.object, slot),
+            (None, None) => format!("PKCS#11 key `{}`", self.object),
+        }
+    }
+}
+
+/// `name=value`, the value percent-decoded.
+fn split(attribute: &str) -> Result<(&str, String), String> {
+    let (name, value) = attribute
+        .split_once('=')
+        .ok_or_else(|| format!("pkcs11: expected name=value, got `{}`", attribute))?;
+    Ok((name, decode(value)?))
+}
+
+fn decode(s: &str) -> Result<String, String> {
+    let mut out = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        if b != b'%' {
+            out.push(b);
+            continue;
+        }
+        let digits = [bytes.next(), bytes.next()];
+        let byte = match digits {
+            [Some(hi), Some(lo)] => std::str::from_utf8(&[hi, lo])
+                .ok()
+                .and_then(|h| u8::from_str_radix(h, 16).ok()),
+            _ => None,
+        };
+        out.push(byte.ok_or_else(|| format!("pkcs11: bad %-escape in `{}`", s))?);
+    }
+    String::from_utf8(out).map_err(|_| format!("pkcs11: `{}` is not UTF-8", s))
+}
+
+/// The PIN, from `pin-source` or the terminal.
+#[cfg(feature = "pkcs11")]
+fn pin(locator: &Locator) -> io::Result<String> {
+    match &locator.pin_source {
+        Some(path) => Ok(std::fs::read_to_string(path)?
+            .trim_end_matches(['\r', '\n'])
+            .to_string()),
+        None => rpassword::prompt_password(format!("PIN for {}: ", locator.describe())),
+    }
+}
+
+/// Loads the module, logs in to the token and has the key sign
+/// [`MESSAGE`].
+#[cfg(feature = "pkcs11")]
+pub fn load(locator: &Locator) -> io::Result<Key> {
+    use cryptoki::context::{CInitializeArgs, Pkcs11};
+    use cryptoki::mechanism::Mechanism;
+    use cryptoki::object::{Attribute, ObjectClass};
+    use cryptoki::session::UserType;
+    use cryptoki::types::AuthPin;
+
+    let fail = |what: &str, e: cryptoki::error::Error| io::Error::other(format!("{}: {}", what, e));
+    let context = Pkcs11::new(&locator.module)
+        .map_err(|e| fail(&format!("cannot load {}", locator.module.display()), e))?;
+    context
+        .initialize(CInitializeArgs::OsThreads)
+        .map_err(|e| fail("cannot initialize the module", e))?;
+    let mut slots = Vec::new();
+    for slot in context
+        .get_slots_with_token()
+        .map_err(|e| fail("cannot list tokens", e))?
+    {
+        let info = context
+            .get_token_info(slot)
+            .map_err(|e| fail("cannot read a token", e))?;
+        let wanted = locator.slot.is_none_or(|id| id == slot.id())
+            && locator.token.as_deref().is_none_or(|t| t == info.label());
+        if wanted {
+            slots.push(slot);
+        }
+    }
+    let slot = match slots[..] {
+        [slot] => slot,
+        [] => {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "no such token in the module",
+            ))
+        }
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} tokens match; name one with token= or slot-id=",
+                    slots.len()
+                ),
+            ))
+        }
+    };
+    let session = context
+        .open_ro_session(slot)
+        .map_err(|e| fail("cannot open a session", e))?;
+    session
+        .login(UserType::User, Some(&AuthPin::new(pin(locator)?)))
+        .map_err(|e| fail("cannot log in", e))?;
+    let objects = session
+        .find_objects(&[
+            Attribute::Class(ObjectClass::SECRET_KEY),
+            Attribute::Label(locator.object.as_bytes().to_vec()),
+        ])
+        .map_err(|e| fail("cannot search the token", e))?;
+    let [object] = objects[..] else {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!(
+                "{} secret keys are labelled `{}`, not one",
+                objects.len(),
+                locator.object
+            ),
+        ));
+    };
+    let mac = session
+        .sign(&Mechanism::Sha256Hmac, object, MESSAGE)
+        .map_err(|e| fail("the key cannot be used for SHA256-HMAC", e))?;
+    let mac: [u8; 32] = mac.try_into().map_err(|mac: Vec<u8>| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("the token gave a {}-byte HMAC", mac.len()),
+        )
+    })?;
+    Ok(Key::from(mac))
+}
+
+#[cfg(not(feature = "pkcs11"))]
+pub fn load(_: &Locator) -> io::Result<Key> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "this build cannot use PKCS#11 tokens (built without the `pkcs11` feature)",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uris_name_the_module_token_and_key() {
+        assert_eq!(
+            Locator::parse(
+                "token=ci%20hsm;object=run-master?module-path=/usr/lib/p11.so\
+                 &pin-source=file:/run/pin"
+            ),
+            Ok(Locator {
+                module: "/usr/lib/p11.so".into(),
+                token: Some("ci hsm".into()),
+                slot: None,
+                object: "run-master".into(),
+                pin_source: Some("/run/pin".into()),
+            })
+        );
+        let slot = Locator::parse("slot-id=3;object=k?module-path=p11.so").unwrap();
+        assert_eq!(slot.describe(), "PKCS#11 key `k` in slot 3");
+        assert_eq!((slot.slot, slot.token), (Some(3), None));
+
+        for (bad, error) in [
+            ("object=k", "module-path"),
+            ("token=ci?module-path=p11.so", "object="),
+            (
+                "object=k;id=%01?module-path=p11.so",
+                "`id` is not supported",
+            ),
+            ("object=k?module-path=p11.so&pin-value=1234", "`pin-value`"),
+            ("object=k;slot-id=x?module-path=p11.so", "slot-id"),
+            ("object=%zz?module-path=p11.so", "%-escape"),
+            ("object?module-path=p11.so", "name=value"),
+        ] {
+            let e = Locator::parse(bad).unwrap_err();
+            assert!(e.contains(error), "{:?}: {}", bad, e);
+        }
+    }
+
+    #[cfg(feature = "pkcs11")]
+    #[test]
+    fn missing_modules_are_named() {
+        let locator = Locator::parse("object=k?module-path=/nonexistent/p11.so").unwrap();
+        let e = load(&locator).unwrap_err();
+        assert!(e.to_string().contains("/nonexistent/p11.so"), "{}", e);
+    }
+
+    #[cfg(not(feature = "pkcs11"))]
+    #[test]
+    fn builds_without_the_feature_cannot_load_keys() {
+        let locator = Locator::parse("object=k?module-path=p11.so").unwrap();
+        let e = load(&locator).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::Unsupported);
+        assert!(e.to_string().contains("`pkcs11` feature"), "{}", e);
+    }
+}
@@ -0,0 +1,352 @@
+//! Password policies: required character classes, length ranges, repeat
+//! limits, forbidden substrings and excluded weak patterns.
+//!
+//! Compliant passwords are made by rejection: each record's candidate is
+//! encoded from the keystream as usual, and one the policy turns down is
+//! [resampled](crate::resample) until it complies, so every compliant
+//! password stays equally likely and records keep their fixed width. How
+//! many tries a record may take depends on the fraction of candidates the
+//! policy accepts, which is estimated once, from a fixed public keystream,
+//! when the generator is built.
+//!
+//! The length range is a check, not a choice: every candidate has the
+//! encoder's length, so a policy whose range leaves it out is refused when
+//! the generator is built rather than screened record by record.
+
+use std::fmt;
+
+use serde::Deserialize;
+
+use crate::pin::WeakPattern;
+use crate::{settings, Cipher, Encoder};
+
+/// Candidates drawn to estimate a policy's acceptance rate.
+const ESTIMATE_SAMPLES: usize = 1 << 16;
+
+/// Samples whose keystream is held at once while estimating.
+const ESTIMATE_BLOCK: usize = 1 << 10;
+
+/// Policies accepting less than this are rejected as impractical.
+const MIN_ACCEPTANCE: f64 = 0.01;
+
+/// Whether a byte belongs to a character class.
+type ClassTest = fn(&u8) -> bool;
+
+/// Character classes a policy can require, with their names.
+const CLASSES: [(ClassTest, &str); 4] = [
+    (u8::is_ascii_lowercase, "lowercase letters"),
+    (u8::is_ascii_uppercase, "uppercase letters"),
+    (u8::is_ascii_digit, "digits"),
+    (u8::is_ascii_punctuation, "symbols"),
+];
+
+/// What every generated password must satisfy. Counts are minimums; the
+/// default policy accepts everything.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PasswordPolicy {
+    /// Bounds the encoder's fixed length must fall within; they do not
+    /// make lengths vary
+    pub min_length: Option<usize>,
+    pub max_length: Option<usize>,
+    pub min_lower: usize,
+    pub min_upper: usize,
+    pub min_digits: usize,
+    /// ASCII punctuation, as in the `alnum+symbols` charset
+    pub min_symbols: usize,
+    /// Longest allowed run of one repeated character
+    pub max_repeat: Option<usize>,
+    /// Substrings no password may contain, compared ASCII case-insensitively
+    pub forbidden: Vec<String>,
+    /// Patterns no password may show
+    pub exclude: Vec<WeakPattern>,
+}
+
+impl PasswordPolicy {
+    /// Parses a policy file: TOML with one `name = value` per line, where
+    /// `forbidden` and `exclude` (of [`WeakPattern`]s) are arrays of
+    /// strings and everything else an integer.
+    ///
+    /// ```text
+    /// min_upper = 1
+    /// min_digits = 1
+    /// max_repeat = 2
+    /// forbidden = ["password", "qwerty"]
+    /// exclude = ["sequences", "common:20"]
+    /// ```
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let file: PolicyFile = settings::parse(text)?;
+        let exclude = file
+            .exclude
+            .iter()
+            .map(|p| WeakPattern::parse(p))
+            .collect::<Result<_, _>>()
+            .map_err(|e| format!("`exclude`: {}", e))?;
+        Ok(PasswordPolicy {
+            min_length: file.min_length,
+            max_length: file.max_length,
+            min_lower: file.min_lower,
+            min_upper: file.min_upper,
+            min_digits: file.min_digits,
+            min_symbols: file.min_symbols,
+            max_repeat: file.max_repeat,
+            forbidden: file.forbidden,
+            exclude,
+        })
+    }
+
+    /// Whether `password` complies.
+    pub fn check(&self, password: &[u8]) -> bool {
+        let len = password.len();
+        if self.min_length.is_some_and(|min| len < min)
+            || self.max_length.is_some_and(|max| len > max)
+        {
+            return false;
+        }
+        let short = CLASSES
+            .iter()
+            .zip(self.minimums())
+            .any(|((class, _), min)| password.iter().filter(|b| class(b)).count() < min);
+        if short {
+            return false;
+        }
+        if let Some(max) = self.max_repeat {
+            let longest = password
+                .chunk_by(|a, b| a == b)
+                .map(|run| run.len())
+                .max()
+                .unwrap_or(0);
+            if longest > max {
+                return false;
+            }
+        }
+        let forbidden = self.forbidden.iter().any(|word| {
+            !word.is_empty()
+                && password
+                    .windows(word.len())
+                    .any(|w| w.eq_ignore_ascii_case(word.as_bytes()))
+        });
+        !forbidden && !self.exclude.iter().any(|p| p.matches(password))
+    }
+
+    /// Required counts for each of [`CLASSES`].
+    fn minimums(&self) -> [usize; 4] {
+        [
+            self.min_lower,
+            self.min_upper,
+            self.min_digits,
+            self.min_symbols,
+        ]
+    }
+
+    /// Why no password from `encoder` can comply, if that is plain from the
+    /// policy alone.
+    fn unsatisfiable(&self, encoder: &Encoder) -> Option<String> {
+        let len = encoder.length();
+        if self.min_length.is_some_and(|min| len < min)
+            || self.max_length.is_some_and(|max| len > max)
+        {
+            return Some(format!(
+                "the password length {} is outside the policy's range",
+                len
+            ));
+        }
+        for ((class, name), min) in CLASSES.iter().zip(self.minimums()) {
+            if min > 0 && !encoder.alphabet().iter().any(class) {
+                return Some(format!("the charset has no {}", name));
+            }
+        }
+        let required: usize = self.minimums().iter().sum();
+        (required > len).then(|| format!("{} required characters do not fit in {}", required, len))
+    }
+}
+
+impl fmt::Display for PasswordPolicy {
+    /// The policy in [`parse`](Self::parse) syntax, on one line with `;`
+    /// between settings.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut settings = Vec::new();
+        let optional = [
+            ("min_length", self.min_length),
+            ("max_length", self.max_length),
+            ("max_repeat", self.max_repeat),
+        ];
+        for (name, value) in optional {
+            if let Some(value) = value {
+                settings.push(format!("{} = {}", name, value));
+            }
+        }
+        let minimums = [
+            ("min_lower", self.min_lower),
+            ("min_upper", self.min_upper),
+            ("min_digits", self.min_digits),
+            ("min_symbols", self.min_symbols),
+        ];
+        for (name, value) in minimums {
+            if value > 0 {
+                settings.push(format!("{} = {}", name, value));
+            }
+        }
+        if !self.forbidden.is_empty() {
+            settings.push(format!("forbidden = {:?}", self.forbidden));
+        }
+        if !self.exclude.is_empty() {
+            let patterns: Vec<String> = self.exclude.iter().map(|p| p.to_string()).collect();
+            settings.push(format!("exclude = {:?}", patterns));
+        }
+        f.write_str(&settings.join("; "))
+    }
+}
+
+/// A policy file, before its patterns are parsed.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PolicyFile {
+    min_length: Option<usize>,
+    max_length: Option<usize>,
+    #[serde(default)]
+    min_lower: usize,
+    #[serde(default)]
+    min_upper: usize,
+    #[serde(default)]
+    min_digits: usize,
+    #[serde(default)]
+    min_symbols: usize,
+    max_repeat: Option<usize>,
+    #[serde(default)]
+    forbidden: Vec<String>,
+    #[serde(default)]
+    exclude: Vec<String>,
+}
+
+/// A policy applied to one encoder, with its estimated acceptance rate.
+#[derive(Clone, Debug)]
+pub(crate) struct Screen {
+    pub policy: PasswordPolicy,
+    /// A conservative estimate of the fraction of candidates accepted
+    pub acceptance: f64,
+}
+
+impl Screen {
+    pub fn new(policy: PasswordPolicy, encoder: &Encoder) -> Result<Self, String> {
+        if let Some(reason) = policy.unsatisfiable(encoder) {
+            return Err(reason);
+        }
+        // A public keystream, so the estimate (and with it the bound on
+        // tries) is the same for every run. It is read a block
+        // of samples at a time, however long the candidates.
+        let mut stream = vec![0u8; ESTIMATE_BLOCK * encoder.budget()];
+        let mut candidate = vec![0u8; encoder.length()];
+        let mut accepted = 0;
+        for block in 0..ESTIMATE_SAMPLES / ESTIMATE_BLOCK {
+            let offset = (block * stream.len()) as u64;
+            stream.fill(0);
+            Cipher::ChaCha20
+                .backend()
+                .apply_keystream(&[0; 16].into(), 0, offset, &mut stream);
+            accepted += stream
+                .chunks_exact(encoder.budget())
+                .filter(|s| {
+                    encoder.encode(s, &mut candidate);
+                    policy.check(&candidate)
+                })
+                .count();
+        }
+        let acceptance = wilson_lower_bound(accepted, ESTIMATE_SAMPLES);
+        if acceptance < MIN_ACCEPTANCE {
+            return Err(format!(
+                "only {:.2}% of candidates comply; loosen the policy or use a longer password",
+                accepted as f64 * 100.0 / ESTIMATE_SAMPLES as f64
+            ));
+        }
+        Ok(Screen { policy, acceptance })
+    }
+}
+
+/// Lower end of the Wilson score interval at 5 sigma.
+fn wilson_lower_bound(successes: usize, trials: usize) -> f64 {
+    let (n, z) = (trials as f64, 5.0);
+    let p = successes as f64 / n;
+    let centre = p + z * z / (2.0 * n);
+    let spread = z * (p * (1.0 - p) / n + z * z / (4.0 * n * n)).sqrt();
+    ((centre - spread) / (1.0 + z * z / n)).max(0.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Charset;
+
+    #[test]
+    fn policies_parse_and_print() {
+        let text = "# house rules\nmin_upper = 1\nmin_digits = 2  # at least two\n\
+                    max_repeat = 2\nforbidden = [\"pass\", \"q\\\"x\"]\n\
+                    exclude = [\"years\", \"common:5\"]\n";
+        let policy = PasswordPolicy::parse(text).unwrap();
+        assert_eq!(policy.min_upper, 1);
+        assert_eq!(policy.min_digits, 2);
+        assert_eq!(policy.max_repeat, Some(2));
+        assert_eq!(policy.forbidden, ["pass", "q\"x"]);
+        assert_eq!(policy.exclude, [WeakPattern::Years, WeakPattern::Common(5)]);
+        let again = PasswordPolicy::parse(&policy.to_string().replace("; ", "\n")).unwrap();
+        assert_eq!(again, policy);
+        let err = |text| PasswordPolicy::parse(text).unwrap_err();
+        assert!(err("min_uper = 1").starts_with("line 1: unknown field `min_uper`"));
+        assert!(err("\nmin_upper = -1").starts_with("line 2: invalid value"));
+        assert!(err("min_upper = \"1\"").starts_with("line 1: invalid type"));
+        assert!(err("exclude = [\"years\", \"nope\"]").starts_with("`exclude`: "));
+    }
+
+    #[test]
+    fn check_applies_every_rule() {
+        let policy = PasswordPolicy {
+            min_upper: 1,
+            min_digits: 1,
+            min_symbols: 1,
+            max_repeat: Some(2),
+            forbidden: vec!["pass".into()],
+            ..Default::default()
+        };
+        assert!(policy.check(b"aB3$cc"));
+        assert!(!policy.check(b"ab3$cc"));
+        assert!(!policy.check(b"aB3$ccc"));
+        assert!(!policy.check(b"aB3$PaSs"));
+        assert!(!policy.check(b"aB3cc"));
+    }
+
+    #[test]
+    fn impossible_policies_are_refused() {
+        let hex = Encoder::new(&Charset::Hex, 8);
+        let upper = PasswordPolicy {
+            min_upper: 1,
+            ..Default::default()
+        };
+        assert!(Screen::new(upper, &hex).is_err());
+        let digits = PasswordPolicy {
+            min_digits: 9,
+            ..Default::default()
+        };
+        assert!(Screen::new(digits, &hex).is_err());
+        let hopeless = PasswordPolicy {
+            min_digits: 6,
+            ..Default::default()
+        };
+        assert!(Screen::new(hopeless, &Encoder::new(&Charset::Alnum, 8)).is_err());
+    }
+
+    #[test]
+    fn acceptance_is_estimated() {
+        let alnum = Encoder::new(&Charset::Alnum, 12);
+        let policy = PasswordPolicy {
+            min_digits: 1,
+            ..Default::default()
+        };
+        let screen = Screen::new(policy, &alnum).unwrap();
+        // 1 - (52/62)^12 of alnum passwords hold a digit
+        assert!((screen.acceptance - 0.879).abs() < 0.01);
+        assert!(screen.acceptance < 0.879);
+        // Tries enough that a record runs out with probability below 2^-64
+        let bound = crate::resample::bound(screen.acceptance);
+        assert!((1.0 - screen.acceptance).powi(bound as i32) < 2f64.powi(-64));
+        assert!((1.0 - screen.acceptance).powi(bound as i32 - 1) >= 2f64.powi(-64));
+    }
+}
@@ -0,0 +1,60 @@
+//! Records shown on screen: the `--preview` sample after a run, and the
+//! live records of a `--demo` run's progress panel.
+//!
+//! Both show records as the output has them, through the run's sink. They
+//! generate those records again instead of reading them back, since a
+//! streamed run kept none of them.
+
+use std::sync::Arc;
+
+use mass_password_gen_optimized::{OutputSink, PasswordGenerator};
+
+/// Records the `--progress panel` preview shows.
+const PREVIEW_RECORDS: usize = 3;
+
+/// Prints the first `count` records of the run, in its output format.
+pub fn sample(generator: &PasswordGenerator, sink: Option<&dyn OutputSink>, count: usize) {
+    for (i, password) in generator.records().take(count).enumerate() {
+        match sink {
+            Some(sink) => {
+                let mut line = Vec::new();
+                sink.write_record(i, &password, &mut line);
+                if line.is_empty() {
+                    // Removed by --verify-unique
+                    continue;
+                }
+                let line = String::from_utf8_lossy(&line);
+                status!("Password[{}] = {}", i, line.trim_end());
+            }
+            None if generator.is_binary() => status!("Password[{}] = {:02x?}", i, password),
+            None => status!("Password[{}] = {}", i, String::from_utf8_lossy(&password)),
+        }
+    }
+}
+/// The `--progress panel` preview of a `--demo` run: the first records of
+/// the chunk being written, as its output shows them.
+pub fn demo(
+    generator: &PasswordGenerator,
+    sink: Option<Arc<dyn OutputSink>>,
+) -> Box<dyn FnMut(usize) -> Vec<String> + Send> {
+    let generator = generator.clone();
+    Box::new(move |written| {
+        let chunk = written.saturating_sub(1) / generator.chunk_size();
+        let first = chunk * generator.chunk_size();
+        let mut head =
+            vec![0u8; PREVIEW_RECORDS.min(generator.chunk_len(chunk)) * generator.record_width()];
+        let used = generator.fill_chunk(chunk, &mut Vec::new(), &mut head);
+        head.truncate(used);
+        let mut shown = Vec::new();
+        generator.for_each_record(&head, |record| {
+            let index = first + shown.len();
+            let mut text = Vec::new();
+            match &sink {
+                Some(sink) => sink.credential(index, record, &mut text),
+                None => text.extend_from_slice(record),
+            }
+            shown.push(format!("{:>9}  {}", index, String::from_utf8_lossy(&text)));
+        });
+        shown
+    })
+}
@@ -0,0 +1,295 @@
+//! `--progress`: live progress of a run on stderr.
+//!
+//! Workers bump two atomic counters as each chunk completes; a reporter
+//! thread reads them every tick and prints either a one-line bar, redrawn in
+//! place, one JSON object per line for other programs to follow, or (for
+//! `--demo`) a panel of the bar and a preview of the records, redrawn in
+//! place with ANSI cursor movement. The
+//! rate (and so the ETA) is taken over the last few seconds, so it follows
+//! the run's current speed rather than its average.
+
+use clap::ValueEnum;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+const GIB: f64 = 1024.0 * 1024.0 * 1024.0;
+
+/// Samples the rate is measured over.
+const RATE_WINDOW: usize = 10;
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Style {
+    /// A bar redrawn in place
+    Bar,
+    /// One JSON object per line
+    Json,
+    /// A panel with a preview of the records, redrawn in place (--demo
+    /// only, whose records are safe to show)
+    Panel,
+}
+
+impl Style {
+    fn interval(self) -> Duration {
+        match self {
+            Style::Bar | Style::Panel => Duration::from_millis(250),
+            Style::Json => Duration::from_secs(1),
+        }
+    }
+}
+
+/// How far the run has got; shared with the workers.
+pub struct Meter {
+    total: usize,
+    records: AtomicUsize,
+    bytes: AtomicU64,
+    done: AtomicBool,
+}
+
+impl Meter {
+    /// A meter for a run of `total` records, `done` of which are already
+    /// written (a resumed run).
+    pub fn new(total: usize, done: usize) -> Self {
+        Meter {
+            total,
+            records: AtomicUsize::new(done),
+            bytes: AtomicU64::new(0),
+            done: AtomicBool::new(false),
+        }
+    }
+
+    /// Counts a completed chunk of `records` records and `bytes` bytes.
+    pub fn chunk_done(&self, records: usize, bytes: u64) {
+        self.records.fetch_add(records, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn total(&self) -> usize {
+        self.total
+    }
+
+    /// Records written so far, those of an earlier, resumed run included.
+    pub fn records(&self) -> usize {
+        self.records.load(Ordering::Relaxed)
+    }
+
+    /// Output bytes so far, before compression.
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+}
+
+/// The rate of a run over its last [`RATE_WINDOW`] samples.
+pub struct Rate(VecDeque<(Instant, usize)>);
+
+impl Rate {
+    /// A rate from `records` already written now.
+    pub fn new(records: usize) -> Self {
+        Rate(VecDeque::from([(Instant::now(), records)]))
+    }
+
+    /// Records per second, now that `records` are written.
+    pub fn sample(&mut self, records: usize) -> f64 {
+        let now = Instant::now();
+        self.0.push_back((now, records));
+        if self.0.len() > RATE_WINDOW {
+            self.0.pop_front();
+        }
+        let (then, before) = self.0[0];
+        let secs = now.duration_since(then).as_secs_f64();
+        if secs > 0.0 {
+            (records - before) as f64 / secs
+        } else {
+            0.0
+        }
+    }
+}
+
+/// One progress line, as `--progress json` writes it.
+#[derive(Serialize)]
+struct Line {
+    elapsed_secs: f64,
+    records: usize,
+    total: usize,
+    /// Output bytes so far, before compression
+    bytes: u64,
+    records_per_sec: f64,
+    eta_secs: Option<f64>,
+    rss_bytes: Option<u64>,
+}
+
+/// What a [`Style::Panel`] shows besides the progress.
+pub struct Preview {
+    /// The panel's first line
+    pub title: String,
+    /// The records to show once `n` records are written
+    pub records: Box<dyn FnMut(usize) -> Vec<String> + Send>,
+}
+
+/// The reporter thread; [`finish`](Reporter::finish) prints the last line.
+pub struct Reporter {
+    meter: Arc<Meter>,
+    thread: JoinHandle<()>,
+}
+
+impl Reporter {
+    /// Starts reporting on `meter` in `style`, sampling memory with `rss`;
+    /// a panel shows `preview` too.
+    pub fn start(
+        meter: Arc<Meter>,
+        style: Style,
+        mut rss: impl FnMut() -> Option<u64> + Send + 'static,
+        mut preview: Option<Preview>,
+    ) -> Self {
+        let shared = Arc::clone(&meter);
+        let thread = std::thread::spawn(move || {
+            let meter = shared;
+            let start = Instant::now();
+            let mut window = Rate::new(meter.records());
+            // Length of the bar (or lines of the panel) drawn last, to blank
+            // out what a shorter one would leave behind
+            let mut drawn = 0usize;
+            loop {
+                if !meter.done.load(Ordering::Relaxed) {
+                    // finish() wakes us early
+                    std::thread::park_timeout(style.interval());
+                }
+                let last = meter.done.load(Ordering::Relaxed);
+                let records = meter.records();
+                let rate = window.sample(records);
+                let line = Line {
+                    elapsed_secs: start.elapsed().as_secs_f64(),
+                    records,
+                    total: meter.total,
+                    bytes: meter.bytes(),
+                    records_per_sec: rate,
+                    eta_secs: (rate > 0.0).then(|| (meter.total - records) as f64 / rate),
+                    rss_bytes: rss(),
+                };
+                let mut err = io::stderr().lock();
+                let _ = match style {
+                    Style::Bar => {
+                        let bar = bar(&line);
+                        let end = if last { "\n" } else { "" };
+                        let pad = drawn.saturating_sub(bar.len());
+                        drawn = bar.len();
+                        write!(err, "\r{}{:pad$}{}", bar, "", end)
+                    }
+                    Style::Json => writeln!(err, "{}", serde_json::to_string(&line).unwrap()),
+                    Style::Panel => {
+                        let (title, records) = match &mut preview {
+                            Some(p) => (p.title.as_str(), (p.records)(line.records)),
+                            None => ("", Vec::new()),
+                        };
+                        let lines = panel(&line, title, &records);
+                        // Back up over the panel drawn last, clearing each
+                        // line as it is redrawn
+                        if drawn > 0 {
+                            let _ = write!(err, "\x1b[{}A", drawn);
+                        }
+                        drawn = lines.len();
+                        lines
+                            .iter()
+                            .try_for_each(|l| write!(err, "\r\x1b[2K{}\n", l))
+                    }
+                };
+                if last {
+                    return;
+                }
+            }
+        });
+        Reporter { meter, thread }
+    }
+
+    /// Prints the final state and stops the reporter.
+    pub fn finish(self) {
+        self.meter.done.store(true, Ordering::Relaxed);
+        self.thread.thread().unpark();
+        let _ = self.thread.join();
+    }
+}
+
+/// `[#####-----]  48.1%  481000/1000000  5.2 M/s  0.01 GiB  ETA 0:00:10  RSS 0.02 GiB`
+fn bar(line: &Line) -> String {
+    const WIDTH: usize = 24;
+    let fraction = line.records as f64 / line.total.max(1) as f64;
+    let filled = ((fraction * WIDTH as f64) as usize).min(WIDTH);
+    let eta = match line.eta_secs {
+        Some(secs) if line.records < line.total => clock(secs),
+        Some(_) => clock(0.0),
+        None => "--:--:--".to_string(),
+    };
+    let mut bar = format!(
+        "[{}{}] {:5.1}%  {}/{}  {:.1} M/s  {:.2} GiB  ETA {}",
+        "#".repeat(filled),
+        "-".repeat(WIDTH - filled),
+        fraction * 100.0,
+        line.records,
+        line.total,
+        line.records_per_sec / 1e6,
+        line.bytes as f64 / GIB,
+        eta
+    );
+    if let Some(rss) = line.rss_bytes {
+        bar += &format!("  RSS {:.2} GiB", rss as f64 / GIB);
+    }
+    bar
+}
+
+/// `secs` as `h:mm:ss`.
+pub fn clock(secs: f64) -> String {
+    let secs = secs.round() as u64;
+    format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+/// `bar` and `preview` in a box under `title`.
+fn panel(line: &Line, title: &str, preview: &[String]) -> Vec<String> {
+    let mut rows = vec![title.to_string(), bar(line)];
+    rows.extend(preview.iter().cloned());
+    let width = rows.iter().map(String::len).max().unwrap_or(0);
+    let rule = format!("+{}+", "-".repeat(width + 2));
+    let mut lines = vec![rule.clone()];
+    lines.extend(rows.iter().map(|row| format!("| {:width$} |", row)));
+    lines.push(rule);
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bars_and_panels_show_progress() {
+        let mut line = Line {
+            elapsed_secs: 5.0,
+            records: 250,
+            total: 1000,
+            bytes: 0,
+            records_per_sec: 10.0,
+            eta_secs: Some(75.0),
+            rss_bytes: None,
+        };
+        assert_eq!(
+            bar(&line),
+            "[######------------------]  25.0%  250/1000  0.0 M/s  0.00 GiB  ETA 0:01:15"
+        );
+        line.eta_secs = None;
+        line.rss_bytes = Some(1 << 30);
+        assert!(bar(&line).ends_with("ETA --:--:--  RSS 1.00 GiB"));
+
+        line.rss_bytes = None;
+        let preview = ["0  DEMO-abc".to_string()];
+        let lines = panel(&line, "DEMO", &preview);
+        let width = lines[0].len();
+        assert_eq!(lines.len(), 5);
+        assert!(lines.iter().all(|l| l.len() == width));
+        assert_eq!(lines[0], lines[4]);
+        assert!(lines[1].starts_with("| DEMO ") && lines[1].ends_with(" |"));
+        assert!(lines[2].starts_with("| [######---"));
+        assert!(lines[3].starts_with("| 0  DEMO-abc "));
+    }
+}
@@ -0,0 +1,452 @@
+//! Encryption to a person's public key, for files that only they should be
+//! able to read: a replay bundle's key, the shards of one team's records,
+//! a custodian's key share.
+//!
+//! As with [signatures](crate::signing), the tools that decrypt a file are
+//! the ones that encrypt it: `age:RECIPIENT` runs `age --encrypt` to an age
+//! recipient (`age1...`, or an SSH public key), and `gpg:KEYID` runs `gpg
+//! --encrypt` to a key in the local keyring. Each encrypted file is named
+//! for its tool, `FILE.age` or `FILE.gpg`, and decrypts with it alone:
+//!
+//! ```text
+//! age --decrypt -i key.txt -o bundle.key bundle.key.age
+//! gpg --decrypt --output pw-00001.txt pw-00001.txt.gpg
+//! ```
+//!
+//! `--encrypt-records FILE` gives the shards of each slice of a run to its
+//! own recipient. The file has a table per slice, any name, with the
+//! recipient and the records, from `START` and up to (not including)
+//! `END`, or to the end of the run:
+//!
+//! ```text
+//! [payments]
+//! recipient = "age:age1ql3z7hjy54pw3hyww5ayyfg7zqgvc7w3j2elw8zmrj2kg5sfn9aqmcac8p"
+//! records = "0..500000"
+//!
+//! [search]
+//! recipient = "gpg:search-oncall@example.com"
+//! records = "500000.."
+//! ```
+//!
+//! Every shard falls wholly to one slice, and every record to some slice,
+//! so a slice starts and ends on a multiple of `--shard-size`.
+
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{self, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use indexmap::IndexMap;
+use mass_password_gen_optimized::settings;
+use serde::Deserialize;
+
+/// Whom a file is encrypted to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Recipient {
+    /// An age recipient, `age1...` or an SSH public key
+    Age(String),
+    /// A gpg public key, by ID, fingerprint or user ID
+    Gpg(String),
+}
+
+impl Recipient {
+    /// Parses `age:RECIPIENT` or `gpg:KEYID`.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s.split_once(':') {
+            Some(("age", key)) if !key.trim().is_empty() => Ok(Recipient::Age(key.to_string())),
+            Some(("gpg", key)) if !key.is_empty() => Ok(Recipient::Gpg(key.to_string())),
+            _ => Err(format!("expected age:RECIPIENT or gpg:KEYID, not {:?}", s)),
+        }
+    }
+
+    fn tool(&self) -> &'static str {
+        match self {
+            Recipient::Age(_) => "age",
+            Recipient::Gpg(_) => "gpg",
+        }
+    }
+
+    /// Where the encryption of the file at `path` goes: `path` with the
+    /// tool's extension added.
+    pub fn encrypted_path(&self, path: &Path) -> PathBuf {
+        let mut encrypted = OsString::from(path.as_os_str());
+        encrypted.push(".");
+        encrypted.push(self.tool());
+        PathBuf::from(encrypted)
+    }
+
+    /// Checks that the tool is there and knows the recipient, before a run
+    /// that ends in encrypting starts.
+    pub fn check(&self) -> io::Result<()> {
+        let mut command = match self {
+            Recipient::Age(_) => {
+                let mut command = Command::new("age");
+                command.arg("--version");
+                command
+            }
+            Recipient::Gpg(key) => {
+                let mut command = Command::new("gpg");
+                command.args(["--batch", "--list-keys", key]);
+                command
+            }
+        };
+        let found = command
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .map_err(|e| tool_error(self.tool(), e))?
+            .success();
+        match found {
+            true => Ok(()),
+            false => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no {} to encrypt to", self),
+            )),
+        }
+    }
+
+    /// Encrypts `data` to the recipient into a new file at `output`.
+    pub fn encrypt(&self, data: &[u8], output: &Path) -> io::Result<()> {
+        let mut child = self
+            .command(output)
+            .stdin(Stdio::piped())
+            .stdout(io::stderr())
+            .spawn()
+            .map_err(|e| tool_error(self.tool(), e))?;
+        let written = child.stdin.take().unwrap().write_all(data);
+        let status = child.wait()?;
+        self.encrypted(output, status)?;
+        written
+    }
+
+    /// Encrypts the file at `path` to the recipient beside it, at
+    /// [`encrypted_path`](Self::encrypted_path), and removes it once the
+    /// encryption is whole. Gives where the encryption went.
+    pub fn encrypt_file(&self, path: &Path) -> io::Result<PathBuf> {
+        let output = self.encrypted_path(path);
+        let status = self
+            .command(&output)
+            .stdin(File::open(path)?)
+            .stdout(io::stderr())
+            .status()
+            .map_err(|e| tool_error(self.tool(), e))?;
+        self.encrypted(&output, status)?;
+        std::fs::remove_file(path)?;
+        Ok(output)
+    }
+
+    /// The tool's command encrypting its standard input into `output`.
+    fn command(&self, output: &Path) -> Command {
+        match self {
+            Recipient::Age(key) => {
+                let mut command = Command::new("age");
+                command.args(["--encrypt", "--recipient", key, "--output"]);
+                command.arg(output);
+                command
+            }
+            Recipient::Gpg(key) => {
+                let mut command = Command::new("gpg");
+                command
+                    .args(["--batch", "--yes", "--trust-model", "always"])
+                    .args(["--recipient", key, "--encrypt", "--output"])
+                    .arg(output);
+                command
+            }
+        }
+    }
+
+    fn encrypted(&self, output: &Path, status: std::process::ExitStatus) -> io::Result<()> {
+        match status.success() {
+            true => Ok(()),
+            false => Err(io::Error::other(format!(
+                "{} could not encrypt {} to {}: {}",
+                self.tool(),
+                output.display(),
+                self,
+                status
+            ))),
+        }
+    }
+}
+
+impl std::fmt::Display for Recipient {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Recipient::Age(key) => write!(f, "age:{}", key),
+            Recipient::Gpg(key) => write!(f, "gpg:{}", key),
+        }
+    }
+}
+
+/// One slice of `--encrypt-records`: a run's records that go to one
+/// recipient.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Slice {
+    pub name: String,
+    pub recipient: Recipient,
+    pub records: Range<usize>,
+}
+
+/// A slice's table in a recipients file.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, expecting = "a `[name]` table")]
+struct SliceSettings {
+    recipient: String,
+    records: String,
+}
+
+/// An `--encrypt-records` file: the slices of a run, in order of their
+/// records.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Recipients {
+    slices: Vec<Slice>,
+}
+
+impl Recipients {
+    /// Parses a recipients file, a `[name]` table per slice with its
+    /// `recipient` and `records` as quoted strings.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let tables: IndexMap<String, SliceSettings> = settings::parse(text)?;
+        let mut slices = tables
+            .into_iter()
+            .map(|(name, table)| {
+                let err = |msg: String| format!("`[{}]`: {}", name, msg);
+                Ok(Slice {
+                    recipient: Recipient::parse(&table.recipient).map_err(err)?,
+                    records: crate::parse_range(&table.records).map_err(err)?,
+                    name,
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        if slices.is_empty() {
+            return Err("a recipients file needs at least one `[name]`".to_string());
+        }
+        slices.sort_by_key(|slice| slice.records.start);
+        if let Some(pair) = slices
+            .windows(2)
+            .find(|pair| pair[0].records.end > pair[1].records.start)
+        {
+            return Err(format!(
+                "`[{}]` and `[{}]` both take record {}",
+                pair[0].name, pair[1].name, pair[1].records.start
+            ));
+        }
+        Ok(Recipients { slices })
+    }
+
+    pub fn slices(&self) -> &[Slice] {
+        &self.slices
+    }
+
+    /// Each recipient, once.
+    pub fn recipients(&self) -> Vec<&Recipient> {
+        let mut recipients: Vec<&Recipient> = Vec::new();
+        for slice in &self.slices {
+            if !recipients.contains(&&slice.recipient) {
+                recipients.push(&slice.recipient);
+            }
+        }
+        recipients
+    }
+
+    /// The slice of each shard of a run of `count` records in shards of
+    /// `shard_size`, which each fall wholly to one.
+    pub fn assign(&self, count: usize, shard_size: usize) -> Result<Vec<&Slice>, String> {
+        (0..count.div_ceil(shard_size))
+            .map(|n| {
+                let (first, end) = (n * shard_size, ((n + 1) * shard_size).min(count));
+                let slice = self
+                    .slices
+                    .iter()
+                    .find(|slice| slice.records.contains(&first))
+                    .ok_or_else(|| {
+                        format!(
+                            "records {}..{} (shard {}) are in no slice of the recipients file",
+                            first, end, n
+                        )
+                    })?;
+                match slice.records.end < end {
+                    true => Err(format!(
+                        "`[{}]` ends at record {}, partway through shard {} ({}..{}); \
+                         slices start and end on a multiple of --shard-size ({})",
+                        slice.name, slice.records.end, n, first, end, shard_size
+                    )),
+                    false => Ok(slice),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Decrypts the file at `path` with the tool its extension names: `.age`
+/// with the age identity file `identity`, `.gpg` with the local keyring.
+pub fn decrypt(path: &Path, identity: Option<&Path>) -> io::Result<Vec<u8>> {
+    let (tool, mut command) = match path.extension().and_then(|e| e.to_str()) {
+        Some("age") => {
+            let identity = identity.ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "{} is encrypted with age; give the identity to decrypt it with \
+                         --identity",
+                        path.display()
+                    ),
+                )
+            })?;
+            let mut command = Command::new("age");
+            command.args(["--decrypt", "--identity"]).arg(identity);
+            ("age", command)
+        }
+        Some("gpg") => {
+            let mut command = Command::new("gpg");
+            command.args(["--batch", "--quiet", "--decrypt"]);
+            ("gpg", command)
+        }
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is neither .age nor .gpg", path.display()),
+            ))
+        }
+    };
+    let output = command
+        .arg(path)
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|e| tool_error(tool, e))?;
+    match output.status.success() {
+        true => Ok(output.stdout),
+        false => Err(io::Error::other(format!(
+            "{} could not decrypt {}: {}",
+            tool,
+            path.display(),
+            output.status
+        ))),
+    }
+}
+
+fn tool_error(tool: &str, e: io::Error) -> io::Error {
+    io::Error::new(e.kind(), format!("cannot run {}: {}", tool, e))
+}
+
+/// A gpg key for tests, made once in a keyring of the tests' own (the
+/// process's `GNUPGHOME`), or `None` where this machine has no gpg.
+#[cfg(all(test, unix))]
+pub(crate) fn test_key() -> Option<Recipient> {
+    static KEY: std::sync::OnceLock<Option<Recipient>> = std::sync::OnceLock::new();
+    KEY.get_or_init(|| {
+        let dir = std::env::temp_dir().join(format!("mpg_gnupg_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut perms = std::fs::metadata(&dir).unwrap().permissions();
+        std::os::unix::fs::PermissionsExt::set_mode(&mut perms, 0o700);
+        std::fs::set_permissions(&dir, perms).unwrap();
+        std::env::set_var("GNUPGHOME", &dir);
+        let made = Command::new("gpg")
+            .args(["--batch", "--passphrase", "", "--quick-gen-key"])
+            .args(["mpg-test@example.com", "default", "default", "never"])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status();
+        made.is_ok_and(|status| status.success())
+            .then(|| Recipient::Gpg("mpg-test@example.com".to_string()))
+    })
+    .clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recipients_are_named_by_kind() {
+        assert_eq!(
+            Recipient::parse("age:age1qyqszqgpqyqszqgpqyqszqgpqyqszqgp"),
+            Ok(Recipient::Age(
+                "age1qyqszqgpqyqszqgpqyqszqgpqyqszqgp".to_string()
+            ))
+        );
+        assert_eq!(
+            Recipient::parse("gpg:ops@example.com"),
+            Ok(Recipient::Gpg("ops@example.com".to_string()))
+        );
+        assert!(Recipient::parse("gpg:").is_err());
+        assert!(Recipient::parse("age1qyqszqgp").is_err());
+        let gpg = Recipient::Gpg("ops".to_string());
+        assert_eq!(
+            gpg.encrypted_path(Path::new("d/pw-00001.txt")),
+            Path::new("d/pw-00001.txt.gpg")
+        );
+        assert!(decrypt(Path::new("d/pw-00001.txt"), None).is_err());
+        assert!(decrypt(Path::new("d/key.age"), None)
+            .unwrap_err()
+            .to_string()
+            .contains("--identity"));
+    }
+
+    #[test]
+    fn recipients_files_slice_a_run() {
+        let recipients = Recipients::parse(
+            "# who reads what\n\
+             [search]\n\
+             recipient = \"gpg:search@example.com\"\n\
+             records = \"2000..\"  # the rest\n\
+             \n\
+             [payments]\n\
+             records = \"0..2000\"\n\
+             recipient = \"age:age1qyqszqgpqyqszqgpqyqszqgpqyqszqgp\"\n",
+        )
+        .unwrap();
+        assert_eq!(recipients.recipients().len(), 2);
+        let shards = recipients.assign(4500, 1000).unwrap();
+        let names: Vec<&str> = shards.iter().map(|slice| slice.name.as_str()).collect();
+        assert_eq!(
+            names,
+            ["payments", "payments", "search", "search", "search"]
+        );
+        assert!(recipients
+            .assign(4500, 1500)
+            .unwrap_err()
+            .contains("partway through shard 1"));
+
+        let parse = |text: &str| Recipients::parse(text).unwrap_err();
+        assert!(parse("").contains("at least one"));
+        assert!(parse("recipient = \"gpg:a\"").contains("expected a `[name]` table"));
+        assert!(parse("[a]\nrecords = \"0..\"").contains("missing field `recipient`"));
+        assert!(parse("[a]\nrecipient = gpg:a").starts_with("line 2: string values must be quoted"));
+        assert!(parse("[a]\nkey = \"gpg:a\"").starts_with("line 2: unknown field `key`"));
+        assert!(parse("[a]\nrecipient = \"pgp:a\"\nrecords = \"0..\"").starts_with("`[a]`: "));
+        let overlapping = "[a]\nrecipient = \"gpg:a\"\nrecords = \"0..100\"\n\
+                           [b]\nrecipient = \"gpg:b\"\nrecords = \"50..\"";
+        assert!(parse(overlapping).contains("both take record 50"));
+        let gap = Recipients::parse("[a]\nrecipient = \"gpg:a\"\nrecords = \"0..100\"").unwrap();
+        assert!(gap.assign(200, 100).unwrap_err().contains("no slice"));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn files_decrypt_to_what_was_encrypted() {
+        let Some(recipient) = test_key() else {
+            // Nothing to encrypt with on this machine
+            return;
+        };
+        recipient.check().unwrap();
+        let dir = std::env::temp_dir().join(format!("mpg_recipient_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let encrypted = recipient.encrypted_path(&dir.join("s-00001.txt"));
+        recipient.encrypt(b"records\n", &encrypted).unwrap();
+        assert_ne!(std::fs::read(&encrypted).unwrap(), b"records\n");
+        assert_eq!(decrypt(&encrypted, None).unwrap(), b"records\n");
+        let shard = dir.join("s-00002.txt");
+        std::fs::write(&shard, b"more records\n").unwrap();
+        let encrypted = recipient.encrypt_file(&shard).unwrap();
+        assert!(!shard.exists());
+        assert_eq!(decrypt(&encrypted, None).unwrap(), b"more records\n");
+        assert!(Recipient::Gpg("nobody@example.com".to_string())
+            .check()
+            .is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
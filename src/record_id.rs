@@ -0,0 +1,149 @@
+//! Stable external identifiers for records, written beside the password.
+//!
+//! `--with-id uuidv5:NAMESPACE` gives each record the name-based UUID
+//! (RFC 9562, version 5) of its index, written in decimal, under
+//! `NAMESPACE`. The same namespace always maps an index to the same UUID,
+//! on any machine and in any chunk order, so a system can refer to a
+//! record by its UUID and leave the index and the password out.
+//!
+//! The UUID hides the index only as well as the namespace is kept: anyone
+//! who knows it can hash indexes until one matches. A random namespace
+//! (any UUID) kept with the run's key does; the well-known `dns`, `url`,
+//! `oid` and `x500` namespaces are for stability, not secrecy.
+
+use std::fmt;
+
+use sha1::{Digest, Sha1};
+
+/// Characters of a UUID as written, `8-4-4-4-12` hex digits.
+pub const UUID_LEN: usize = 36;
+
+/// The namespaces RFC 9562 defines, by the names `--with-id` takes.
+const WELL_KNOWN: [(&str, &str); 4] = [
+    ("dns", "6ba7b810-9dad-11d1-80b4-00c04fd430c8"),
+    ("url", "6ba7b811-9dad-11d1-80b4-00c04fd430c8"),
+    ("oid", "6ba7b812-9dad-11d1-80b4-00c04fd430c8"),
+    ("x500", "6ba7b814-9dad-11d1-80b4-00c04fd430c8"),
+];
+
+/// How each record's identifier is derived.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RecordIds {
+    /// The UUIDv5 of the record's index under a namespace
+    UuidV5 { namespace: [u8; 16] },
+}
+
+impl RecordIds {
+    /// Parses `uuidv5:NAMESPACE`, where `NAMESPACE` is a UUID or one of
+    /// `dns`, `url`, `oid` and `x500`.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let Some(namespace) = s.strip_prefix("uuidv5:") else {
+            return Err(format!(
+                "unknown identifier scheme `{}` (expected uuidv5:NAMESPACE)",
+                s
+            ));
+        };
+        let known = WELL_KNOWN
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(namespace));
+        let uuid = known.map_or(namespace, |(_, uuid)| uuid);
+        let namespace = parse_uuid(uuid).ok_or_else(|| {
+            format!(
+                "`{}` is not a namespace: expected a UUID such as \
+                 6ba7b810-9dad-11d1-80b4-00c04fd430c8, or dns, url, oid or x500",
+                namespace
+            )
+        })?;
+        Ok(RecordIds::UuidV5 { namespace })
+    }
+
+    /// The identifier of record `index`.
+    pub fn uuid(&self, index: usize) -> [u8; 16] {
+        let RecordIds::UuidV5 { namespace } = self;
+        let mut hash = Sha1::new();
+        hash.update(namespace);
+        hash.update(index.to_string().as_bytes());
+        let mut uuid: [u8; 16] = hash.finalize()[..16].try_into().unwrap();
+        uuid[6] = (uuid[6] & 0x0f) | 0x50;
+        uuid[8] = (uuid[8] & 0x3f) | 0x80;
+        uuid
+    }
+
+    /// Appends record `index`'s identifier, [`UUID_LEN`] characters.
+    pub fn push(&self, index: usize, out: &mut Vec<u8>) {
+        push_uuid(&self.uuid(index), out);
+    }
+}
+
+impl fmt::Display for RecordIds {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let RecordIds::UuidV5 { namespace } = self;
+        let mut uuid = Vec::with_capacity(UUID_LEN);
+        push_uuid(namespace, &mut uuid);
+        let uuid = String::from_utf8(uuid).unwrap();
+        match WELL_KNOWN.iter().find(|(_, known)| *known == uuid) {
+            Some((name, _)) => write!(f, "uuidv5:{}", name),
+            None => write!(f, "uuidv5:{}", uuid),
+        }
+    }
+}
+
+/// Parses a UUID in its `8-4-4-4-12` form, in either case.
+fn parse_uuid(s: &str) -> Option<[u8; 16]> {
+    let groups: Vec<&str> = s.split('-').collect();
+    let lens: Vec<usize> = groups.iter().map(|g| g.len()).collect();
+    if lens != [8, 4, 4, 4, 12] || !s.bytes().all(|b| b == b'-' || b.is_ascii_hexdigit()) {
+        return None;
+    }
+    let hex = groups.concat();
+    let mut uuid = [0; 16];
+    for (i, byte) in uuid.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(2 * i..2 * i + 2)?, 16).ok()?;
+    }
+    Some(uuid)
+}
+
+fn push_uuid(uuid: &[u8; 16], out: &mut Vec<u8>) {
+    for (i, part) in [
+        &uuid[..4],
+        &uuid[4..6],
+        &uuid[6..8],
+        &uuid[8..10],
+        &uuid[10..],
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        if i > 0 {
+            out.push(b'-');
+        }
+        crate::format::push_hex(part, out);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_are_uuidv5_of_the_index() {
+        let ids = RecordIds::parse("uuidv5:dns").unwrap();
+        assert_eq!(ids.to_string(), "uuidv5:dns");
+        // Python's uuid.uuid5(uuid.NAMESPACE_DNS, "12345")
+        let mut out = Vec::new();
+        ids.push(12345, &mut out);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "08d517c3-ac51-54e8-9036-a65fc977ab11"
+        );
+        let own = RecordIds::parse("uuidv5:0F2A6E3C-1B7D-4C8E-9A5F-3D2B1C0E9F8A").unwrap();
+        assert_eq!(
+            own.to_string(),
+            "uuidv5:0f2a6e3c-1b7d-4c8e-9a5f-3d2b1c0e9f8a"
+        );
+        assert_ne!(own.uuid(12345), ids.uuid(12345));
+        assert!(RecordIds::parse("uuidv4:dns").is_err());
+        assert!(RecordIds::parse("uuidv5:0f2a6e3c1b7d4c8e9a5f3d2b1c0e9f8a").is_err());
+        assert!(RecordIds::parse("uuidv5:0f2a6e3c-1b7d-4c8e-9a5f-3d2b1c0e9f8g").is_err());
+    }
+}
@@ -0,0 +1,259 @@
+//! Reference files: a non-secret listing of a run, written in the same pass
+//! as the passwords, that can be shared where the passwords cannot.
+//!
+//! A reference is CSV with one `index,id,checksum` row per record. The id
+//! is the run's key fingerprint and the index, so it stays unique when
+//! references from several runs are merged. The checksum is 32 hex digits
+//! of HMAC-SHA256 over the index and the credential as the output shows it
+//! (see [`OutputSink::credential`]: raw blocks as hex in a CSV, say), keyed
+//! with the reference key, which is separate from the run's key: without
+//! it, even a short password cannot be brute-forced back from its checksum,
+//! and with it anyone can check a credential against its row.
+//!
+//! Rows have a length fixed by their index's number of digits, so each
+//! chunk's rows go straight to their offset from whichever thread generated
+//! the chunk.
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::fs::File;
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use crate::format::{decimal_digits, indexed_len, push_hex};
+use crate::stream::write_all_at;
+use crate::{key_fingerprint, Lane, OutputSink, PasswordGenerator};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Bytes of the HMAC kept in a checksum.
+const CHECKSUM_LEN: usize = 16;
+
+const HEADER: &[u8] = b"index,id,checksum\n";
+
+/// Checksums records under a reference key.
+#[derive(Clone)]
+pub struct ReferenceKey {
+    mac: HmacSha256,
+}
+
+impl ReferenceKey {
+    pub fn new(key: &[u8]) -> Self {
+        ReferenceKey {
+            mac: Lane::REFERENCE.mac(key),
+        }
+    }
+
+    /// The checksum of record `index`, given as its credential (for raw
+    /// output, the native bytes: the raw block, or the text without its
+    /// newline), in hex.
+    pub fn checksum(&self, index: usize, record: &[u8]) -> String {
+        let mut hex = Vec::with_capacity(CHECKSUM_LEN * 2);
+        self.push_checksum(index, record, &mut hex);
+        String::from_utf8(hex).expect("hex is ASCII")
+    }
+
+    fn push_checksum(&self, index: usize, record: &[u8], out: &mut Vec<u8>) {
+        let mut mac = self.mac.clone();
+        mac.update(&(index as u64).to_le_bytes());
+        mac.update(record);
+        push_hex(&mac.finalize().into_bytes()[..CHECKSUM_LEN], out);
+    }
+}
+
+/// Writes a run's reference file as its chunks are generated; attach it with
+/// [`PasswordGenerator::with_reference`].
+pub struct ReferenceWriter {
+    key: ReferenceKey,
+    /// The run's key fingerprint, which ids start with
+    run: String,
+    /// The run's output format, which decides what each credential is
+    sink: Option<Arc<dyn OutputSink>>,
+    file: File,
+    /// The first write that failed; later chunks are not written
+    error: Mutex<Option<io::Error>>,
+}
+
+impl ReferenceWriter {
+    /// A writer for `generator`'s run, written out through `sink` (`None`
+    /// for raw output), into `file`, which should be empty. Writes the
+    /// header.
+    pub fn new(
+        generator: &PasswordGenerator,
+        sink: Option<Arc<dyn OutputSink>>,
+        key: &[u8],
+        file: File,
+    ) -> io::Result<Self> {
+        write_all_at(&file, HEADER, 0)?;
+        Ok(ReferenceWriter {
+            key: ReferenceKey::new(key),
+            run: key_fingerprint(&generator.key),
+            sink,
+            file,
+            error: Mutex::new(None),
+        })
+    }
+
+    /// Length of the row for record `index`.
+    fn row_len(&self, index: usize) -> usize {
+        let index = decimal_digits(index);
+        index + 1 + self.run.len() + 1 + index + 1 + CHECKSUM_LEN * 2 + 1
+    }
+
+    /// Writes the rows for the records `data` holds, the first of which is
+    /// record `first`.
+    pub(crate) fn write_chunk(&self, generator: &PasswordGenerator, first: usize, data: &[u8]) {
+        if self.error.lock().unwrap().is_some() {
+            return;
+        }
+        let mut rows =
+            Vec::with_capacity(data.len() / generator.record_width() * self.row_len(first));
+        let mut index = first;
+        let mut credential = Vec::new();
+        generator.for_each_record(data, |record| {
+            let record = match &self.sink {
+                Some(sink) => {
+                    credential.clear();
+                    sink.credential(index, record, &mut credential);
+                    &credential[..]
+                }
+                None => record,
+            };
+            let number = index.to_string();
+            rows.extend_from_slice(number.as_bytes());
+            rows.push(b',');
+            rows.extend_from_slice(self.run.as_bytes());
+            rows.push(b':');
+            rows.extend_from_slice(number.as_bytes());
+            rows.push(b',');
+            self.key.push_checksum(index, record, &mut rows);
+            rows.push(b'\n');
+            index += 1;
+        });
+        let offset = HEADER.len() as u128
+            + indexed_len(0..first, |index| Some(self.row_len(index))).expect("rows have a length");
+        if let Err(e) = write_all_at(&self.file, &rows, offset as u64) {
+            self.error.lock().unwrap().get_or_insert(e);
+        }
+    }
+
+    /// The first write that failed, if any.
+    pub fn finish(&self) -> io::Result<()> {
+        match self.error.lock().unwrap().take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{hex, Charset, Encoder, Format, Passphrase};
+
+    #[test]
+    fn references_list_every_record_once() {
+        let path = std::env::temp_dir().join("mpg_reference_test.csv");
+        let builders = [
+            (PasswordGenerator::builder(), Format::Raw),
+            (PasswordGenerator::builder(), Format::Csv),
+            (
+                PasswordGenerator::builder().encoder(Encoder::new(&Charset::Alnum, 12)),
+                Format::Base64,
+            ),
+            (
+                PasswordGenerator::builder()
+                    .passphrase(Passphrase::new(4, "-", crate::Capitalization::Lower, 0).unwrap()),
+                Format::Jsonl,
+            ),
+        ];
+        for (builder, format) in builders {
+            let g = builder
+                .key([5; 16])
+                .count(1234)
+                .chunk_size(100)
+                .build()
+                .unwrap();
+            let sink: Option<Arc<dyn OutputSink>> = format.sink(&g).map(Arc::from);
+            let file = File::create(&path).unwrap();
+            let writer = ReferenceWriter::new(&g, sink.clone(), &[9; 16], file).unwrap();
+            let writer = Arc::new(writer);
+            let tapped = g.with_reference(Arc::clone(&writer));
+            // Written in parallel, out of order
+            assert_eq!(tapped.generate(), g.generate());
+            writer.finish().unwrap();
+
+            let text = std::fs::read_to_string(&path).unwrap();
+            let mut lines = text.lines();
+            assert_eq!(lines.next(), Some("index,id,checksum"));
+            let key = ReferenceKey::new(&[9; 16]);
+            let run = key_fingerprint(&[5; 16]);
+            let records: Vec<Vec<u8>> = g.records().collect();
+            assert_eq!(lines.clone().count(), records.len());
+            for (index, (line, record)) in lines.zip(&records).enumerate() {
+                // The credential as the output shows it
+                let credential = match (&sink, format) {
+                    (None, _) => record.clone(),
+                    (Some(_), Format::Csv) => hex(record).into(),
+                    (Some(sink), _) => {
+                        let mut line = Vec::new();
+                        sink.write_record(index, record, &mut line);
+                        let line = String::from_utf8(line).unwrap();
+                        let field = match format {
+                            Format::Jsonl => line.split('"').nth(5).unwrap(),
+                            _ => line.trim_end(),
+                        };
+                        field.as_bytes().to_vec()
+                    }
+                };
+                let row = format!(
+                    "{},{}:{},{}",
+                    index,
+                    run,
+                    index,
+                    key.checksum(index, &credential)
+                );
+                assert_eq!(line, row);
+            }
+        }
+        // The checksum depends on the reference key and the index
+        let key = ReferenceKey::new(&[9; 16]);
+        assert_ne!(key.checksum(0, b"x"), key.checksum(1, b"x"));
+        assert_ne!(
+            key.checksum(0, b"x"),
+            ReferenceKey::new(&[8; 16]).checksum(0, b"x")
+        );
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn rows_have_their_length_and_failures_are_kept() {
+        let g = PasswordGenerator::builder()
+            .key([5; 16])
+            .count(1005)
+            .chunk_size(7)
+            .build()
+            .unwrap();
+        let path = std::env::temp_dir().join("mpg_reference_rows.csv");
+        let file = File::create(&path).unwrap();
+        let writer = Arc::new(ReferenceWriter::new(&g, None, &[9; 16], file).unwrap());
+        g.with_reference(Arc::clone(&writer)).generate();
+        writer.finish().unwrap();
+        let text = std::fs::read_to_string(&path).unwrap();
+        // Across 9 to 10, 99 to 100 and 999 to 1000 digits
+        for (index, line) in text.lines().skip(1).enumerate() {
+            assert_eq!(line.len() + 1, writer.row_len(index), "{}", line);
+        }
+
+        // A file that cannot be written: the first error is reported once
+        let writer = ReferenceWriter {
+            file: File::open(&path).unwrap(),
+            ..ReferenceWriter::new(&g, None, &[9; 16], File::create(&path).unwrap()).unwrap()
+        };
+        writer.write_chunk(&g, 0, &g.chunk(0).data);
+        writer.write_chunk(&g, 7, &g.chunk(1).data);
+        assert!(writer.finish().is_err());
+        assert!(writer.finish().is_ok());
+        std::fs::remove_file(&path).ok();
+    }
+}
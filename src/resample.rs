@@ -0,0 +1,217 @@
+//! Per-record resampling, for filters that turn some candidates down.
+//!
+//! A filter (today a [policy](crate::policy), which also carries PIN
+//! exclusions, banned words and blocklists) sees each record's candidate
+//! where an unfiltered run would have put it, at the record's own slot of
+//! the keystream. When the filter turns it down, replacements come from a
+//! reserved [lane](crate::Lane), [`Lane::RESAMPLE`]: the subkey it derives
+//! from the run's key, with the record's index as the nonce, and each try's
+//! keystream budget after the last. So a record depends on nothing but its
+//! index, however the run is chunked, and a record the filter accepts is
+//! the unfiltered run's record at that index.
+//!
+//! Tries are bounded. The bound is set from a lower estimate of the
+//! fraction of candidates the filter accepts so that any record using it
+//! up has probability below 2^-64. Should one anyway, generation stops
+//! rather than emit a record the filter turned down. Each run keeps counts
+//! of how often records were resampled, in [`ResampleStats`].
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{Cipher, Key, Lane};
+
+/// Tries whose keystream is generated at a time once a record is
+/// resampled.
+const BATCH: usize = 16;
+
+/// How often a run's records were resampled.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ResampleStats {
+    /// Records generated
+    pub records: u64,
+    /// Records whose first candidate was turned down
+    pub resampled: u64,
+    /// Candidates drawn from the resample lane, over all records
+    pub replacements: u64,
+    /// Most tries one record took, its first candidate included
+    pub most_tries: u64,
+    /// Tries a record may take
+    pub bound: u64,
+}
+
+impl ResampleStats {
+    /// The fraction of records resampled.
+    pub fn rate(&self) -> f64 {
+        self.resampled as f64 / self.records.max(1) as f64
+    }
+}
+
+/// Counts kept by every copy of a run's generator.
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    records: AtomicU64,
+    resampled: AtomicU64,
+    replacements: AtomicU64,
+    most_tries: AtomicU64,
+}
+
+impl Counters {
+    fn add(&self, tally: &Tally) {
+        self.records.fetch_add(tally.records, Ordering::Relaxed);
+        self.resampled.fetch_add(tally.resampled, Ordering::Relaxed);
+        self.replacements
+            .fetch_add(tally.replacements, Ordering::Relaxed);
+        self.most_tries
+            .fetch_max(tally.most_tries, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self, bound: usize) -> ResampleStats {
+        ResampleStats {
+            records: self.records.load(Ordering::Relaxed),
+            resampled: self.resampled.load(Ordering::Relaxed),
+            replacements: self.replacements.load(Ordering::Relaxed),
+            most_tries: self.most_tries.load(Ordering::Relaxed),
+            bound: bound as u64,
+        }
+    }
+}
+
+/// Counts for one chunk, added to the run's at the end.
+#[derive(Default)]
+struct Tally {
+    records: u64,
+    resampled: u64,
+    replacements: u64,
+    most_tries: u64,
+}
+
+/// Tries a record may take for a filter accepting at least `acceptance`
+/// of its candidates: all of them turned down has probability below 2^-64.
+pub(crate) fn bound(acceptance: f64) -> usize {
+    let bound = 64.0 * std::f64::consts::LN_2 / -(-acceptance).ln_1p();
+    (bound.ceil() as usize).max(1)
+}
+
+/// Resamples the records of one chunk.
+pub(crate) struct Resampler<'a> {
+    /// The run's subkey in the resample lane
+    key: Key,
+    cipher: Cipher,
+    /// Keystream bytes per candidate
+    budget: usize,
+    bound: usize,
+    counters: &'a Counters,
+    tally: Tally,
+    scratch: Vec<u8>,
+}
+
+impl<'a> Resampler<'a> {
+    pub fn new(
+        key: &Key,
+        cipher: Cipher,
+        budget: usize,
+        bound: usize,
+        counters: &'a Counters,
+    ) -> Self {
+        Resampler {
+            key: Lane::RESAMPLE.key(key),
+            cipher,
+            budget,
+            bound,
+            counters,
+            tally: Tally::default(),
+            scratch: Vec::new(),
+        }
+    }
+
+    /// Offers `accept` the candidate keystream `first` of record `index`,
+    /// then replacements from the lane until it takes one.
+    pub fn draw(&mut self, index: usize, first: &[u8], mut accept: impl FnMut(&[u8]) -> bool) {
+        let mut tries = 1;
+        if !accept(first) {
+            self.tally.resampled += 1;
+            'lane: loop {
+                let batch = BATCH.min(self.bound - tries);
+                assert!(
+                    batch > 0,
+                    "record {} was turned down {} times, which has probability below 2^-64; \
+                     the filter's acceptance estimate cannot be right",
+                    index,
+                    tries
+                );
+                let offset = ((tries - 1) * self.budget) as u64;
+                self.scratch.clear();
+                self.scratch.resize(batch * self.budget, 0);
+                self.cipher.backend().apply_keystream(
+                    &self.key,
+                    index as u64,
+                    offset,
+                    &mut self.scratch,
+                );
+                for stream in self.scratch.chunks_exact(self.budget) {
+                    tries += 1;
+                    if accept(stream) {
+                        break 'lane;
+                    }
+                }
+            }
+            self.tally.replacements += tries as u64 - 1;
+        }
+        self.tally.records += 1;
+        self.tally.most_tries = self.tally.most_tries.max(tries as u64);
+    }
+}
+
+impl Drop for Resampler<'_> {
+    fn drop(&mut self) {
+        self.counters.add(&self.tally);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replacements_come_from_the_lane() {
+        assert_eq!(bound(1.0), 1);
+        assert_eq!(bound(0.5), 64);
+        let counters = Counters::default();
+        let key = Key::from([9; 16]);
+        let mut seen = Vec::new();
+        {
+            let mut resampler = Resampler::new(&key, Cipher::Aes128, 4, 64, &counters);
+            resampler.draw(7, &[0; 4], |stream| {
+                seen.push(stream.to_vec());
+                seen.len() == 20
+            });
+            resampler.draw(8, &[1; 4], |_| true);
+        }
+        let mut lane = vec![0u8; 19 * 4];
+        Cipher::Aes128
+            .backend()
+            .apply_keystream(&Lane::RESAMPLE.key(&key), 7, 0, &mut lane);
+        assert_eq!(seen[0], [0; 4]);
+        assert_eq!(seen[1..].concat(), lane);
+        let stats = counters.stats(64);
+        assert_eq!(
+            stats,
+            ResampleStats {
+                records: 2,
+                resampled: 1,
+                replacements: 19,
+                most_tries: 20,
+                bound: 64,
+            }
+        );
+        assert_eq!(stats.rate(), 0.5);
+    }
+
+    #[test]
+    #[should_panic(expected = "turned down 3 times")]
+    fn tries_are_bounded() {
+        let counters = Counters::default();
+        let mut resampler = Resampler::new(&Key::from([9; 16]), Cipher::Aes128, 4, 3, &counters);
+        resampler.draw(0, &[0; 4], |_| false);
+    }
+}
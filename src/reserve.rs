@@ -0,0 +1,336 @@
+//! Claiming records of a pre-generated corpus for online issuance.
+//!
+//! A [`ReservationStore`] is a small SQLite database kept next to a corpus.
+//! It holds the index of the first unclaimed record and a log of every
+//! claim; [`reserve`](ReservationStore::reserve) advances the index in one
+//! immediate transaction, so concurrent issuers (threads or processes) never
+//! get overlapping ranges. A claim is never given back to the pool. Once
+//! its records have been handed over the issuer marks it
+//! [`delivered`](ReservationStore::delivered); until then the claimant may
+//! fetch it again with [`undelivered`](ReservationStore::undelivered), so a
+//! delivery cut short loses nothing and no record goes to two claimants.
+
+use std::fmt;
+use std::ops::Range;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior};
+
+/// How long to wait for another issuer's transaction to finish.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Why a reservation failed.
+#[derive(Debug)]
+pub enum ReservationError {
+    /// Fewer records are left than were asked for; nothing was claimed
+    Exhausted { requested: usize, remaining: usize },
+    /// The store was made for a corpus of a different size
+    Mismatch { store: usize, corpus: usize },
+    /// No claim by this claimant starts at `first`
+    NoClaim { first: usize },
+    /// The claim starting at `first` was already delivered
+    Delivered { first: usize },
+    /// The database could not be read or written
+    Store(rusqlite::Error),
+}
+
+impl fmt::Display for ReservationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReservationError::Exhausted {
+                requested,
+                remaining,
+            } => write!(
+                f,
+                "cannot claim {} records, only {} are left",
+                requested, remaining
+            ),
+            ReservationError::Mismatch { store, corpus } => write!(
+                f,
+                "the reservation store is for a corpus of {} records, not {}",
+                store, corpus
+            ),
+            ReservationError::NoClaim { first } => {
+                write!(f, "no claim of yours starts at record {}", first)
+            }
+            ReservationError::Delivered { first } => {
+                write!(
+                    f,
+                    "the claim starting at record {} was already delivered",
+                    first
+                )
+            }
+            ReservationError::Store(e) => write!(f, "reservation store: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ReservationError {}
+
+impl From<rusqlite::Error> for ReservationError {
+    fn from(e: rusqlite::Error) -> Self {
+        ReservationError::Store(e)
+    }
+}
+
+/// One claim from the log.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Claim {
+    pub records: Range<usize>,
+    /// Unix seconds
+    pub claimed_at: u64,
+    pub claimant: Option<String>,
+    /// Unix seconds, once the records were handed over
+    pub delivered_at: Option<u64>,
+}
+
+/// Tracks which records of a corpus of `total` records have been claimed.
+pub struct ReservationStore {
+    conn: Connection,
+    total: usize,
+}
+
+impl ReservationStore {
+    /// Opens the store at `path` for a corpus of `total` records, creating
+    /// it if needed.
+    pub fn open(path: &Path, total: usize) -> Result<Self, ReservationError> {
+        let mut conn = Connection::open(path)?;
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        tx.execute_batch(
+            "CREATE TABLE IF NOT EXISTS corpus (
+                 id INTEGER PRIMARY KEY CHECK (id = 0),
+                 total INTEGER NOT NULL,
+                 next INTEGER NOT NULL
+             );
+             CREATE TABLE IF NOT EXISTS claims (
+                 first INTEGER PRIMARY KEY,
+                 count INTEGER NOT NULL,
+                 claimed_at INTEGER NOT NULL,
+                 claimant TEXT
+             );",
+        )?;
+        // Stores made before deliveries were recorded lack the column
+        let has_delivered: bool = tx.query_row(
+            "SELECT COUNT(*) > 0 FROM pragma_table_info('claims') WHERE name = 'delivered_at'",
+            [],
+            |row| row.get(0),
+        )?;
+        if !has_delivered {
+            tx.execute_batch("ALTER TABLE claims ADD COLUMN delivered_at INTEGER")?;
+        }
+        let stored: Option<usize> = tx
+            .query_row("SELECT total FROM corpus", [], |row| row.get(0))
+            .optional()?;
+        match stored {
+            None => {
+                tx.execute(
+                    "INSERT INTO corpus (id, total, next) VALUES (0, ?1, 0)",
+                    [total],
+                )?;
+            }
+            Some(store) if store != total => {
+                return Err(ReservationError::Mismatch {
+                    store,
+                    corpus: total,
+                })
+            }
+            Some(_) => {}
+        }
+        tx.commit()?;
+        Ok(ReservationStore { conn, total })
+    }
+
+    pub fn total(&self) -> usize {
+        self.total
+    }
+
+    /// Records not yet claimed.
+    pub fn remaining(&self) -> Result<usize, ReservationError> {
+        let next: usize = self
+            .conn
+            .query_row("SELECT next FROM corpus", [], |row| row.get(0))?;
+        Ok(self.total - next)
+    }
+
+    /// Claims the next `count` unclaimed records, all or nothing.
+    pub fn reserve(
+        &mut self,
+        count: usize,
+        claimant: Option<&str>,
+    ) -> Result<Range<usize>, ReservationError> {
+        let tx = self
+            .conn
+            .transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let next: usize = tx.query_row("SELECT next FROM corpus", [], |row| row.get(0))?;
+        let remaining = self.total - next;
+        if count > remaining {
+            return Err(ReservationError::Exhausted {
+                requested: count,
+                remaining,
+            });
+        }
+        tx.execute("UPDATE corpus SET next = ?1", [next + count])?;
+        tx.execute(
+            "INSERT INTO claims (first, count, claimed_at, claimant) VALUES (?1, ?2, ?3, ?4)",
+            params![next, count, now(), claimant],
+        )?;
+        tx.commit()?;
+        Ok(next..next + count)
+    }
+
+    /// The records of `claimant`'s claim starting at `first`, if they have
+    /// not been delivered yet.
+    pub fn undelivered(
+        &self,
+        first: usize,
+        claimant: Option<&str>,
+    ) -> Result<Range<usize>, ReservationError> {
+        let claim: Option<(usize, Option<u64>)> = self
+            .conn
+            .query_row(
+                "SELECT count, delivered_at FROM claims WHERE first = ?1 AND claimant IS ?2",
+                params![first, claimant],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        match claim {
+            None => Err(ReservationError::NoClaim { first }),
+            Some((_, Some(_))) => Err(ReservationError::Delivered { first }),
+            Some((count, None)) => Ok(first..first + count),
+        }
+    }
+
+    /// Records that the claim starting at `first` was handed over.
+    pub fn delivered(&mut self, first: usize) -> Result<(), ReservationError> {
+        let updated = self.conn.execute(
+            "UPDATE claims SET delivered_at = ?2 WHERE first = ?1",
+            params![first, now()],
+        )?;
+        if updated == 0 {
+            return Err(ReservationError::NoClaim { first });
+        }
+        Ok(())
+    }
+
+    /// Every claim so far, in index order.
+    pub fn claims(&self) -> Result<Vec<Claim>, ReservationError> {
+        let mut query = self.conn.prepare(
+            "SELECT first, count, claimed_at, claimant, delivered_at FROM claims ORDER BY first",
+        )?;
+        let claims = query.query_map([], |row| {
+            let first: usize = row.get(0)?;
+            let count: usize = row.get(1)?;
+            Ok(Claim {
+                records: first..first + count,
+                claimed_at: row.get(2)?,
+                claimant: row.get(3)?,
+                delivered_at: row.get(4)?,
+            })
+        })?;
+        Ok(claims.collect::<Result<_, _>>()?)
+    }
+}
+
+/// Unix seconds now.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn claims_never_overlap() {
+        let path = std::env::temp_dir().join("mpg_reservations_test.sqlite");
+        let _ = std::fs::remove_file(&path);
+        let mut store = ReservationStore::open(&path, 100).unwrap();
+        assert_eq!(store.reserve(3, Some("a")).unwrap(), 0..3);
+        assert_eq!(store.reserve(5, None).unwrap(), 3..8);
+
+        // Concurrent issuers, each with its own connection
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let path = path.clone();
+                std::thread::spawn(move || {
+                    let mut store = ReservationStore::open(&path, 100).unwrap();
+                    (0..10)
+                        .map(|_| store.reserve(2, None).unwrap())
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        let mut claimed: Vec<_> = threads
+            .into_iter()
+            .flat_map(|t| t.join().unwrap())
+            .collect();
+        claimed.sort_by_key(|r| r.start);
+        assert!(claimed.windows(2).all(|w| w[0].end == w[1].start));
+        assert_eq!((claimed[0].start, claimed[39].end), (8, 88));
+
+        assert!(matches!(
+            store.reserve(13, None),
+            Err(ReservationError::Exhausted {
+                requested: 13,
+                remaining: 12
+            })
+        ));
+        assert_eq!(store.remaining().unwrap(), 12);
+        let claims = store.claims().unwrap();
+        assert_eq!(claims.len(), 42);
+        assert_eq!(claims[0].claimant.as_deref(), Some("a"));
+        assert!(matches!(
+            ReservationStore::open(&path, 99),
+            Err(ReservationError::Mismatch { .. })
+        ));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn claims_can_be_fetched_again_until_delivered() {
+        let path = std::env::temp_dir().join("mpg_reservations_delivery_test.sqlite");
+        let _ = std::fs::remove_file(&path);
+        // A store from before deliveries were recorded
+        Connection::open(&path)
+            .unwrap()
+            .execute_batch(
+                "CREATE TABLE corpus (id INTEGER PRIMARY KEY, total INTEGER, next INTEGER);
+                 INSERT INTO corpus VALUES (0, 10, 2);
+                 CREATE TABLE claims (first INTEGER PRIMARY KEY, count INTEGER,
+                                      claimed_at INTEGER, claimant TEXT);
+                 INSERT INTO claims VALUES (0, 2, 0, NULL);",
+            )
+            .unwrap();
+        let mut store = ReservationStore::open(&path, 10).unwrap();
+        assert_eq!(store.claims().unwrap()[0].delivered_at, None);
+        assert_eq!(store.undelivered(0, None).unwrap(), 0..2);
+
+        let claimed = store.reserve(3, Some("a")).unwrap();
+        assert_eq!(claimed, 2..5);
+        assert_eq!(store.undelivered(2, Some("a")).unwrap(), 2..5);
+        // Only by its claimant, and only where a claim starts
+        assert!(matches!(
+            store.undelivered(2, Some("b")),
+            Err(ReservationError::NoClaim { first: 2 })
+        ));
+        assert!(matches!(
+            store.undelivered(3, Some("a")),
+            Err(ReservationError::NoClaim { .. })
+        ));
+
+        store.delivered(2).unwrap();
+        assert!(matches!(
+            store.undelivered(2, Some("a")),
+            Err(ReservationError::Delivered { first: 2 })
+        ));
+        assert!(store.claims().unwrap()[1].delivered_at.is_some());
+        assert!(store.delivered(7).is_err());
+        // Fetching again claims nothing new
+        assert_eq!(store.remaining().unwrap(), 5);
+        std::fs::remove_file(path).unwrap();
+    }
+}
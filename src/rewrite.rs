@@ -0,0 +1,234 @@
+//! `rewrite`: an existing CSV export with one of its columns replaced by
+//! new passwords, for rotating the credentials of a dataset in place.
+//!
+//! The input is read twice, a row at a time. The first pass finds the
+//! column by its name in the header and counts the data rows that need a
+//! password. The second pass writes each row again, with the field in that
+//! column replaced by the next record. Everything else is copied byte for
+//! byte: the other fields, their quoting and the line endings. Blank lines
+//! are copied through too, and get no record.
+//!
+//! Rows are split as RFC 4180 has it, so a quoted field may hold commas,
+//! doubled quotes and line breaks. New fields are quoted only when they
+//! need it, and raw records are written as hex.
+
+use std::io::{self, BufRead, Write};
+use std::ops::Range;
+
+use crate::format::push_csv_field;
+use crate::PasswordGenerator;
+
+/// The rows of a CSV file.
+struct Rows<R> {
+    input: R,
+    /// Lines read so far
+    line: usize,
+}
+
+impl<R: BufRead> Rows<R> {
+    /// Reads the next row into `row`, line ending included, and gives the
+    /// line it starts on; `None` at the end of the input.
+    fn next(&mut self, row: &mut Vec<u8>) -> io::Result<Option<usize>> {
+        row.clear();
+        let first = self.line + 1;
+        let mut quoted = false;
+        loop {
+            let start = row.len();
+            if self.input.read_until(b'\n', row)? == 0 {
+                if quoted {
+                    return Err(invalid(first, "a quoted field is never closed"));
+                }
+                return Ok((!row.is_empty()).then_some(first));
+            }
+            self.line += 1;
+            let quotes = row[start..].iter().filter(|&&b| b == b'"').count();
+            quoted ^= quotes % 2 == 1;
+            if !quoted {
+                return Ok(Some(first));
+            }
+        }
+    }
+}
+
+/// Finds the column named `name` in the header of the CSV `input`, and
+/// counts its data rows. Gives the column's position and the count, or an
+/// error for a missing column or a row without that field.
+pub fn survey(input: impl BufRead, name: &str) -> io::Result<(usize, usize)> {
+    let mut rows = Rows { input, line: 0 };
+    let mut row = Vec::new();
+    if rows.next(&mut row)?.is_none() {
+        return Err(invalid(1, "the input is empty, with no header"));
+    }
+    let header = body(&row);
+    let header = header.strip_prefix(b"\xef\xbb\xbf").unwrap_or(header);
+    let column = fields(header)
+        .into_iter()
+        .position(|field| unquote(&header[field]) == name.as_bytes())
+        .ok_or_else(|| invalid(1, &format!("the header has no column {:?}", name)))?;
+    let mut count = 0;
+    while let Some(line) = rows.next(&mut row)? {
+        if body(&row).is_empty() {
+            continue;
+        }
+        field(&row, column, line)?;
+        count += 1;
+    }
+    Ok((column, count))
+}
+
+/// Copies the CSV `input` to `out` with field `column` of each data row
+/// replaced by the next of `generator`'s records, which must number as
+/// many as [`survey`] counted rows.
+pub fn rewrite(
+    input: impl BufRead,
+    column: usize,
+    generator: &PasswordGenerator,
+    mut out: impl Write,
+) -> io::Result<()> {
+    let mut rows = Rows { input, line: 0 };
+    let (mut row, mut rewritten) = (Vec::new(), Vec::new());
+    let mut records = generator.records();
+    if rows.next(&mut row)?.is_some() {
+        out.write_all(&row)?;
+    }
+    while let Some(line) = rows.next(&mut row)? {
+        if body(&row).is_empty() {
+            out.write_all(&row)?;
+            continue;
+        }
+        let field = field(&row, column, line)?;
+        let record = records
+            .next()
+            .ok_or_else(|| invalid(line, "more rows than when the input was counted"))?;
+        rewritten.clear();
+        rewritten.extend_from_slice(&row[..field.start]);
+        push_csv_field(generator.is_binary(), &record, &mut rewritten);
+        rewritten.extend_from_slice(&row[field.end..]);
+        out.write_all(&rewritten)?;
+    }
+    if records.next().is_some() {
+        return Err(invalid(
+            rows.line,
+            "fewer rows than when the input was counted",
+        ));
+    }
+    out.flush()
+}
+
+/// The row without its line ending.
+fn body(row: &[u8]) -> &[u8] {
+    let row = row.strip_suffix(b"\n").unwrap_or(row);
+    row.strip_suffix(b"\r").unwrap_or(row)
+}
+
+/// Where each field of `row` (without its line ending) is, quotes included.
+fn fields(row: &[u8]) -> Vec<Range<usize>> {
+    let (mut fields, mut start, mut quoted) = (Vec::new(), 0, false);
+    for (i, &b) in row.iter().enumerate() {
+        match b {
+            b'"' => quoted = !quoted,
+            b',' if !quoted => {
+                fields.push(start..i);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    fields.push(start..row.len());
+    fields
+}
+
+/// Where field `column` of the row starting on `line` is.
+fn field(row: &[u8], column: usize, line: usize) -> io::Result<Range<usize>> {
+    let fields = fields(body(row));
+    let found = fields.len();
+    fields.into_iter().nth(column).ok_or_else(|| {
+        invalid(
+            line,
+            &format!("{} fields, with none in column {}", found, column + 1),
+        )
+    })
+}
+
+/// A field's text, without its quotes.
+fn unquote(field: &[u8]) -> Vec<u8> {
+    match field
+        .strip_prefix(b"\"")
+        .and_then(|f| f.strip_suffix(b"\""))
+    {
+        Some(inner) => {
+            let mut text = Vec::with_capacity(inner.len());
+            let mut bytes = inner.iter();
+            while let Some(&b) = bytes.next() {
+                text.push(b);
+                if b == b'"' {
+                    // The second quote of `""`
+                    bytes.next();
+                }
+            }
+            text
+        }
+        None => field.to_vec(),
+    }
+}
+
+fn invalid(line: usize, e: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {}", line, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Charset, Encoder};
+
+    #[test]
+    fn only_the_column_changes() {
+        let csv = "\u{feff}id,\"pass\"\"word\",note\r\n\
+                   1,old1,plain\r\n\
+                   \r\n\
+                   2,\"old,2\",\"two\nlines, \"\"quoted\"\"\"\r\n\
+                   3,,last";
+        let (column, count) = survey(csv.as_bytes(), "pass\"word").unwrap();
+        assert_eq!((column, count), (1, 3));
+        let g = PasswordGenerator::builder()
+            .key([7; 16])
+            .count(count)
+            .chunk_size(2)
+            .encoder(Encoder::new(&Charset::Custom("a,b".into()), 8))
+            .build()
+            .unwrap();
+        let mut out = Vec::new();
+        rewrite(csv.as_bytes(), column, &g, &mut out).unwrap();
+        let mut expected: Vec<u8> = b"\xef\xbb\xbfid,\"pass\"\"word\",note\r\n".to_vec();
+        let p: Vec<Vec<u8>> = g.records().collect();
+        let field = |record: &[u8]| {
+            let mut f = Vec::new();
+            push_csv_field(false, record, &mut f);
+            f
+        };
+        for (before, record, after) in [
+            ("1,", &p[0], ",plain\r\n\r\n"),
+            ("2,", &p[1], ",\"two\nlines, \"\"quoted\"\"\"\r\n"),
+            ("3,", &p[2], ",last"),
+        ] {
+            expected.extend_from_slice(before.as_bytes());
+            expected.extend_from_slice(&field(record));
+            expected.extend_from_slice(after.as_bytes());
+        }
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            String::from_utf8(expected).unwrap()
+        );
+    }
+
+    #[test]
+    fn bad_input_is_refused() {
+        let e = survey("id,user\n1,a\n".as_bytes(), "password").unwrap_err();
+        assert!(e.to_string().contains("no column \"password\""), "{}", e);
+        let e = survey("id,password\n1,a\n2\n".as_bytes(), "password").unwrap_err();
+        assert!(e.to_string().starts_with("line 3: 1 fields"), "{}", e);
+        let e = survey("password\n\"open\n".as_bytes(), "password").unwrap_err();
+        assert!(e.to_string().contains("never closed"), "{}", e);
+        assert_eq!(unquote(b"\"a\"\"\"\"b\""), b"a\"\"b");
+    }
+}
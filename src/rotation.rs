@@ -0,0 +1,188 @@
+//! `rotation-plan`: the credentials of one run set against those of the
+//! next, index by index, for rotation automation to apply.
+//!
+//! Both runs are generated again from their keys and parameters, so the
+//! plan needs neither corpus on hand. Record `i` of the old run is replaced
+//! by record `i` of the new; where one run is longer, the indexes past the
+//! other's end are retired (old run only) or issued (new run only).
+//!
+//! Changes are written as CSV (`index,change,old,new`, with a header) or as
+//! JSON lines (`{"index":..,"change":"..","old":"..","new":".."}`), the
+//! side a change lacks empty in CSV and `null` in JSON. Raw records are
+//! written as hex.
+
+use std::io::{self, Write};
+
+use serde::Serialize;
+
+use crate::format::{push_csv_field, push_json_string};
+use crate::PasswordGenerator;
+
+/// How a rotation plan is written.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PlanFormat {
+    /// `index,change,old,new` rows with a header
+    #[default]
+    Csv,
+    /// One JSON object per change
+    Jsonl,
+}
+
+impl PlanFormat {
+    /// Parses `csv` or `jsonl`.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s {
+            "csv" => Ok(PlanFormat::Csv),
+            "jsonl" => Ok(PlanFormat::Jsonl),
+            _ => Err(format!(
+                "unknown plan format `{}` (expected csv or jsonl)",
+                s
+            )),
+        }
+    }
+}
+
+/// One side of a rotation plan: a run, and whether its records are raw.
+pub struct PlanRun<'a> {
+    pub generator: &'a PasswordGenerator,
+    pub binary: bool,
+}
+
+/// How many of each change a plan holds.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct PlanSummary {
+    /// Indexes in both runs, whose credential is replaced
+    pub rotated: usize,
+    /// Indexes in the old run only
+    pub retired: usize,
+    /// Indexes in the new run only
+    pub issued: usize,
+}
+
+/// Writes the plan from `old` to `new` to `out` in `format`.
+pub fn write_plan(
+    old: &PlanRun,
+    new: &PlanRun,
+    format: PlanFormat,
+    out: &mut dyn Write,
+) -> io::Result<PlanSummary> {
+    let mut summary = PlanSummary::default();
+    let mut buf = match format {
+        PlanFormat::Csv => b"index,change,old,new\n".to_vec(),
+        PlanFormat::Jsonl => Vec::new(),
+    };
+    let (mut olds, mut news) = (old.generator.records(), new.generator.records());
+    for index in 0.. {
+        let (was, is) = (olds.next(), news.next());
+        let change = match (&was, &is) {
+            (Some(_), Some(_)) => {
+                summary.rotated += 1;
+                "rotate"
+            }
+            (Some(_), None) => {
+                summary.retired += 1;
+                "retire"
+            }
+            (None, Some(_)) => {
+                summary.issued += 1;
+                "issue"
+            }
+            (None, None) => break,
+        };
+        let sides = [(was, old.binary), (is, new.binary)];
+        match format {
+            PlanFormat::Csv => {
+                write!(buf, "{},{}", index, change)?;
+                for (record, binary) in &sides {
+                    buf.push(b',');
+                    if let Some(record) = record {
+                        push_csv_field(*binary, record, &mut buf);
+                    }
+                }
+            }
+            PlanFormat::Jsonl => {
+                write!(buf, r#"{{"index":{},"change":"{}""#, index, change)?;
+                for ((record, binary), name) in sides.iter().zip(["old", "new"]) {
+                    write!(buf, r#","{}":"#, name)?;
+                    match record {
+                        Some(record) => push_json_string(*binary, record, &mut buf),
+                        None => buf.extend_from_slice(b"null"),
+                    }
+                }
+                buf.push(b'}');
+            }
+        }
+        buf.push(b'\n');
+        if buf.len() >= 1 << 16 {
+            out.write_all(&buf)?;
+            buf.clear();
+        }
+    }
+    out.write_all(&buf)?;
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Charset, Encoder};
+
+    fn run(key: u8, count: usize) -> PasswordGenerator {
+        PasswordGenerator::builder()
+            .key([key; 16])
+            .count(count)
+            .chunk_size(2)
+            .encoder(Encoder::new(&Charset::Alnum, 10))
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn plans_pair_records_by_index() {
+        let (old, new) = (run(1, 3), run(2, 4));
+        let plan = |format| {
+            let mut out = Vec::new();
+            let summary = write_plan(
+                &PlanRun {
+                    generator: &old,
+                    binary: false,
+                },
+                &PlanRun {
+                    generator: &new,
+                    binary: false,
+                },
+                format,
+                &mut out,
+            )
+            .unwrap();
+            (String::from_utf8(out).unwrap(), summary)
+        };
+        let text = |generator: &PasswordGenerator, i| {
+            String::from_utf8(generator.records().nth(i).unwrap()).unwrap()
+        };
+
+        let (csv, summary) = plan(PlanFormat::Csv);
+        assert_eq!(
+            summary,
+            PlanSummary {
+                rotated: 3,
+                retired: 0,
+                issued: 1
+            }
+        );
+        let rows: Vec<&str> = csv.lines().collect();
+        assert_eq!(rows[0], "index,change,old,new");
+        assert_eq!(
+            rows[2],
+            format!("1,rotate,{},{}", text(&old, 1), text(&new, 1))
+        );
+        assert_eq!(rows[4], format!("3,issue,,{}", text(&new, 3)));
+
+        let (jsonl, _) = plan(PlanFormat::Jsonl);
+        let last: serde_json::Value = serde_json::from_str(jsonl.lines().last().unwrap()).unwrap();
+        assert_eq!(last["change"], "issue");
+        assert_eq!(last["old"], serde_json::Value::Null);
+        assert_eq!(last["new"], text(&new, 3));
+        assert!(PlanFormat::parse("xml").is_err());
+    }
+}
@@ -0,0 +1,472 @@
+//! The routes of `serve`: `GET /passwords` generates a fresh batch under a
+//! key of its own, or pages through the `--run-key` run, `POST /reserve`
+//! hands out records of a corpus that no other client gets, and `GET
+//! /stats` reports on the chunk cache. The HTTP around them is
+//! [`serve`](crate::serve)'s.
+
+use std::error::Error;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use clap::Parser;
+use mass_password_gen_optimized::{key_fingerprint, random_key, Cipher, Format, PasswordGenerator};
+use sysinfo::{System, SystemExt};
+#[cfg(feature = "reservations")]
+use {
+    crate::commands,
+    mass_password_gen_optimized::{CorpusReader, ReservationError, ReservationStore},
+    std::{
+        io,
+        path::{Path, PathBuf},
+    },
+};
+
+use crate::cache::ChunkCache;
+use crate::cursor::PagedRun;
+use crate::notify::Notifier;
+use crate::{
+    keyprovider, listen, parse_count, parse_record_format, serve, CipherChoice, PasswordQuery,
+    RecordArgs, ServeArgs, STATUS_TO_STDERR,
+};
+
+/// Passwords per chunk for `serve` requests, which are small.
+const SERVE_CHUNK: usize = 64 * 1024;
+
+/// Parameters that ask `GET /passwords` for a page of the `--run-key` run.
+const PAGING: [&str; 2] = ["cursor", "limit"];
+
+/// Record options `GET /passwords` takes as query parameters; --policy
+/// names a file, which is not for clients to choose.
+const QUERY_OPTIONS: [&str; 11] = [
+    "count",
+    "charset",
+    "length",
+    "template",
+    "mode",
+    "words",
+    "separator",
+    "capitalize",
+    "digits",
+    "exclude",
+    "format",
+];
+
+/// Runs `serve` until the process is stopped; `run` describes the
+/// `--run-key` run.
+pub fn serve(options: &ServeArgs, run: &RecordArgs) -> Result<(), Box<dyn Error>> {
+    STATUS_TO_STDERR.store(true, Ordering::Relaxed);
+    let ServeArgs {
+        ref bind,
+        max_count,
+        ..
+    } = *options;
+    let mut policy = serve::Policy {
+        rate: options.rate_limit,
+        ..Default::default()
+    };
+    policy.token = match options.token_file.as_deref() {
+        Some(path) => Some(serve::read_token(path)?),
+        None => None,
+    };
+    let paged = match &options.run_key {
+        Some(uri) => Some(paged_run(options, run, uri)?),
+        None => None,
+    };
+    #[cfg(feature = "reservations")]
+    let corpus = match options.reserve_from.as_deref() {
+        Some(path) => {
+            let reader =
+                CorpusReader::open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+            let default_store = PathBuf::from(format!("{}.reservations", path.display()));
+            let total = reader
+                .count()
+                .ok_or_else(|| format!("{}: the corpus does not say its size", path.display()))?;
+            let store = options.store.as_deref().unwrap_or(&default_store);
+            let store = ReservationStore::open(store, total).map_err(|e| e.to_string())?;
+            status!(
+                "Reserving from {}: {} of {} records left",
+                path.display(),
+                store.remaining().map_err(|e| e.to_string())?,
+                total
+            );
+            Some((path, reader, std::sync::Mutex::new(store)))
+        }
+        None => None,
+    };
+    if let Some(path) = &options.audit_log {
+        let log = serve::AuditLog::open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        policy.audit = Some(log);
+    }
+    #[cfg(feature = "tls")]
+    if let (Some(cert), Some(key)) = (&options.tls_cert, &options.tls_key) {
+        let config = crate::tls::server_config(cert, key, options.client_ca.as_deref())?;
+        policy.tls = Some(config);
+    }
+    #[cfg(feature = "tls")]
+    let (https, certificates) = (policy.tls.is_some(), options.client_ca.is_some());
+    #[cfg(not(feature = "tls"))]
+    let (https, certificates) = (false, false);
+    let listener = bind.bind().map_err(|e| format!("{}: {}", bind, e))?;
+    let required = match (certificates, policy.token.is_some()) {
+        (false, false) => "no authentication",
+        (true, false) => "client certificate required",
+        (false, true) => "bearer token required",
+        (true, true) => "client certificate and bearer token required",
+    };
+    let mut terms = vec![required.to_string()];
+    if let Some(rate) = policy.rate {
+        terms.push(format!(
+            "{} requests per {:?} a client",
+            rate.requests, rate.per
+        ));
+    }
+    if let Some(path) = &options.audit_log {
+        terms.push(format!("audited to {}", path.display()));
+    }
+    let scheme = if https { "https" } else { "http" };
+    let at = match listener.local()? {
+        listen::Address::Tcp(address) => format!("{}://{}/", scheme, address),
+        address => format!("{} over {}", scheme, address),
+    };
+    status!(
+        "Serving on {} ({}; at most {} records a request)",
+        at,
+        terms.join("; "),
+        max_count
+    );
+    let requests = Arc::new(AtomicUsize::new(0));
+    let service = Notifier::from_env().map(|notifier| {
+        let requests = Arc::clone(&requests);
+        let serving = format!("Serving on {}", at);
+        notifier.ready(serving.clone(), move || {
+            let status = format!("{}; {} requests", serving, requests.load(Ordering::Relaxed));
+            (status, true)
+        })
+    });
+    let served = serve::run(listener, &policy, |request| {
+        requests.fetch_add(1, Ordering::Relaxed);
+        match (request.method.as_str(), request.path.as_str()) {
+            ("GET", "/passwords")
+                if request
+                    .query
+                    .iter()
+                    .any(|(name, _)| PAGING.contains(&name.as_str())) =>
+            {
+                serve_page(request, max_count, paged.as_ref())
+            }
+            ("GET", "/passwords") => serve_passwords(request, max_count),
+            #[cfg(feature = "reservations")]
+            ("POST", "/reserve") => match &corpus {
+                Some((path, reader, store)) => {
+                    serve_reservation(request, max_count, path, reader, store)
+                }
+                None => {
+                    serve::Reply::Error(404, "start the server with --reserve-from".to_string())
+                }
+            },
+            #[cfg(not(feature = "reservations"))]
+            ("POST", "/reserve") => serve::Reply::Error(
+                404,
+                "this build has no reservations (built without the `reservations` feature)"
+                    .to_string(),
+            ),
+            ("GET", "/stats") => serve_stats(paged.as_ref()),
+            (_, "/passwords" | "/reserve" | "/stats") => {
+                serve::Reply::Error(405, format!("{} is not allowed here", request.method))
+            }
+            (_, path) => serve::Reply::Error(404, format!("no route {}", path)),
+        }
+    });
+    if let Some(service) = service {
+        service.stop(format!("Stopped serving on {}", at));
+    }
+    served?;
+    Ok(())
+}
+
+/// `GET /passwords`: a fresh batch under a key of its own.
+fn serve_passwords(request: &serve::Request, max_count: usize) -> serve::Reply<'static> {
+    let bad = |message: String| serve::Reply::Error(400, message);
+    let mut argv = Vec::new();
+    for (name, value) in &request.query {
+        if !QUERY_OPTIONS.contains(&name.as_str()) {
+            return bad(format!("unknown parameter `{}`", name));
+        }
+        argv.push(format!("--{}={}", name, value));
+    }
+    let records = match PasswordQuery::try_parse_from(argv) {
+        Ok(query) => query.records,
+        Err(e) => {
+            // Clap's message, without the usage and help lines that follow
+            let message = e.to_string();
+            let lines: Vec<&str> = message
+                .lines()
+                .take_while(|line| !line.is_empty() && !line.starts_with("Usage"))
+                .map(str::trim)
+                .collect();
+            return bad(lines.join(" ").trim_start_matches("error: ").to_string());
+        }
+    };
+    if records.count > max_count {
+        return bad(format!("count is limited to {}", max_count));
+    }
+    if records.format == Format::ExpiringToken {
+        return bad("tokens under a per-request key could never be validated".to_string());
+    }
+    let key = match random_key() {
+        Ok(key) => key,
+        Err(e) => return serve::Reply::Error(500, format!("no key: {}", e)),
+    };
+    let generator = match records
+        .configure(
+            PasswordGenerator::builder()
+                .key(key)
+                .chunk_size(SERVE_CHUNK),
+        )
+        .and_then(|builder| builder.build().map_err(|e| e.to_string()))
+    {
+        Ok(generator) => generator,
+        Err(e) => return bad(e),
+    };
+    let sink = match records.sink(&generator) {
+        Ok(sink) => sink,
+        Err(e) => return bad(e),
+    };
+    serve::Reply::Stream {
+        content_type: content_type(records.format, generator.is_binary()),
+        headers: vec![
+            ("X-Records", generator.count().to_string()),
+            ("X-Key-Fingerprint", key_fingerprint(&key)),
+        ],
+        pulled: Some(serve::Pulled {
+            source: format!("key:{}", key_fingerprint(&key)),
+            records: 0..generator.count(),
+        }),
+        body: Box::new(move |out| {
+            match &sink {
+                Some(sink) => generator.write_formatted(sink.as_ref(), out, |_, _| ())?,
+                None => generator.write_to(out, |_, _| ())?,
+            };
+            Ok(())
+        }),
+    }
+}
+
+/// The fixed run of `--run-key`, as a file run with `--deterministic --key`
+/// and the same options would write it.
+fn paged_run(
+    options: &ServeArgs,
+    records: &RecordArgs,
+    uri: &keyprovider::KeyUri,
+) -> Result<PagedRun, Box<dyn Error>> {
+    let key = uri.load()?;
+    let cipher = match options.cipher {
+        Some(CipherChoice::Auto) => {
+            return Err(
+                "--cipher auto could pick another cipher after a restart, and \
+                        cursors would no longer find their records; pin one"
+                    .into(),
+            )
+        }
+        Some(choice) => choice.pinned().expect("pinned"),
+        None => Cipher::default().for_key(&key),
+    };
+    if records.format == Format::ExpiringToken {
+        return Err("tokens expire from when they are minted, so pages of them \
+                    could never be fetched again"
+            .into());
+    }
+    let generator = records
+        .configure(
+            PasswordGenerator::builder()
+                .key(key)
+                .cipher(cipher)
+                .chunk_size(options.chunk_size),
+        )?
+        .build()
+        .map_err(|e| e.to_string())?;
+    let sink = records.sink(&generator)?;
+    let mut sys = System::new();
+    sys.refresh_memory();
+    let cache = options.cache_memory.bytes(sys.available_memory());
+    status!(
+        "Paging {} records from {}, fingerprint {} (caching up to {} MiB of chunks)",
+        generator.count(),
+        uri.provider().describe(),
+        key_fingerprint(&key),
+        cache >> 20
+    );
+    Ok(PagedRun::new(
+        generator,
+        records.format,
+        sink,
+        &key,
+        records.shape(),
+        ChunkCache::new(cache.try_into().unwrap_or(usize::MAX)),
+    ))
+}
+
+/// `GET /passwords?cursor=C&limit=N[&format=F]`: the next N records of the
+/// `--run-key` run from cursor C (the start if empty), with the cursor of
+/// the page after in `X-Next-Cursor` until the run ends.
+fn serve_page<'a>(
+    request: &serve::Request,
+    max_count: usize,
+    paged: Option<&'a PagedRun>,
+) -> serve::Reply<'a> {
+    let bad = |message: String| serve::Reply::Error(400, message);
+    let Some(run) = paged else {
+        return serve::Reply::Error(404, "start the server with --run-key to page".to_string());
+    };
+    let (mut cursor, mut limit, mut format) = ("", max_count, None);
+    for (name, value) in &request.query {
+        match name.as_str() {
+            "cursor" => cursor = value.as_str(),
+            "limit" => match parse_count(value) {
+                Ok(0) => return bad("limit: a page has at least one record".to_string()),
+                Ok(n) if n > max_count => return bad(format!("limit is limited to {}", max_count)),
+                Ok(n) => limit = n,
+                Err(e) => return bad(format!("limit: {}", e)),
+            },
+            "format" => match parse_record_format(value) {
+                Ok(f) => format = Some(f),
+                Err(e) => return bad(e),
+            },
+            _ => {
+                return bad(format!(
+                    "`{}` is fixed by the --run-key run; pages take cursor, limit and format",
+                    name
+                ))
+            }
+        }
+    }
+    let start = match run.parse(cursor) {
+        Ok(start) => start,
+        Err(e) => return bad(e),
+    };
+    let sink = match run.sink_for(format) {
+        Ok(sink) => sink,
+        Err(e) => return bad(e),
+    };
+    let count = run.generator().count();
+    let end = count.min(start.saturating_add(limit));
+    let mut headers = vec![
+        ("X-Records", (end - start).to_string()),
+        ("X-First-Index", start.to_string()),
+        ("X-Key-Fingerprint", run.fingerprint().to_string()),
+    ];
+    if end < count {
+        headers.push(("X-Next-Cursor", run.cursor(end)));
+    }
+    serve::Reply::Stream {
+        content_type: content_type(format.unwrap_or(run.format()), run.generator().is_binary()),
+        headers,
+        pulled: Some(serve::Pulled {
+            source: format!("key:{}", run.fingerprint()),
+            records: start..end,
+        }),
+        body: Box::new(move |out| run.page(&sink, start..end, out)),
+    }
+}
+
+/// `GET /stats`: the chunk cache's size and hit rate, as JSON.
+fn serve_stats(paged: Option<&PagedRun>) -> serve::Reply<'static> {
+    let stats = serde_json::json!({
+        "run_key": paged.map(|run| run.fingerprint()),
+        "cache": paged.map(|run| run.cache().stats()),
+    });
+    serve::Reply::Stream {
+        content_type: "application/json",
+        headers: Vec::new(),
+        pulled: None,
+        body: Box::new(move |out| writeln!(out, "{:#}", stats)),
+    }
+}
+
+/// `POST /reserve?count=K[&claimant=NAME][&format=F]`: claims the next K
+/// records of the corpus and returns them. `refetch=FIRST` instead of
+/// `count` returns the claim starting at FIRST again, if it was never
+/// delivered in full.
+#[cfg(feature = "reservations")]
+fn serve_reservation<'a>(
+    request: &serve::Request,
+    max_count: usize,
+    corpus: &Path,
+    reader: &'a CorpusReader,
+    store: &'a std::sync::Mutex<ReservationStore>,
+) -> serve::Reply<'a> {
+    let bad = |message: String| serve::Reply::Error(400, message);
+    let (mut count, mut refetch, mut claimant, mut format) = (None, None, None, Format::Raw);
+    for (name, value) in &request.query {
+        match name.as_str() {
+            "count" => match parse_count(value) {
+                Ok(n) => count = Some(n),
+                Err(e) => return bad(format!("count: {}", e)),
+            },
+            "refetch" => match value.parse::<usize>() {
+                Ok(first) => refetch = Some(first),
+                Err(_) => return bad(format!("refetch: `{}` is not a record index", value)),
+            },
+            "claimant" => claimant = Some(value.clone()),
+            "format" => match parse_record_format(value) {
+                Ok(f) => format = f,
+                Err(e) => return bad(e),
+            },
+            _ => return bad(format!("unknown parameter `{}`", name)),
+        }
+    }
+    let Ok(mut locked) = store.lock() else {
+        return serve::Reply::Error(500, "the reservation store is unusable".to_string());
+    };
+    let claimed = match (count, refetch) {
+        (Some(count), None) if count > max_count => {
+            return bad(format!("count is limited to {}", max_count))
+        }
+        (Some(count), None) => locked.reserve(count, claimant.as_deref()),
+        (None, Some(first)) => locked.undelivered(first, claimant.as_deref()),
+        (None, None) => return bad("count or refetch is required".to_string()),
+        (Some(_), Some(_)) => return bad("give count or refetch, not both".to_string()),
+    };
+    drop(locked);
+    let claimed = match claimed {
+        Ok(claimed) => claimed,
+        Err(e @ ReservationError::NoClaim { .. }) => {
+            return serve::Reply::Error(404, e.to_string())
+        }
+        Err(e @ (ReservationError::Exhausted { .. } | ReservationError::Delivered { .. })) => {
+            return serve::Reply::Error(409, e.to_string())
+        }
+        Err(e) => return serve::Reply::Error(500, e.to_string()),
+    };
+    serve::Reply::Stream {
+        content_type: content_type(format, reader.manifest().binary),
+        headers: vec![("X-Reserved", format!("{}..{}", claimed.start, claimed.end))],
+        pulled: Some(serve::Pulled {
+            source: format!("corpus:{}", corpus.display()),
+            records: claimed.clone(),
+        }),
+        body: Box::new(move |out| {
+            let first = claimed.start;
+            commands::cat_to(reader, Some(claimed), format, out)?;
+            out.flush()?;
+            // Until this is recorded the claimant may fetch the claim again
+            store
+                .lock()
+                .map_err(|_| io::Error::other("the reservation store is unusable"))?
+                .delivered(first)
+                .map_err(io::Error::other)
+        }),
+    }
+}
+
+/// The `Content-Type` of records in `format`.
+fn content_type(format: Format, binary: bool) -> &'static str {
+    match format {
+        Format::Raw if binary => "application/octet-stream",
+        Format::Raw | Format::Hex | Format::Base64 | Format::ExpiringToken | Format::Hashcat => {
+            "text/plain"
+        }
+        Format::Csv => "text/csv",
+        Format::Jsonl => "application/x-ndjson",
+    }
+}
@@ -0,0 +1,518 @@
+//! Records of several secrets: a password, an API key and a TOTP seed, say,
+//! generated together for each index.
+//!
+//! A [`Schema`] names the fields of a record and says what each holds. Each
+//! field is an ordinary run of its own, keyed with lane `field/<name>` of
+//! the run's key (see [`Lane`]), so the fields of one record are
+//! independent of each other and of every other field, and a field keeps
+//! its values when fields are added, removed or reordered around it.
+//!
+//! A schema file is TOML with a table per field, in column order. A field
+//! takes a `charset` and `length`, a `template`, or passphrase settings
+//! (`words`, `separator`, `capitalize`, `digits`); an empty table is a raw
+//! 16-byte block, written as hex.
+//!
+//! ```text
+//! [password]
+//! charset = "alnum+symbols"
+//! length = 24
+//!
+//! [totp_seed]
+//! charset = "custom:ABCDEFGHIJKLMNOPQRSTUVWXYZ234567"
+//! length = 32
+//!
+//! [recovery]
+//! words = 5
+//! capitalize = "title"
+//!
+//! [device_secret]
+//! ```
+
+use std::sync::Mutex;
+
+use indexmap::IndexMap;
+use serde::Deserialize;
+
+use crate::format::{decimal_digits, may_need_escaping, push_csv_field, push_json_string};
+use crate::{
+    settings, Capitalization, Charset, Encoder, Format, Key, Lane, OutputSink, Passphrase,
+    PasswordGenerator, PasswordGeneratorBuilder, Template,
+};
+
+/// What one field holds.
+#[derive(Clone, Debug)]
+enum Shape {
+    Raw,
+    Encoded(Encoder),
+    Passphrase(Passphrase),
+}
+
+/// One named field of a [`Schema`].
+#[derive(Clone, Debug)]
+pub struct Field {
+    name: String,
+    shape: Shape,
+}
+
+impl Field {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The field's lane of a run key.
+    pub fn lane(&self) -> String {
+        format!("field/{}", self.name)
+    }
+
+    /// `builder`, keyed with the run's key, set up to generate this field:
+    /// its encoding, under its lane of the key.
+    fn configure(&self, builder: PasswordGeneratorBuilder, key: &Key) -> PasswordGeneratorBuilder {
+        let builder = builder.key(Lane::new(&self.lane()).key(key));
+        match &self.shape {
+            Shape::Raw => builder,
+            Shape::Encoded(encoder) => builder.encoder(encoder.clone()),
+            Shape::Passphrase(passphrase) => builder.passphrase(passphrase.clone()),
+        }
+    }
+}
+
+/// The fields of a record, in column order.
+#[derive(Clone, Debug)]
+pub struct Schema {
+    fields: Vec<Field>,
+}
+
+impl Schema {
+    /// Parses a schema file; see the [module docs](self).
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let tables: IndexMap<String, FieldSettings> = settings::parse(text)?;
+        if tables.is_empty() {
+            return Err("a schema needs at least one `[field]`".to_string());
+        }
+        let fields = tables
+            .into_iter()
+            .map(|(name, table)| {
+                let err = |e: String| format!("field `{}`: {}", name, e);
+                validate_name(&name).map_err(err)?;
+                let shape = shape(table).map_err(err)?;
+                Ok(Field { name, shape })
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(Schema { fields })
+    }
+
+    pub fn fields(&self) -> &[Field] {
+        &self.fields
+    }
+
+    /// `builder`, keyed with the run's key, set up to generate the first
+    /// field. The run it builds is what [`sink`](Self::sink) writes the
+    /// other fields alongside.
+    pub fn configure(
+        &self,
+        builder: PasswordGeneratorBuilder,
+        key: &Key,
+    ) -> PasswordGeneratorBuilder {
+        self.fields[0].configure(builder, key)
+    }
+
+    /// The sink writing whole records as `format` (CSV or JSON Lines): the
+    /// first field from `run`, built by [`configure`](Self::configure), and
+    /// the others generated alongside it, each laid out like `run` under
+    /// its own lane of `key`.
+    pub fn sink(
+        &self,
+        format: Format,
+        run: &PasswordGenerator,
+        key: &Key,
+    ) -> Result<SchemaSink, String> {
+        if !matches!(format, Format::Csv | Format::Jsonl) {
+            return Err(format!(
+                "a schema's records are written as csv or jsonl, not {}",
+                format
+            ));
+        }
+        let layout = PasswordGenerator::builder()
+            .cipher(run.cipher())
+            .iv_strategy(run.iv_strategy())
+            .count(run.count())
+            .chunk_size(run.chunk_size());
+        let others = self.fields[1..]
+            .iter()
+            .map(|field| {
+                field
+                    .configure(layout.clone(), key)
+                    .build()
+                    .map_err(|e| format!("field `{}`: {}", field.name, e))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let names: Vec<&str> = self.fields.iter().map(|f| f.name.as_str()).collect();
+        let header = match format {
+            Format::Csv => format!("index,{}\n", names.join(",")).into_bytes(),
+            _ => Vec::new(),
+        };
+        let csv = format == Format::Csv;
+        let columns = std::iter::once(run)
+            .chain(&others)
+            .map(|g| (g.is_binary(), column_len(g, csv)))
+            .collect();
+        Ok(SchemaSink {
+            csv,
+            header,
+            names: names.iter().map(|n| n.to_string()).collect(),
+            columns,
+            others,
+            recent: Mutex::new(None),
+        })
+    }
+}
+
+/// Each field's records of one chunk.
+type ChunkFields = Vec<Vec<Vec<u8>>>;
+
+/// Writes the records of a [`Schema`]; see [`Schema::sink`].
+pub struct SchemaSink {
+    csv: bool,
+    header: Vec<u8>,
+    names: Vec<String>,
+    /// Whether each field is raw blocks, and its serialized length if fixed
+    columns: Vec<(bool, Option<usize>)>,
+    /// The generators of every field but the first
+    others: Vec<PasswordGenerator>,
+    /// The other fields' records of the chunk [`write_record`] last needed
+    ///
+    /// [`write_record`]: OutputSink::write_record
+    recent: Mutex<Option<(usize, ChunkFields)>>,
+}
+
+impl SchemaSink {
+    /// Appends one record from its fields' native values, in column order.
+    fn write_row(&self, index: usize, values: &[&[u8]], out: &mut Vec<u8>) {
+        let fields = self.columns.iter().map(|&(binary, _)| binary).zip(values);
+        if self.csv {
+            out.extend_from_slice(index.to_string().as_bytes());
+            for (binary, value) in fields {
+                out.push(b',');
+                push_csv_field(binary, value, out);
+            }
+            out.push(b'\n');
+        } else {
+            out.extend_from_slice(b"{\"index\":");
+            out.extend_from_slice(index.to_string().as_bytes());
+            for (name, (binary, value)) in self.names.iter().zip(fields) {
+                out.extend_from_slice(b",\"");
+                out.extend_from_slice(name.as_bytes());
+                out.extend_from_slice(b"\":");
+                push_json_string(binary, value, out);
+            }
+            out.extend_from_slice(b"}\n");
+        }
+    }
+
+    /// The other fields' records of chunk `chunk_idx`, one vector per field.
+    fn chunk_fields(&self, chunk_idx: usize) -> ChunkFields {
+        self.others
+            .iter()
+            .map(|g| {
+                let data = g.chunk(chunk_idx).data;
+                let mut records = Vec::with_capacity(g.chunk_len(chunk_idx));
+                g.for_each_record(&data, |record| records.push(record.to_vec()));
+                records
+            })
+            .collect()
+    }
+}
+
+/// Serialized length of the values of `generator`'s field, if fixed.
+fn column_len(generator: &PasswordGenerator, csv: bool) -> Option<usize> {
+    let len = match (generator.is_binary(), generator.encoder()) {
+        (true, _) => 32,
+        (false, Some(_)) if !may_need_escaping(generator) => generator.record_width() - 1,
+        _ => return None,
+    };
+    Some(len + if csv { 0 } else { 2 })
+}
+
+impl OutputSink for SchemaSink {
+    fn header(&self) -> &[u8] {
+        &self.header
+    }
+
+    fn write_record(&self, index: usize, record: &[u8], out: &mut Vec<u8>) {
+        let Some(chunk_size) = self.others.first().map(PasswordGenerator::chunk_size) else {
+            return self.write_row(index, &[record], out);
+        };
+        let chunk_idx = index / chunk_size;
+        let mut recent = self.recent.lock().unwrap_or_else(|e| e.into_inner());
+        if recent.as_ref().is_none_or(|(idx, _)| *idx != chunk_idx) {
+            *recent = Some((chunk_idx, self.chunk_fields(chunk_idx)));
+        }
+        let (_, fields) = recent.as_ref().expect("just filled");
+        let at = index % chunk_size;
+        let mut row = vec![record];
+        row.extend(fields.iter().map(|records| &records[at][..]));
+        self.write_row(index, &row, out);
+    }
+
+    fn record_len(&self, index: usize, _record_len: usize) -> Option<usize> {
+        let framing = match self.csv {
+            true => decimal_digits(index) + self.names.len() + 1,
+            false => {
+                let names: usize = self.names.iter().map(|n| n.len() + r#","":"#.len()).sum();
+                r#"{"index":}"#.len() + 1 + decimal_digits(index) + names
+            }
+        };
+        let values = self
+            .columns
+            .iter()
+            .map(|&(_, len)| len)
+            .sum::<Option<usize>>()?;
+        Some(framing + values)
+    }
+
+    fn write_chunk(
+        &self,
+        generator: &PasswordGenerator,
+        first: usize,
+        data: &[u8],
+        out: &mut Vec<u8>,
+    ) {
+        debug_assert_eq!(first % generator.chunk_size(), 0, "chunks start aligned");
+        let fields = self.chunk_fields(first / generator.chunk_size());
+        let mut at = 0;
+        let mut row = Vec::with_capacity(self.columns.len());
+        generator.for_each_record(data, |record| {
+            row.clear();
+            row.push(record);
+            row.extend(fields.iter().map(|records| &records[at][..]));
+            self.write_row(first + at, &row, out);
+            at += 1;
+        });
+    }
+}
+
+/// A field's table in a schema file.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields, expecting = "a `[field]` table")]
+struct FieldSettings {
+    charset: Option<String>,
+    length: Option<usize>,
+    template: Option<String>,
+    words: Option<usize>,
+    separator: Option<String>,
+    capitalize: Option<String>,
+    digits: Option<usize>,
+}
+
+/// Field names are column names and JSON keys, so they keep to
+/// identifier characters.
+fn validate_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || !name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_') {
+        return Err(format!(
+            "field names are letters, digits and `_`, not `{}`",
+            name
+        ));
+    }
+    if name == "index" {
+        return Err("`index` is the record's own column".to_string());
+    }
+    Ok(())
+}
+
+/// The shape a field's settings describe.
+fn shape(table: FieldSettings) -> Result<Shape, String> {
+    let FieldSettings {
+        charset,
+        length,
+        template,
+        words,
+        separator,
+        capitalize,
+        digits,
+    } = table;
+    let passphrase = words.is_some() || separator.is_some() || capitalize.is_some();
+    match (charset, template, passphrase) {
+        (None, None, false) if length.is_none() && digits.is_none() => Ok(Shape::Raw),
+        (Some(charset), None, false) if digits.is_none() => {
+            let length = length.unwrap_or(16);
+            if !(1..=4096).contains(&length) {
+                return Err("`length` is 1 to 4096".to_string());
+            }
+            Ok(Shape::Encoded(Encoder::new(
+                &Charset::parse(&charset)?,
+                length,
+            )))
+        }
+        (None, Some(template), false) if length.is_none() && digits.is_none() => Ok(
+            Shape::Encoded(Encoder::from_template(&Template::parse(&template)?)),
+        ),
+        (None, None, true) if length.is_none() => {
+            let capitalize = match capitalize {
+                Some(c) => Capitalization::parse(&c)?,
+                None => Capitalization::default(),
+            };
+            Ok(Shape::Passphrase(Passphrase::new(
+                words.unwrap_or(6),
+                separator.as_deref().unwrap_or("-"),
+                capitalize,
+                digits.unwrap_or(0),
+            )?))
+        }
+        _ => Err(
+            "set a `charset` (with `length`), a `template`, or passphrase settings \
+             (`words`, `separator`, `capitalize`, `digits`), not several"
+                .to_string(),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCHEMA: &str = r#"
+        # A device's secrets
+        [password]
+        charset = "custom:ab,\"c"   # needs quoting
+        length = 12
+
+        [pin]
+        template = "C-####"
+
+        [recovery]
+        words = 3
+        capitalize = "title"
+
+        [secret]
+    "#;
+
+    fn run(schema: &Schema, key: &Key, count: usize) -> PasswordGenerator {
+        schema
+            .configure(PasswordGenerator::builder().count(count).chunk_size(7), key)
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn schemas_parse_and_check_their_fields() {
+        let schema = Schema::parse(SCHEMA).unwrap();
+        let names: Vec<&str> = schema.fields().iter().map(Field::name).collect();
+        assert_eq!(names, ["password", "pin", "recovery", "secret"]);
+        assert!(matches!(schema.fields()[3].shape, Shape::Raw));
+
+        for (text, error) in [
+            ("", "at least one"),
+            (
+                "charset = \"hex\"",
+                "line 1: invalid type: string \"hex\", expected a `[field]`",
+            ),
+            ("[a]\n[a]", "line 2: duplicate key"),
+            ("[index]", "own column"),
+            ("[a-b]", "letters, digits"),
+            ("[a]\ncharset = hex", "line 2: string values must be quoted"),
+            (
+                "[a]\nlength = \"8\"\ncharset = \"hex\"",
+                "line 2: invalid type: string \"8\"",
+            ),
+            ("[a]\ncolour = \"red\"", "line 2: unknown field `colour`"),
+            ("[a]\ncharset = \"hex\"\nwords = 4", "not several"),
+            ("[a]\ncharset = \"hex\"\nlength = 0", "1 to 4096"),
+            ("[a]\ncharset = \"nope\"", "unknown charset"),
+        ] {
+            let err = Schema::parse(text).unwrap_err();
+            assert!(err.contains(error), "{:?}: {}", text, err);
+        }
+    }
+
+    #[test]
+    fn fields_come_from_their_own_lanes() {
+        let schema = Schema::parse(SCHEMA).unwrap();
+        let key = Key::from([5; 16]);
+        let g = run(&schema, &key, 20);
+        let sink = schema.sink(Format::Csv, &g, &key).unwrap();
+        let mut out = sink.header().to_vec();
+        for chunk in 0..g.num_chunks() {
+            let chunk = g.chunk(chunk);
+            sink.write_chunk(&g, chunk.first_record, &chunk.data, &mut out);
+        }
+        let text = String::from_utf8(out).unwrap();
+        let mut lines = text.lines();
+        assert_eq!(lines.next(), Some("index,password,pin,recovery,secret"));
+
+        // Each column is the run its field's lane of the key generates
+        let lane = |n: usize| {
+            schema.fields()[n]
+                .configure(PasswordGenerator::builder().count(20).chunk_size(7), &key)
+                .build()
+                .unwrap()
+                .records()
+                .collect::<Vec<_>>()
+        };
+        let passwords: Vec<Vec<u8>> = g.records().collect();
+        let columns = [passwords, lane(1), lane(2), lane(3)];
+        for (i, line) in lines.enumerate() {
+            let mut expected = i.to_string().into_bytes();
+            for (n, column) in columns.iter().enumerate() {
+                expected.push(b',');
+                push_csv_field(n == 3, &column[i], &mut expected);
+            }
+            assert_eq!(line.as_bytes(), expected);
+        }
+        // The password column needed quoting somewhere
+        assert!(text.contains(",\""));
+
+        // A field keeps its values wherever it stands, and differs from the
+        // run key's own records
+        let alone = Schema::parse("[secret]\n").unwrap();
+        let records: Vec<Vec<u8>> = run(&alone, &key, 20).records().collect();
+        assert_eq!(records, columns[3]);
+        let plain = PasswordGenerator::builder().key(key).count(20).build();
+        assert_ne!(plain.unwrap().records().collect::<Vec<_>>(), records);
+    }
+
+    #[test]
+    fn single_records_and_lengths_match_whole_chunks() {
+        let key = Key::from([6; 32]);
+        for (schema, format) in [
+            (SCHEMA, Format::Jsonl),
+            (
+                "[a]\ncharset = \"hex\"\nlength = 10\n[b]\n[c]\ntemplate = \"##\"",
+                Format::Csv,
+            ),
+            ("[a]\ncharset = \"hex\"\n[b]\n", Format::Jsonl),
+        ] {
+            let schema = Schema::parse(schema).unwrap();
+            let g = run(&schema, &key, 30);
+            let sink = schema.sink(format, &g, &key).unwrap();
+            let mut whole = Vec::new();
+            for chunk in 0..g.num_chunks() {
+                let chunk = g.chunk(chunk);
+                sink.write_chunk(&g, chunk.first_record, &chunk.data, &mut whole);
+            }
+            let mut single = Vec::new();
+            for (i, record) in g.records().enumerate() {
+                let start = single.len();
+                sink.write_record(i, &record, &mut single);
+                if let Some(len) = sink.record_len(i, record.len()) {
+                    assert_eq!(single.len() - start, len);
+                }
+            }
+            assert_eq!(
+                String::from_utf8(single).unwrap(),
+                String::from_utf8(whole).unwrap()
+            );
+        }
+        // Passphrases and quoted fields have no fixed length; the others do
+        let fixed = Schema::parse("[a]\ncharset = \"hex\"\n[b]\n").unwrap();
+        let g = run(&fixed, &key, 3);
+        let sink = fixed.sink(Format::Jsonl, &g, &key).unwrap();
+        assert!(sink.record_len(0, 16).is_some());
+        let varied = Schema::parse(SCHEMA).unwrap();
+        let g = run(&varied, &key, 3);
+        let sink = varied.sink(Format::Csv, &g, &key).unwrap();
+        assert_eq!(sink.record_len(0, 12), None);
+
+        let err = fixed.sink(Format::Hex, &g, &key).err().unwrap();
+        assert!(err.contains("csv or jsonl"), "{}", err);
+    }
+}
@@ -0,0 +1,724 @@
+//! `--seal`: a run no one can read until enough of its custodians agree,
+//! for draws and the like whose records must stay unseen until their time.
+//!
+//! Once the manifest is written (and signed), every shard is encrypted
+//! under one fresh 256-bit key and left as `FILE.sealed`. The key is split
+//! into a share per custodian with Shamir's scheme over GF(2^8), so that any
+//! `threshold` shares give it back and fewer tell nothing of it, and each
+//! share is encrypted to its custodian (as in [`recipient`]) beside the
+//! manifest. The key itself is never written down.
+//!
+//! The custodians file gives the threshold, then a `[name]` table per
+//! custodian:
+//!
+//! ```text
+//! threshold = 2
+//!
+//! [alice]
+//! recipient = "age:age1ql3z7hjy54pw3hyww5ayyfg7zqgvc7w3j2elw8zmrj2kg5sfn9aqmcac8p"
+//!
+//! [bob]
+//! recipient = "gpg:bob@example.com"
+//!
+//! [carol]
+//! recipient = "gpg:carol@example.com"
+//! ```
+//!
+//! Each custodian decrypts their own share, a line of text, and hands it
+//! over; `unlock` puts `threshold` of them together, checks the key against
+//! the manifest, and decrypts every shard back to its name. A sealed file is
+//! the shard under ChaCha20 followed by an HMAC-SHA256 of the ciphertext,
+//! with the cipher key, nonce and MAC key drawn from the run's key and the
+//! shard's name by HKDF. The MAC is checked before anything is decrypted.
+
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use chacha20::cipher::{KeyIvInit, StreamCipher};
+use chacha20::ChaCha20;
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use indexmap::IndexMap;
+use mass_password_gen_optimized::{hex, settings, Custodian, Manifest, Seal};
+use serde::Deserialize;
+
+use crate::failure::Failure;
+use crate::permissions;
+use crate::recipient::{self, Recipient};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// What a sealed shard's name has added.
+pub const EXTENSION: &str = "sealed";
+
+/// HKDF info for the keys of a sealed file, before the file's name.
+const INFO: &[u8] = b"mass_password_gen_optimized seal v1 ";
+
+/// What the manifest's key check is the MAC of.
+const CHECK_LABEL: &[u8] = b"mass_password_gen_optimized seal key check";
+
+/// The first word of a share's text.
+const SHARE_TAG: &str = "mpg-share-v1";
+
+/// Bytes encrypted or decrypted at a time.
+const BLOCK: usize = 1 << 20;
+
+const MAC_LEN: usize = 32;
+
+/// A custodians file as written: the threshold, then a table per
+/// custodian, in share order.
+#[derive(Deserialize)]
+struct CustodiansFile {
+    threshold: usize,
+    #[serde(flatten)]
+    holders: IndexMap<String, HolderSettings>,
+}
+
+/// A custodian's table in a custodians file.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, expecting = "a `[name]` table")]
+struct HolderSettings {
+    recipient: String,
+}
+
+/// A `--seal` file: how many shares unlock the run, and who holds them.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Custodians {
+    pub threshold: usize,
+    /// In share order
+    pub holders: Vec<(String, Recipient)>,
+}
+
+impl Custodians {
+    /// Parses a custodians file: `threshold = K`, then a `[name]` table
+    /// per custodian with their `recipient`.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let file: CustodiansFile = settings::parse(text)?;
+        let holders = file
+            .holders
+            .into_iter()
+            .map(|(name, table)| {
+                let err = |msg: String| format!("`[{}]`: {}", name, msg);
+                if name.is_empty()
+                    || !name
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+                {
+                    return Err(err(
+                        "a custodian's name is letters, digits, `-` and `_`, for the name of \
+                         their share's file"
+                            .to_string(),
+                    ));
+                }
+                let recipient = Recipient::parse(&table.recipient).map_err(err)?;
+                Ok((name, recipient))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        let threshold = file.threshold;
+        match (threshold, holders.len()) {
+            (_, n) if n > 255 => Err(format!("{} custodians; at most 255 can hold shares", n)),
+            (0 | 1, _) => Err(format!(
+                "a threshold of {} lets any one custodian unlock the run; it takes at least 2",
+                threshold
+            )),
+            (k, n) if k > n => Err(format!(
+                "a threshold of {} with {} custodians could never be met",
+                k, n
+            )),
+            _ => Ok(Custodians { threshold, holders }),
+        }
+    }
+
+    /// Checks that each custodian's tool is there and knows them.
+    pub fn check(&self) -> io::Result<()> {
+        self.holders
+            .iter()
+            .try_for_each(|(_, recipient)| recipient.check())
+    }
+
+    /// Where each custodian's share goes, beside the manifest at
+    /// `manifest_path` and relative to its directory, before the tool's
+    /// extension.
+    fn share_file(&self, manifest_path: &Path, name: &str) -> PathBuf {
+        let file = manifest_path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy();
+        let stem = file.strip_suffix(".manifest.json").unwrap_or(&file);
+        PathBuf::from(format!("{}.{}.share", stem, name))
+    }
+}
+
+/// The key a run is sealed under.
+pub struct SealKey([u8; 32]);
+
+impl SealKey {
+    /// A fresh key from the operating system's CSPRNG.
+    pub fn random() -> io::Result<Self> {
+        let mut key = [0; 32];
+        getrandom::fill(&mut key).map_err(io::Error::other)?;
+        Ok(SealKey(key))
+    }
+
+    /// The MAC the manifest keeps to check the key by, as hex.
+    pub fn check(&self) -> String {
+        let mut mac = <HmacSha256 as Mac>::new_from_slice(&self.0).expect("any key length");
+        mac.update(CHECK_LABEL);
+        hex(&mac.finalize().into_bytes())
+    }
+
+    /// The cipher and MAC of the file named `name`.
+    fn file_keys(&self, name: &Path) -> (ChaCha20, HmacSha256) {
+        let name = name.file_name().unwrap_or_default().to_string_lossy();
+        let mut okm = [0; 32 + 12 + 32];
+        Hkdf::<Sha256>::new(None, &self.0)
+            .expand_multi_info(&[INFO, name.as_bytes()], &mut okm)
+            .expect("76 bytes is within HKDF-SHA256's limit");
+        let (key, rest) = okm.split_at(32);
+        let (nonce, mac_key) = rest.split_at(12);
+        (
+            ChaCha20::new(key.into(), nonce.into()),
+            <HmacSha256 as Mac>::new_from_slice(mac_key).expect("any key length"),
+        )
+    }
+
+    /// Encrypts the file at `path` beside it, at `path` with `.sealed`
+    /// added and the same mode, and removes it once the sealed file is
+    /// whole.
+    pub fn seal_file(&self, path: &Path) -> io::Result<PathBuf> {
+        let sealed = sealed_path(path);
+        let (mut cipher, mut mac) = self.file_keys(path);
+        let mut input = BufReader::new(File::open(path)?);
+        let mode = permissions::mode_of(path)?;
+        let mut output = BufWriter::new(permissions::create(&sealed, mode)?);
+        let mut block = vec![0; BLOCK];
+        loop {
+            let n = read_block(&mut input, &mut block)?;
+            if n == 0 {
+                break;
+            }
+            cipher.apply_keystream(&mut block[..n]);
+            mac.update(&block[..n]);
+            output.write_all(&block[..n])?;
+        }
+        output.write_all(&mac.finalize().into_bytes())?;
+        output
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .sync_all()?;
+        fs::remove_file(path)?;
+        Ok(sealed)
+    }
+
+    /// Decrypts the sealed file of `path` back to `path`, with its mode,
+    /// once its MAC checks out, and removes the sealed file.
+    pub fn unseal_file(&self, path: &Path) -> io::Result<()> {
+        let sealed = sealed_path(path);
+        let (mut cipher, mut mac) = self.file_keys(path);
+        let len = fs::metadata(&sealed)?.len();
+        let body = len.checked_sub(MAC_LEN as u64).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is cut short", sealed.display()),
+            )
+        })?;
+        let mut input = BufReader::new(File::open(&sealed)?);
+        let mut block = vec![0; BLOCK];
+        let mut body_in = (&mut input).take(body);
+        loop {
+            let n = read_block(&mut body_in, &mut block)?;
+            if n == 0 {
+                break;
+            }
+            mac.update(&block[..n]);
+        }
+        let mut tag = [0; MAC_LEN];
+        input.read_exact(&mut tag)?;
+        mac.verify_slice(&tag).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} does not match its MAC: it was altered, or sealed under another key or \
+                     name",
+                    sealed.display()
+                ),
+            )
+        })?;
+        let mut input = BufReader::new(File::open(&sealed)?).take(body);
+        let mut output = BufWriter::new(permissions::create(path, permissions::mode_of(&sealed)?)?);
+        loop {
+            let n = read_block(&mut input, &mut block)?;
+            if n == 0 {
+                break;
+            }
+            cipher.apply_keystream(&mut block[..n]);
+            output.write_all(&block[..n])?;
+        }
+        output
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .sync_all()?;
+        fs::remove_file(&sealed)
+    }
+}
+
+/// `path` with `.sealed` added.
+pub fn sealed_path(path: &Path) -> PathBuf {
+    let mut sealed = OsString::from(path.as_os_str());
+    sealed.push(".");
+    sealed.push(EXTENSION);
+    PathBuf::from(sealed)
+}
+
+/// Fills as much of `block` as `input` has left.
+fn read_block(input: &mut impl Read, block: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < block.len() {
+        match input.read(&mut block[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
+
+/// A sealing under way: the key, and the [`Seal`] the manifest records.
+pub struct Sealing<'a> {
+    key: SealKey,
+    custodians: &'a Custodians,
+    pub seal: Seal,
+}
+
+impl<'a> Sealing<'a> {
+    /// A fresh key for the run whose manifest goes to `manifest_path`.
+    pub fn new(custodians: &'a Custodians, manifest_path: &Path) -> io::Result<Self> {
+        let key = SealKey::random()?;
+        let seal = Seal {
+            threshold: custodians.threshold,
+            custodians: custodians
+                .holders
+                .iter()
+                .map(|(name, recipient)| Custodian {
+                    name: name.clone(),
+                    recipient: recipient.to_string(),
+                    share: recipient.encrypted_path(&custodians.share_file(manifest_path, name)),
+                })
+                .collect(),
+            key_check: key.check(),
+        };
+        Ok(Sealing {
+            key,
+            custodians,
+            seal,
+        })
+    }
+
+    /// What the shards record as encrypted to: `seal:K-of-N`.
+    pub fn label(&self) -> String {
+        format!(
+            "seal:{}-of-{}",
+            self.custodians.threshold,
+            self.custodians.holders.len()
+        )
+    }
+
+    /// Seals the shards at `paths` and writes each custodian's share to
+    /// them in `dir`, the manifest's directory.
+    pub fn finish(self, dir: &Path, paths: &[PathBuf]) -> io::Result<()> {
+        for path in paths {
+            self.key.seal_file(path)?;
+        }
+        let shares = split(&self.key, &self.seal)?;
+        for ((share, (_, recipient)), custodian) in shares
+            .iter()
+            .zip(&self.custodians.holders)
+            .zip(&self.seal.custodians)
+        {
+            recipient.encrypt(
+                format!("{}\n", share).as_bytes(),
+                &dir.join(&custodian.share),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// One custodian's share of a sealed run's key: the key's polynomials at
+/// `x`, with the threshold and the start of the key check, which tell
+/// shares of different seals apart.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Share {
+    check: String,
+    threshold: usize,
+    x: u8,
+    y: [u8; 32],
+}
+
+/// Characters of the key check a share carries.
+const SHARE_CHECK_LEN: usize = 16;
+
+impl Share {
+    /// Parses a share as [`Display`](std::fmt::Display) writes it.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let invalid = || format!("not a share of a sealed run: {:?}", s);
+        let words: Vec<&str> = s.split_whitespace().collect();
+        let [SHARE_TAG, check, threshold, x, y] = words[..] else {
+            return Err(invalid());
+        };
+        let y: Vec<u8> = (0..y.len())
+            .step_by(2)
+            .map(|i| y.get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok()))
+            .collect::<Option<_>>()
+            .ok_or_else(invalid)?;
+        Ok(Share {
+            check: check.to_string(),
+            threshold: threshold.parse().map_err(|_| invalid())?,
+            x: x.parse().ok().filter(|&x| x > 0).ok_or_else(invalid)?,
+            y: y.try_into().map_err(|_| invalid())?,
+        })
+    }
+
+    /// Which custodian holds this share, counted from 1.
+    pub fn number(&self) -> usize {
+        self.x as usize
+    }
+}
+
+impl std::fmt::Display for Share {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} {} {} {}",
+            SHARE_TAG,
+            self.check,
+            self.threshold,
+            self.x,
+            hex(&self.y)
+        )
+    }
+}
+
+/// Splits `key` into a share per custodian of `seal`.
+pub fn split(key: &SealKey, seal: &Seal) -> io::Result<Vec<Share>> {
+    // Each byte of the key is the constant term of a polynomial of degree
+    // threshold - 1 with random coefficients
+    let mut coefficients = vec![[0u8; 32]; seal.threshold - 1];
+    for c in &mut coefficients {
+        getrandom::fill(c).map_err(io::Error::other)?;
+    }
+    Ok((1..=seal.custodians.len() as u8)
+        .map(|x| {
+            let mut y = [0; 32];
+            for (i, y) in y.iter_mut().enumerate() {
+                *y = coefficients
+                    .iter()
+                    .rev()
+                    .fold(0, |acc, c| gf_mul(acc, x) ^ c[i]);
+                *y = gf_mul(*y, x) ^ key.0[i];
+            }
+            Share {
+                check: seal.key_check[..SHARE_CHECK_LEN].to_string(),
+                threshold: seal.threshold,
+                x,
+                y,
+            }
+        })
+        .collect())
+}
+
+/// The key `shares` of `seal` give back, once there are enough of them and
+/// it checks out.
+pub fn combine(shares: &[Share], seal: &Seal) -> Result<SealKey, String> {
+    let mut distinct: Vec<&Share> = Vec::new();
+    for share in shares {
+        if share.check != seal.key_check[..SHARE_CHECK_LEN] || share.threshold != seal.threshold {
+            return Err(format!(
+                "share {} is of another seal than this run's",
+                share.x
+            ));
+        }
+        if share.number() > seal.custodians.len() {
+            return Err(format!("share {} is of no custodian of this run", share.x));
+        }
+        if !distinct.iter().any(|seen| seen.x == share.x) {
+            distinct.push(share);
+        }
+    }
+    if distinct.len() < seal.threshold {
+        return Err(format!(
+            "{} different share{} of the {} it takes to unlock the run",
+            distinct.len(),
+            if distinct.len() == 1 { "" } else { "s" },
+            seal.threshold
+        ));
+    }
+    // Lagrange interpolation at 0, where in GF(2^8) subtraction is XOR
+    let chosen = &distinct[..seal.threshold];
+    let mut key = [0u8; 32];
+    for (j, share) in chosen.iter().enumerate() {
+        let basis = chosen
+            .iter()
+            .enumerate()
+            .filter(|&(m, _)| m != j)
+            .fold(1, |acc, (_, other)| {
+                gf_mul(acc, gf_mul(other.x, gf_inv(other.x ^ share.x)))
+            });
+        for (k, y) in key.iter_mut().zip(&share.y) {
+            *k ^= gf_mul(basis, *y);
+        }
+    }
+    let key = SealKey(key);
+    match key.check() == seal.key_check {
+        true => Ok(key),
+        false => Err(
+            "the shares give a key that does not match the manifest's key check; \
+                      one of them is damaged"
+                .to_string(),
+        ),
+    }
+}
+
+/// What `unlock` did.
+pub struct Unlocked {
+    /// Whose shares gave the key back, in share order
+    pub custodians: Vec<String>,
+    /// Shards decrypted
+    pub opened: usize,
+    /// Shards already decrypted by an earlier `unlock`
+    pub already: usize,
+}
+
+/// Opens the sealed run whose manifest is at `manifest_path` with the
+/// shares in `share_files`: as each custodian decrypted it, or still
+/// encrypted to them (`.age` with `identity`, `.gpg` with the keyring).
+/// The manifest is left as it is, so the run can be verified once open.
+pub fn unlock(
+    manifest_path: &Path,
+    share_files: &[PathBuf],
+    identity: Option<&Path>,
+) -> Result<Unlocked, Box<dyn std::error::Error>> {
+    let manifest = Manifest::load(manifest_path)?;
+    let seal = manifest.seal.as_ref().ok_or_else(|| {
+        Failure::Other(format!(
+            "{} is not of a sealed run",
+            manifest_path.display()
+        ))
+    })?;
+    let shares = share_files
+        .iter()
+        .map(|path| {
+            let encrypted = path.extension().is_some_and(|e| e == "age" || e == "gpg");
+            let text = match encrypted {
+                true => recipient::decrypt(path, identity)?,
+                false => fs::read(path)?,
+            };
+            Share::parse(String::from_utf8_lossy(&text).trim())
+                .map_err(|e| format!("{}: {}", path.display(), e).into())
+        })
+        .collect::<Result<Vec<_>, Box<dyn std::error::Error>>>()?;
+    let key = combine(&shares, seal).map_err(Failure::Key)?;
+    let mut custodians: Vec<usize> = shares.iter().map(Share::number).collect();
+    custodians.sort_unstable();
+    custodians.dedup();
+
+    let dir = manifest_path.parent().unwrap_or(Path::new(""));
+    let (mut opened, mut already) = (0, 0);
+    for shard in &manifest.shards {
+        let path = dir.join(&shard.path);
+        if sealed_path(&path).exists() {
+            key.unseal_file(&path).map_err(|e| match e.kind() {
+                io::ErrorKind::InvalidData => Failure::Verification(e.to_string()),
+                _ => Failure::Io(e),
+            })?;
+            opened += 1;
+        } else if path.exists() {
+            already += 1;
+        } else {
+            return Err(Failure::Verification(format!(
+                "{} is missing, sealed or not",
+                path.display()
+            ))
+            .into());
+        }
+    }
+    Ok(Unlocked {
+        custodians: custodians
+            .into_iter()
+            .map(|x| seal.custodians[x - 1].name.clone())
+            .collect(),
+        opened,
+        already,
+    })
+}
+
+/// Multiplies in GF(2^8) with the AES polynomial.
+fn gf_mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0;
+    while b != 0 {
+        if b & 1 != 0 {
+            product ^= a;
+        }
+        let carry = a & 0x80 != 0;
+        a <<= 1;
+        if carry {
+            a ^= 0x1b;
+        }
+        b >>= 1;
+    }
+    product
+}
+
+/// The inverse of nonzero `a` in GF(2^8): a^254.
+fn gf_inv(a: u8) -> u8 {
+    let (mut result, mut base, mut exp) = (1, a, 254u8);
+    while exp != 0 {
+        if exp & 1 != 0 {
+            result = gf_mul(result, base);
+        }
+        base = gf_mul(base, base);
+        exp >>= 1;
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sealed_by(threshold: usize, custodians: usize, key: &SealKey) -> Seal {
+        Seal {
+            threshold,
+            custodians: (0..custodians)
+                .map(|n| Custodian {
+                    name: format!("c{}", n),
+                    recipient: "gpg:c".to_string(),
+                    share: PathBuf::from(format!("run.c{}.share.gpg", n)),
+                })
+                .collect(),
+            key_check: key.check(),
+        }
+    }
+
+    #[test]
+    fn any_threshold_of_shares_unlocks() {
+        assert_eq!(gf_mul(0x57, 0x83), 0xc1);
+        assert!((1..=255u8).all(|a| gf_mul(a, gf_inv(a)) == 1));
+
+        let key = SealKey::random().unwrap();
+        let seal = sealed_by(3, 5, &key);
+        let shares = split(&key, &seal).unwrap();
+        let text: Vec<String> = shares.iter().map(Share::to_string).collect();
+        let shares: Vec<Share> = text.iter().map(|s| Share::parse(s).unwrap()).collect();
+        for chosen in [[0, 1, 2], [4, 2, 0], [1, 3, 4]] {
+            let some: Vec<Share> = chosen.iter().map(|&i| shares[i].clone()).collect();
+            assert_eq!(combine(&some, &seal).unwrap().0, key.0);
+        }
+        let err = combine(
+            &[shares[0].clone(), shares[1].clone(), shares[1].clone()],
+            &seal,
+        );
+        assert!(err.err().unwrap().contains("2 different shares of the 3"));
+
+        let mut damaged = shares[..3].to_vec();
+        damaged[1].y[7] ^= 1;
+        assert!(combine(&damaged, &seal).err().unwrap().contains("damaged"));
+        let other = split(
+            &SealKey::random().unwrap(),
+            &sealed_by(3, 5, &SealKey::random().unwrap()),
+        );
+        let mut mixed = shares[..2].to_vec();
+        mixed.push(other.unwrap()[2].clone());
+        assert!(combine(&mixed, &seal)
+            .err()
+            .unwrap()
+            .contains("another seal"));
+        assert!(Share::parse("mpg-share-v1 00 2 0 00").is_err());
+    }
+
+    #[test]
+    fn sealed_files_open_only_whole_and_under_their_names() {
+        let dir = std::env::temp_dir().join(format!("mpg_seal_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("run-00001.txt");
+        let records: Vec<u8> = (0..3 * BLOCK / 2).map(|i| (i % 251) as u8).collect();
+        fs::write(&path, &records).unwrap();
+        let key = SealKey::random().unwrap();
+        let sealed = key.seal_file(&path).unwrap();
+        assert!(!path.exists());
+        assert_eq!(fs::read(&sealed).unwrap().len(), records.len() + MAC_LEN);
+
+        // Another name, another key, or one altered byte is refused
+        let renamed = dir.join("run-00002.txt");
+        fs::copy(&sealed, sealed_path(&renamed)).unwrap();
+        assert!(key.unseal_file(&renamed).is_err());
+        assert!(SealKey::random().unwrap().unseal_file(&path).is_err());
+        let mut altered = fs::read(&sealed).unwrap();
+        altered[BLOCK + 5] ^= 1;
+        fs::write(sealed_path(&renamed), &altered).unwrap();
+        assert!(key.unseal_file(&renamed).is_err());
+        assert!(!renamed.exists());
+
+        key.unseal_file(&path).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), records);
+        assert!(!sealed.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn custodians_files_name_a_threshold() {
+        let custodians = Custodians::parse(
+            "threshold = 2\n\
+             [alice]\n\
+             recipient = \"gpg:alice@example.com\"\n\
+             [bob]\n\
+             recipient = \"age:age1qyqszqgpqyqszqgpqyqszqgpqyqszqgp\"\n",
+        )
+        .unwrap();
+        assert_eq!(custodians.threshold, 2);
+        let names: Vec<&str> = custodians.holders.iter().map(|(n, _)| n.as_str()).collect();
+        assert_eq!(names, ["alice", "bob"]);
+        assert_eq!(
+            custodians.share_file(Path::new("out/pw.manifest.json"), "bob"),
+            Path::new("pw.bob.share")
+        );
+
+        let parse = |text: &str| Custodians::parse(text).unwrap_err();
+        let two = "[a]\nrecipient = \"gpg:a\"\n[b]\nrecipient = \"gpg:b\"\n";
+        for (text, error) in [
+            (two.to_string(), "missing field `threshold`"),
+            (format!("threshold = 1\n{}", two), "at least 2"),
+            (format!("threshold = 3\n{}", two), "never be met"),
+            (
+                format!("threshold = \"2\"\n{}", two),
+                "line 1: invalid type: string",
+            ),
+            (
+                "threshold = 2\n[\"a b\"]\nrecipient = \"gpg:a\"".to_string(),
+                "letters, digits",
+            ),
+            (
+                "threshold = 2\n[a]\n[a]".to_string(),
+                "line 3: duplicate key",
+            ),
+            (
+                "threshold = 2\n[a]\n[b]".to_string(),
+                "missing field `recipient`",
+            ),
+            (
+                "threshold = 2\n[a]\nkey = \"gpg:a\"".to_string(),
+                "unknown field `key`",
+            ),
+            (
+                "[a]\nthreshold = 2".to_string(),
+                "missing field `threshold`",
+            ),
+        ] {
+            let err = parse(&text);
+            assert!(err.contains(error), "{:?}: {}", text, err);
+        }
+    }
+}
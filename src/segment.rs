@@ -0,0 +1,180 @@
+//! Jobs of more records than one run can hold, up to 2^128 - 1.
+//!
+//! A run counts its records in `usize` and addresses its keystream with
+//! 64-bit nonces and offsets, so one key covers at most 2^64 keystream
+//! bytes. A larger job is planned as a sequence of segments: fixed-size runs
+//! of `segment_len` records (the last may be shorter), each keyed with its
+//! own key: lane `segment/<number>` of the job's key (see [`segment_key`]
+//! and [`Lane`](crate::Lane)). Record `i` of the job is record
+//! `i % segment_len` of segment `i / segment_len`, so indices into the job
+//! are `u128` while each segment is an ordinary run that streams,
+//! checkpoints and verifies on its own.
+//!
+//! ```
+//! use mass_password_gen_optimized::SegmentPlan;
+//!
+//! let plan = SegmentPlan::new(1 << 70, 1 << 40).unwrap();
+//! assert_eq!(plan.segments(), 1 << 30);
+//! assert_eq!(plan.locate((1 << 40) + 5), Some((1, 5)));
+//! ```
+
+use crate::{Key, Lane};
+
+/// A job split into segments.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SegmentPlan {
+    total: u128,
+    segment_len: u64,
+}
+
+/// One segment of a [`SegmentPlan`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Segment {
+    /// Position in the plan, from 0
+    pub number: u128,
+    /// Job index of the segment's first record
+    pub first: u128,
+    /// Records in the segment
+    pub count: u64,
+}
+
+impl SegmentPlan {
+    /// `total` records in segments of `segment_len` records.
+    pub fn new(total: u128, segment_len: u64) -> Result<Self, String> {
+        if total == 0 {
+            return Err("a job needs at least one record".to_string());
+        }
+        if segment_len == 0 {
+            return Err("a segment needs at least one record".to_string());
+        }
+        Ok(SegmentPlan { total, segment_len })
+    }
+
+    /// Records in the whole job.
+    pub fn total(&self) -> u128 {
+        self.total
+    }
+
+    /// Records per segment, but for the last.
+    pub fn segment_len(&self) -> u64 {
+        self.segment_len
+    }
+
+    /// Number of segments.
+    pub fn segments(&self) -> u128 {
+        self.total.div_ceil(self.segment_len as u128)
+    }
+
+    /// Segment `number`, or `None` past the last.
+    pub fn segment(&self, number: u128) -> Option<Segment> {
+        let first = number.checked_mul(self.segment_len as u128)?;
+        if first >= self.total {
+            return None;
+        }
+        Some(Segment {
+            number,
+            first,
+            count: (self.total - first).min(self.segment_len as u128) as u64,
+        })
+    }
+
+    /// The segment holding job record `index`, and the record's index in
+    /// it.
+    pub fn locate(&self, index: u128) -> Option<(u128, u64)> {
+        (index < self.total).then(|| {
+            let len = self.segment_len as u128;
+            (index / len, (index % len) as u64)
+        })
+    }
+}
+
+/// The key of segment `number` of a job keyed with `key`, as wide as
+/// `key`.
+pub fn segment_key(key: &Key, number: u128) -> Key {
+    Lane::new(&format!("segment/{}", number)).key(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{IvStrategy, PasswordGenerator};
+
+    #[test]
+    fn segments_tile_the_job() {
+        let plan = SegmentPlan::new(25, 10).unwrap();
+        assert_eq!(plan.segments(), 3);
+        let segments: Vec<Segment> = (0..3).map(|n| plan.segment(n).unwrap()).collect();
+        assert_eq!(
+            segments
+                .iter()
+                .map(|s| (s.first, s.count))
+                .collect::<Vec<_>>(),
+            [(0, 10), (10, 10), (20, 5)]
+        );
+        assert_eq!(plan.segment(3), None);
+        assert_eq!(plan.locate(0), Some((0, 0)));
+        assert_eq!(plan.locate(24), Some((2, 4)));
+        assert_eq!(plan.locate(25), None);
+
+        assert_eq!(
+            plan.segment(1),
+            SegmentPlan::new(99, 10).unwrap().segment(1)
+        );
+
+        // Every segment has its own key, decided by the job's key alone
+        let (job, other) = (Key::from([7; 16]), Key::from([8; 16]));
+        assert_ne!(segment_key(&job, 0), segment_key(&job, 1));
+        assert_ne!(segment_key(&job, 0), job);
+        assert_ne!(segment_key(&job, 0), segment_key(&other, 0));
+        assert_eq!(segment_key(&job, 1 << 100), segment_key(&job, 1 << 100));
+        // ...and is as wide as the job's
+        let wide = Key::from([7; 32]);
+        assert_eq!(segment_key(&wide, 0).bits(), 256);
+        assert_ne!(segment_key(&wide, 0)[..16], segment_key(&job, 0)[..]);
+
+        assert!(SegmentPlan::new(0, 10).is_err());
+        assert!(SegmentPlan::new(10, 0).is_err());
+    }
+
+    #[test]
+    fn plans_reach_past_two_to_the_sixty_four() {
+        let plan = SegmentPlan::new(u128::MAX, 1 << 63).unwrap();
+        assert_eq!(plan.segments(), 1 << 65);
+        let last = plan.segment(plan.segments() - 1).unwrap();
+        assert_eq!(
+            (last.first, last.count),
+            (u128::MAX - (1 << 63) + 1, (1 << 63) - 1)
+        );
+        assert_eq!(
+            plan.locate(u128::MAX - 1),
+            Some(((1 << 65) - 1, (1 << 63) - 2))
+        );
+        assert_eq!(plan.segment(plan.segments()), None);
+        assert_eq!(plan.segment(u128::MAX), None);
+    }
+
+    #[test]
+    fn a_segment_is_an_ordinary_run() {
+        let plan = SegmentPlan::new(1000, 300).unwrap();
+        let run = |number| {
+            let segment = plan.segment(number).unwrap();
+            PasswordGenerator::builder()
+                .key(segment_key(&[3; 16].into(), number))
+                .iv_strategy(IvStrategy::Continuous)
+                .count(segment.count as usize)
+                .chunk_size(64)
+                .build()
+                .unwrap()
+        };
+        let job: Vec<Vec<u8>> = (0..plan.segments())
+            .flat_map(|n| run(n).records().collect::<Vec<_>>())
+            .collect();
+        assert_eq!(job.len(), 1000);
+        let (number, offset) = plan.locate(650).unwrap();
+        assert_eq!((number, offset), (2, 50));
+        assert_eq!(run(2).records().nth(50).unwrap(), job[650]);
+        // No two segments share a block
+        let blocks: std::collections::HashSet<&Vec<u8>> = job.iter().collect();
+        assert_eq!(blocks.len(), 1000);
+    }
+}
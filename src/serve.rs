@@ -0,0 +1,744 @@
+//! `serve`: a small HTTP/1.1 server for other services to fetch passwords
+//! from.
+//!
+//! Just enough HTTP for the job, on the standard library alone: one thread
+//! per connection and at most [`MAX_CONNECTIONS`] of them (a client past
+//! that gets a 503), one request per connection, and responses streamed with
+//! chunked transfer encoding as they are generated, so a large batch never
+//! sits in memory. What each route returns is up to the caller's handler;
+//! this module parses requests, enforces the [`Policy`] (bearer token,
+//! per-client rate limit, audit log, and with the `tls` feature HTTPS and
+//! client certificates) and frames the replies.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::ops::Range;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Longest request head (request line and headers) accepted.
+const MAX_HEAD: usize = 16 << 10;
+
+/// Connections served at once.
+pub const MAX_CONNECTIONS: usize = 64;
+
+/// How long a client may take to send its request.
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a client may leave a reply unread before it is dropped.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Bytes per chunk of a streamed body.
+const CHUNK_BYTES: usize = 64 << 10;
+
+/// Clients tracked at once before those whose allowance is full again are
+/// forgotten.
+const TRACKED_CLIENTS: usize = 4096;
+
+/// Who may use the server, and what is kept of their requests. `/health`
+/// is open to anyone, and neither limited nor audited.
+#[derive(Default)]
+pub struct Policy {
+    /// Required as `Authorization: Bearer <token>`
+    pub token: Option<String>,
+    /// Requests each client may make
+    pub rate: Option<RateLimit>,
+    pub audit: Option<AuditLog>,
+    /// Serve HTTPS with this configuration
+    #[cfg(feature = "tls")]
+    pub tls: Option<std::sync::Arc<rustls::ServerConfig>>,
+}
+
+/// The token of a `--token-file`: its text, trimmed.
+pub fn read_token(path: &Path) -> Result<String, String> {
+    let token = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let token = token.trim().to_string();
+    if token.is_empty() {
+        return Err(format!("{}: the token file is empty", path.display()));
+    }
+    Ok(token)
+}
+
+/// `--rate-limit`: requests a client may make in a period, in a burst or
+/// spread out.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RateLimit {
+    pub requests: u32,
+    pub per: Duration,
+}
+
+impl RateLimit {
+    /// Parses `N/s`, `N/min` or `N/h`.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let invalid = || format!("invalid rate `{}` (expected N/s, N/min or N/h)", s);
+        let (requests, per) = s.split_once('/').ok_or_else(invalid)?;
+        let requests: u32 = requests.trim().parse().map_err(|_| invalid())?;
+        let per = match per.trim() {
+            "s" | "sec" | "second" => Duration::from_secs(1),
+            "m" | "min" | "minute" => Duration::from_secs(60),
+            "h" | "hour" => Duration::from_secs(3600),
+            _ => return Err(invalid()),
+        };
+        if requests == 0 {
+            return Err(format!("`{}` would refuse every request", s));
+        }
+        Ok(RateLimit { requests, per })
+    }
+
+    fn per_second(&self) -> f64 {
+        self.requests as f64 / self.per.as_secs_f64()
+    }
+}
+
+/// Each client's allowance: a bucket of up to `requests` that refills at
+/// the limit's rate.
+struct Allowances {
+    limit: RateLimit,
+    clients: Mutex<HashMap<String, (f64, Instant)>>,
+}
+
+impl Allowances {
+    /// Takes one request from `client`'s allowance, or says how long until
+    /// it has one.
+    fn take(&self, client: &str, now: Instant) -> Result<(), Duration> {
+        let (full, rate) = (self.limit.requests as f64, self.limit.per_second());
+        let refilled =
+            |(left, at): (f64, Instant)| full.min(left + (now - at).as_secs_f64() * rate);
+        let mut clients = self.clients.lock().unwrap_or_else(|e| e.into_inner());
+        if clients.len() >= TRACKED_CLIENTS && !clients.contains_key(client) {
+            clients.retain(|_, allowance| refilled(*allowance) < full);
+        }
+        let allowance = clients.entry(client.to_string()).or_insert((full, now));
+        let left = refilled(*allowance);
+        if left < 1.0 {
+            return Err(Duration::from_secs_f64((1.0 - left) / rate));
+        }
+        *allowance = (left - 1.0, now);
+        Ok(())
+    }
+}
+
+/// `--audit-log`: a JSON line per request, appended as it finishes.
+pub struct AuditLog(Mutex<File>);
+
+impl AuditLog {
+    /// Opens `path` for appending, creating it readable by its owner only.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let mut options = std::fs::OpenOptions::new();
+        options.append(true).create(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        Ok(AuditLog(Mutex::new(options.open(path)?)))
+    }
+
+    /// Appends `entry` as a line.
+    pub fn record(&self, entry: serde_json::Value) -> io::Result<()> {
+        let line = format!("{}\n", entry);
+        let mut file = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        file.write_all(line.as_bytes())
+    }
+}
+
+/// Which records a reply hands out, for the audit log.
+pub struct Pulled {
+    /// `key:<fingerprint>` of the key they are generated under, or
+    /// `corpus:<path>` of the corpus they are read from
+    pub source: String,
+    pub records: Range<usize>,
+}
+
+/// A parsed request.
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    /// Query parameters in order, percent-decoded
+    pub query: Vec<(String, String)>,
+    bearer: Option<String>,
+}
+
+/// A streamed response body: writes the whole body to the connection.
+pub type Body<'a> = Box<dyn FnOnce(&mut (dyn Write + Send)) -> io::Result<()> + 'a>;
+
+/// What a handler answers.
+pub enum Reply<'a> {
+    /// 200, with extra headers, and the body streamed in chunks
+    Stream {
+        content_type: &'static str,
+        headers: Vec<(&'static str, String)>,
+        pulled: Option<Pulled>,
+        body: Body<'a>,
+    },
+    /// An error status and a one-line message
+    Error(u16, String),
+}
+
+use crate::listen::{Listener, Peer, Stream};
+
+/// Serves `handler` on `listener` under `policy` until the process is
+/// stopped.
+pub fn run<'a, H>(listener: Listener, policy: &Policy, handler: H) -> io::Result<()>
+where
+    H: Fn(&Request) -> Reply<'a> + Sync,
+{
+    let active = AtomicUsize::new(0);
+    let allowances = policy.rate.map(|limit| Allowances {
+        limit,
+        clients: Mutex::default(),
+    });
+    std::thread::scope(|scope| loop {
+        let (mut stream, peer) = match listener.accept() {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                eprintln!("accept failed: {}", e);
+                continue;
+            }
+        };
+        if active.fetch_add(1, Ordering::AcqRel) >= MAX_CONNECTIONS {
+            active.fetch_sub(1, Ordering::AcqRel);
+            eprintln!(
+                "{}: refused, {} connections open",
+                peer.address, MAX_CONNECTIONS
+            );
+            // Short enough to fit the socket's buffer, but never let a
+            // stalled client hold up accepting
+            let second = Duration::from_secs(1);
+            let _ = stream
+                .set_timeouts(second, second)
+                .and_then(|()| respond_error(&mut stream, 503, "too many connections", &[]));
+            continue;
+        }
+        let (handler, active, allowances) = (&handler, &active, allowances.as_ref());
+        scope.spawn(move || {
+            let guard = Guard { policy, allowances };
+            if let Err(e) = accept(stream, &peer, &guard, handler) {
+                eprintln!("{}: {}", peer.address, e);
+            }
+            active.fetch_sub(1, Ordering::AcqRel);
+        });
+    })
+}
+
+/// A connection's view of the policy.
+struct Guard<'p> {
+    policy: &'p Policy,
+    allowances: Option<&'p Allowances>,
+}
+
+/// Sets up `stream`, with TLS if the policy says so, and answers its
+/// request. A client is its certificate if it presented one.
+fn accept<'a, H>(mut stream: Stream, peer: &Peer, guard: &Guard, handler: &H) -> io::Result<()>
+where
+    H: Fn(&Request) -> Reply<'a> + Sync,
+{
+    stream.set_timeouts(READ_TIMEOUT, WRITE_TIMEOUT)?;
+    #[cfg(feature = "tls")]
+    if let Some(config) = &guard.policy.tls {
+        let (mut stream, certificate) = crate::tls::accept(config, stream)?;
+        let client = certificate.unwrap_or_else(|| peer.client.clone());
+        connection(&mut stream, &peer.address, &client, guard, handler)?;
+        stream.conn.send_close_notify();
+        return stream.flush();
+    }
+    connection(&mut stream, &peer.address, &peer.client, guard, handler)
+}
+
+/// Answers the one request on `stream`, from `client`.
+fn connection<'a, S, H>(
+    stream: &mut S,
+    peer: &str,
+    client: &str,
+    guard: &Guard,
+    handler: &H,
+) -> io::Result<()>
+where
+    S: Read + Write + Send,
+    H: Fn(&Request) -> Reply<'a> + Sync,
+{
+    let started = Instant::now();
+    // Any body past the head is left unread
+    let request = read_request(&mut BufReader::new(&mut *stream));
+    let mut out = BufWriter::with_capacity(CHUNK_BYTES, stream);
+    let request = match request {
+        Ok(request) => request,
+        Err(e) => return respond_error(&mut out, 400, &e.to_string(), &[]),
+    };
+    let (status, pulled) = match reply(&request, client, guard, started, handler) {
+        Err(wait) => {
+            let retry = (
+                "Retry-After",
+                wait.as_secs_f64().ceil().max(1.0).to_string(),
+            );
+            respond_error(&mut out, 429, "rate limit exceeded", &[retry])?;
+            (429, None)
+        }
+        Ok(Reply::Error(status, message)) => {
+            respond_error(&mut out, status, &message, &[])?;
+            (status, None)
+        }
+        Ok(Reply::Stream {
+            content_type,
+            headers,
+            pulled,
+            body,
+        }) => {
+            write!(
+                out,
+                "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nTransfer-Encoding: chunked\r\n\
+                 Connection: close\r\n",
+                content_type
+            )?;
+            for (name, value) in headers {
+                write!(out, "{}: {}\r\n", name, value)?;
+            }
+            out.write_all(b"\r\n")?;
+            let mut chunked = Chunked(out);
+            // An error now can only cut the body short, which the missing
+            // last chunk tells the client
+            if let Err(e) = body(&mut chunked).and_then(|()| chunked.finish()) {
+                audit(
+                    guard,
+                    client,
+                    &request,
+                    200,
+                    pulled.map(|p| (p, false)),
+                    started,
+                )?;
+                return Err(e);
+            }
+            (200, pulled.map(|p| (p, true)))
+        }
+    };
+    eprintln!(
+        "{} {} {} {} in {:.2?}",
+        peer,
+        request.method,
+        request.path,
+        status,
+        started.elapsed()
+    );
+    audit(guard, client, &request, status, pulled, started)
+}
+
+/// The reply to `request`: `/health` for anyone, then the rate limit
+/// (the wait until `client` may ask again if over it), the token, and the
+/// handler.
+fn reply<'a, H>(
+    request: &Request,
+    client: &str,
+    guard: &Guard,
+    now: Instant,
+    handler: &H,
+) -> Result<Reply<'a>, Duration>
+where
+    H: Fn(&Request) -> Reply<'a> + Sync,
+{
+    if request.path == "/health" {
+        return Ok(Reply::Stream {
+            content_type: "text/plain",
+            headers: Vec::new(),
+            pulled: None,
+            body: Box::new(|out| out.write_all(b"ok\n")),
+        });
+    }
+    if let Some(allowances) = guard.allowances {
+        allowances.take(client, now)?;
+    }
+    let token = guard.policy.token.as_deref();
+    if token.is_some_and(|token| !request.bearer.as_deref().is_some_and(|b| same(b, token))) {
+        return Ok(Reply::Error(
+            401,
+            "missing or wrong bearer token".to_string(),
+        ));
+    }
+    Ok(handler(request))
+}
+
+/// Appends the request to the audit log, if there is one: with the
+/// records it pulled and whether they were all sent.
+fn audit(
+    guard: &Guard,
+    client: &str,
+    request: &Request,
+    status: u16,
+    pulled: Option<(Pulled, bool)>,
+    started: Instant,
+) -> io::Result<()> {
+    let Some(log) = &guard.policy.audit else {
+        return Ok(());
+    };
+    if request.path == "/health" {
+        return Ok(());
+    }
+    let at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0.0, |d| d.as_secs_f64());
+    let mut entry = serde_json::json!({
+        "at": (at * 1000.0).round() / 1000.0,
+        "client": client,
+        "method": request.method,
+        "path": request.path,
+        "status": status,
+        "ms": started.elapsed().as_millis() as u64,
+    });
+    if let Some((pulled, complete)) = pulled {
+        entry["source"] = pulled.source.into();
+        entry["first"] = pulled.records.start.into();
+        entry["end"] = pulled.records.end.into();
+        entry["complete"] = complete.into();
+    }
+    log.record(entry)
+}
+
+fn respond_error(
+    out: &mut impl Write,
+    status: u16,
+    message: &str,
+    headers: &[(&str, String)],
+) -> io::Result<()> {
+    let reason = match status {
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "Error",
+    };
+    let body = format!("{}\n", message);
+    write!(
+        out,
+        "HTTP/1.1 {} {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n",
+        status,
+        reason,
+        body.len()
+    )?;
+    if status == 401 {
+        out.write_all(b"WWW-Authenticate: Bearer\r\n")?;
+    }
+    for (name, value) in headers {
+        write!(out, "{}: {}\r\n", name, value)?;
+    }
+    write!(out, "\r\n{}", body)?;
+    out.flush()
+}
+
+/// Reads the request line and headers; any body is ignored.
+fn read_request(input: &mut impl BufRead) -> io::Result<Request> {
+    let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidData, what.to_string());
+    let mut head = Vec::new();
+    let mut lines = Vec::new();
+    loop {
+        let start = head.len();
+        let n = input
+            .by_ref()
+            .take((MAX_HEAD - start) as u64)
+            .read_until(b'\n', &mut head)?;
+        if n == 0 || !head.ends_with(b"\n") {
+            return Err(invalid("incomplete or oversized request head"));
+        }
+        let line = String::from_utf8(head[start..].to_vec())
+            .map_err(|_| invalid("request head is not UTF-8"))?;
+        let line = line.trim_end_matches(['\r', '\n']).to_string();
+        if line.is_empty() {
+            break;
+        }
+        lines.push(line);
+    }
+    let mut request_line = lines
+        .first()
+        .ok_or_else(|| invalid("empty request"))?
+        .split(' ');
+    let (Some(method), Some(target), Some(version)) = (
+        request_line.next(),
+        request_line.next(),
+        request_line.next(),
+    ) else {
+        return Err(invalid("malformed request line"));
+    };
+    if !version.starts_with("HTTP/1.") {
+        return Err(invalid("only HTTP/1.x is served"));
+    }
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let query = query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            Ok((decode(name)?, decode(value)?))
+        })
+        .collect::<Result<_, String>>()
+        .map_err(|e| invalid(&e))?;
+    let bearer = lines[1..].iter().find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        if !name.trim().eq_ignore_ascii_case("authorization") {
+            return None;
+        }
+        value.trim().strip_prefix("Bearer ").map(str::to_string)
+    });
+    Ok(Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        query,
+        bearer,
+    })
+}
+
+/// Percent-decodes a query component, `+` meaning a space.
+fn decode(s: &str) -> Result<String, String> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => out.push(b' '),
+            b'%' => {
+                let hex = s
+                    .get(i + 1..i + 3)
+                    .filter(|h| h.bytes().all(|b| b.is_ascii_hexdigit()))
+                    .and_then(|h| u8::from_str_radix(h, 16).ok())
+                    .ok_or_else(|| format!("bad percent-escape in `{}`", s))?;
+                out.push(hex);
+                i += 2;
+            }
+            b => out.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8(out).map_err(|_| format!("`{}` is not UTF-8 once decoded", s))
+}
+
+/// Compares tokens in time independent of where they differ.
+fn same(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0u8, |diff, (x, y)| diff | (x ^ y))
+            == 0
+}
+
+/// Chunked transfer encoding over a buffered connection.
+struct Chunked<W: Write>(W);
+
+impl<W: Write> Write for Chunked<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !buf.is_empty() {
+            write!(self.0, "{:x}\r\n", buf.len())?;
+            self.0.write_all(buf)?;
+            self.0.write_all(b"\r\n")?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl<W: Write> Chunked<W> {
+    /// Writes the last chunk.
+    fn finish(mut self) -> io::Result<()> {
+        self.0.write_all(b"0\r\n\r\n")?;
+        self.0.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{TcpListener, TcpStream};
+
+    #[test]
+    fn requests_parse_and_bodies_are_chunked() {
+        let head = "GET /passwords?count=3&charset=custom%3Aab%2Bc&separator=+ HTTP/1.1\r\n\
+                    Host: localhost\r\nauthorization: Bearer s3cret\r\n\r\nignored";
+        let request = read_request(&mut head.as_bytes()).unwrap();
+        assert_eq!(
+            (request.method.as_str(), request.path.as_str()),
+            ("GET", "/passwords")
+        );
+        let query: Vec<(&str, &str)> = request
+            .query
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect();
+        assert_eq!(
+            query,
+            [
+                ("count", "3"),
+                ("charset", "custom:ab+c"),
+                ("separator", " ")
+            ]
+        );
+        assert_eq!(request.bearer.as_deref(), Some("s3cret"));
+        assert!(same("s3cret", "s3cret") && !same("s3cret", "s3cres") && !same("s3", "s3cret"));
+        for bad in [
+            "GET /\r\n\r\n",
+            "GET / HTTP/1.1\r\n",
+            "GET /?a=%zz HTTP/1.1\r\n\r\n",
+            "GET /?a=%+f HTTP/1.1\r\n\r\n",
+            "GET /?a=%4 HTTP/1.1\r\n\r\n",
+        ] {
+            assert!(read_request(&mut bad.as_bytes()).is_err(), "{:?}", bad);
+        }
+
+        let mut chunked = Chunked(Vec::new());
+        chunked.write_all(b"hello, ").unwrap();
+        chunked.write_all(b"").unwrap();
+        chunked.write_all(&[b'x'; 20]).unwrap();
+        let Chunked(out) = chunked;
+        let mut expected = b"7\r\nhello, \r\n14\r\n".to_vec();
+        expected.extend_from_slice(&[b'x'; 20]);
+        expected.extend_from_slice(b"\r\n");
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn the_token_guards_every_route_but_health() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        // The server runs until the test process exits
+        std::thread::spawn(move || {
+            let policy = Policy {
+                token: Some("s3cret".to_string()),
+                ..Default::default()
+            };
+            run(Listener::Tcp(listener), &policy, |request| Reply::Stream {
+                content_type: "text/plain",
+                headers: vec![("X-Path", request.path.clone())],
+                pulled: None,
+                body: Box::new(|out| out.write_all(b"hi")),
+            })
+        });
+        let get = |head: &str| {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(head.as_bytes()).unwrap();
+            let mut reply = String::new();
+            stream.read_to_string(&mut reply).unwrap();
+            reply
+        };
+        let status = |reply: &str| reply.lines().next().unwrap().to_string();
+
+        assert_eq!(
+            status(&get("GET /health HTTP/1.1\r\n\r\n")),
+            "HTTP/1.1 200 OK"
+        );
+        let refused = get("GET /passwords HTTP/1.1\r\n\r\n");
+        assert_eq!(status(&refused), "HTTP/1.1 401 Unauthorized");
+        assert!(refused.contains("WWW-Authenticate: Bearer"));
+        assert_eq!(
+            status(&get(
+                "GET /passwords HTTP/1.1\r\nAuthorization: Bearer s3cres\r\n\r\n"
+            )),
+            "HTTP/1.1 401 Unauthorized"
+        );
+        let served = get("GET /passwords?a=1 HTTP/1.1\r\nAuthorization: Bearer s3cret\r\n\r\n");
+        assert_eq!(status(&served), "HTTP/1.1 200 OK");
+        assert!(served.contains("X-Path: /passwords\r\n"));
+        assert!(served.ends_with("\r\n\r\n2\r\nhi\r\n0\r\n\r\n"));
+        assert_eq!(
+            status(&get("GET /?a=%+f HTTP/1.1\r\n\r\n")),
+            "HTTP/1.1 400 Bad Request"
+        );
+    }
+
+    #[test]
+    fn allowances_refill_at_the_limit() {
+        assert_eq!(
+            RateLimit::parse("120/min"),
+            Ok(RateLimit {
+                requests: 120,
+                per: Duration::from_secs(60)
+            })
+        );
+        for bad in ["0/s", "10", "10/day", "-1/s", "x/h"] {
+            assert!(RateLimit::parse(bad).is_err(), "{}", bad);
+        }
+        let allowances = Allowances {
+            limit: RateLimit::parse("2/min").unwrap(),
+            clients: Mutex::default(),
+        };
+        let start = Instant::now();
+        assert_eq!(allowances.take("a", start), Ok(()));
+        assert_eq!(allowances.take("a", start), Ok(()));
+        let wait = allowances.take("a", start).unwrap_err();
+        assert_eq!(wait.as_secs(), 30);
+        // Clients have allowances of their own
+        assert_eq!(allowances.take("b", start), Ok(()));
+        let later = start + Duration::from_secs(31);
+        assert_eq!(allowances.take("a", later), Ok(()));
+        assert!(allowances.take("a", later).is_err());
+    }
+
+    #[test]
+    fn limits_and_audits_each_client() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let log = std::env::temp_dir().join(format!("mpg_audit_{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&log);
+        let policy = Policy {
+            rate: Some(RateLimit::parse("2/h").unwrap()),
+            audit: Some(AuditLog::open(&log).unwrap()),
+            ..Default::default()
+        };
+        std::thread::spawn(move || {
+            run(Listener::Tcp(listener), &policy, |_| Reply::Stream {
+                content_type: "text/plain",
+                headers: Vec::new(),
+                pulled: Some(Pulled {
+                    source: "key:0123".to_string(),
+                    records: 10..12,
+                }),
+                body: Box::new(move |out| out.write_all(b"ab\ncd\n")),
+            })
+        });
+        let get = |path: &str| {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            write!(stream, "GET {} HTTP/1.1\r\n\r\n", path).unwrap();
+            let mut reply = String::new();
+            stream.read_to_string(&mut reply).unwrap();
+            reply
+        };
+        assert!(get("/a").starts_with("HTTP/1.1 200"));
+        assert!(get("/health").starts_with("HTTP/1.1 200"));
+        assert!(get("/b").starts_with("HTTP/1.1 200"));
+        let limited = get("/c");
+        assert!(limited.starts_with("HTTP/1.1 429 Too Many Requests"));
+        assert!(limited.contains("Retry-After: 1800\r\n"));
+
+        // The entry is written once the reply is sent
+        let entries: Vec<serde_json::Value> = std::fs::read_to_string(&log)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0]["client"], "127.0.0.1");
+        assert_eq!(
+            (&entries[0]["path"], &entries[0]["status"]),
+            (&"/a".into(), &200.into())
+        );
+        assert_eq!(
+            (
+                &entries[1]["source"],
+                &entries[1]["first"],
+                &entries[1]["end"]
+            ),
+            (&"key:0123".into(), &10.into(), &12.into())
+        );
+        assert_eq!(entries[1]["complete"], true);
+        assert_eq!(entries[2]["status"], 429);
+        assert!(entries[2].get("source").is_none());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&log).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        std::fs::remove_file(&log).unwrap();
+    }
+}
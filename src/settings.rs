@@ -0,0 +1,47 @@
+//! The TOML files a run is given: `--config` options, a `--policy`, a
+//! `--schema`, `--encrypt-records` slices and `--seal` custodians. Each is
+//! read into its own serde types, so numbers are bare and text is quoted in
+//! all of them, and a file that does not fit is refused by line, as `line
+//! N: ...`.
+
+use std::fmt;
+use std::ops::Range;
+use std::path::Path;
+
+use serde::de::DeserializeOwned;
+
+/// Parses `text` as TOML into `T`.
+pub fn parse<T: DeserializeOwned>(text: &str) -> Result<T, String> {
+    toml::from_str(text).map_err(|e| match e.span() {
+        Some(span) => at(text, span, e.message()),
+        None => e.message().to_string(),
+    })
+}
+
+/// `msg`, about what is at `span` of `text`, prefixed with its line.
+pub fn at(text: &str, span: Range<usize>, msg: impl fmt::Display) -> String {
+    let line = text[..span.start.min(text.len())].matches('\n').count() + 1;
+    format!("line {}: {}", line, msg)
+}
+
+/// Reads the file at `path` with `parse`, naming `path` in any error.
+pub fn read<T>(path: &Path, parse: impl FnOnce(&str) -> Result<T, String>) -> Result<T, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    parse(&text).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn errors_name_their_line() {
+        let parse = |text: &str| parse::<BTreeMap<String, u32>>(text);
+        assert_eq!(parse("a = 1\nb = 2").unwrap().len(), 2);
+        let err = parse("a = 1\n\nb = \"2\"").unwrap_err();
+        assert!(err.starts_with("line 3: "), "{}", err);
+        let err = parse("a = 1\na = 2").unwrap_err();
+        assert!(err.starts_with("line 2: "), "{}", err);
+    }
+}
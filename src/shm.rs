@@ -0,0 +1,163 @@
+//! Shared-memory segments of raw records, for a consumer on the same host
+//! that would otherwise read a corpus back off disk.
+//!
+//! `--output shm://NAME` writes the run into the POSIX shared-memory
+//! segment `NAME`, which on Linux is the file `/dev/shm/NAME`: memory, not
+//! disk. The segment holds raw records after a [header](crate::layout),
+//! so a consumer needs nothing but the name to find the records in it. It
+//! is written under a temporary name and renamed into place once the run
+//! is complete, so a consumer that can open it sees every record.
+//!
+//! ```no_run
+//! use mass_password_gen_optimized::shm::SharedSegment;
+//!
+//! let segment = SharedSegment::open("passwords").unwrap();
+//! for index in 0..segment.len() {
+//!     let record = segment.record(index).unwrap();
+//!     assert_eq!(record.len(), segment.layout().width);
+//! }
+//! ```
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::PathBuf;
+
+use crate::layout::{RawLayout, HEADER_LEN};
+use crate::mmap::ReadMapping;
+
+/// Where Linux keeps POSIX shared-memory segments.
+pub const SHM_DIR: &str = "/dev/shm";
+
+/// The file behind the segment `name`.
+pub fn path(name: &str) -> Result<PathBuf, String> {
+    if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+        return Err(format!("{:?} cannot name a shared-memory segment", name));
+    }
+    if !cfg!(target_os = "linux") {
+        return Err(format!(
+            "shared-memory segments are files in {}, which only Linux has",
+            SHM_DIR
+        ));
+    }
+    Ok(PathBuf::from(SHM_DIR).join(name))
+}
+
+/// The file a segment is written to before it is complete.
+pub fn partial_path(name: &str) -> Result<PathBuf, String> {
+    path(&format!(".{}.partial", name))
+}
+
+/// A segment mapped read-only.
+pub struct SharedSegment {
+    map: ReadMapping,
+    layout: RawLayout,
+    count: usize,
+}
+
+impl SharedSegment {
+    /// Maps the segment `name` and reads its header.
+    pub fn open(name: &str) -> io::Result<Self> {
+        let path = path(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let file = File::open(&path)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        let len = file.metadata()?.len() as usize;
+        let invalid = |e: String| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {}", path.display(), e),
+            )
+        };
+        let mut head = Vec::with_capacity(HEADER_LEN);
+        (&file).take(HEADER_LEN as u64).read_to_end(&mut head)?;
+        let (layout, count) = match RawLayout::from_header(&head) {
+            Ok(Some(described)) => described,
+            Ok(None) => return Err(invalid("not a segment of records".to_string())),
+            Err(e) => return Err(invalid(e)),
+        };
+        let needed = count
+            .checked_mul(layout.stride)
+            .and_then(|records| records.checked_add(layout.header_len()));
+        if needed.is_none_or(|needed| needed > len) {
+            return Err(invalid(format!(
+                "too short to hold the {} records its header counts",
+                count
+            )));
+        }
+        Ok(SharedSegment {
+            map: ReadMapping::new(&file, len)?,
+            layout,
+            count,
+        })
+    }
+
+    /// How the records are laid out.
+    pub fn layout(&self) -> &RawLayout {
+        &self.layout
+    }
+
+    /// Records in the segment.
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Record `index`, without its filler.
+    pub fn record(&self, index: usize) -> Option<&[u8]> {
+        if index >= self.count {
+            return None;
+        }
+        // `open` checked that every record fits
+        let start = self.layout.header_len() + index * self.layout.stride;
+        Some(&self.bytes()[start..start + self.layout.width])
+    }
+
+    /// The whole segment, header included.
+    pub fn bytes(&self) -> &[u8] {
+        self.map.bytes()
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use crate::layout::{Fill, PaddedSink};
+    use crate::PasswordGenerator;
+
+    #[test]
+    fn segments_map_back_to_their_records() {
+        assert!(path("a/b").is_err());
+        assert!(path("..").is_err());
+        let g = PasswordGenerator::builder()
+            .key([5; 16])
+            .count(200)
+            .chunk_size(100)
+            .build()
+            .unwrap();
+        let layout = RawLayout {
+            header: true,
+            ..RawLayout::aligned(16, 32, Fill::Zero).unwrap()
+        };
+        let mut out = Vec::new();
+        g.write_formatted(&PaddedSink::new(layout.clone(), 200), &mut out, |_, _| ())
+            .unwrap();
+        let name = format!("mpg_shm_{}", std::process::id());
+        std::fs::write(path(&name).unwrap(), &out).unwrap();
+        let segment = SharedSegment::open(&name).unwrap();
+        assert_eq!(segment.layout(), &layout);
+        assert_eq!(segment.len(), 200);
+        let records: Vec<_> = (0..200)
+            .map(|i| segment.record(i).unwrap().to_vec())
+            .collect();
+        assert_eq!(records, g.records().collect::<Vec<_>>());
+        assert_eq!(segment.record(200), None);
+        drop(segment);
+
+        // Cut short, it is refused
+        std::fs::write(path(&name).unwrap(), &out[..out.len() - 1]).unwrap();
+        assert!(SharedSegment::open(&name).is_err());
+        std::fs::remove_file(path(&name).unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,229 @@
+//! `shred`: destroys a sharded run and what belongs to it, for when its
+//! retention is up.
+//!
+//! The manifest names everything: each shard, as written or left encrypted
+//! (`.age`, `.gpg`, `.sealed`), with its signature; the shares of a sealed
+//! run; the ledgers beside it of `reserve` and of the shards the
+//! `--on-shard-complete` hook failed on; and the manifest itself and its
+//! signature, which go last, so a shred that fails part way can be run
+//! again. A `--checkpoint` sidecar is not looked for: the
+//! manifest does not say where it is, and a completed run removed it.
+//!
+//! Each file is overwritten with random bytes and synced before it is
+//! unlinked, except on copy-on-write filesystems (btrfs, ZFS, bcachefs) and
+//! log-structured ones (F2FS, NILFS), where an overwrite lands in new blocks
+//! and leaves the old ones as they were; there it is only unlinked, and said
+//! so. On Linux the file's blocks are then punched out, which a filesystem
+//! mounted with `discard` passes on to the SSD as a TRIM.
+
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use chacha20::cipher::{KeyIvInit, StreamCipher};
+use chacha20::ChaCha20;
+use serde::Serialize;
+
+use mass_password_gen_optimized::Manifest;
+
+use crate::{hooks, seal};
+
+/// Bytes overwritten at a time.
+const BLOCK: usize = 1 << 20;
+
+/// Extensions a shard may have been left with, or have beside it.
+const SHARD_EXTENSIONS: [&str; 4] = ["sig", "age", "gpg", seal::EXTENSION];
+
+/// What a file's reservation ledger's SQLite keeps beside it.
+const LEDGER_SUFFIXES: [&str; 4] = ["", "-journal", "-wal", "-shm"];
+
+/// How a file was destroyed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Erasure {
+    /// Overwritten with random bytes, synced, then unlinked
+    Overwritten,
+    /// Only unlinked: its filesystem writes overwrites elsewhere
+    UnlinkedOnly,
+}
+
+/// One file `shred` destroyed.
+#[derive(Debug, Serialize)]
+pub struct Shredded {
+    pub path: PathBuf,
+    pub bytes: u64,
+    pub erasure: Erasure,
+    /// Whether its blocks were punched out, for a TRIM
+    pub trimmed: bool,
+}
+
+/// The files of the run whose manifest is at `manifest_path` that are
+/// there to destroy, in the order they go.
+pub fn artifacts(manifest_path: &Path, manifest: &Manifest) -> Vec<PathBuf> {
+    let dir = manifest_path.parent().unwrap_or(Path::new(""));
+    let mut files = Vec::new();
+    for shard in &manifest.shards {
+        let path = dir.join(&shard.path);
+        files.push(path.clone());
+        files.extend(
+            SHARD_EXTENSIONS
+                .iter()
+                .map(|ext| with_suffix(&path, &format!(".{}", ext))),
+        );
+    }
+    if let Some(seal) = &manifest.seal {
+        files.extend(seal.custodians.iter().map(|c| dir.join(&c.share)));
+    }
+    let ledger = with_suffix(manifest_path, ".reservations");
+    files.extend(
+        LEDGER_SUFFIXES
+            .iter()
+            .map(|suffix| with_suffix(&ledger, suffix)),
+    );
+    files.push(hooks::ledger(manifest_path));
+    files.push(with_suffix(manifest_path, ".sig"));
+    files.push(manifest_path.to_path_buf());
+    files.retain(|path| path.symlink_metadata().is_ok_and(|m| m.is_file()));
+    files
+}
+
+/// Overwrites the file at `path` where that erases it, punches out its
+/// blocks where the filesystem can, and unlinks it.
+pub fn shred_file(path: &Path) -> io::Result<Shredded> {
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    let bytes = file.metadata()?.len();
+    let erasure = match copy_on_write(&file) {
+        true => Erasure::UnlinkedOnly,
+        false => {
+            overwrite(&mut file, bytes)?;
+            Erasure::Overwritten
+        }
+    };
+    let trimmed = punch_hole(&file, bytes);
+    drop(file);
+    fs::remove_file(path)?;
+    Ok(Shredded {
+        path: path.to_path_buf(),
+        bytes,
+        erasure,
+        trimmed,
+    })
+}
+
+/// Writes `bytes` of a ChaCha20 keystream under a random key over `file`
+/// from its start, and syncs it.
+fn overwrite(file: &mut File, bytes: u64) -> io::Result<()> {
+    let mut key = [0; 32];
+    getrandom::fill(&mut key).map_err(io::Error::other)?;
+    let mut stream = ChaCha20::new(&key.into(), &[0; 12].into());
+    let mut block = vec![0; BLOCK];
+    let mut left = bytes;
+    while left > 0 {
+        let n = left.min(BLOCK as u64) as usize;
+        block[..n].fill(0);
+        stream.apply_keystream(&mut block[..n]);
+        file.write_all(&block[..n])?;
+        left -= n as u64;
+    }
+    file.sync_all()
+}
+
+/// Whether `file` is on a filesystem whose overwrites go to new blocks.
+#[cfg(target_os = "linux")]
+fn copy_on_write(file: &File) -> bool {
+    use std::os::fd::AsRawFd;
+    const BTRFS: u64 = 0x9123_683e;
+    const ZFS: u64 = 0x2fc1_2fc1;
+    const BCACHEFS: u64 = 0xca45_1a4e;
+    const F2FS: u64 = 0xf2f5_2010;
+    const NILFS: u64 = 0x3434;
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::fstatfs(file.as_raw_fd(), &mut stat) } != 0 {
+        return false;
+    }
+    // The magic is 32 bits, in a field signed on some targets
+    let magic = stat.f_type as u64 & 0xffff_ffff;
+    [BTRFS, ZFS, BCACHEFS, F2FS, NILFS].contains(&magic)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn copy_on_write(_file: &File) -> bool {
+    false
+}
+
+/// Frees the first `bytes` of `file`'s blocks, which a filesystem mounted
+/// with `discard` passes on as a TRIM; whether it could.
+#[cfg(target_os = "linux")]
+fn punch_hole(file: &File, bytes: u64) -> bool {
+    use std::os::fd::AsRawFd;
+    bytes > 0
+        && unsafe {
+            libc::fallocate(
+                file.as_raw_fd(),
+                libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                0,
+                bytes as libc::off_t,
+            )
+        } == 0
+}
+
+#[cfg(not(target_os = "linux"))]
+fn punch_hole(_file: &File, _bytes: u64) -> bool {
+    false
+}
+
+/// `path` with `suffix` added to its name.
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runs_are_found_and_destroyed_in_full() {
+        let dir = std::env::temp_dir().join(format!("mpg_shred_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let manifest_path = dir.join("pw.manifest.json");
+        let manifest: Manifest = serde_json::from_str(
+            r#"{"version": 1, "format": "raw", "binary": true,
+                "shards": [{"path": "pw-00001.txt", "first": 0},
+                           {"path": "pw-00002.txt", "first": 1000}],
+                "seal": {"threshold": 2, "key_check": "",
+                         "custodians": [{"name": "alice", "recipient": "gpg:alice",
+                                         "share": "pw.alice.share.gpg"}]}}"#,
+        )
+        .unwrap();
+        let present = [
+            "pw-00001.txt.sealed",
+            "pw-00002.txt",
+            "pw-00002.txt.sig",
+            "pw.alice.share.gpg",
+            "pw.manifest.json.reservations",
+            "pw.manifest.json.hook-failures",
+            "pw.manifest.json",
+        ];
+        for name in present {
+            fs::write(dir.join(name), name.repeat(1000)).unwrap();
+        }
+        fs::write(dir.join("pw-00003.txt"), "another run's").unwrap();
+        let found = artifacts(&manifest_path, &manifest);
+        let names: Vec<_> = found.iter().map(|p| p.file_name().unwrap()).collect();
+        assert_eq!(names, present);
+
+        let mut file = OpenOptions::new().write(true).open(&found[1]).unwrap();
+        overwrite(&mut file, 12_000).unwrap();
+        let left = fs::read(&found[1]).unwrap();
+        assert_eq!(left.len(), 12_000);
+        assert!(!left.windows(12).any(|w| w == b"pw-00002.txt"));
+
+        let shredded = shred_file(&found[1]).unwrap();
+        assert_eq!(shredded.bytes, 12_000);
+        assert!(!found[1].exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,205 @@
+//! `--sign-with`: detached signatures for each shard and the manifest, made
+//! once the manifest is written, so whoever receives a corpus can check
+//! where it came from without a checksum sent some other way.
+//!
+//! The signatures are made by the tools that check them: `gpg:KEYID` runs
+//! `gpg --detach-sign` with that secret key, and `ssh:KEYFILE` runs
+//! `ssh-keygen -Y sign` with that private key, in the namespace
+//! [`SSH_NAMESPACE`]. Either way each file gets a `.sig` beside it:
+//!
+//! ```text
+//! gpg --verify pw-00001.txt.sig pw-00001.txt
+//! ssh-keygen -Y verify -f allowed_signers -I ops@example.com \
+//!     -n mass-password-gen -s pw-00001.txt.sig < pw-00001.txt
+//! ```
+
+use std::ffi::OsString;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// Namespace of `ssh:` signatures, which `ssh-keygen -Y verify` needs.
+pub const SSH_NAMESPACE: &str = "mass-password-gen";
+
+const AGE_CANNOT_SIGN: &str = "age keys cannot sign, only encrypt; sign with an SSH key \
+                               (ssh:KEYFILE) or a gpg key (gpg:KEYID)";
+
+/// What `--sign-with` signs with.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Signer {
+    /// A gpg secret key, by ID, fingerprint or user ID
+    Gpg(String),
+    /// An SSH private key file
+    Ssh(PathBuf),
+}
+
+impl Signer {
+    /// Parses `gpg:KEYID` or `ssh:KEYFILE`.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s.split_once(':') {
+            Some(("gpg", key)) if !key.is_empty() => Ok(Signer::Gpg(key.to_string())),
+            Some(("ssh", key)) if !key.is_empty() => Ok(Signer::Ssh(PathBuf::from(key))),
+            Some(("age", _)) => Err(AGE_CANNOT_SIGN.to_string()),
+            _ => Err(format!("expected gpg:KEYID or ssh:KEYFILE, not {:?}", s)),
+        }
+    }
+
+    /// The signature of the file at `path`.
+    pub fn signature_path(path: &Path) -> PathBuf {
+        let mut sig = OsString::from(path.as_os_str());
+        sig.push(".sig");
+        PathBuf::from(sig)
+    }
+
+    /// Checks that the key is there to sign with, before a run that ends
+    /// in signing starts.
+    pub fn check(&self) -> io::Result<()> {
+        let found = match self {
+            Signer::Gpg(key) => Command::new("gpg")
+                .args(["--batch", "--list-secret-keys", key])
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status()
+                .map_err(|e| tool_error("gpg", e))?
+                .success(),
+            Signer::Ssh(key) => std::fs::metadata(key).is_ok_and(|meta| meta.is_file()),
+        };
+        match found {
+            true => Ok(()),
+            false => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("--sign-with: no {} to sign with", self),
+            )),
+        }
+    }
+
+    /// Signs the file at `path`, writing its `.sig`.
+    pub fn sign(&self, path: &Path) -> io::Result<PathBuf> {
+        let sig = Self::signature_path(path);
+        let (tool, mut command) = match self {
+            Signer::Gpg(key) => {
+                let mut command = Command::new("gpg");
+                command
+                    .args(["--batch", "--yes", "--local-user", key, "--detach-sign"])
+                    .arg("--output")
+                    .arg(&sig)
+                    .arg(path);
+                ("gpg", command)
+            }
+            Signer::Ssh(key) => {
+                // ssh-keygen will not overwrite a signature
+                match std::fs::remove_file(&sig) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+                let mut command = Command::new("ssh-keygen");
+                command
+                    .args(["-q", "-Y", "sign", "-n", SSH_NAMESPACE, "-f"])
+                    .arg(key)
+                    .arg(path);
+                ("ssh-keygen", command)
+            }
+        };
+        // Their chatter goes to stderr, clear of records or JSON on stdout
+        let status = command
+            .stdin(Stdio::null())
+            .stdout(io::stderr())
+            .status()
+            .map_err(|e| tool_error(tool, e))?;
+        match status.success() {
+            true => Ok(sig),
+            false => Err(io::Error::other(format!(
+                "{} could not sign {}: {}",
+                tool,
+                path.display(),
+                status
+            ))),
+        }
+    }
+
+    /// Signs each file of `paths`, in turn: a key behind a passphrase
+    /// prompt asks once.
+    pub fn sign_all(&self, paths: &[PathBuf]) -> io::Result<()> {
+        paths.iter().try_for_each(|path| self.sign(path).map(drop))
+    }
+}
+
+impl std::fmt::Display for Signer {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Signer::Gpg(key) => write!(f, "gpg key {}", key),
+            Signer::Ssh(key) => write!(f, "SSH key {}", key.display()),
+        }
+    }
+}
+
+fn tool_error(tool: &str, e: io::Error) -> io::Error {
+    io::Error::new(e.kind(), format!("--sign-with: cannot run {}: {}", tool, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signers_are_named_by_kind() {
+        assert_eq!(
+            Signer::parse("gpg:0xDEADBEEF"),
+            Ok(Signer::Gpg("0xDEADBEEF".to_string()))
+        );
+        assert_eq!(
+            Signer::parse("ssh:/etc/mpg/id_ed25519"),
+            Ok(Signer::Ssh(PathBuf::from("/etc/mpg/id_ed25519")))
+        );
+        assert!(Signer::parse("age:AGE-SECRET-KEY-1")
+            .unwrap_err()
+            .contains("cannot sign"));
+        assert!(Signer::parse("gpg:").is_err());
+        assert!(Signer::parse("/etc/mpg/id_ed25519").is_err());
+    }
+
+    #[test]
+    fn ssh_signatures_check_out() {
+        let dir = std::env::temp_dir().join(format!("mpg_signing_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let key = dir.join("id_ed25519");
+        let made = Command::new("ssh-keygen")
+            .args(["-q", "-t", "ed25519", "-N", "", "-f"])
+            .arg(&key)
+            .status();
+        if !made.is_ok_and(|status| status.success()) {
+            // Nothing to sign with on this machine
+            std::fs::remove_dir_all(&dir).unwrap();
+            return;
+        }
+        let signer = Signer::Ssh(key.clone());
+        signer.check().unwrap();
+        let shard = dir.join("s-00001.txt");
+        std::fs::write(&shard, "records\n").unwrap();
+        signer.sign_all(std::slice::from_ref(&shard)).unwrap();
+        // Signing again replaces the signature
+        let sig = signer.sign(&shard).unwrap();
+        let check = |data: &str| {
+            let mut checker = Command::new("ssh-keygen")
+                .args(["-q", "-Y", "check-novalidate", "-n", SSH_NAMESPACE, "-s"])
+                .arg(&sig)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .spawn()
+                .unwrap();
+            use std::io::Write;
+            checker
+                .stdin
+                .take()
+                .unwrap()
+                .write_all(data.as_bytes())
+                .unwrap();
+            checker.wait().unwrap().success()
+        };
+        assert!(check("records\n"));
+        assert!(!check("records, changed\n"));
+        assert!(Signer::Ssh(dir.join("missing")).check().is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,273 @@
+//! Sizes and plans, worked out before anything is generated: `size` and
+//! `plan`, and the memory check of a run without `--output`. They build
+//! the run's generator under a throwaway key, since no size depends on the
+//! key, so their figures are the ones a real run produces.
+
+use mass_password_gen_optimized::{format, Key, OutputSink, PasswordGenerator, SegmentPlan};
+use sysinfo::{System, SystemExt};
+
+use crate::generate::run_sink;
+use crate::{failure, memory, Args, RecordArgs, GIB};
+
+/// Shards hold whole chunks, so that no chunk straddles two files.
+pub fn check_shard_size(shard_size: usize, chunk_size: usize) -> Result<(), String> {
+    if shard_size.is_multiple_of(chunk_size) {
+        return Ok(());
+    }
+    Err(format!(
+        "--shard-size must be a multiple of the chunk size ({}), so that no chunk \
+         straddles two shards",
+        chunk_size
+    ))
+}
+
+/// The smallest shard size, in whole chunks, that splits `count` records
+/// into at most `shards` shards.
+pub fn shard_size_for(count: usize, chunk_size: usize, shards: usize) -> usize {
+    count.div_ceil(shards.max(1)).div_ceil(chunk_size).max(1) * chunk_size
+}
+
+/// Runs `size`: the output size of a run, from the same record widths a
+/// real run uses, and with `--shard-size` or `--shards` the shards it
+/// writes.
+pub fn show_size(
+    records: &RecordArgs,
+    chunk_size: usize,
+    shard_size: Option<usize>,
+    shards: Option<usize>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // The width of a record does not depend on the key
+    let generator = records
+        .configure(PasswordGenerator::builder().key([0; 16]))?
+        .chunk_size(chunk_size)
+        .build()?;
+    if let Some(shard_size) = shard_size {
+        check_shard_size(shard_size, chunk_size)?;
+    }
+    let count = generator.count();
+    let shard_size =
+        shard_size.or_else(|| shards.map(|shards| shard_size_for(count, chunk_size, shards)));
+    let width = generator.record_width() as u128;
+    let sink = records.sink(&generator)?;
+    let range_len = |range: std::ops::Range<usize>| match &sink {
+        None => generator.fixed_width().then(|| range.len() as u128 * width),
+        Some(sink) => format::serialized_range_len(sink.as_ref(), &generator, range),
+    };
+    let header = sink.as_ref().map_or(0, |s| s.header().len() as u128);
+
+    println!("Records:     {}", count);
+    println!(
+        "Records are {} in {} format",
+        records.encoding(),
+        records.format
+    );
+    match range_len(0..count) {
+        Some(total) => {
+            let total = header + total;
+            println!(
+                "Total:       {} bytes ({:.2} GiB)",
+                total,
+                total as f64 / GIB
+            );
+        }
+        None if sink.is_none() => {
+            // Passphrases and drawn lengths vary; give the expectation and
+            // the bound
+            let lengths = generator.encoder().and_then(|enc| enc.lengths());
+            let mean = match (generator.passphrase(), lengths) {
+                (Some(pass), _) => pass.mean_len(),
+                (None, Some(lengths)) => lengths.mean(),
+                (None, None) => 0.0,
+            } + 1.0;
+            println!(
+                "Total:       ~{:.0} bytes ({:.2} GiB), at most {} bytes",
+                count as f64 * mean,
+                count as f64 * mean / GIB,
+                count as u128 * width
+            );
+        }
+        None => println!(
+            "Total:       depends on the generated content (quoting/escaping or \
+             variable-length records); {} bytes before formatting",
+            generator.output_len()
+        ),
+    }
+
+    if let Some(shard_size) = shard_size {
+        if let Some(shards) = shards {
+            let written = generator.num_shards(shard_size);
+            println!(
+                "Shard size:  --shard-size {} writes {} shard{}{}",
+                shard_size,
+                written,
+                if written == 1 { "" } else { "s" },
+                if written < shards {
+                    " as shards hold whole chunks"
+                } else {
+                    ""
+                }
+            );
+        }
+        // Every shard but the last is full, as `write_sharded` cuts them
+        let full = count / shard_size;
+        let groups = [
+            (full, shard_size, 0),
+            (
+                generator.num_shards(shard_size) - full,
+                count % shard_size,
+                full * shard_size,
+            ),
+        ];
+        for (n, len, first) in groups {
+            if n == 0 {
+                continue;
+            }
+            // Shards of equal record counts can still differ in size when the
+            // format includes the record index, so show the first and last
+            let sizes = [first..first + len, first + (n - 1) * len..first + n * len]
+                .map(|r| range_len(r).map_or("?".to_string(), |b| b.to_string()));
+            if sizes[0] == "?" {
+                println!(
+                    "Shards:      {} x {} records (size depends on content)",
+                    n, len
+                );
+            } else if sizes[0] == sizes[1] {
+                println!(
+                    "Shards:      {} x {} records = {} bytes each",
+                    n, len, sizes[0]
+                );
+            } else {
+                println!(
+                    "Shards:      {} x {} records = {} to {} bytes",
+                    n, len, sizes[0], sizes[1]
+                );
+            }
+        }
+        if header > 0 {
+            println!("(plus a {}-byte header per output file)", header);
+        }
+    }
+    Ok(())
+}
+
+/// Runs `plan`: the segments of a job and the options that generate each.
+pub fn show_plan(
+    total: u128,
+    segment_size: usize,
+    show: u128,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let plan = SegmentPlan::new(total, segment_size as u64)?;
+    let segments = plan.segments();
+    println!(
+        "Job:         {} records in {} segment{} of {} records",
+        total,
+        segments,
+        if segments == 1 { "" } else { "s" },
+        segment_size
+    );
+    // The first and last `show`; a plan can have far too many to list
+    let head = show.min(segments);
+    let tail = segments.saturating_sub(show).max(head);
+    for number in (0..head).chain(tail..segments) {
+        if number == tail && tail > head {
+            println!("...");
+        }
+        let segment = plan
+            .segment(number)
+            .expect("numbers below segments() exist");
+        println!(
+            "Segment {}: records {}..{}, --total {} --segment {} --count {}",
+            number,
+            segment.first,
+            segment.first + segment.count as u128,
+            total,
+            number,
+            segment_size
+        );
+    }
+    println!(
+        "Run each with the job's --key (and the same record options); every segment is an \
+         ordinary run with its own key fingerprint, checkpoint and manifest."
+    );
+    Ok(())
+}
+
+/// Checks a run without --output against --max-memory before anything is
+/// generated, and caps or redirects it as --over-memory says.
+pub fn plan_memory(args: &mut Args) -> Result<(), Box<dyn std::error::Error>> {
+    // The output's size does not depend on the key
+    let key = Key::from([0; 16]);
+    let mut probe = args.records.configure(
+        PasswordGenerator::builder()
+            .key(key)
+            .chunk_size(args.chunk_size),
+    )?;
+    if let Some(schema) = &args.schema {
+        probe = schema.configure(probe, &key);
+    }
+    let probe = probe.build()?;
+    let need = approx_output_len(run_sink(args, &probe, &key)?.as_deref(), &probe);
+    let mut sys = System::new();
+    sys.refresh_memory();
+    let available = sys.available_memory();
+    let budget = args.max_memory.unwrap_or_default().bytes(available);
+    let over = args.over_memory.clone().unwrap_or_default();
+    match memory::plan(need, probe.count(), budget, &over).map_err(failure::Failure::Memory)? {
+        memory::Plan::Fits => {}
+        memory::Plan::Cap(count) => {
+            status!(
+                "MEMORY: the run needs {}, over the budget of {} ({} available); \
+                 generating only the first {} of {} records (--over-memory cap)\n",
+                memory::size(need),
+                memory::size(budget),
+                memory::size(available),
+                count,
+                probe.count()
+            );
+            args.records.count = count;
+        }
+        memory::Plan::Stream(path) => {
+            status!(
+                "MEMORY: the run needs {}, over the budget of {} ({} available); \
+                 streaming it to {} instead (--over-memory stream)\n",
+                memory::size(need),
+                memory::size(budget),
+                memory::size(available),
+                path.display()
+            );
+            args.output = Some(path);
+        }
+    }
+    Ok(())
+}
+
+/// Bytes of the run's output: exact for fixed-width records, an upper bound
+/// for passphrases, and the unformatted size where formatting depends on
+/// the content.
+pub fn approx_output_len(sink: Option<&dyn OutputSink>, generator: &PasswordGenerator) -> u64 {
+    let len = match sink {
+        None => generator.output_len() as u128,
+        Some(sink) => {
+            format::serialized_len(sink, generator).unwrap_or(generator.output_len() as u128)
+        }
+    };
+    len as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shard_counts_give_whole_chunk_shard_sizes() {
+        assert_eq!(shard_size_for(4_000, 1_000, 4), 1_000);
+        assert_eq!(shard_size_for(4_001, 1_000, 4), 2_000);
+        assert_eq!(shard_size_for(10_000, 1_000, 3), 4_000);
+        assert_eq!(shard_size_for(500, 1_000, 8), 1_000);
+        for (count, shards) in [(4_001, 4), (10_000, 3), (999_999, 7)] {
+            let size = shard_size_for(count, 1_000, shards);
+            assert!(count.div_ceil(size) <= shards);
+            assert!(count.div_ceil(size - 1_000) > shards);
+        }
+    }
+}
@@ -0,0 +1,258 @@
+//! `soak`: burn-in for new hardware. Generates the run over and over, under
+//! a fresh key each pass, into buffers that stay allocated for the whole
+//! soak. After each pass a random sample of the chunks just made is
+//! recomputed and compared byte for byte with the held copies; after the
+//! last pass, every chunk is.
+//!
+//! Each chunk's SHA-256 is taken as it is made, which tells the two faults
+//! apart: a held copy that no longer matches its digest changed in memory,
+//! while one that still does but differs from the recomputation was
+//! computed differently the second time.
+
+use std::error::Error;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use mass_password_gen_optimized::PasswordGenerator;
+use rayon::prelude::*;
+use sha2::{Digest, Sha256};
+
+/// A chunk as it was made, kept for comparing later.
+#[derive(Default)]
+pub struct Held {
+    bytes: Vec<u8>,
+    digest: [u8; 32],
+}
+
+impl Held {
+    /// Makes chunk `chunk_idx` of `generator` into this buffer.
+    pub fn fill(&mut self, generator: &PasswordGenerator, chunk_idx: usize, scratch: &mut Vec<u8>) {
+        make(generator, chunk_idx, scratch, &mut self.bytes);
+        self.digest = Sha256::digest(&self.bytes).into();
+    }
+}
+
+/// Why a held chunk differs from its recomputation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fault {
+    /// The held copy no longer matches the digest taken when it was made
+    Memory,
+    /// The held copy is as it was made, but making it again gave other bytes
+    Compute,
+}
+
+/// A held chunk that differs from its recomputation.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Mismatch {
+    pub chunk: usize,
+    /// The record holding the first differing byte, counted over the run
+    pub record: usize,
+    /// The first differing byte's offset in that record
+    pub byte: usize,
+    /// The address the first differing byte was held at
+    pub address: usize,
+    pub held: u8,
+    pub fresh: u8,
+    /// Bytes of the chunk that differ
+    pub differing: usize,
+    pub fault: Fault,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "chunk {}: {} byte{} differ, the first at record {} byte {} (address {:#x}): held \
+             {:#04x}, recomputed {:#04x}; {}",
+            self.chunk,
+            self.differing,
+            if self.differing == 1 { "" } else { "s" },
+            self.record,
+            self.byte,
+            self.address,
+            self.held,
+            self.fresh,
+            match self.fault {
+                Fault::Memory => "the held copy changed in memory",
+                Fault::Compute => "the chunk was computed differently the second time",
+            }
+        )
+    }
+}
+
+/// How one pass went.
+pub struct Pass {
+    /// Counted from 1
+    pub number: usize,
+    pub records: usize,
+    /// Generating, not counting the comparison
+    pub elapsed: Duration,
+    /// Chunks recomputed and compared
+    pub verified: usize,
+    pub mismatches: Vec<Mismatch>,
+    /// Whether this was the last pass, which compared every chunk
+    pub last: bool,
+}
+
+/// Runs passes on `pool` until `duration` is up, each with a generator from
+/// `next` (all the same shape, under different keys), comparing `sample`
+/// chunks after each, and hands each pass to `report`. A pass is never cut
+/// short, so the soak ends after the first that ends past `duration`.
+pub fn run(
+    pool: &rayon::ThreadPool,
+    duration: Duration,
+    sample: usize,
+    mut next: impl FnMut() -> Result<PasswordGenerator, Box<dyn Error>>,
+    mut report: impl FnMut(&Pass),
+) -> Result<(), Box<dyn Error>> {
+    let start = Instant::now();
+    let mut held: Vec<Held> = Vec::new();
+    let mut rng = rand::thread_rng();
+    for number in 1.. {
+        let generator = next()?;
+        held.resize_with(generator.num_chunks(), Held::default);
+        let begun = Instant::now();
+        pool.install(|| {
+            held.par_iter_mut()
+                .enumerate()
+                .for_each_init(Vec::new, |scratch, (chunk_idx, held)| {
+                    held.fill(&generator, chunk_idx, scratch)
+                })
+        });
+        let elapsed = begun.elapsed();
+        let last = start.elapsed() >= duration;
+        let chunks = match last {
+            true => (0..held.len()).collect(),
+            false => {
+                rand::seq::index::sample(&mut rng, held.len(), sample.min(held.len())).into_vec()
+            }
+        };
+        let mut mismatches: Vec<Mismatch> = pool.install(|| {
+            chunks
+                .par_iter()
+                .map_init(
+                    || (Vec::new(), Vec::new()),
+                    |(scratch, fresh), &chunk_idx| {
+                        compare(&generator, chunk_idx, &held[chunk_idx], scratch, fresh)
+                    },
+                )
+                .flatten()
+                .collect()
+        });
+        mismatches.sort_unstable_by_key(|m| m.chunk);
+        report(&Pass {
+            number,
+            records: generator.count(),
+            elapsed,
+            verified: chunks.len(),
+            mismatches,
+            last,
+        });
+        if last {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Makes chunk `chunk_idx` of `generator` again into `fresh` and compares
+/// it with `held`.
+pub fn compare(
+    generator: &PasswordGenerator,
+    chunk_idx: usize,
+    held: &Held,
+    scratch: &mut Vec<u8>,
+    fresh: &mut Vec<u8>,
+) -> Option<Mismatch> {
+    make(generator, chunk_idx, scratch, fresh);
+    let mut differing = held
+        .bytes
+        .iter()
+        .zip(fresh.iter())
+        .enumerate()
+        .filter(|(_, (held, fresh))| held != fresh);
+    let (offset, (&held_byte, &fresh_byte)) = differing.next()?;
+    let width = generator.record_width();
+    let (record, byte) = match generator.fixed_width() {
+        true => (offset / width, offset % width),
+        // Records end in newlines; the recomputation has them where they belong
+        false => {
+            let start = fresh[..offset]
+                .iter()
+                .rposition(|&b| b == b'\n')
+                .map_or(0, |i| i + 1);
+            let record = fresh[..start].iter().filter(|&&b| b == b'\n').count();
+            (record, offset - start)
+        }
+    };
+    let intact = Sha256::digest(&held.bytes)[..] == held.digest;
+    Some(Mismatch {
+        chunk: chunk_idx,
+        record: chunk_idx * generator.chunk_size() + record,
+        byte,
+        address: held.bytes[offset..].as_ptr() as usize,
+        held: held_byte,
+        fresh: fresh_byte,
+        differing: 1 + differing.count(),
+        fault: match intact {
+            true => Fault::Compute,
+            false => Fault::Memory,
+        },
+    })
+}
+
+/// Makes chunk `chunk_idx` of `generator` into `out`, zeroed first as
+/// [`PasswordGenerator::fill_chunk`] expects.
+fn make(generator: &PasswordGenerator, chunk_idx: usize, scratch: &mut Vec<u8>, out: &mut Vec<u8>) {
+    out.clear();
+    out.resize(generator.chunk_len(chunk_idx) * generator.record_width(), 0);
+    generator.fill_chunk(chunk_idx, scratch, out);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mass_password_gen_optimized::{Capitalization, Passphrase};
+
+    #[test]
+    fn mismatches_are_located_and_told_apart() {
+        let generator = PasswordGenerator::builder()
+            .key([7; 16])
+            .count(100)
+            .chunk_size(40)
+            .passphrase(Passphrase::new(3, "-", Capitalization::Title, 0).unwrap())
+            .build()
+            .unwrap();
+        let mut scratch = Vec::new();
+        let mut held = Held::default();
+        held.fill(&generator, 1, &mut scratch);
+        assert_eq!(
+            compare(&generator, 1, &held, &mut scratch, &mut Vec::new()),
+            None
+        );
+
+        // A byte of the fifth record's second word flips after it was made
+        let fifth = held
+            .bytes
+            .split(|&b| b == b'\n')
+            .take(4)
+            .map(|r| r.len() + 1)
+            .sum::<usize>();
+        let offset = fifth + held.bytes[fifth..].iter().position(|&b| b == b'-').unwrap() + 1;
+        held.bytes[offset] ^= 0x20;
+        let mismatch = compare(&generator, 1, &held, &mut scratch, &mut Vec::new()).unwrap();
+        assert_eq!(
+            (mismatch.record, mismatch.byte, mismatch.differing),
+            (44, offset - fifth, 1)
+        );
+        assert_eq!(mismatch.held ^ mismatch.fresh, 0x20);
+        assert_eq!(mismatch.address, held.bytes.as_ptr() as usize + offset);
+        assert_eq!(mismatch.fault, Fault::Memory);
+        assert!(mismatch.to_string().contains("changed in memory"));
+
+        // The same bytes held as made, but against a chunk made otherwise
+        held.digest = Sha256::digest(&held.bytes).into();
+        let mismatch = compare(&generator, 1, &held, &mut scratch, &mut Vec::new()).unwrap();
+        assert_eq!(mismatch.fault, Fault::Compute);
+    }
+}
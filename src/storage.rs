@@ -0,0 +1,324 @@
+//! `probe-storage` and `--tune-storage`: what the filesystem an output goes
+//! to can take, measured with a few seconds of scratch writes.
+//!
+//! A probe reads the filesystem's block size, tries a direct (O_DIRECT)
+//! write, then times synced writes of several sizes to one file and fits
+//! them as [`AdaptiveBuffer`] would, giving the write size and look-ahead a
+//! run should use. Last it writes 1, 2, 4, ... files at once to see how far
+//! parallel writers scale, which says how many shards pay. The scratch
+//! files are removed whatever happens; they hold public noise.
+
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use mass_password_gen_optimized::buffer::{settle, PROBE_SIZES};
+use mass_password_gen_optimized::AdaptiveBuffer;
+use serde::Serialize;
+
+use crate::permissions;
+
+/// Rounds of the write sizes timed.
+const ROUNDS: usize = 4;
+
+/// How long each count of parallel writers writes for.
+const PARALLEL_PHASE: Duration = Duration::from_millis(400);
+
+/// Most written by each count of parallel writers, so a fast device does
+/// not fill the disk.
+const PARALLEL_BYTES: u64 = 512 << 20;
+
+/// Most parallel writers tried.
+const MAX_WRITERS: usize = 16;
+
+/// Share of the best throughput the fewest writers must reach to be
+/// picked.
+const GOOD_ENOUGH: f64 = 0.9;
+
+/// What a probe found.
+#[derive(Debug, Serialize)]
+pub struct Probe {
+    pub dir: PathBuf,
+    /// Preferred I/O size the filesystem reports
+    pub block_size: u64,
+    /// Whether a direct write worked, and why not
+    pub direct_io: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub direct_io_error: Option<String>,
+    pub latency_ms: f64,
+    pub mib_per_sec: f64,
+    pub jitter: f64,
+    /// Write size and chunks per worker ahead a run should use
+    pub write_size: usize,
+    pub queue_depth: usize,
+    /// Throughput of each count of parallel writers tried
+    pub writers: Vec<Writers>,
+    /// The fewest writers within 10% of the best
+    pub best_writers: usize,
+    #[serde(skip)]
+    samples: Vec<(usize, f64)>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Writers {
+    pub writers: usize,
+    pub mib_per_sec: f64,
+}
+
+impl Probe {
+    /// The buffer for a run to `dir`, settled on what the probe found.
+    pub fn buffer(&self) -> AdaptiveBuffer {
+        AdaptiveBuffer::settled(settle(&self.samples))
+    }
+
+    /// Shards for a run of `count` records in chunks of `chunk_size` that
+    /// keeps the best count of writers busy; `None` if one file does as
+    /// well.
+    pub fn shard_size(&self, count: usize, chunk_size: usize) -> Option<usize> {
+        if self.best_writers < 2 {
+            return None;
+        }
+        let size = count.div_ceil(self.best_writers).div_ceil(chunk_size) * chunk_size;
+        (size < count).then_some(size)
+    }
+
+    /// Prints the findings.
+    pub fn show(&self) {
+        status!("Storage at {}:", self.dir.display());
+        status!("  block size:   {} bytes", self.block_size);
+        match &self.direct_io_error {
+            None => status!("  direct I/O:   works"),
+            Some(e) => status!("  direct I/O:   unavailable ({})", e),
+        }
+        status!(
+            "  synced writes: {:.3} ms latency, {:.0} MiB/s, jitter {:.2}x",
+            self.latency_ms,
+            self.mib_per_sec,
+            self.jitter
+        );
+        for writers in &self.writers {
+            status!(
+                "  {:>2} writer{}:  {:.0} MiB/s",
+                writers.writers,
+                if writers.writers == 1 { " " } else { "s" },
+                writers.mib_per_sec
+            );
+        }
+        status!(
+            "  suggests:     {} KiB writes, {} chunk{} per worker ahead, {} parallel writer{}",
+            self.write_size >> 10,
+            self.queue_depth,
+            if self.queue_depth == 1 { "" } else { "s" },
+            self.best_writers,
+            if self.best_writers == 1 { "" } else { "s" }
+        );
+    }
+}
+
+/// Scratch files of one step of a probe, named for it and removed when it
+/// ends.
+struct Scratch {
+    step: &'static str,
+    paths: Vec<PathBuf>,
+}
+
+impl Scratch {
+    fn new(step: &'static str) -> Self {
+        Scratch {
+            step,
+            paths: Vec::new(),
+        }
+    }
+
+    fn path(&mut self, dir: &Path) -> PathBuf {
+        let name = format!(
+            ".mpg-probe.{}.{}.{}",
+            std::process::id(),
+            self.step,
+            self.paths.len()
+        );
+        self.paths.push(dir.join(name));
+        self.paths[self.paths.len() - 1].clone()
+    }
+
+    fn file(&mut self, dir: &Path) -> io::Result<File> {
+        let path = self.path(dir);
+        permissions::create(&path, permissions::DEFAULT_MODE)
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        for path in &self.paths {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Probes the filesystem of `dir`, trying up to `threads` parallel writers.
+pub fn probe(dir: &Path, threads: usize) -> io::Result<Probe> {
+    let mut noise = vec![0; PROBE_SIZES[PROBE_SIZES.len() - 1]];
+    getrandom::fill(&mut noise).map_err(io::Error::other)?;
+
+    let mut scratch = Scratch::new("sequential");
+    let mut file = scratch.file(dir)?;
+    let block_size = block_size(&file);
+    let direct_io_error = direct_write(&mut Scratch::new("direct"), dir, block_size).err();
+
+    let mut samples = Vec::new();
+    for _ in 0..ROUNDS {
+        for size in PROBE_SIZES {
+            let begun = Instant::now();
+            file.write_all(&noise[..size])?;
+            file.sync_data()?;
+            samples.push((size, begun.elapsed().as_secs_f64()));
+        }
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+    }
+    drop((file, scratch));
+    let settled = settle(&samples);
+
+    let mut writers = Vec::new();
+    let mut count = 1;
+    while count <= threads.clamp(1, MAX_WRITERS) {
+        let mut scratch = Scratch::new("parallel");
+        let files = (0..count)
+            .map(|_| scratch.file(dir))
+            .collect::<io::Result<Vec<_>>>()?;
+        let rate = parallel(files, &noise[..settled.write_size.min(noise.len())])?;
+        drop(scratch);
+        writers.push(Writers {
+            writers: count,
+            mib_per_sec: rate / (1024.0 * 1024.0),
+        });
+        count *= 2;
+    }
+    let best = writers.iter().map(|w| w.mib_per_sec).fold(0.0, f64::max);
+    let best_writers = writers
+        .iter()
+        .find(|w| w.mib_per_sec >= GOOD_ENOUGH * best)
+        .map_or(1, |w| w.writers);
+
+    Ok(Probe {
+        dir: dir.to_path_buf(),
+        block_size,
+        direct_io: direct_io_error.is_none(),
+        direct_io_error: direct_io_error.map(|e| e.to_string()),
+        latency_ms: settled.latency.as_secs_f64() * 1e3,
+        mib_per_sec: settled.throughput / (1024.0 * 1024.0),
+        jitter: settled.jitter,
+        write_size: settled.write_size,
+        queue_depth: settled.chunks_ahead,
+        writers,
+        best_writers,
+        samples,
+    })
+}
+
+/// Bytes per second of `files` written at once, `data` at a time, for
+/// [`PARALLEL_PHASE`] or [`PARALLEL_BYTES`] between them, and synced.
+fn parallel(files: Vec<File>, data: &[u8]) -> io::Result<f64> {
+    let written = AtomicU64::new(0);
+    let begun = Instant::now();
+    let done =
+        || begun.elapsed() >= PARALLEL_PHASE || written.load(Ordering::Relaxed) >= PARALLEL_BYTES;
+    std::thread::scope(|scope| {
+        let writers: Vec<_> = files
+            .into_iter()
+            .map(|mut file| {
+                let (written, done) = (&written, &done);
+                scope.spawn(move || -> io::Result<()> {
+                    while !done() {
+                        file.write_all(data)?;
+                        written.fetch_add(data.len() as u64, Ordering::Relaxed);
+                    }
+                    file.sync_data()
+                })
+            })
+            .collect();
+        writers
+            .into_iter()
+            .try_for_each(|writer| writer.join().expect("writers do not panic"))
+    })?;
+    Ok(written.into_inner() as f64 / begun.elapsed().as_secs_f64())
+}
+
+/// The filesystem's preferred I/O size for `file`.
+fn block_size(file: &File) -> u64 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        file.metadata().map_or(4096, |meta| meta.blksize())
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = file;
+        4096
+    }
+}
+
+/// Writes one aligned block with O_DIRECT, bypassing the page cache.
+fn direct_write(scratch: &mut Scratch, dir: &Path, block_size: u64) -> io::Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        let path = scratch.path(dir);
+        let mut file = File::options()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .custom_flags(libc::O_DIRECT)
+            .mode(permissions::DEFAULT_MODE)
+            .open(&path)?;
+        // Direct writes need a buffer aligned to the block size
+        let align = block_size.max(512) as usize;
+        let buffer = vec![0u8; 2 * align];
+        let start = buffer.as_ptr().align_offset(align);
+        file.write_all(&buffer[start..start + align])
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = (scratch, dir, block_size);
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "only probed on Linux",
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn probes_leave_nothing_behind() {
+        let dir = std::env::temp_dir().join(format!("mpg_storage_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let probe = probe(&dir, 2).unwrap();
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+        assert!(probe.block_size > 0 && probe.write_size > 0 && probe.queue_depth >= 1);
+        assert_eq!(
+            probe.writers.iter().map(|w| w.writers).collect::<Vec<_>>(),
+            [1, 2]
+        );
+        assert!(probe.best_writers == 1 || probe.best_writers == 2);
+        assert_eq!(probe.buffer().report().write_size, probe.write_size);
+
+        let one = Probe {
+            best_writers: 1,
+            ..probe
+        };
+        assert_eq!(one.shard_size(1000, 100), None);
+        let four = Probe {
+            best_writers: 4,
+            ..one
+        };
+        // 250 records each, rounded up to whole chunks of 100
+        assert_eq!(four.shard_size(1000, 100), Some(300));
+        assert_eq!(four.shard_size(100, 100), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,944 @@
+//! Streaming output: each chunk is generated into a small buffer and written
+//! out straight away, so memory stays bounded by roughly
+//! `chunk_size * threads` no matter how many passwords are requested.
+
+use rayon::prelude::*;
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Mutex};
+use std::time::Instant;
+
+use crate::mmap::Mapping;
+use crate::{AdaptiveBuffer, ChunkCodec, OutputSink, PasswordGenerator};
+
+/// Where a chunk was written by
+/// [`PasswordGenerator::write_with_fallback`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Landing {
+    Primary,
+    Fallback,
+}
+
+/// What happened during a run written with a fallback destination.
+#[derive(Debug)]
+pub struct FailoverReport {
+    /// Where each chunk landed, in chunk order
+    pub landings: Vec<Landing>,
+    /// The primary's first error, which made the run switch over
+    pub primary_error: Option<io::Error>,
+    /// True if chunks sit at their own byte offsets in whichever file they
+    /// landed in (fixed-width raw output); false if each file holds its
+    /// chunks back to back, in order
+    pub positioned: bool,
+}
+
+/// What one device took in a run written with
+/// [`PasswordGenerator::write_striped`].
+#[derive(Clone, Debug, Default, PartialEq, serde::Serialize)]
+pub struct DeviceWrites {
+    pub shards: usize,
+    pub bytes: u64,
+    /// From the start of the run to the device's last write
+    pub seconds: f64,
+}
+
+/// How far a run into a file has got, for resuming it after a crash.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Progress {
+    /// Chunks `0..chunks` are fully written
+    pub chunks: usize,
+    /// Bytes of output they take up, header included
+    pub bytes: u64,
+}
+
+/// What [`PasswordGenerator::write_shards`] writes, and where.
+pub struct ShardWrite<'a> {
+    pub sink: Option<&'a dyn OutputSink>,
+    /// Compresses each shard, header and chunks framed one by one as
+    /// [`PasswordGenerator::write_adaptive`] does
+    pub codec: Option<&'a dyn ChunkCodec>,
+    /// Each shard's file, or `None` for one that could not be created
+    pub files: &'a [Option<File>],
+    /// Records per shard, a multiple of the chunk size
+    pub shard_size: usize,
+    /// The first shard to write; those before it were written by the run
+    /// this one resumes and are left as they are, their files unused
+    pub from: usize,
+    /// Opens the file a shard goes to instead when its own fails; without
+    /// it, the run fails with the shard
+    pub spare: Option<&'a (dyn Fn(usize) -> io::Result<File> + Sync)>,
+}
+
+/// Where each shard of a run written with
+/// [`PasswordGenerator::write_shards`] landed.
+#[derive(Debug)]
+pub struct ShardReport {
+    /// Where each shard landed, in shard order
+    pub landings: Vec<Landing>,
+    /// Why each shard that moved onto its spare left its own file; none
+    /// for a shard that had no file
+    pub errors: Vec<(usize, io::Error)>,
+}
+
+/// A primary file with a hot spare. The first failed write to the primary
+/// switches every later write to the fallback; the failed chunk is retried
+/// there, so no chunk is lost.
+struct Failover<'a> {
+    primary: Option<&'a File>,
+    fallback: &'a File,
+    failed: AtomicBool,
+    error: Mutex<Option<io::Error>>,
+}
+
+impl Failover<'_> {
+    fn write(&self, write: impl Fn(&File) -> io::Result<()>) -> io::Result<Landing> {
+        if let Some(primary) = self.primary {
+            if !self.failed.load(Ordering::Relaxed) {
+                match write(primary) {
+                    Ok(()) => return Ok(Landing::Primary),
+                    Err(e) => {
+                        self.failed.store(true, Ordering::Relaxed);
+                        self.error.lock().unwrap().get_or_insert(e);
+                    }
+                }
+            }
+        }
+        write(self.fallback)?;
+        Ok(Landing::Fallback)
+    }
+}
+
+/// Generates into a file with positioned writes: every chunk knows its own
+/// byte offset, so chunks can land in any order without coordination.
+/// Variable-width records have no fixed offsets and go through
+/// [`to_writer`] instead.
+pub(crate) fn to_file<T, F>(
+    generator: &PasswordGenerator,
+    file: &File,
+    on_chunk: F,
+) -> io::Result<Vec<T>>
+where
+    T: Send,
+    F: Fn(usize, usize) -> T + Sync,
+{
+    if !generator.fixed_width() {
+        let mut file = file;
+        return to_writer(generator, None, None, None, &mut file, on_chunk);
+    }
+    let written = positioned(
+        generator,
+        0,
+        |_, buf, offset| {
+            guarded(generator, buf, |buf, at| {
+                write_all_at(file, buf, offset + at)
+            })
+            .map(|()| Landing::Primary)
+        },
+        on_chunk,
+    )?;
+    Ok(written.into_iter().map(|(t, _)| t).collect())
+}
+
+/// Like [`to_file`], but generates each chunk straight into a memory map
+/// of `file`, sized to the whole run, instead of a buffer that is then
+/// written. If the file cannot be mapped, or records are variable-width,
+/// falls back to [`to_file`] and returns why.
+pub(crate) fn to_mapped_file<T, F>(
+    generator: &PasswordGenerator,
+    file: &File,
+    on_chunk: F,
+) -> io::Result<(Vec<T>, Option<io::Error>)>
+where
+    T: Send,
+    F: Fn(usize, usize) -> T + Sync,
+{
+    // Truncating first zeroes any old contents, which CTR needs since it XORs
+    file.set_len(0)?;
+    if !generator.fixed_width() {
+        let why = io::Error::new(
+            io::ErrorKind::Unsupported,
+            "variable-width records have no fixed offsets",
+        );
+        return Ok((to_file(generator, file, on_chunk)?, Some(why)));
+    }
+    let len = generator.output_len();
+    file.set_len(len as u64)?;
+    let mapping = match Mapping::new(file, len) {
+        Ok(mapping) => mapping,
+        Err(e) => return Ok((to_file(generator, file, on_chunk)?, Some(e))),
+    };
+    let width = generator.record_width();
+    let results = (0..generator.num_chunks())
+        .into_par_iter()
+        .map_init(Vec::new, |scratch, chunk_idx| {
+            let len = generator.chunk_len(chunk_idx);
+            let offset = chunk_idx * generator.chunk_size() * width;
+            // SAFETY: chunks cover disjoint byte ranges of the run
+            let out = unsafe { mapping.slice(offset, len * width) };
+            generator.fill_chunk(chunk_idx, scratch, out);
+            on_chunk(chunk_idx, len)
+        })
+        .collect();
+    mapping.flush()?;
+    Ok((results, None))
+}
+
+/// Like [`to_file`], or [`to_writer`] for sequential output, but moves over
+/// to `fallback` if writing to `primary` fails (or there is no `primary`).
+pub(crate) fn to_files_with_fallback<T, F>(
+    generator: &PasswordGenerator,
+    sink: Option<&dyn OutputSink>,
+    primary: Option<&File>,
+    fallback: &File,
+    on_chunk: F,
+) -> io::Result<(Vec<T>, FailoverReport)>
+where
+    T: Send,
+    F: Fn(usize, usize) -> T + Sync,
+{
+    let failover = Failover {
+        primary,
+        fallback,
+        failed: AtomicBool::new(false),
+        error: Mutex::new(None),
+    };
+    let is_positioned = sink.is_none() && generator.fixed_width();
+    let written = if is_positioned {
+        positioned(
+            generator,
+            0,
+            |_, buf, offset| {
+                failover.write(|file| {
+                    guarded(generator, buf, |buf, at| {
+                        write_all_at(file, buf, offset + at)
+                    })
+                })
+            },
+            on_chunk,
+        )?
+    } else {
+        let mut write = |buf: &[u8]| {
+            failover.write(|mut file| {
+                // Cut off a partly written chunk so that each file stays a
+                // clean run of whole chunks
+                let start = file.stream_position()?;
+                guarded(generator, buf, |buf, _| file.write_all(buf)).inspect_err(|_| {
+                    let _ = file.set_len(start);
+                })
+            })
+        };
+        if let Some(sink) = sink {
+            write(sink.header())?;
+        }
+        let chunks = 0..generator.num_chunks();
+        ordered(generator, sink, None, None, chunks, &mut write, on_chunk)?
+    };
+    let (results, landings) = written.into_iter().unzip();
+    Ok((
+        results,
+        FailoverReport {
+            landings,
+            primary_error: failover.error.into_inner().unwrap(),
+            positioned: is_positioned,
+        },
+    ))
+}
+
+/// Like [`to_file`], or [`to_writer`] for sequential output, but only
+/// generates the chunks after `from` and reports each time the fully
+/// written prefix of the run grows. Whatever `file` holds past `from` is
+/// overwritten.
+pub(crate) fn to_file_resumable<T, F, P>(
+    generator: &PasswordGenerator,
+    sink: Option<&dyn OutputSink>,
+    file: &File,
+    from: Progress,
+    on_chunk: F,
+    on_progress: P,
+) -> io::Result<Vec<T>>
+where
+    T: Send,
+    F: Fn(usize, usize) -> T + Sync,
+    P: FnMut(Progress) + Send,
+{
+    if file.metadata()?.len() < from.bytes {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "the output is shorter than its checkpoint says",
+        ));
+    }
+    let written = if sink.is_none() && generator.fixed_width() {
+        // Chunks finish out of order; only a gapless prefix counts as done
+        let width = generator.record_width() as u64;
+        let state = Mutex::new((from, BTreeSet::new(), on_progress));
+        positioned(
+            generator,
+            from.chunks,
+            |chunk_idx, buf, offset| {
+                guarded(generator, buf, |buf, at| {
+                    write_all_at(file, buf, offset + at)
+                })?;
+                let mut state = state.lock().unwrap();
+                let (progress, finished, on_progress) = &mut *state;
+                finished.insert(chunk_idx);
+                let before = progress.chunks;
+                while finished.remove(&progress.chunks) {
+                    progress.chunks += 1;
+                }
+                if progress.chunks > before {
+                    let records = (progress.chunks * generator.chunk_size()).min(generator.count());
+                    progress.bytes = records as u64 * width;
+                    on_progress(*progress);
+                }
+                Ok(Landing::Primary)
+            },
+            on_chunk,
+        )?
+    } else {
+        // Anything past the checkpoint is a partly written chunk
+        file.set_len(from.bytes)?;
+        let mut file = file;
+        file.seek(SeekFrom::Start(from.bytes))?;
+        let mut progress = from;
+        let mut on_progress = on_progress;
+        if let Some(sink) = sink.filter(|_| from.chunks == 0) {
+            file.write_all(sink.header())?;
+            progress.bytes += sink.header().len() as u64;
+        }
+        ordered(
+            generator,
+            sink,
+            None,
+            None,
+            from.chunks..generator.num_chunks(),
+            &mut |buf| {
+                guarded(generator, buf, |buf, _| file.write_all(buf))?;
+                progress.chunks += 1;
+                progress.bytes += buf.len() as u64;
+                on_progress(progress);
+                Ok(Landing::Primary)
+            },
+            on_chunk,
+        )?
+    };
+    Ok(written.into_iter().map(|(t, _)| t).collect())
+}
+
+/// Generates into one file per shard of `shard_chunks` chunks, each
+/// starting with the sink's header. Raw fixed-width records land with
+/// positioned writes, all shards at once; anything else is written in
+/// order, a shard at a time.
+pub(crate) fn to_shards<T, F, S>(
+    generator: &PasswordGenerator,
+    sink: Option<&dyn OutputSink>,
+    files: &[File],
+    shard_chunks: usize,
+    on_chunk: F,
+    on_shard: S,
+) -> io::Result<Vec<T>>
+where
+    T: Send,
+    F: Fn(usize, usize) -> T + Sync,
+    S: Fn(usize) + Sync,
+{
+    check_shard_files(generator, files, shard_chunks)?;
+    if sink.is_none() && generator.fixed_width() {
+        let shard_len = (shard_chunks * generator.chunk_size() * generator.record_width()) as u64;
+        // Chunks written of each shard, which finishes with its last
+        let done: Vec<AtomicUsize> = files.iter().map(|_| AtomicUsize::new(0)).collect();
+        let written = positioned(
+            generator,
+            0,
+            |chunk_idx, buf, offset| {
+                let shard = chunk_idx / shard_chunks;
+                let offset = offset % shard_len;
+                guarded(generator, buf, |buf, at| {
+                    write_all_at(&files[shard], buf, offset + at)
+                })?;
+                let chunks = shard_chunks.min(generator.num_chunks() - shard * shard_chunks);
+                if done[shard].fetch_add(1, Ordering::AcqRel) + 1 == chunks {
+                    on_shard(shard);
+                }
+                Ok(Landing::Primary)
+            },
+            on_chunk,
+        )?;
+        return Ok(written.into_iter().map(|(t, _)| t).collect());
+    }
+    let mut results = Vec::with_capacity(generator.num_chunks());
+    for (shard, mut file) in files.iter().enumerate() {
+        if let Some(sink) = sink {
+            file.write_all(sink.header())?;
+        }
+        let first = shard * shard_chunks;
+        let chunks = first..(first + shard_chunks).min(generator.num_chunks());
+        let written = ordered(
+            generator,
+            sink,
+            None,
+            None,
+            chunks,
+            &mut |buf| {
+                guarded(generator, buf, |buf, _| file.write_all(buf)).map(|()| Landing::Primary)
+            },
+            &on_chunk,
+        )?;
+        results.extend(written.into_iter().map(|(t, _)| t));
+        on_shard(shard);
+    }
+    Ok(results)
+}
+
+/// Writes shards as [`to_shards`] does, in order and a shard at a time,
+/// moving a shard whole onto its spare at the first write its own file
+/// fails, or at once if it has no file. The chunks it had written are
+/// generated again into the spare, which then takes the rest of it.
+pub(crate) fn to_shards_with_spares<T, F, S>(
+    generator: &PasswordGenerator,
+    shards: &ShardWrite,
+    shard_chunks: usize,
+    on_chunk: F,
+    on_shard: S,
+) -> io::Result<(Vec<T>, ShardReport)>
+where
+    T: Send,
+    F: Fn(usize, usize) -> T + Sync,
+    S: Fn(usize, Landing) + Sync,
+{
+    check_shard_files(generator, shards.files, shard_chunks)?;
+    let (sink, codec) = (shards.sink, shards.codec);
+    let header = framed_header(sink, codec)?;
+    let mut results = Vec::with_capacity(generator.num_chunks());
+    let mut report = ShardReport {
+        landings: Vec::with_capacity(shards.files.len()),
+        errors: Vec::new(),
+    };
+    report
+        .landings
+        .resize(shards.from.min(shards.files.len()), Landing::Primary);
+    for (shard, primary) in shards.files.iter().enumerate().skip(shards.from) {
+        let first = shard * shard_chunks;
+        let chunks = first..(first + shard_chunks).min(generator.num_chunks());
+        let errors = &mut report.errors;
+        let mut spare = match primary.as_ref() {
+            Some(mut file) => match file.write_all(&header) {
+                Ok(()) => None,
+                Err(e) => Some(spill(
+                    generator,
+                    shards,
+                    shard,
+                    first..first,
+                    Some(e),
+                    errors,
+                )?),
+            },
+            None => Some(spill(generator, shards, shard, first..first, None, errors)?),
+        };
+        let mut next = first;
+        let written = ordered(
+            generator,
+            sink,
+            codec,
+            None,
+            chunks,
+            &mut |buf| {
+                if let (None, Some(mut file)) = (&spare, primary.as_ref()) {
+                    match guarded(generator, buf, |buf, _| file.write_all(buf)) {
+                        Ok(()) => {
+                            next += 1;
+                            return Ok(Landing::Primary);
+                        }
+                        Err(e) => {
+                            let file =
+                                spill(generator, shards, shard, first..next, Some(e), errors)?;
+                            spare = Some(file);
+                        }
+                    }
+                }
+                let mut file = spare
+                    .as_ref()
+                    .expect("a shard off its own file has a spare");
+                guarded(generator, buf, |buf, _| file.write_all(buf))?;
+                Ok(Landing::Fallback)
+            },
+            &on_chunk,
+        )?;
+        results.extend(written.into_iter().map(|(t, _)| t));
+        let landing = match spare {
+            Some(_) => Landing::Fallback,
+            None => Landing::Primary,
+        };
+        report.landings.push(landing);
+        on_shard(shard, landing);
+    }
+    Ok((results, report))
+}
+
+/// Opens `shard`'s spare, noting `why` its own file failed, and writes its
+/// header and `chunks` into it again for the shard to go on there. Without
+/// a spare, fails with `why`.
+fn spill(
+    generator: &PasswordGenerator,
+    shards: &ShardWrite,
+    shard: usize,
+    chunks: Range<usize>,
+    why: Option<io::Error>,
+    errors: &mut Vec<(usize, io::Error)>,
+) -> io::Result<File> {
+    let Some(open) = shards.spare else {
+        return Err(why.unwrap_or_else(|| {
+            io::Error::other(format!(
+                "shard {} has neither a file nor a spare",
+                shard + 1
+            ))
+        }));
+    };
+    errors.extend(why.map(|e| (shard, e)));
+    let mut file = open(shard)?;
+    file.write_all(&framed_header(shards.sink, shards.codec)?)?;
+    ordered(
+        generator,
+        shards.sink,
+        shards.codec,
+        None,
+        chunks,
+        &mut |buf| {
+            guarded(generator, buf, |buf, _| file.write_all(buf)).map(|()| Landing::Fallback)
+        },
+        |_, _| (),
+    )?;
+    Ok(file)
+}
+
+/// Refuses `files` unless there is one for each shard of `shard_chunks`
+/// chunks.
+fn check_shard_files<E>(
+    generator: &PasswordGenerator,
+    files: &[E],
+    shard_chunks: usize,
+) -> io::Result<()> {
+    let shards = generator.num_chunks().div_ceil(shard_chunks.max(1));
+    if shard_chunks == 0 || files.len() != shards {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("the run needs {} shard files, not {}", shards, files.len()),
+        ));
+    }
+    Ok(())
+}
+
+/// Writes shards of `shard_chunks` chunks to `files` as [`to_shards`]
+/// does, compressed through `codec` if given, spread over `devices`: shard `n` is on device `n % devices`. Each
+/// device has a thread that writes its shards in order while the workers
+/// generate; one wave of chunks per device waits for it at most, so a slow
+/// device holds up only its own shards.
+pub(crate) fn to_stripes<T, F, S>(
+    generator: &PasswordGenerator,
+    shards: &ShardWrite,
+    devices: usize,
+    shard_chunks: usize,
+    on_chunk: F,
+    on_shard: S,
+) -> io::Result<(Vec<T>, Vec<DeviceWrites>)>
+where
+    T: Send,
+    F: Fn(usize, usize) -> T + Sync,
+    S: Fn(usize) + Sync,
+{
+    check_shard_files(generator, shards.files, shard_chunks)?;
+    if shards.spare.is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "shards striped over devices have no spares",
+        ));
+    }
+    if shards.from > 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "shards striped over devices are written all at once, so cannot resume",
+        ));
+    }
+    let files = shards
+        .files
+        .iter()
+        .enumerate()
+        .map(|(n, file)| {
+            file.as_ref().ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("shard {} has no file", n + 1),
+                )
+            })
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    let (sink, codec) = (shards.sink, shards.codec);
+    let devices = devices.clamp(1, files.len().max(1));
+    let started = Instant::now();
+    let header = framed_header(sink, codec)?;
+    let (on_chunk, on_shard) = (&on_chunk, &on_shard);
+    let striped = std::thread::scope(|scope| {
+        let devices: Vec<_> = (0..devices)
+            .map(|device| {
+                let shards = (device..files.len()).step_by(devices);
+                let (files, header) = (&files[..], &header[..]);
+                scope.spawn(move || {
+                    let device = Device {
+                        files,
+                        codec,
+                        header,
+                        started,
+                        on_shard,
+                    };
+                    to_device(generator, sink, device, shards, shard_chunks, on_chunk)
+                })
+            })
+            .collect();
+        devices
+            .into_iter()
+            .map(|device| device.join().expect("writers do not panic"))
+            .collect::<io::Result<Vec<_>>>()
+    })?;
+
+    let mut shards = Vec::with_capacity(files.len());
+    let mut reports = Vec::with_capacity(striped.len());
+    for (written, report) in striped {
+        shards.extend(written);
+        reports.push(report);
+    }
+    shards.sort_by_key(|(shard, _)| *shard);
+    let results = shards.into_iter().flat_map(|(_, results)| results);
+    Ok((results.collect(), reports))
+}
+
+/// Shards by number, with what `on_chunk` gave for each of their chunks.
+type Shards<T> = Vec<(usize, Vec<T>)>;
+
+/// What the writer of one device of [`to_stripes`] writes to, and tells.
+struct Device<'a, S> {
+    files: &'a [&'a File],
+    codec: Option<&'a dyn ChunkCodec>,
+    /// What starts each shard, framed through the codec
+    header: &'a [u8],
+    started: Instant,
+    on_shard: &'a S,
+}
+
+/// Generates `shards` of `files` for one device of [`to_stripes`] and hands
+/// their chunks to a writer thread of the device's own; `None` after a
+/// shard's last chunk tells the writer the shard is done.
+fn to_device<T, F, S>(
+    generator: &PasswordGenerator,
+    sink: Option<&dyn OutputSink>,
+    device: Device<S>,
+    shards: impl Iterator<Item = usize>,
+    shard_chunks: usize,
+    on_chunk: &F,
+) -> io::Result<(Shards<T>, DeviceWrites)>
+where
+    T: Send,
+    F: Fn(usize, usize) -> T + Sync,
+    S: Fn(usize) + Sync,
+{
+    let Device {
+        files,
+        codec,
+        header,
+        started,
+        on_shard,
+    } = device;
+    let (queue, chunks) =
+        mpsc::sync_channel::<(usize, Option<Vec<u8>>)>(rayon::current_num_threads());
+    // Buffers the writer is done with, for the next chunks
+    let spare = Mutex::new(Vec::<Vec<u8>>::new());
+    std::thread::scope(|scope| {
+        let writer = scope.spawn(|| -> io::Result<DeviceWrites> {
+            let mut report = DeviceWrites::default();
+            for (shard, buf) in chunks {
+                let Some(buf) = buf else {
+                    on_shard(shard);
+                    continue;
+                };
+                let mut file = files[shard];
+                guarded(generator, &buf, |buf, _| file.write_all(buf))?;
+                report.bytes += buf.len() as u64;
+                report.seconds = started.elapsed().as_secs_f64();
+                spare.lock().unwrap().push(buf);
+            }
+            Ok(report)
+        });
+
+        let stopped = || io::Error::other("the device's writer stopped");
+        let fed = (|| -> io::Result<Shards<T>> {
+            let mut written = Vec::new();
+            for shard in shards {
+                let mut file = files[shard];
+                file.write_all(header)?;
+                let first = shard * shard_chunks;
+                let range = first..(first + shard_chunks).min(generator.num_chunks());
+                let results = ordered(
+                    generator,
+                    sink,
+                    codec,
+                    None,
+                    range,
+                    &mut |chunk| {
+                        let mut buf = spare.lock().unwrap().pop().unwrap_or_default();
+                        buf.clear();
+                        buf.extend_from_slice(chunk);
+                        queue
+                            .send((shard, Some(buf)))
+                            .map(|()| Landing::Primary)
+                            .map_err(|_| stopped())
+                    },
+                    on_chunk,
+                )?;
+                queue.send((shard, None)).map_err(|_| stopped())?;
+                written.push((shard, results.into_iter().map(|(t, _)| t).collect()));
+            }
+            Ok(written)
+        })();
+        drop(queue);
+        // A writer that failed is why feeding it stopped, so its error comes first
+        let mut report = writer.join().expect("writers do not panic")?;
+        let written = fed?;
+        report.shards = written.len();
+        report.bytes += (header.len() * written.len()) as u64;
+        Ok((written, report))
+    })
+}
+
+/// Runs chunks `first..` in parallel and hands each to `write` with its
+/// index and byte offset.
+fn positioned<T, F, W>(
+    generator: &PasswordGenerator,
+    first: usize,
+    write: W,
+    on_chunk: F,
+) -> io::Result<Vec<(T, Landing)>>
+where
+    T: Send,
+    F: Fn(usize, usize) -> T + Sync,
+    W: Fn(usize, &[u8], u64) -> io::Result<Landing> + Sync,
+{
+    let width = generator.record_width();
+    (first..generator.num_chunks())
+        .into_par_iter()
+        .map_init(
+            || (Vec::new(), Vec::new()),
+            |(scratch, buf): &mut (Vec<u8>, Vec<u8>), chunk_idx| {
+                let len = generator.chunk_len(chunk_idx);
+                // resize() zeroes the buffer, which CTR needs since it XORs
+                buf.clear();
+                buf.resize(len * width, 0);
+                generator.fill_chunk(chunk_idx, scratch, buf);
+                let offset = chunk_idx * generator.chunk_size() * width;
+                let landing = write(chunk_idx, buf, offset as u64)?;
+                Ok((on_chunk(chunk_idx, len), landing))
+            },
+        )
+        .collect()
+}
+
+/// Generates into a sequential writer such as stdout, serializing through
+/// `sink` and then compressing through `codec` where given. With a `buffer`,
+/// writes go through it, and it decides how far generation runs ahead.
+pub(crate) fn to_writer<T, F>(
+    generator: &PasswordGenerator,
+    sink: Option<&dyn OutputSink>,
+    codec: Option<&dyn ChunkCodec>,
+    buffer: Option<&AdaptiveBuffer>,
+    out: &mut (dyn Write + Send),
+    on_chunk: F,
+) -> io::Result<Vec<T>>
+where
+    T: Send,
+    F: Fn(usize, usize) -> T + Sync,
+{
+    out.write_all(&framed_header(sink, codec)?)?;
+    let written = ordered(
+        generator,
+        sink,
+        codec,
+        buffer,
+        0..generator.num_chunks(),
+        &mut |buf| {
+            guarded(generator, buf, |buf, _| match buffer {
+                Some(buffer) => buffer.write(out, buf),
+                None => out.write_all(buf),
+            })
+            .map(|()| Landing::Primary)
+        },
+        on_chunk,
+    )?;
+    match buffer {
+        Some(buffer) => buffer.flush(out)?,
+        None => out.flush()?,
+    }
+    Ok(written.into_iter().map(|(t, _)| t).collect())
+}
+
+/// The sink's header as it starts a file, a frame of its own through
+/// `codec` if given.
+fn framed_header(
+    sink: Option<&dyn OutputSink>,
+    codec: Option<&dyn ChunkCodec>,
+) -> io::Result<Vec<u8>> {
+    let header = sink.map_or(&[][..], |sink| sink.header());
+    let mut frame = Vec::new();
+    match codec {
+        Some(codec) if !header.is_empty() => codec.encode(header, &mut frame)?,
+        _ => frame.extend_from_slice(header),
+    }
+    Ok(frame)
+}
+
+/// Hands `chunks` to `write` in order, serialized through `sink` and
+/// compressed through `codec` where given. Callers write the sink's header
+/// themselves.
+///
+/// Chunks have to be written in order, so we generate one wave of chunks
+/// (one per worker thread, or as many per thread as `buffer` asks for) in
+/// parallel and write it out while the next wave is being generated. Two
+/// waves are in flight at most.
+fn ordered<T, F>(
+    generator: &PasswordGenerator,
+    sink: Option<&dyn OutputSink>,
+    codec: Option<&dyn ChunkCodec>,
+    buffer: Option<&AdaptiveBuffer>,
+    chunks: Range<usize>,
+    write: &mut (dyn FnMut(&[u8]) -> io::Result<Landing> + Send),
+    on_chunk: F,
+) -> io::Result<Vec<(T, Landing)>>
+where
+    T: Send,
+    F: Fn(usize, usize) -> T + Sync,
+{
+    let num_chunks = chunks.end;
+    let width = generator.record_width();
+    let workers = rayon::current_num_threads().max(1);
+    let mut results = Vec::with_capacity(num_chunks);
+    let mut landings = Vec::with_capacity(num_chunks);
+    let mut pending: Vec<Vec<u8>> = Vec::new();
+    let mut spare: Vec<Vec<u8>> = Vec::new();
+    let mut next = chunks.start;
+
+    while next < num_chunks || !pending.is_empty() {
+        let wave = workers * buffer.map_or(1, AdaptiveBuffer::chunks_ahead);
+        let end = (next + wave).min(num_chunks);
+        let mut buffers: Vec<Vec<u8>> = (next..end)
+            .map(|_| spare.pop().unwrap_or_default())
+            .collect();
+
+        let (written, generated) = rayon::join(
+            || -> io::Result<()> {
+                for buf in &pending {
+                    landings.push(write(buf)?);
+                }
+                Ok(())
+            },
+            || {
+                buffers
+                    .par_iter_mut()
+                    .zip(next..end)
+                    .map_init(
+                        || (Vec::new(), Vec::new(), Vec::new()),
+                        |(scratch, records, serialized), (buf, chunk_idx)| {
+                            let len = generator.chunk_len(chunk_idx);
+                            // Without a sink or codec the records are the output
+                            let target = if sink.is_some() || codec.is_some() {
+                                &mut *records
+                            } else {
+                                &mut *buf
+                            };
+                            target.clear();
+                            target.resize(len * width, 0);
+                            let used = generator.fill_chunk(chunk_idx, scratch, target);
+                            target.truncate(used);
+                            if let Some(sink) = sink {
+                                let target = if codec.is_some() {
+                                    &mut *serialized
+                                } else {
+                                    &mut *buf
+                                };
+                                target.clear();
+                                let first = chunk_idx * generator.chunk_size();
+                                sink.write_chunk(generator, first, records, target);
+                            }
+                            if let Some(codec) = codec {
+                                buf.clear();
+                                let input = if sink.is_some() {
+                                    &*serialized
+                                } else {
+                                    &*records
+                                };
+                                codec.encode(input, buf)?;
+                            }
+                            Ok(on_chunk(chunk_idx, len))
+                        },
+                    )
+                    .collect::<io::Result<Vec<_>>>()
+            },
+        );
+        written?;
+        let generated = generated?;
+
+        results.extend(generated);
+        spare.append(&mut pending);
+        pending = buffers;
+        next = end;
+    }
+
+    Ok(results.into_iter().zip(landings).collect())
+}
+
+/// Writes one chunk through `write`, which takes each piece with its
+/// offset in the chunk: all of it at once, or under the generator's write
+/// limit a piece at a time as the limit lets each through.
+fn guarded(
+    generator: &PasswordGenerator,
+    buf: &[u8],
+    mut write: impl FnMut(&[u8], u64) -> io::Result<()>,
+) -> io::Result<()> {
+    faulty(buf, |buf| match generator.write_limit() {
+        Some(limit) => buf
+            .chunks(limit.piece_len())
+            .enumerate()
+            .try_for_each(|(i, piece)| {
+                let at = (i * limit.piece_len()) as u64;
+                limit.write(piece.len(), || write(piece, at))
+            }),
+        None => write(buf, 0),
+    })
+}
+
+// Fault-injection builds can fail, stall or kill any chunk write
+#[cfg(feature = "fault-injection")]
+use crate::fault::write as faulty;
+
+#[cfg(not(feature = "fault-injection"))]
+fn faulty(buf: &[u8], mut write: impl FnMut(&[u8]) -> io::Result<()>) -> io::Result<()> {
+    write(buf)
+}
+
+#[cfg(unix)]
+pub(crate) fn write_all_at(file: &File, buf: &[u8], offset: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.write_all_at(buf, offset)
+}
+
+#[cfg(windows)]
+pub(crate) fn write_all_at(file: &File, mut buf: &[u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        let n = file.seek_write(buf, offset)?;
+        if n == 0 {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+        buf = &buf[n..];
+        offset += n as u64;
+    }
+    Ok(())
+}
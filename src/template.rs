@@ -0,0 +1,254 @@
+//! Structured passwords from a template such as `Cvcc-####-llll`.
+//!
+//! Each character of a template is either a class, drawn from the
+//! keystream, or a literal, copied as it is:
+//!
+//! | symbol | draws from |
+//! |--------|------------|
+//! | `c` / `C` | a lowercase / uppercase consonant |
+//! | `v` / `V` | a lowercase / uppercase vowel |
+//! | `l` / `L` | a lowercase / uppercase letter |
+//! | `#` | a digit |
+//! | `s` | an ASCII punctuation character |
+//!
+//! Any other printable character is a literal, except letters, which must
+//! be escaped (`\x`) so that a mistyped class is an error rather than a
+//! fixed character. A template is parsed once into a plan of slots, and
+//! each class slot rejection-samples keystream bytes against its own
+//! alphabet, so every position is uniform over its class.
+
+use std::fmt;
+
+use crate::encoder::{DIGITS, LOWER, SYMBOLS, UPPER};
+
+const CONSONANTS: &[u8] = b"bcdfghjklmnpqrstvwxyz";
+const UPPER_CONSONANTS: &[u8] = b"BCDFGHJKLMNPQRSTVWXYZ";
+const VOWELS: &[u8] = b"aeiou";
+const UPPER_VOWELS: &[u8] = b"AEIOU";
+
+/// One position of a template.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Slot {
+    Literal(u8),
+    /// Bytes at or above `limit` (a multiple of the alphabet size) are
+    /// rejected
+    Class {
+        alphabet: &'static [u8],
+        limit: u16,
+    },
+}
+
+/// A parsed template.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Template {
+    source: String,
+    slots: Vec<Slot>,
+}
+
+impl Template {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut slots = Vec::with_capacity(s.len());
+        let mut chars = s.chars();
+        while let Some(c) = chars.next() {
+            let alphabet = match c {
+                'c' => CONSONANTS,
+                'C' => UPPER_CONSONANTS,
+                'v' => VOWELS,
+                'V' => UPPER_VOWELS,
+                'l' => LOWER,
+                'L' => UPPER,
+                '#' => DIGITS,
+                's' => SYMBOLS,
+                '\\' => {
+                    let literal = chars
+                        .next()
+                        .ok_or("the template ends in the middle of an escape")?;
+                    slots.push(Slot::Literal(literal_byte(literal)?));
+                    continue;
+                }
+                c if c.is_ascii_alphabetic() => {
+                    return Err(format!(
+                        "`{}` is not a template class (write a literal letter as `\\{}`)",
+                        c, c
+                    ))
+                }
+                c => {
+                    slots.push(Slot::Literal(literal_byte(c)?));
+                    continue;
+                }
+            };
+            let n = alphabet.len() as u16;
+            slots.push(Slot::Class {
+                alphabet,
+                limit: 256 - 256 % n,
+            });
+        }
+        if !slots.iter().any(|slot| matches!(slot, Slot::Class { .. })) {
+            return Err(format!("template `{}` has no random positions", s));
+        }
+        Ok(Template {
+            source: s.to_string(),
+            slots,
+        })
+    }
+
+    /// Characters per password.
+    pub fn length(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// Entropy of one password in bits: the sum over its class positions.
+    pub fn entropy_bits(&self) -> f64 {
+        self.classes()
+            .map(|(alphabet, _)| (alphabet.len() as f64).log2())
+            .sum()
+    }
+
+    /// The characters position `position` draws from, or `None` for a
+    /// literal.
+    pub fn alphabet_at(&self, position: usize) -> Option<&'static [u8]> {
+        match self.slots.get(position)? {
+            Slot::Class { alphabet, .. } => Some(alphabet),
+            Slot::Literal(_) => None,
+        }
+    }
+
+    /// The literal at `position`, if it is one.
+    pub fn literal_at(&self, position: usize) -> Option<u8> {
+        match self.slots.get(position)? {
+            Slot::Literal(c) => Some(*c),
+            Slot::Class { .. } => None,
+        }
+    }
+
+    /// Positions drawn from the keystream.
+    pub(crate) fn random_positions(&self) -> usize {
+        self.classes().count()
+    }
+
+    /// The lowest fraction of keystream bytes any position keeps.
+    pub(crate) fn min_acceptance(&self) -> f64 {
+        self.classes()
+            .map(|(_, limit)| limit as f64 / 256.0)
+            .fold(1.0, f64::min)
+    }
+
+    /// Every character a password can contain, sorted.
+    pub(crate) fn characters(&self) -> Vec<u8> {
+        let mut seen = [false; 256];
+        for slot in &self.slots {
+            match *slot {
+                Slot::Literal(c) => seen[c as usize] = true,
+                Slot::Class { alphabet, .. } => {
+                    for &c in alphabet {
+                        seen[c as usize] = true;
+                    }
+                }
+            }
+        }
+        (0..=255u8).filter(|&c| seen[c as usize]).collect()
+    }
+
+    /// Encodes `stream` into `out` (exactly `length()` bytes), each class
+    /// position taking the next accepted byte.
+    pub(crate) fn encode(&self, stream: &[u8], out: &mut [u8]) {
+        let mut next = 0;
+        for (position, (slot, out)) in self.slots.iter().zip(out.iter_mut()).enumerate() {
+            *out = match *slot {
+                Slot::Literal(c) => c,
+                Slot::Class { alphabet, limit } => {
+                    let n = alphabet.len();
+                    loop {
+                        match stream.get(next) {
+                            Some(&b) => {
+                                next += 1;
+                                if (b as u16) < limit {
+                                    break alphabet[b as usize % n];
+                                }
+                            }
+                            // Budget exhausted (< 2^-64 per password, as for
+                            // charsets): a modular mapping, slightly biased
+                            // but deterministic
+                            None => break alphabet[stream[position % stream.len()] as usize % n],
+                        }
+                    }
+                }
+            };
+        }
+    }
+
+    fn classes(&self) -> impl Iterator<Item = (&'static [u8], u16)> + '_ {
+        self.slots.iter().filter_map(|slot| match *slot {
+            Slot::Class { alphabet, limit } => Some((alphabet, limit)),
+            Slot::Literal(_) => None,
+        })
+    }
+}
+
+impl fmt::Display for Template {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+fn literal_byte(c: char) -> Result<u8, String> {
+    if c.is_ascii_graphic() {
+        Ok(c as u8)
+    } else {
+        Err(format!(
+            "template literals must be printable ASCII without spaces, found {:?}",
+            c
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BuildError, Encoder, PasswordGenerator, PasswordPolicy};
+
+    #[test]
+    fn templates_fill_each_position_from_its_class() {
+        let template = Template::parse(r"Cvcc-####-\x\Ls").unwrap();
+        assert_eq!(template.length(), 13);
+        assert_eq!(template.literal_at(4), Some(b'-'));
+        assert_eq!(template.literal_at(11), Some(b'L'));
+        let bits = 21f64.log2() * 3.0 + 5f64.log2() + 4.0 * 10f64.log2() + 32f64.log2();
+        assert!((template.entropy_bits() - bits).abs() < 1e-9);
+        for bad in ["", "----", "Cvx", "C\\", "C v"] {
+            assert!(Template::parse(bad).is_err(), "{:?}", bad);
+        }
+
+        let g = PasswordGenerator::builder()
+            .key([3; 16])
+            .count(2000)
+            .chunk_size(300)
+            .encoder(Encoder::from_template(&template))
+            .build()
+            .unwrap();
+        let records: Vec<Vec<u8>> = g.records().collect();
+        let mut seen = vec![[false; 256]; template.length()];
+        for record in &records {
+            assert_eq!(record.len(), template.length());
+            for (position, &c) in record.iter().enumerate() {
+                match template.alphabet_at(position) {
+                    Some(alphabet) => assert!(alphabet.contains(&c)),
+                    None => assert_eq!(Some(c), template.literal_at(position)),
+                }
+                seen[position][c as usize] = true;
+            }
+        }
+        // Every vowel turns up in the vowel position
+        assert!(VOWELS.iter().all(|&c| seen[1][c as usize]));
+        assert_eq!(g.generate().len(), 2000 * 14);
+
+        let err = PasswordGenerator::builder()
+            .key([3; 16])
+            .count(10)
+            .encoder(Encoder::from_template(&template))
+            .policy(PasswordPolicy::default())
+            .build()
+            .err();
+        assert_eq!(err, Some(BuildError::TemplateConflict));
+    }
+}
@@ -0,0 +1,160 @@
+//! A ceiling on how fast a run writes its output, for storage shared with
+//! others that a run at full speed would swamp.
+//!
+//! A [`WriteLimit`] is a token bucket: it refills at the limit and holds at
+//! most [`BURST`] of it. Chunks are written in pieces no larger than that,
+//! and each piece takes its bytes out of the bucket before it goes ahead.
+//! A piece the bucket cannot cover waits until it could, and the deficit
+//! it leaves makes the next one wait its turn too, so however many workers
+//! write at once the output keeps to the limit, never more than a burst
+//! ahead of it.
+//!
+//! The bucket also keeps what was written each second, so the
+//! [`WriteReport`] shows what the limit did: the rate the run wrote at, its
+//! busiest second, and how long writes waited.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+/// How long at the limit the bucket may save up while writes are idle.
+pub const BURST: Duration = Duration::from_millis(50);
+
+/// Pieces smaller than this cost more in system calls than they smooth.
+const MIN_PIECE: usize = 64 << 10;
+
+/// A write limit in bytes per second; pass it to
+/// [`PasswordGenerator::with_write_limit`](crate::PasswordGenerator::with_write_limit).
+pub struct WriteLimit {
+    bytes_per_sec: f64,
+    state: Mutex<State>,
+}
+
+struct State {
+    tokens: f64,
+    refilled: Instant,
+    started: Option<Instant>,
+    last: Option<Instant>,
+    /// Bytes written in each second since the first write
+    seconds: Vec<u64>,
+    waited: Duration,
+}
+
+/// What a [`WriteLimit`] let through.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct WriteReport {
+    pub limit_bytes_per_sec: u64,
+    pub bytes: u64,
+    /// From the first write to the end of the last
+    pub seconds: f64,
+    pub bytes_per_sec: f64,
+    /// The most written in any whole second, if the writes spanned one
+    pub peak_bytes_per_sec: Option<u64>,
+    /// Time writes spent waiting for the bucket, added up over workers
+    pub throttled_seconds: f64,
+}
+
+impl WriteLimit {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        let bytes_per_sec = bytes_per_sec.max(1) as f64;
+        WriteLimit {
+            bytes_per_sec,
+            state: Mutex::new(State {
+                tokens: bytes_per_sec * BURST.as_secs_f64(),
+                refilled: Instant::now(),
+                started: None,
+                last: None,
+                seconds: Vec::new(),
+                waited: Duration::ZERO,
+            }),
+        }
+    }
+
+    /// The most bytes to write at a time: a burst, or 64 KiB at least.
+    pub(crate) fn piece_len(&self) -> usize {
+        ((self.bytes_per_sec * BURST.as_secs_f64()) as usize).max(MIN_PIECE)
+    }
+
+    /// Writes `len` bytes through `write` once the bucket allows them.
+    pub(crate) fn write<R>(&self, len: usize, write: impl FnOnce() -> R) -> R {
+        let wait = {
+            let mut state = self.state.lock().unwrap();
+            let now = Instant::now();
+            let capacity = self.bytes_per_sec * BURST.as_secs_f64();
+            let refill = now.duration_since(state.refilled).as_secs_f64() * self.bytes_per_sec;
+            state.tokens = (state.tokens + refill).min(capacity) - len as f64;
+            state.refilled = now;
+            let wait = Duration::from_secs_f64((-state.tokens).max(0.0) / self.bytes_per_sec);
+            state.waited += wait;
+            wait
+        };
+        std::thread::sleep(wait);
+        let written = write();
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let started = *state.started.get_or_insert(now - wait);
+        let second = now.duration_since(started).as_secs() as usize;
+        if state.seconds.len() <= second {
+            state.seconds.resize(second + 1, 0);
+        }
+        state.seconds[second] += len as u64;
+        state.last = Some(now);
+        written
+    }
+
+    /// What was written so far.
+    pub fn report(&self) -> WriteReport {
+        let state = self.state.lock().unwrap();
+        let bytes = state.seconds.iter().sum();
+        let seconds = match (state.started, state.last) {
+            (Some(started), Some(last)) => last.duration_since(started).as_secs_f64(),
+            _ => 0.0,
+        };
+        // The last second is cut short by the end of the run
+        let whole = state.seconds.len().saturating_sub(1);
+        WriteReport {
+            limit_bytes_per_sec: self.bytes_per_sec as u64,
+            bytes,
+            seconds,
+            bytes_per_sec: bytes as f64 / seconds.max(f64::MIN_POSITIVE),
+            peak_bytes_per_sec: state.seconds[..whole].iter().copied().max(),
+            throttled_seconds: state.waited.as_secs_f64(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_average_the_limit() {
+        // 40 writes of 64 KiB from 4 workers at 1 MiB/s take 2.5s less
+        // the burst, whatever order they come in
+        let limit = WriteLimit::new(1 << 20);
+        let started = Instant::now();
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for _ in 0..10 {
+                        limit.write(64 << 10, || ());
+                    }
+                });
+            }
+        });
+        let elapsed = started.elapsed().as_secs_f64();
+        assert!(elapsed > 2.3 && elapsed < 3.5, "{}", elapsed);
+
+        let report = limit.report();
+        assert_eq!(report.bytes, 40 << 16);
+        assert!(
+            report.bytes_per_sec < 1.1 * (1 << 20) as f64,
+            "{:?}",
+            report
+        );
+        let peak = report.peak_bytes_per_sec.unwrap();
+        assert!(peak <= (1 << 20) + (128 << 10), "{:?}", report);
+        assert!(report.throttled_seconds > 2.0, "{:?}", report);
+    }
+}
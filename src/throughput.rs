@@ -0,0 +1,162 @@
+//! Throughput over the course of a run: the cold start (allocation, page
+//! faults, turbo ramp-up) apart from the steady state after it, and the
+//! best whole second. A run and `bench` both account for it here.
+//!
+//! Each chunk is a [`Span`] of the run's timeline, its records taken to
+//! have come out evenly from its start to its end, so a chunk straddling
+//! the end of the warm-up counts toward each side in proportion. A worker
+//! does its chunks one after another, and a [`Clock`] takes each to have
+//! started when the worker's previous one ended.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// When a chunk was made, measured from the start of the run, and how
+/// many records it held.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Span {
+    pub start: Duration,
+    pub end: Duration,
+    pub records: usize,
+}
+
+/// Times chunks from the start of a run.
+pub struct Clock {
+    started: Instant,
+    /// When each worker's last chunk ended
+    last: Mutex<HashMap<Option<usize>, Duration>>,
+}
+
+impl Clock {
+    /// A clock whose run starts now.
+    pub fn start() -> Self {
+        Clock {
+            started: Instant::now(),
+            last: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The time since the run started.
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// The span of a chunk of `records` the calling worker just finished.
+    pub fn finished(&self, records: usize) -> Span {
+        let end = self.started.elapsed();
+        let worker = rayon::current_thread_index();
+        let start = self
+            .last
+            .lock()
+            .unwrap()
+            .insert(worker, end)
+            .unwrap_or_default();
+        Span {
+            start,
+            end,
+            records,
+        }
+    }
+}
+
+/// Records per second before and after the warm-up, and in the best whole
+/// second; each `None` where the run was too short to have one.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Split {
+    pub cold: Option<f64>,
+    pub steady: Option<f64>,
+    pub peak: Option<f64>,
+}
+
+impl Split {
+    /// The split of a run that took `duration` and made `spans`, its first
+    /// `warmup` the cold start.
+    pub fn of(spans: &[Span], warmup: Duration, duration: Duration) -> Self {
+        let (warmup, secs) = (warmup.as_secs_f64(), duration.as_secs_f64());
+        let cold_secs = warmup.min(secs);
+        let whole_seconds = secs.floor() as usize;
+        Split {
+            cold: (cold_secs > 0.0).then(|| made_within(spans, 0.0, cold_secs) / cold_secs),
+            steady: (secs > warmup)
+                .then(|| made_within(spans, warmup, f64::INFINITY) / (secs - warmup)),
+            peak: (0..whole_seconds)
+                .map(|s| made_within(spans, s as f64, s as f64 + 1.0))
+                .max_by(f64::total_cmp),
+        }
+    }
+}
+
+/// The records `spans` made from `from` to `to` seconds into the run.
+fn made_within(spans: &[Span], from: f64, to: f64) -> f64 {
+    spans
+        .iter()
+        .map(|span| {
+            let (start, end) = (span.start.as_secs_f64(), span.end.as_secs_f64());
+            if end <= start {
+                // Made in an instant: all of it on one side
+                return match (from..to).contains(&end) {
+                    true => span.records as f64,
+                    false => 0.0,
+                };
+            }
+            let overlap = end.min(to) - start.max(from);
+            span.records as f64 * (overlap / (end - start)).max(0.0)
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span(start: f64, end: f64, records: usize) -> Span {
+        Span {
+            start: Duration::from_secs_f64(start),
+            end: Duration::from_secs_f64(end),
+            records,
+        }
+    }
+
+    #[test]
+    fn chunks_straddling_the_warmup_count_in_proportion() {
+        // The chunk from 0.5s to 1.5s straddles the end of the warm-up
+        let spans = [
+            span(0.0, 0.5, 50),
+            span(0.5, 1.5, 300),
+            span(1.5, 2.5, 200),
+            span(2.5, 3.0, 50),
+        ];
+        let split = Split::of(&spans, Duration::from_secs(1), Duration::from_secs(3));
+        assert_eq!(split.cold, Some(200.0));
+        // 150 of the straddling chunk, then 250 more, over two seconds
+        assert_eq!(split.steady, Some(200.0));
+        assert_eq!(split.peak, Some(250.0));
+
+        let short = Split::of(
+            &spans[..1],
+            Duration::from_secs(1),
+            Duration::from_secs_f64(0.5),
+        );
+        assert_eq!(
+            short,
+            Split {
+                cold: Some(100.0),
+                steady: None,
+                peak: None,
+            }
+        );
+        let unwarmed = Split::of(&spans, Duration::ZERO, Duration::from_secs(3));
+        assert_eq!((unwarmed.cold, unwarmed.steady), (None, Some(200.0)));
+    }
+
+    #[test]
+    fn workers_chunks_follow_on() {
+        let clock = Clock::start();
+        let first = clock.finished(10);
+        let second = clock.finished(20);
+        assert_eq!(first.start, Duration::ZERO);
+        assert_eq!(second.start, first.end);
+        assert!(second.end >= second.start);
+    }
+}
@@ -0,0 +1,316 @@
+//! Synthetic per-record timestamps for time-series test data.
+//!
+//! A [`TimestampSpread`] is a time window and a distribution over it. Bound
+//! to a run with [`for_run`](TimestampSpread::for_run), it maps each record
+//! index to a second within the window by hashing the index with a seed
+//! derived from the run's key, so the same key always gives every record
+//! the same timestamp, in any chunk order and on any machine.
+
+use std::fmt;
+
+use crate::{Lane, PasswordGenerator};
+
+/// Width of a timestamp as written, `2024-01-01T00:00:00Z`.
+pub const TIMESTAMP_LEN: usize = 20;
+
+/// Last second [`TimestampSpread`] windows may reach, so every timestamp
+/// has a four-digit year.
+const MAX_SECS: u64 = 253_402_300_800; // 10000-01-01
+
+/// How timestamps fall within the window.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Distribution {
+    /// Independently uniform over the window
+    #[default]
+    Uniform,
+    /// Evenly spaced, increasing with the record index
+    Ordered,
+    /// Density rising linearly from the start of the window to the end, as
+    /// in a growing user base
+    Ramp,
+    /// Normal around the middle of the window, three standard deviations to
+    /// either edge, clamped to it
+    Normal,
+}
+
+impl Distribution {
+    /// Parses `uniform`, `ordered`, `ramp` or `normal`.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s {
+            "uniform" => Ok(Distribution::Uniform),
+            "ordered" => Ok(Distribution::Ordered),
+            "ramp" => Ok(Distribution::Ramp),
+            "normal" => Ok(Distribution::Normal),
+            _ => Err(format!(
+                "unknown distribution `{}` (expected uniform, ordered, ramp or normal)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Distribution {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Distribution::Uniform => "uniform",
+            Distribution::Ordered => "ordered",
+            Distribution::Ramp => "ramp",
+            Distribution::Normal => "normal",
+        })
+    }
+}
+
+/// A window of unix seconds, `start` inclusive and `end` exclusive.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimestampSpread {
+    pub start: u64,
+    pub end: u64,
+    pub distribution: Distribution,
+}
+
+impl TimestampSpread {
+    /// Parses `START..END`, each `YYYY-MM-DD` or `YYYY-MM-DDTHH:MM:SSZ`. A
+    /// date alone as the end includes that whole day.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let (start, end) = s
+            .split_once("..")
+            .ok_or_else(|| format!("expected START..END, got `{}`", s))?;
+        let start = parse_time(start)?;
+        let end = parse_time(end)? + if end.contains('T') { 0 } else { 86_400 };
+        if start >= end {
+            return Err(format!("`{}` is an empty window", s));
+        }
+        if end > MAX_SECS {
+            return Err(format!("`{}` runs past the year 9999", s));
+        }
+        Ok(TimestampSpread {
+            start,
+            end,
+            distribution: Distribution::Uniform,
+        })
+    }
+
+    /// The spread over the same window with `distribution`.
+    pub fn with_distribution(self, distribution: Distribution) -> Self {
+        TimestampSpread {
+            distribution,
+            ..self
+        }
+    }
+
+    /// Timestamps for the records of `generator`'s run, seeded by its key.
+    pub fn for_run(&self, generator: &PasswordGenerator) -> Timestamps {
+        // A lane of its own, so timestamps say nothing about the keystream
+        let seed = Lane::TIMESTAMPS.subkey(&generator.key);
+        Timestamps {
+            spread: *self,
+            seed: u64::from_le_bytes(seed[..8].try_into().unwrap()),
+            count: generator.count(),
+        }
+    }
+}
+
+impl fmt::Display for TimestampSpread {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}..{} ({})",
+            format_rfc3339(self.start),
+            format_rfc3339(self.end),
+            self.distribution
+        )
+    }
+}
+
+/// A [`TimestampSpread`] bound to a run.
+#[derive(Clone, Copy, Debug)]
+pub struct Timestamps {
+    spread: TimestampSpread,
+    seed: u64,
+    count: usize,
+}
+
+impl Timestamps {
+    /// Unix seconds of record `index`.
+    pub fn at(&self, index: usize) -> u64 {
+        let TimestampSpread {
+            start,
+            end,
+            distribution,
+        } = self.spread;
+        let span = end - start;
+        let offset = match distribution {
+            Distribution::Uniform => (self.unit(index, 0) * span as f64) as u64,
+            Distribution::Ordered => {
+                (index as u128 * span as u128 / self.count.max(1) as u128) as u64
+            }
+            Distribution::Ramp => (self.unit(index, 0).sqrt() * span as f64) as u64,
+            Distribution::Normal => {
+                // Box-Muller; 1 - u keeps the logarithm finite
+                let radius = (-2.0 * (1.0 - self.unit(index, 0)).ln()).sqrt();
+                let z = radius * (std::f64::consts::TAU * self.unit(index, 1)).cos();
+                let centred = 0.5 + z.clamp(-3.0, 3.0) / 6.0;
+                (centred * span as f64) as u64
+            }
+        };
+        start + offset.min(span - 1)
+    }
+
+    /// Record `index`'s timestamp as `YYYY-MM-DDTHH:MM:SSZ`.
+    pub fn format(&self, index: usize) -> String {
+        format_rfc3339(self.at(index))
+    }
+
+    /// A uniform fraction in [0, 1) for the `stream`th draw of record
+    /// `index`.
+    fn unit(&self, index: usize, stream: u64) -> f64 {
+        let z = mix(self.seed ^ mix((index as u64) << 1 | stream));
+        (z >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// The SplitMix64 finalizer.
+fn mix(mut z: u64) -> u64 {
+    z = z.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// Parses `YYYY-MM-DD` (UTC midnight) or `YYYY-MM-DDTHH:MM:SSZ`.
+pub fn parse_time(s: &str) -> Result<u64, String> {
+    let invalid = || format!("expected YYYY-MM-DD or YYYY-MM-DDTHH:MM:SSZ, got `{}`", s);
+    let (date, time) = match s.split_once('T') {
+        Some((date, time)) => (date, Some(time.strip_suffix('Z').ok_or_else(invalid)?)),
+        None => (s, None),
+    };
+    let fields = |text: &str, sep: char| -> Option<Vec<u64>> {
+        text.split(sep).map(|v| v.parse().ok()).collect()
+    };
+    let [y, m, d] = fields(date, '-')
+        .and_then(|v| <[u64; 3]>::try_from(v).ok())
+        .ok_or_else(invalid)?;
+    let [hh, mm, ss] = match time {
+        Some(time) => fields(time, ':')
+            .and_then(|v| <[u64; 3]>::try_from(v).ok())
+            .ok_or_else(invalid)?,
+        None => [0, 0, 0],
+    };
+    if !(1970..=9999).contains(&y)
+        || !(1..=12).contains(&m)
+        || !(1..=31).contains(&d)
+        || hh > 23
+        || mm > 59
+        || ss > 59
+    {
+        return Err(invalid());
+    }
+    let days = days_from_civil(y as i64, m as u32, d as u32) as u64;
+    Ok(days * 86_400 + hh * 3600 + mm * 60 + ss)
+}
+
+/// Formats unix seconds as `YYYY-MM-DDTHH:MM:SSZ`.
+pub fn format_rfc3339(secs: u64) -> String {
+    let (days, rem) = (secs / 86_400, secs % 86_400);
+    let (y, m, d) = civil_from_days(days as i64);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        y,
+        m,
+        d,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+// Howard Hinnant's days-from-civil algorithms (proleptic Gregorian calendar).
+pub fn days_from_civil(y: i64, m: u32, d: u32) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = (m as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + d as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+pub fn civil_from_days(z: i64) -> (i64, u32, u32) {
+    let z = z + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let y = yoe + era * 400 + (m <= 2) as i64;
+    (y, m, d)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timestamps_are_reproducible_and_in_the_window() {
+        let spread = TimestampSpread::parse("2024-01-01..2024-06-30").unwrap();
+        assert_eq!(format_rfc3339(spread.start), "2024-01-01T00:00:00Z");
+        assert_eq!(format_rfc3339(spread.end), "2024-07-01T00:00:00Z");
+        assert_eq!(
+            TimestampSpread::parse("2024-01-01T12:00:00Z..2024-01-01T13:00:00Z")
+                .unwrap()
+                .end
+                - spread.start,
+            13 * 3600
+        );
+        for bad in [
+            "2024-01-01",
+            "2024-02-01..2024-01-01",
+            "2024-13-01..2025-01-01",
+        ] {
+            assert!(TimestampSpread::parse(bad).is_err(), "{}", bad);
+        }
+
+        let generator = |key| {
+            PasswordGenerator::builder()
+                .key(key)
+                .count(10_000)
+                .build()
+                .unwrap()
+        };
+        for distribution in [
+            Distribution::Uniform,
+            Distribution::Ordered,
+            Distribution::Ramp,
+            Distribution::Normal,
+        ] {
+            let spread = spread.with_distribution(distribution);
+            let run = spread.for_run(&generator([1; 16]));
+            let times: Vec<u64> = (0..10_000).map(|i| run.at(i)).collect();
+            assert!(times.iter().all(|t| (spread.start..spread.end).contains(t)));
+            let again = spread.for_run(&generator([1; 16]));
+            assert!((0..10_000).all(|i| again.at(i) == times[i]));
+            let mean = times
+                .iter()
+                .map(|&t| (t - spread.start) as f64)
+                .sum::<f64>()
+                / times.len() as f64
+                / (spread.end - spread.start) as f64;
+            let expected = if distribution == Distribution::Ramp {
+                2.0 / 3.0
+            } else {
+                0.5
+            };
+            assert!((mean - expected).abs() < 0.02, "{} {}", distribution, mean);
+        }
+        let ordered = spread
+            .with_distribution(Distribution::Ordered)
+            .for_run(&generator([1; 16]));
+        assert!((1..10_000).all(|i| ordered.at(i) > ordered.at(i - 1)));
+        let other = spread.for_run(&generator([2; 16]));
+        let run = spread.for_run(&generator([1; 16]));
+        assert!((0..100).any(|i| other.at(i) != run.at(i)));
+        assert_eq!(run.format(0).len(), TIMESTAMP_LEN);
+    }
+}
@@ -0,0 +1,241 @@
+//! `serve --tls-cert`: HTTPS, and with `--client-ca` mutual TLS; and the
+//! client side of both for `fetch`.
+//!
+//! Certificates and keys are read from PEM files. With a client CA, the
+//! handshake fails for any client without a certificate the CA issued, and
+//! a client that has one is known by its certificate's SHA-256 fingerprint
+//! in the rate limits and the audit log. Clients trust the CAs they are
+//! given and no others.
+
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use mass_password_gen_optimized::hex;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{
+    ClientConfig, ClientConnection, RootCertStore, ServerConfig, ServerConnection, StreamOwned,
+};
+use sha2::{Digest, Sha256};
+
+/// The server's configuration: the chain of `cert` with its `key`, and
+/// with `client_ca` a certificate it issued required of every client.
+pub fn server_config(
+    cert: &Path,
+    key: &Path,
+    client_ca: Option<&Path>,
+) -> Result<Arc<ServerConfig>, String> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let chain = certificates(cert)?;
+    let key = PrivateKeyDer::from_pem_file(key).map_err(|e| format!("{}: {}", key.display(), e))?;
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?;
+    let builder = match client_ca {
+        Some(path) => {
+            let verifier =
+                WebPkiClientVerifier::builder_with_provider(Arc::new(roots(path)?), provider)
+                    .build()
+                    .map_err(|e| format!("{}: {}", path.display(), e))?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let config = builder
+        .with_single_cert(chain, key)
+        .map_err(|e| format!("{}: {}", cert.display(), e))?;
+    Ok(Arc::new(config))
+}
+
+/// A client's configuration: trusting the CAs of `ca`, and with `identity`
+/// presenting that certificate chain and key to servers that ask for one.
+pub fn client_config(
+    ca: &Path,
+    identity: Option<(&Path, &Path)>,
+) -> Result<Arc<ClientConfig>, String> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?
+        .with_root_certificates(roots(ca)?);
+    let config = match identity {
+        Some((cert, key)) => {
+            let chain = certificates(cert)?;
+            let key = PrivateKeyDer::from_pem_file(key)
+                .map_err(|e| format!("{}: {}", key.display(), e))?;
+            builder
+                .with_client_auth_cert(chain, key)
+                .map_err(|e| format!("{}: {}", cert.display(), e))?
+        }
+        None => builder.with_no_client_auth(),
+    };
+    Ok(Arc::new(config))
+}
+
+/// The CAs of a PEM file.
+fn roots(path: &Path) -> Result<RootCertStore, String> {
+    let mut roots = RootCertStore::empty();
+    for ca in certificates(path)? {
+        roots
+            .add(ca)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+    }
+    Ok(roots)
+}
+
+/// The certificates of a PEM file, at least one.
+fn certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>, String> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    if certs.is_empty() {
+        return Err(format!("{}: no certificates in it", path.display()));
+    }
+    Ok(certs)
+}
+
+/// Completes the handshake on `stream`, returning the connection and the
+/// fingerprint of the client's certificate, if it presented one.
+pub fn accept<S: Read + Write>(
+    config: &Arc<ServerConfig>,
+    mut stream: S,
+) -> io::Result<(StreamOwned<ServerConnection, S>, Option<String>)> {
+    let mut connection = ServerConnection::new(config.clone()).map_err(io::Error::other)?;
+    while connection.is_handshaking() {
+        connection.complete_io(&mut stream)?;
+    }
+    let client = connection
+        .peer_certificates()
+        .and_then(|chain| chain.first())
+        .map(|cert| fingerprint(cert));
+    Ok((StreamOwned::new(connection, stream), client))
+}
+
+/// Completes the handshake with the server `host` on `stream`.
+pub fn connect<S: Read + Write>(
+    config: &Arc<ClientConfig>,
+    host: &str,
+    mut stream: S,
+) -> io::Result<StreamOwned<ClientConnection, S>> {
+    let name = ServerName::try_from(host.to_string())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("{}: {}", host, e)))?;
+    let mut connection = ClientConnection::new(config.clone(), name).map_err(io::Error::other)?;
+    while connection.is_handshaking() {
+        connection.complete_io(&mut stream)?;
+    }
+    Ok(StreamOwned::new(connection, stream))
+}
+
+/// `sha256:<hex>` of a DER certificate: what `openssl x509 -noout
+/// -fingerprint -sha256` prints, in lowercase and without the colons.
+pub fn fingerprint(cert: &[u8]) -> String {
+    format!("sha256:{}", hex(&Sha256::digest(cert)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::listen::Listener;
+    use crate::serve::{run, AuditLog, Policy, Reply};
+    use rcgen::{BasicConstraints, CertificateParams, ExtendedKeyUsagePurpose, IsCa, KeyPair};
+    use rustls::pki_types::PrivatePkcs8KeyDer;
+    use std::net::{TcpListener, TcpStream};
+
+    #[test]
+    fn clients_need_a_certificate_of_the_ca() {
+        let dir = std::env::temp_dir().join(format!("mpg_tls_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let issue = |names: &[&str], usage, ca: Option<(&rcgen::Certificate, &KeyPair)>| {
+            let key = KeyPair::generate().unwrap();
+            let names: Vec<String> = names.iter().map(|n| n.to_string()).collect();
+            let mut params = CertificateParams::new(names).unwrap();
+            let cert = match ca {
+                Some((ca, ca_key)) => {
+                    params.extended_key_usages = vec![usage];
+                    params.signed_by(&key, ca, ca_key).unwrap()
+                }
+                None => {
+                    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+                    params.self_signed(&key).unwrap()
+                }
+            };
+            (cert, key)
+        };
+        let (ca, ca_key) = issue(&[], ExtendedKeyUsagePurpose::Any, None);
+        let (server, server_key) = issue(
+            &["localhost"],
+            ExtendedKeyUsagePurpose::ServerAuth,
+            Some((&ca, &ca_key)),
+        );
+        let (client, client_key) = issue(
+            &["client"],
+            ExtendedKeyUsagePurpose::ClientAuth,
+            Some((&ca, &ca_key)),
+        );
+        let write = |name: &str, pem: String| {
+            let path = dir.join(name);
+            std::fs::write(&path, pem).unwrap();
+            path
+        };
+        let config = server_config(
+            &write("server.pem", server.pem()),
+            &write("server.key", server_key.serialize_pem()),
+            Some(&write("ca.pem", ca.pem())),
+        )
+        .unwrap();
+        assert!(server_config(&dir.join("ca.pem"), &dir.join("ca.pem"), None).is_err());
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let log = dir.join("audit.jsonl");
+        let policy = Policy {
+            tls: Some(config),
+            audit: Some(AuditLog::open(&log).unwrap()),
+            ..Default::default()
+        };
+        std::thread::spawn(move || {
+            run(Listener::Tcp(listener), &policy, |_| Reply::Stream {
+                content_type: "text/plain",
+                headers: Vec::new(),
+                pulled: None,
+                body: Box::new(|out| out.write_all(b"secret")),
+            })
+        });
+
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut roots = RootCertStore::empty();
+        roots.add(ca.der().clone()).unwrap();
+        let builder = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots);
+        let get = |config: ClientConfig| {
+            let name = ServerName::try_from("localhost").unwrap();
+            let connection = ClientConnection::new(Arc::new(config), name).unwrap();
+            let mut stream = StreamOwned::new(connection, TcpStream::connect(addr).unwrap());
+            let mut reply = String::new();
+            stream
+                .write_all(b"GET /x HTTP/1.1\r\n\r\n")
+                .and_then(|()| stream.read_to_string(&mut reply))
+                .map(|_| reply)
+        };
+
+        let key = PrivatePkcs8KeyDer::from(client_key.serialize_der());
+        let with_cert = builder
+            .clone()
+            .with_client_auth_cert(vec![client.der().clone()], key.into())
+            .unwrap();
+        let reply = get(with_cert).unwrap();
+        assert!(reply.starts_with("HTTP/1.1 200 OK"), "{}", reply);
+        assert!(reply.contains("secret"));
+        // Without a certificate the handshake fails
+        assert!(get(builder.with_no_client_auth()).is_err());
+
+        let audited = std::fs::read_to_string(&log).unwrap();
+        let entry: serde_json::Value = serde_json::from_str(audited.trim()).unwrap();
+        assert_eq!(entry["client"], fingerprint(client.der()));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,374 @@
+//! Self-expiring tokens: each password carries its own expiry and an HMAC
+//! that vouches for both, so a service holding the run's validation key
+//! can reject a stale or forged credential without looking anything up.
+//!
+//! The validation key is the token lane of the run's key (see
+//! [`validation_key`]), not the run's key itself: a service that checks
+//! tokens can mint them, but cannot regenerate the passwords.
+//!
+//! A token is one line of text, `INDEX.EXPIRY.MAC.PASSWORD`: the record's
+//! index, its expiry in unix seconds, 32 hex digits of HMAC-SHA256 over the
+//! three other fields, and the password itself (hex for raw blocks). The
+//! password comes last so that whatever characters it holds, the first
+//! three dots split the token unambiguously.
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::fmt;
+
+use crate::format::{decimal_digits, push_hex, push_text};
+use crate::timestamp::format_rfc3339;
+use crate::{Lane, OutputSink, PasswordGenerator};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Bytes of the HMAC kept in a token.
+const MAC_LEN: usize = 16;
+
+/// Parses a time to live such as `90d`: a whole number of seconds (`s`),
+/// minutes (`m`), hours (`h`), days (`d`) or weeks (`w`). Returns seconds.
+pub fn parse_ttl(s: &str) -> Result<u64, String> {
+    let invalid = || format!("expected a duration such as 90d, 12h or 30m, got `{}`", s);
+    let unit = match s.bytes().last().ok_or_else(invalid)? {
+        b's' => 1,
+        b'm' => 60,
+        b'h' => 3600,
+        b'd' => 86_400,
+        b'w' => 7 * 86_400,
+        _ => return Err(invalid()),
+    };
+    let n: u64 = s[..s.len() - 1].parse().map_err(|_| invalid())?;
+    match n.checked_mul(unit) {
+        Some(0) => Err("the time to live must be positive".to_string()),
+        Some(secs) => Ok(secs),
+        None => Err(invalid()),
+    }
+}
+
+/// Writes `generator`'s records as tokens that all expire at `expires_at`
+/// (unix seconds).
+pub struct ExpiringTokens {
+    mac: HmacSha256,
+    expires_at: u64,
+    binary: bool,
+}
+
+impl ExpiringTokens {
+    pub fn new(generator: &PasswordGenerator, expires_at: u64) -> Self {
+        ExpiringTokens {
+            mac: keyed(&validation_key(&generator.key)),
+            expires_at,
+            binary: generator.is_binary(),
+        }
+    }
+}
+
+impl OutputSink for ExpiringTokens {
+    fn write_record(&self, index: usize, record: &[u8], out: &mut Vec<u8>) {
+        let mut password = Vec::with_capacity(record.len() * 2);
+        self.credential(index, record, &mut password);
+        let tag = tag(&self.mac, index as u64, self.expires_at, &password);
+        out.extend_from_slice(format!("{}.{}.", index, self.expires_at).as_bytes());
+        push_hex(&tag[..MAC_LEN], out);
+        out.push(b'.');
+        out.extend_from_slice(&password);
+        out.push(b'\n');
+    }
+
+    fn record_len(&self, index: usize, record_len: usize) -> Option<usize> {
+        let password = if self.binary {
+            record_len * 2
+        } else {
+            record_len
+        };
+        let expiry = decimal_digits(self.expires_at as usize);
+        Some(decimal_digits(index) + 1 + expiry + 1 + MAC_LEN * 2 + 1 + password + 1)
+    }
+
+    fn credential(&self, _index: usize, record: &[u8], out: &mut Vec<u8>) {
+        push_text(self.binary, record, out);
+    }
+}
+
+/// A token that checked out.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Token {
+    pub index: usize,
+    pub expires_at: u64,
+    pub password: String,
+}
+
+/// Why a token was rejected.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TokenError {
+    /// Not of the form `INDEX.EXPIRY.MAC.PASSWORD`
+    Malformed,
+    /// The MAC does not match: forged, altered, or from another key
+    BadMac,
+    /// Genuine, but past its expiry
+    Expired(Token),
+}
+
+impl fmt::Display for TokenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TokenError::Malformed => f.write_str("not a token"),
+            TokenError::BadMac => f.write_str("the MAC does not match"),
+            TokenError::Expired(token) => write!(
+                f,
+                "record {} expired at {}",
+                token.index,
+                format_rfc3339(token.expires_at)
+            ),
+        }
+    }
+}
+
+impl std::error::Error for TokenError {}
+
+/// The key that checks the tokens of a run keyed with `key`.
+pub fn validation_key(key: &[u8]) -> [u8; 32] {
+    Lane::TOKEN.subkey(key)
+}
+
+/// Checks tokens minted under one key.
+pub struct TokenValidator {
+    mac: HmacSha256,
+}
+
+impl TokenValidator {
+    /// A validator holding only the [`validation_key`].
+    pub fn new(validation_key: &[u8; 32]) -> Self {
+        TokenValidator {
+            mac: keyed(validation_key),
+        }
+    }
+
+    /// A validator for the tokens of a run keyed with `key`.
+    pub fn for_run(key: &[u8]) -> Self {
+        TokenValidator::new(&validation_key(key))
+    }
+
+    /// Checks `token`'s MAC, then its expiry against `now` (unix seconds).
+    /// Expired tokens are reported only once their MAC checks out.
+    pub fn validate(&self, token: &str, now: u64) -> Result<Token, TokenError> {
+        let mut fields = token.splitn(4, '.');
+        let (Some(index), Some(expiry), Some(mac), Some(password)) =
+            (fields.next(), fields.next(), fields.next(), fields.next())
+        else {
+            return Err(TokenError::Malformed);
+        };
+        let decimal = |s: &str| {
+            s.bytes()
+                .all(|b| b.is_ascii_digit())
+                .then(|| s.parse::<u64>().ok())
+                .flatten()
+        };
+        let (Some(index), Some(expires_at)) = (decimal(index), decimal(expiry)) else {
+            return Err(TokenError::Malformed);
+        };
+        let tag = decode_hex(mac).ok_or(TokenError::Malformed)?;
+        let mut expected = self.mac.clone();
+        expected.update(&message(index, expires_at, password.as_bytes()));
+        expected
+            .verify_truncated_left(&tag)
+            .map_err(|_| TokenError::BadMac)?;
+        let token = Token {
+            index: index as usize,
+            expires_at,
+            password: password.to_string(),
+        };
+        if now >= expires_at {
+            return Err(TokenError::Expired(token));
+        }
+        Ok(token)
+    }
+}
+
+fn keyed(validation_key: &[u8; 32]) -> HmacSha256 {
+    HmacSha256::new_from_slice(validation_key).expect("HMAC accepts any key length")
+}
+
+/// The MAC input: index and expiry, then the password.
+fn message(index: u64, expires_at: u64, password: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(16 + password.len());
+    message.extend_from_slice(&index.to_le_bytes());
+    message.extend_from_slice(&expires_at.to_le_bytes());
+    message.extend_from_slice(password);
+    message
+}
+
+fn tag(mac: &HmacSha256, index: u64, expires_at: u64, password: &[u8]) -> Vec<u8> {
+    let mut mac = mac.clone();
+    mac.update(&message(index, expires_at, password));
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Exactly `MAC_LEN` bytes of lowercase hex.
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if s.len() != MAC_LEN * 2 || !s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) {
+        return None;
+    }
+    (0..MAC_LEN)
+        .map(|i| u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Charset, Encoder, Format};
+
+    #[test]
+    fn tokens_validate_until_they_expire() {
+        assert_eq!(parse_ttl("90d"), Ok(90 * 86_400));
+        assert_eq!(parse_ttl("2w"), Ok(14 * 86_400));
+        for bad in ["", "90", "d", "0h", "-1d", "1.5h", "99999999999999999999w"] {
+            assert!(parse_ttl(bad).is_err(), "{:?}", bad);
+        }
+
+        let expires_at = 1_800_000_000;
+        for builder in [
+            PasswordGenerator::builder(),
+            PasswordGenerator::builder().encoder(Encoder::new(&Charset::AlnumSymbols, 20)),
+        ] {
+            let g = builder
+                .key([7; 16])
+                .count(500)
+                .chunk_size(64)
+                .build()
+                .unwrap();
+            let sink = ExpiringTokens::new(&g, expires_at);
+            let mut out = Vec::new();
+            g.write_formatted(&sink, &mut out, |_, _| ()).unwrap();
+            assert_eq!(
+                crate::format::serialized_len(&sink, &g),
+                Some(out.len() as u128)
+            );
+
+            let validator = TokenValidator::for_run(&[7; 16]);
+            let text = String::from_utf8(out).unwrap();
+            let records: Vec<Vec<u8>> = g.records().collect();
+            for (index, line) in text.lines().enumerate() {
+                let token = validator.validate(line, expires_at - 1).unwrap();
+                assert_eq!((token.index, token.expires_at), (index, expires_at));
+                let mut password = Vec::new();
+                if g.is_binary() {
+                    push_hex(&records[index], &mut password);
+                } else {
+                    password = records[index].clone();
+                }
+                assert_eq!(token.password.as_bytes(), password);
+                assert!(matches!(
+                    validator.validate(line, expires_at),
+                    Err(TokenError::Expired(t)) if t == token
+                ));
+            }
+
+            let line = text.lines().nth(3).unwrap();
+            let other = TokenValidator::for_run(&[8; 16]);
+            assert_eq!(other.validate(line, 0), Err(TokenError::BadMac));
+            // Moving the expiry, or the record, breaks the MAC
+            let later = line.replacen(".1800000000.", ".1900000000.", 1);
+            assert_eq!(validator.validate(&later, 0), Err(TokenError::BadMac));
+            let moved = format!("4{}", &line[1..]);
+            assert_eq!(validator.validate(&moved, 0), Err(TokenError::BadMac));
+            for bad in [
+                "",
+                "3.1800000000.00",
+                "x.1.00000000000000000000000000000000.p",
+            ] {
+                assert_eq!(validator.validate(bad, 0), Err(TokenError::Malformed));
+            }
+        }
+        assert_eq!(Format::parse("expiring-token"), Ok(Format::ExpiringToken));
+    }
+
+    #[test]
+    fn tokens_split_on_their_first_three_dots() {
+        let mac = keyed(&validation_key(&[7; 16]));
+        let mint = |index: u64, password: &str| {
+            let mut token = format!("{}.1800000000.", index).into_bytes();
+            let mac = tag(&mac, index, 1_800_000_000, password.as_bytes());
+            push_hex(&mac[..MAC_LEN], &mut token);
+            String::from_utf8(token).unwrap() + "." + password
+        };
+        let validator = TokenValidator::for_run(&[7; 16]);
+        let dotted = mint(3, "a.b..c.");
+        assert_eq!(validator.validate(&dotted, 0).unwrap().password, "a.b..c.");
+        assert_eq!(
+            validator.validate(&dotted.replacen("a.b", "a.c", 1), 0),
+            Err(TokenError::BadMac)
+        );
+        let genuine = mint(12, "pw");
+        assert!(validator.validate(&genuine, 0).is_ok());
+        // Only plain decimals and lowercase MACs are tokens
+        for bad in [
+            format!("+{}", genuine),
+            genuine.replacen("12.", " 12.", 1),
+            genuine.to_uppercase().replacen("PW", "pw", 1),
+            genuine.replacen(".pw", "", 1),
+        ] {
+            assert_eq!(
+                validator.validate(&bad, 0),
+                Err(TokenError::Malformed),
+                "{}",
+                bad
+            );
+        }
+
+        let expired = validator.validate(&genuine, 1_800_000_000).unwrap_err();
+        assert_eq!(
+            expired.to_string(),
+            format!("record 12 expired at {}", format_rfc3339(1_800_000_000))
+        );
+
+        // The credential is the bare password, for checksums and previews
+        let g = PasswordGenerator::builder()
+            .key([7; 16])
+            .count(1)
+            .build()
+            .unwrap();
+        let mut credential = Vec::new();
+        ExpiringTokens::new(&g, 1).credential(0, &[0xab; 16], &mut credential);
+        assert_eq!(credential, "ab".repeat(16).as_bytes());
+    }
+
+    #[test]
+    fn the_validation_key_cannot_regenerate_records() {
+        use crate::Key;
+        let key = Key::from([7; 16]);
+        let run = |key: Key| {
+            PasswordGenerator::builder()
+                .key(key)
+                .count(64)
+                .chunk_size(16)
+                .build()
+                .unwrap()
+        };
+        let g = run(key);
+        let mut out = Vec::new();
+        g.write_formatted(&ExpiringTokens::new(&g, 1_800_000_000), &mut out, |_, _| ())
+            .unwrap();
+        let text = String::from_utf8(out).unwrap();
+
+        // The validation key alone checks every token...
+        let checked = validation_key(&key);
+        let validator = TokenValidator::new(&checked);
+        assert!(text.lines().all(|line| validator.validate(line, 0).is_ok()));
+        // ...but is not the run's key, nor a run key whose records, at
+        // either width, are the run's
+        assert!(checked.windows(16).all(|w| w != &key[..]));
+        let records: Vec<Vec<u8>> = g.records().collect();
+        for guess in [Key::from(checked), Key::from_slice(&checked[..16]).unwrap()] {
+            let shared = run(guess).records().filter(|r| records.contains(r)).count();
+            assert_eq!(shared, 0);
+        }
+        // The run's key itself is no validation key
+        let mut raw = [0; 32];
+        raw[..16].copy_from_slice(&key);
+        assert!(text
+            .lines()
+            .all(|line| TokenValidator::new(&raw).validate(line, 0) == Err(TokenError::BadMac)));
+    }
+}
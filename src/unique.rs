@@ -0,0 +1,358 @@
+//! Finding duplicate records after encoding.
+//!
+//! Raw 16-byte blocks never repeat, but short encoded passwords and
+//! passphrases can. [`find_duplicates`] regenerates the run twice without
+//! writing it. The first pass inserts every record into a Bloom filter and
+//! keeps the records it reports as already seen. The second pass confirms
+//! those candidates exactly, so the result is exact; the filter's
+//! false-positive rate only decides how many candidates are held in memory.
+//!
+//! The filter keeps all of a record's bits in one 64-bit word, set with a
+//! single atomic `fetch_or`, so workers can insert concurrently without
+//! missing a duplicate: of two equal records, whichever sets its word
+//! second sees every bit already set.
+
+use rayon::prelude::*;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::hash::Hasher;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use crate::{OutputSink, PasswordGenerator};
+
+/// Most bits a record sets in its word; more does not help a one-word
+/// filter.
+const MAX_HASHES: u32 = 10;
+
+/// False-positive rates below this need impractically large filters.
+pub const MIN_FP_RATE: f64 = 1e-6;
+
+/// A Bloom filter with every item's bits in a single word.
+struct Filter {
+    words: Vec<AtomicU64>,
+    hashes: u32,
+}
+
+impl Filter {
+    /// The smallest filter for `items` items at false-positive rate at most
+    /// `fp_rate`.
+    fn new(items: usize, fp_rate: f64) -> Self {
+        let items = items.max(1) as f64;
+        // Start from the standard optimum and grow until the estimate for
+        // a one-word filter fits
+        let mut bits = -items * fp_rate.ln() / std::f64::consts::LN_2.powi(2);
+        loop {
+            let words = (bits / 64.0).ceil().max(1.0);
+            let hashes =
+                ((bits / items * std::f64::consts::LN_2).round() as u32).clamp(1, MAX_HASHES);
+            if false_positive_rate(items / words, hashes) <= fp_rate {
+                return Filter {
+                    words: (0..words as usize).map(|_| AtomicU64::new(0)).collect(),
+                    hashes,
+                };
+            }
+            bits *= 1.1;
+        }
+    }
+
+    fn bytes(&self) -> usize {
+        self.words.len() * 8
+    }
+
+    /// Adds `record`, returning whether it may have been added before.
+    fn insert(&self, record: &[u8]) -> bool {
+        let (word, mask) = self.locate(record);
+        self.words[word].fetch_or(mask, Ordering::Relaxed) & mask == mask
+    }
+
+    /// The word `record` goes into and the bits it sets there.
+    fn locate(&self, record: &[u8]) -> (usize, u64) {
+        let hash = |seed: u64| {
+            let mut h = DefaultHasher::new();
+            h.write_u64(seed);
+            h.write(record);
+            h.finish()
+        };
+        let word = ((hash(0) as u128 * self.words.len() as u128) >> 64) as usize;
+        let positions = hash(1);
+        let mask = (0..self.hashes).fold(0, |mask, i| mask | 1 << ((positions >> (6 * i)) & 63));
+        (word, mask)
+    }
+}
+
+/// Expected false-positive rate of a one-word filter holding `load` items
+/// per word on average, each setting `hashes` bits: the word a query lands
+/// in holds a Poisson-distributed number of items.
+fn false_positive_rate(load: f64, hashes: u32) -> f64 {
+    let limit = (load + 10.0 * load.sqrt() + 20.0) as i32;
+    let mut p_items = (-load).exp();
+    let mut rate = 0.0;
+    for items in 0..=limit {
+        let set = 1.0 - (63.0f64 / 64.0).powi(items * hashes as i32);
+        rate += p_items * set.powi(hashes as i32);
+        p_items *= load / (items + 1) as f64;
+    }
+    rate
+}
+
+/// The duplicates in a run, from [`find_duplicates`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Duplicates {
+    /// Records equal to an earlier record, in index order
+    pub indices: Vec<usize>,
+    /// Distinct values that occur more than once
+    pub values: usize,
+    /// Records the filter flagged, true duplicates included
+    pub candidates: usize,
+    /// Size of the filter
+    pub filter_bytes: usize,
+}
+
+/// Finds every record of `generator`'s run that repeats an earlier one,
+/// using a filter with false-positive rate `fp_rate` (at least
+/// [`MIN_FP_RATE`]).
+pub fn find_duplicates(generator: &PasswordGenerator, fp_rate: f64) -> Duplicates {
+    assert!(
+        (MIN_FP_RATE..1.0).contains(&fp_rate),
+        "false-positive rate out of range"
+    );
+    let filter = Filter::new(generator.count(), fp_rate);
+    let candidates = Mutex::new(HashSet::new());
+    let flagged: usize = for_each_chunk(generator, |_, data| {
+        let mut flagged = Vec::new();
+        generator.for_each_record(data, |record| {
+            if filter.insert(record) {
+                flagged.push(record.to_vec());
+            }
+        });
+        let count = flagged.len();
+        candidates.lock().unwrap().extend(flagged);
+        count
+    })
+    .into_iter()
+    .sum();
+    let candidates = candidates.into_inner().unwrap();
+    let mut duplicates = Duplicates {
+        candidates: flagged,
+        filter_bytes: filter.bytes(),
+        ..Duplicates::default()
+    };
+    if candidates.is_empty() {
+        return duplicates;
+    }
+
+    // Every occurrence of a candidate, in index order
+    let occurrences = for_each_chunk(generator, |first, data| {
+        let mut found = Vec::new();
+        let mut index = first;
+        generator.for_each_record(data, |record| {
+            if candidates.contains(record) {
+                found.push((index, record.to_vec()));
+            }
+            index += 1;
+        });
+        found
+    });
+    let mut seen: HashMap<Vec<u8>, usize> = HashMap::new();
+    for (index, record) in occurrences.into_iter().flatten() {
+        let count = seen.entry(record).or_default();
+        if *count > 0 {
+            duplicates.indices.push(index);
+        }
+        *count += 1;
+    }
+    duplicates.values = seen.values().filter(|&&count| count > 1).count();
+    duplicates
+}
+
+/// Runs `f(first_index, records)` on every chunk of the run in parallel and
+/// returns the results in chunk order.
+fn for_each_chunk<T: Send>(
+    generator: &PasswordGenerator,
+    f: impl Fn(usize, &[u8]) -> T + Sync,
+) -> Vec<T> {
+    let width = generator.record_width();
+    (0..generator.num_chunks())
+        .into_par_iter()
+        .map_init(
+            || (Vec::new(), Vec::new()),
+            |(scratch, out), chunk_idx| {
+                out.clear();
+                out.resize(generator.chunk_len(chunk_idx) * width, 0);
+                let used = generator.fill_chunk(chunk_idx, scratch, out);
+                f(chunk_idx * generator.chunk_size(), &out[..used])
+            },
+        )
+        .collect()
+}
+
+/// Wraps the output format (or native output, for `None`) and drops the
+/// records at the given indices; the rest keep their indices.
+pub struct WithoutRecords {
+    inner: Option<Box<dyn OutputSink>>,
+    binary: bool,
+    skip: HashSet<usize>,
+}
+
+impl WithoutRecords {
+    /// Drops `skip` from records serialized by `inner`; `binary` says
+    /// whether native records are raw blocks rather than lines of text.
+    pub fn new(
+        inner: Option<Box<dyn OutputSink>>,
+        binary: bool,
+        skip: impl IntoIterator<Item = usize>,
+    ) -> Self {
+        WithoutRecords {
+            inner,
+            binary,
+            skip: skip.into_iter().collect(),
+        }
+    }
+}
+
+impl OutputSink for WithoutRecords {
+    fn header(&self) -> &[u8] {
+        self.inner.as_ref().map_or(b"", |sink| sink.header())
+    }
+
+    fn write_record(&self, index: usize, record: &[u8], out: &mut Vec<u8>) {
+        if self.skip.contains(&index) {
+            return;
+        }
+        match &self.inner {
+            Some(sink) => sink.write_record(index, record, out),
+            None => {
+                out.extend_from_slice(record);
+                if !self.binary {
+                    out.push(b'\n');
+                }
+            }
+        }
+    }
+
+    fn record_len(&self, _index: usize, _record_len: usize) -> Option<usize> {
+        None
+    }
+
+    fn credential(&self, index: usize, record: &[u8], out: &mut Vec<u8>) {
+        match &self.inner {
+            Some(sink) => sink.credential(index, record, out),
+            None => out.extend_from_slice(record),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Charset, Encoder, Format};
+
+    #[test]
+    fn duplicates_are_found_exactly() {
+        // 2000 passwords from only 256 possible values
+        let g = PasswordGenerator::builder()
+            .key([5; 16])
+            .count(2000)
+            .chunk_size(300)
+            .encoder(Encoder::new(&Charset::Hex, 2))
+            .build()
+            .unwrap();
+        let records: Vec<Vec<u8>> = g.records().collect();
+        let mut first = HashMap::new();
+        let expected: Vec<usize> = (0..records.len())
+            .filter(|&i| *first.entry(&records[i]).or_insert(i) != i)
+            .collect();
+        let dups = find_duplicates(&g, 0.01);
+        assert_eq!(dups.indices, expected);
+        let repeated: HashSet<_> = expected.iter().map(|&i| &records[i]).collect();
+        assert_eq!(dups.values, repeated.len());
+        assert!(dups.candidates >= expected.len());
+
+        let sink = WithoutRecords::new(Format::Csv.sink(&g), false, dups.indices);
+        let mut out = Vec::new();
+        g.write_formatted(&sink, &mut out, |_, _| ()).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        let passwords: Vec<_> = text.lines().skip(1).map(|l| &l[l.len() - 2..]).collect();
+        assert_eq!(passwords.len(), first.len());
+        assert_eq!(passwords.iter().collect::<HashSet<_>>().len(), first.len());
+    }
+
+    #[test]
+    fn native_records_are_dropped_and_counted() {
+        // Raw blocks never repeat
+        let raw = PasswordGenerator::builder()
+            .key([6; 16])
+            .count(3000)
+            .chunk_size(500)
+            .build()
+            .unwrap();
+        let dups = find_duplicates(&raw, MIN_FP_RATE);
+        assert!(dups.indices.is_empty() && dups.values == 0);
+        assert_eq!(dups.filter_bytes, Filter::new(3000, MIN_FP_RATE).bytes());
+
+        // 16 values for 200 records: every value repeats many times over
+        let g = PasswordGenerator::builder()
+            .key([6; 16])
+            .count(200)
+            .encoder(Encoder::new(&Charset::Hex, 1))
+            .build()
+            .unwrap();
+        let dups = find_duplicates(&g, 0.01);
+        assert_eq!((dups.indices.len(), dups.values), (200 - 16, 16));
+
+        let skip = [0, 5, 199];
+        let sink = WithoutRecords::new(None, false, skip);
+        let mut out = Vec::new();
+        g.write_formatted(&sink, &mut out, |_, _| ()).unwrap();
+        let expected: Vec<u8> = g
+            .records()
+            .enumerate()
+            .filter(|(i, _)| !skip.contains(i))
+            .flat_map(|(_, mut record)| {
+                record.push(b'\n');
+                record
+            })
+            .collect();
+        assert_eq!(out, expected);
+
+        let sink = WithoutRecords::new(None, true, [1]);
+        let mut out = Vec::new();
+        raw.write_formatted(&sink, &mut out, |_, _| ()).unwrap();
+        let blocks: Vec<Vec<u8>> = raw.records().collect();
+        assert_eq!(out.len(), 2999 * 16);
+        assert_eq!((&out[..16], &out[16..32]), (&blocks[0][..], &blocks[2][..]));
+        // Dropped or not, a record's credential is what the inner sink says
+        let mut credential = Vec::new();
+        sink.credential(1, &blocks[1], &mut credential);
+        assert_eq!(credential, blocks[1]);
+    }
+
+    #[test]
+    fn concurrent_inserts_see_each_other() {
+        let filter = Filter::new(20_000, 1e-4);
+        // Every value goes in twice, from any threads in any order
+        let seen: Vec<bool> = (0u32..40_000)
+            .into_par_iter()
+            .map(|i| filter.insert(&(i % 20_000).to_le_bytes()))
+            .collect();
+        assert!((0..20_000).all(|i| seen[i] || seen[i + 20_000]));
+    }
+
+    #[test]
+    fn filter_meets_its_false_positive_rate() {
+        for rate in [1e-2, 1e-4, MIN_FP_RATE] {
+            let filter = Filter::new(10_000, rate);
+            assert!(
+                false_positive_rate(10_000.0 / filter.words.len() as f64, filter.hashes) <= rate
+            );
+        }
+        let filter = Filter::new(10_000, 1e-3);
+        let flagged = (0u32..10_000)
+            .filter(|i| filter.insert(&i.to_le_bytes()))
+            .count();
+        // Each insert is a query against the ones before, so at most ~10
+        assert!(flagged < 40, "{} false positives", flagged);
+        assert!((0u32..10_000).all(|i| filter.insert(&i.to_le_bytes())));
+    }
+}
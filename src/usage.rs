@@ -0,0 +1,281 @@
+//! Local-only usage metering.
+//!
+//! Runs tagged with `--tenant` append one line to a ledger file on this
+//! machine; nothing is ever sent anywhere. The ledger is plain tab-separated
+//! text (`unix_secs  tenant  records  encoding`) so it can be inspected or
+//! rotated with ordinary tools, and `usage` summarizes it.
+
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use mass_password_gen_optimized::timestamp::{civil_from_days, days_from_civil};
+
+/// One generation run, as recorded in the ledger.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub timestamp: u64,
+    pub tenant: String,
+    pub records: u64,
+    pub encoding: String,
+}
+
+/// Per-tenant totals over a time range.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Summary {
+    pub runs: u64,
+    pub records: u64,
+    pub first: u64,
+    pub last: u64,
+}
+
+/// Default ledger location: `$XDG_STATE_HOME/mass_password_gen/usage.tsv`,
+/// falling back to `~/.local/state` (or `%LOCALAPPDATA%` on Windows).
+pub fn default_ledger_path() -> PathBuf {
+    let base = std::env::var_os("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("LOCALAPPDATA").map(PathBuf::from))
+        .or_else(|| std::env::var_os("HOME").map(|h| Path::new(&h).join(".local/state")))
+        .unwrap_or_else(|| PathBuf::from("."));
+    base.join("mass_password_gen").join("usage.tsv")
+}
+
+/// Tenant names end up as a ledger column, so they can't contain separators.
+pub fn validate_tenant(s: &str) -> Result<String, String> {
+    if s.is_empty() || s.chars().any(|c| c.is_control() || c == '\t') {
+        return Err(format!("invalid tenant name `{}`", s));
+    }
+    Ok(s.to_string())
+}
+
+/// Appends one entry for a completed run.
+pub fn record(path: &Path, tenant: &str, records: u64, encoding: &str) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let line = format!("{}\t{}\t{}\t{}\n", timestamp, tenant, records, encoding);
+    // A single short write in append mode lands atomically, so concurrent
+    // runs can share a ledger.
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?
+        .write_all(line.as_bytes())
+}
+
+/// What [`read`] found in a ledger.
+#[derive(Debug, Default)]
+pub struct Ledger {
+    pub entries: Vec<Entry>,
+    /// Line numbers (from 1) that could not be parsed and were skipped,
+    /// such as a line cut short by a crash mid-append
+    pub malformed: Vec<usize>,
+}
+
+/// Reads every entry, skipping lines that don't parse; a missing ledger
+/// just means nothing was recorded yet.
+pub fn read(path: &Path) -> io::Result<Ledger> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Ledger::default()),
+        Err(e) => return Err(e),
+    };
+    let mut ledger = Ledger::default();
+    for (i, line) in text.lines().enumerate() {
+        if line.is_empty() {
+            continue;
+        }
+        match parse_entry(line) {
+            Some(entry) => ledger.entries.push(entry),
+            None => ledger.malformed.push(i + 1),
+        }
+    }
+    Ok(ledger)
+}
+
+fn parse_entry(line: &str) -> Option<Entry> {
+    let mut fields = line.split('\t');
+    let entry = Entry {
+        timestamp: fields.next()?.parse().ok()?,
+        tenant: fields.next()?.to_string(),
+        records: fields.next()?.parse().ok()?,
+        encoding: fields.next()?.to_string(),
+    };
+    Some(entry)
+}
+
+/// Totals per tenant for entries with `since <= timestamp < until`.
+pub fn summarize(
+    entries: &[Entry],
+    since: Option<u64>,
+    until: Option<u64>,
+    tenant: Option<&str>,
+) -> BTreeMap<String, Summary> {
+    let mut totals: BTreeMap<String, Summary> = BTreeMap::new();
+    for entry in entries {
+        if since.is_some_and(|s| entry.timestamp < s)
+            || until.is_some_and(|u| entry.timestamp >= u)
+            || tenant.is_some_and(|t| entry.tenant != t)
+        {
+            continue;
+        }
+        let summary = totals.entry(entry.tenant.clone()).or_default();
+        if summary.runs == 0 {
+            summary.first = entry.timestamp;
+        }
+        summary.runs += 1;
+        summary.records += entry.records;
+        summary.first = summary.first.min(entry.timestamp);
+        summary.last = summary.last.max(entry.timestamp);
+    }
+    totals
+}
+
+/// Parses `YYYY-MM-DD` (UTC midnight) or a raw unix timestamp.
+pub fn parse_date(s: &str) -> Result<u64, String> {
+    if let Ok(secs) = s.parse::<u64>() {
+        return Ok(secs);
+    }
+    let invalid = || format!("expected YYYY-MM-DD or unix seconds, got `{}`", s);
+    let mut parts = s.splitn(3, '-');
+    let (y, m, d) = (parts.next(), parts.next(), parts.next());
+    let y: i64 = y.and_then(|v| v.parse().ok()).ok_or_else(invalid)?;
+    let m: u32 = m.and_then(|v| v.parse().ok()).ok_or_else(invalid)?;
+    let d: u32 = d.and_then(|v| v.parse().ok()).ok_or_else(invalid)?;
+    if !(1..=12).contains(&m) || !(1..=31).contains(&d) || y < 1970 {
+        return Err(invalid());
+    }
+    // Days past the end of the month would roll over into the next one
+    let days = days_from_civil(y, m, d);
+    if civil_from_days(days) != (y, m, d) {
+        return Err(format!("`{}` is not a date", s));
+    }
+    Ok(days as u64 * 86_400)
+}
+
+/// Formats a unix timestamp as `YYYY-MM-DD HH:MM` UTC.
+pub fn format_timestamp(secs: u64) -> String {
+    let (days, rem) = (secs / 86_400, secs % 86_400);
+    let (y, m, d) = civil_from_days(days as i64);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}",
+        y,
+        m,
+        d,
+        rem / 3600,
+        rem % 3600 / 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(timestamp: u64, tenant: &str, records: u64) -> Entry {
+        Entry {
+            timestamp,
+            tenant: tenant.into(),
+            records,
+            encoding: "alnum".into(),
+        }
+    }
+
+    #[test]
+    fn ledger_lines_parse_back() {
+        assert_eq!(
+            parse_entry("1700000000\tacme\t500\talnum"),
+            Some(entry(1_700_000_000, "acme", 500))
+        );
+        for bad in [
+            "",
+            "1700000000\tacme\t500",
+            "soon\tacme\t500\talnum",
+            "1700000000\tacme\t-5\talnum",
+        ] {
+            assert_eq!(parse_entry(bad), None, "{:?}", bad);
+        }
+
+        let path = std::env::temp_dir().join("mpg_usage_ledger.tsv");
+        let _ = fs::remove_file(&path);
+        assert!(read(&path).unwrap().entries.is_empty());
+        record(&path, "acme", 500, "alnum").unwrap();
+        record(&path, "globex", 7, "raw").unwrap();
+        let entries = read(&path).unwrap().entries;
+        assert_eq!(entries.len(), 2);
+        assert_eq!(
+            (entries[1].tenant.as_str(), entries[1].records),
+            ("globex", 7)
+        );
+        fs::write(&path, "1\tacme\t1\traw\n\nbroken\n2\tacme\t2\traw\n3\tac").unwrap();
+        let ledger = read(&path).unwrap();
+        assert_eq!(ledger.entries.len(), 2);
+        assert_eq!(ledger.entries[1].records, 2);
+        assert_eq!(ledger.malformed, [3, 5]);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn summaries_keep_to_the_range_and_tenant() {
+        let entries = [
+            entry(100, "acme", 1),
+            entry(200, "acme", 10),
+            entry(300, "globex", 100),
+            entry(400, "acme", 1000),
+        ];
+        let all = summarize(&entries, None, None, None);
+        assert_eq!(
+            all["acme"],
+            Summary {
+                runs: 3,
+                records: 1011,
+                first: 100,
+                last: 400,
+            }
+        );
+        assert_eq!(all["globex"].records, 100);
+
+        // `since` is inclusive, `until` exclusive
+        let window = summarize(&entries, Some(200), Some(400), None);
+        assert_eq!(window["acme"].runs, 1);
+        assert_eq!((window["acme"].first, window["acme"].last), (200, 200));
+        assert_eq!(window["globex"].runs, 1);
+
+        let acme = summarize(&entries, None, None, Some("acme"));
+        assert_eq!(acme.keys().collect::<Vec<_>>(), ["acme"]);
+        assert!(summarize(&entries, Some(500), None, None).is_empty());
+    }
+
+    #[test]
+    fn dates_and_tenants_are_checked() {
+        assert_eq!(parse_date("1970-01-02"), Ok(86_400));
+        assert_eq!(parse_date("2024-02-29"), Ok(1_709_164_800));
+        assert_eq!(parse_date("1700000000"), Ok(1_700_000_000));
+        assert_eq!(
+            format_timestamp(parse_date("2024-03-01").unwrap() + 3_660),
+            "2024-03-01 01:01"
+        );
+        for bad in [
+            "2024-02-31",
+            "2023-02-29",
+            "2024-04-31",
+            "2024-13-01",
+            "2024-00-10",
+            "1969-12-31",
+            "2024-1",
+            "yesterday",
+        ] {
+            assert!(parse_date(bad).is_err(), "{}", bad);
+        }
+
+        assert_eq!(validate_tenant("acme corp"), Ok("acme corp".into()));
+        for bad in ["", "a\tb", "a\nb"] {
+            assert!(validate_tenant(bad).is_err(), "{:?}", bad);
+        }
+    }
+}
@@ -0,0 +1,196 @@
+//! `serve --bind vsock:CID:PORT`: AF_VSOCK stream sockets, which connect a
+//! virtual machine and its host without a network between them.
+//!
+//! The standard library has no vsock support, so this is the few socket
+//! calls `serve` and `fetch` need, on Linux. A CID names a machine: 2 is the host, and
+//! a listener on [`CID_ANY`] takes connections to any CID of this one.
+
+use std::io::{self, Read, Write};
+use std::mem::{size_of, zeroed};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::time::Duration;
+
+/// Listen on whatever CID this machine has.
+pub const CID_ANY: u32 = libc::VMADDR_CID_ANY;
+
+/// Connections a listener queues before accepting them.
+const BACKLOG: libc::c_int = 128;
+
+/// A listening vsock socket.
+pub struct Listener(OwnedFd);
+
+/// A connection, accepted by a [`Listener`] or made with
+/// [`Stream::connect`].
+pub struct Stream(OwnedFd);
+
+fn check(result: libc::c_int) -> io::Result<libc::c_int> {
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(result)
+}
+
+fn address(cid: u32, port: u32) -> libc::sockaddr_vm {
+    // SAFETY: sockaddr_vm is plain integers, for which zero is valid
+    let mut address: libc::sockaddr_vm = unsafe { zeroed() };
+    address.svm_family = libc::AF_VSOCK as libc::sa_family_t;
+    address.svm_cid = cid;
+    address.svm_port = port;
+    address
+}
+
+/// A fresh vsock stream socket.
+fn socket() -> io::Result<OwnedFd> {
+    // SAFETY: a plain socket call; the descriptor is owned from here on
+    let fd =
+        check(unsafe { libc::socket(libc::AF_VSOCK, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) })?;
+    // SAFETY: `fd` is a fresh descriptor nothing else owns
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+impl Listener {
+    /// Listens on `port` of `cid`.
+    pub fn bind(cid: u32, port: u32) -> io::Result<Self> {
+        let fd = socket()?;
+        let address = address(cid, port);
+        // SAFETY: `address` is a sockaddr_vm of the length given
+        check(unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &address as *const libc::sockaddr_vm as *const libc::sockaddr,
+                size_of::<libc::sockaddr_vm>() as libc::socklen_t,
+            )
+        })?;
+        // SAFETY: listen on a socket this owns
+        check(unsafe { libc::listen(fd.as_raw_fd(), BACKLOG) })?;
+        Ok(Listener(fd))
+    }
+
+    /// The CID and port it listens on.
+    pub fn local(&self) -> io::Result<(u32, u32)> {
+        // SAFETY: as in `address`
+        let mut address: libc::sockaddr_vm = unsafe { zeroed() };
+        let mut len = size_of::<libc::sockaddr_vm>() as libc::socklen_t;
+        // SAFETY: `address` has room for the `len` bytes written
+        check(unsafe {
+            libc::getsockname(
+                self.0.as_raw_fd(),
+                &mut address as *mut libc::sockaddr_vm as *mut libc::sockaddr,
+                &mut len,
+            )
+        })?;
+        Ok((address.svm_cid, address.svm_port))
+    }
+
+    /// The next connection, and the CID it comes from.
+    pub fn accept(&self) -> io::Result<(Stream, u32)> {
+        // SAFETY: as in `address`
+        let mut address: libc::sockaddr_vm = unsafe { zeroed() };
+        let mut len = size_of::<libc::sockaddr_vm>() as libc::socklen_t;
+        // SAFETY: `address` has room for the `len` bytes written, and the
+        // new descriptor is owned from here on
+        let fd = check(unsafe {
+            libc::accept4(
+                self.0.as_raw_fd(),
+                &mut address as *mut libc::sockaddr_vm as *mut libc::sockaddr,
+                &mut len,
+                libc::SOCK_CLOEXEC,
+            )
+        })?;
+        // SAFETY: `fd` is a fresh descriptor nothing else owns
+        let stream = Stream(unsafe { OwnedFd::from_raw_fd(fd) });
+        Ok((stream, address.svm_cid))
+    }
+}
+
+impl Stream {
+    /// Connects to `port` of `cid`.
+    pub fn connect(cid: u32, port: u32) -> io::Result<Self> {
+        let fd = socket()?;
+        let address = address(cid, port);
+        // SAFETY: as in `Listener::bind`
+        check(unsafe {
+            libc::connect(
+                fd.as_raw_fd(),
+                &address as *const libc::sockaddr_vm as *const libc::sockaddr,
+                size_of::<libc::sockaddr_vm>() as libc::socklen_t,
+            )
+        })?;
+        Ok(Stream(fd))
+    }
+
+    /// Sets how long reads and writes may block.
+    pub fn set_timeouts(&self, read: Duration, write: Duration) -> io::Result<()> {
+        for (option, timeout) in [(libc::SO_RCVTIMEO, read), (libc::SO_SNDTIMEO, write)] {
+            let timeval = libc::timeval {
+                tv_sec: timeout.as_secs() as libc::time_t,
+                tv_usec: timeout.subsec_micros() as libc::suseconds_t,
+            };
+            // SAFETY: `timeval` is the option's value, of the length given
+            check(unsafe {
+                libc::setsockopt(
+                    self.0.as_raw_fd(),
+                    libc::SOL_SOCKET,
+                    option,
+                    &timeval as *const libc::timeval as *const libc::c_void,
+                    size_of::<libc::timeval>() as libc::socklen_t,
+                )
+            })?;
+        }
+        Ok(())
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // SAFETY: `buf` has room for the `buf.len()` bytes read
+        let n = unsafe { libc::read(self.0.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len()) };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(n as usize)
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // SAFETY: `buf` holds the `buf.len()` bytes sent; a closed peer is
+        // an error instead of SIGPIPE
+        let n = unsafe {
+            libc::send(
+                self.0.as_raw_fd(),
+                buf.as_ptr().cast(),
+                buf.len(),
+                libc::MSG_NOSIGNAL,
+            )
+        };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(n as usize)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listeners_bind_a_port_of_their_own() {
+        let listener = match Listener::bind(CID_ANY, libc::VMADDR_PORT_ANY) {
+            Ok(listener) => listener,
+            // A kernel without vsock, where there is nothing to deliver to
+            Err(e) if e.raw_os_error() == Some(libc::EAFNOSUPPORT) => return,
+            Err(e) => panic!("{}", e),
+        };
+        let (cid, port) = listener.local().unwrap();
+        assert_eq!(cid, CID_ANY);
+        assert_ne!(port, libc::VMADDR_PORT_ANY);
+        let taken = Listener::bind(CID_ANY, port).err().unwrap();
+        assert_eq!(taken.kind(), io::ErrorKind::AddrInUse);
+    }
+}
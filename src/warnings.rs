@@ -0,0 +1,275 @@
+//! Warnings about risky configurations, checked once the run's options are
+//! resolved and before anything is generated.
+//!
+//! Each check looks at the [`Config`] and may add a [`Warning`] in one of
+//! three categories. They are advice rather than errors: the run goes ahead
+//! unless `--deny-warnings` asks for any warning to fail it, as a production
+//! pipeline would.
+
+use std::fmt;
+use std::path::Path;
+
+use mass_password_gen_optimized::analysis::{self, GUESS_RATES};
+use mass_password_gen_optimized::PasswordGenerator;
+
+/// Fewer bits per record than this is weak against an offline attack.
+const MIN_BITS: f64 = 64.0;
+
+/// Expected duplicates in the run above which a duplicate is likely enough
+/// to mention (about a 1% chance of any).
+const DUPLICATE_RISK: f64 = 0.01;
+
+/// Share of the machine's memory an in-memory run may take.
+const MEMORY_SHARE: f64 = 0.8;
+
+/// Chunks smaller than this cost more to schedule than to generate.
+const MIN_CHUNK_BYTES: usize = 64 << 10;
+
+/// What a warning is about.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Category {
+    /// The output may be guessable or exposed
+    Security,
+    /// The run will be slower than it could be
+    Performance,
+    /// The run may not fit, or the keyspace may not hold it
+    Capacity,
+}
+
+impl fmt::Display for Category {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Category::Security => "security",
+            Category::Performance => "performance",
+            Category::Capacity => "capacity",
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct Warning {
+    pub category: Category,
+    pub message: String,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "WARNING [{}]: {}", self.category, self.message)
+    }
+}
+
+/// Where the run's key came from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeySource {
+    /// Fresh from the OS CSPRNG
+    Random,
+    /// From the key store or a checkpoint
+    Stored,
+    /// Typed on the command line with --key-hex
+    CommandLine,
+    /// The published benchmark key
+    Published,
+    /// The published demo key, on purpose
+    Demo,
+    /// None: the run writes filler
+    Filler,
+}
+
+/// The resolved options of a run.
+pub struct Config<'a> {
+    pub generator: &'a PasswordGenerator,
+    pub key: KeySource,
+    /// An AES cipher on a CPU without AES instructions
+    pub software_aes: bool,
+    pub threads: usize,
+    pub logical_cpus: usize,
+    /// The output file; `None` for stdout or memory
+    pub output: Option<&'a Path>,
+    /// --output-mode
+    pub output_mode: u32,
+    /// True if the whole run is held in memory
+    pub in_memory: bool,
+    pub total_memory: u64,
+    /// True if --verify-unique checks the run for duplicates
+    pub unique_checked: bool,
+}
+
+/// Every warning `config` deserves, security first.
+pub fn evaluate(config: &Config) -> Vec<Warning> {
+    let mut warnings = Vec::new();
+    let mut warn = |category, message: String| warnings.push(Warning { category, message });
+    let generator = config.generator;
+    let real = matches!(
+        config.key,
+        KeySource::Random | KeySource::Stored | KeySource::CommandLine
+    );
+
+    match config.key {
+        KeySource::Published => warn(
+            Category::Security,
+            "--deterministic without --key-hex uses the published benchmark key; these \
+             passwords are public, do not use them for anything"
+                .to_string(),
+        ),
+        KeySource::CommandLine => warn(
+            Category::Security,
+            "the key was given with --key-hex, where shell history and process listings \
+             see it; store it with `key store` and pass --key instead"
+                .to_string(),
+        ),
+        _ => {}
+    }
+    let bits = analysis::entropy_bits(generator);
+    if real && bits < MIN_BITS {
+        let (attack, rate) = GUESS_RATES[GUESS_RATES.len() - 1];
+        warn(
+            Category::Security,
+            format!(
+                "{:.1} bits per password is weak: {} guesses one in {} on average",
+                bits,
+                attack,
+                analysis::format_duration(analysis::crack_seconds(bits, rate))
+            ),
+        );
+    }
+    if let (true, Some(path)) = (real, config.output) {
+        if cfg!(unix) && config.output_mode & 0o044 != 0 {
+            warn(
+                Category::Security,
+                format!(
+                    "{} will hold real passwords unencrypted and readable by other users \
+                     (--output-mode {:04o})",
+                    path.display(),
+                    config.output_mode
+                ),
+            );
+        }
+    }
+
+    if config.software_aes {
+        warn(
+            Category::Performance,
+            "no AES instructions on this CPU, so AES-CTR runs in software (often ~10x \
+             slower); consider --cipher chacha20 or --cipher auto"
+                .to_string(),
+        );
+    }
+    if config.threads > config.logical_cpus {
+        warn(
+            Category::Performance,
+            format!(
+                "{} worker threads on {} logical CPUs only take turns",
+                config.threads, config.logical_cpus
+            ),
+        );
+    }
+    let chunk_bytes = generator.chunk_len(0) * generator.record_width();
+    if generator.num_chunks() > 1 && chunk_bytes < MIN_CHUNK_BYTES {
+        warn(
+            Category::Performance,
+            format!(
+                "chunks of {} records ({} bytes) cost more to schedule than to generate; \
+                 try --chunk-size {} or --perf-mode",
+                generator.chunk_size(),
+                chunk_bytes,
+                MIN_CHUNK_BYTES.div_ceil(generator.record_width())
+            ),
+        );
+    }
+
+    if config.key != KeySource::Filler && !config.unique_checked {
+        let count = generator.count() as f64;
+        let duplicates = analysis::expected_duplicates(count, bits.exp2());
+        if duplicates > DUPLICATE_RISK {
+            warn(
+                Category::Capacity,
+                format!(
+                    "~{:.2} duplicate passwords expected among {} drawn from 2^{:.1}; use \
+                     a longer length or larger charset, or check with --verify-unique",
+                    duplicates,
+                    generator.count(),
+                    bits
+                ),
+            );
+        }
+    }
+    let bytes = generator.output_len() as f64;
+    if config.in_memory && bytes > config.total_memory as f64 * MEMORY_SHARE {
+        warn(
+            Category::Capacity,
+            format!(
+                "the run takes {:.2} GiB in memory, on a machine with {:.2} GiB; stream it \
+                 with --output instead",
+                bytes / (1u64 << 30) as f64,
+                config.total_memory as f64 / (1u64 << 30) as f64
+            ),
+        );
+    }
+    warnings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mass_password_gen_optimized::encoder::{Charset, Encoder};
+
+    #[test]
+    fn risky_configurations_warn() {
+        let build = |charset, length, count| {
+            PasswordGenerator::builder()
+                .key([1; 16])
+                .count(count)
+                .chunk_size(1_000_000)
+                .encoder(Encoder::new(&charset, length))
+                .build()
+                .unwrap()
+        };
+        let categories = |config: &Config| -> Vec<Category> {
+            evaluate(config).iter().map(|w| w.category).collect()
+        };
+        let strong = build(Charset::Alnum, 24, 1000);
+        let mut config = Config {
+            generator: &strong,
+            key: KeySource::Random,
+            software_aes: false,
+            threads: 4,
+            logical_cpus: 4,
+            output: None,
+            output_mode: crate::permissions::DEFAULT_MODE,
+            in_memory: true,
+            total_memory: 16 << 30,
+            unique_checked: false,
+        };
+        assert!(evaluate(&config).is_empty());
+        config.output = Some(Path::new("out.txt"));
+        assert!(evaluate(&config).is_empty());
+        // Only a mode that lets others read is worth a warning
+        config.output_mode = 0o640;
+        assert_eq!(categories(&config), [Category::Security]);
+        config.output_mode = 0o600;
+
+        config.key = KeySource::Published;
+        config.threads = 8;
+        assert_eq!(
+            categories(&config),
+            [Category::Security, Category::Performance]
+        );
+
+        // Six digits: weak, and a million of them repeat
+        let weak = build(Charset::Custom(b"0123456789".to_vec()), 6, 1_000_000);
+        config = Config {
+            generator: &weak,
+            key: KeySource::Random,
+            threads: 4,
+            total_memory: 1 << 20,
+            ..config
+        };
+        assert_eq!(
+            categories(&config),
+            [Category::Security, Category::Capacity, Category::Capacity]
+        );
+        config.key = KeySource::Filler;
+        config.in_memory = false;
+        assert!(evaluate(&config).is_empty());
+    }
+}
@@ -0,0 +1,259 @@
+//! Owner watermarks that survive shuffling and partial leaks.
+//!
+//! A record is selected for marking by an HMAC of everything but its last
+//! character (or last byte, for raw blocks). Selected records get that last
+//! position overwritten with a value also derived from the HMAC. Without the
+//! watermark key the marked position looks as random as the rest; with it, a
+//! candidate file can be tested record by record, in any order, because the
+//! check only needs the record's own contents.
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::io::{self, BufRead};
+
+/// One record in this many is marked.
+const RATE_DENOM: u64 = 16;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// A keyed watermark, parsed from `key:<32 hex chars>`.
+#[derive(Clone)]
+pub struct Watermark {
+    mac: HmacSha256,
+}
+
+impl Watermark {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let hex = s
+            .strip_prefix("key:")
+            .ok_or_else(|| format!("expected key:<hex>, got `{}`", s))?;
+        let key = crate::parse_key_hex(hex)?;
+        Ok(Watermark {
+            mac: HmacSha256::new_from_slice(&key).expect("HMAC accepts any key length"),
+        })
+    }
+
+    /// Returns the mark value if `prefix` selects its record for marking.
+    fn tag(&self, prefix: &[u8]) -> Option<u64> {
+        let mut mac = self.mac.clone();
+        mac.update(prefix);
+        let out = mac.finalize().into_bytes();
+        let selector = u64::from_le_bytes(out[0..8].try_into().unwrap());
+        let value = u64::from_le_bytes(out[8..16].try_into().unwrap());
+        (selector < u64::MAX / RATE_DENOM).then_some(value)
+    }
+
+    /// The byte the last position of a selected record must hold.
+    fn mark_byte(value: u64, alphabet: Option<&[u8]>) -> u8 {
+        match alphabet {
+            // Reducing a 64-bit value mod a small alphabet has negligible bias
+            Some(alphabet) => alphabet[(value % alphabet.len() as u64) as usize],
+            None => value as u8,
+        }
+    }
+
+    /// Marks `record` in place if it is selected. `alphabet` is the charset
+    /// for text records, or `None` for raw blocks.
+    pub fn apply(&self, record: &mut [u8], alphabet: Option<&[u8]>) {
+        let Some((last, prefix)) = record.split_last_mut() else {
+            return;
+        };
+        if let Some(value) = self.tag(prefix) {
+            *last = Self::mark_byte(value, alphabet);
+        }
+    }
+
+    /// `None` if `record` is not selected, otherwise whether it carries the mark.
+    pub fn check(&self, record: &[u8], alphabet: Option<&[u8]>) -> Option<bool> {
+        let (last, prefix) = record.split_last()?;
+        let value = self.tag(prefix)?;
+        Some(*last == Self::mark_byte(value, alphabet))
+    }
+
+    /// Tests every record from `input`: newline-separated text when
+    /// `alphabet` is given, otherwise consecutive 16-byte raw blocks.
+    pub fn detect(
+        &self,
+        mut input: impl BufRead,
+        alphabet: Option<&[u8]>,
+    ) -> io::Result<Detection> {
+        let mut detection = Detection {
+            checked: 0,
+            selected: 0,
+            matched: 0,
+            chance: match alphabet {
+                Some(alphabet) => 1.0 / alphabet.len() as f64,
+                None => 1.0 / 256.0,
+            },
+        };
+        let mut record = Vec::new();
+        loop {
+            record.clear();
+            let n = match alphabet {
+                Some(_) => input.read_until(b'\n', &mut record)?,
+                None => read_block(&mut input, &mut record)?,
+            };
+            if n == 0 {
+                break;
+            }
+            if alphabet.is_some() {
+                while record.last().is_some_and(|&c| c == b'\n' || c == b'\r') {
+                    record.pop();
+                }
+            }
+            detection.checked += 1;
+            if let Some(matched) = self.check(&record, alphabet) {
+                detection.selected += 1;
+                detection.matched += matched as u64;
+            }
+        }
+        Ok(detection)
+    }
+}
+
+/// Reads up to one 16-byte raw record.
+fn read_block(input: &mut impl BufRead, record: &mut Vec<u8>) -> io::Result<usize> {
+    record.resize(16, 0);
+    let mut filled = 0;
+    while filled < 16 {
+        match input.read(&mut record[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    record.truncate(filled);
+    Ok(filled)
+}
+
+/// Outcome of testing a candidate corpus for a watermark.
+#[derive(Debug)]
+pub struct Detection {
+    /// Records read
+    pub checked: u64,
+    /// Records the watermark key selects for marking
+    pub selected: u64,
+    /// Selected records that carry the expected mark
+    pub matched: u64,
+    /// Probability an unmarked selected record matches anyway
+    pub chance: f64,
+}
+
+impl Detection {
+    /// Probability of seeing at least `matched` matches in a corpus that was
+    /// never marked, i.e. P(X >= matched) for X ~ Binomial(selected, chance).
+    pub fn p_value(&self) -> f64 {
+        let (n, k, p) = (self.selected, self.matched, self.chance);
+        if k == 0 {
+            return 1.0;
+        }
+        let (ln_p, ln_q) = (p.ln(), (1.0 - p).ln());
+        // ln C(n, k) built up incrementally, then walk the upper tail
+        let mut ln_choose = 0.0;
+        for i in 0..k {
+            ln_choose += ((n - i) as f64 / (i + 1) as f64).ln();
+        }
+        let mut log_term = ln_choose + k as f64 * ln_p + (n - k) as f64 * ln_q;
+        let mut total = 0.0;
+        for i in k..=n {
+            let term = log_term.exp();
+            total += term;
+            if term < total * 1e-17 {
+                break;
+            }
+            log_term += ((n - i) as f64 / (i + 1) as f64).ln() + ln_p - ln_q;
+        }
+        total.min(1.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Charset, Encoder, PasswordGenerator};
+
+    const KEY: &str = "key:000102030405060708090a0b0c0d0e0f";
+
+    fn marked(encoder: Option<Encoder>) -> PasswordGenerator {
+        let mut builder = PasswordGenerator::builder()
+            .key([4; 16])
+            .count(4000)
+            .chunk_size(512)
+            .watermark(Watermark::parse(KEY).unwrap());
+        if let Some(encoder) = encoder {
+            builder = builder.encoder(encoder);
+        }
+        builder.build().unwrap()
+    }
+
+    #[test]
+    fn marks_survive_shuffling_and_partial_leaks() {
+        let enc = Encoder::new(&Charset::Alnum, 14);
+        let alphabet = enc.alphabet().to_vec();
+        let text = marked(Some(enc)).generate();
+        // A leak of every third record, in reverse order
+        let mut lines: Vec<&[u8]> = text
+            .split(|&b| b == b'\n')
+            .filter(|l| !l.is_empty())
+            .collect();
+        lines = lines.into_iter().step_by(3).rev().collect();
+        let leak = lines.join(&b'\n');
+
+        let watermark = Watermark::parse(KEY).unwrap();
+        let found = watermark.detect(&leak[..], Some(&alphabet)).unwrap();
+        assert_eq!(found.checked, lines.len() as u64);
+        assert!(found.selected > 0);
+        assert_eq!(found.matched, found.selected);
+        assert!(found.p_value() < 1e-20, "{}", found.p_value());
+
+        // Another key sees only chance agreement
+        let other = Watermark::parse("key:ffffffffffffffffffffffffffffffff").unwrap();
+        let unmarked = other.detect(&leak[..], Some(&alphabet)).unwrap();
+        assert!(unmarked.matched < unmarked.selected);
+        assert!(unmarked.p_value() > 1e-3, "{}", unmarked.p_value());
+
+        assert!(Watermark::parse("000102030405060708090a0b0c0d0e0f").is_err());
+        assert!(Watermark::parse("key:0001").is_err());
+    }
+
+    #[test]
+    fn raw_blocks_carry_the_mark_in_their_last_byte() {
+        let blocks = marked(None).generate();
+        let watermark = Watermark::parse(KEY).unwrap();
+        let found = watermark.detect(&blocks[..], None).unwrap();
+        assert_eq!(found.checked, 4000);
+        assert_eq!(found.matched, found.selected);
+        // About one record in RATE_DENOM
+        let expected = 4000 / RATE_DENOM;
+        assert!(
+            found.selected.abs_diff(expected) < expected / 2,
+            "{}",
+            found.selected
+        );
+
+        // Changing a marked record's last byte breaks its mark
+        let mut record = blocks
+            .chunks_exact(16)
+            .find(|r| watermark.check(r, None).is_some())
+            .unwrap()
+            .to_vec();
+        assert_eq!(watermark.check(&record, None), Some(true));
+        record[15] ^= 1;
+        assert_eq!(watermark.check(&record, None), Some(false));
+        assert_eq!(watermark.check(b"", None), None);
+    }
+
+    #[test]
+    fn p_values_follow_the_binomial_tail() {
+        let detection = |selected, matched| Detection {
+            checked: selected,
+            selected,
+            matched,
+            chance: 0.5,
+        };
+        assert_eq!(detection(10, 0).p_value(), 1.0);
+        // P(X >= 10) and P(X >= 9) for ten fair coins
+        assert!((detection(10, 10).p_value() - 1.0 / 1024.0).abs() < 1e-12);
+        assert!((detection(10, 9).p_value() - 11.0 / 1024.0).abs() < 1e-12);
+        assert!(detection(1000, 1000).p_value() < 1e-300);
+    }
+}
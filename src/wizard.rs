@@ -0,0 +1,314 @@
+//! `wizard`: asks for the options of a run one at a time, checking each
+//! answer with the parser the option has, then prints the command line
+//! that runs it and saves the options as a file for `--config`.
+//!
+//! It covers what a one-off corpus job needs (how many records and of
+//! what kind, a policy, the format, where they go and where the key comes
+//! from); the rest of the options can be added to the command line or the
+//! file afterwards. Questions go to stderr and the command line to stdout.
+
+use std::error::Error;
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+
+use mass_password_gen_optimized::pin::{self, WeakPattern};
+use mass_password_gen_optimized::{Charset, Format, PasswordGenerator, Wordlist};
+
+use crate::config::RunConfig;
+use crate::{keyprovider, parse_count, read_policy};
+
+/// Asks for a run's options on the terminal, prints its command line, and
+/// saves the options to `save` (or where the user says).
+pub fn run(save: Option<&Path>) -> Result<(), Box<dyn Error>> {
+    if let Some(path) = save.filter(|path| path.exists()) {
+        return Err(format!("{} exists; --save writes a new file", path.display()).into());
+    }
+    let mut input = io::stdin().lock();
+    let mut out = io::stderr();
+    let mut wizard = Wizard {
+        input: &mut input,
+        out: &mut out,
+    };
+    let config = wizard.options()?;
+    let save = match save {
+        Some(path) => Some(path.to_path_buf()),
+        None if io::stdin().is_terminal() => wizard.ask(
+            "Save the options to a file, for --config (blank to skip)",
+            "",
+            new_file,
+        )?,
+        None => None,
+    };
+    writeln!(out, "\nThe run, as a command line:")?;
+    println!("{}", config.command_line());
+    if let Some(path) = save {
+        let header =
+            "# Written by `wizard`; run with --config FILE, or see `explain --config FILE`\n";
+        std::fs::write(&path, format!("{}{}", header, config))
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        writeln!(out, "Saved to {}", path.display())?;
+    }
+    Ok(())
+}
+
+/// The questions, asked on `input` and `out`.
+struct Wizard<'a> {
+    input: &'a mut dyn BufRead,
+    out: &'a mut dyn Write,
+}
+
+impl Wizard<'_> {
+    /// Asks for every option in turn, and checks them together as the run
+    /// would.
+    fn options(&mut self) -> Result<RunConfig, String> {
+        let mut config = RunConfig::default();
+        let count = self.ask("Records to generate", "1000000", parse_count)?;
+        config.set("count", count.to_string());
+
+        let mode = self.ask(
+            "What to generate: password, passphrase or pin",
+            "password",
+            |s| match s {
+                "password" | "passphrase" | "pin" => Ok(s.to_string()),
+                _ => Err("answer password, passphrase or pin".to_string()),
+            },
+        )?;
+        match mode.as_str() {
+            "passphrase" => {
+                config.set("mode", "passphrase");
+                let wordlist = self.ask(
+                    "Wordlist: eff, eff-short, es, fr, it, pt or file:PATH",
+                    "eff",
+                    |s| Wordlist::parse(s).map(|_| s.to_string()),
+                )?;
+                if wordlist != "eff" {
+                    config.set("wordlist", wordlist);
+                }
+                let words = self.ask("Words per passphrase", "6", |s| number(s, 1, 256))?;
+                config.set("words", words.to_string());
+            }
+            "pin" => {
+                config.set("mode", "pin");
+                let digits = self.ask("Digits per PIN (4 to 12)", "6", |s| {
+                    let digits = number(s, 4, 12)?;
+                    pin::encoder(digits).map(|_| digits)
+                })?;
+                config.set("digits", digits.to_string());
+                let exclude = self.ask(
+                    "Weak PINs to leave out, comma-separated: repeats, sequences, years or \
+                     common:N (none for none)",
+                    "repeats,sequences",
+                    |s| match s {
+                        "none" => Ok(None),
+                        _ => s
+                            .split(',')
+                            .map(|p| WeakPattern::parse(p.trim()).map(|p| p.to_string()))
+                            .collect::<Result<Vec<_>, _>>()
+                            .map(|patterns| Some(patterns.join(","))),
+                    },
+                )?;
+                if let Some(exclude) = exclude {
+                    config.set("exclude", exclude);
+                }
+            }
+            _ => {
+                let charset = self.ask(
+                    "Characters: alnum, alnum+symbols, hex, base64url or custom:CHARS, or raw \
+                     for 16-byte binary blocks",
+                    "alnum",
+                    |s| match s {
+                        "raw" => Ok(None),
+                        _ => Charset::parse(s).map(|_| Some(s.to_string())),
+                    },
+                )?;
+                if let Some(charset) = charset {
+                    config.set("charset", charset);
+                    let length =
+                        self.ask("Characters per password", "16", |s| number(s, 1, 4096))?;
+                    config.set("length", length.to_string());
+                    let policy = self.ask(
+                        "Policy file the passwords must satisfy (blank for none)",
+                        "",
+                        |s| match s {
+                            "" => Ok(None),
+                            _ => read_policy(s).map(|_| Some(s.to_string())),
+                        },
+                    )?;
+                    if let Some(policy) = policy {
+                        config.set("policy", policy);
+                    }
+                }
+            }
+        }
+
+        let format = self.ask(
+            "Output format: raw (a password a line, or 16-byte blocks), hex, base64, csv or jsonl",
+            "raw",
+            |s| match Format::parse(s)? {
+                Format::ExpiringToken | Format::Hashcat => Err(format!(
+                    "the wizard leaves out {} output, which takes options of its own; add \
+                     --format {} to the command line it prints",
+                    s, s
+                )),
+                _ => Ok(s.to_string()),
+            },
+        )?;
+        if format != "raw" {
+            config.set("format", format);
+        }
+
+        let output = self.ask("Output file, or - for stdout", "passwords.txt", |s| {
+            Ok(s.to_string())
+        })?;
+        config.set("output", output);
+
+        let key = self.ask(
+            "Key: random for a fresh one (the output can never be made again), or where a \
+             kept key comes from, to make it again: keyring:NAME, env:VAR, file:PATH or prompt",
+            "random",
+            |s| match s {
+                "random" => Ok(None),
+                _ => keyprovider::KeyUri::parse(s).map(|_| Some(s.to_string())),
+            },
+        )?;
+        if let Some(key) = key {
+            config.switch("deterministic");
+            config.set("key", key);
+        }
+
+        let args = config.to_args().map_err(|e| e.render().to_string())?;
+        args.records
+            .configure(PasswordGenerator::builder().key([0; 16]))
+            .and_then(|builder| builder.build().map_err(|e| e.to_string()))?;
+        Ok(config)
+    }
+
+    /// Asks `question` until an answer passes `check`, which is given the
+    /// answer trimmed, or `default` for a blank one.
+    fn ask<T>(
+        &mut self,
+        question: &str,
+        default: &str,
+        check: impl Fn(&str) -> Result<T, String>,
+    ) -> Result<T, String> {
+        loop {
+            let prompt = match default {
+                "" => format!("{}: ", question),
+                _ => format!("{} [{}]: ", question, default),
+            };
+            let mut line = String::new();
+            self.out
+                .write_all(prompt.as_bytes())
+                .and_then(|()| self.out.flush())
+                .and_then(|()| self.input.read_line(&mut line))
+                .map_err(|e| e.to_string())
+                .and_then(|read| match read {
+                    0 => Err("the answers ran out before the questions did".to_string()),
+                    _ => Ok(()),
+                })?;
+            let answer = match line.trim() {
+                "" => default,
+                answer => answer,
+            };
+            match check(answer) {
+                Ok(value) => return Ok(value),
+                Err(e) => {
+                    let _ = writeln!(self.out, "  {}", e);
+                }
+            }
+        }
+    }
+}
+
+/// Parses a whole number from `least` to `most`.
+fn number(s: &str, least: usize, most: usize) -> Result<usize, String> {
+    match s.parse() {
+        Ok(n) if (least..=most).contains(&n) => Ok(n),
+        _ => Err(format!("answer a number from {} to {}", least, most)),
+    }
+}
+
+/// Checks that nothing is at `s` yet, for a file to save to.
+fn new_file(s: &str) -> Result<Option<PathBuf>, String> {
+    match s {
+        "" => Ok(None),
+        _ if Path::new(s).exists() => Err(format!("{} exists; name a new file", s)),
+        _ => Ok(Some(PathBuf::from(s))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The options given `answers`, one a line, and what was said.
+    fn answer(answers: &str) -> (Result<RunConfig, String>, String) {
+        let mut input = answers.as_bytes();
+        let mut out = Vec::new();
+        let config = Wizard {
+            input: &mut input,
+            out: &mut out,
+        }
+        .options();
+        (config, String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn answers_become_options() {
+        let (config, said) = answer("5e3\n\nhex\n20\n\ncsv\nout.csv\nkeyring:q3\n");
+        let config = config.unwrap();
+        assert_eq!(
+            config.args(),
+            [
+                "--count",
+                "5000",
+                "--charset",
+                "hex",
+                "--length",
+                "20",
+                "--format",
+                "csv",
+                "--output",
+                "out.csv",
+                "--deterministic",
+                "--key",
+                "keyring:q3",
+            ]
+        );
+        assert!(said.contains("Records to generate [1000000]: "));
+
+        // Defaults all the way, but for a passphrase
+        let (config, _) = answer("\npassphrase\n\n\n\n\nrandom\n");
+        assert_eq!(
+            config.unwrap().args(),
+            [
+                "--count",
+                "1000000",
+                "--mode",
+                "passphrase",
+                "--words",
+                "6",
+                "--output",
+                "passwords.txt"
+            ]
+        );
+    }
+
+    #[test]
+    fn bad_answers_are_asked_again() {
+        let (config, said) =
+            answer("0\n10\npin\n3\n4\nyears,decades\nnone\nhashcat\nraw\n-\nkms:a\n\n");
+        assert_eq!(
+            config.unwrap().args(),
+            ["--count", "10", "--mode", "pin", "--digits", "4", "--output", "-"]
+        );
+        assert!(said.contains("  count must be greater than zero"));
+        assert!(said.contains("  answer a number from 4 to 12"));
+        assert!(said.contains("decades"));
+        assert!(said.contains("add --format hashcat"));
+        assert!(said.contains("kms: keys are not supported"));
+
+        let (config, _) = answer("10\npassword\n");
+        assert!(config.unwrap_err().contains("ran out"));
+    }
+}
@@ -0,0 +1,293 @@
+//! Resume and failover under injected faults: however a checkpointed run is
+//! killed, resuming it must produce exactly the output of an undisturbed
+//! run, sharded or not. Needs `--features fault-injection`.
+
+#![cfg(feature = "fault-injection")]
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+const KEY: &str = "000102030405060708090a0b0c0d0e0f";
+
+/// Record options of the runs tested: positioned raw blocks and text, and
+/// in-order CSV and passphrases.
+const SHAPES: [&[&str]; 4] = [
+    &[],
+    &["--charset", "alnum", "--length", "12"],
+    &[
+        "--charset",
+        "alnum+symbols",
+        "--length",
+        "10",
+        "--format",
+        "csv",
+    ],
+    &["--mode", "passphrase", "--words", "4"],
+];
+
+/// Whether chunks of `shape` are written at their own offsets.
+fn positioned(shape: &[&str]) -> bool {
+    !shape.contains(&"--format") && !shape.contains(&"passphrase")
+}
+
+fn run(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_mass_password_gen_optimized"))
+        .args(["--count", "20000", "--threads", "2"])
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+fn scratch(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("mpg_fault_{}_{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn path(p: &Path) -> &str {
+    p.to_str().unwrap()
+}
+
+/// An undisturbed run of `shape` into `dir/expected`.
+fn expected(dir: &Path, shape: &[&str]) -> Vec<u8> {
+    let out = dir.join("expected");
+    let mut args = vec!["--deterministic", "--key-hex", KEY, "--chunk-size", "100"];
+    args.extend_from_slice(&["--output", path(&out)]);
+    args.extend_from_slice(shape);
+    assert!(run(&args).status.success());
+    fs::read(out).unwrap()
+}
+
+/// Starts a checkpointed run of `shape` under `faults`, which must stop it,
+/// then resumes it without faults. Returns the output and the chunk the
+/// run resumed after.
+fn interrupted_then_resumed(dir: &Path, shape: &[&str], faults: &str) -> (Vec<u8>, usize) {
+    let out = dir.join("output");
+    let ckpt = dir.join("run.ckpt");
+    let mut args = vec!["--deterministic", "--key-hex", KEY, "--chunk-size", "100"];
+    args.extend_from_slice(&["--output", path(&out), "--checkpoint", path(&ckpt)]);
+    args.extend_from_slice(&["--fault-inject", faults]);
+    args.extend_from_slice(shape);
+    let failed = run(&args);
+    assert!(!failed.status.success(), "{} did not stop the run", faults);
+    assert!(ckpt.exists());
+
+    let mut args = vec!["--resume", path(&ckpt)];
+    args.extend_from_slice(shape);
+    let resumed = run(&args);
+    assert!(
+        resumed.status.success(),
+        "{}",
+        String::from_utf8_lossy(&resumed.stderr)
+    );
+    assert!(!ckpt.exists());
+    let status = String::from_utf8_lossy(&resumed.stdout);
+    let after = status
+        .split("Resuming after chunk ")
+        .nth(1)
+        .and_then(|s| s.split(' ').next()?.parse().ok())
+        .unwrap();
+    (fs::read(out).unwrap(), after)
+}
+
+#[test]
+fn killed_runs_resume_to_identical_output() {
+    let dir = scratch("kill");
+    // Stalls keep the run going long enough for checkpoints to be saved
+    for faults in ["delay=1:25ms,kill-after=150", "delay=1:25ms,tear-after=130"] {
+        for shape in SHAPES {
+            let expected = expected(&dir, shape);
+            let (resumed, after) = interrupted_then_resumed(&dir, shape, faults);
+            assert!(
+                after > 0,
+                "{:?} under {}: nothing was checkpointed",
+                shape,
+                faults
+            );
+            assert!(resumed == expected, "{:?} under {}", shape, faults);
+        }
+    }
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn failed_writes_resume_to_identical_output() {
+    let dir = scratch("fail");
+    for shape in SHAPES {
+        let expected = expected(&dir, shape);
+        let (resumed, _) = interrupted_then_resumed(&dir, shape, "fail-writes=0.02,seed=5");
+        assert!(resumed == expected, "{:?}", shape);
+    }
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn failed_writes_fall_back_without_losing_chunks() {
+    let dir = scratch("fallback");
+    for shape in SHAPES {
+        let expected = expected(&dir, shape);
+        let (primary, spare) = (dir.join("primary"), dir.join("spare"));
+        let mut args = vec!["--chunk-size", "100", "--deterministic", "--key-hex", KEY];
+        args.extend_from_slice(&["--output", path(&primary), "--fallback", path(&spare)]);
+        args.extend_from_slice(&["--fault-inject", "fail-at=50"]);
+        args.extend_from_slice(shape);
+        let output = run(&args);
+        assert!(output.status.success());
+        let report = String::from_utf8_lossy(&output.stdout);
+        assert!(report.contains("injected write failure"), "{}", report);
+
+        // Positioned chunks sit at their own offsets in whichever file they
+        // landed in; in-order ones run back to back, the primary's first
+        let (primary, spare) = (fs::read(primary).unwrap(), fs::read(spare).unwrap());
+        let merged = if positioned(shape) {
+            let mut merged = vec![0; expected.len()];
+            for file in [primary, spare] {
+                for (m, b) in merged.iter_mut().zip(file) {
+                    *m |= b;
+                }
+            }
+            merged
+        } else {
+            [primary, spare].concat()
+        };
+        assert!(merged == expected, "{:?}", shape);
+    }
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn failed_shards_move_whole_to_the_fallback_directory() {
+    let dir = scratch("spill");
+    let (own, expected, spare) = (dir.join("own"), dir.join("expected"), dir.join("spare"));
+    for shape in SHAPES {
+        for d in [&own, &expected, &spare] {
+            let _ = fs::remove_dir_all(d);
+            fs::create_dir_all(d).unwrap();
+        }
+        let sharded = |out: &Path, more: &[&str]| {
+            let out = out.join("pw.txt");
+            let mut args = vec!["--chunk-size", "100", "--deterministic", "--key-hex", KEY];
+            args.extend_from_slice(&["--output", path(&out), "--shard-size", "5000"]);
+            args.extend_from_slice(more);
+            args.extend_from_slice(shape);
+            run(&args)
+        };
+        assert!(sharded(&expected, &[]).status.success());
+        let output = sharded(
+            &own,
+            &["--fallback", path(&spare), "--fault-inject", "fail-at=50"],
+        );
+        let report = String::from_utf8_lossy(&output.stdout);
+        assert!(output.status.success(), "{}", report);
+        assert!(report.contains("injected write failure"), "{}", report);
+
+        // The manifest gives each shard where it landed, whole
+        let manifest: serde_json::Value =
+            serde_json::from_slice(&fs::read(own.join("pw.manifest.json")).unwrap()).unwrap();
+        let paths: Vec<PathBuf> = manifest["shards"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|shard| own.join(shard["path"].as_str().unwrap()))
+            .collect();
+        assert_eq!(paths.len(), 4);
+        let moved: Vec<_> = paths.iter().filter(|p| p.starts_with(&spare)).collect();
+        assert_eq!(moved.len(), 1, "{:?}", paths);
+        assert!(!own.join(moved[0].file_name().unwrap()).exists());
+        for (n, landed) in paths.iter().enumerate() {
+            let name = format!("pw-{:05}.txt", n + 1);
+            assert!(
+                fs::read(landed).unwrap() == fs::read(expected.join(name)).unwrap(),
+                "{:?} shard {}",
+                shape,
+                n + 1
+            );
+        }
+        let verified = Command::new(env!("CARGO_BIN_EXE_mass_password_gen_optimized"))
+            .args(["verify", path(&own.join("pw.manifest.json"))])
+            .output()
+            .unwrap();
+        assert!(
+            verified.status.success(),
+            "{}",
+            String::from_utf8_lossy(&verified.stdout)
+        );
+    }
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn killed_sharded_runs_resume_after_the_last_finished_shard() {
+    let dir = scratch("shards");
+    let (own, expected) = (dir.join("own"), dir.join("expected"));
+    let ckpt = dir.join("run.ckpt");
+    let verify = |manifest: &Path| {
+        Command::new(env!("CARGO_BIN_EXE_mass_password_gen_optimized"))
+            .args(["verify", path(manifest)])
+            .output()
+            .unwrap()
+    };
+    for shape in SHAPES {
+        for d in [&own, &expected] {
+            let _ = fs::remove_dir_all(d);
+            fs::create_dir_all(d).unwrap();
+        }
+        let sharded = |out: &Path, more: &[&str]| {
+            let out = out.join("pw.txt");
+            let mut args = vec!["--chunk-size", "100", "--deterministic", "--key-hex", KEY];
+            args.extend_from_slice(&["--output", path(&out), "--shard-size", "5000"]);
+            args.extend_from_slice(more);
+            args.extend_from_slice(shape);
+            run(&args)
+        };
+        assert!(sharded(&expected, &[]).status.success());
+        let killed = sharded(
+            &own,
+            &[
+                "--checkpoint",
+                path(&ckpt),
+                "--fault-inject",
+                "delay=1:25ms,kill-after=120",
+            ],
+        );
+        assert!(!killed.status.success(), "{:?} was not killed", shape);
+
+        // The manifest lists the shards finished so far, and no more
+        let manifest = own.join("pw.manifest.json");
+        let listed: serde_json::Value =
+            serde_json::from_slice(&fs::read(&manifest).unwrap()).unwrap();
+        let listed = listed["shards"].as_array().unwrap().len();
+        assert!((1..4).contains(&listed), "{:?}: {} shards", shape, listed);
+        assert!(!verify(&manifest).status.success());
+
+        let mut args = vec!["--resume", path(&ckpt)];
+        args.extend_from_slice(shape);
+        let resumed = run(&args);
+        let status = String::from_utf8_lossy(&resumed.stdout);
+        assert!(resumed.status.success(), "{}", status);
+        assert!(
+            status.contains(&format!("Resuming after shard {} of 4", listed)),
+            "{}",
+            status
+        );
+        assert!(!ckpt.exists());
+        for n in 1..=4 {
+            let name = format!("pw-{:05}.txt", n);
+            assert!(
+                fs::read(own.join(&name)).unwrap() == fs::read(expected.join(&name)).unwrap(),
+                "{:?} shard {}",
+                shape,
+                n
+            );
+        }
+        let verified = verify(&manifest);
+        assert!(
+            verified.status.success(),
+            "{}",
+            String::from_utf8_lossy(&verified.stdout)
+        );
+    }
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,244 @@
+//! Property tests of the guarantees performance work must not break, at
+//! small scale: a run is a function of its key and options, Continuous
+//! output does not depend on the chunk size, raw blocks never repeat,
+//! encoded records hold exactly their alphabet, every output format reads
+//! back to the records written, and a resumed run ends up byte for byte
+//! the run that was never interrupted.
+
+use mass_password_gen_optimized::{
+    Capitalization, Charset, Cipher, CorpusReader, Encoder, Format, IvStrategy, Key, Passphrase,
+    PasswordGenerator, PasswordGeneratorBuilder, Progress, Template,
+};
+use proptest::prelude::*;
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::path::PathBuf;
+
+/// The record encodings tested: raw blocks, each built-in charset, a
+/// template and a passphrase.
+fn shape() -> impl Strategy<Value = Shape> {
+    prop_oneof![
+        Just(Shape::Raw),
+        (0..4usize, 1..40usize).prop_map(|(charset, length)| Shape::Text(
+            [
+                Charset::Alnum,
+                Charset::AlnumSymbols,
+                Charset::Hex,
+                Charset::Base64Url
+            ][charset]
+                .clone(),
+            length
+        )),
+        Just(Shape::Template),
+        (1..6usize).prop_map(Shape::Passphrase),
+    ]
+}
+
+#[derive(Clone, Debug)]
+enum Shape {
+    Raw,
+    Text(Charset, usize),
+    Template,
+    Passphrase(usize),
+}
+
+impl Shape {
+    fn apply(&self, builder: PasswordGeneratorBuilder) -> PasswordGeneratorBuilder {
+        match self {
+            Shape::Raw => builder,
+            Shape::Text(charset, length) => builder.encoder(Encoder::new(charset, *length)),
+            Shape::Template => builder.encoder(Encoder::from_template(
+                &Template::parse("Cvcv-####").unwrap(),
+            )),
+            Shape::Passphrase(words) => {
+                builder.passphrase(Passphrase::new(*words, "-", Capitalization::Lower, 2).unwrap())
+            }
+        }
+    }
+}
+
+/// A generator under as much of `key` as `cipher` takes.
+fn build(
+    key: [u8; 32],
+    cipher: Cipher,
+    iv_strategy: IvStrategy,
+    count: usize,
+    chunk_size: usize,
+    shape: &Shape,
+) -> PasswordGenerator {
+    let builder = PasswordGenerator::builder()
+        .key(Key::from_slice(&key[..cipher.key_len()]).unwrap())
+        .cipher(cipher)
+        .iv_strategy(iv_strategy)
+        .count(count)
+        .chunk_size(chunk_size);
+    shape.apply(builder).build().unwrap()
+}
+
+fn cipher() -> impl Strategy<Value = Cipher> {
+    prop::sample::select(Cipher::ALL.to_vec())
+}
+
+fn iv_strategy() -> impl Strategy<Value = IvStrategy> {
+    prop::sample::select(IvStrategy::ALL.to_vec())
+}
+
+/// The output formats whose sink needs nothing beyond the generator.
+fn format() -> impl Strategy<Value = Format> {
+    prop::sample::select(vec![
+        Format::Raw,
+        Format::Hex,
+        Format::Base64,
+        Format::Csv,
+        Format::Jsonl,
+    ])
+}
+
+/// A scratch file for `test`, apart from the other tests running alongside.
+fn scratch(test: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("mpg_properties_{}_{}", test, std::process::id()))
+}
+
+/// The whole of `g`'s output in `format`, written in one go.
+fn written(g: &PasswordGenerator, format: Format) -> Vec<u8> {
+    let mut out = Vec::new();
+    match format.sink(g) {
+        None => g.write_to(&mut out, |_, _| ()).unwrap(),
+        Some(sink) => g.write_formatted(&*sink, &mut out, |_, _| ()).unwrap(),
+    };
+    out
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(48))]
+
+    #[test]
+    fn runs_are_deterministic(
+        key in any::<[u8; 32]>(),
+        cipher in cipher(),
+        iv_strategy in iv_strategy(),
+        count in 1..600usize,
+        chunk_size in 1..200usize,
+        shape in shape(),
+    ) {
+        let g = build(key, cipher, iv_strategy, count, chunk_size, &shape);
+        let again = build(key, cipher, iv_strategy, count, chunk_size, &shape);
+        let output = g.generate();
+        prop_assert_eq!(&output, &again.generate());
+        let mut written = Vec::new();
+        g.write_to(&mut written, |_, _| ()).unwrap();
+        prop_assert_eq!(&output, &written);
+        prop_assert_eq!(g.records().count(), count);
+    }
+
+    #[test]
+    fn continuous_output_ignores_chunk_size(
+        key in any::<[u8; 32]>(),
+        cipher in cipher(),
+        count in 1..600usize,
+        chunk_sizes in (1..200usize, 1..200usize),
+        shape in shape(),
+    ) {
+        let (a, b) = chunk_sizes;
+        let continuous = IvStrategy::Continuous;
+        prop_assert_eq!(
+            build(key, cipher, continuous, count, a, &shape).generate(),
+            build(key, cipher, continuous, count, b, &shape).generate()
+        );
+    }
+
+    #[test]
+    fn raw_blocks_never_repeat(
+        key in any::<[u8; 32]>(),
+        cipher in cipher(),
+        iv_strategy in iv_strategy(),
+        count in 1..2000usize,
+        chunk_size in 1..300usize,
+    ) {
+        let g = build(key, cipher, iv_strategy, count, chunk_size, &Shape::Raw);
+        let blocks: HashSet<Vec<u8>> = g.records().collect();
+        prop_assert_eq!(blocks.len(), count);
+    }
+
+    #[test]
+    fn encoded_records_hold_only_their_alphabet(
+        key in any::<[u8; 32]>(),
+        cipher in cipher(),
+        count in 1..300usize,
+        chunk_size in 1..100usize,
+        charset in 0..5usize,
+        length in 1..64usize,
+        custom in prop::collection::btree_set(0x21u8..0x7f, 2..40),
+    ) {
+        let charset = match charset {
+            0 => Charset::Alnum,
+            1 => Charset::AlnumSymbols,
+            2 => Charset::Hex,
+            3 => Charset::Base64Url,
+            _ => Charset::Custom(custom.into_iter().collect()),
+        };
+        let alphabet: HashSet<u8> = charset.alphabet().into_iter().collect();
+        let shape = Shape::Text(charset, length);
+        let g = build(key, cipher, IvStrategy::ChunkIndex, count, chunk_size, &shape);
+        for record in g.records() {
+            prop_assert_eq!(record.len(), length);
+            prop_assert!(record.iter().all(|b| alphabet.contains(b)), "{:?}", record);
+        }
+    }
+
+    #[test]
+    fn formats_read_back_to_their_records(
+        key in any::<[u8; 32]>(),
+        count in 1..400usize,
+        chunk_size in 1..100usize,
+        shape in shape(),
+        format in format(),
+    ) {
+        let g = build(key, Cipher::Aes128, IvStrategy::ChunkIndex, count, chunk_size, &shape);
+        let path = scratch("formats");
+        fs::write(&path, written(&g, format)).unwrap();
+        let reader = CorpusReader::file(&path, format, g.is_binary(), None).unwrap();
+        let read: Vec<Vec<u8>> = reader
+            .records()
+            .map(|record| record.unwrap().password)
+            .collect();
+        fs::remove_file(&path).unwrap();
+        prop_assert_eq!(read, g.records().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn resumed_runs_match_uninterrupted_ones(
+        key in any::<[u8; 32]>(),
+        iv_strategy in iv_strategy(),
+        count in 1..600usize,
+        chunk_size in 1..100usize,
+        shape in shape(),
+        format in format(),
+        stop in any::<prop::sample::Index>(),
+        torn in prop::collection::vec(any::<u8>(), 0..64),
+    ) {
+        let g = build(key, Cipher::Aes128, iv_strategy, count, chunk_size, &shape);
+        let sink = format.sink(&g);
+        let sink = sink.as_deref();
+        let expected = written(&g, format);
+        let path = scratch("resume");
+
+        let mut checkpoints = vec![Progress::default()];
+        let out = File::create(&path).unwrap();
+        g.write_resumable(sink, &out, Progress::default(), |_, _| (), |p| checkpoints.push(p))
+            .unwrap();
+        prop_assert_eq!(fs::read(&path).unwrap(), expected.clone());
+
+        // Interrupted after any checkpoint, part way into the next chunk
+        let from = *stop.get(&checkpoints);
+        let mut damaged = expected[..from.bytes as usize].to_vec();
+        let room = expected.len() - damaged.len();
+        damaged.extend_from_slice(&torn[..torn.len().min(room)]);
+        fs::write(&path, damaged).unwrap();
+        let out = File::options().write(true).open(&path).unwrap();
+        g.write_resumable(sink, &out, from, |_, _| (), |_| ()).unwrap();
+        let resumed = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        prop_assert_eq!(resumed, expected);
+    }
+}